@@ -1,6 +1,13 @@
 use std::{env, path::PathBuf};
 
 fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/ktulhu/v1/ktulhu.proto");
+        tonic_build::compile_protos("proto/ktulhu/v1/ktulhu.proto")
+            .expect("Couldn't compile the gRPC protos");
+    }
+
     let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR"));
     let llama_dir = manifest_dir.join("llama.cpp");
     let include_main = llama_dir.join("include");
@@ -37,6 +44,7 @@ fn main() {
         .clang_arg(format!("-I{}", include_ggml.display()))
         .clang_arg(format!("-I{}", llama_dir.display()))
         .allowlist_function("llama_.*")
+        .allowlist_function("ggml_backend_dev_.*")
         .allowlist_type("llama_.*")
         .allowlist_var("LLAMA_.*")
         .allowlist_type("ggml_.*")
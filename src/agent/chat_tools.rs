@@ -0,0 +1,612 @@
+//! Opt-in tool calls for ws chat turns. Before the reply streams, the model
+//! is asked (JSON only, like `run_agent`) whether it needs a tool; calls are
+//! executed server-side, reported to the client as `tool_call` /
+//! `tool_result` frames, and their results are injected into the system
+//! prompt of the reply.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use axum::extract::ws::Message as WsMessage;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+use crate::conversation::build_mistral_prompt;
+use crate::inference::{json_schema::StructuredOutput, InferenceService};
+use crate::manager::ModelManager;
+use crate::model::message::Message;
+use crate::tools::web_search::{self, SearchProvider};
+use crate::vector::{VectorFilter, VectorStore};
+
+const DEFAULT_TOOLS: &str = "calculator,retrieval,web_search";
+const DEFAULT_RETRIEVAL_COLLECTIONS: &str = "docs";
+const DEFAULT_MAX_STEPS: usize = 4;
+const RETRIEVAL_TOP_K: usize = 3;
+/// Longest tool output kept in the prompt and sent to the client.
+const MAX_RESULT_CHARS: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatToolKind {
+    Calculator,
+    Retrieval,
+    WebSearch,
+}
+
+impl ChatToolKind {
+    pub const ALL: [ChatToolKind; 3] = [
+        ChatToolKind::Calculator,
+        ChatToolKind::Retrieval,
+        ChatToolKind::WebSearch,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChatToolKind::Calculator => "calculator",
+            ChatToolKind::Retrieval => "retrieval",
+            ChatToolKind::WebSearch => "web_search",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn usage(self) -> &'static str {
+        match self {
+            ChatToolKind::Calculator => {
+                r#"calculator – evaluate arithmetic: {"tool":"calculator","args":{"expression":"(2+3)*4"}}"#
+            }
+            ChatToolKind::Retrieval => {
+                r#"retrieval – search the knowledge base: {"tool":"retrieval","args":{"query":"...","collection":"docs"}}"#
+            }
+            ChatToolKind::WebSearch => {
+                r#"web_search – search the web: {"tool":"web_search","args":{"query":"..."}}"#
+            }
+        }
+    }
+
+    /// Schema of a call to this tool.
+    fn call_schema(self) -> Value {
+        let text = json!({ "type": "string", "minLength": 1 });
+        let (properties, required) = match self {
+            ChatToolKind::Calculator => (
+                json!({ "expression": {
+                    "type": "string",
+                    "minLength": 1,
+                    "maxLength": MAX_EXPRESSION_CHARS,
+                } }),
+                json!(["expression"]),
+            ),
+            ChatToolKind::Retrieval => (
+                json!({ "query": text, "collection": text }),
+                json!(["query"]),
+            ),
+            ChatToolKind::WebSearch => (json!({ "query": text }), json!(["query"])),
+        };
+        json!({
+            "type": "object",
+            "properties": {
+                "tool": { "const": self.as_str() },
+                "args": { "type": "object", "properties": properties, "required": required },
+            },
+            "required": ["tool", "args"],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatTool {
+    Calculator { expression: String },
+    Retrieval { query: String, collection: String },
+    WebSearch { query: String },
+}
+
+impl ChatTool {
+    fn kind(&self) -> ChatToolKind {
+        match self {
+            ChatTool::Calculator { .. } => ChatToolKind::Calculator,
+            ChatTool::Retrieval { .. } => ChatToolKind::Retrieval,
+            ChatTool::WebSearch { .. } => ChatToolKind::WebSearch,
+        }
+    }
+
+    fn args(&self) -> Value {
+        match self {
+            ChatTool::Calculator { expression } => json!({ "expression": expression }),
+            ChatTool::Retrieval { query, collection } => {
+                json!({ "query": query, "collection": collection })
+            }
+            ChatTool::WebSearch { query } => json!({ "query": query }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChatToolAction {
+    Call(ChatTool),
+    Done,
+}
+
+/// Tools the server allows in chat (`CHAT_TOOLS`, comma separated).
+pub fn allowed_tools() -> Vec<ChatToolKind> {
+    std::env::var("CHAT_TOOLS")
+        .unwrap_or_else(|_| DEFAULT_TOOLS.to_string())
+        .split(',')
+        .filter_map(ChatToolKind::parse)
+        .collect()
+}
+
+/// Collections `retrieval` may search (`CHAT_TOOL_RETRIEVAL_COLLECTIONS`).
+fn retrieval_collections() -> Vec<String> {
+    std::env::var("CHAT_TOOL_RETRIEVAL_COLLECTIONS")
+        .unwrap_or_else(|_| DEFAULT_RETRIEVAL_COLLECTIONS.to_string())
+        .split(',')
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn max_steps() -> usize {
+    std::env::var("CHAT_TOOLS_MAX_STEPS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_STEPS)
+}
+
+/// A tool-enabled turn, run by the inference worker before the reply.
+pub struct ToolSession {
+    pub tools: Vec<ChatToolKind>,
+    pub history: Vec<Message>,
+    pub system_prompt: String,
+    pub chat_id: String,
+    pub models: Arc<ModelManager>,
+    pub vectors: Arc<dyn VectorStore>,
+    pub web_search: Option<Arc<dyn SearchProvider>>,
+}
+
+impl ToolSession {
+    /// Tools the client asked for that the server allows; `None` when that
+    /// leaves nothing.
+    pub fn new(
+        requested: &[String],
+        history: &[Message],
+        system_prompt: &str,
+        chat_id: &str,
+        models: Arc<ModelManager>,
+        vectors: Arc<dyn VectorStore>,
+        web_search: Option<Arc<dyn SearchProvider>>,
+    ) -> Option<Self> {
+        let allowed = allowed_tools();
+        let mut tools: Vec<ChatToolKind> = Vec::new();
+        for kind in requested
+            .iter()
+            .filter_map(|name| ChatToolKind::parse(name))
+        {
+            if allowed.contains(&kind) && !tools.contains(&kind) {
+                tools.push(kind);
+            }
+        }
+        (!tools.is_empty()).then(|| Self {
+            tools,
+            history: history.to_vec(),
+            system_prompt: system_prompt.to_string(),
+            chat_id: chat_id.to_string(),
+            models,
+            vectors,
+            web_search,
+        })
+    }
+
+    /// Let the model call tools until it is done or `CHAT_TOOLS_MAX_STEPS`
+    /// is reached, streaming each call and result to `sender`. Returns the
+    /// reply prompt with the results injected, or `None` when no tool ran.
+    pub async fn run(
+        &self,
+        infer: &InferenceService,
+        sender: &mpsc::Sender<WsMessage>,
+        cancel: Arc<AtomicBool>,
+    ) -> Option<String> {
+        let format = match self.decision_format() {
+            Ok(format) => format,
+            Err(err) => {
+                debug!("tool decision schema rejected: {err}");
+                return None;
+            }
+        };
+        let mut results: Vec<String> = Vec::new();
+        for step in 0..max_steps() {
+            if cancel.load(Ordering::SeqCst) || sender.is_closed() {
+                return None;
+            }
+            let prompt = build_mistral_prompt(&self.history, Some(&self.decision_prompt(&results)));
+            let value = match infer.generate_json(prompt, &format, cancel.clone()).await {
+                Ok(value) => value,
+                Err(err) => {
+                    debug!("tool step failed: {err}");
+                    break;
+                }
+            };
+            let tool = match tool_action(&value) {
+                Ok(ChatToolAction::Call(tool)) => tool,
+                Ok(ChatToolAction::Done) => break,
+                Err(err) => {
+                    debug!("no tool call in model output: {err}");
+                    break;
+                }
+            };
+
+            let kind = tool.kind();
+            let _ = send(
+                sender,
+                json!({
+                    "type": "tool_call",
+                    "chat_id": self.chat_id,
+                    "step": step,
+                    "tool": kind.as_str(),
+                    "args": tool.args(),
+                }),
+            )
+            .await;
+
+            let outcome = if self.tools.contains(&kind) {
+                self.execute(&tool).await
+            } else {
+                Err(anyhow!(
+                    "tool `{}` is not enabled for this turn",
+                    kind.as_str()
+                ))
+            };
+            let (ok, output) = match outcome {
+                Ok(output) => (true, output),
+                Err(err) => (false, format!("error: {err}")),
+            };
+            let output: String = output.chars().take(MAX_RESULT_CHARS).collect();
+            info!(
+                chat_id = self.chat_id.as_str(),
+                tool = kind.as_str(),
+                ok,
+                "chat tool call"
+            );
+            let _ = send(
+                sender,
+                json!({
+                    "type": "tool_result",
+                    "chat_id": self.chat_id,
+                    "step": step,
+                    "tool": kind.as_str(),
+                    "ok": ok,
+                    "output": output,
+                }),
+            )
+            .await;
+            results.push(format!("{} {} → {}", kind.as_str(), tool.args(), output));
+        }
+
+        if results.is_empty() {
+            return None;
+        }
+        let system_prompt = format!(
+            "{}\n\nTool results (use them in your answer; do not output JSON or mention tool names):\n{}",
+            self.system_prompt,
+            results.join("\n")
+        );
+        Some(build_mistral_prompt(&self.history, Some(&system_prompt)))
+    }
+
+    /// A call to one of the enabled tools, or `{"final": true}`.
+    fn decision_format(&self) -> Result<StructuredOutput> {
+        let mut options = vec![json!({
+            "type": "object",
+            "properties": { "final": { "type": "boolean" } },
+            "required": ["final"],
+        })];
+        options.extend(self.tools.iter().map(|kind| kind.call_schema()));
+        StructuredOutput::new(json!({ "anyOf": options }))
+    }
+
+    fn decision_prompt(&self, results: &[String]) -> String {
+        let mut out = String::from(
+            "Decide whether a tool is needed to answer the last user message. Respond ONLY with JSON: a tool call, or {\"final\": true} when no (more) tools are needed.\nTools:\n",
+        );
+        for kind in &self.tools {
+            out.push_str("- ");
+            out.push_str(kind.usage());
+            out.push('\n');
+        }
+        if !results.is_empty() {
+            out.push_str("Results so far:\n");
+            for result in results {
+                out.push_str(result);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    async fn execute(&self, tool: &ChatTool) -> Result<String> {
+        match tool {
+            ChatTool::Calculator { expression } => {
+                let value = evaluate(expression)?;
+                Ok(format_number(value))
+            }
+            ChatTool::Retrieval { query, collection } => {
+                if !retrieval_collections().iter().any(|c| c == collection) {
+                    bail!("collection `{collection}` is not searchable");
+                }
+                let (_, encoder) = self
+                    .models
+                    .embedder(None)
+                    .ok_or_else(|| anyhow!("no embedding model loaded"))?;
+                let input = vec![query.clone()];
+                let embedded =
+                    tokio::task::spawn_blocking(move || encoder.embed_batch(&input)).await??;
+                let vector = embedded
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("empty embedding"))?
+                    .vector;
+                let hits = self
+                    .vectors
+                    .query(
+                        collection,
+                        &vector,
+                        RETRIEVAL_TOP_K,
+                        &VectorFilter::default(),
+                    )
+                    .await?;
+                if hits.is_empty() {
+                    return Ok("no matches".into());
+                }
+                Ok(hits
+                    .iter()
+                    .map(|hit| {
+                        let text = hit
+                            .metadata
+                            .get("text")
+                            .cloned()
+                            .unwrap_or_else(|| format!("{:?}", hit.metadata));
+                        format!("[{:.2}] {text}", hit.score)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            }
+            ChatTool::WebSearch { query } => {
+                let provider = self
+                    .web_search
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("web search is not configured on this server"))?;
+                let results = provider
+                    .search(query, "", web_search::result_count())
+                    .await?;
+                if results.is_empty() {
+                    return Ok("no results".into());
+                }
+                Ok(web_search::numbered(&results))
+            }
+        }
+    }
+}
+
+async fn send(sender: &mpsc::Sender<WsMessage>, value: Value) -> Result<()> {
+    sender
+        .send(WsMessage::Text(value.to_string().into()))
+        .await
+        .map_err(|_| anyhow!("ws channel closed"))
+}
+
+/// Parse a tool decision: `{"tool": name, "args": {...}}` or
+/// `{"final": ...}`. Code fences around the JSON are tolerated.
+pub fn parse_tool_action(text: &str) -> Result<ChatToolAction> {
+    let text = text
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim();
+    let value: Value = serde_json::from_str(text)?;
+    tool_action(&value)
+}
+
+/// Tool decision from an already parsed value.
+fn tool_action(value: &Value) -> Result<ChatToolAction> {
+    if value.get("final").is_some() {
+        return Ok(ChatToolAction::Done);
+    }
+
+    let tool_name = value
+        .get("tool")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("missing tool name"))?;
+    let args = value
+        .get("args")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("missing args"))?;
+    let arg = |name: &str| {
+        args.get(name)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("missing {name}"))
+    };
+
+    let tool = match ChatToolKind::parse(tool_name) {
+        Some(ChatToolKind::Calculator) => ChatTool::Calculator {
+            expression: arg("expression")?,
+        },
+        Some(ChatToolKind::Retrieval) => ChatTool::Retrieval {
+            query: arg("query")?,
+            collection: arg("collection").unwrap_or_else(|_| DEFAULT_RETRIEVAL_COLLECTIONS.into()),
+        },
+        Some(ChatToolKind::WebSearch) => ChatTool::WebSearch {
+            query: arg("query")?,
+        },
+        None => bail!("unknown tool: {tool_name}"),
+    };
+    Ok(ChatToolAction::Call(tool))
+}
+
+pub(crate) fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{value}")
+    }
+}
+
+/// Longest expression [`evaluate`] takes.
+const MAX_EXPRESSION_CHARS: usize = 1024;
+/// Deepest nesting of parentheses, unary minus and `^` chains; the parser
+/// recurses once per level, so this bounds its stack.
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+/// Evaluate `+ - * / % ^`, parentheses and unary minus over decimals.
+pub fn evaluate(expression: &str) -> Result<f64> {
+    if expression.chars().count() > MAX_EXPRESSION_CHARS {
+        bail!("expression is longer than {MAX_EXPRESSION_CHARS} characters");
+    }
+    let tokens: Vec<char> = expression.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut parser = Calc {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.expr()?;
+    if parser.pos != parser.tokens.len() {
+        bail!("unexpected `{}`", parser.tokens[parser.pos]);
+    }
+    if !value.is_finite() {
+        bail!("result is not a finite number");
+    }
+    Ok(value)
+}
+
+struct Calc {
+    tokens: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Calc {
+    fn peek(&self) -> Option<char> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    /// Run `parse` one nesting level down.
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<f64>) -> Result<f64> {
+        if self.depth == MAX_EXPRESSION_DEPTH {
+            bail!("expression nests deeper than {MAX_EXPRESSION_DEPTH} levels");
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn expr(&mut self) -> Result<f64> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.power()?;
+            value = match op {
+                '*' => value * rhs,
+                _ if rhs == 0.0 => bail!("division by zero"),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn power(&mut self) -> Result<f64> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            // Right-associative.
+            let exponent = self.nested(Self::power)?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<f64> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(-self.nested(Self::unary)?);
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.nested(Self::expr)?;
+                if self.peek() != Some(')') {
+                    bail!("missing `)`");
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let literal: String = self.tokens[start..self.pos].iter().collect();
+                literal
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!("bad number `{literal}`"))
+            }
+            Some(c) => bail!("unexpected `{c}`"),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calculator_and_tool_action_parsing() {
+        assert_eq!(evaluate("(2 + 3) * 4 - 2^3^0").unwrap(), 18.0);
+        assert_eq!(evaluate("-1.5 * -2").unwrap(), 3.0);
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("2 +").is_err());
+
+        let deep = |open: &str, close: &str, n| format!("{}1{}", open.repeat(n), close.repeat(n));
+        assert_eq!(evaluate(&deep("(", ")", 60)).unwrap(), 1.0);
+        assert_eq!(evaluate(&deep("-", "", 60)).unwrap(), 1.0);
+        assert!(evaluate(&deep("(", ")", 500)).is_err());
+        assert!(evaluate(&deep("-", "", 1000)).is_err());
+        assert!(evaluate(&deep("1^", "", 100)).is_err());
+        assert!(evaluate(&"1+".repeat(MAX_EXPRESSION_CHARS)).is_err());
+
+        assert_eq!(
+            parse_tool_action(r#"{"tool":"calculator","args":{"expression":"1+1"}}"#).unwrap(),
+            ChatToolAction::Call(ChatTool::Calculator {
+                expression: "1+1".into()
+            })
+        );
+        assert_eq!(
+            parse_tool_action("```json\n{\"final\": true}\n```").unwrap(),
+            ChatToolAction::Done
+        );
+        assert!(parse_tool_action(r#"{"tool":"run_cmd","args":{}}"#).is_err());
+    }
+}
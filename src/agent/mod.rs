@@ -1,13 +1,19 @@
-use std::{
-    fs,
-    process::Command,
-    sync::{atomic::AtomicBool, Arc},
+pub mod chat_tools;
+pub mod runs;
+pub mod sandbox;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
 };
 
 use anyhow::{anyhow, bail, Result};
-use serde_json::Value;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::inference::{backend::GenerationBackend, json_schema::StructuredOutput};
 
-use crate::inference::llama_cpp_service::LlamaCppService;
+use self::{runs::AgentEvent, sandbox::Sandbox};
 
 #[derive(Debug)]
 pub enum Tool {
@@ -16,6 +22,16 @@ pub enum Tool {
     WriteFile { path: String, content: String },
 }
 
+impl Tool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::RunCmd { .. } => "run_cmd",
+            Tool::ReadFile { .. } => "read_file",
+            Tool::WriteFile { .. } => "write_file",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum AgentAction {
     Tool { tool: Tool },
@@ -27,29 +43,120 @@ pub struct AgentState {
     pub max_steps: usize,
 }
 
-pub async fn run_agent(llama: &LlamaCppService, goal: &str) -> Result<()> {
+/// Step limit of a run; a run policy may lower it.
+pub const MAX_STEPS: usize = 20;
+
+/// Shape of every model action; sampling is constrained to it.
+static ACTION_FORMAT: Lazy<StructuredOutput> = Lazy::new(|| {
+    let path = json!({ "type": "string", "minLength": 1 });
+    let tool = |name: &str, args: Value| {
+        json!({
+            "type": "object",
+            "properties": { "tool": { "const": name }, "args": args },
+            "required": ["tool", "args"],
+        })
+    };
+    StructuredOutput::new(json!({
+        "anyOf": [
+            {
+                "type": "object",
+                "properties": { "final": { "type": "string" } },
+                "required": ["final"],
+            },
+            tool("run_cmd", json!({
+                "type": "object",
+                "properties": { "cmd": { "type": "string", "minLength": 1 } },
+                "required": ["cmd"],
+            })),
+            tool("read_file", json!({
+                "type": "object",
+                "properties": { "path": path },
+                "required": ["path"],
+            })),
+            tool("write_file", json!({
+                "type": "object",
+                "properties": { "path": path, "content": { "type": "string" } },
+                "required": ["path", "content"],
+            })),
+        ]
+    }))
+    .expect("invalid agent action schema")
+});
+
+pub async fn run_agent(llama: &dyn GenerationBackend, goal: &str) -> Result<()> {
+    let sandbox = Sandbox::from_env()?;
+    println!("🔒 agent jail: {}", sandbox.root().display());
     let cancel = Arc::new(AtomicBool::new(false));
+
+    if let Some(message) = run_steps(llama, goal, &sandbox, MAX_STEPS, cancel, |_| {}).await? {
+        println!("✅ DONE:\n{message}");
+    }
+    Ok(())
+}
+
+/// The agent loop. Every model action and tool result is passed to
+/// `on_event`. Returns the final message, or `None` when `cancel` was set.
+pub async fn run_steps<F>(
+    llama: &dyn GenerationBackend,
+    goal: &str,
+    sandbox: &Sandbox,
+    max_steps: usize,
+    cancel: Arc<AtomicBool>,
+    mut on_event: F,
+) -> Result<Option<String>>
+where
+    F: FnMut(AgentEvent),
+{
     let mut state = AgentState {
         history: Vec::new(),
-        max_steps: 20,
+        max_steps,
     };
 
     for step in 0..state.max_steps {
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
         let prompt = build_prompt(goal, &state);
-        let output = llama.generate_completion(prompt, cancel.clone()).await?;
+        let value = llama
+            .generate_json(prompt, &ACTION_FORMAT, cancel.clone())
+            .await?;
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        let output = value.to_string();
+        on_event(AgentEvent::Action {
+            step,
+            output: output.clone(),
+        });
 
-        let action = parse_action(output.trim())?;
+        let action = parse_action(&value)?;
 
         match action {
             AgentAction::Tool { tool } => {
-                let result = execute_tool(tool)?;
+                let name = tool.name();
+                // Commands block on the child process.
+                let result = tokio::task::block_in_place(|| execute_tool(sandbox, tool));
+                // Refusals go back to the model so it can pick another route.
+                let (ok, result) = match result {
+                    Ok(result) => (true, result),
+                    Err(err) => (false, format!("error: {err}")),
+                };
+                on_event(AgentEvent::ToolResult {
+                    step,
+                    tool: name.to_string(),
+                    ok,
+                    result: result.clone(),
+                });
                 state
                     .history
                     .push(format!("Tool result (step {step}):\n{result}"));
             }
             AgentAction::Final { message } => {
-                println!("✅ DONE:\n{message}");
-                return Ok(());
+                on_event(AgentEvent::Final {
+                    step,
+                    message: message.clone(),
+                });
+                return Ok(Some(message));
             }
         }
 
@@ -83,9 +190,7 @@ fn build_prompt(goal: &str, state: &AgentState) -> String {
     out
 }
 
-fn parse_action(text: &str) -> Result<AgentAction> {
-    let value: Value = serde_json::from_str(text)?;
-
+fn parse_action(value: &Value) -> Result<AgentAction> {
     if let Some(final_msg) = value.get("final") {
         let message = final_msg.as_str().unwrap_or("").to_string();
         return Ok(AgentAction::Final { message });
@@ -136,23 +241,15 @@ fn parse_action(text: &str) -> Result<AgentAction> {
     Ok(AgentAction::Tool { tool: action })
 }
 
-fn execute_tool(tool: Tool) -> Result<String> {
+fn execute_tool(sandbox: &Sandbox, tool: Tool) -> Result<String> {
     match tool {
-        Tool::RunCmd { cmd } => {
-            let output = Command::new("sh").arg("-c").arg(&cmd).output()?;
-            Ok(format!(
-                "status: {}\nstdout:\n{}\nstderr:\n{}",
-                output.status,
-                String::from_utf8_lossy(&output.stdout),
-                String::from_utf8_lossy(&output.stderr)
-            ))
-        }
+        Tool::RunCmd { cmd } => sandbox.run(&cmd),
         Tool::ReadFile { path } => {
-            let contents = fs::read_to_string(&path)?;
+            let contents = sandbox.read_file(&path)?;
             Ok(format!("Read {} bytes from {}", contents.len(), path))
         }
         Tool::WriteFile { path, content } => {
-            fs::write(&path, content)?;
+            sandbox.write_file(&path, &content)?;
             Ok(format!("Wrote file {}", path))
         }
     }
@@ -0,0 +1,204 @@
+//! Agent runs started over the admin API. Each run executes in the
+//! background; its events are streamed to the caller and appended to a
+//! transcript in RocksDB, so a dropped stream loses nothing. Runs are
+//! stopped through the registry by id.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::db::DBLayer;
+use crate::inference::backend::GenerationBackend;
+
+use super::sandbox::{Sandbox, ToolPolicy};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentEvent {
+    Started {
+        run_id: String,
+        goal: String,
+        jail: String,
+    },
+    /// Raw model output for the step.
+    Action {
+        step: usize,
+        output: String,
+    },
+    ToolResult {
+        step: usize,
+        tool: String,
+        ok: bool,
+        result: String,
+    },
+    Final {
+        step: usize,
+        message: String,
+    },
+    Failed {
+        error: String,
+    },
+    Cancelled,
+}
+
+impl AgentEvent {
+    /// Value of the `type` tag.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AgentEvent::Started { .. } => "started",
+            AgentEvent::Action { .. } => "action",
+            AgentEvent::ToolResult { .. } => "tool_result",
+            AgentEvent::Final { .. } => "final",
+            AgentEvent::Failed { .. } => "failed",
+            AgentEvent::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentRunStatus {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentRunRecord {
+    pub run_id: String,
+    pub goal: String,
+    pub policy: ToolPolicy,
+    pub status: AgentRunStatus,
+    pub events: Vec<AgentEvent>,
+    pub started_ts: i64,
+    #[serde(default)]
+    pub finished_ts: Option<i64>,
+}
+
+/// Cancellation flags of the runs in progress.
+#[derive(Clone, Default)]
+pub struct AgentRunRegistry {
+    inner: Arc<RwLock<HashMap<String, Arc<AtomicBool>>>>,
+}
+
+impl AgentRunRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(&self, run_id: &str) -> Arc<AtomicBool> {
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.inner
+            .write()
+            .unwrap()
+            .insert(run_id.to_string(), cancel.clone());
+        cancel
+    }
+
+    fn finish(&self, run_id: &str) {
+        self.inner.write().unwrap().remove(run_id);
+    }
+
+    /// Ask a running run to stop. False when no such run is in progress.
+    pub fn cancel(&self, run_id: &str) -> bool {
+        match self.inner.read().unwrap().get(run_id) {
+            Some(cancel) => {
+                cancel.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Start a run in the background, confined to `sandbox` (see
+/// [`Sandbox::unattended`]). The receiver yields the run's events, ending
+/// with `final`, `failed` or `cancelled`.
+pub async fn start_run(
+    llama: Arc<dyn GenerationBackend>,
+    db: Arc<DBLayer>,
+    registry: AgentRunRegistry,
+    goal: String,
+    policy: ToolPolicy,
+    sandbox: Sandbox,
+) -> Result<(String, mpsc::UnboundedReceiver<AgentEvent>)> {
+    let max_steps = policy
+        .max_steps
+        .unwrap_or(super::MAX_STEPS)
+        .clamp(1, super::MAX_STEPS);
+    let run_id = Uuid::new_v4().to_string();
+
+    let started = AgentEvent::Started {
+        run_id: run_id.clone(),
+        goal: goal.clone(),
+        jail: sandbox.root().display().to_string(),
+    };
+    let mut record = AgentRunRecord {
+        run_id: run_id.clone(),
+        goal: goal.clone(),
+        policy,
+        status: AgentRunStatus::Running,
+        events: vec![started.clone()],
+        started_ts: chrono::Utc::now().timestamp(),
+        finished_ts: None,
+    };
+    db.save_agent_run(&record).await?;
+
+    let cancel = registry.register(&run_id);
+    let (stream_tx, stream_rx) = mpsc::unbounded_channel();
+    let _ = stream_tx.send(started);
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<AgentEvent>();
+
+    let id = run_id.clone();
+    tokio::spawn(async move {
+        let steps_tx = event_tx.clone();
+        let outcome = super::run_steps(&llama, &goal, &sandbox, max_steps, cancel, |event| {
+            let _ = steps_tx.send(event);
+        })
+        .await;
+        let last = match outcome {
+            Ok(Some(_)) => None,
+            Ok(None) => Some(AgentEvent::Cancelled),
+            Err(err) => Some(AgentEvent::Failed {
+                error: err.to_string(),
+            }),
+        };
+        if let Some(event) = last {
+            let _ = event_tx.send(event);
+        }
+    });
+
+    // Persist every event before forwarding it; the stream may be gone.
+    tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            record.status = match event {
+                AgentEvent::Final { .. } => AgentRunStatus::Done,
+                AgentEvent::Failed { .. } => AgentRunStatus::Failed,
+                AgentEvent::Cancelled => AgentRunStatus::Cancelled,
+                _ => AgentRunStatus::Running,
+            };
+            if record.status != AgentRunStatus::Running {
+                record.finished_ts = Some(chrono::Utc::now().timestamp());
+            }
+            record.events.push(event.clone());
+            if let Err(err) = db.save_agent_run(&record).await {
+                warn!(run_id = id.as_str(), "agent run save failed: {err}");
+            }
+            let _ = stream_tx.send(event);
+        }
+        registry.finish(&id);
+    });
+
+    Ok((run_id, stream_rx))
+}
@@ -0,0 +1,518 @@
+//! Confinement for the agent's tools. Commands run inside a working
+//! directory jail with a scrubbed environment, a command allowlist (or,
+//! with `AGENT_CMD_MODE=deny`, a denylist), a wall-clock limit and capped
+//! output; file tools may not leave the jail. Every tool use, refused or
+//! not, is appended to a JSONL audit log.
+//!
+//! This is a best-effort guard against a misbehaving model, not an OS-level
+//! sandbox: run the agent as an unprivileged user as well.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Denylist of `AGENT_CMD_MODE=deny`. Shells, interpreters and programs
+/// that run other programs are on it, since the check only sees the
+/// program they are given, not what they run.
+const DEFAULT_DENY: &str = "sudo,su,doas,ssh,scp,sftp,curl,wget,nc,ncat,dd,mkfs,mount,umount,shutdown,reboot,systemctl,crontab,\
+sh,bash,dash,zsh,ksh,fish,csh,tcsh,busybox,env,xargs,exec,eval,source,command,builtin,nohup,nice,timeout,time,watch,\
+python,python2,python3,perl,ruby,node,deno,php,lua,awk,gawk,mawk,find";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024;
+/// Paths outside the jail that commands may still name.
+const ALLOWED_OUTSIDE: &[&str] = &["/dev/null", "/dev/stdout", "/dev/stderr"];
+pub const TOOL_NAMES: &[&str] = &["run_cmd", "read_file", "write_file"];
+
+/// Per-run restrictions on top of the environment configuration. A policy
+/// can only narrow what the sandbox allows, never widen it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolPolicy {
+    /// Tools the model may use (`run_cmd`, `read_file`, `write_file`); all
+    /// when unset.
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+    /// Programs allowed for this run, intersected with `AGENT_CMD_ALLOW`.
+    #[serde(default)]
+    pub allow_cmds: Option<Vec<String>>,
+    /// Programs denied in addition to `AGENT_CMD_DENY`.
+    #[serde(default)]
+    pub deny_cmds: Vec<String>,
+    /// Lower command timeout; the environment limit still applies.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub max_steps: Option<usize>,
+}
+
+pub struct Sandbox {
+    root: PathBuf,
+    tools: Vec<String>,
+    /// Programs allowed to run. Empty refuses every command, unless
+    /// `deny_mode` lets anything not denied through.
+    allow: Vec<String>,
+    deny_mode: bool,
+    deny: Vec<String>,
+    timeout: Duration,
+    max_output: usize,
+    audit_log: PathBuf,
+}
+
+fn list_from_env(name: &str, default: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+impl Sandbox {
+    /// `AGENT_JAIL_DIR` (default the current directory),
+    /// `AGENT_CMD_ALLOW`, `AGENT_CMD_MODE` (`allow`, the default, or
+    /// `deny`), `AGENT_CMD_DENY`, `AGENT_CMD_TIMEOUT_SECS`
+    /// (default 30), `AGENT_MAX_OUTPUT_BYTES` (default 64 KiB) and
+    /// `AGENT_AUDIT_LOG` (default `logs/agent_audit.jsonl`).
+    pub fn from_env() -> Result<Self> {
+        let root = match std::env::var("AGENT_JAIL_DIR") {
+            Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir),
+            _ => std::env::current_dir()?,
+        };
+        let root = fs::canonicalize(&root)
+            .with_context(|| format!("agent jail {} not found", root.display()))?;
+        let timeout = std::env::var("AGENT_CMD_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let max_output = std::env::var("AGENT_MAX_OUTPUT_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(DEFAULT_MAX_OUTPUT_BYTES);
+        let audit_log = std::env::var("AGENT_AUDIT_LOG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("logs/agent_audit.jsonl"));
+
+        Ok(Self {
+            root,
+            tools: TOOL_NAMES.iter().map(|t| t.to_string()).collect(),
+            allow: list_from_env("AGENT_CMD_ALLOW", ""),
+            deny_mode: std::env::var("AGENT_CMD_MODE")
+                .is_ok_and(|mode| mode.trim().eq_ignore_ascii_case("deny")),
+            deny: list_from_env("AGENT_CMD_DENY", DEFAULT_DENY),
+            timeout: Duration::from_secs(timeout),
+            max_output,
+            audit_log,
+        })
+    }
+
+    /// The sandbox of a run started over HTTP, which nobody watches:
+    /// `AGENT_JAIL_DIR` must name a directory apart from the server's
+    /// working directory, which holds its credentials and database, and
+    /// `run_cmd` needs a command allowlist.
+    pub fn unattended(policy: &ToolPolicy) -> Result<Self> {
+        if !std::env::var("AGENT_JAIL_DIR").is_ok_and(|dir| !dir.trim().is_empty()) {
+            bail!("AGENT_JAIL_DIR is not set");
+        }
+        let sandbox = Self::from_env()?.with_policy(policy);
+        let cwd = fs::canonicalize(std::env::current_dir()?)?;
+        if sandbox.root.starts_with(&cwd) || cwd.starts_with(&sandbox.root) {
+            bail!("AGENT_JAIL_DIR must be outside the server's working directory");
+        }
+        if sandbox.tools.iter().any(|t| t == "run_cmd") && sandbox.allow.is_empty() {
+            bail!("run_cmd needs a command allowlist (AGENT_CMD_ALLOW or allow_cmds)");
+        }
+        Ok(sandbox)
+    }
+
+    /// Narrow the sandbox to `policy`.
+    pub fn with_policy(mut self, policy: &ToolPolicy) -> Self {
+        if let Some(tools) = &policy.tools {
+            self.tools.retain(|t| tools.contains(t));
+        }
+        if let Some(allow) = &policy.allow_cmds {
+            self.allow = if self.allow.is_empty() {
+                allow.clone()
+            } else {
+                self.allow
+                    .iter()
+                    .filter(|a| allow.contains(a))
+                    .cloned()
+                    .collect()
+            };
+            // An empty intersection must not fall back to "anything".
+            if self.allow.is_empty() {
+                self.tools.retain(|t| t != "run_cmd");
+            }
+        }
+        self.deny.extend(policy.deny_cmds.iter().cloned());
+        if let Some(secs) = policy.timeout_secs.filter(|s| *s > 0) {
+            self.timeout = self.timeout.min(Duration::from_secs(secs));
+        }
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Absolute path of `path` (relative paths are taken from the jail),
+    /// refusing anything that resolves outside the jail, symlinks included.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let joined = self.root.join(path);
+        let lexical = normalize(&joined);
+        if !lexical.starts_with(&self.root) {
+            bail!("path {path} is outside the agent jail");
+        }
+        // Resolve symlinks through the deepest existing ancestor.
+        let mut existing = lexical.as_path();
+        while !existing.exists() {
+            existing = existing
+                .parent()
+                .ok_or_else(|| anyhow!("path {path} has no existing parent"))?;
+        }
+        if !fs::canonicalize(existing)?.starts_with(&self.root) {
+            bail!("path {path} leaves the agent jail through a symlink");
+        }
+        Ok(lexical)
+    }
+
+    /// Allow/denylist on every program of the command line, no shell
+    /// expansion, and no path arguments outside the jail.
+    pub fn check_command(&self, cmd: &str) -> Result<()> {
+        // `$HOME/..`, `~` and `` `...` `` are expanded by the shell after
+        // the paths below were checked.
+        if let Some(c) = cmd.chars().find(|c| matches!(c, '$' | '~' | '`')) {
+            bail!("shell expansion (`{c}`) is not allowed");
+        }
+        if self.allow.is_empty() && !self.deny_mode {
+            bail!("no commands are allowed (set AGENT_CMD_ALLOW)");
+        }
+        for program in programs(cmd) {
+            let name = Path::new(&program)
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or(&program)
+                .to_string();
+            if self.deny.contains(&name) {
+                bail!("command `{name}` is denied");
+            }
+            if !self.allow.is_empty() && !self.allow.contains(&name) {
+                bail!("command `{name}` is not on the allowlist");
+            }
+        }
+        for word in cmd.split(|c: char| c.is_whitespace() || "=;&|<>()\"'".contains(c)) {
+            let looks_like_path = word.starts_with('/') || word.contains("..");
+            if !looks_like_path || ALLOWED_OUTSIDE.contains(&word) {
+                continue;
+            }
+            self.resolve(word)?;
+        }
+        Ok(())
+    }
+
+    fn check_tool(&self, tool: &str) -> Result<()> {
+        if !self.tools.iter().any(|t| t == tool) {
+            bail!("tool {tool} is not allowed for this run");
+        }
+        Ok(())
+    }
+
+    /// Run `cmd` with `sh -c` inside the jail, killed after the timeout.
+    pub fn run(&self, cmd: &str) -> Result<String> {
+        let started = Instant::now();
+        let result = self
+            .check_tool("run_cmd")
+            .and_then(|()| self.check_command(cmd))
+            .and_then(|()| self.spawn(cmd));
+        self.audit(json!({
+            "tool": "run_cmd",
+            "cmd": cmd,
+            "ok": result.is_ok(),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+            "duration_ms": started.elapsed().as_millis() as u64,
+        }));
+        result
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<String> {
+        let result = self
+            .check_tool("read_file")
+            .and_then(|()| self.resolve(path))
+            .and_then(|p| Ok(fs::read_to_string(p)?));
+        self.audit(json!({
+            "tool": "read_file",
+            "path": path,
+            "ok": result.is_ok(),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        }));
+        result
+    }
+
+    pub fn write_file(&self, path: &str, content: &str) -> Result<()> {
+        let result = self
+            .check_tool("write_file")
+            .and_then(|()| self.resolve(path))
+            .and_then(|p| {
+                if let Some(parent) = p.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Ok(fs::write(p, content)?)
+            });
+        self.audit(json!({
+            "tool": "write_file",
+            "path": path,
+            "bytes": content.len(),
+            "ok": result.is_ok(),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        }));
+        result
+    }
+
+    fn spawn(&self, cmd: &str) -> Result<String> {
+        use std::os::unix::process::CommandExt;
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .current_dir(&self.root)
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .env("HOME", &self.root)
+            .env("LANG", "C.UTF-8")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Own process group so the timeout kills the whole pipeline.
+            .process_group(0)
+            .spawn()?;
+
+        let stdout = capture(child.stdout.take(), self.max_output);
+        let stderr = capture(child.stderr.take(), self.max_output);
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                let _ = Command::new("kill")
+                    .arg("-KILL")
+                    .arg(format!("-{}", child.id()))
+                    .status();
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        let status = match status {
+            Some(status) => status.to_string(),
+            None => format!("killed after {:?}", self.timeout),
+        };
+        Ok(format!(
+            "status: {status}\nstdout:\n{stdout}\nstderr:\n{stderr}"
+        ))
+    }
+
+    fn audit(&self, mut entry: serde_json::Value) {
+        entry["ts"] = json!(chrono::Utc::now().timestamp());
+        entry["jail"] = json!(self.root.display().to_string());
+        let write = || -> Result<()> {
+            if let Some(parent) = self.audit_log.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.audit_log)?;
+            writeln!(file, "{entry}")?;
+            Ok(())
+        };
+        if let Err(err) = write() {
+            eprintln!("⚠️ agent audit log write failed: {err}");
+        }
+    }
+}
+
+/// Read a pipe on its own thread (so a full pipe cannot stall the child),
+/// keeping at most `limit` bytes.
+fn capture<R: Read + Send + 'static>(pipe: Option<R>, limit: usize) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return String::new();
+        };
+        let mut kept = Vec::new();
+        let mut buf = [0u8; 8192];
+        let mut total = 0usize;
+        while let Ok(n) = pipe.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let room = limit.saturating_sub(kept.len());
+            kept.extend_from_slice(&buf[..n.min(room)]);
+            total += n;
+        }
+        let mut out = String::from_utf8_lossy(&kept).into_owned();
+        if total > kept.len() {
+            out.push_str(&format!("\n[truncated {} bytes]", total - kept.len()));
+        }
+        out
+    })
+}
+
+/// First word of every simple command in a shell line, skipping
+/// `VAR=value` prefixes.
+fn programs(cmd: &str) -> Vec<String> {
+    cmd.split([';', '&', '|', '\n', '(', ')', '`', '$'])
+        .filter_map(|segment| {
+            segment
+                .split_whitespace()
+                .find(|word| !word.contains('='))
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// Lexically resolve `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::CurDir => {}
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jail(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("agent_jail_{name}_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn sandbox(root: &Path, allow: &[&str]) -> Sandbox {
+        Sandbox {
+            root: fs::canonicalize(root).unwrap(),
+            tools: TOOL_NAMES.iter().map(|t| t.to_string()).collect(),
+            allow: allow.iter().map(|a| a.to_string()).collect(),
+            deny_mode: allow.is_empty(),
+            deny: DEFAULT_DENY.split(',').map(str::to_string).collect(),
+            timeout: Duration::from_secs(5),
+            max_output: 16,
+            audit_log: std::env::temp_dir().join("agent_audit_test.jsonl"),
+        }
+    }
+
+    #[test]
+    fn file_tools_stay_in_the_jail() {
+        let root = jail("files");
+        let sandbox = sandbox(&root, &[]);
+
+        assert!(sandbox.write_file("notes/a.txt", "hi").is_ok());
+        assert!(sandbox.write_file("../escape.txt", "x").is_err());
+        assert!(sandbox.write_file("/etc/passwd", "x").is_err());
+        assert!(sandbox.check_command("cat ../../etc/shadow").is_err());
+        assert!(sandbox.check_command("ls > /dev/null").is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn allow_mode_is_the_default_and_refuses_unlisted_commands() {
+        let root = jail("allow");
+        let mut sandbox = sandbox(&root, &["ls", "cargo"]);
+        assert!(!sandbox.deny_mode);
+
+        assert!(sandbox.check_command("ls -la && cargo test").is_ok());
+        assert!(sandbox.check_command("ls | sh").is_err());
+        assert!(sandbox.check_command("printf hi").is_err());
+
+        sandbox.allow.clear();
+        assert!(sandbox.check_command("ls").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deny_mode_refuses_programs_that_run_other_programs() {
+        let root = jail("deny");
+        let sandbox = sandbox(&root, &[]);
+
+        assert!(sandbox.check_command("ls -la && cargo test").is_ok());
+        for cmd in [
+            "echo hi | sudo tee x",
+            "sh -c 'curl example.com'",
+            "bash -c 'wget example.com'",
+            "env curl example.com",
+            "ls | xargs wget",
+            "python3 -c 'import os'",
+            "FOO=1 /usr/bin/perl -e 1",
+        ] {
+            assert!(sandbox.check_command(cmd).is_err(), "{cmd}");
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn shell_expansion_is_refused() {
+        let root = jail("expand");
+        let sandbox = sandbox(&root, &["cat", "ls", "echo"]);
+
+        for cmd in [
+            "cat $HOME/../x",
+            "cat ${HOME}/../x",
+            "ls ~/",
+            "cat ~root/.ssh/id_rsa",
+            "echo `id`",
+            "echo $(id)",
+        ] {
+            assert!(sandbox.check_command(cmd).is_err(), "{cmd}");
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn policy_narrows_tools_and_commands() {
+        let root = jail("policy");
+        let sandbox = sandbox(&root, &["ls", "printf"]);
+        assert!(sandbox.write_file("notes/a.txt", "hi").is_ok());
+
+        let out = sandbox.run("printf 0123456789abcdefghij").unwrap();
+        assert!(out.contains("[truncated 4 bytes]"), "{out}");
+
+        let sandbox = sandbox.with_policy(&ToolPolicy {
+            tools: Some(vec!["run_cmd".into(), "read_file".into()]),
+            allow_cmds: Some(vec!["ls".into()]),
+            ..ToolPolicy::default()
+        });
+        assert!(sandbox.write_file("notes/b.txt", "x").is_err());
+        assert!(sandbox.read_file("notes/a.txt").is_ok());
+        assert!(sandbox.run("ls").is_ok());
+        assert!(sandbox.run("printf hi").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
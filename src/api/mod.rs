@@ -0,0 +1,291 @@
+//! HTTP plumbing shared by every router: a request id per request and the
+//! JSON error envelope.
+//!
+//! The id comes from `X-Request-Id` when the client sends a usable one and
+//! is generated otherwise. It is echoed in the response header, recorded on
+//! the request's tracing span, and put into every error body:
+//!
+//! ```json
+//! { "error": { "code": "user_not_found", "message": null, "request_id": "…" } }
+//! ```
+//!
+//! Request bodies are capped at [`json_body_limit`] unless a router sets its
+//! own `DefaultBodyLimit` (uploads, model inputs, the admin API).
+//!
+//! Handlers can return [`ApiError`] directly. Plain-text error responses,
+//! such as the `(StatusCode, String)` tuples most handlers still return and
+//! axum's extractor rejections, are rewritten into the same envelope on the
+//! way out.
+
+use std::fmt::Display;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{FromRequestParts, Request},
+    http::{header, request::Parts, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tracing::{info_span, Instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied id that is kept.
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// Largest plain-text error body rewritten into the envelope.
+const MAX_ERROR_BODY_BYTES: usize = 64 * 1024;
+const DEFAULT_JSON_BODY_LIMIT: usize = 64 * 1024;
+const DEFAULT_LARGE_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+fn limit_var(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
+}
+
+/// Body cap of ordinary JSON endpoints (`JSON_BODY_LIMIT_BYTES`, default
+/// 64 KiB).
+pub fn json_body_limit() -> usize {
+    limit_var("JSON_BODY_LIMIT_BYTES", DEFAULT_JSON_BODY_LIMIT)
+}
+
+/// Body cap of endpoints that take long texts or whole configs: generation
+/// and embedding inputs and the admin API (`LARGE_BODY_LIMIT_BYTES`,
+/// default 2 MiB).
+pub fn large_body_limit() -> usize {
+    limit_var("LARGE_BODY_LIMIT_BYTES", DEFAULT_LARGE_BODY_LIMIT)
+}
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// Id of the request being handled.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_header(value: Option<&HeaderValue>) -> Self {
+        let supplied = value
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| {
+                !v.is_empty()
+                    && v.len() <= MAX_REQUEST_ID_LEN
+                    && v.bytes().all(|b| b.is_ascii_graphic())
+            });
+        Self(supplied.map_or_else(|| Uuid::new_v4().to_string(), str::to_string))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| Self::from_header(parts.headers.get(REQUEST_ID_HEADER))))
+    }
+}
+
+/// Id of the request this task is handling, if any.
+pub fn current_request_id() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Middleware: assign the request id, run the request inside a span that
+/// carries it, and make sure error responses use the envelope.
+pub async fn request_context(mut req: Request, next: Next) -> Response {
+    let request_id = RequestId::from_header(req.headers().get(REQUEST_ID_HEADER));
+    let id = request_id.0.clone();
+    req.extensions_mut().insert(request_id);
+
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        path = %req.uri().path(),
+    );
+    let response = CURRENT
+        .scope(id.clone(), next.run(req))
+        .instrument(span)
+        .await;
+
+    let mut response = envelope_plain_error(response, &id).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+async fn envelope_plain_error(response: Response, request_id: &str) -> Response {
+    let status = response.status();
+    let plain = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(true, |v| v.starts_with("text/plain"));
+    if !(status.is_client_error() || status.is_server_error()) || !plain {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // Either way the body is replaced, so its old length no longer holds.
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let Ok(bytes) = to_bytes(body, MAX_ERROR_BODY_BYTES).await else {
+        return Response::from_parts(parts, Body::from("error body too large"));
+    };
+    let text = String::from_utf8_lossy(&bytes);
+    let envelope = ApiError::from((status, text.trim().to_string())).envelope(Some(request_id));
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(
+        parts,
+        Body::from(serde_json::to_vec(&envelope).unwrap_or_default()),
+    )
+}
+
+/// An error response: HTTP status, a snake_case `code` clients can match
+/// on, and an optional human-readable `message`.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: String,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    #[schema(example = "user_not_found")]
+    pub code: String,
+    pub message: Option<String>,
+    /// Same as the `X-Request-Id` response header; quote it when reporting
+    /// a problem.
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: impl Into<String>) -> Self {
+        Self {
+            status,
+            code: code.into(),
+            message: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn bad_request(code: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code)
+    }
+
+    pub fn not_found(code: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code)
+    }
+
+    pub fn internal(err: impl Display) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_server_error")
+            .with_message(err.to_string())
+    }
+
+    fn envelope(&self, request_id: Option<&str>) -> ErrorEnvelope {
+        ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code.clone(),
+                message: self.message.clone(),
+                request_id: request_id.map(str::to_string),
+            },
+        }
+    }
+}
+
+/// Code for a status, e.g. `bad_request` for 400.
+fn status_code_name(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_ascii_lowercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+fn is_code(text: &str) -> bool {
+    !text.is_empty()
+        && text.len() <= 64
+        && text
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Tuples carry either a code (`user_not_found`) or a free-form message;
+/// messages get the status name as their code.
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, text): (StatusCode, String)) -> Self {
+        if is_code(&text) {
+            Self::new(status, text)
+        } else if text.is_empty() {
+            Self::new(status, status_code_name(status))
+        } else {
+            Self::new(status, status_code_name(status)).with_message(text)
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let envelope = self.envelope(current_request_id().as_deref());
+        (self.status, Json(envelope)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tuples_map_to_codes_or_messages() {
+        let coded = ApiError::from((StatusCode::NOT_FOUND, "user_not_found".to_string()));
+        assert_eq!(coded.code, "user_not_found");
+        assert_eq!(coded.message, None);
+
+        let described =
+            ApiError::from((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()));
+        assert_eq!(described.code, "unauthorized");
+        assert_eq!(described.message.as_deref(), Some("Invalid credentials"));
+
+        let empty = ApiError::from((StatusCode::INTERNAL_SERVER_ERROR, String::new()));
+        assert_eq!(empty.code, "internal_server_error");
+    }
+
+    #[test]
+    fn client_request_ids_are_kept_only_when_sane() {
+        let kept = RequestId::from_header(Some(&HeaderValue::from_static("fe-123")));
+        assert_eq!(kept.0, "fe-123");
+        let replaced = RequestId::from_header(Some(&HeaderValue::from_static("has space")));
+        assert_ne!(replaced.0, "has space");
+        assert!(Uuid::parse_str(&replaced.0).is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+//! Plain text from uploaded documents (PDF, DOCX, Markdown, text), split
+//! into chunks that the prompt builder can feed to the model.
+
+use std::{collections::HashSet, io::Read, sync::OnceLock};
+
+use regex::Regex;
+
+use crate::{
+    model::upload::StoredFile,
+    storage::{StorageService, MIME_DOCX, MIME_MARKDOWN, MIME_PDF, MIME_TEXT},
+};
+
+const DEFAULT_MAX_TEXT_CHARS: usize = 50_000;
+pub const CHUNK_CHARS: usize = 1_500;
+/// `word/document.xml` of a DOCX is read at most this far, zip bombs aside.
+const MAX_DOCX_XML_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Text kept per document (`ATTACHMENT_MAX_TEXT_CHARS`, default 50k chars).
+fn max_text_chars() -> usize {
+    std::env::var("ATTACHMENT_MAX_TEXT_CHARS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_MAX_TEXT_CHARS)
+}
+
+/// Read a stored upload and return its text in chunks; empty when the file
+/// type carries no text or extraction failed.
+pub async fn extract_chunks(storage: &StorageService, file: &StoredFile) -> Vec<String> {
+    let bytes = match storage.read(file).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            tracing::warn!(file_id = file.id.as_str(), "failed to read upload: {err}");
+            return Vec::new();
+        }
+    };
+    match extract_text(bytes, &file.mime_type).await {
+        Some(text) => chunk_text(&text, CHUNK_CHARS),
+        None => Vec::new(),
+    }
+}
+
+/// Plain text of a document, when its type carries any.
+pub async fn extract_text(bytes: Vec<u8>, mime: &str) -> Option<String> {
+    let text = match mime {
+        MIME_TEXT => String::from_utf8_lossy(&bytes).into_owned(),
+        MIME_MARKDOWN => markdown_to_text(&String::from_utf8_lossy(&bytes)),
+        MIME_PDF => tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&bytes))
+            .await
+            .ok()?
+            .ok()?,
+        MIME_DOCX => tokio::task::spawn_blocking(move || docx_to_text(&bytes))
+            .await
+            .ok()??,
+        _ => return None,
+    };
+    let text = normalize_whitespace(&text);
+    (!text.is_empty()).then(|| text.chars().take(max_text_chars()).collect())
+}
+
+/// Paragraph text of `word/document.xml`.
+fn docx_to_text(bytes: &[u8]) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).ok()?;
+    let entry = archive.by_name("word/document.xml").ok()?;
+    let mut xml = String::new();
+    entry
+        .take(MAX_DOCX_XML_BYTES)
+        .read_to_string(&mut xml)
+        .ok()?;
+    Some(docx_xml_to_text(&xml))
+}
+
+fn docx_xml_to_text(xml: &str) -> String {
+    let mut out = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start + 1..start + len];
+        let after = &rest[start + len + 1..];
+        let name = tag
+            .trim_end_matches('/')
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+        match name {
+            "w:t" if !tag.ends_with('/') => {
+                let end = after.find("</w:t>").unwrap_or(after.len());
+                out.push_str(&unescape_xml(&after[..end]));
+                rest = &after[end..];
+                continue;
+            }
+            "w:tab" => out.push('\t'),
+            "w:br" | "w:cr" => out.push('\n'),
+            "/w:p" => out.push_str("\n\n"),
+            _ => {}
+        }
+        rest = after;
+    }
+    out
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Drop Markdown syntax that carries no meaning for the model: heading and
+/// emphasis markers, code fences, images and link targets.
+fn markdown_to_text(markdown: &str) -> String {
+    static IMAGE: OnceLock<Regex> = OnceLock::new();
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    static EMPHASIS: OnceLock<Regex> = OnceLock::new();
+    let image = IMAGE.get_or_init(|| Regex::new(r"!\[([^\]]*)\]\([^)]*\)").unwrap());
+    let link = LINK.get_or_init(|| Regex::new(r"\[([^\]]+)\]\([^)]*\)").unwrap());
+    let emphasis = EMPHASIS.get_or_init(|| Regex::new(r"(\*\*|__|\*|`)").unwrap());
+
+    markdown
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| {
+            let line = line.trim_start().trim_start_matches('#').trim_start();
+            let line = image.replace_all(line, "$1");
+            let line = link.replace_all(&line, "$1");
+            emphasis.replace_all(&line, "").into_owned()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Collapse runs of spaces and keep at most one blank line between
+/// paragraphs.
+fn normalize_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push_str("\n\n");
+        } else if !out.is_empty() {
+            out.push('\n');
+        }
+        blank = false;
+        out.push_str(&line);
+    }
+    out
+}
+
+/// Split text into chunks of at most `max_chars`, breaking at paragraph
+/// and then word boundaries.
+pub fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for paragraph in text.split("\n\n").filter(|p| !p.trim().is_empty()) {
+        for piece in split_long(paragraph.trim(), max_chars) {
+            let needed = piece.chars().count() + if current.is_empty() { 0 } else { 2 };
+            if !current.is_empty() && current.chars().count() + needed > max_chars {
+                chunks.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push_str("\n\n");
+            }
+            current.push_str(&piece);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn split_long(paragraph: &str, max_chars: usize) -> Vec<String> {
+    if paragraph.chars().count() <= max_chars {
+        return vec![paragraph.to_string()];
+    }
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    for word in paragraph.split_inclusive(char::is_whitespace) {
+        if !current.is_empty() && current.chars().count() + word.chars().count() > max_chars {
+            pieces.push(current.trim_end().to_string());
+            current.clear();
+        }
+        if word.chars().count() > max_chars {
+            // A single "word" longer than a chunk (base64, tables without
+            // spaces): hard split.
+            let chars: Vec<char> = word.chars().collect();
+            for part in chars.chunks(max_chars) {
+                pieces.push(part.iter().collect());
+            }
+            continue;
+        }
+        current.push_str(word);
+    }
+    if !current.trim().is_empty() {
+        pieces.push(current.trim_end().to_string());
+    }
+    pieces
+}
+
+/// Pick chunks of several documents for the prompt within one
+/// `budget_chars`. Every document gets its opening chunk, cut to an even
+/// share of the budget if need be; the rest goes to the chunks sharing the
+/// most words with `query`, whichever document they are in. Each
+/// document's picks keep their order.
+pub fn select_chunks<'a>(
+    documents: &[&'a [String]],
+    query: &str,
+    budget_chars: usize,
+) -> Vec<Vec<&'a str>> {
+    let query_words: HashSet<String> = words(query)
+        .filter(|w| w.chars().count() > 2 || w.chars().all(|c| c.is_ascii_digit()))
+        .collect();
+    let share = budget_chars / documents.iter().filter(|d| !d.is_empty()).count().max(1);
+
+    let mut picked: Vec<Vec<(usize, &str)>> = vec![Vec::new(); documents.len()];
+    let mut used = 0;
+    let mut ranked = Vec::new();
+    for (doc, chunks) in documents.iter().enumerate() {
+        let Some((opening, rest)) = chunks.split_first() else {
+            continue;
+        };
+        let opening = truncate_chars(opening, share);
+        if !opening.is_empty() {
+            used += opening.chars().count();
+            picked[doc].push((0, opening));
+        }
+        for (offset, chunk) in rest.iter().enumerate() {
+            let score = words(chunk).filter(|w| query_words.contains(w)).count();
+            ranked.push((score, doc, offset + 1));
+        }
+    }
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+
+    for (_, doc, idx) in ranked {
+        let chunk = documents[doc][idx].as_str();
+        let len = chunk.chars().count();
+        if used + len > budget_chars {
+            continue;
+        }
+        used += len;
+        picked[doc].push((idx, chunk));
+    }
+    picked
+        .into_iter()
+        .map(|mut chunks| {
+            chunks.sort_unstable_by_key(|(idx, _)| *idx);
+            chunks.into_iter().map(|(_, chunk)| chunk).collect()
+        })
+        .collect()
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docx_paragraphs_and_entities() {
+        let xml = r#"<w:document><w:body><w:p><w:r><w:t>Term &amp; Conditions</w:t></w:r></w:p><w:p><w:r><w:t xml:space="preserve">Rent is </w:t></w:r><w:r><w:t>500</w:t></w:r><w:tab/><w:r><w:t>EUR</w:t></w:r></w:p></w:body></w:document>"#;
+        assert_eq!(
+            normalize_whitespace(&docx_xml_to_text(xml)),
+            "Term & Conditions\n\nRent is 500 EUR"
+        );
+    }
+
+    #[test]
+    fn chunks_respect_limit_and_selection_keeps_order() {
+        let text = (0..20)
+            .map(|i| format!("Paragraph {i} about clause {i} of the lease."))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let chunks = chunk_text(&text, 120);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 120));
+
+        let picked = select_chunks(&[&chunks], "what does paragraph 17 say", 250);
+        assert_eq!(picked[0][0], chunks[0]);
+        assert!(picked[0].iter().any(|c| c.contains("Paragraph 17")));
+    }
+
+    #[test]
+    fn every_document_gets_text_within_the_budget() {
+        let documents: Vec<Vec<String>> = (0..6)
+            .map(|d| {
+                (0..3)
+                    .map(|c| format!("doc{d} part{c} ").repeat(CHUNK_CHARS / 12))
+                    .collect()
+            })
+            .collect();
+        let slices: Vec<&[String]> = documents.iter().map(Vec::as_slice).collect();
+        let picked = select_chunks(&slices, "part2", 6_000);
+
+        assert!(picked.iter().all(|chunks| !chunks.is_empty()));
+        assert!(picked.iter().all(|chunks| chunks[0].starts_with("doc")));
+        let total: usize = picked.iter().flatten().map(|c| c.chars().count()).sum();
+        assert!(total <= 6_000, "{total}");
+    }
+
+    #[test]
+    fn markdown_markup_is_dropped() {
+        let md = "# Title\n\nSee [the docs](https://x.y) and **bold** text.\n```\ncode\n```";
+        assert_eq!(
+            markdown_to_text(md),
+            "Title\n\nSee the docs and bold text.\ncode"
+        );
+    }
+}
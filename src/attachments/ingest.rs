@@ -0,0 +1,191 @@
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::{
+    db::DBLayer,
+    inference::{
+        vision::ImageDescriber,
+        whisper::{Transcriber, Transcript},
+    },
+    model::{message::MessageAttachment, upload::StoredFile},
+    storage::{is_audio, StorageService},
+};
+
+use super::{describe_image, extract::extract_chunks, IncomingAttachment};
+
+const MAX_ATTACHMENTS: usize = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IngestError {
+    TooMany,
+    UnknownFile(String),
+    NotOwner(String),
+    TooLarge(String),
+    NotAudio(String),
+    TranscriptionUnavailable,
+    TranscriptionFailed,
+    EmptyTranscript,
+}
+
+impl IngestError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            IngestError::TooMany => "too_many_attachments",
+            IngestError::UnknownFile(_) => "unknown_file_id",
+            IngestError::NotOwner(_) => "file_not_owned_by_device",
+            IngestError::TooLarge(_) => "file_too_large",
+            IngestError::NotAudio(_) => "file_not_audio",
+            IngestError::TranscriptionUnavailable => "transcription_unavailable",
+            IngestError::TranscriptionFailed => "transcription_failed",
+            IngestError::EmptyTranscript => "empty_transcript",
+        }
+    }
+}
+
+/// Client-supplied OCR text is ignored unless `ATTACHMENTS_TRUST_CLIENT_TEXT=1`
+/// (for clients that do not upload files yet).
+fn trust_client_text() -> bool {
+    std::env::var("ATTACHMENTS_TRUST_CLIENT_TEXT")
+        .map(|v| matches!(v.trim(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Upload `file_id`, if `device_hash` uploaded it or belongs to the
+/// uploader's account, and it is within the size limit.
+pub async fn load_owned_upload(
+    db: &DBLayer,
+    storage: &StorageService,
+    device_hash: &str,
+    file_id: &str,
+) -> Result<StoredFile, IngestError> {
+    let file = db
+        .load_upload(file_id)
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(|| IngestError::UnknownFile(file_id.to_string()))?;
+    let user_id = db.find_user_id_by_device(device_hash).await.ok().flatten();
+    if !file.is_owned_by(device_hash, user_id.as_deref()) {
+        return Err(IngestError::NotOwner(file_id.to_string()));
+    }
+    if file.size as usize > storage.max_bytes() {
+        return Err(IngestError::TooLarge(file_id.to_string()));
+    }
+    Ok(file)
+}
+
+/// Transcript of an uploaded audio file. `language` (ISO 639-1) skips
+/// language detection.
+pub async fn transcribe_upload(
+    transcriber: Option<&dyn Transcriber>,
+    file: &StoredFile,
+    language: Option<&str>,
+) -> Result<Transcript, IngestError> {
+    if !is_audio(&file.mime_type) {
+        return Err(IngestError::NotAudio(file.id.clone()));
+    }
+    let transcriber = transcriber.ok_or(IngestError::TranscriptionUnavailable)?;
+    let transcript = transcriber
+        .transcribe(Path::new(&file.path), language)
+        .await
+        .map_err(|err| {
+            warn!(
+                file_id = file.id.as_str(),
+                transcriber = transcriber.name(),
+                "transcription failed: {err}"
+            );
+            IngestError::TranscriptionFailed
+        })?;
+    if transcript.text.trim().is_empty() {
+        return Err(IngestError::EmptyTranscript);
+    }
+    Ok(transcript)
+}
+
+/// Transcript of the first uploaded audio attachment of a ws prompt, which
+/// then stands in for the prompt's text. `None` without audio.
+pub async fn transcribe_voice_message(
+    db: &DBLayer,
+    storage: &StorageService,
+    transcriber: Option<&dyn Transcriber>,
+    device_hash: &str,
+    incoming: &[IncomingAttachment],
+    language: Option<&str>,
+) -> Result<Option<Transcript>, IngestError> {
+    for file_id in incoming
+        .iter()
+        .filter_map(|att| att.file_id.as_deref())
+        .filter(|id| !id.is_empty())
+    {
+        let file = load_owned_upload(db, storage, device_hash, file_id).await?;
+        if is_audio(&file.mime_type) {
+            return transcribe_upload(transcriber, &file, language)
+                .await
+                .map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Turn the attachments of a ws prompt into what gets stored on the user
+/// message. Uploaded files are checked against their record (owner, size,
+/// detected type) and their text is extracted and chunked here; images
+/// are described by `vision` when configured. Attachments without a
+/// `file_id` keep only their name, description and labels.
+pub async fn ingest_attachments(
+    db: &DBLayer,
+    storage: &StorageService,
+    device_hash: &str,
+    incoming: &[IncomingAttachment],
+    vision: Option<&dyn ImageDescriber>,
+) -> Result<Vec<MessageAttachment>, IngestError> {
+    if incoming.len() > MAX_ATTACHMENTS {
+        return Err(IngestError::TooMany);
+    }
+
+    let trust_client = trust_client_text();
+    let mut out = Vec::with_capacity(incoming.len());
+    for att in incoming {
+        let Some(file_id) = att.file_id.as_deref().filter(|id| !id.is_empty()) else {
+            out.push(MessageAttachment {
+                id: att.id.clone(),
+                filename: att.filename.clone(),
+                mime_type: att.mime_type.clone(),
+                preview_base64: att.preview_base64.clone(),
+                path: None,
+                size: None,
+                description: att.description.clone(),
+                ocr_text: att.ocr_text.clone().filter(|_| trust_client),
+                text_chunks: Vec::new(),
+                image_description: None,
+                labels: att.labels.clone().unwrap_or_default(),
+            });
+            continue;
+        };
+
+        let file = load_owned_upload(db, storage, device_hash, file_id).await?;
+        // Voice messages become the message text instead.
+        let text_chunks = if is_audio(&file.mime_type) {
+            Vec::new()
+        } else {
+            extract_chunks(storage, &file).await
+        };
+        let image_description = describe_image(vision, &file).await;
+
+        out.push(MessageAttachment {
+            id: att.id.clone(),
+            filename: file.filename.clone(),
+            mime_type: Some(file.mime_type.clone()),
+            preview_base64: att.preview_base64.clone(),
+            path: Some(file.path.clone()),
+            size: Some(file.size as usize),
+            description: att.description.clone(),
+            ocr_text: None,
+            text_chunks,
+            image_description,
+            labels: att.labels.clone().unwrap_or_default(),
+        });
+    }
+    Ok(out)
+}
@@ -0,0 +1,164 @@
+//! Append-only audit trail of admin and auth actions: role changes, user
+//! deletions, prompt edits and logins. Entries are stored in RocksDB under
+//! `audit:{ts}:{id}`, are never updated or deleted, and outlive the users
+//! they mention.
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db::DBLayer;
+
+pub const ROLE_CHANGE: &str = "role_change";
+pub const USER_DELETE: &str = "user_delete";
+pub const PROMPT_EDIT: &str = "prompt_edit";
+pub const PROMPT_RELOAD: &str = "prompt_reload";
+pub const SYSTEM_PROMPT_EDIT: &str = "system_prompt_edit";
+pub const LOGIN: &str = "login";
+pub const LOGIN_FAILED: &str = "login_failed";
+pub const REGISTER: &str = "register";
+pub const BULK_JOB: &str = "bulk_job";
+pub const RETENTION_OVERRIDE: &str = "retention_override";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub ts: i64,
+    pub action: String,
+    /// `admin:{username}`, `user:{id}` or, for failed logins, the login
+    /// that was tried (`email:{address}`).
+    pub actor: String,
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Peer address of the connection.
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// `X-Forwarded-For` as sent; only meaningful behind a trusted proxy.
+    #[serde(default)]
+    pub forwarded_for: Option<String>,
+    #[serde(default)]
+    pub device_hash: Option<String>,
+    #[serde(default)]
+    pub details: Value,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str, actor: impl Into<String>, ctx: &AuditContext) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            ts: chrono::Utc::now().timestamp(),
+            action: action.to_string(),
+            actor: actor.into(),
+            target: None,
+            ip: ctx.ip.clone(),
+            forwarded_for: ctx.forwarded_for.clone(),
+            device_hash: None,
+            details: Value::Null,
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn device_hash(mut self, device_hash: Option<&str>) -> Self {
+        self.device_hash = device_hash.map(str::to_string);
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+
+    /// True when `filter` is the whole actor or only its id part.
+    pub fn actor_matches(&self, filter: &str) -> bool {
+        self.actor == filter
+            || self
+                .actor
+                .split_once(':')
+                .is_some_and(|(_, id)| id == filter)
+    }
+}
+
+/// Admin username, set by the internal auth middleware.
+#[derive(Debug, Clone)]
+pub struct AdminActor(pub String);
+
+/// Where a request came from, and the admin making it on internal routes.
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    pub admin: Option<String>,
+    pub ip: Option<String>,
+    pub forwarded_for: Option<String>,
+}
+
+impl AuditContext {
+    /// `admin:{username}`; `admin` when the route is not behind the
+    /// internal auth middleware.
+    pub fn admin_actor(&self) -> String {
+        match &self.admin {
+            Some(username) => format!("admin:{username}"),
+            None => "admin".to_string(),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuditContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            admin: parts
+                .extensions
+                .get::<AdminActor>()
+                .map(|actor| actor.0.clone()),
+            ip: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.0.ip().to_string()),
+            forwarded_for: parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// Store an entry. Failures are logged, never returned: the action itself
+/// already happened.
+pub async fn record(db: &DBLayer, entry: AuditEntry) {
+    info!(
+        target: "audit",
+        action = %entry.action,
+        actor = %entry.actor,
+        target_id = entry.target.as_deref().unwrap_or_default(),
+        "audit entry"
+    );
+    if let Err(err) = db.append_audit_entry(&entry).await {
+        warn!(action = %entry.action, "failed to store audit entry: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actor_filter_matches_whole_actor_or_id() {
+        let entry = AuditEntry::new(LOGIN, "user:42", &AuditContext::default());
+        assert!(entry.actor_matches("user:42"));
+        assert!(entry.actor_matches("42"));
+        assert!(!entry.actor_matches("4"));
+        assert!(!entry.actor_matches("admin:42x"));
+    }
+}
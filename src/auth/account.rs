@@ -0,0 +1,195 @@
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::typed_header::TypedHeader;
+use headers::{authorization::Bearer, Authorization};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::attachments::storage_root;
+use crate::audit::{self, AuditContext, AuditEntry};
+use crate::auth::{tokens::authenticate, types::DeleteAccountRequest, utils::verify_password};
+use crate::{model::user::User, ws::AppState};
+
+const DEFAULT_REAUTH_WINDOW_SECS: i64 = 10 * 60;
+
+/// What was removed for a deleted account.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AccountDeletion {
+    pub user_id: String,
+    pub chats: usize,
+    pub messages: usize,
+    pub files: usize,
+    pub stripe_subscription_cancelled: bool,
+    pub stripe_customer_deleted: bool,
+}
+
+/// Remove a user and everything they own: chats and messages (including
+/// chats of their devices nobody else claimed), attachment files and
+/// upload records under `STORAGE_DIR`, sessions, device links and the
+/// Stripe customer.
+///
+/// Stripe goes first so a billing failure leaves the account intact and
+/// the deletion can be retried.
+pub async fn delete_account(state: &AppState, user: &User) -> Result<AccountDeletion> {
+    let mut report = AccountDeletion {
+        user_id: user.id.clone(),
+        chats: 0,
+        messages: 0,
+        files: 0,
+        stripe_subscription_cancelled: false,
+        stripe_customer_deleted: false,
+    };
+
+    if user.stripe_subscription_id.is_some() || user.stripe_customer_id.is_some() {
+        let payment = state
+            .payment
+            .as_ref()
+            .ok_or_else(|| anyhow!("payments_not_configured"))?;
+        if let Some(subscription_id) = user.stripe_subscription_id.as_deref() {
+            payment.cancel_subscription(subscription_id).await?;
+            report.stripe_subscription_cancelled = true;
+        }
+        if let Some(customer_id) = user.stripe_customer_id.as_deref() {
+            payment.delete_customer(customer_id).await?;
+            report.stripe_customer_deleted = true;
+        }
+    }
+
+    let mut files = HashSet::new();
+    for chat in state.db.list_chats_for_user(&user.id).await? {
+        if chat
+            .user_id
+            .as_deref()
+            .is_some_and(|owner| owner != user.id)
+        {
+            continue;
+        }
+        for msg in state.db.list_messages_for_chat(&chat.id).await? {
+            report.messages += 1;
+            files.extend(msg.attachments.into_iter().filter_map(|att| att.path));
+        }
+        state.db.delete_thread(&chat.id).await?;
+        report.chats += 1;
+    }
+
+    for upload in state.db.list_uploads_for_user(&user.id).await? {
+        state.db.delete_upload(&upload).await?;
+        files.insert(upload.path);
+    }
+
+    report.files = remove_stored_files(files);
+    state.db.delete_user(&user.id).await?;
+    Ok(report)
+}
+
+/// Delete attachment files, ignoring anything outside the storage root.
+pub(crate) fn remove_stored_files(paths: HashSet<String>) -> usize {
+    let Ok(root) = fs::canonicalize(storage_root()) else {
+        return 0;
+    };
+    paths
+        .into_iter()
+        .filter_map(|path| fs::canonicalize(PathBuf::from(path)).ok())
+        .filter(|path| path.starts_with(&root))
+        .filter(|path| fs::remove_file(path).is_ok())
+        .count()
+}
+
+fn reauth_window() -> i64 {
+    std::env::var("ACCOUNT_DELETE_REAUTH_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_REAUTH_WINDOW_SECS)
+}
+
+/// Delete the caller's account. Requires re-authentication: the current
+/// password (email accounts) or a session that logged in within
+/// `ACCOUNT_DELETE_REAUTH_SECS`.
+#[utoipa::path(
+    delete,
+    path = "/api/account",
+    tag = "auth",
+    request_body(content = Option<DeleteAccountRequest>, description = "Current password for email accounts"),
+    responses(
+        (status = 200, description = "Account and owned data removed", body = AccountDeletion),
+        (status = 401, description = "invalid_token / invalid_password"),
+        (status = 403, description = "reauthentication_required"),
+        (status = 502, description = "Stripe cleanup failed; nothing was deleted"),
+        (status = 503, description = "payments_not_configured while the account has Stripe linkage"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_account_handler(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    body: Option<Json<DeleteAccountRequest>>,
+) -> Result<Json<AccountDeletion>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let user = state
+        .db
+        .load_user(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "user_not_found".to_string()))?;
+
+    let password = body.and_then(|Json(req)| req.password);
+    match (user.password_hash.as_deref(), password) {
+        (Some(hash), Some(password)) => {
+            let valid = verify_password(hash, &password)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            if !valid {
+                return Err((StatusCode::UNAUTHORIZED, "invalid_password".into()));
+            }
+        }
+        _ => {
+            let session = match claims.sid.as_deref() {
+                Some(sid) => state
+                    .db
+                    .load_auth_session(&user.id, sid)
+                    .await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+                None => None,
+            };
+            let now = chrono::Utc::now().timestamp();
+            let fresh = session.is_some_and(|s| now - s.created_ts <= reauth_window());
+            if !fresh {
+                return Err((StatusCode::FORBIDDEN, "reauthentication_required".into()));
+            }
+        }
+    }
+
+    let report = delete_account(&state, &user).await.map_err(|e| {
+        tracing::warn!(user_id = %user.id, error = %e, "account deletion failed");
+        let message = e.to_string();
+        let status = if message.starts_with("stripe_error") {
+            StatusCode::BAD_GATEWAY
+        } else if message == "payments_not_configured" {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        (status, message)
+    })?;
+
+    tracing::info!(
+        target: "audit",
+        user_id = %report.user_id,
+        chats = report.chats,
+        messages = report.messages,
+        files = report.files,
+        "account deleted by user"
+    );
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::USER_DELETE, format!("user:{}", user.id), &ctx)
+            .target(format!("user:{}", user.id))
+            .details(json!({ "email": user.email, "removed": report })),
+    )
+    .await;
+
+    Ok(Json(report))
+}
@@ -1,25 +1,29 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
 use axum::{extract::State, Json};
-use jsonwebtoken::{decode_header, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode_header, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::{
+    audit::{self, AuditContext, AuditEntry},
+    auth::jwt::issue_token_pair,
     db::DBLayer,
     model::user::{User, UserRole},
     ws::AppState,
 };
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct AppleAuthRequest {
     pub id_token: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+#[schema(as = AppleAuthResponse)]
 pub struct AuthResponse {
     pub jwt: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
     pub user_id: String,
     pub email: Option<String>,
 }
@@ -31,13 +35,6 @@ struct AppleIdClaims {
     email: Option<String>,
 }
 
-// Your own JWT claims for clients
-#[derive(Debug, Serialize, Deserialize)]
-struct JwtClaims {
-    sub: String,
-    exp: usize,
-}
-
 #[derive(Debug, Deserialize)]
 struct JwkSet {
     keys: Vec<Jwk>,
@@ -50,8 +47,20 @@ struct Jwk {
     e: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/auth/apple",
+    tag = "auth",
+    request_body = AppleAuthRequest,
+    responses(
+        (status = 200, description = "Signed in with an Apple ID token", body = AppleAuthResponse),
+        (status = 400, description = "Apple login disabled"),
+        (status = 401, description = "ID token rejected"),
+    )
+)]
 pub async fn apple_login_handler(
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(payload): Json<AppleAuthRequest>,
 ) -> Result<Json<AuthResponse>, (axum::http::StatusCode, String)> {
     if state.apple_client_id.is_empty() {
@@ -144,32 +153,26 @@ pub async fn apple_login_handler(
         )
     })?;
 
-    // 6) Issue your own JWT
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as usize;
-
-    let exp = now + 60 * 60 * 24 * 7; // 7 days
-    let app_claims = JwtClaims {
-        sub: user.id.clone(),
-        exp,
-    };
-
-    let jwt = jsonwebtoken::encode(
-        &Header::default(),
-        &app_claims,
-        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    // 6) Issue your own tokens
+    let tokens = issue_token_pair(&state.db, &state.jwt_secret, &user.id, None)
+        .await
+        .map_err(|e| {
+            (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("JWT sign error: {e}"),
+            )
+        })?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::LOGIN, format!("user:{}", user.id), &ctx)
+            .details(json!({ "provider": "apple" })),
     )
-    .map_err(|e| {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("JWT sign error: {e}"),
-        )
-    })?;
+    .await;
 
     Ok(Json(AuthResponse {
-        jwt,
+        jwt: tokens.jwt,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
         user_id: user.id,
         email: user.external_id.or(user
             .meta
@@ -247,6 +250,9 @@ async fn upsert_apple_user(db: &DBLayer, claims: &AppleIdClaims) -> anyhow::Resu
         role: UserRole::Free,
         stripe_customer_id: None,
         stripe_subscription_id: None,
+        preferences: Default::default(),
+        plan: None,
+        plan_history: Vec::new(),
     };
 
     db.save_user(&user).await?;
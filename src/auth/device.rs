@@ -0,0 +1,356 @@
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::typed_header::TypedHeader;
+use base64::Engine;
+use headers::{authorization::Bearer, Authorization};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::auth::tokens::authenticate;
+use crate::auth::types::{ClaimDeviceRequest, RegisterDeviceRequest, RegisterDeviceResponse};
+use crate::db::DBLayer;
+use crate::ws::AppState;
+
+const SIGNED_PREFIX: &str = "dv1.";
+const MIN_DEVICE_HASH_LEN: usize = 8;
+const MAX_DEVICE_HASH_LEN: usize = 128;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeviceHashError {
+    Missing,
+    BadLength,
+    BadCharacters,
+    BadSignature,
+    /// Legacy (client-made) hash while `DEVICE_REQUIRE_SIGNED` is on.
+    Unsigned,
+    /// Legacy hash that was already exchanged for a server-minted id. The
+    /// new id is not disclosed: anyone may present an old hash.
+    Migrated,
+}
+
+impl DeviceHashError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DeviceHashError::Missing => "missing_device_hash",
+            DeviceHashError::BadLength | DeviceHashError::BadCharacters => "invalid_device_hash",
+            DeviceHashError::BadSignature => "invalid_device_signature",
+            DeviceHashError::Unsigned => "device_registration_required",
+            DeviceHashError::Migrated => "device_migrated",
+        }
+    }
+}
+
+/// Length and charset rules every device hash must pass, signed or not.
+pub fn validate_device_hash_format(device_hash: &str) -> Result<(), DeviceHashError> {
+    if device_hash.is_empty() {
+        return Err(DeviceHashError::Missing);
+    }
+    if !(MIN_DEVICE_HASH_LEN..=MAX_DEVICE_HASH_LEN).contains(&device_hash.len()) {
+        return Err(DeviceHashError::BadLength);
+    }
+    if !device_hash
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+    {
+        return Err(DeviceHashError::BadCharacters);
+    }
+    Ok(())
+}
+
+/// Mints and verifies server-issued device ids of the form
+/// `dv1.<random>.<hmac>`. Client-made hashes from older builds stay accepted
+/// until `DEVICE_REQUIRE_SIGNED=1`.
+pub struct DeviceIdSigner {
+    secret: Vec<u8>,
+    require_signed: bool,
+}
+
+impl DeviceIdSigner {
+    pub fn new(secret: &str, require_signed: bool) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+            require_signed,
+        }
+    }
+
+    /// `DEVICE_ID_SECRET`, falling back to a key derived from the JWT secret.
+    pub fn from_env(jwt_secret: &str) -> Self {
+        let secret = std::env::var("DEVICE_ID_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| format!("{jwt_secret}:device_id"));
+        let require_signed = std::env::var("DEVICE_REQUIRE_SIGNED")
+            .map(|v| matches!(v.trim(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        Self::new(&secret, require_signed)
+    }
+
+    fn mac(&self, body: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key size");
+        mac.update(body.as_bytes());
+        mac
+    }
+
+    pub fn mint(&self) -> String {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let body = b64.encode(bytes);
+        let sig = self.mac(&body).finalize().into_bytes();
+        format!("{SIGNED_PREFIX}{body}.{}", b64.encode(&sig[..16]))
+    }
+
+    /// Whether legacy hashes are refused, which also closes their migration.
+    pub fn requires_signed(&self) -> bool {
+        self.require_signed
+    }
+
+    pub fn is_signed_format(device_hash: &str) -> bool {
+        device_hash.starts_with(SIGNED_PREFIX)
+    }
+
+    fn verify_signature(&self, device_hash: &str) -> bool {
+        let Some((body, sig)) = device_hash[SIGNED_PREFIX.len()..].split_once('.') else {
+            return false;
+        };
+        let Ok(sig) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(sig) else {
+            return false;
+        };
+        sig.len() == 16 && self.mac(body).verify_truncated_left(&sig).is_ok()
+    }
+
+    /// Full check for a hash presented by a client.
+    pub async fn check(&self, db: &DBLayer, device_hash: &str) -> Result<(), DeviceHashError> {
+        validate_device_hash_format(device_hash)?;
+        if Self::is_signed_format(device_hash) {
+            return if self.verify_signature(device_hash) {
+                Ok(())
+            } else {
+                Err(DeviceHashError::BadSignature)
+            };
+        }
+        if let Ok(Some(_)) = db.load_device_alias(device_hash).await {
+            return Err(DeviceHashError::Migrated);
+        }
+        if self.require_signed {
+            return Err(DeviceHashError::Unsigned);
+        }
+        Ok(())
+    }
+}
+
+/// Validate an optional device hash from an HTTP payload; empty means none.
+pub(crate) async fn checked_device_hash(
+    state: &AppState,
+    device_hash: Option<&str>,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let Some(device_hash) = device_hash.map(str::trim).filter(|d| !d.is_empty()) else {
+        return Ok(None);
+    };
+    state
+        .device_ids
+        .check(&state.db, device_hash)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.code().to_string()))?;
+    Ok(Some(device_hash.to_string()))
+}
+
+/// Issue a server-minted device id. Clients that still use a hash they made
+/// themselves pass it as `legacy_device_hash`; its chats and user link move
+/// to the new id and the old hash is retired. Migration closes with
+/// `DEVICE_REQUIRE_SIGNED`, and a retry only gets the id minted the first
+/// time back with a token of the user the device is linked to.
+#[utoipa::path(
+    post,
+    path = "/api/devices/register",
+    tag = "auth",
+    request_body(content = Option<RegisterDeviceRequest>, description = "Optional legacy hash to migrate"),
+    responses(
+        (status = 200, description = "New device id", body = RegisterDeviceResponse),
+        (status = 400, description = "invalid_device_hash / device_migration_closed"),
+        (status = 409, description = "device_already_migrated"),
+    )
+)]
+pub async fn register_device_handler(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    body: Option<Json<RegisterDeviceRequest>>,
+) -> Result<Json<RegisterDeviceResponse>, (StatusCode, String)> {
+    let legacy = body
+        .and_then(|Json(req)| req.legacy_device_hash)
+        .map(|d| d.trim().to_string())
+        .filter(|d| !d.is_empty());
+
+    let Some(legacy) = legacy else {
+        return Ok(Json(RegisterDeviceResponse {
+            device_hash: state.device_ids.mint(),
+            migrated_from: None,
+            migrated_chats: 0,
+        }));
+    };
+
+    validate_device_hash_format(&legacy).map_err(|e| (StatusCode::BAD_REQUEST, e.code().into()))?;
+    if DeviceIdSigner::is_signed_format(&legacy) {
+        return Err((StatusCode::BAD_REQUEST, "already_registered".into()));
+    }
+    if state.device_ids.requires_signed() {
+        return Err((StatusCode::BAD_REQUEST, "device_migration_closed".into()));
+    }
+
+    // Retried migration: the id minted the first time goes only to the
+    // user the device is linked to.
+    if let Some(existing) = state
+        .db
+        .load_device_alias(&legacy)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    {
+        let claims = match auth {
+            Some(TypedHeader(auth)) => Some(authenticate(&state, auth.token()).await?),
+            None => None,
+        };
+        let owner = state
+            .db
+            .find_user_id_by_device(&existing)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let is_owner =
+            matches!((&claims, &owner), (Some(claims), Some(owner)) if claims.sub == *owner);
+        if !is_owner {
+            return Err((StatusCode::CONFLICT, "device_already_migrated".into()));
+        }
+        return Ok(Json(RegisterDeviceResponse {
+            device_hash: existing,
+            migrated_from: Some(legacy),
+            migrated_chats: 0,
+        }));
+    }
+
+    let device_hash = state.device_ids.mint();
+    let migrated_chats = state
+        .db
+        .migrate_device_hash(&legacy, &device_hash)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    tracing::info!(
+        target: "audit",
+        legacy_device_hash = %legacy,
+        device_hash = %device_hash,
+        migrated_chats,
+        "legacy device hash migrated"
+    );
+
+    Ok(Json(RegisterDeviceResponse {
+        device_hash,
+        migrated_from: Some(legacy),
+        migrated_chats,
+    }))
+}
+
+/// Move the anonymous chats of a device to the logged-in user. The device is
+/// linked to the user as well, so chats it creates later show up in
+/// `/internal/chats/by-user`. A device already linked to another account is
+/// refused.
+#[utoipa::path(
+    post,
+    path = "/api/auth/claim-device",
+    tag = "auth",
+    request_body = ClaimDeviceRequest,
+    responses(
+        (status = 200, description = "`{ user_id, device_hash, count, chats }` – chats now owned by the user"),
+        (status = 400, description = "missing_device_hash / invalid_device_hash / device_migrated"),
+        (status = 401, description = "invalid_token"),
+        (status = 409, description = "device_owned_by_another_user"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn claim_device_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(req): Json<ClaimDeviceRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let device_hash = checked_device_hash(&state, Some(&req.device_hash))
+        .await?
+        .ok_or((StatusCode::BAD_REQUEST, "missing_device_hash".to_string()))?;
+    let device_hash = device_hash.as_str();
+
+    let owner = state
+        .db
+        .find_user_id_by_device(device_hash)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match owner.as_deref() {
+        Some(owner) if owner != claims.sub => {
+            return Err((StatusCode::CONFLICT, "device_owned_by_another_user".into()));
+        }
+        Some(_) => {}
+        None => state
+            .db
+            .add_device_for_user(&claims.sub, device_hash)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+    }
+
+    let chats = state
+        .db
+        .claim_device_chats(device_hash, &claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    tracing::info!(
+        target: "audit",
+        user_id = %claims.sub,
+        device_hash = %device_hash,
+        chats = chats.len(),
+        "device claimed"
+    );
+
+    Ok(Json(json!({
+        "user_id": claims.sub,
+        "device_hash": device_hash,
+        "count": chats.len(),
+        "chats": chats
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minted_ids_verify_and_tampering_fails() {
+        let signer = DeviceIdSigner::new("secret", true);
+        let id = signer.mint();
+        assert!(validate_device_hash_format(&id).is_ok());
+        assert!(signer.verify_signature(&id));
+
+        let other = DeviceIdSigner::new("other", true);
+        assert!(!other.verify_signature(&id));
+
+        let mut forged = id.clone();
+        forged.replace_range(4..5, if &id[4..5] == "A" { "B" } else { "A" });
+        assert!(!signer.verify_signature(&forged));
+    }
+
+    #[test]
+    fn format_rules() {
+        assert_eq!(
+            validate_device_hash_format(""),
+            Err(DeviceHashError::Missing)
+        );
+        assert_eq!(
+            validate_device_hash_format("abc"),
+            Err(DeviceHashError::BadLength)
+        );
+        assert_eq!(
+            validate_device_hash_format("abcdef12<script>"),
+            Err(DeviceHashError::BadCharacters)
+        );
+        assert!(validate_device_hash_format("3f2a9c0d4b5e6f70").is_ok());
+    }
+}
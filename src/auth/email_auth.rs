@@ -2,18 +2,33 @@ use axum::{extract::State, Json};
 use serde_json::json;
 use uuid::Uuid;
 
+use crate::auth::device::checked_device_hash;
+use crate::auth::jwt::issue_token_pair;
 use crate::auth::types::*;
 use crate::auth::utils::*;
 use crate::{
+    audit::{self, AuditContext, AuditEntry},
     model::user::{User, UserRole},
     ws::AppState,
 };
 
+#[utoipa::path(
+    post,
+    path = "/api/auth/register",
+    tag = "auth",
+    request_body = EmailRegisterRequest,
+    responses(
+        (status = 200, description = "Account created", body = EmailAuthResponse),
+        (status = 400, description = "Email already registered"),
+    )
+)]
 pub async fn email_register_handler(
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(req): Json<EmailRegisterRequest>,
 ) -> Result<Json<EmailAuthResponse>, (axum::http::StatusCode, String)> {
     let email = req.email.trim().to_lowercase();
+    let device_hash = checked_device_hash(&state, req.device_hash.as_deref()).await?;
 
     // Check existing user
     let users = state
@@ -51,6 +66,9 @@ pub async fn email_register_handler(
         role: UserRole::Free,
         stripe_customer_id: None,
         stripe_subscription_id: None,
+        preferences: Default::default(),
+        plan: None,
+        plan_history: Vec::new(),
     };
 
     state
@@ -60,25 +78,72 @@ pub async fn email_register_handler(
         .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     // Add device if needed
-    if let Some(device_hash) = req.device_hash {
-        let _ = state.db.add_device_for_user(&user.id, &device_hash).await;
+    if let Some(device_hash) = &device_hash {
+        let _ = state.db.add_device_for_user(&user.id, device_hash).await;
     }
 
-    // Issue JWT
-    let jwt = create_app_jwt(&state, &user.id);
+    // Issue tokens
+    let tokens = issue_token_pair(
+        &state.db,
+        &state.jwt_secret,
+        &user.id,
+        device_hash.as_deref(),
+    )
+    .await
+    .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::REGISTER, format!("user:{}", user.id), &ctx)
+            .device_hash(device_hash.as_deref())
+            .details(json!({ "provider": "email" })),
+    )
+    .await;
 
     Ok(Json(EmailAuthResponse {
-        jwt,
+        jwt: tokens.jwt,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
         user_id: user.id,
         email,
     }))
 }
 
+/// Record a rejected email login and build the error returned for it.
+async fn login_failed(
+    state: &AppState,
+    ctx: &AuditContext,
+    email: &str,
+    device_hash: Option<&str>,
+    reason: &str,
+    message: &str,
+) -> (axum::http::StatusCode, String) {
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::LOGIN_FAILED, format!("email:{email}"), ctx)
+            .device_hash(device_hash)
+            .details(json!({ "provider": "email", "reason": reason })),
+    )
+    .await;
+    (axum::http::StatusCode::UNAUTHORIZED, message.to_string())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/login",
+    tag = "auth",
+    request_body = EmailLoginRequest,
+    responses(
+        (status = 200, description = "Signed in", body = EmailAuthResponse),
+        (status = 401, description = "Invalid credentials"),
+    )
+)]
 pub async fn email_login_handler(
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(req): Json<EmailLoginRequest>,
 ) -> Result<Json<EmailAuthResponse>, (axum::http::StatusCode, String)> {
     let email = req.email.trim().to_lowercase();
+    let device_hash = checked_device_hash(&state, req.device_hash.as_deref()).await?;
 
     // Load users
     let users = state
@@ -87,50 +152,84 @@ pub async fn email_login_handler(
         .await
         .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
-    let user = users
+    let Some(user) = users
         .into_iter()
         .find(|u| u.email.as_deref() == Some(&email))
-        .ok_or((
-            axum::http::StatusCode::UNAUTHORIZED,
-            "Invalid credentials".into(),
-        ))?;
+    else {
+        let device = device_hash.as_deref();
+        return Err(login_failed(
+            &state,
+            &ctx,
+            &email,
+            device,
+            "unknown_email",
+            "Invalid credentials",
+        )
+        .await);
+    };
 
     // Extract password hash
-    let hash = user
-        .password_hash
-        .clone()
-        .or_else(|| {
-            user.meta
-                .as_ref()
-                .and_then(|m| m.get("password_hash"))
-                .and_then(|v| v.as_str().map(|s| s.to_string()))
-        })
-        .ok_or((
-            axum::http::StatusCode::UNAUTHORIZED,
-            "Account has no password".into(),
-        ))?;
+    let Some(hash) = user.password_hash.clone().or_else(|| {
+        user.meta
+            .as_ref()
+            .and_then(|m| m.get("password_hash"))
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+    }) else {
+        let device = device_hash.as_deref();
+        return Err(login_failed(
+            &state,
+            &ctx,
+            &email,
+            device,
+            "no_password",
+            "Account has no password",
+        )
+        .await);
+    };
 
     // Verify
     let valid = verify_password(&hash, &req.password)
         .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     if !valid {
-        return Err((
-            axum::http::StatusCode::UNAUTHORIZED,
-            "Invalid credentials".into(),
-        ));
+        let device = device_hash.as_deref();
+        return Err(login_failed(
+            &state,
+            &ctx,
+            &email,
+            device,
+            "invalid_password",
+            "Invalid credentials",
+        )
+        .await);
     }
 
     // Device registration
-    if let Some(device_hash) = req.device_hash {
-        let _ = state.db.add_device_for_user(&user.id, &device_hash).await;
+    if let Some(device_hash) = &device_hash {
+        let _ = state.db.add_device_for_user(&user.id, device_hash).await;
     }
 
-    // JWT
-    let jwt = create_app_jwt(&state, &user.id);
+    // Issue tokens
+    let tokens = issue_token_pair(
+        &state.db,
+        &state.jwt_secret,
+        &user.id,
+        device_hash.as_deref(),
+    )
+    .await
+    .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::LOGIN, format!("user:{}", user.id), &ctx)
+            .device_hash(device_hash.as_deref())
+            .details(json!({ "provider": "email" })),
+    )
+    .await;
 
     Ok(Json(EmailAuthResponse {
-        jwt,
+        jwt: tokens.jwt,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
         user_id: user.id,
         email,
     }))
@@ -1,28 +1,31 @@
 use axum::{extract::State, Json};
-use jsonwebtoken::{
-    decode, decode_header, Algorithm, DecodingKey, EncodingKey, Header, Validation,
-};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-use super::google_keys::GoogleJwkCache;
+use super::{device::checked_device_hash, google_keys::GoogleJwkCache, jwt::issue_token_pair};
 use crate::{
+    audit::{self, AuditContext, AuditEntry},
     db::DBLayer,
     model::user::{User, UserRole},
     ws::AppState,
 };
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct GoogleAuthRequest {
     pub id_token: String,
     pub device_hash: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+#[schema(as = GoogleAuthResponse)]
 pub struct AuthResponse {
     pub jwt: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
     pub user_id: String,
     pub email: Option<String>,
 }
@@ -33,14 +36,20 @@ struct GoogleClaims {
     pub email: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AppClaims {
-    pub sub: String,
-    pub exp: usize,
-}
-
+#[utoipa::path(
+    post,
+    path = "/api/auth/google",
+    tag = "auth",
+    request_body = GoogleAuthRequest,
+    responses(
+        (status = 200, description = "Signed in with a Google ID token", body = GoogleAuthResponse),
+        (status = 400, description = "Google login disabled"),
+        (status = 401, description = "ID token rejected"),
+    )
+)]
 pub async fn google_login_handler(
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(payload): Json<GoogleAuthRequest>,
 ) -> Result<Json<AuthResponse>, (axum::http::StatusCode, String)> {
     if state.google_client_id.is_empty() {
@@ -50,6 +59,8 @@ pub async fn google_login_handler(
         ));
     }
 
+    let device_hash = checked_device_hash(&state, Some(&payload.device_hash)).await?;
+
     // --- decode JWT header ---
     let header = decode_header(&payload.id_token).map_err(|e| {
         (
@@ -98,29 +109,31 @@ pub async fn google_login_handler(
         .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     // --- REGISTER DEVICE FOR THIS USER ---
-    if !payload.device_hash.is_empty() {
-        let _ = state
-            .db
-            .add_device_for_user(&user.id, &payload.device_hash)
-            .await;
+    if let Some(device_hash) = &device_hash {
+        let _ = state.db.add_device_for_user(&user.id, device_hash).await;
     }
 
-    // --- Issue our own JWT ---
-    let exp = chrono::Utc::now().timestamp() as usize + 60 * 60 * 24 * 7;
-    let my_claims = AppClaims {
-        sub: user.id.clone(),
-        exp,
-    };
-
-    let jwt = jsonwebtoken::encode(
-        &Header::default(),
-        &my_claims,
-        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    // --- Issue our own tokens ---
+    let tokens = issue_token_pair(
+        &state.db,
+        &state.jwt_secret,
+        &user.id,
+        device_hash.as_deref(),
+    )
+    .await
+    .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::LOGIN, format!("user:{}", user.id), &ctx)
+            .device_hash(device_hash.as_deref())
+            .details(json!({ "provider": "google" })),
     )
-    .unwrap();
+    .await;
 
     Ok(Json(AuthResponse {
-        jwt,
+        jwt: tokens.jwt,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
         user_id: user.id,
         email: claims.email,
     }))
@@ -195,6 +208,9 @@ async fn upsert_google_user(db: &DBLayer, claims: &GoogleClaims) -> anyhow::Resu
         role: UserRole::Free,
         stripe_customer_id: None,
         stripe_subscription_id: None,
+        preferences: Default::default(),
+        plan: None,
+        plan_history: Vec::new(),
     };
 
     db.save_user(&user).await?;
@@ -1,18 +1,274 @@
-use anyhow::Result;
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    db::DBLayer,
+    model::auth_token::{AuthSession, RefreshToken},
+};
+
+const DEFAULT_ACCESS_TOKEN_TTL_SECS: i64 = 15 * 60;
+const DEFAULT_REFRESH_TOKEN_TTL_SECS: i64 = 60 * 60 * 24 * 30;
+const DEFAULT_TOKEN_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+    #[serde(default)]
+    pub iat: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Session (refresh token family) the access token was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+/// Access token plus the refresh token that can renew it.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TokenPair {
+    pub jwt: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+fn ttl_from_env(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
+}
+
+pub fn access_token_ttl() -> i64 {
+    ttl_from_env("ACCESS_TOKEN_TTL_SECS", DEFAULT_ACCESS_TOKEN_TTL_SECS)
+}
+
+pub fn refresh_token_ttl() -> i64 {
+    ttl_from_env("REFRESH_TOKEN_TTL_SECS", DEFAULT_REFRESH_TOKEN_TTL_SECS)
+}
+
+/// Purge expired tokens and sessions now and every
+/// `TOKEN_PURGE_INTERVAL_SECS` (default hourly, 0 turns it off). Returns
+/// whether the task was started.
+pub fn spawn_token_purge(db: Arc<DBLayer>) -> bool {
+    let interval = std::env::var("TOKEN_PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TOKEN_PURGE_INTERVAL_SECS);
+    if interval == 0 {
+        return false;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            // The first tick fires at once, clearing what built up while
+            // the server was down.
+            ticker.tick().await;
+            match db.purge_expired_tokens().await {
+                Ok(0) => {}
+                Ok(purged) => info!(purged, "purged expired auth tokens"),
+                Err(err) => warn!("token purge failed: {err}"),
+            }
+        }
+    });
+    true
+}
+
+/// Refresh tokens are opaque; only their sha256 is persisted.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+pub fn create_access_token(
+    user_id: &str,
+    session_id: Option<&str>,
+    secret: &str,
+) -> Result<String> {
+    let now = chrono::Utc::now().timestamp();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: (now + access_token_ttl()) as usize,
+        iat: now as usize,
+        jti: Some(Uuid::new_v4().to_string()),
+        sid: session_id.map(|s| s.to_string()),
+    };
+    Ok(encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?)
+}
+
+fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+async fn issue_for_session(
+    db: &DBLayer,
+    secret: &str,
+    user_id: &str,
+    session_id: &str,
+    device_hash: Option<String>,
+) -> Result<TokenPair> {
+    let now = chrono::Utc::now().timestamp();
+    let refresh_token = generate_refresh_token();
+    db.save_refresh_token(&RefreshToken {
+        token_hash: hash_token(&refresh_token),
+        user_id: user_id.to_string(),
+        session_id: session_id.to_string(),
+        device_hash,
+        created_ts: now,
+        expires_ts: now + refresh_token_ttl(),
+        rotated_ts: None,
+    })
+    .await?;
+
+    Ok(TokenPair {
+        jwt: create_access_token(user_id, Some(session_id), secret)?,
+        refresh_token,
+        expires_in: access_token_ttl(),
+    })
+}
+
+/// Start a new session for a successful login.
+pub async fn issue_token_pair(
+    db: &DBLayer,
+    secret: &str,
+    user_id: &str,
+    device_hash: Option<&str>,
+) -> Result<TokenPair> {
+    let now = chrono::Utc::now().timestamp();
+    let session = AuthSession {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        device_hash: device_hash.map(|s| s.to_string()),
+        created_ts: now,
+        last_used_ts: now,
+        expires_ts: now + refresh_token_ttl(),
+    };
+    db.save_auth_session(&session).await?;
+
+    issue_for_session(
+        db,
+        secret,
+        user_id,
+        &session.id,
+        session.device_hash.clone(),
+    )
+    .await
+}
+
+/// Exchange a refresh token for a new pair. Each refresh token is single
+/// use: presenting an already rotated token revokes the whole session.
+pub async fn rotate_refresh_token(
+    db: &DBLayer,
+    secret: &str,
+    refresh_token: &str,
+) -> Result<TokenPair> {
+    let token_hash = hash_token(refresh_token);
+    let now = chrono::Utc::now().timestamp();
+    // Claiming marks the token rotated in the same step as reading it, so
+    // two concurrent refreshes cannot both get a new pair.
+    let record = db
+        .claim_refresh_token(&token_hash, now)
+        .await?
+        .ok_or_else(|| anyhow!("unknown refresh token"))?;
+
+    if db.is_session_revoked(&record.session_id).await? {
+        return Err(anyhow!("session revoked"));
+    }
+
+    if record.expires_ts < now {
+        return Err(anyhow!("refresh token expired"));
+    }
 
-#[derive(Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
+    if record.rotated_ts.is_some() {
+        tracing::warn!(
+            user_id = %record.user_id,
+            session_id = %record.session_id,
+            "refresh token reuse detected; revoking session"
+        );
+        revoke_session(db, &record.user_id, &record.session_id).await?;
+        return Err(anyhow!("refresh token reuse"));
+    }
+
+    if let Some(mut session) = db
+        .load_auth_session(&record.user_id, &record.session_id)
+        .await?
+    {
+        session.last_used_ts = now;
+        session.expires_ts = now + refresh_token_ttl();
+        db.save_auth_session(&session).await?;
+    }
+
+    issue_for_session(
+        db,
+        secret,
+        &record.user_id,
+        &record.session_id,
+        record.device_hash.clone(),
+    )
+    .await
 }
 
-pub fn decode_jwt(token: &str, secret: &str) -> Result<String> {
+/// Verify signature and expiry only; revocation is checked by `decode_jwt`.
+pub fn decode_claims(token: &str, secret: &str) -> Result<Claims> {
     let data = decode::<Claims>(
         token,
         &DecodingKey::from_secret(secret.as_bytes()),
         &Validation::default(),
     )?;
-    Ok(data.claims.sub)
+    Ok(data.claims)
+}
+
+/// Verify a token and make sure neither it nor its session was revoked.
+pub async fn verify_jwt(token: &str, secret: &str, db: &DBLayer) -> Result<Claims> {
+    let claims = decode_claims(token, secret)?;
+    if let Some(jti) = claims.jti.as_deref() {
+        if db.is_jti_revoked(jti).await? {
+            return Err(anyhow!("token revoked"));
+        }
+    }
+    if let Some(sid) = claims.sid.as_deref() {
+        if db.is_session_revoked(sid).await? {
+            return Err(anyhow!("session revoked"));
+        }
+    }
+    Ok(claims)
+}
+
+pub async fn decode_jwt(token: &str, secret: &str, db: &DBLayer) -> Result<String> {
+    Ok(verify_jwt(token, secret, db).await?.sub)
+}
+
+/// End a session: its refresh tokens stop rotating and its access tokens
+/// are rejected by `verify_jwt`.
+pub async fn revoke_session(db: &DBLayer, user_id: &str, session_id: &str) -> Result<()> {
+    db.revoke_session(session_id).await?;
+    db.delete_auth_session(user_id, session_id).await
+}
+
+/// Blacklist the presented access token and end its session.
+pub async fn revoke_access_token(db: &DBLayer, claims: &Claims) -> Result<()> {
+    if let Some(jti) = claims.jti.as_deref() {
+        db.revoke_jti(jti, claims.exp as i64).await?;
+    }
+    if let Some(sid) = claims.sid.as_deref() {
+        revoke_session(db, &claims.sub, sid).await?;
+    }
+    Ok(())
 }
@@ -0,0 +1,80 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use tracing::info;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutgoingMail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+/// Delivery backend for transactional mail (password resets, etc.).
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: OutgoingMail) -> Result<()>;
+}
+
+/// Development mailer: writes the message to the log instead of sending it.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: OutgoingMail) -> Result<()> {
+        info!(to = %mail.to, subject = %mail.subject, "mail (log only):\n{}", mail.text);
+        Ok(())
+    }
+}
+
+/// Posts each message as JSON to an HTTP relay (e.g. a transactional mail
+/// provider or an internal notification service).
+pub struct WebhookMailer {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl WebhookMailer {
+    pub fn new(url: String, token: Option<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self { client, url, token })
+    }
+}
+
+#[async_trait]
+impl Mailer for WebhookMailer {
+    async fn send(&self, mail: OutgoingMail) -> Result<()> {
+        let mut req = self.client.post(&self.url).json(&mail);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("mail relay returned {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+/// `MAILER_WEBHOOK_URL` selects the webhook mailer (`MAILER_WEBHOOK_TOKEN`
+/// is sent as a bearer token); otherwise mail is only logged.
+pub fn mailer_from_env() -> Arc<dyn Mailer> {
+    let url = dotenvy::var("MAILER_WEBHOOK_URL")
+        .ok()
+        .filter(|v| !v.trim().is_empty());
+    if let Some(url) = url {
+        let token = dotenvy::var("MAILER_WEBHOOK_TOKEN")
+            .ok()
+            .filter(|v| !v.is_empty());
+        match WebhookMailer::new(url, token) {
+            Ok(mailer) => return Arc::new(mailer),
+            Err(err) => tracing::warn!("webhook mailer unavailable, falling back to log: {err}"),
+        }
+    }
+    Arc::new(LogMailer)
+}
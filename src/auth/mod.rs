@@ -1,12 +1,21 @@
+pub mod account;
 pub mod apple;
+pub mod device;
 pub mod email_auth;
 pub mod google;
 pub mod google_keys;
 pub mod jwt;
+pub mod mailer;
+pub mod password_reset;
+pub mod rate_limit;
+pub mod tokens;
 pub mod types;
 pub mod utils;
 use crate::ws::AppState;
-use axum::{routing::post, Router};
+use axum::{
+    routing::{delete, get, post},
+    Router,
+};
 
 use crate::auth::email_auth::{email_login_handler, email_register_handler};
 
@@ -17,4 +26,25 @@ pub fn router() -> Router<AppState> {
         .route("/api/auth/apple", post(apple::apple_login_handler))
         .route("/api/auth/register", post(email_register_handler))
         .route("/api/auth/login", post(email_login_handler))
+        .route(
+            "/api/auth/password/forgot",
+            post(password_reset::forgot_password_handler),
+        )
+        .route(
+            "/api/auth/password/reset",
+            post(password_reset::reset_password_handler),
+        )
+        .route(
+            "/api/devices/register",
+            post(device::register_device_handler),
+        )
+        .route("/api/auth/claim-device", post(device::claim_device_handler))
+        .route("/api/account", delete(account::delete_account_handler))
+        .route("/api/auth/refresh", post(tokens::refresh_handler))
+        .route("/api/auth/logout", post(tokens::logout_handler))
+        .route("/api/auth/sessions", get(tokens::list_sessions_handler))
+        .route(
+            "/api/auth/sessions/{session_id}",
+            delete(tokens::revoke_session_handler),
+        )
 }
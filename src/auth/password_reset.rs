@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use axum::{extract::State, http::StatusCode, Json};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::auth::{
+    jwt::revoke_session,
+    mailer::OutgoingMail,
+    rate_limit::RateLimiter,
+    types::{ForgotPasswordRequest, ResetPasswordRequest},
+    utils::hash_password,
+};
+use crate::{model::user::User, ws::AppState};
+
+const DEFAULT_RESET_TTL_SECS: i64 = 30 * 60;
+const RESET_PURPOSE: &str = "password_reset";
+
+static FORGOT_LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(3, Duration::from_secs(15 * 60)));
+static RESET_LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(5, Duration::from_secs(15 * 60)));
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResetClaims {
+    sub: String,
+    exp: usize,
+    purpose: String,
+    /// Fingerprint of the password hash at issue time; changing the password
+    /// invalidates every outstanding reset token.
+    pwf: String,
+}
+
+fn reset_ttl() -> i64 {
+    std::env::var("PASSWORD_RESET_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_RESET_TTL_SECS)
+}
+
+// Reset tokens are signed with a derived key so they can never pass as
+// access tokens.
+fn reset_secret(jwt_secret: &str) -> String {
+    format!("{jwt_secret}:{RESET_PURPOSE}")
+}
+
+fn password_fingerprint(user: &User) -> String {
+    let hash = user.password_hash.as_deref().unwrap_or_default();
+    Sha256::digest(hash.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn create_reset_token(user: &User, jwt_secret: &str) -> Result<String> {
+    let claims = ResetClaims {
+        sub: user.id.clone(),
+        exp: (chrono::Utc::now().timestamp() + reset_ttl()) as usize,
+        purpose: RESET_PURPOSE.to_string(),
+        pwf: password_fingerprint(user),
+    };
+    Ok(encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(reset_secret(jwt_secret).as_bytes()),
+    )?)
+}
+
+fn decode_reset_token(token: &str, jwt_secret: &str) -> Result<ResetClaims> {
+    let data = decode::<ResetClaims>(
+        token,
+        &DecodingKey::from_secret(reset_secret(jwt_secret).as_bytes()),
+        &Validation::default(),
+    )?;
+    if data.claims.purpose != RESET_PURPOSE {
+        return Err(anyhow!("wrong token purpose"));
+    }
+    Ok(data.claims)
+}
+
+fn reset_mail(email: &str, token: &str) -> OutgoingMail {
+    let minutes = reset_ttl() / 60;
+    let text = match dotenvy::var("PASSWORD_RESET_URL") {
+        Ok(base) if !base.is_empty() => format!(
+            "Use this link to choose a new password:\n{base}{token}\n\nThe link expires in {minutes} minutes. If you did not request a reset, ignore this email."
+        ),
+        _ => format!(
+            "Your password reset code:\n{token}\n\nIt expires in {minutes} minutes. If you did not request a reset, ignore this email."
+        ),
+    };
+    OutgoingMail {
+        to: email.to_string(),
+        subject: "Reset your password".into(),
+        text,
+    }
+}
+
+/// Always answers the same way so the endpoint cannot be used to probe
+/// which emails are registered.
+#[utoipa::path(
+    post,
+    path = "/api/auth/password/forgot",
+    tag = "auth",
+    request_body = ForgotPasswordRequest,
+    responses(
+        (status = 200, description = "`{ sent: true }` whether or not the email exists"),
+        (status = 429, description = "too_many_requests"),
+    )
+)]
+pub async fn forgot_password_handler(
+    State(state): State<AppState>,
+    Json(req): Json<ForgotPasswordRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let email = req.email.trim().to_lowercase();
+    let accepted = Json(json!({ "sent": true }));
+
+    if !FORGOT_LIMITER.check(&email) {
+        warn!(target: "audit", event = "password_reset_rate_limited", email = %email);
+        return Err((StatusCode::TOO_MANY_REQUESTS, "too_many_requests".into()));
+    }
+
+    let users = state
+        .db
+        .list_users()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let Some(user) = users
+        .into_iter()
+        .find(|u| u.email.as_deref() == Some(email.as_str()) && u.password_hash.is_some())
+    else {
+        info!(target: "audit", event = "password_reset_unknown_email", email = %email);
+        return Ok(accepted);
+    };
+
+    let token = create_reset_token(&user, &state.jwt_secret)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if let Err(err) = state.mailer.send(reset_mail(&email, &token)).await {
+        warn!(target: "audit", event = "password_reset_mail_failed", user_id = %user.id, "{err}");
+        return Err((StatusCode::BAD_GATEWAY, "mail_delivery_failed".into()));
+    }
+
+    info!(target: "audit", event = "password_reset_requested", user_id = %user.id);
+    Ok(accepted)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/password/reset",
+    tag = "auth",
+    request_body = ResetPasswordRequest,
+    responses(
+        (status = 200, description = "`{ reset: true }`; all sessions are revoked"),
+        (status = 400, description = "invalid_reset_token"),
+        (status = 429, description = "too_many_requests"),
+    )
+)]
+pub async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(req): Json<ResetPasswordRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    if req.password.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "password_required".into()));
+    }
+
+    let claims = decode_reset_token(&req.token, &state.jwt_secret).map_err(|_| {
+        warn!(target: "audit", event = "password_reset_invalid_token");
+        (StatusCode::BAD_REQUEST, "invalid_reset_token".to_string())
+    })?;
+
+    if !RESET_LIMITER.check(&claims.sub) {
+        warn!(target: "audit", event = "password_reset_rate_limited", user_id = %claims.sub);
+        return Err((StatusCode::TOO_MANY_REQUESTS, "too_many_requests".into()));
+    }
+
+    let mut user = state
+        .db
+        .load_user(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::BAD_REQUEST, "invalid_reset_token".to_string()))?;
+
+    if password_fingerprint(&user) != claims.pwf {
+        warn!(target: "audit", event = "password_reset_token_reused", user_id = %user.id);
+        return Err((StatusCode::BAD_REQUEST, "invalid_reset_token".into()));
+    }
+
+    user.password_hash = Some(
+        hash_password(&req.password)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+    );
+    state
+        .db
+        .save_user(&user)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // A reset usually means the old password leaked; sign out everywhere.
+    let sessions = state
+        .db
+        .list_auth_sessions(&user.id)
+        .await
+        .unwrap_or_default();
+    for session in &sessions {
+        if let Err(err) = revoke_session(&state.db, &user.id, &session.id).await {
+            warn!(user_id = %user.id, session_id = %session.id, "failed to revoke session: {err}");
+        }
+    }
+
+    info!(
+        target: "audit",
+        event = "password_reset_completed",
+        user_id = %user.id,
+        sessions_revoked = sessions.len()
+    );
+    Ok(Json(json!({ "reset": true })))
+}
@@ -0,0 +1,171 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use headers::{authorization::Bearer, Authorization};
+use serde_json::{json, Value};
+
+use crate::auth::jwt::{
+    decode_claims, hash_token, revoke_access_token, revoke_session, rotate_refresh_token,
+    verify_jwt, Claims, TokenPair,
+};
+use crate::auth::types::*;
+use crate::ws::AppState;
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/refresh",
+    tag = "auth",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Rotated token pair", body = TokenPair),
+        (status = 401, description = "invalid_refresh_token"),
+    )
+)]
+pub async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Json<TokenPair>, (StatusCode, String)> {
+    let pair = rotate_refresh_token(&state.db, &state.jwt_secret, &req.refresh_token)
+        .await
+        .map_err(|e| {
+            tracing::debug!(error = %e, "refresh rejected");
+            (StatusCode::UNAUTHORIZED, "invalid_refresh_token".into())
+        })?;
+    Ok(Json(pair))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/logout",
+    tag = "auth",
+    request_body(content = Option<LogoutRequest>, description = "Optional refresh token to revoke"),
+    responses(
+        (status = 200, description = "`{ logged_out: true }`"),
+        (status = 401, description = "invalid_token"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn logout_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    body: Option<Json<LogoutRequest>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    // Expired tokens carry nothing worth revoking, so only a valid one is accepted.
+    let claims = decode_claims(auth.token(), &state.jwt_secret)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "invalid_token".into()))?;
+
+    revoke_access_token(&state.db, &claims)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Tokens minted before sessions existed have no `sid`; fall back to the
+    // refresh token the client hands back, if any.
+    if let Some(Json(LogoutRequest {
+        refresh_token: Some(refresh_token),
+    })) = body
+    {
+        if let Ok(Some(record)) = state
+            .db
+            .load_refresh_token(&hash_token(&refresh_token))
+            .await
+        {
+            if record.user_id == claims.sub {
+                revoke_session(&state.db, &record.user_id, &record.session_id)
+                    .await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            }
+        }
+    }
+
+    Ok(Json(json!({ "logged_out": true })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/auth/sessions",
+    tag = "auth",
+    responses(
+        (status = 200, description = "`{ count, sessions: [{ id, device_hash, created_ts, last_used_ts, expires_ts, current }] }`"),
+        (status = 401, description = "invalid_token"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_sessions_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let mut sessions = state
+        .db
+        .list_auth_sessions(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.last_used_ts));
+
+    let rows: Vec<Value> = sessions
+        .into_iter()
+        .map(|s| {
+            json!({
+                "id": s.id,
+                "device_hash": s.device_hash,
+                "created_ts": s.created_ts,
+                "last_used_ts": s.last_used_ts,
+                "expires_ts": s.expires_ts,
+                "current": claims.sid.as_deref() == Some(s.id.as_str()),
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "count": rows.len(),
+        "sessions": rows
+    })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/auth/sessions/{session_id}",
+    tag = "auth",
+    params(("session_id" = String, Path, description = "Session id from `GET /api/auth/sessions`")),
+    responses(
+        (status = 200, description = "`{ session_id, revoked, current }`"),
+        (status = 404, description = "session_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn revoke_session_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    state
+        .db
+        .load_auth_session(&claims.sub, &session_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "session_not_found".to_string()))?;
+
+    revoke_session(&state.db, &claims.sub, &session_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(json!({
+        "session_id": session_id,
+        "revoked": true,
+        "current": claims.sid.as_deref() == Some(session_id.as_str()),
+    })))
+}
+
+/// Resolve a Bearer token to its claims, rejecting revoked tokens.
+pub(crate) async fn authenticate(
+    state: &AppState,
+    token: &str,
+) -> Result<Claims, (StatusCode, String)> {
+    verify_jwt(token, &state.jwt_secret, &state.db)
+        .await
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "invalid_token".into()))
+}
@@ -1,22 +1,72 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct EmailRegisterRequest {
     pub email: String,
     pub password: String,
     pub device_hash: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct EmailLoginRequest {
     pub email: String,
     pub password: String,
     pub device_hash: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct EmailAuthResponse {
     pub jwt: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
     pub user_id: String,
     pub email: String,
 }
+
+#[derive(Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LogoutRequest {
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ClaimDeviceRequest {
+    pub device_hash: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterDeviceRequest {
+    /// Client-made hash used by older builds; migrated to the new id.
+    #[serde(default)]
+    pub legacy_device_hash: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RegisterDeviceResponse {
+    pub device_hash: String,
+    pub migrated_from: Option<String>,
+    pub migrated_chats: usize,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeleteAccountRequest {
+    #[serde(default)]
+    pub password: Option<String>,
+}
@@ -2,11 +2,6 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use jsonwebtoken::{EncodingKey, Header};
-use serde::Serialize;
-
-use crate::ws::AppState;
-
 pub fn hash_password(password: &str) -> anyhow::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
     let hash = Argon2::default()
@@ -21,23 +16,3 @@ pub fn verify_password(hash: &str, password: &str) -> anyhow::Result<bool> {
         .verify_password(password.as_bytes(), &parsed)
         .is_ok())
 }
-
-pub fn create_app_jwt(state: &AppState, user_id: &str) -> String {
-    let exp = chrono::Utc::now().timestamp() as usize + 60 * 60 * 24 * 7;
-    let claims = AppClaims {
-        sub: user_id.to_string(),
-        exp,
-    };
-    jsonwebtoken::encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
-    )
-    .unwrap()
-}
-
-#[derive(Serialize)]
-struct AppClaims {
-    sub: String,
-    exp: usize,
-}
@@ -1,5 +1,8 @@
-use anyhow::Result;
-use ktulhuMain::{agent, manager::ModelManager};
+use anyhow::{Context, Result};
+use ktulhuMain::{
+    agent,
+    manager::{ModelManager, PRIMARY_GENERATOR},
+};
 use std::env;
 
 #[tokio::main]
@@ -17,5 +20,8 @@ async fn main() -> Result<()> {
     println!("🎯 Agent goal: {goal}");
 
     let models = ModelManager::new().await?;
-    agent::run_agent(models.mistral_llama.as_ref(), &goal).await
+    let llama = models
+        .generator(PRIMARY_GENERATOR)
+        .context("no llama.cpp model loaded")?;
+    agent::run_agent(&*llama, &goal).await
 }
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use ktulhuMain::{
+    inference::{remote, InferenceService},
+    manager::{ModelManager, FALLBACK_GENERATOR, PRIMARY_GENERATOR},
+    server::{self, ServerConfig},
+};
+use tracing_subscriber::EnvFilter;
+
+/// Standalone inference service: loads the llama.cpp models and serves
+/// `/inference/v1` to web tiers started with `INFERENCE_URL`.
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenvy::dotenv().ok();
+    dotenvy::from_filename("config/llamacpp.env").ok();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    // An open service generates for anyone who reaches the port, with no
+    // moderation or quota; refuse to start that way unless asked to.
+    if remote::service_token().is_none() {
+        let allow_open = std::env::var("INFERENCE_ALLOW_OPEN").is_ok_and(|v| v.trim() == "1");
+        if !allow_open {
+            bail!("INFERENCE_TOKEN is not set; set it, or INFERENCE_ALLOW_OPEN=1 to serve without one");
+        }
+        println!("⚠️  INFERENCE_ALLOW_OPEN=1 — anyone who can reach this port can generate");
+    }
+
+    let models = Arc::new(ModelManager::new().await?);
+    tokio::spawn(models.clone().warm_up());
+    if models.spawn_gpu_watchdog() {
+        println!("🧯 GPU memory watchdog running (GPU_WATCHDOG_INTERVAL_SECS)");
+    }
+
+    let primary = models
+        .generator(PRIMARY_GENERATOR)
+        .context("no llama.cpp model loaded")?;
+    let infer = Arc::new(
+        InferenceService::new(primary).with_fallback(models.generator(FALLBACK_GENERATOR)),
+    );
+
+    let config = ServerConfig::from_env()?;
+    println!(
+        "🧠 Inference service on {}://{}/inference/v1",
+        config.scheme().0,
+        config.addr
+    );
+    server::serve(remote::service_router(infer, models), config).await
+}
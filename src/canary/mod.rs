@@ -0,0 +1,343 @@
+//! Periodic self-test of the loaded models. A fixed set of prompts goes
+//! through the generator, the summarizer prompt and the intent router; the
+//! outputs are checked against loose expectations and regressions are
+//! published as `canary_regression` events.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    conversation::build_mistral_prompt,
+    db::DBLayer,
+    events::{CanaryRegression, Event, EventBus},
+    inference::InferenceService,
+    manager::ModelManager,
+    model::message::Message,
+    prompts,
+    ws::inference_worker::build_summary_prompt,
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const CASE_TIMEOUT: Duration = Duration::from_secs(90);
+const CHATML_MARKERS: &[&str] = &["<|im_start|>", "<|im_end|>", "[INST]", "[/INST]", "</s>"];
+const PREVIEW_CHARS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanaryTarget {
+    Generator,
+    Summarizer,
+    IntentRouter,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Check {
+    NonEmpty,
+    NoChatMl,
+    Contains(&'static str),
+    MaxChars(usize),
+    /// Routing result survives a JSON round trip and names a prompt key.
+    RoutingJson,
+}
+
+struct CanaryCase {
+    name: &'static str,
+    target: CanaryTarget,
+    input: &'static str,
+    checks: &'static [Check],
+}
+
+const CASES: &[CanaryCase] = &[
+    CanaryCase {
+        name: "greeting",
+        target: CanaryTarget::Generator,
+        input: "Say hello in one short sentence.",
+        checks: &[Check::NonEmpty, Check::NoChatMl],
+    },
+    CanaryCase {
+        name: "arithmetic",
+        target: CanaryTarget::Generator,
+        input: "What is 2 + 2? Answer with the number only.",
+        checks: &[Check::NonEmpty, Check::NoChatMl, Check::Contains("4")],
+    },
+    CanaryCase {
+        name: "chat_summary",
+        target: CanaryTarget::Summarizer,
+        input: "Can you help me plan a three day trip to Lisbon in spring?",
+        checks: &[Check::NonEmpty, Check::NoChatMl, Check::MaxChars(60)],
+    },
+    CanaryCase {
+        name: "routing",
+        target: CanaryTarget::IntentRouter,
+        input: "What is the capital of France?",
+        checks: &[Check::RoutingJson],
+    },
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryCaseResult {
+    pub name: String,
+    pub target: CanaryTarget,
+    pub passed: bool,
+    pub failures: Vec<String>,
+    pub latency_ms: u128,
+    pub output_preview: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryReport {
+    pub ts: i64,
+    /// Hash of the model path and prompt templates the run used.
+    pub fingerprint: String,
+    pub passed: bool,
+    pub results: Vec<CanaryCaseResult>,
+    /// Cases that passed on the previous run and fail now.
+    #[serde(default)]
+    pub regressions: Vec<String>,
+}
+
+/// Identifies the model/prompt configuration so a failing run can be tied
+/// to what changed.
+pub fn config_fingerprint() -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(std::env::var("LLAMA_CLI_MODEL").unwrap_or_default());
+    for lang in prompts::known_languages() {
+        let set = prompts::prompt_templates(&lang);
+        hasher.update(&lang);
+        hasher.update(&set.default_prompt);
+        let mut keys: Vec<_> = set.prompts.iter().collect();
+        keys.sort();
+        for (key, template) in keys {
+            hasher.update(key);
+            hasher.update(template);
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn user_turn(text: &str) -> Message {
+    Message {
+        id: "canary".into(),
+        chat_id: "canary".into(),
+        session_id: None,
+        user_id: None,
+        device_hash: None,
+        role: "user".into(),
+        text: Some(text.to_string()),
+        language: Some("en".into()),
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: chrono::Utc::now().timestamp(),
+        meta: None,
+    }
+}
+
+async fn run_case(
+    case: &CanaryCase,
+    models: &Arc<ModelManager>,
+    infer: &Arc<InferenceService>,
+) -> CanaryCaseResult {
+    let started = Instant::now();
+    let output: anyhow::Result<String> = match case.target {
+        CanaryTarget::Generator | CanaryTarget::Summarizer => {
+            let history = vec![user_turn(case.input)];
+            let prompt = if case.target == CanaryTarget::Summarizer {
+                build_summary_prompt(&history, Some("en"))
+            } else {
+                build_mistral_prompt(&history, Some(&prompts::prompt_for_intent("", Some("en"))))
+            };
+            let cancel = Arc::new(AtomicBool::new(false));
+            let result = tokio::time::timeout(
+                CASE_TIMEOUT,
+                infer.generate_completion(prompt, cancel.clone()),
+            )
+            .await;
+            // Stop the backend if the case timed out mid-generation.
+            cancel.store(true, Ordering::SeqCst);
+            match result {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("timed out after {:?}", CASE_TIMEOUT)),
+            }
+        }
+        CanaryTarget::IntentRouter => {
+            let models = models.clone();
+            let input = case.input;
+            let handle = tokio::task::spawn_blocking(move || {
+                crate::classifier::routing::route_intent(&models, input, Some("en"))
+                    .and_then(|result| Ok(serde_json::to_string(&result)?))
+            });
+            match tokio::time::timeout(CASE_TIMEOUT, handle).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(join_err)) => Err(anyhow::anyhow!("classifier panicked: {join_err}")),
+                Err(_) => Err(anyhow::anyhow!("timed out after {:?}", CASE_TIMEOUT)),
+            }
+        }
+    };
+    let latency_ms = started.elapsed().as_millis();
+
+    let mut failures = Vec::new();
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            failures.push(format!("error: {err}"));
+            String::new()
+        }
+    };
+    if failures.is_empty() {
+        for check in case.checks {
+            if let Some(failure) = evaluate(*check, &output) {
+                failures.push(failure);
+            }
+        }
+    }
+
+    CanaryCaseResult {
+        name: case.name.to_string(),
+        target: case.target,
+        passed: failures.is_empty(),
+        failures,
+        latency_ms,
+        output_preview: output.chars().take(PREVIEW_CHARS).collect(),
+    }
+}
+
+fn evaluate(check: Check, output: &str) -> Option<String> {
+    let trimmed = output.trim();
+    match check {
+        Check::NonEmpty => trimmed.is_empty().then(|| "empty output".to_string()),
+        Check::NoChatMl => CHATML_MARKERS
+            .iter()
+            .find(|marker| output.contains(*marker))
+            .map(|marker| format!("chat template marker leaked: {marker}")),
+        Check::Contains(needle) => {
+            (!trimmed.contains(needle)).then(|| format!("expected `{needle}` in output"))
+        }
+        Check::MaxChars(max) => {
+            let len = trimmed.chars().count();
+            (len > max).then(|| format!("output has {len} chars, limit {max}"))
+        }
+        Check::RoutingJson => match serde_json::from_str::<serde_json::Value>(output) {
+            Ok(value) => {
+                let key = value
+                    .get("prompt_key")
+                    .and_then(|k| k.as_str())
+                    .unwrap_or("");
+                key.is_empty()
+                    .then(|| "routing result has no prompt_key".to_string())
+            }
+            Err(err) => Some(format!("routing result is not valid JSON: {err}")),
+        },
+    }
+}
+
+/// Run every case once, store the report and publish an event for
+/// regressions (cases that passed last time, or any failure on the first
+/// run of a new fingerprint).
+pub async fn run_canary(
+    models: &Arc<ModelManager>,
+    infer: &Arc<InferenceService>,
+    db: &DBLayer,
+    events: &EventBus,
+) -> anyhow::Result<CanaryReport> {
+    let previous = db.load_canary_report().await.ok().flatten();
+
+    let mut results = Vec::with_capacity(CASES.len());
+    for case in CASES {
+        results.push(run_case(case, models, infer).await);
+    }
+
+    let fingerprint = config_fingerprint();
+    let config_changed = previous
+        .as_ref()
+        .map_or(true, |prev| prev.fingerprint != fingerprint);
+    let regressions: Vec<String> = results
+        .iter()
+        .filter(|r| !r.passed)
+        .filter(|r| {
+            config_changed
+                || previous
+                    .as_ref()
+                    .is_some_and(|prev| prev.results.iter().any(|p| p.name == r.name && p.passed))
+        })
+        .map(|r| r.name.clone())
+        .collect();
+
+    let report = CanaryReport {
+        ts: chrono::Utc::now().timestamp(),
+        fingerprint,
+        passed: results.iter().all(|r| r.passed),
+        results,
+        regressions,
+    };
+    db.save_canary_report(&report).await?;
+
+    if report.regressions.is_empty() {
+        info!(
+            passed = report.passed,
+            fingerprint = report.fingerprint.as_str(),
+            "canary run finished"
+        );
+    } else {
+        warn!(
+            regressions = ?report.regressions,
+            fingerprint = report.fingerprint.as_str(),
+            "canary regression"
+        );
+        events.publish(Event::CanaryRegression(CanaryRegression {
+            fingerprint: report.fingerprint.clone(),
+            previous_fingerprint: previous.map(|p| p.fingerprint),
+            failures: report
+                .results
+                .iter()
+                .filter(|r| report.regressions.contains(&r.name))
+                .map(|r| format!("{}: {}", r.name, r.failures.join("; ")))
+                .collect(),
+            ts: report.ts,
+        }));
+    }
+
+    Ok(report)
+}
+
+/// `CANARY_INTERVAL_SECS` (default 6h, `0` disables). The first run starts
+/// right after boot so model or prompt changes are caught on deploy.
+pub fn spawn_canary(
+    models: Arc<ModelManager>,
+    infer: Arc<InferenceService>,
+    db: Arc<DBLayer>,
+    events: EventBus,
+) -> bool {
+    let interval = std::env::var("CANARY_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_INTERVAL_SECS);
+    if interval == 0 {
+        return false;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            if let Err(err) = run_canary(&models, &infer, &db, &events).await {
+                warn!("canary run failed: {err}");
+            }
+        }
+    });
+    true
+}
@@ -0,0 +1,81 @@
+//! Language identification for user turns (whatlang trigram model). Used
+//! for prompt localization; callers fall back to the client's UI language
+//! when detection is uncertain.
+
+use whatlang::Lang;
+
+const DEFAULT_MIN_CONFIDENCE: f64 = 0.5;
+/// Below this many letters trigram statistics are noise.
+const MIN_LETTERS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LanguageGuess {
+    /// ISO 639-1 code.
+    pub language: &'static str,
+    pub confidence: f64,
+}
+
+/// Minimum whatlang confidence for a guess to be used
+/// (`LANGUAGE_DETECT_MIN_CONFIDENCE`, 0..=1).
+pub fn min_confidence() -> f64 {
+    std::env::var("LANGUAGE_DETECT_MIN_CONFIDENCE")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| (0.0..=1.0).contains(v))
+        .unwrap_or(DEFAULT_MIN_CONFIDENCE)
+}
+
+/// Language of `text`, or `None` when the text is too short, the language
+/// has no ISO 639-1 mapping here, or confidence is below `min_confidence`.
+pub fn identify(text: &str) -> Option<LanguageGuess> {
+    if text.chars().filter(|c| c.is_alphabetic()).count() < MIN_LETTERS {
+        return None;
+    }
+    let info = whatlang::detect(text)?;
+    let language = iso639_1(info.lang())?;
+    (info.confidence() >= min_confidence()).then_some(LanguageGuess {
+        language,
+        confidence: info.confidence(),
+    })
+}
+
+fn iso639_1(lang: Lang) -> Option<&'static str> {
+    Some(match lang {
+        Lang::Eng => "en",
+        Lang::Spa => "es",
+        Lang::Por => "pt",
+        Lang::Rus => "ru",
+        Lang::Ukr => "uk",
+        Lang::Bel => "be",
+        Lang::Bul => "bg",
+        Lang::Deu => "de",
+        Lang::Fra => "fr",
+        Lang::Ita => "it",
+        Lang::Nld => "nl",
+        Lang::Pol => "pl",
+        Lang::Ces => "cs",
+        Lang::Tur => "tr",
+        Lang::Cmn => "zh",
+        Lang::Jpn => "ja",
+        Lang::Kor => "ko",
+        Lang::Ara => "ar",
+        Lang::Heb => "he",
+        Lang::Hin => "hi",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifies_long_text_and_skips_short_text() {
+        let guess = identify(
+            "Привет! Подскажи, пожалуйста, как настроить резервное копирование базы данных?",
+        )
+        .expect("russian detected");
+        assert_eq!(guess.language, "ru");
+        assert_eq!(identify("ok thanks"), None);
+    }
+}
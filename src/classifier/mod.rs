@@ -1 +1,3 @@
+pub mod language;
+pub mod recency;
 pub mod routing;
@@ -0,0 +1,118 @@
+//! Questions whose answer depends on recent events (news, prices, scores,
+//! "latest" releases). The model's knowledge stops at its training cutoff,
+//! so these are answered from a web search when one is configured.
+//!
+//! Some words only mean "recent" next to a topic that changes: "score" or
+//! "последний" alone also fit a quiz or a paragraph, so `WEAK` phrases
+//! count only together with a `TOPIC` word. `\b` in these patterns is
+//! Unicode-aware, so it bounds Cyrillic words too.
+
+use chrono::Datelike;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+fn build(patterns: &[&str]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(&format!("(?i){pattern}")).expect("recency pattern"))
+        .collect()
+}
+
+/// Phrases that ask about recent events on their own.
+static STRONG: Lazy<Vec<Regex>> = Lazy::new(|| {
+    build(&[
+        // en
+        r"\b(today|tonight|yesterday|this (week|month|year)|right now|at the moment)\b",
+        r"\b(latest|newest|breaking|upcoming)\b",
+        r"\b(news|headlines?|weather|forecast|elections?|stock price|exchange rate)\b",
+        r"\bwho (won|is winning|leads?)\b",
+        // es
+        r"\b(hoy|ayer|esta semana|este (mes|año)|ahora mismo)\b",
+        r"\b(noticias|clima|pronóstico del tiempo|elecciones)\b",
+        // pt
+        r"\b(hoje|ontem|esta semana|este (mês|ano)|agora mesmo)\b",
+        r"\b(notícias|previsão do tempo|eleições|cotação)\b",
+        // ru
+        r"\b(сегодня|вчера|на этой неделе|в этом (месяце|году)|на данный момент)\b",
+        r"\b(новост\w*|погод\w*|выбор(ы|ов|ах)|курс (доллара|евро|рубля|валют\w*))\b",
+    ])
+});
+
+/// Words that hint at recency but also have everyday meanings.
+static WEAK: Lazy<Vec<Regex>> = Lazy::new(|| {
+    build(&[
+        r"\b(recent|recently|currently|scores?|current)\b",
+        r"\b(último|última|últimos|últimas|reciente|recientes|actualmente)\b",
+        r"\b(mais recente|últimos|atualmente)\b",
+        r"\b(сейчас|последн\w*|свеж\w*|курс\w*|прогноз\w*)\b",
+    ])
+});
+
+/// Topics whose state changes, which make a `WEAK` word about the news.
+static TOPIC: Lazy<Vec<Regex>> = Lazy::new(|| {
+    build(&[
+        r"\b(games?|match(es)?|season|league|tournament|prices?|markets?|stocks?|shares|bitcoin|crypto|dollar|euro|release[sd]?|version|update|events?|happening|president|war|earthquake|launch(ed)?)\b",
+        r"\b(partido|temporada|liga|precios?|mercado|acciones|versión|eventos?|pasando|presidente|guerra)\b",
+        r"\b(jogo|partida|temporada|preços?|mercado|ações|versão|eventos?|acontecendo|presidente|guerra)\b",
+        r"\b(матч\w*|игр[аеыу]|сезон\w*|турнир\w*|цен[аыу]|рын\w*|акци[йия]\w*|биткоин\w*|доллар\w*|евро|рубл\w*|валют\w*|верси[яию]|обновлени\w*|событи\w*|происходит|президент\w*|войн\w*)\b",
+    ])
+});
+
+static YEAR: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(20\d\d)\b").expect("year pattern"));
+
+fn any_match(patterns: &[Regex], text: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.is_match(text))
+}
+
+/// True when the text asks about recent events: a recency phrase, a
+/// hinting word next to a changing topic, or a year from last year on.
+pub fn is_recency_sensitive(text: &str) -> bool {
+    if any_match(&STRONG, text) || (any_match(&WEAK, text) && any_match(&TOPIC, text)) {
+        return true;
+    }
+    let this_year = chrono::Utc::now().year();
+    YEAR.captures_iter(text)
+        .filter_map(|caps| caps[1].parse::<i32>().ok())
+        .any(|year| year >= this_year - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_recency_phrases_and_recent_years() {
+        assert!(is_recency_sensitive("What's the latest iPhone?"));
+        assert!(is_recency_sensitive("¿Quién ganó las elecciones ayer?"));
+        assert!(is_recency_sensitive("Какая погода в Москве?"));
+        let year = chrono::Utc::now().year();
+        assert!(is_recency_sensitive(&format!("Best laptops of {year}")));
+        assert!(!is_recency_sensitive("How do I reverse a linked list?"));
+        assert!(!is_recency_sensitive("What happened in 1969?"));
+    }
+
+    #[test]
+    fn hinting_words_need_a_changing_topic() {
+        assert!(is_recency_sensitive(
+            "What was the score of the Lakers game?"
+        ));
+        assert!(is_recency_sensitive("Какой сейчас курс доллара?"));
+        assert!(is_recency_sensitive("Что сейчас происходит на рынке?"));
+        assert!(is_recency_sensitive("Кто победил на выборах?"));
+        assert!(is_recency_sensitive("Последние новости"));
+
+        assert!(!is_recency_sensitive("Перепиши последний абзац"));
+        assert!(!is_recency_sensitive("Помоги с выбором цвета для кухни"));
+        assert!(!is_recency_sensitive("Сделай выбор между двумя вариантами"));
+        assert!(!is_recency_sensitive("Я сейчас учу Python, объясни циклы"));
+        assert!(!is_recency_sensitive("Какой курс лучше для новичка?"));
+        assert!(!is_recency_sensitive(
+            "How do I compute the z-score of a sample?"
+        ));
+        assert!(!is_recency_sensitive("I am currently learning Rust"));
+        assert!(!is_recency_sensitive("Summarize the most recent paragraph"));
+        assert!(!is_recency_sensitive(
+            "Explain the underscore syntax in Rust"
+        ));
+    }
+}
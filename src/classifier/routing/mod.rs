@@ -4,7 +4,9 @@ use tracing::{debug, info};
 
 use crate::{manager::ModelManager, prompts};
 
-const SUPPORT_INTENT_THRESHOLD: f32 = 0.3;
+pub mod rules;
+use rules::{MultiIntentMode, RoutingConfig, RoutingSignals};
+
 const PHATIC_LABELS: &[&str] = &["SMALL_TALK", "CONTENTFUL"];
 const SPEECH_ACT_LABELS: &[&str] = &["SOCIAL", "ASKING", "DIRECTING", "EXPRESSING", "SHARING"];
 const DOMAIN_LABELS: &[&str] = &[
@@ -18,6 +20,9 @@ const DOMAIN_LABELS: &[&str] = &[
 ];
 const EXPECTATION_LABELS: &[&str] = &["NONE", "INFO", "ADVICE", "ACTION", "OTHER"];
 const SUPPORT_LABELS: &[&str] = &["NO_SUPPORT", "SUPPORT"];
+/// Labels of the optional safety head; `crate::moderation` decides what to
+/// do with them.
+pub const SAFETY_LABELS: &[&str] = &["SAFE", "SELF_HARM", "ILLEGAL_ACTIVITY", "SEXUAL_MINORS"];
 
 #[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
 pub enum IntentKind {
@@ -47,6 +52,46 @@ pub enum ReasoningProfile {
     RiddleMetaphor,
 }
 
+impl ReasoningProfile {
+    pub const ALL: [ReasoningProfile; 10] = [
+        ReasoningProfile::General,
+        ReasoningProfile::ReflectiveAnalysis,
+        ReasoningProfile::RegulatedTaxLegal,
+        ReasoningProfile::FormalLogic,
+        ReasoningProfile::ConstraintPuzzle,
+        ReasoningProfile::MathWordProblem,
+        ReasoningProfile::AlgorithmicCode,
+        ReasoningProfile::Planning,
+        ReasoningProfile::ArgumentCritique,
+        ReasoningProfile::RiddleMetaphor,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReasoningProfile::General => "general",
+            ReasoningProfile::ReflectiveAnalysis => "reflective_analysis",
+            ReasoningProfile::RegulatedTaxLegal => "regulated_tax_legal",
+            ReasoningProfile::FormalLogic => "formal_logic",
+            ReasoningProfile::ConstraintPuzzle => "constraint_puzzle",
+            ReasoningProfile::MathWordProblem => "math_word_problem",
+            ReasoningProfile::AlgorithmicCode => "algorithmic_code",
+            ReasoningProfile::Planning => "planning",
+            ReasoningProfile::ArgumentCritique => "argument_critique",
+            ReasoningProfile::RiddleMetaphor => "riddle_metaphor",
+        }
+    }
+
+    /// Accepts the snake_case name (`regulated_tax_legal`) or the variant
+    /// name as serialized in `classifier_debug` (`RegulatedTaxLegal`).
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.trim();
+        Self::ALL.into_iter().find(|profile| {
+            profile.as_str().eq_ignore_ascii_case(name)
+                || format!("{profile:?}").eq_ignore_ascii_case(name)
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct HeadPrediction {
     pub label: String,
@@ -90,6 +135,32 @@ pub struct IntentRoutingResult {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub support: Option<HeadPrediction>,
     pub support_intent: bool,
+    /// Safety head prediction; for multi-intent messages the least safe
+    /// segment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety: Option<HeadPrediction>,
+    /// Per-utterance routing when a multi-intent message was classified
+    /// segment by segment; the top-level fields follow the segment listed
+    /// in `notes` as routing the message.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<RoutingSegment>,
+    /// Question about recent events, answered with web search results when
+    /// a search provider is configured.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub recency_sensitive: bool,
+}
+
+/// Routing of one significant utterance of a multi-intent message.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoutingSegment {
+    pub text: String,
+    pub speech_act: String,
+    pub domain: String,
+    pub expectation: String,
+    pub support_intent: bool,
+    pub intent_kind: IntentKind,
+    pub routing_path: RoutingPath,
+    pub prompt_key: String,
 }
 
 impl Default for IntentRoutingResult {
@@ -107,6 +178,9 @@ impl Default for IntentRoutingResult {
             notes: vec!["default routing result".into()],
             support: None,
             support_intent: false,
+            safety: None,
+            segments: Vec::new(),
+            recency_sensitive: false,
         }
     }
 }
@@ -117,9 +191,19 @@ pub fn route_intent(
     language_hint: Option<&str>,
 ) -> Result<IntentRoutingResult> {
     let trimmed = text.trim();
+    let config = rules::current();
     let mut result = IntentRoutingResult::default();
-    result.language = normalize_language(language_hint);
     result.notes.clear();
+    match super::language::identify(trimmed) {
+        Some(guess) => {
+            result.language = guess.language.to_string();
+            result.notes.push(format!(
+                "language={} (detected {:.2})",
+                guess.language, guess.confidence
+            ));
+        }
+        None => result.language = normalize_language(language_hint),
+    }
 
     if trimmed.is_empty() {
         result.routing_path = RoutingPath::EmptyInput;
@@ -131,17 +215,129 @@ pub fn route_intent(
     }
 
     let utterances = split_into_utterances(trimmed);
-    if has_multi_intent(&utterances) {
+    let min_chars = config.thresholds.multi_intent_min_chars;
+    let multi_intent = has_multi_intent(&utterances, min_chars);
+    if multi_intent {
         result
             .notes
             .push("multiple significant utterances detected".into());
     }
-    let classify_input = utterances
-        .first()
-        .cloned()
-        .unwrap_or_else(|| trimmed.to_string());
 
-    let logits = models.intent_router.classify(&classify_input)?;
+    let classified = if multi_intent && config.multi_intent.mode == MultiIntentMode::PerUtterance {
+        let mut classified = Vec::new();
+        for utterance in utterances
+            .iter()
+            .filter(|u| u.chars().count() >= min_chars)
+            .take(config.multi_intent.max_segments.max(1))
+        {
+            let routed =
+                classify_utterance(models, &config, utterance, utterance, &result.language)?;
+            result.segments.push(RoutingSegment {
+                text: utterance.clone(),
+                speech_act: routed.speech_act.label.clone(),
+                domain: routed.domain.label.clone(),
+                expectation: routed.expectation.label.clone(),
+                support_intent: routed.support_intent,
+                intent_kind: routed.final_intent_kind,
+                routing_path: routed.routing_path,
+                prompt_key: routed.prompt_key.clone(),
+            });
+            classified.push(routed);
+        }
+        for (idx, segment) in result.segments.iter().enumerate() {
+            result.notes.push(format!(
+                "segment {}: {}/{}/{} → {}",
+                idx + 1,
+                segment.speech_act,
+                segment.domain,
+                segment.expectation,
+                segment.prompt_key
+            ));
+        }
+        result.safety = classified
+            .iter()
+            .filter_map(|c| c.safety.as_ref())
+            .max_by(|a, b| {
+                unsafe_probability(a)
+                    .partial_cmp(&unsafe_probability(b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .cloned();
+        let primary = primary_segment(&result.segments);
+        result.notes.push(format!(
+            "segment {} routes the message ({:?})",
+            primary + 1,
+            result.segments[primary].routing_path
+        ));
+        classified.swap_remove(primary)
+    } else {
+        let classify_input = utterances.first().map(String::as_str).unwrap_or(trimmed);
+        classify_utterance(models, &config, classify_input, trimmed, &result.language)?
+    };
+
+    log_head_predictions(
+        &classified.speech_act,
+        &classified.domain,
+        &classified.expectation,
+        classified.phatic.as_ref(),
+        classified.support.as_ref(),
+    );
+    result.notes.extend(classified.notes);
+    result.speech_act = classified.speech_act;
+    result.domain = classified.domain;
+    result.expectation = classified.expectation;
+    result.phatic = classified.phatic;
+    result.support = classified.support;
+    result.support_intent = classified.support_intent;
+    if result.safety.is_none() {
+        result.safety = classified.safety;
+    }
+    result.final_intent_kind = classified.final_intent_kind;
+    result.routing_path = classified.routing_path;
+    result.reasoning_profile = classified.reasoning_profile;
+    result.prompt_key = classified.prompt_key;
+
+    let asks = result.speech_act.label == "ASKING"
+        || result.expectation.label == "INFO"
+        || trimmed.contains('?');
+    if asks && !result.support_intent && super::recency::is_recency_sensitive(trimmed) {
+        result.recency_sensitive = true;
+        result
+            .notes
+            .push("recency-sensitive question → web search".into());
+    }
+
+    log_prompt_selection(&result);
+    Ok(result)
+}
+
+/// Heads and route for one classifier input.
+struct ClassifiedUtterance {
+    speech_act: HeadPrediction,
+    domain: HeadPrediction,
+    expectation: HeadPrediction,
+    phatic: Option<HeadPrediction>,
+    support: Option<HeadPrediction>,
+    support_intent: bool,
+    safety: Option<HeadPrediction>,
+    final_intent_kind: IntentKind,
+    routing_path: RoutingPath,
+    reasoning_profile: Option<ReasoningProfile>,
+    prompt_key: String,
+    notes: Vec<String>,
+}
+
+/// Classify `input` and run it through the rules. Preference topics are
+/// looked up in `context`.
+fn classify_utterance(
+    models: &ModelManager,
+    config: &RoutingConfig,
+    input: &str,
+    context: &str,
+    language: &str,
+) -> Result<ClassifiedUtterance> {
+    let logits = models.intent_router.classify(input)?;
+    let mut notes = Vec::new();
 
     let mut speech_act = decode_head(&logits.speech_act, SPEECH_ACT_LABELS)?;
     speech_act.label = speech_act.label.to_ascii_uppercase();
@@ -149,84 +345,77 @@ pub fn route_intent(
     domain.label = domain.label.to_ascii_lowercase();
     let mut expectation = decode_head(&logits.expectation, EXPECTATION_LABELS)?;
     expectation.label = expectation.label.to_ascii_uppercase();
-    let phatic_prediction = logits
+    let phatic = logits
         .phatic
         .as_ref()
         .and_then(|values| decode_head(values, PHATIC_LABELS).ok());
+    let safety = logits
+        .safety
+        .as_ref()
+        .and_then(|values| decode_head(values, SAFETY_LABELS).ok());
 
-    let (support_pred, support_on) = decode_support(
+    let (support, support_intent) = decode_support(
         logits.support.as_deref(),
         SUPPORT_LABELS,
-        SUPPORT_INTENT_THRESHOLD,
+        config.thresholds.support_intent,
     )?;
-    result.support = support_pred.clone();
-    result.support_intent = support_on;
-    if support_on {
-        if let Some(pred) = &support_pred {
-            result
-                .notes
-                .push(format!("support_intent=ON ({:.2})", pred.score));
+    if support_intent {
+        if let Some(pred) = &support {
+            notes.push(format!("support_intent=ON ({:.2})", pred.score));
         } else {
-            result.notes.push("support_intent=ON".into());
+            notes.push("support_intent=ON".into());
         }
     }
 
-    if speech_act.label == "DIRECTING"
-        && expectation.label == "ADVICE"
-        && matches!(domain.label.as_str(), "personal" | "social")
-    {
-        speech_act.label = "EXPRESSING".to_string();
-        result
-            .notes
-            .push("DIRECTING+ADVICE in personal/social → reinterpreting as EXPRESSING".into());
+    let (effective_act, override_note) =
+        config.effective_speech_act(&speech_act.label, &expectation.label, &domain.label);
+    if let Some(note) = override_note {
+        speech_act.label = effective_act;
+        notes.push(note.to_string());
     }
 
-    result.notes.push(format!(
+    notes.push(format!(
         "speech_act={} ({:.2})",
         speech_act.label.as_str(),
         speech_act.score
     ));
-    result.notes.push(format!(
+    notes.push(format!(
         "domain={} ({:.2})",
         domain.label.as_str(),
         domain.score
     ));
-    result.notes.push(format!(
+    notes.push(format!(
         "expectation={} ({:.2})",
         expectation.label.as_str(),
         expectation.score
     ));
 
-    log_head_predictions(
-        &speech_act,
-        &domain,
-        &expectation,
-        phatic_prediction.as_ref(),
-        support_pred.as_ref(),
-    );
-    result.phatic = phatic_prediction.clone();
-
-    if result.support_intent {
-        result
-            .notes
-            .push("support intent override → support_reflective prompt".into());
-        result.speech_act = speech_act;
-        result.domain = domain;
-        result.expectation = expectation;
-        result.final_intent_kind = IntentKind::ChatCasual;
-        result.routing_path = RoutingPath::ChatLayer;
-        result.reasoning_profile = None;
-        result.prompt_key = "support_reflective".to_string();
-        log_prompt_selection(&result);
-        return Ok(result);
+    if support_intent {
+        notes.push(format!(
+            "support intent override → {} prompt",
+            config.support_prompt
+        ));
+        return Ok(ClassifiedUtterance {
+            speech_act,
+            domain,
+            expectation,
+            phatic,
+            support,
+            support_intent,
+            safety,
+            final_intent_kind: IntentKind::ChatCasual,
+            routing_path: RoutingPath::ChatLayer,
+            reasoning_profile: None,
+            prompt_key: config.support_prompt.clone(),
+            notes,
+        });
     }
 
-    let preference_hint = speech_act.label == "EXPRESSING"
-        && domain.label == "personal"
-        && mentions_preference_topics(trimmed);
-
-    let support_label = result.support.as_ref().map(|p| p.label.as_str());
-    let support_is_no_support = matches!(support_label, Some("NO_SUPPORT"));
+    let preference_hint = config.mentions_preference_topic(context);
+    let support_is_no_support = matches!(
+        support.as_ref().map(|p| p.label.as_str()),
+        Some("NO_SUPPORT")
+    );
 
     let (final_kind, routing_path, prompt_stub, mut routing_notes) = resolve_routing(
         &speech_act.label,
@@ -235,53 +424,161 @@ pub fn route_intent(
         preference_hint,
         support_is_no_support,
     );
-    result.notes.append(&mut routing_notes);
+    notes.append(&mut routing_notes);
 
     let reasoning_profile = if routing_path == RoutingPath::TaskLayer {
         Some(select_reasoning_profile(
-            text,
-            Some(result.language.as_str()),
-            prompt_stub,
+            input,
+            Some(language),
+            &prompt_stub,
             final_kind,
         ))
     } else {
         None
     };
 
-    let mut prompt_key = prompts::resolved_prompt_key(prompt_stub, reasoning_profile);
-    let expectation_forces_reasoning =
-        matches!(expectation.label.as_str(), "INFO" | "ADVICE" | "ACTION");
-    if domain.label == "technical"
-        && prompt_key != "reasoning"
-        && prompt_key != "advice_practical"
-        && expectation_forces_reasoning
-    {
-        if result.support_intent {
-            result
-                .notes
-                .push("support intent active → empathetic-first response".into());
-        } else {
-            result
-                .notes
-                .push("domain=technical → forcing reasoning prompt".into());
-            prompt_key = "reasoning".to_string();
-        }
-    }
+    let mut prompt_key = prompts::resolved_prompt_key(&prompt_stub, reasoning_profile);
+    if config.forces_reasoning(&domain.label, &expectation.label, &prompt_key) {
+        notes.push("domain=technical → forcing reasoning prompt".into());
+        prompt_key = config.technical_reasoning.prompt.clone();
+    }
+
+    Ok(ClassifiedUtterance {
+        speech_act,
+        domain,
+        expectation,
+        phatic,
+        support,
+        support_intent,
+        safety,
+        final_intent_kind: final_kind,
+        routing_path,
+        reasoning_profile,
+        prompt_key,
+        notes,
+    })
+}
 
-    if result.support_intent {
-        prompt_key = "support_reflective".to_string();
+/// Probability mass outside `SAFE` (the first safety label).
+fn unsafe_probability(prediction: &HeadPrediction) -> f32 {
+    match prediction.distribution.first() {
+        Some(safe) => 1.0 - safe,
+        None if prediction.label == SAFETY_LABELS[0] => 1.0 - prediction.score,
+        None => prediction.score,
     }
+}
 
-    result.speech_act = speech_act;
-    result.domain = domain;
-    result.expectation = expectation;
-    result.final_intent_kind = final_kind;
-    result.routing_path = routing_path;
-    result.reasoning_profile = reasoning_profile;
-    result.prompt_key = prompt_key;
+/// Index of the segment that routes the whole message: the first task-layer
+/// segment, otherwise the first one.
+fn primary_segment(segments: &[RoutingSegment]) -> usize {
+    segments
+        .iter()
+        .position(|s| s.routing_path == RoutingPath::TaskLayer)
+        .unwrap_or(0)
+}
 
-    log_prompt_selection(&result);
-    Ok(result)
+/// Force a client-chosen reasoning profile onto a routing result. The
+/// prompt depends only on the profile, so the same override always renders
+/// the same prompt whatever the classifiers said.
+pub fn apply_reasoning_profile_override(
+    result: &mut IntentRoutingResult,
+    profile: ReasoningProfile,
+) {
+    result.notes.push(format!(
+        "reasoning_profile override → {} (detected {:?})",
+        profile.as_str(),
+        result.reasoning_profile.map(ReasoningProfile::as_str)
+    ));
+    result.final_intent_kind = IntentKind::Reasoning;
+    result.routing_path = RoutingPath::TaskLayer;
+    result.reasoning_profile = Some(profile);
+    result.prompt_key = prompts::resolved_prompt_key(intent_for_profile(profile), Some(profile));
+    result.support_intent = false;
+    log_prompt_selection(result);
+}
+
+/// One classifier outcome and the prompt key it ends up rendering.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptKeyRoute {
+    pub prompt_key: String,
+    pub speech_act: &'static str,
+    pub domain: &'static str,
+    pub expectation: &'static str,
+    pub intent_kind: IntentKind,
+    pub routing_path: RoutingPath,
+    /// Extra signal required for this route (`preference_topic`,
+    /// `no_support_needed`, `support_intent`), if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<&'static str>,
+    /// Task-layer routes can still be escalated to `reasoning` by the
+    /// text-dependent reasoning profile.
+    pub may_escalate_to_reasoning: bool,
+}
+
+/// Enumerate every head-label combination through the same routing rules as
+/// `route_intent`, so callers can see which intents land on which prompt.
+pub fn prompt_key_routes() -> Vec<PromptKeyRoute> {
+    let config = rules::current();
+    let mut routes = Vec::new();
+    let variants: [(bool, bool, Option<&'static str>); 3] = [
+        (false, false, None),
+        (true, false, Some("preference_topic")),
+        (false, true, Some("no_support_needed")),
+    ];
+
+    for &speech_act in SPEECH_ACT_LABELS {
+        for &domain in DOMAIN_LABELS {
+            for &expectation in EXPECTATION_LABELS {
+                let (effective_act, _) =
+                    config.effective_speech_act(speech_act, expectation, domain);
+
+                let mut baseline: Option<String> = None;
+                for (preference_hint, no_support, condition) in variants {
+                    let (intent_kind, routing_path, mut key, _) = resolve_routing(
+                        &effective_act,
+                        expectation,
+                        domain,
+                        preference_hint,
+                        no_support,
+                    );
+                    if config.forces_reasoning(domain, expectation, &key) {
+                        key = config.technical_reasoning.prompt.clone();
+                    }
+                    let key = prompts::plan_prompt_key(&key, domain, expectation);
+
+                    // Conditional variants are only listed when they change the outcome.
+                    match &baseline {
+                        Some(base) if *base == key => continue,
+                        Some(_) => {}
+                        None => baseline = Some(key.clone()),
+                    }
+
+                    routes.push(PromptKeyRoute {
+                        prompt_key: key,
+                        speech_act,
+                        domain,
+                        expectation,
+                        intent_kind,
+                        routing_path,
+                        condition,
+                        may_escalate_to_reasoning: routing_path == RoutingPath::TaskLayer,
+                    });
+                }
+            }
+        }
+    }
+
+    routes.push(PromptKeyRoute {
+        prompt_key: config.support_prompt.clone(),
+        speech_act: "*",
+        domain: "*",
+        expectation: "*",
+        intent_kind: IntentKind::ChatCasual,
+        routing_path: RoutingPath::ChatLayer,
+        condition: Some("support_intent"),
+        may_escalate_to_reasoning: false,
+    });
+    routes
 }
 
 fn decode_head(logits: &[f32], labels: &[&str]) -> Result<HeadPrediction> {
@@ -417,189 +714,22 @@ fn log_prompt_selection(result: &IntentRoutingResult) {
     );
 }
 
+/// Apply the rule table of the routing config in effect.
 fn resolve_routing(
     speech_act: &str,
     expectation: &str,
     domain: &str,
     preference_hint: bool,
     support_is_no_support: bool,
-) -> (IntentKind, RoutingPath, &'static str, Vec<String>) {
-    let mut notes = Vec::new();
-    if speech_act == "EXPRESSING"
-        && domain == "personal"
-        && expectation == "ADVICE"
-        && support_is_no_support
-    {
-        notes.push("personal expressive advice without support need → chat_narrative".into());
-        return (
-            IntentKind::ChatCasual,
-            RoutingPath::ChatLayer,
-            "chat_narrative",
-            notes,
-        );
-    }
-
-    if speech_act == "EXPRESSING" && domain == "personal" && preference_hint {
-        notes.push("personal preference topic detected → opinion_casual prompt".into());
-        return (
-            IntentKind::ChatCasual,
-            RoutingPath::ChatLayer,
-            "opinion_casual",
-            notes,
-        );
-    }
-
-    if expectation == "NONE" && domain == "personal" {
-        if speech_act == "DIRECTING" {
-            notes.push("personal narrative detected → chat_narrative prompt".into());
-            return (
-                IntentKind::ChatCasual,
-                RoutingPath::ChatLayer,
-                "chat_narrative",
-                notes,
-            );
-        }
-
-        if speech_act == "EXPRESSING" {
-            notes.push("personal reflection detected → chat_narrative prompt".into());
-            return (
-                IntentKind::ChatCasual,
-                RoutingPath::ChatLayer,
-                "chat_narrative",
-                notes,
-            );
-        }
-    }
-
-    if speech_act == "EXPRESSING" {
-        notes.push("EXPRESSING speech act → chat layer".into());
-        let prompt = if expectation == "NONE" && domain == "technical" {
-            notes.push("EXPRESSING + expectation NONE → reflective technical chat".into());
-            "chat_technical_reflective"
-        } else {
-            intent_from_domain(domain)
-        };
-        return (
-            IntentKind::ChatCasual,
-            RoutingPath::ChatLayer,
-            prompt,
-            notes,
-        );
-    }
-
-    if speech_act == "DIRECTING"
-        && matches!(expectation, "INFO" | "ADVICE")
-        && matches!(domain, "technical" | "legal")
-    {
-        notes.push("DIRECTING + info/advice (technical/legal) → task escalation".into());
-        let intent = intent_from_domain(domain);
-        let intent_kind = if intent == "reasoning" {
-            IntentKind::Reasoning
-        } else {
-            IntentKind::Task
-        };
-        return (intent_kind, RoutingPath::TaskLayer, intent, notes);
-    }
-
-    if speech_act == "ASKING" && domain != "social" {
-        notes.push("ASKING intent outside social → task escalation".into());
-        let intent = intent_from_domain(domain);
-        let intent_kind = if intent == "reasoning" {
-            IntentKind::Reasoning
-        } else {
-            IntentKind::Task
-        };
-        return (intent_kind, RoutingPath::TaskLayer, intent, notes);
-    }
-
-    if speech_act == "DIRECTING" && expectation != "ADVICE" && domain == "technical" {
-        notes.push("DIRECTING + technical domain → reasoning depth".into());
-        return (
-            IntentKind::ChatCasual,
-            RoutingPath::ChatLayer,
-            "reasoning",
-            notes,
-        );
-    }
-
-    if speech_act == "COLLABORATIVE" {
-        notes.push("COLLABORATIVE/INITIATING → rapport handling".into());
-        let prompt = intent_from_domain(domain);
-        return (
-            IntentKind::ChatCasual,
-            RoutingPath::ChatLayer,
-            prompt,
-            notes,
-        );
-    }
-
-    notes.push("chat-first routing applied".into());
-    let prompt = intent_from_domain(domain);
-    (
-        IntentKind::ChatCasual,
-        RoutingPath::ChatLayer,
-        prompt,
-        notes,
-    )
-}
-
-fn intent_from_domain(domain: &str) -> &'static str {
-    match domain {
-        "technical" => "reasoning",
-        "legal" => "advice_practical",
-        "personal" => "opinion_reflective",
-        "social" => "chat_casual",
-        _ => "chat_casual",
-    }
-}
-
-fn mentions_preference_topics(text: &str) -> bool {
-    const PREFERENCE_TOKENS: &[&str] = &[
-        "book",
-        "books",
-        "novel",
-        "novels",
-        "movie",
-        "movies",
-        "film",
-        "films",
-        "music",
-        "song",
-        "songs",
-        "album",
-        "albums",
-        "artist",
-        "artists",
-        "band",
-        "bands",
-        "tv",
-        "show",
-        "shows",
-        "series",
-        "podcast",
-        "podcasts",
-        "game",
-        "games",
-        "food",
-        "foods",
-        "cuisine",
-        "taste",
-        "tastes",
-        "flavor",
-        "flavour",
-        "favorite",
-        "favorites",
-        "favourite",
-        "favourites",
-        "preference",
-        "preferences",
-        "genre",
-        "genres",
-    ];
-    text.to_ascii_lowercase()
-        .split(|c: char| !c.is_ascii_alphabetic())
-        .filter(|token| !token.is_empty())
-        .any(|token| PREFERENCE_TOKENS.contains(&token))
+) -> (IntentKind, RoutingPath, String, Vec<String>) {
+    let (kind, path, prompt, note) = rules::current().resolve(&RoutingSignals {
+        speech_act,
+        domain,
+        expectation,
+        preference_topic: preference_hint,
+        no_support: support_is_no_support,
+    });
+    (kind, path, prompt, vec![note])
 }
 
 fn normalize_language(language: Option<&str>) -> String {
@@ -650,11 +780,10 @@ fn push_segment(buffer: &mut String, segments: &mut Vec<String>) {
     buffer.clear();
 }
 
-fn has_multi_intent(utterances: &[String]) -> bool {
-    const MULTI_INTENT_MIN_LEN: usize = 40;
+fn has_multi_intent(utterances: &[String], min_chars: usize) -> bool {
     utterances
         .iter()
-        .filter(|u| u.chars().count() >= MULTI_INTENT_MIN_LEN)
+        .filter(|u| u.chars().count() >= min_chars)
         .count()
         > 1
 }
@@ -668,6 +797,15 @@ fn select_reasoning_profile(
     profile_from_intent(intent)
 }
 
+/// Inverse of `profile_from_intent`.
+fn intent_for_profile(profile: ReasoningProfile) -> &'static str {
+    match profile {
+        ReasoningProfile::RegulatedTaxLegal => "regulated_tax_legal",
+        ReasoningProfile::ReflectiveAnalysis => "opinion_reflective",
+        _ => "reasoning",
+    }
+}
+
 fn profile_from_intent(intent: &str) -> ReasoningProfile {
     match intent {
         "reasoning" => ReasoningProfile::General,
@@ -702,6 +840,66 @@ mod tests {
         assert_eq!(prompt, "opinion_casual");
     }
 
+    #[test]
+    fn prompt_key_routes_cover_legal_override() {
+        let routes = prompt_key_routes();
+        assert!(routes
+            .iter()
+            .filter(|r| r.domain == "legal")
+            .all(|r| r.prompt_key == "advice_practical"));
+        assert!(routes
+            .iter()
+            .any(|r| r.prompt_key == "opinion_casual" && r.condition == Some("preference_topic")));
+    }
+
+    #[test]
+    fn reasoning_profile_override_wins_over_detection() {
+        assert_eq!(
+            ReasoningProfile::parse("math_word_problem"),
+            Some(ReasoningProfile::MathWordProblem)
+        );
+        assert_eq!(
+            ReasoningProfile::parse("RegulatedTaxLegal"),
+            Some(ReasoningProfile::RegulatedTaxLegal)
+        );
+        assert_eq!(ReasoningProfile::parse("tax"), None);
+
+        let mut result = IntentRoutingResult {
+            support_intent: true,
+            prompt_key: "support_reflective".into(),
+            ..IntentRoutingResult::default()
+        };
+        apply_reasoning_profile_override(&mut result, ReasoningProfile::FormalLogic);
+        assert_eq!(result.routing_path, RoutingPath::TaskLayer);
+        assert_eq!(
+            result.reasoning_profile,
+            Some(ReasoningProfile::FormalLogic)
+        );
+        assert_eq!(result.prompt_key, "reasoning");
+        assert!(!result.support_intent);
+    }
+
+    #[test]
+    fn task_segment_routes_multi_intent_message() {
+        let segment = |routing_path, prompt_key: &str| RoutingSegment {
+            text: String::new(),
+            speech_act: "ASKING".into(),
+            domain: "general".into(),
+            expectation: "INFO".into(),
+            support_intent: false,
+            intent_kind: IntentKind::ChatCasual,
+            routing_path,
+            prompt_key: prompt_key.into(),
+        };
+        let segments = [
+            segment(RoutingPath::ChatLayer, "chat_casual"),
+            segment(RoutingPath::TaskLayer, "reasoning"),
+            segment(RoutingPath::TaskLayer, "advice_practical"),
+        ];
+        assert_eq!(primary_segment(&segments), 1);
+        assert_eq!(primary_segment(&segments[..1]), 0);
+    }
+
     #[test]
     fn expressing_personal_advice_without_support_goes_to_chat_narrative() {
         let (_, _, prompt, _) = resolve_routing("EXPRESSING", "ADVICE", "personal", false, true);
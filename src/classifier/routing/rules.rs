@@ -0,0 +1,482 @@
+//! Routing thresholds and the rule table, loaded from `config/routing.yaml`
+//! (`ROUTING_CONFIG`) so routing can be tuned without a rebuild. The copy
+//! of the file in the repo is compiled in and used when the runtime file is
+//! missing or invalid.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    IntentKind, ReasoningProfile, RoutingPath, DOMAIN_LABELS, EXPECTATION_LABELS, SPEECH_ACT_LABELS,
+};
+
+const BUILTIN: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/config/routing.yaml"));
+const DOMAIN_PROMPT: &str = "$domain";
+
+static CONFIG: Lazy<RwLock<Arc<RoutingConfig>>> = Lazy::new(|| {
+    let config = match RoutingConfig::from_file(&config_path()) {
+        Ok(config) => config,
+        Err(err) => {
+            warn!("routing config: {err:#}; using the built-in rules");
+            RoutingConfig::builtin()
+        }
+    };
+    RwLock::new(Arc::new(config))
+});
+
+fn config_path() -> PathBuf {
+    std::env::var("ROUTING_CONFIG")
+        .unwrap_or_else(|_| "config/routing.yaml".to_string())
+        .into()
+}
+
+/// Rules in effect.
+pub fn current() -> Arc<RoutingConfig> {
+    CONFIG.read().expect("routing config lock poisoned").clone()
+}
+
+/// Re-read the config file. The rules in effect are kept when the file is
+/// unreadable or invalid.
+pub fn reload() -> Result<Arc<RoutingConfig>> {
+    let path = config_path();
+    let config = Arc::new(RoutingConfig::from_file(&path)?);
+    *CONFIG.write().expect("routing config lock poisoned") = config.clone();
+    info!(
+        path = %path.display(),
+        rules = config.rules.len(),
+        "routing config reloaded"
+    );
+    Ok(config)
+}
+
+/// A label or a list of accepted labels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Labels {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Labels {
+    fn values(&self) -> &[String] {
+        match self {
+            Labels::One(label) => std::slice::from_ref(label),
+            Labels::Many(labels) => labels,
+        }
+    }
+
+    fn contains(&self, label: &str) -> bool {
+        self.values().iter().any(|v| v.eq_ignore_ascii_case(label))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speech_act: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expectation: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speech_act_not: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_not: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expectation_not: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preference_topic: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_support: Option<bool>,
+}
+
+/// What the rules are matched against.
+pub struct RoutingSignals<'a> {
+    pub speech_act: &'a str,
+    pub domain: &'a str,
+    pub expectation: &'a str,
+    pub preference_topic: bool,
+    pub no_support: bool,
+}
+
+impl Conditions {
+    pub fn matches(&self, signals: &RoutingSignals) -> bool {
+        let is = |labels: &Option<Labels>, value: &str| {
+            labels.as_ref().map_or(true, |l| l.contains(value))
+        };
+        let is_not = |labels: &Option<Labels>, value: &str| {
+            labels.as_ref().map_or(true, |l| !l.contains(value))
+        };
+        is(&self.speech_act, signals.speech_act)
+            && is(&self.domain, signals.domain)
+            && is(&self.expectation, signals.expectation)
+            && is_not(&self.speech_act_not, signals.speech_act)
+            && is_not(&self.domain_not, signals.domain)
+            && is_not(&self.expectation_not, signals.expectation)
+            && self
+                .preference_topic
+                .map_or(true, |v| v == signals.preference_topic)
+            && self.no_support.map_or(true, |v| v == signals.no_support)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let check = |labels: &Option<Labels>, known: &[&str], head: &str| -> Result<()> {
+            for label in labels.iter().flat_map(Labels::values) {
+                if !known.iter().any(|k| k.eq_ignore_ascii_case(label)) {
+                    bail!("unknown {head} label `{label}`");
+                }
+            }
+            Ok(())
+        };
+        check(&self.speech_act, SPEECH_ACT_LABELS, "speech_act")?;
+        check(&self.speech_act_not, SPEECH_ACT_LABELS, "speech_act")?;
+        check(&self.domain, DOMAIN_LABELS, "domain")?;
+        check(&self.domain_not, DOMAIN_LABELS, "domain")?;
+        check(&self.expectation, EXPECTATION_LABELS, "expectation")?;
+        check(&self.expectation_not, EXPECTATION_LABELS, "expectation")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RulePath {
+    Chat,
+    Task,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    pub note: String,
+    #[serde(default)]
+    pub when: Conditions,
+    pub path: RulePath,
+    /// Prompt key, or `$domain` for the domain's prompt.
+    pub prompt: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelOverride {
+    pub note: String,
+    pub when: Conditions,
+    pub set_speech_act: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Thresholds {
+    pub support_intent: f32,
+    pub multi_intent_min_chars: usize,
+}
+
+/// How a message with several significant utterances is classified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiIntentMode {
+    /// Classify the first utterance only.
+    #[default]
+    First,
+    /// Classify each significant utterance; a task-layer utterance routes
+    /// the message over chat-layer ones.
+    PerUtterance,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultiIntent {
+    #[serde(default)]
+    pub mode: MultiIntentMode,
+    /// Upper bound on classifier calls per message.
+    #[serde(default = "default_max_segments")]
+    pub max_segments: usize,
+}
+
+impl Default for MultiIntent {
+    fn default() -> Self {
+        Self {
+            mode: MultiIntentMode::default(),
+            max_segments: default_max_segments(),
+        }
+    }
+}
+
+fn default_max_segments() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TechnicalReasoning {
+    pub domains: Vec<String>,
+    pub expectations: Vec<String>,
+    pub keep_prompts: Vec<String>,
+    pub prompt: String,
+}
+
+/// Extra stop strings and a lower token cap for some replies.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationLimits {
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+}
+
+impl GenerationLimits {
+    fn validate(&self) -> Result<()> {
+        if self.stop.iter().any(|s| s.is_empty()) {
+            bail!("stop strings must not be empty");
+        }
+        if self.max_tokens == Some(0) {
+            bail!("max_tokens must be positive");
+        }
+        Ok(())
+    }
+
+    /// Both sets of stop strings and the lower token cap.
+    fn merge(&mut self, other: &GenerationLimits) {
+        for stop in &other.stop {
+            if !self.stop.contains(stop) {
+                self.stop.push(stop.clone());
+            }
+        }
+        self.max_tokens = match (self.max_tokens, other.max_tokens) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+/// Generation limits by prompt key and by reasoning profile.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Generation {
+    #[serde(default)]
+    pub prompts: BTreeMap<String, GenerationLimits>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, GenerationLimits>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingConfig {
+    pub thresholds: Thresholds,
+    #[serde(default)]
+    pub multi_intent: MultiIntent,
+    pub support_prompt: String,
+    #[serde(default)]
+    pub label_overrides: Vec<LabelOverride>,
+    pub domain_prompts: std::collections::BTreeMap<String, String>,
+    pub default_domain_prompt: String,
+    pub rules: Vec<RoutingRule>,
+    pub fallback: RoutingRule,
+    pub technical_reasoning: TechnicalReasoning,
+    #[serde(default)]
+    pub preference_topics: Vec<String>,
+    #[serde(default)]
+    pub generation: Generation,
+}
+
+impl RoutingConfig {
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN).expect("invalid built-in routing config")
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        Self::parse(&raw).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        let config: RoutingConfig = serde_yaml::from_str(raw)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.thresholds.support_intent) {
+            bail!("thresholds.support_intent must be within 0..=1");
+        }
+        for rule in self.rules.iter().chain(std::iter::once(&self.fallback)) {
+            rule.when
+                .validate()
+                .map_err(|err| anyhow!("rule `{}`: {err}", rule.note))?;
+            if rule.prompt.trim().is_empty() {
+                bail!("rule `{}` has no prompt", rule.note);
+            }
+        }
+        for rewrite in &self.label_overrides {
+            rewrite
+                .when
+                .validate()
+                .map_err(|err| anyhow!("label override `{}`: {err}", rewrite.note))?;
+            if !SPEECH_ACT_LABELS
+                .iter()
+                .any(|k| k.eq_ignore_ascii_case(&rewrite.set_speech_act))
+            {
+                bail!(
+                    "label override `{}`: unknown speech_act `{}`",
+                    rewrite.note,
+                    rewrite.set_speech_act
+                );
+            }
+        }
+        for (prompt, limits) in &self.generation.prompts {
+            limits
+                .validate()
+                .map_err(|err| anyhow!("generation prompt `{prompt}`: {err}"))?;
+        }
+        for (profile, limits) in &self.generation.profiles {
+            if ReasoningProfile::parse(profile).is_none() {
+                bail!("generation: unknown reasoning profile `{profile}`");
+            }
+            limits
+                .validate()
+                .map_err(|err| anyhow!("generation profile `{profile}`: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Limits for a reply with `prompt_key` and `profile`: the stop strings
+    /// of both and the lower token cap.
+    pub fn generation_limits(
+        &self,
+        prompt_key: &str,
+        profile: Option<ReasoningProfile>,
+    ) -> GenerationLimits {
+        let mut limits = GenerationLimits::default();
+        if let Some(prompt) = self.generation.prompts.get(prompt_key) {
+            limits.merge(prompt);
+        }
+        let by_profile = profile.and_then(|profile| {
+            self.generation
+                .profiles
+                .iter()
+                .find(|(name, _)| ReasoningProfile::parse(name) == Some(profile))
+        });
+        if let Some((_, profile)) = by_profile {
+            limits.merge(profile);
+        }
+        limits
+    }
+
+    /// Speech act after label overrides, with the note of the override
+    /// that applied.
+    pub fn effective_speech_act(
+        &self,
+        speech_act: &str,
+        expectation: &str,
+        domain: &str,
+    ) -> (String, Option<&str>) {
+        let signals = RoutingSignals {
+            speech_act,
+            domain,
+            expectation,
+            preference_topic: false,
+            no_support: false,
+        };
+        match self
+            .label_overrides
+            .iter()
+            .find(|o| o.when.matches(&signals))
+        {
+            Some(rewrite) => (
+                rewrite.set_speech_act.to_ascii_uppercase(),
+                Some(rewrite.note.as_str()),
+            ),
+            None => (speech_act.to_string(), None),
+        }
+    }
+
+    pub fn domain_prompt(&self, domain: &str) -> &str {
+        self.domain_prompts
+            .get(domain)
+            .unwrap_or(&self.default_domain_prompt)
+    }
+
+    /// First matching rule (or the fallback) as intent kind, path, prompt
+    /// key and note.
+    pub fn resolve(&self, signals: &RoutingSignals) -> (IntentKind, RoutingPath, String, String) {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.when.matches(signals))
+            .unwrap_or(&self.fallback);
+        let prompt = if rule.prompt == DOMAIN_PROMPT {
+            self.domain_prompt(signals.domain).to_string()
+        } else {
+            rule.prompt.clone()
+        };
+        let (kind, path) = match rule.path {
+            RulePath::Chat => (IntentKind::ChatCasual, RoutingPath::ChatLayer),
+            RulePath::Task if prompt == "reasoning" => {
+                (IntentKind::Reasoning, RoutingPath::TaskLayer)
+            }
+            RulePath::Task => (IntentKind::Task, RoutingPath::TaskLayer),
+        };
+        (kind, path, prompt, rule.note.clone())
+    }
+
+    pub fn forces_reasoning(&self, domain: &str, expectation: &str, prompt_key: &str) -> bool {
+        let tr = &self.technical_reasoning;
+        tr.domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+            && tr
+                .expectations
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(expectation))
+            && !tr.keep_prompts.iter().any(|p| p == prompt_key)
+    }
+
+    pub fn mentions_preference_topic(&self, text: &str) -> bool {
+        text.to_lowercase()
+            .split(|c: char| !c.is_alphabetic())
+            .filter(|token| !token.is_empty())
+            .any(|token| self.preference_topics.iter().any(|t| t == token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_config_parses_and_rejects_unknown_labels() {
+        let config = RoutingConfig::builtin();
+        assert!(!config.rules.is_empty());
+
+        let broken = BUILTIN.replace("domain: personal", "domain: persnal");
+        let err = RoutingConfig::parse(&broken).unwrap_err().to_string();
+        assert!(err.contains("persnal"), "{err}");
+    }
+
+    #[test]
+    fn generation_limits_combine_prompt_and_profile() {
+        let raw = format!(
+            "{BUILTIN}\ngeneration:\n  prompts:\n    reasoning: {{ stop: [\"###\"], max_tokens: 900 }}\n  profiles:\n    planning: {{ stop: [\"###\", \"\\nPlan end\"], max_tokens: 600 }}\n"
+        );
+        let config = RoutingConfig::parse(&raw).unwrap();
+        let limits = config.generation_limits("reasoning", Some(ReasoningProfile::Planning));
+        assert_eq!(limits.stop, vec!["###", "\nPlan end"]);
+        assert_eq!(limits.max_tokens, Some(600));
+        let limits = config.generation_limits("chat_casual", Some(ReasoningProfile::FormalLogic));
+        assert!(limits.stop.is_empty());
+        assert_eq!(limits.max_tokens, None);
+
+        let broken =
+            format!("{BUILTIN}\ngeneration:\n  profiles:\n    planing: {{ max_tokens: 10 }}\n");
+        let err = RoutingConfig::parse(&broken).unwrap_err().to_string();
+        assert!(err.contains("planing"), "{err}");
+    }
+}
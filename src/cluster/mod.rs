@@ -0,0 +1,377 @@
+//! Running more than one replica. The worker queue, sockets and reply
+//! buffers live in process memory, so every chat is served by one node.
+//! `CLUSTER_MODE` picks how that node is found:
+//!
+//! - `single` (default): one node, every chat is local.
+//! - `affinity`: rendezvous hashing of the chat id over `CLUSTER_NODES`
+//!   (`id=url` pairs, comma separated). Needs no extra service, but adding
+//!   or removing a node moves a share of the chats.
+//! - `redis` (`redis-backplane` feature, `REDIS_URL`): the first node to
+//!   take a prompt of a chat leases it for `CLUSTER_CHAT_LEASE_SECS`, so
+//!   chats spread over whichever nodes are up. `cancel` of a reply running
+//!   on another node is relayed over Redis pub/sub.
+//!
+//! A prompt reaching the wrong node is answered with a `wrong_node` error
+//! carrying the owner's URL, and `GET /api/cluster/route` tells clients
+//! and load balancers where a chat lives before they connect.
+
+#[cfg(feature = "redis-backplane")]
+mod redis_backplane;
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::ws::{AppState, ResumeRegistry};
+
+#[cfg(feature = "redis-backplane")]
+pub use redis_backplane::RedisBackplane;
+
+const DEFAULT_NODE_ID: &str = "node-0";
+const DEFAULT_LEASE_SECS: u64 = 600;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Node {
+    pub id: String,
+    /// Public base URL of the node, e.g. `https://node-a.example.com`.
+    pub url: String,
+}
+
+/// `cancel` of a reply that is not buffered on the node the client talks
+/// to. Nodes only honour it for a reply of the same chat and device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteCancel {
+    /// Node that relayed the cancel.
+    pub origin: String,
+    pub request_id: String,
+    pub chat_id: String,
+    pub device_hash: String,
+}
+
+/// Shared state between nodes.
+#[async_trait]
+pub trait Backplane: Send + Sync {
+    /// Owner of `chat_id`. The chat is leased to `node` for `lease` when
+    /// nobody holds it, and the lease is renewed when `node` already does.
+    async fn claim_chat(&self, chat_id: &str, node: &Node, lease: Duration) -> Result<Node>;
+
+    async fn publish_cancel(&self, cancel: &RemoteCancel) -> Result<()>;
+
+    /// Cancels published by every node, this one included.
+    fn cancels(&self) -> broadcast::Receiver<RemoteCancel>;
+}
+
+#[derive(Clone)]
+enum Mode {
+    Single,
+    Affinity(Arc<Vec<Node>>),
+    Backplane {
+        backplane: Arc<dyn Backplane>,
+        lease: Duration,
+    },
+}
+
+/// This node and the way chats are assigned to nodes.
+#[derive(Clone)]
+pub struct Cluster {
+    node: Node,
+    mode: Mode,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// `id=url` pairs separated by commas.
+fn parse_nodes(raw: &str) -> Result<Vec<Node>> {
+    let mut nodes: Vec<Node> = Vec::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((id, url)) = entry.split_once('=') else {
+            bail!("CLUSTER_NODES entry `{entry}` is not `id=url`");
+        };
+        let (id, url) = (id.trim(), url.trim().trim_end_matches('/'));
+        if id.is_empty() || url.is_empty() {
+            bail!("CLUSTER_NODES entry `{entry}` is not `id=url`");
+        }
+        if nodes.iter().any(|n| n.id == id) {
+            bail!("CLUSTER_NODES lists `{id}` twice");
+        }
+        nodes.push(Node {
+            id: id.to_string(),
+            url: url.to_string(),
+        });
+    }
+    Ok(nodes)
+}
+
+fn rendezvous_score(node_id: &str, chat_id: &str) -> u64 {
+    let digest = Sha256::digest(format!("{node_id}\n{chat_id}").as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+}
+
+/// Node with the highest hash of its id and the chat id. Removing a node
+/// only moves the chats it owned.
+fn rendezvous<'a>(nodes: &'a [Node], chat_id: &str) -> Option<&'a Node> {
+    nodes
+        .iter()
+        .max_by_key(|node| rendezvous_score(&node.id, chat_id))
+}
+
+impl Cluster {
+    /// One node serving every chat.
+    pub fn single() -> Self {
+        Self {
+            node: Node {
+                id: env("NODE_ID").unwrap_or_else(|| DEFAULT_NODE_ID.to_string()),
+                url: env("NODE_URL").unwrap_or_default(),
+            },
+            mode: Mode::Single,
+        }
+    }
+
+    pub async fn from_env() -> Result<Self> {
+        let mode = env("CLUSTER_MODE")
+            .unwrap_or_else(|| "single".into())
+            .to_ascii_lowercase();
+        let node_id = env("NODE_ID").unwrap_or_else(|| DEFAULT_NODE_ID.to_string());
+        match mode.as_str() {
+            "single" => Ok(Self::single()),
+            "affinity" => {
+                let nodes = parse_nodes(&env("CLUSTER_NODES").unwrap_or_default())?;
+                let Some(node) = nodes.iter().find(|n| n.id == node_id).cloned() else {
+                    bail!("NODE_ID `{node_id}` is not listed in CLUSTER_NODES");
+                };
+                Ok(Self {
+                    node,
+                    mode: Mode::Affinity(Arc::new(nodes)),
+                })
+            }
+            "redis" => {
+                let Some(url) = env("NODE_URL") else {
+                    bail!("CLUSTER_MODE=redis needs NODE_URL, the public URL of this node");
+                };
+                let node = Node { id: node_id, url };
+                let lease = env("CLUSTER_CHAT_LEASE_SECS")
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(DEFAULT_LEASE_SECS);
+                Ok(Self {
+                    node,
+                    mode: Mode::Backplane {
+                        backplane: redis_backplane().await?,
+                        lease: Duration::from_secs(lease),
+                    },
+                })
+            }
+            other => bail!("unknown CLUSTER_MODE `{other}` (single, affinity or redis)"),
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn mode(&self) -> &'static str {
+        match self.mode {
+            Mode::Single => "single",
+            Mode::Affinity(_) => "affinity",
+            Mode::Backplane { .. } => "redis",
+        }
+    }
+
+    /// Node serving `chat_id`. Claims the chat for this node in `redis`
+    /// mode.
+    pub async fn route(&self, chat_id: &str) -> Result<Node> {
+        match &self.mode {
+            Mode::Single => Ok(self.node.clone()),
+            Mode::Affinity(nodes) => Ok(rendezvous(nodes, chat_id)
+                .cloned()
+                .unwrap_or_else(|| self.node.clone())),
+            Mode::Backplane { backplane, lease } => {
+                backplane.claim_chat(chat_id, &self.node, *lease).await
+            }
+        }
+    }
+
+    /// The other node serving `chat_id`, or `None` when it is this one.
+    pub async fn remote_owner(&self, chat_id: &str) -> Result<Option<Node>> {
+        let owner = self.route(chat_id).await?;
+        Ok((owner.id != self.node.id).then_some(owner))
+    }
+
+    /// Ask the other nodes to cancel reply `request_id`. `false` when
+    /// there is no backplane to relay it over.
+    pub async fn relay_cancel(&self, request_id: &str, chat_id: &str, device_hash: &str) -> bool {
+        let Mode::Backplane { backplane, .. } = &self.mode else {
+            return false;
+        };
+        let cancel = RemoteCancel {
+            origin: self.node.id.clone(),
+            request_id: request_id.to_string(),
+            chat_id: chat_id.to_string(),
+            device_hash: device_hash.to_string(),
+        };
+        match backplane.publish_cancel(&cancel).await {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(request_id, "failed to relay cancel: {err}");
+                false
+            }
+        }
+    }
+
+    /// Stop local replies cancelled on other nodes. `false` when there is
+    /// no backplane.
+    pub fn spawn_cancel_listener(&self, resume: ResumeRegistry) -> bool {
+        let Mode::Backplane { backplane, .. } = &self.mode else {
+            return false;
+        };
+        let mut cancels = backplane.cancels();
+        let node_id = self.node.id.clone();
+        tokio::spawn(async move {
+            loop {
+                match cancels.recv().await {
+                    Ok(cancel) if cancel.origin == node_id => {}
+                    Ok(cancel) => {
+                        let owner = resume
+                            .owner(&cancel.request_id)
+                            .filter(|owner| owner.matches(&cancel.chat_id, &cancel.device_hash));
+                        if let Some(owner) = owner {
+                            debug!(
+                                request_id = cancel.request_id.as_str(),
+                                origin = cancel.origin.as_str(),
+                                "cancelled by another node"
+                            );
+                            owner
+                                .cancel
+                                .store(true, std::sync::atomic::Ordering::SeqCst);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "cancel relay lagged");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        true
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/api/cluster/route", get(route_handler))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteQuery {
+    pub chat_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChatRoute {
+    pub chat_id: String,
+    /// Node to open the websocket on for this chat.
+    pub node: Node,
+    /// The node answering is that node.
+    pub local: bool,
+    /// `single`, `affinity` or `redis`.
+    #[schema(example = "affinity")]
+    pub mode: String,
+}
+
+/// Node serving a chat. In `redis` mode an unowned chat is leased to the
+/// node answering.
+#[utoipa::path(
+    get,
+    path = "/api/cluster/route",
+    tag = "status",
+    params(("chat_id" = String, Query, description = "Chat to route")),
+    responses(
+        (status = 200, description = "Node serving the chat", body = ChatRoute),
+        (status = 503, description = "The backplane is unreachable (`cluster_unavailable`)"),
+    )
+)]
+pub async fn route_handler(
+    State(state): State<AppState>,
+    Query(query): Query<RouteQuery>,
+) -> Result<Json<ChatRoute>, (StatusCode, String)> {
+    let node = state.cluster.route(&query.chat_id).await.map_err(|err| {
+        warn!(
+            chat_id = query.chat_id.as_str(),
+            "chat routing failed: {err}"
+        );
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "cluster_unavailable".to_string(),
+        )
+    })?;
+    Ok(Json(ChatRoute {
+        local: node.id == state.cluster.node().id,
+        chat_id: query.chat_id,
+        node,
+        mode: state.cluster.mode().to_string(),
+    }))
+}
+
+#[cfg(feature = "redis-backplane")]
+async fn redis_backplane() -> Result<Arc<dyn Backplane>> {
+    let url = env("REDIS_URL").unwrap_or_else(|| "redis://127.0.0.1:6379".into());
+    let backplane = RedisBackplane::connect(&url)
+        .await
+        .with_context(|| format!("failed to connect to redis at {url}"))?;
+    Ok(Arc::new(backplane))
+}
+
+#[cfg(not(feature = "redis-backplane"))]
+async fn redis_backplane() -> Result<Arc<dyn Backplane>> {
+    bail!("CLUSTER_MODE=redis but built without the `redis-backplane` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nodes_and_routes_chats() {
+        let nodes = parse_nodes("a=https://a.example.com/, b = https://b.example.com").unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].url, "https://a.example.com");
+        assert_eq!(nodes[1].id, "b");
+        assert!(parse_nodes("a=https://a, a=https://b").is_err());
+        assert!(parse_nodes("https://a").is_err());
+
+        let nodes = parse_nodes("a=https://a,b=https://b,c=https://c").unwrap();
+        let chats: Vec<String> = (0..300).map(|i| format!("chat-{i}")).collect();
+        let owners: Vec<&str> = chats
+            .iter()
+            .map(|chat| rendezvous(&nodes, chat).unwrap().id.as_str())
+            .collect();
+        for id in ["a", "b", "c"] {
+            assert!(owners.iter().filter(|o| **o == id).count() > 50);
+        }
+
+        // Dropping `c` only moves the chats `c` owned.
+        let without_c = &nodes[..2];
+        for (chat, owner) in chats.iter().zip(&owners) {
+            let moved = rendezvous(without_c, chat).unwrap().id.as_str();
+            if *owner != "c" {
+                assert_eq!(moved, *owner);
+            }
+        }
+    }
+}
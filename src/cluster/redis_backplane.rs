@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::{aio::MultiplexedConnection, Client};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use super::{Backplane, Node, RemoteCancel};
+
+const CANCEL_CHANNEL: &str = "ktulhu:cancel";
+const CANCEL_CAPACITY: usize = 256;
+/// Wait before subscribing again after the pub/sub connection dropped.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+fn chat_key(chat_id: &str) -> String {
+    format!("ktulhu:chat:{chat_id}")
+}
+
+/// Chat leases as `SET NX PX` keys holding the owner node as JSON, and
+/// cancels over a pub/sub channel.
+pub struct RedisBackplane {
+    conn: MultiplexedConnection,
+    cancels: broadcast::Sender<RemoteCancel>,
+}
+
+impl RedisBackplane {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = Client::open(url)?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        let (cancels, _) = broadcast::channel(CANCEL_CAPACITY);
+        // Fail startup rather than run without cancels.
+        let pubsub = subscribe(&client).await?;
+        tokio::spawn(forward_cancels(client, pubsub, cancels.clone()));
+        Ok(Self { conn, cancels })
+    }
+}
+
+async fn subscribe(client: &Client) -> redis::RedisResult<redis::aio::PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(CANCEL_CHANNEL).await?;
+    Ok(pubsub)
+}
+
+/// Pass cancels from Redis on to [`Backplane::cancels`], subscribing
+/// again whenever the connection drops.
+async fn forward_cancels(
+    client: Client,
+    mut pubsub: redis::aio::PubSub,
+    cancels: broadcast::Sender<RemoteCancel>,
+) {
+    loop {
+        {
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                let Ok(payload) = msg.get_payload::<String>() else {
+                    continue;
+                };
+                match serde_json::from_str::<RemoteCancel>(&payload) {
+                    Ok(cancel) => {
+                        let _ = cancels.send(cancel);
+                    }
+                    Err(err) => warn!("ignoring malformed cancel from redis: {err}"),
+                }
+            }
+        }
+        warn!("redis cancel subscription closed, resubscribing");
+        pubsub = loop {
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            match subscribe(&client).await {
+                Ok(pubsub) => break pubsub,
+                Err(err) => warn!("failed to resubscribe to redis cancels: {err}"),
+            }
+        };
+    }
+}
+
+#[async_trait]
+impl Backplane for RedisBackplane {
+    async fn claim_chat(&self, chat_id: &str, node: &Node, lease: Duration) -> Result<Node> {
+        let key = chat_key(chat_id);
+        let value = serde_json::to_string(node)?;
+        let lease_ms = lease.as_millis() as u64;
+        let mut conn = self.conn.clone();
+        // A lease can expire between `SET NX` and `GET`; try once more.
+        for _ in 0..2 {
+            let claimed: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(&value)
+                .arg("NX")
+                .arg("PX")
+                .arg(lease_ms)
+                .query_async(&mut conn)
+                .await?;
+            if claimed.is_some() {
+                return Ok(node.clone());
+            }
+            let current: Option<String> =
+                redis::cmd("GET").arg(&key).query_async(&mut conn).await?;
+            let Some(current) = current else {
+                continue;
+            };
+            let owner: Node = serde_json::from_str(&current)?;
+            if owner.id == node.id {
+                let _: () = redis::cmd("PEXPIRE")
+                    .arg(&key)
+                    .arg(lease_ms)
+                    .query_async(&mut conn)
+                    .await?;
+            }
+            return Ok(owner);
+        }
+        Ok(node.clone())
+    }
+
+    async fn publish_cancel(&self, cancel: &RemoteCancel) -> Result<()> {
+        let payload = serde_json::to_string(cancel)?;
+        let mut conn = self.conn.clone();
+        let _: () = redis::cmd("PUBLISH")
+            .arg(CANCEL_CHANNEL)
+            .arg(payload)
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    fn cancels(&self) -> broadcast::Receiver<RemoteCancel> {
+        self.cancels.subscribe()
+    }
+}
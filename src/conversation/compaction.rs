@@ -0,0 +1,440 @@
+//! Rolling long-form summaries of long chats. Only the last
+//! `HISTORY_WINDOW` messages go into the prompt; once a chat is longer than
+//! `CHAT_COMPACTION_AFTER` messages, the turns that fell out of the window are
+//! folded into a `summary_long` message, which the prompt builder puts in
+//! place of the pruned history.
+
+use std::{
+    collections::HashSet,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db::DBLayer;
+use crate::inference::InferenceService;
+use crate::model::message::Message;
+
+use super::{build_mistral_prompt, strip_chatml_markers, trim_history, trim_partial_chatml};
+
+pub const LONG_SUMMARY_ROLE: &str = "summary_long";
+/// Messages kept verbatim in the prompt.
+pub const HISTORY_WINDOW: usize = 24;
+
+const DEFAULT_COMPACTION_AFTER: usize = 30;
+const DEFAULT_REPLY_RESERVE: usize = 512;
+const DEFAULT_COMPACTION_EVERY: usize = 10;
+/// Longest message text quoted to the summarizer.
+const MAX_QUOTED_CHARS: usize = 1500;
+
+const COMPACTION_PROMPT: &str = "You keep a running summary of a long conversation between a user and an assistant. Rewrite the current summary so it also covers the new messages. Keep names, facts, numbers, decisions, preferences the user stated and open questions; drop small talk. Write in the language of the conversation, in at most 250 words of plain prose. Output only the summary.";
+
+static IN_FLIGHT: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Chat length that starts compaction (`CHAT_COMPACTION_AFTER`, default
+/// 30; `0` disables it).
+fn compaction_after() -> usize {
+    std::env::var("CHAT_COMPACTION_AFTER")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_COMPACTION_AFTER)
+}
+
+/// Uncovered pruned turns that trigger a refresh of an existing summary
+/// (`CHAT_COMPACTION_EVERY`, default 10).
+fn compaction_every() -> usize {
+    std::env::var("CHAT_COMPACTION_EVERY")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_COMPACTION_EVERY)
+}
+
+/// Context tokens kept free for the reply (`CONTEXT_REPLY_RESERVE`,
+/// default 512, at most half the context).
+fn reply_reserve(context_length: usize) -> usize {
+    std::env::var("CONTEXT_REPLY_RESERVE")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_REPLY_RESERVE)
+        .min(context_length / 2)
+}
+
+fn is_turn(message: &Message) -> bool {
+    matches!(message.role.as_str(), "user" | "assistant")
+}
+
+/// Trim to the last `max_messages` messages or a few more: the start of
+/// the window moves a third of the window at a time, so the prompt prefix,
+/// and the KV cache the chat's llama context holds for it, stays the same
+/// for several turns instead of shifting every turn. Only messages the
+/// summary plan counts as pruned are cut. When anything was cut, the
+/// chat's long summary is put first so the prompt builder can stand it in
+/// for the pruned part.
+pub fn compact_history(history: Vec<Message>, max_messages: usize) -> Vec<Message> {
+    let summary = history
+        .iter()
+        .rev()
+        .find(|m| m.role == LONG_SUMMARY_ROLE)
+        .cloned();
+    let history: Vec<Message> = history
+        .into_iter()
+        .filter(|m| m.role != LONG_SUMMARY_ROLE)
+        .collect();
+    let step = (max_messages / 3).max(1);
+    let excess = history.len().saturating_sub(max_messages);
+    let cut = excess - excess % step;
+    let pruned = cut > 0;
+    let keep = history.len() - cut;
+    let mut history = trim_history(history, keep);
+    if let (true, Some(summary)) = (pruned, summary) {
+        history.insert(0, summary);
+    }
+    history
+}
+
+/// A prompt that does not fit the model context even with the history cut
+/// down to the latest message.
+#[derive(Debug, Clone, Copy)]
+pub struct ContextOverflow {
+    /// Tokens the prompt may use: the context minus the reply reserve.
+    pub limit: usize,
+    pub context_length: usize,
+    pub prompt_tokens: usize,
+}
+
+/// Drop the oldest message of `history`, after a leading long summary
+/// while there are turns to drop, then any assistant messages the cut left
+/// at the front. The latest message is kept; false when nothing is left to
+/// drop.
+fn drop_oldest(history: &mut Vec<Message>) -> bool {
+    if history.len() <= 1 {
+        return false;
+    }
+    let summary = history[0].role == LONG_SUMMARY_ROLE;
+    let start = usize::from(summary && history.len() > 2);
+    history.remove(start);
+    while history.len() > start + 1 && history[start].role == "assistant" {
+        history.remove(start);
+    }
+    true
+}
+
+/// The prompt `render` makes of `history`, with the oldest messages dropped
+/// from `history` until it leaves room for the reply in the model context.
+/// A failed token count lets the prompt through as it is.
+pub async fn fit_context(
+    infer: &InferenceService,
+    history: &mut Vec<Message>,
+    render: impl Fn(&[Message]) -> String,
+) -> Result<String, ContextOverflow> {
+    let mut dropped = 0;
+    loop {
+        let prompt = render(history);
+        let prompt_tokens = match infer.count_tokens(&prompt).await {
+            Ok(count) => count,
+            Err(err) => {
+                warn!("prompt token count failed, not checking the context: {err}");
+                return Ok(prompt);
+            }
+        };
+        // Known once counted, for a remote service.
+        let context_length = infer.context_length();
+        let limit = context_length - reply_reserve(context_length);
+        if context_length == 0 || prompt_tokens <= limit {
+            if dropped > 0 {
+                info!(
+                    dropped,
+                    prompt_tokens, limit, "trimmed history to fit the context"
+                );
+            }
+            return Ok(prompt);
+        }
+        if !drop_oldest(history) {
+            return Err(ContextOverflow {
+                limit,
+                context_length,
+                prompt_tokens,
+            });
+        }
+        dropped += 1;
+    }
+}
+
+/// What the next summary has to fold in.
+struct CompactionPlan<'a> {
+    previous: Option<&'a Message>,
+    turns: Vec<&'a Message>,
+}
+
+fn plan(
+    history: &[Message],
+    window: usize,
+    after: usize,
+    every: usize,
+) -> Option<CompactionPlan<'_>> {
+    let previous = history.iter().rev().find(|m| m.role == LONG_SUMMARY_ROLE);
+    let messages: Vec<&Message> = history
+        .iter()
+        .filter(|m| m.role != LONG_SUMMARY_ROLE)
+        .collect();
+    if after == 0 || messages.len() <= after.max(window) {
+        return None;
+    }
+    let pruned = &messages[..messages.len() - window];
+
+    // Turns after the last one the previous summary covered.
+    let through_id = previous
+        .and_then(|m| m.meta.as_ref())
+        .and_then(|meta| meta.get("through_id"))
+        .and_then(|id| id.as_str());
+    let start = through_id
+        .and_then(|id| pruned.iter().position(|m| m.id == id))
+        .map_or(0, |idx| idx + 1);
+    let turns: Vec<&Message> = pruned[start..]
+        .iter()
+        .copied()
+        .filter(|m| is_turn(m))
+        .collect();
+
+    let due = match previous {
+        None => !turns.is_empty(),
+        Some(_) => turns.len() >= every,
+    };
+    due.then_some(CompactionPlan { previous, turns })
+}
+
+/// True when the chat has pruned turns worth (re)summarizing.
+pub fn needs_compaction(history: &[Message]) -> bool {
+    plan(
+        history,
+        HISTORY_WINDOW,
+        compaction_after(),
+        compaction_every(),
+    )
+    .is_some()
+}
+
+fn compaction_prompt(chat_id: &str, plan: &CompactionPlan<'_>) -> String {
+    let mut body = String::from("Current summary:\n");
+    match plan.previous.and_then(|m| m.text.as_deref()) {
+        Some(text) => body.push_str(text.trim()),
+        None => body.push_str("(none yet)"),
+    }
+    body.push_str("\n\nNew messages:\n");
+    for message in &plan.turns {
+        let speaker = if message.role == "user" {
+            "User"
+        } else {
+            "Assistant"
+        };
+        let text: String = message
+            .text
+            .as_deref()
+            .unwrap_or_default()
+            .trim()
+            .chars()
+            .take(MAX_QUOTED_CHARS)
+            .collect();
+        body.push_str(&format!("{speaker}: {text}\n"));
+    }
+
+    let request = Message {
+        id: Uuid::new_v4().to_string(),
+        chat_id: chat_id.to_string(),
+        session_id: None,
+        user_id: None,
+        device_hash: None,
+        role: "user".into(),
+        text: Some(body),
+        language: None,
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: chrono::Utc::now().timestamp(),
+        meta: None,
+    };
+    build_mistral_prompt(&[request], Some(COMPACTION_PROMPT))
+}
+
+/// Fold the chat's pruned turns into its long summary. Returns whether a
+/// new summary was stored.
+pub async fn compact_chat(db: &DBLayer, infer: &InferenceService, chat_id: &str) -> Result<bool> {
+    let history = db.list_messages_for_chat(chat_id).await?;
+    let Some(plan) = plan(
+        &history,
+        HISTORY_WINDOW,
+        compaction_after(),
+        compaction_every(),
+    ) else {
+        return Ok(false);
+    };
+    let Some(last) = plan.turns.last() else {
+        return Ok(false);
+    };
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let raw = infer
+        .generate_completion(compaction_prompt(chat_id, &plan), cancel)
+        .await?;
+    let summary = strip_chatml_markers(trim_partial_chatml(&raw))
+        .trim()
+        .to_string();
+    if summary.is_empty() {
+        return Ok(false);
+    }
+
+    let covered = plan
+        .previous
+        .and_then(|m| m.meta.as_ref())
+        .and_then(|meta| meta.get("covered"))
+        .and_then(|n| n.as_u64())
+        .unwrap_or(0)
+        + plan.turns.len() as u64;
+    let language = history
+        .iter()
+        .rev()
+        .filter_map(|m| m.language.clone())
+        .find(|lang| !lang.trim().is_empty());
+    let message = Message {
+        id: Uuid::new_v4().to_string(),
+        chat_id: chat_id.to_string(),
+        session_id: None,
+        user_id: None,
+        device_hash: None,
+        role: LONG_SUMMARY_ROLE.into(),
+        text: Some(summary),
+        language,
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: chrono::Utc::now().timestamp(),
+        meta: Some(json!({
+            "through_id": last.id,
+            "through_ts": last.ts,
+            "covered": covered,
+        })),
+    };
+
+    db.remove_messages_by_role(chat_id, LONG_SUMMARY_ROLE)
+        .await?;
+    db.save_message(&message).await?;
+    info!(chat_id, covered, "long summary updated");
+    Ok(true)
+}
+
+/// Run `compact_chat` in the background, at most once per chat at a time.
+pub fn spawn_compaction(db: Arc<DBLayer>, infer: Arc<InferenceService>, chat_id: String) {
+    if !IN_FLIGHT.lock().unwrap().insert(chat_id.clone()) {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(err) = compact_chat(&db, &infer, &chat_id).await {
+            warn!(chat_id = chat_id.as_str(), "chat compaction failed: {err}");
+        }
+        IN_FLIGHT.lock().unwrap().remove(&chat_id);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: usize, role: &str, meta: Option<serde_json::Value>) -> Message {
+        Message {
+            id: format!("m{id}"),
+            chat_id: "c".into(),
+            session_id: None,
+            user_id: None,
+            device_hash: None,
+            role: role.into(),
+            text: Some(format!("text {id}")),
+            language: None,
+            attachments: Vec::new(),
+            liked: false,
+            feedback: None,
+            ts: id as i64,
+            meta,
+        }
+    }
+
+    fn chat(turns: usize) -> Vec<Message> {
+        (0..turns)
+            .map(|i| message(i, if i % 2 == 0 { "user" } else { "assistant" }, None))
+            .collect()
+    }
+
+    #[test]
+    fn plans_only_uncovered_pruned_turns() {
+        let history = chat(8);
+        assert!(plan(&history, 4, 8, 2).is_none());
+
+        let history = chat(10);
+        let first = plan(&history, 4, 8, 2).unwrap();
+        assert_eq!(first.turns.len(), 6);
+
+        let mut history = chat(11);
+        history.push(message(
+            99,
+            LONG_SUMMARY_ROLE,
+            Some(json!({ "through_id": "m5" })),
+        ));
+        // m6 is the only new pruned turn; the refresh waits for two.
+        assert!(plan(&history, 4, 8, 2).is_none());
+        history.insert(11, message(11, "assistant", None));
+        let refresh = plan(&history, 4, 8, 2).unwrap();
+        let ids: Vec<&str> = refresh.turns.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["m6", "m7"]);
+    }
+
+    #[test]
+    fn long_summary_stands_in_for_pruned_history() {
+        let mut history = chat(6);
+        history.push(message(99, LONG_SUMMARY_ROLE, None));
+
+        let trimmed = compact_history(history.clone(), 4);
+        assert_eq!(trimmed.len(), 5);
+        assert_eq!(trimmed[0].role, LONG_SUMMARY_ROLE);
+        assert_eq!(trimmed[1].id, "m2");
+
+        let full = compact_history(history, 10);
+        assert!(full.iter().all(|m| m.role != LONG_SUMMARY_ROLE));
+    }
+
+    #[test]
+    fn drops_oldest_turns_before_the_summary() {
+        let mut history = chat(5);
+        history.insert(0, message(99, LONG_SUMMARY_ROLE, None));
+
+        assert!(drop_oldest(&mut history));
+        let ids: Vec<&str> = history.iter().map(|m| m.id.as_str()).collect();
+        // m1 is an assistant reply to the dropped prompt.
+        assert_eq!(ids, vec!["m99", "m2", "m3", "m4"]);
+
+        while history.len() > 2 {
+            assert!(drop_oldest(&mut history));
+        }
+        assert!(drop_oldest(&mut history));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].id, "m4");
+        assert!(!drop_oldest(&mut history));
+    }
+
+    #[test]
+    fn window_start_moves_in_steps() {
+        let first_id = |turns: usize| compact_history(chat(turns), HISTORY_WINDOW)[0].id.clone();
+        assert_eq!(first_id(HISTORY_WINDOW), "m0");
+        // Same first message, so the same cached prefix, for several turns.
+        assert_eq!(first_id(HISTORY_WINDOW + 2), "m0");
+        assert_eq!(first_id(HISTORY_WINDOW + 7), "m0");
+        assert_eq!(first_id(HISTORY_WINDOW + 8), "m8");
+        assert_eq!(first_id(HISTORY_WINDOW + 15), "m8");
+        assert!(
+            compact_history(chat(HISTORY_WINDOW + 15), HISTORY_WINDOW).len() < HISTORY_WINDOW + 8
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::model::chat::{ChatLanguage, LanguageChange};
+
+const DEFAULT_SWITCH_AFTER: u32 = 2;
+
+const EN_WORDS: &[&str] = &[
+    "the", "and", "is", "are", "you", "what", "how", "with", "this", "that", "can", "please",
+    "have", "for", "my", "it", "do", "not",
+];
+const ES_WORDS: &[&str] = &[
+    "el", "la", "los", "las", "que", "es", "por", "para", "con", "una", "como", "qué", "cómo",
+    "pero", "está", "hola", "gracias", "puedes", "mi", "y",
+];
+const PT_WORDS: &[&str] = &[
+    "o", "os", "as", "que", "é", "não", "para", "com", "uma", "como", "você", "obrigado",
+    "obrigada", "olá", "está", "meu", "minha", "isso", "e",
+];
+
+/// Outcome of feeding one user turn into the chat's language state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanguageTransition {
+    Unchanged,
+    /// First turn fixed the chat language.
+    Locked(String),
+    /// A different language showed up; the chat keeps its language for now.
+    Suggested {
+        from: String,
+        to: String,
+    },
+    /// The chat switched after repeated turns in the new language.
+    Switched {
+        from: String,
+        to: String,
+    },
+}
+
+/// Consecutive turns in a new language before the chat follows it.
+/// `LANGUAGE_SWITCH_AFTER=0` turns automatic switching off, leaving the
+/// switch to an explicit `set_language` from the client.
+pub fn switch_after() -> u32 {
+    std::env::var("LANGUAGE_SWITCH_AFTER")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_SWITCH_AFTER)
+}
+
+pub fn normalize(language: &str) -> Option<String> {
+    let code = language
+        .split(|c| c == '-' || c == '_')
+        .next()
+        .map(|part| part.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    (!code.is_empty()).then_some(code)
+}
+
+/// Language of a user turn. Function words settle the common en/es/pt
+/// cases; everything else goes through the language-ID model
+/// (`crate::classifier::language`). Short or ambiguous text yields `None`.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    function_word_guess(text)
+        .or_else(|| crate::classifier::language::identify(text).map(|guess| guess.language))
+}
+
+/// Only answers when the text is clearly Cyrillic or has enough function
+/// words of one Latin language.
+fn function_word_guess(text: &str) -> Option<&'static str> {
+    let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+    if letters == 0 {
+        return None;
+    }
+    let cyrillic = text
+        .chars()
+        .filter(|c| matches!(*c, '\u{0400}'..='\u{04FF}'))
+        .count();
+    if cyrillic * 2 > letters {
+        // Russian, Ukrainian, Bulgarian… are told apart by the model.
+        return crate::classifier::language::identify(text)
+            .map(|guess| guess.language)
+            .or(Some("ru"));
+    }
+
+    let lowered = text.to_lowercase();
+    let words: Vec<&str> = lowered
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let hits = |list: &[&str]| words.iter().filter(|w| list.contains(w)).count();
+
+    let mut scores = [
+        ("en", hits(EN_WORDS)),
+        ("es", hits(ES_WORDS)),
+        ("pt", hits(PT_WORDS)),
+    ];
+    scores.sort_by(|a, b| b.1.cmp(&a.1));
+    let (best, best_hits) = scores[0];
+    if best_hits >= 2 && best_hits > scores[1].1 {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+/// Feed one user turn. `detected` is the language of the text (if it could
+/// be told), `hint` the client's UI language, used only for the first turn.
+pub fn observe(
+    state: &mut ChatLanguage,
+    detected: Option<&str>,
+    hint: Option<&str>,
+    switch_after: u32,
+    now: i64,
+) -> LanguageTransition {
+    let Some(locked) = state.locked.clone() else {
+        let initial = detected
+            .map(str::to_string)
+            .or_else(|| hint.and_then(normalize))
+            .unwrap_or_else(|| "en".to_string());
+        lock(state, initial.clone(), "initial", now);
+        return LanguageTransition::Locked(initial);
+    };
+
+    let Some(detected) = detected else {
+        return LanguageTransition::Unchanged;
+    };
+    if detected == locked {
+        state.pending = None;
+        state.pending_count = 0;
+        return LanguageTransition::Unchanged;
+    }
+
+    if state.pending.as_deref() == Some(detected) {
+        state.pending_count += 1;
+    } else {
+        state.pending = Some(detected.to_string());
+        state.pending_count = 1;
+    }
+
+    if switch_after > 0 && !state.pinned && state.pending_count >= switch_after {
+        lock(state, detected.to_string(), "auto", now);
+        LanguageTransition::Switched {
+            from: locked,
+            to: detected.to_string(),
+        }
+    } else {
+        LanguageTransition::Suggested {
+            from: locked,
+            to: detected.to_string(),
+        }
+    }
+}
+
+/// Pin the chat to a language the user chose, so it no longer switches on
+/// its own. `None` unpins it and keeps the current language.
+pub fn pin(state: &mut ChatLanguage, language: Option<String>, now: i64) {
+    match language {
+        Some(language) => {
+            lock(state, language, "user", now);
+            state.pinned = true;
+        }
+        None => state.pinned = false,
+    }
+}
+
+/// Fix the chat language, recording the change unless it is a no-op.
+pub fn lock(state: &mut ChatLanguage, language: String, reason: &str, now: i64) {
+    state.pending = None;
+    state.pending_count = 0;
+    if state.locked.as_deref() == Some(language.as_str()) {
+        return;
+    }
+    state.history.push(LanguageChange {
+        from: state.locked.take(),
+        to: language.clone(),
+        reason: reason.to_string(),
+        ts: now,
+    });
+    state.locked = Some(language);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_after_repeated_turns_only() {
+        let mut state = ChatLanguage::default();
+        assert_eq!(
+            observe(&mut state, None, Some("es-ES"), 2, 1),
+            LanguageTransition::Locked("es".into())
+        );
+        assert_eq!(
+            observe(&mut state, Some("en"), None, 2, 2),
+            LanguageTransition::Suggested {
+                from: "es".into(),
+                to: "en".into()
+            }
+        );
+        // Back to Spanish resets the pending switch.
+        observe(&mut state, Some("es"), None, 2, 3);
+        assert_eq!(state.pending, None);
+
+        observe(&mut state, Some("en"), None, 2, 4);
+        assert_eq!(
+            observe(&mut state, Some("en"), None, 2, 5),
+            LanguageTransition::Switched {
+                from: "es".into(),
+                to: "en".into()
+            }
+        );
+        assert_eq!(state.locked.as_deref(), Some("en"));
+        assert_eq!(state.history.len(), 2);
+    }
+
+    #[test]
+    fn pinned_language_only_suggests() {
+        let mut state = ChatLanguage::default();
+        observe(&mut state, Some("ru"), None, 2, 1);
+        pin(&mut state, Some("ru".into()), 2);
+        for ts in 3..6 {
+            assert!(matches!(
+                observe(&mut state, Some("en"), Some("en"), 2, ts),
+                LanguageTransition::Suggested { .. }
+            ));
+        }
+        assert_eq!(state.locked.as_deref(), Some("ru"));
+
+        pin(&mut state, None, 6);
+        assert!(matches!(
+            observe(&mut state, Some("en"), None, 2, 7),
+            LanguageTransition::Switched { .. }
+        ));
+    }
+
+    #[test]
+    fn detects_clear_cases() {
+        assert_eq!(detect_language("Привет, как дела?"), Some("ru"));
+        assert_eq!(detect_language("what is the weather like"), Some("en"));
+        assert_eq!(detect_language("hola, ¿cómo está el clima?"), Some("es"));
+        assert_eq!(detect_language("ok"), None);
+    }
+}
@@ -1,4 +1,14 @@
-use crate::{attachments::message_attachment_summaries, model::message::Message};
+pub mod compaction;
+pub mod language;
+pub mod postprocess;
+pub mod summary_drift;
+
+use crate::{
+    attachments::{
+        message_attachment_context, message_attachment_summaries, prompt_document_chars,
+    },
+    model::message::Message,
+};
 use minijinja::Environment;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
@@ -76,6 +86,32 @@ pub fn build_mistral_prompt(history: &[Message], system_prompt: Option<&str>) ->
         .unwrap_or_else(|err| panic!("chat template rendering failed: {err}"))
 }
 
+/// Longest accepted per-chat or per-user system prompt override.
+pub const MAX_SYSTEM_PROMPT_OVERRIDE_CHARS: usize = 4000;
+
+/// Intent-selected system prompt followed by the user's own instructions:
+/// the per-user ones first, then the per-chat ones, which win on conflict.
+pub fn merge_system_prompt(
+    intent_prompt: &str,
+    user_override: Option<&str>,
+    chat_override: Option<&str>,
+) -> String {
+    let overrides: Vec<&str> = [user_override, chat_override]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if overrides.is_empty() {
+        return intent_prompt.to_string();
+    }
+    format!(
+        "{}\n\nInstructions from the user for this conversation (follow them unless they conflict with the rules above; later ones take precedence):\n{}",
+        intent_prompt.trim_end(),
+        overrides.join("\n\n")
+    )
+}
+
 pub fn trim_history(mut history: Vec<Message>, max_messages: usize) -> Vec<Message> {
     if history.len() <= max_messages {
         return history;
@@ -104,6 +140,17 @@ pub fn strip_chatml_markers(text: &str) -> String {
         .replace("<|im_end|>", "")
 }
 
+/// [`STOP_SEQS`] followed by `extra`, without duplicates or empty strings.
+pub fn stop_sequences<I: IntoIterator<Item = String>>(extra: I) -> Vec<String> {
+    let mut stops: Vec<String> = STOP_SEQS.iter().map(|s| s.to_string()).collect();
+    for stop in extra {
+        if !stop.is_empty() && !stops.contains(&stop) {
+            stops.push(stop);
+        }
+    }
+    stops
+}
+
 pub fn trim_partial_chatml(text: &str) -> &str {
     let mut end = STOP_SEQS
         .iter()
@@ -231,9 +278,28 @@ fn render_with_template(
 fn template_messages(history: &[Message], system_prompt: Option<&str>) -> Vec<TemplateMessage> {
     let mut messages = Vec::new();
 
-    if let Some(sys) = system_prompt.filter(|s| !s.trim().is_empty()) {
+    // A long summary in the history stands in for the turns pruned before
+    // it and rides along in the system prompt.
+    let long_summary = history
+        .iter()
+        .rev()
+        .find(|m| m.role == compaction::LONG_SUMMARY_ROLE)
+        .and_then(|m| m.text.as_deref())
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|summary| format!("Summary of the earlier part of this conversation:\n{summary}"));
+    let system = match (
+        system_prompt.map(str::trim).filter(|s| !s.is_empty()),
+        long_summary,
+    ) {
+        (Some(sys), Some(summary)) => Some(format!("{sys}\n\n{summary}")),
+        (Some(sys), None) => Some(sys.to_string()),
+        (None, summary) => summary,
+    };
+
+    if let Some(sys) = system {
         let context = MessageTemplateContext {
-            body: Some(sanitize_template_text(sys.trim())),
+            body: Some(sanitize_template_text(&sys)),
             attachments: Vec::new(),
         };
         messages.push(TemplateMessage {
@@ -242,7 +308,15 @@ fn template_messages(history: &[Message], system_prompt: Option<&str>) -> Vec<Te
         });
     }
 
-    for msg in history {
+    // Only the latest message carrying document text gets it in full; older
+    // ones fall back to their summaries to keep the context window for the
+    // conversation.
+    let document_idx = history
+        .iter()
+        .rposition(|msg| msg.attachments.iter().any(|a| !a.text_chunks.is_empty()));
+
+    for (idx, msg) in history.iter().enumerate() {
+        let with_documents = Some(idx) == document_idx;
         let role = match msg.role.as_str() {
             "user" => "user",
             "assistant" => "assistant",
@@ -251,7 +325,7 @@ fn template_messages(history: &[Message], system_prompt: Option<&str>) -> Vec<Te
         };
 
         if role == "system" {
-            let context = assemble_message_context(msg);
+            let context = assemble_message_context(msg, with_documents);
             if !context.is_empty() {
                 messages.push(TemplateMessage {
                     role: role.into(),
@@ -261,7 +335,7 @@ fn template_messages(history: &[Message], system_prompt: Option<&str>) -> Vec<Te
             continue;
         }
 
-        let context = assemble_message_context(msg);
+        let context = assemble_message_context(msg, with_documents);
         if context.is_empty() {
             continue;
         }
@@ -292,15 +366,20 @@ fn push_with_alternation(
     });
 }
 
-fn assemble_message_context(msg: &Message) -> MessageTemplateContext {
-    let body = msg
-        .text
-        .as_deref()
-        .map(str::trim)
-        .filter(|s| !s.is_empty())
-        .map(sanitize_template_text);
+fn assemble_message_context(msg: &Message, with_documents: bool) -> MessageTemplateContext {
+    let text = msg.text.as_deref().map(str::trim).filter(|s| !s.is_empty());
+    let body = text.map(sanitize_template_text);
 
-    let attachments = message_attachment_summaries(&msg.attachments)
+    let notes = if with_documents {
+        message_attachment_context(
+            &msg.attachments,
+            text.unwrap_or_default(),
+            prompt_document_chars(),
+        )
+    } else {
+        message_attachment_summaries(&msg.attachments)
+    };
+    let attachments = notes
         .into_iter()
         .map(|a| sanitize_template_text(&a))
         .collect();
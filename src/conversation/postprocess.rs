@@ -0,0 +1,355 @@
+//! Clean-up applied to every finished model reply, whichever backend
+//! produced it: a list of [`OutputFilter`]s run in order, configured with
+//! `OUTPUT_FILTERS` (default `chatml,token_text`).
+
+use std::fs;
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use tracing::warn;
+
+use super::{strip_chatml_markers, trim_partial_chatml};
+use crate::inference::byte_decoder::{normalize_token_text, tidy_decoded_text};
+
+const DEFAULT_FILTERS: &str = "chatml,token_text";
+const DEFAULT_PROFANITY_FILE: &str = "config/profanity.txt";
+
+static URL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"https?://[^\s<>()\[\]"'`]+"#).expect("valid url regex"));
+
+pub trait OutputFilter: Send + Sync {
+    /// Name used in `OUTPUT_FILTERS`.
+    fn name(&self) -> &'static str;
+
+    fn apply(&self, text: &str) -> String;
+
+    /// Whether the filter changes what the model said rather than only
+    /// removing template and tokenizer artifacts. Streamed tokens are not
+    /// filtered, so clients need the final text when this is set.
+    fn rewrites(&self) -> bool {
+        true
+    }
+}
+
+/// Cuts the reply at the first stop sequence, drops chat template markers
+/// and surrounding whitespace.
+pub struct ChatmlMarkers;
+
+impl OutputFilter for ChatmlMarkers {
+    fn name(&self) -> &'static str {
+        "chatml"
+    }
+
+    fn apply(&self, text: &str) -> String {
+        strip_chatml_markers(trim_partial_chatml(text))
+            .trim()
+            .to_string()
+    }
+
+    fn rewrites(&self) -> bool {
+        false
+    }
+}
+
+/// Turns tokenizer space markers (`Ġ`, `▁`) into spaces and drops
+/// zero-width spaces.
+pub struct TokenText;
+
+impl OutputFilter for TokenText {
+    fn name(&self) -> &'static str {
+        "token_text"
+    }
+
+    fn apply(&self, text: &str) -> String {
+        tidy_decoded_text(text)
+    }
+
+    fn rewrites(&self) -> bool {
+        false
+    }
+}
+
+/// [`TokenText`] plus decoding of byte-fallback tokens (`ðŁĺĬ` → 😊), for
+/// models whose detokenizer leaves them in.
+pub struct ByteFallback;
+
+impl OutputFilter for ByteFallback {
+    fn name(&self) -> &'static str {
+        "byte_fallback"
+    }
+
+    fn apply(&self, text: &str) -> String {
+        normalize_token_text(text)
+    }
+
+    fn rewrites(&self) -> bool {
+        false
+    }
+}
+
+/// Replaces listed words, matched whole and case-insensitively, with one
+/// `*` per character.
+pub struct ProfanityMask {
+    pattern: Option<Regex>,
+}
+
+impl ProfanityMask {
+    pub fn new<I, S>(words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let words: Vec<String> = words
+            .into_iter()
+            .map(|w| w.as_ref().trim().to_string())
+            .filter(|w| !w.is_empty() && !w.starts_with('#'))
+            .map(|w| regex::escape(&w))
+            .collect();
+        let pattern = (!words.is_empty())
+            .then(|| Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|"))).ok())
+            .flatten();
+        Self { pattern }
+    }
+
+    /// One word per line from `PROFANITY_WORDS_FILE` (default
+    /// `config/profanity.txt`); `#` starts a comment line.
+    pub fn from_env() -> Self {
+        let path = std::env::var("PROFANITY_WORDS_FILE")
+            .unwrap_or_else(|_| DEFAULT_PROFANITY_FILE.to_string());
+        match fs::read_to_string(&path) {
+            Ok(list) => Self::new(list.lines()),
+            Err(err) => {
+                warn!("profanity filter enabled but {path} is unreadable: {err}");
+                Self::new(Vec::<String>::new())
+            }
+        }
+    }
+}
+
+impl OutputFilter for ProfanityMask {
+    fn name(&self) -> &'static str {
+        "profanity"
+    }
+
+    fn apply(&self, text: &str) -> String {
+        match &self.pattern {
+            Some(pattern) => pattern
+                .replace_all(text, |caps: &Captures| "*".repeat(caps[0].chars().count()))
+                .into_owned(),
+            None => text.to_string(),
+        }
+    }
+}
+
+/// Rewrites URL prefixes, e.g. internal hosts to their public names.
+pub struct LinkRewrite {
+    rules: Vec<(String, String)>,
+}
+
+impl LinkRewrite {
+    pub fn new(rules: Vec<(String, String)>) -> Self {
+        Self { rules }
+    }
+
+    /// `OUTPUT_LINK_REWRITES=from=>to;from=>to`; the first matching prefix
+    /// wins.
+    pub fn from_env() -> Self {
+        let spec = std::env::var("OUTPUT_LINK_REWRITES").unwrap_or_default();
+        Self::new(parse_link_rewrites(&spec))
+    }
+}
+
+fn parse_link_rewrites(spec: &str) -> Vec<(String, String)> {
+    spec.split(';')
+        .filter_map(|rule| {
+            let (from, to) = rule.split_once("=>")?;
+            let from = from.trim();
+            (!from.is_empty()).then(|| (from.to_string(), to.trim().to_string()))
+        })
+        .collect()
+}
+
+impl OutputFilter for LinkRewrite {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    fn apply(&self, text: &str) -> String {
+        if self.rules.is_empty() {
+            return text.to_string();
+        }
+        URL.replace_all(text, |caps: &Captures| {
+            let url = &caps[0];
+            self.rules
+                .iter()
+                .find_map(|(from, to)| {
+                    url.strip_prefix(from.as_str())
+                        .map(|rest| to.clone() + rest)
+                })
+                .unwrap_or_else(|| url.to_string())
+        })
+        .into_owned()
+    }
+}
+
+/// Outside code blocks: `\r\n` becomes `\n`, runs of blank lines become
+/// one, and `*`/`+` bullets become `-`. An unclosed code block is closed.
+pub struct MarkdownNormalize;
+
+impl OutputFilter for MarkdownNormalize {
+    fn name(&self) -> &'static str {
+        "markdown"
+    }
+
+    fn apply(&self, text: &str) -> String {
+        let text = text.replace("\r\n", "\n");
+        let mut lines: Vec<String> = Vec::new();
+        let mut in_fence = false;
+        let mut blank = false;
+        for line in text.split('\n') {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") {
+                in_fence = !in_fence;
+                blank = false;
+                lines.push(line.trim_end().to_string());
+                continue;
+            }
+            if in_fence {
+                lines.push(line.to_string());
+                continue;
+            }
+            if trimmed.is_empty() {
+                if !blank {
+                    lines.push(String::new());
+                }
+                blank = true;
+                continue;
+            }
+            blank = false;
+            let indent = &line[..line.len() - trimmed.len()];
+            // `* * *` is a rule, not a list.
+            let rule = trimmed.chars().all(|c| c == '*' || c.is_whitespace());
+            match trimmed
+                .strip_prefix("* ")
+                .or_else(|| trimmed.strip_prefix("+ "))
+            {
+                Some(item) if !rule => lines.push(format!("{indent}- {item}")),
+                _ => lines.push(line.to_string()),
+            }
+        }
+        if in_fence {
+            lines.push("```".to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+pub struct OutputPipeline {
+    filters: Vec<Box<dyn OutputFilter>>,
+}
+
+impl OutputPipeline {
+    pub fn new(filters: Vec<Box<dyn OutputFilter>>) -> Self {
+        Self { filters }
+    }
+
+    /// Filters named in a comma-separated list, in that order. Unknown
+    /// names are skipped with a warning.
+    pub fn from_names(spec: &str) -> Self {
+        let filters = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| -> Option<Box<dyn OutputFilter>> {
+                match name {
+                    "chatml" => Some(Box::new(ChatmlMarkers)),
+                    "token_text" => Some(Box::new(TokenText)),
+                    "byte_fallback" => Some(Box::new(ByteFallback)),
+                    "profanity" => Some(Box::new(ProfanityMask::from_env())),
+                    "links" => Some(Box::new(LinkRewrite::from_env())),
+                    "markdown" => Some(Box::new(MarkdownNormalize)),
+                    other => {
+                        warn!("ignoring unknown output filter {other:?}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self::new(filters)
+    }
+
+    pub fn from_env() -> Self {
+        let spec = std::env::var("OUTPUT_FILTERS").unwrap_or_else(|_| DEFAULT_FILTERS.to_string());
+        Self::from_names(&spec)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.filters.iter().map(|f| f.name()).collect()
+    }
+
+    /// See [`OutputFilter::rewrites`].
+    pub fn rewrites(&self) -> bool {
+        self.filters.iter().any(|f| f.rewrites())
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.filters
+            .iter()
+            .fold(text.to_string(), |text, filter| filter.apply(&text))
+    }
+}
+
+static PIPELINE: Lazy<OutputPipeline> = Lazy::new(OutputPipeline::from_env);
+
+/// The pipeline configured by `OUTPUT_FILTERS`.
+pub fn pipeline() -> &'static OutputPipeline {
+    &PIPELINE
+}
+
+/// A finished reply as users should see it, without surrounding
+/// whitespace.
+pub fn clean_output(raw: &str) -> String {
+    PIPELINE.apply(raw).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_pipeline_strips_template_artifacts() {
+        let pipeline = OutputPipeline::from_names(DEFAULT_FILTERS);
+        assert_eq!(pipeline.names(), ["chatml", "token_text"]);
+        assert!(!pipeline.rewrites());
+        assert_eq!(
+            pipeline.apply(" Hello\u{2581}there[/INST]</s>ignored"),
+            "Hello there"
+        );
+    }
+
+    #[test]
+    fn masks_whole_words_only() {
+        let mask = ProfanityMask::new(["darn", "# comment"]);
+        assert_eq!(mask.apply("Darn, darned darn."), "****, darned ****.");
+    }
+
+    #[test]
+    fn rewrites_link_prefixes() {
+        let links = LinkRewrite::new(parse_link_rewrites(
+            "http://docs.internal/=>https://docs.example.com/; bad",
+        ));
+        assert_eq!(
+            links.apply("See (http://docs.internal/a?b=1) or http://other/x."),
+            "See (https://docs.example.com/a?b=1) or http://other/x."
+        );
+    }
+
+    #[test]
+    fn normalizes_markdown_outside_code() {
+        let text = "* one\r\n+ two\n\n\n\n* * *\n```\n* code\n\n\n";
+        assert_eq!(
+            MarkdownNormalize.apply(text),
+            "- one\n- two\n\n* * *\n```\n* code\n\n\n\n```"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Topic drift of the chat title. The `summary` message is written after
+//! the first exchange; every `SUMMARY_DRIFT_EVERY` user turns after it, the
+//! summary and those turns are embedded, and a cosine distance above
+//! `SUMMARY_DRIFT_THRESHOLD` means the chat moved on and the summary is
+//! rewritten.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+use crate::inference::intent_router::RobertaIntentRouter;
+use crate::model::message::Message;
+use crate::vector::cosine_similarity;
+
+const DEFAULT_DRIFT_EVERY: usize = 4;
+const DEFAULT_DRIFT_THRESHOLD: f32 = 0.5;
+/// Longest user turn quoted into the drift sample.
+const MAX_QUOTED_CHARS: usize = 500;
+
+/// User turns after the summary between drift checks
+/// (`SUMMARY_DRIFT_EVERY`, default 4; `0` disables the check).
+fn drift_every() -> usize {
+    std::env::var("SUMMARY_DRIFT_EVERY")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_DRIFT_EVERY)
+}
+
+/// Cosine distance that counts as drift (`SUMMARY_DRIFT_THRESHOLD`,
+/// default 0.5).
+pub fn drift_threshold() -> f32 {
+    std::env::var("SUMMARY_DRIFT_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|v| (0.0..=2.0).contains(v))
+        .unwrap_or(DEFAULT_DRIFT_THRESHOLD)
+}
+
+/// The current summary and the text of the user turns to compare it with,
+/// when a check is due: there is a summary and the number of user turns
+/// after it is a positive multiple of `every`.
+pub fn drift_sample(history: &[Message], every: usize) -> Option<(&Message, String)> {
+    if every == 0 {
+        return None;
+    }
+    let summary = history.iter().rev().find(|m| m.role == "summary")?;
+    let since: Vec<&str> = history
+        .iter()
+        .filter(|m| m.role == "user" && m.ts > summary.ts)
+        .filter_map(|m| m.text.as_deref())
+        .filter(|text| !text.trim().is_empty())
+        .collect();
+    if since.is_empty() || since.len() % every != 0 {
+        return None;
+    }
+    let recent = since[since.len() - every..]
+        .iter()
+        .map(|text| text.chars().take(MAX_QUOTED_CHARS).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some((summary, recent))
+}
+
+/// Cosine distance between the summary and the recent turns, or `None`
+/// when no check is due.
+pub async fn summary_drift(
+    history: &[Message],
+    encoder: Arc<RobertaIntentRouter>,
+) -> Result<Option<f32>> {
+    let Some((summary, recent)) = drift_sample(history, drift_every()) else {
+        return Ok(None);
+    };
+    let Some(title) = summary.text.clone().filter(|t| !t.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let input = vec![title, recent];
+    let embedded = tokio::task::spawn_blocking(move || encoder.embed_batch(&input)).await??;
+    let [title, recent] = &embedded[..] else {
+        return Err(anyhow!("expected two embeddings, got {}", embedded.len()));
+    };
+    Ok(Some(1.0 - cosine_similarity(&title.vector, &recent.vector)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ts: i64, role: &str) -> Message {
+        Message {
+            id: format!("m{ts}"),
+            chat_id: "c".into(),
+            session_id: None,
+            user_id: None,
+            device_hash: None,
+            role: role.into(),
+            text: Some(format!("text {ts}")),
+            language: None,
+            attachments: Vec::new(),
+            liked: false,
+            feedback: None,
+            ts,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn samples_every_few_turns_after_the_summary() {
+        let mut history = vec![
+            message(0, "user"),
+            message(1, "assistant"),
+            message(2, "summary"),
+        ];
+        assert!(drift_sample(&history, 2).is_none());
+
+        history.push(message(3, "user"));
+        history.push(message(4, "assistant"));
+        assert!(drift_sample(&history, 2).is_none());
+
+        history.push(message(5, "user"));
+        let (summary, recent) = drift_sample(&history, 2).unwrap();
+        assert_eq!(summary.id, "m2");
+        assert_eq!(recent, "text 3\ntext 5");
+
+        history.push(message(6, "user"));
+        assert!(drift_sample(&history, 2).is_none());
+        assert!(drift_sample(&history, 0).is_none());
+    }
+}
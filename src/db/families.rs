@@ -0,0 +1,241 @@
+//! Column families. Each kind of record lives in its own family with
+//! options that suit how it is read: messages get a per-chat prefix
+//! extractor, embeddings stay uncompressed, and expired tokens and cached
+//! reasoning results are dropped during compaction. [`Store`](super::persistence::Store)
+//! picks the family from the key prefix, so callers keep using plain keys.
+
+use rocksdb::{
+    compaction_filter::Decision, BlockBasedOptions, DBCompressionType, Options, SliceTransform,
+};
+use serde::{Deserialize, Serialize};
+
+use super::tuning::DbTuning;
+
+const MESSAGE_MARKER: &[u8] = b":msg:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Default,
+    Messages,
+    Chats,
+    Users,
+    Indexes,
+    Embeddings,
+    Counters,
+    /// Records that expire: revoked access tokens and cached reasoning.
+    Transient,
+}
+
+/// Key prefixes of every family but [`Family::Default`]. The first match
+/// wins, so `chat:meta:` comes before the message keys' `chat:`.
+const ROUTES: &[(&str, Family)] = &[
+    ("chat:meta:", Family::Chats),
+    ("chat:", Family::Messages),
+    ("user:", Family::Users),
+    ("user_device:", Family::Users),
+    ("device_lookup:", Family::Users),
+    ("device_alias:", Family::Users),
+    ("user_chat:", Family::Indexes),
+    ("user_chat_index:", Family::Indexes),
+    ("device_chat:", Family::Indexes),
+    ("device_chat_index:", Family::Indexes),
+    ("search:", Family::Indexes),
+    ("search_index:", Family::Indexes),
+    ("moderation_chat:", Family::Indexes),
+    ("vector:", Family::Embeddings),
+    ("counter:", Family::Counters),
+    ("storage_usage:", Family::Counters),
+    ("stats:", Family::Counters),
+    ("revoked_jti:", Family::Transient),
+    ("reasoning_cache:", Family::Transient),
+];
+
+impl Family {
+    pub const ALL: [Family; 8] = [
+        Family::Default,
+        Family::Messages,
+        Family::Chats,
+        Family::Users,
+        Family::Indexes,
+        Family::Embeddings,
+        Family::Counters,
+        Family::Transient,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::Default => rocksdb::DEFAULT_COLUMN_FAMILY_NAME,
+            Family::Messages => "messages",
+            Family::Chats => "chats",
+            Family::Users => "users",
+            Family::Indexes => "indexes",
+            Family::Embeddings => "embeddings",
+            Family::Counters => "counters",
+            Family::Transient => "transient",
+        }
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        Self::ALL.iter().map(|family| family.name()).collect()
+    }
+
+    /// The family a key belongs to.
+    pub fn of(key: &[u8]) -> Family {
+        ROUTES
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_bytes()))
+            .map_or(Family::Default, |(_, family)| *family)
+    }
+
+    /// RocksDB options tailored to the family; `tuning` from the
+    /// environment is applied on top, so `ROCKSDB_COMPRESSION[_<CF>]`
+    /// still wins.
+    pub(super) fn options(self, tuning: &DbTuning, reasoning_ttl: i64) -> Options {
+        let mut opts = Options::default();
+        match self {
+            Family::Default => {}
+            Family::Messages => {
+                // Every read is a scan over one chat's `chat:{id}:msg:`.
+                opts.set_prefix_extractor(SliceTransform::create(
+                    "chat_messages",
+                    message_prefix,
+                    Some(is_message_key),
+                ));
+                opts.set_memtable_prefix_bloom_ratio(0.1);
+                opts.set_block_based_table_factory(&bloom_filter());
+                opts.set_compression_type(DBCompressionType::Lz4);
+                opts.set_bottommost_compression_type(DBCompressionType::Zstd);
+            }
+            Family::Chats | Family::Users => {
+                opts.set_block_based_table_factory(&bloom_filter());
+                opts.set_compression_type(DBCompressionType::Lz4);
+            }
+            Family::Indexes => {
+                opts.set_compression_type(DBCompressionType::Lz4);
+            }
+            Family::Embeddings | Family::Counters => {
+                // Floats barely compress; counters are a few bytes each.
+                opts.set_compression_type(DBCompressionType::None);
+            }
+            Family::Transient => {
+                opts.set_compression_type(DBCompressionType::Lz4);
+                opts.set_compaction_filter(
+                    "transient_expiry",
+                    move |_level: u32, key: &[u8], value: &[u8]| {
+                        if expired(key, value, chrono::Utc::now().timestamp(), reasoning_ttl) {
+                            Decision::Remove
+                        } else {
+                            Decision::Keep
+                        }
+                    },
+                );
+            }
+        }
+        tuning.apply_cf(&mut opts, self.name());
+        opts
+    }
+}
+
+fn bloom_filter() -> BlockBasedOptions {
+    let mut table = BlockBasedOptions::default();
+    table.set_bloom_filter(10.0, false);
+    table
+}
+
+fn find_marker(key: &[u8]) -> Option<usize> {
+    key.windows(MESSAGE_MARKER.len())
+        .position(|window| window == MESSAGE_MARKER)
+}
+
+/// `chat:{chat_id}:msg:` of a message key.
+pub(super) fn message_prefix(key: &[u8]) -> &[u8] {
+    match find_marker(key) {
+        Some(pos) => &key[..pos + MESSAGE_MARKER.len()],
+        None => key,
+    }
+}
+
+pub(super) fn is_message_key(key: &[u8]) -> bool {
+    key.starts_with(b"chat:") && find_marker(key).is_some()
+}
+
+#[derive(Deserialize)]
+struct CreatedAt {
+    created_ts: i64,
+}
+
+/// Whether compaction may drop a transient record: a revoked access token
+/// past its expiry, or a reasoning result older than `reasoning_ttl`
+/// seconds. Records it cannot read are kept.
+pub(super) fn expired(key: &[u8], value: &[u8], now: i64, reasoning_ttl: i64) -> bool {
+    if key.starts_with(b"revoked_jti:") {
+        return std::str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .is_some_and(|expires_ts| expires_ts < now);
+    }
+    if key.starts_with(b"reasoning_cache:") {
+        return serde_json::from_slice::<CreatedAt>(value)
+            .is_ok_and(|cached| cached.created_ts + reasoning_ttl < now);
+    }
+    false
+}
+
+/// Size and compaction state of one column family. Backends without
+/// RocksDB's internals leave those fields empty.
+#[derive(Debug, Clone, Serialize)]
+pub struct FamilyStats {
+    pub name: &'static str,
+    pub estimated_keys: Option<u64>,
+    pub live_data_bytes: Option<u64>,
+    pub sst_files_bytes: Option<u64>,
+    pub memtable_bytes: Option<u64>,
+    /// SST file count for levels 0..=6.
+    pub files_per_level: Vec<u64>,
+    pub compaction_pending: bool,
+    pub pending_compaction_bytes: Option<u64>,
+    /// RocksDB's `cfstats` report: compaction and stall statistics.
+    pub compaction_stats: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_route_to_families_and_transient_records_expire() {
+        assert_eq!(Family::of(b"chat:meta:c1"), Family::Chats);
+        assert_eq!(
+            Family::of(b"chat:c1:msg:00000000000000000001:m1"),
+            Family::Messages
+        );
+        assert_eq!(Family::of(b"user_chat:u1:c1"), Family::Indexes);
+        assert_eq!(Family::of(b"user_chat_index:built"), Family::Indexes);
+        assert_eq!(Family::of(b"user:u1"), Family::Users);
+        assert_eq!(Family::of(b"storage_usage:user:u1"), Family::Counters);
+        assert_eq!(Family::of(b"webhook:w1"), Family::Default);
+        assert_eq!(Family::of(b"moderation_chat:c1:1:m1"), Family::Indexes);
+        assert_eq!(Family::of(b"moderation:1:m1"), Family::Default);
+
+        let key = b"chat:c1:msg:00000000000000000001:m1";
+        assert_eq!(message_prefix(key), b"chat:c1:msg:");
+        assert!(!is_message_key(b"chat:meta:c1"));
+
+        assert!(expired(b"revoked_jti:a", b"99", 100, 0));
+        assert!(!expired(b"revoked_jti:a", b"101", 100, 0));
+        assert!(expired(
+            b"reasoning_cache:k",
+            br#"{"created_ts":10}"#,
+            100,
+            60
+        ));
+        assert!(!expired(
+            b"reasoning_cache:k",
+            br#"{"created_ts":50}"#,
+            100,
+            60
+        ));
+        assert!(!expired(b"reasoning_cache:k", b"garbage", 100, 60));
+        assert!(!expired(b"webhook:w1", b"1", 100, 0));
+    }
+}
@@ -0,0 +1,269 @@
+//! Schema migrations, run in order at startup. `schema_version` holds the
+//! version of the last one applied and `schema_migration:{version}` a
+//! record of each, for `/internal/admin/db/migrations`. To change a key
+//! layout or backfill a field, append a [`Migration`] with the next
+//! version and handle it in [`apply`]; it runs once on every database.
+
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{normalize_message, DBLayer};
+use crate::model::{message::Message, upload::StoredFile};
+
+const VERSION_KEY: &str = "schema_version";
+const RECORD_PREFIX: &str = "schema_migration:";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Every migration, by ascending version with no gaps.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "user_chat_index",
+        description: "Index chats by owning user (`user_chat:`).",
+    },
+    Migration {
+        version: 2,
+        name: "device_chat_index",
+        description: "Index chats by device (`device_chat:`).",
+    },
+    Migration {
+        version: 3,
+        name: "search_index",
+        description: "Index message words for admin search (`search:`).",
+    },
+    Migration {
+        version: 4,
+        name: "message_stats",
+        description: "Count messages and likes per chat (`stats:`).",
+    },
+    Migration {
+        version: 5,
+        name: "normalize_message_text",
+        description: "Store message and attachment text in its tidied form.",
+    },
+    Migration {
+        version: 6,
+        name: "upload_device_alias",
+        description: "Move uploads of re-registered devices to their signed id.",
+    },
+    Migration {
+        version: 7,
+        name: "moderation_chat_index",
+        description: "Index moderation records by chat (`moderation_chat:`).",
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+fn record_key(version: u32) -> String {
+    format!("{RECORD_PREFIX}{version:06}")
+}
+
+/// A migration as it ran on this database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    pub applied_ts: i64,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaStatus {
+    pub schema_version: u32,
+    pub latest_version: u32,
+    pub applied: Vec<AppliedMigration>,
+    pub pending: Vec<Migration>,
+}
+
+async fn apply(db: &DBLayer, migration: &Migration) -> Result<()> {
+    match migration.version {
+        1 => db.ensure_user_chat_index().await,
+        2 => db.ensure_device_chat_index().await,
+        3 => db.ensure_search_index().await,
+        4 => db.ensure_message_stats().await,
+        5 => normalize_stored_messages(db),
+        6 => move_aliased_uploads(db).await,
+        7 => db.ensure_moderation_chat_index(),
+        version => bail!("no migration step for schema version {version}"),
+    }
+}
+
+/// Rewrite messages saved before their text was tidied on save, with
+/// their search terms and stats.
+fn normalize_stored_messages(db: &DBLayer) -> Result<()> {
+    let mut stale = Vec::new();
+    for item in db.db.iterator(rocksdb::IteratorMode::From(
+        b"chat:",
+        rocksdb::Direction::Forward,
+    )) {
+        let (key, val) = item?;
+        if !key.starts_with(b"chat:") {
+            break;
+        }
+        let Ok(msg) = serde_json::from_slice::<Message>(&val) else {
+            continue;
+        };
+        let normalized = normalize_message(msg.clone());
+        let changed = normalized.text != msg.text
+            || normalized
+                .attachments
+                .iter()
+                .zip(&msg.attachments)
+                .any(|(new, old)| {
+                    new.description != old.description || new.ocr_text != old.ocr_text
+                });
+        if changed {
+            stale.push((key, msg, normalized));
+        }
+    }
+
+    let _guard = db.counter_lock.lock().unwrap();
+    for (key, old, new) in &stale {
+        let mut batch = db.db.batch();
+        batch.put(key, serde_json::to_vec(new)?);
+        DBLayer::unindex_message(&mut batch, old);
+        DBLayer::index_message(&mut batch, std::str::from_utf8(key)?, new);
+        db.track_message_write(&mut batch, Some(old), Some(new), None)?;
+        db.db.write(batch)?;
+    }
+    if !stale.is_empty() {
+        info!(messages = stale.len(), "normalized stored message text");
+    }
+    Ok(())
+}
+
+/// Point uploads still recorded under a legacy device hash at the signed
+/// id it was migrated to, so the device can keep using them.
+async fn move_aliased_uploads(db: &DBLayer) -> Result<()> {
+    let mut uploads = Vec::new();
+    for item in db.db.iterator(rocksdb::IteratorMode::From(
+        b"upload:",
+        rocksdb::Direction::Forward,
+    )) {
+        let (key, val) = item?;
+        if !key.starts_with(b"upload:") {
+            break;
+        }
+        if let Ok(file) = serde_json::from_slice::<StoredFile>(&val) {
+            uploads.push(file);
+        }
+    }
+
+    let mut moved = 0;
+    for mut file in uploads {
+        if let Some(new_hash) = db.load_device_alias(&file.device_hash).await? {
+            file.device_hash = new_hash;
+            db.save_upload(&file).await?;
+            moved += 1;
+        }
+    }
+    if moved > 0 {
+        info!(uploads = moved, "moved uploads to signed device ids");
+    }
+    Ok(())
+}
+
+impl DBLayer {
+    fn schema_version(&self) -> Result<u32> {
+        Ok(self
+            .db
+            .get(VERSION_KEY)?
+            .and_then(|v| std::str::from_utf8(&v).ok()?.parse().ok())
+            .unwrap_or(0))
+    }
+
+    /// Apply the migrations newer than `schema_version`, in order. Fails
+    /// on a database written by a newer build, and stops at the first
+    /// migration that fails; the next start retries it.
+    pub async fn migrate(&self) -> Result<Vec<AppliedMigration>> {
+        let current = self.schema_version()?;
+        if current > latest_version() {
+            bail!(
+                "database schema version {current} is newer than this build knows ({})",
+                latest_version()
+            );
+        }
+        let mut applied = Vec::new();
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            let started = Instant::now();
+            apply(self, migration).await.map_err(|err| {
+                anyhow::anyhow!(
+                    "migration {} ({}) failed: {err}",
+                    migration.version,
+                    migration.name
+                )
+            })?;
+            let record = AppliedMigration {
+                version: migration.version,
+                name: migration.name.to_string(),
+                applied_ts: chrono::Utc::now().timestamp(),
+                elapsed_ms: started.elapsed().as_millis() as u64,
+            };
+            let mut batch = self.db.batch();
+            batch.put(record_key(migration.version), serde_json::to_vec(&record)?);
+            batch.put(VERSION_KEY, migration.version.to_string());
+            self.db.write(batch)?;
+            info!(
+                version = migration.version,
+                name = migration.name,
+                elapsed_ms = record.elapsed_ms,
+                "schema migration applied"
+            );
+            applied.push(record);
+        }
+        Ok(applied)
+    }
+
+    /// The schema version, the migrations applied to this database and
+    /// those still pending.
+    pub fn schema_status(&self) -> Result<SchemaStatus> {
+        let schema_version = self.schema_version()?;
+        let mut applied = Vec::new();
+        for item in self.db.iterator(rocksdb::IteratorMode::From(
+            RECORD_PREFIX.as_bytes(),
+            rocksdb::Direction::Forward,
+        )) {
+            let (key, val) = item?;
+            if !key.starts_with(RECORD_PREFIX.as_bytes()) {
+                break;
+            }
+            applied.push(serde_json::from_slice(&val)?);
+        }
+        Ok(SchemaStatus {
+            schema_version,
+            latest_version: latest_version(),
+            applied,
+            pending: MIGRATIONS
+                .iter()
+                .filter(|m| m.version > schema_version)
+                .copied()
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_ascend_without_gaps() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1, "{}", migration.name);
+        }
+        assert_eq!(latest_version(), MIGRATIONS.len() as u32);
+        assert!(record_key(2) < record_key(10));
+    }
+}
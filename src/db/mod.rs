@@ -1,30 +1,92 @@
 use anyhow::Result;
-use rocksdb::{Direction, IteratorMode, Options, DB};
+use rocksdb::{Direction, IteratorMode};
+use serde::Serialize;
 use serde_json;
+use tracing::{info, warn};
 
 use crate::{
-    inference::byte_decoder::tidy_decoded_text,
-    model::{chat::Chat, message::Message, user::User, user_device::UserDevice},
+    agent::runs::AgentRunRecord,
+    audit::AuditEntry,
+    canary::CanaryReport,
+    inference::{byte_decoder::tidy_decoded_text, reasoning::ReasoningResult},
+    internal_api::bulk::BulkJob,
+    maintenance::MaintenanceWindow,
+    model::{
+        auth_token::{AuthSession, RefreshToken},
+        chat::{Chat, ChatLanguage},
+        message::{FeedbackKind, Message, MessageFeedback},
+        routing::RoutingRecord,
+        upload::StoredFile,
+        user::User,
+        user_device::UserDevice,
+    },
+    moderation::ModerationRecord,
+    personas::Persona,
+    prompts::PromptOverride,
+    redaction,
+    retention::RetentionOverride,
+    schedules::Schedule,
+    share::ShareLink,
+    status::Incident,
+    vector::VectorRecord,
+    webhooks::Webhook,
 };
 
 use std::{
-    cmp::Ordering,
-    collections::{BinaryHeap, HashSet},
+    cmp::{Ordering, Reverse},
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
     str,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Mutex,
+    },
 };
 
+pub mod families;
+pub mod migrations;
+pub mod persistence;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod rocks;
+pub mod search;
+pub mod stats;
+pub mod tuning;
+use families::{Family, FamilyStats};
+use persistence::{Batch, Store};
+use stats::{ChatStats, CountDelta, MessageCounts, SummaryRef};
+use tuning::DbTuning;
+
 const DEVICE_CHAT_INDEX_FLAG: &str = "device_chat_index:built";
+const USER_CHAT_INDEX_FLAG: &str = "user_chat_index:built";
+const SEARCH_INDEX_FLAG: &str = "search_index:built";
+
+/// Secondary indexes [`DBLayer::rebuild_index`] can rebuild.
+pub const SECONDARY_INDEXES: [&str; 4] = ["user_chat", "device_chat", "search", "stats"];
 
 pub struct DBLayer {
-    db: DB,
+    db: Store,
+    // Serializes read-modify-write on counters.
+    counter_lock: Mutex<()>,
+    // Serializes refresh token rotation, so a token is claimed once.
+    token_lock: Mutex<()>,
+    tuning: DbTuning,
+    // Set while a manual full compaction runs.
+    compacting: AtomicBool,
 }
 
 impl DBLayer {
     pub fn new(path: &str) -> Result<Self> {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        let db = DB::open(&opts, path)?;
-        Ok(Self { db })
+        let tuning = DbTuning::from_env(&Family::names());
+        let db = Store::new(persistence::from_env(path, &tuning)?);
+        Ok(Self {
+            db,
+            counter_lock: Mutex::new(()),
+            token_lock: Mutex::new(()),
+            tuning,
+            compacting: AtomicBool::new(false),
+        })
     }
 
     // ============================================================
@@ -51,25 +113,99 @@ impl DBLayer {
         format!("{}{}", Self::device_chat_prefix(device_hash), chat_id)
     }
 
-    fn add_chat_to_device_index(&self, device_hash: &str, chat_id: &str) -> Result<()> {
+    fn add_chat_to_device_index(batch: &mut Batch, device_hash: &str, chat_id: &str) {
         if device_hash.is_empty() {
-            return Ok(());
+            return;
         }
-        let key = Self::device_chat_key(device_hash, chat_id);
-        self.db.put(key, chat_id.as_bytes())?;
-        self.db.put(DEVICE_CHAT_INDEX_FLAG, b"1")?;
-        Ok(())
+        batch.put(Self::device_chat_key(device_hash, chat_id), chat_id);
+        batch.put(DEVICE_CHAT_INDEX_FLAG, b"1");
     }
 
-    fn remove_chat_from_device_index(&self, device_hash: &str, chat_id: &str) -> Result<()> {
+    fn remove_chat_from_device_index(batch: &mut Batch, device_hash: &str, chat_id: &str) {
         if device_hash.is_empty() {
+            return;
+        }
+        batch.delete(Self::device_chat_key(device_hash, chat_id));
+    }
+
+    fn user_chat_prefix(user_id: &str) -> String {
+        format!("user_chat:{user_id}:")
+    }
+
+    fn user_chat_key(user_id: &str, chat_id: &str) -> String {
+        format!("{}{}", Self::user_chat_prefix(user_id), chat_id)
+    }
+
+    fn add_chat_to_user_index(batch: &mut Batch, user_id: &str, chat_id: &str) {
+        if user_id.is_empty() {
+            return;
+        }
+        batch.put(Self::user_chat_key(user_id, chat_id), chat_id);
+    }
+
+    fn remove_chat_from_user_index(batch: &mut Batch, user_id: &str, chat_id: &str) {
+        if user_id.is_empty() {
+            return;
+        }
+        batch.delete(Self::user_chat_key(user_id, chat_id));
+    }
+
+    fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, _) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            keys.push(k.to_string());
+        }
+        Ok(keys)
+    }
+
+    async fn ensure_user_chat_index(&self) -> Result<()> {
+        if self.db.get(USER_CHAT_INDEX_FLAG)?.is_some() {
             return Ok(());
         }
-        let key = Self::device_chat_key(device_hash, chat_id);
-        self.db.delete(key)?;
+
+        for key in self.scan_keys("user_chat:")? {
+            self.db.delete(key)?;
+        }
+        let mut batch = self.db.batch();
+        for chat in self.list_chats().await? {
+            if let Some(user_id) = chat.user_id.as_deref() {
+                Self::add_chat_to_user_index(&mut batch, user_id, &chat.id);
+            }
+        }
+        batch.put(USER_CHAT_INDEX_FLAG, b"1");
+        self.db.write(batch)?;
         Ok(())
     }
 
+    /// Drop one of [`SECONDARY_INDEXES`] and build it again from the chats
+    /// and messages.
+    pub async fn rebuild_index(&self, index: &str) -> Result<()> {
+        match index {
+            "user_chat" => {
+                self.db.delete(USER_CHAT_INDEX_FLAG)?;
+                self.ensure_user_chat_index().await
+            }
+            "device_chat" => self.rebuild_device_chat_index().await,
+            "search" => {
+                self.db.delete(SEARCH_INDEX_FLAG)?;
+                self.ensure_search_index().await
+            }
+            "stats" => {
+                self.db.delete(stats::BUILT_FLAG)?;
+                self.ensure_message_stats().await
+            }
+            other => Err(anyhow::anyhow!("unknown index {other}")),
+        }
+    }
+
     async fn ensure_device_chat_index(&self) -> Result<()> {
         if self.db.get(DEVICE_CHAT_INDEX_FLAG)?.is_some() {
             return Ok(());
@@ -110,11 +246,41 @@ impl DBLayer {
         Ok(())
     }
 
+    /// Store a message with its search terms and stats in one write.
     pub async fn save_message(&self, msg: &Message) -> Result<()> {
+        let _guard = self.counter_lock.lock().unwrap();
+        let mut batch = self.db.batch();
+        self.stage_message(&mut batch, msg, None)?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// [`save_message`](Self::save_message) and [`save_chat`](Self::save_chat)
+    /// in one write, for a message that also moves its chat's
+    /// `updated_ts` up.
+    pub async fn save_message_with_chat(&self, msg: &Message, chat: &Chat) -> Result<()> {
+        let _guard = self.counter_lock.lock().unwrap();
+        let previous_chat = self.stored_chat(&chat.id)?;
+        let mut batch = self.db.batch();
+        Self::stage_chat(&mut batch, chat, previous_chat.as_ref())?;
+        self.stage_message(&mut batch, msg, Some(chat))?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Add a message, its search terms and its stats to `batch`. `chat` is
+    /// the chat record written in the same batch, if any. Callers hold
+    /// `counter_lock`.
+    fn stage_message(&self, batch: &mut Batch, msg: &Message, chat: Option<&Chat>) -> Result<()> {
         let key = Self::msg_key(&msg.chat_id, msg.ts, &msg.id);
-        let stored = normalize_message(msg.clone());
-        let val = serde_json::to_vec(&stored)?;
-        self.db.put(key, val)?;
+        let stored = redaction::message_for_storage(normalize_message(msg.clone()));
+        let previous = self
+            .db
+            .get(&key)?
+            .and_then(|v| serde_json::from_slice::<Message>(&v).ok());
+        batch.put(&key, serde_json::to_vec(&stored)?);
+        Self::index_message(batch, &key, &stored);
+        self.track_message_write(batch, previous.as_ref(), Some(&stored), chat)?;
         Ok(())
     }
 
@@ -166,25 +332,68 @@ impl DBLayer {
     }
 
     pub async fn delete_message(&self, chat_id: &str, message_id: &str) -> Result<bool> {
-        if let Some((key, _)) = self.find_message_entry(chat_id, message_id)? {
-            self.db.delete(key)?;
+        if let Some((key, msg)) = self.find_message_entry(chat_id, message_id)? {
+            let _guard = self.counter_lock.lock().unwrap();
+            let mut batch = self.db.batch();
+            batch.delete(key);
+            Self::unindex_message(&mut batch, &msg);
+            Self::delete_routing_record(&mut batch, message_id);
+            Self::delete_moderation_record(&mut batch, chat_id, msg.ts, message_id);
+            self.track_message_write(&mut batch, Some(&msg), None, None)?;
+            self.db.write(batch)?;
             return Ok(true);
         }
         Ok(false)
     }
 
-    pub async fn set_message_liked(
+    /// Delete stored messages with their search terms, routing records and
+    /// stats, one batch per message. Each is read again under the lock, so
+    /// one deleted in the meantime is skipped rather than counted twice.
+    /// Returns the number actually deleted.
+    pub async fn delete_messages(&self, messages: &[Message]) -> Result<usize> {
+        let _guard = self.counter_lock.lock().unwrap();
+        let mut deleted = 0;
+        for msg in messages {
+            let key = Self::msg_key(&msg.chat_id, msg.ts, &msg.id);
+            let Some(val) = self.db.get(&key)? else {
+                continue;
+            };
+            let stored = normalize_message(serde_json::from_slice::<Message>(&val)?);
+            let mut batch = self.db.batch();
+            batch.delete(&key);
+            Self::unindex_message(&mut batch, &stored);
+            Self::delete_routing_record(&mut batch, &stored.id);
+            Self::delete_moderation_record(&mut batch, &stored.chat_id, stored.ts, &stored.id);
+            self.track_message_write(&mut batch, Some(&stored), None, None)?;
+            self.db.write(batch)?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    /// Set or clear (`None`) a message's feedback, keeping `liked` in step.
+    /// Returns the message as it was before the update, `None` when it
+    /// does not exist.
+    pub async fn set_message_feedback(
         &self,
         chat_id: &str,
         message_id: &str,
-        liked: bool,
-    ) -> Result<bool> {
-        if let Some((key, mut msg)) = self.find_message_entry(chat_id, message_id)? {
-            msg.liked = liked;
-            self.db.put(key, serde_json::to_vec(&msg)?)?;
-            return Ok(true);
+        feedback: Option<MessageFeedback>,
+    ) -> Result<Option<Message>> {
+        if let Some((key, msg)) = self.find_message_entry(chat_id, message_id)? {
+            let mut updated = msg.clone();
+            updated.liked = feedback
+                .as_ref()
+                .is_some_and(|f| f.kind == FeedbackKind::Liked);
+            updated.feedback = feedback;
+            let _guard = self.counter_lock.lock().unwrap();
+            let mut batch = self.db.batch();
+            batch.put(key, serde_json::to_vec(&updated)?);
+            self.track_message_write(&mut batch, Some(&msg), Some(&updated), None)?;
+            self.db.write(batch)?;
+            return Ok(Some(msg));
         }
-        Ok(false)
+        Ok(None)
     }
 
     /// Collect the latest raw messages across all chats, ordered by timestamp desc.
@@ -237,17 +446,33 @@ impl DBLayer {
             }
         }
 
+        let sandbox_ids: HashSet<String> = self
+            .list_sandbox_chats()
+            .await?
+            .into_iter()
+            .map(|chat| chat.id)
+            .collect();
+
         let mut heap = BinaryHeap::new();
         let mut seq = 0usize;
 
-        for item in self.db.iterator(IteratorMode::Start) {
+        for item in self
+            .db
+            .iterator(IteratorMode::From(b"chat:", Direction::Forward))
+        {
             let (key, val) = item?;
             let key_str = str::from_utf8(&key)?;
-            if !key_str.starts_with("chat:") || !key_str.contains(":msg:") {
+            if !key_str.starts_with("chat:") {
+                break;
+            }
+            if !key_str.contains(":msg:") {
                 continue;
             }
 
             let msg: Message = serde_json::from_slice(&val)?;
+            if sandbox_ids.contains(&msg.chat_id) {
+                continue;
+            }
             let msg = normalize_message(msg);
             seq = seq.wrapping_add(1);
             let entry = HeapEntry {
@@ -278,35 +503,59 @@ impl DBLayer {
     // ============================================================
     // CHAT STORAGE
     // ============================================================
+    /// Save a chat and move its device and user index entries. The
+    /// previous record is read under `counter_lock`, so the index diff is
+    /// never taken against a chat another write has since replaced.
     pub async fn save_chat(&self, chat: &Chat) -> Result<()> {
-        let key = format!("chat:meta:{}", chat.id);
-        let previous_chat: Option<Chat> = self
+        let _guard = self.counter_lock.lock().unwrap();
+        let previous_chat = self.stored_chat(&chat.id)?;
+        let mut batch = self.db.batch();
+        Self::stage_chat(&mut batch, chat, previous_chat.as_ref())?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Like [`load_chat`](Self::load_chat), but a record that does not
+    /// parse is an error.
+    fn stored_chat(&self, id: &str) -> Result<Option<Chat>> {
+        Ok(self
             .db
-            .get(&key)?
+            .get(format!("chat:meta:{id}"))?
             .map(|val| serde_json::from_slice::<Chat>(&val))
-            .transpose()?;
+            .transpose()?)
+    }
 
+    /// Add a chat record and the device and user index changes it makes
+    /// over `previous_chat` to `batch`.
+    fn stage_chat(batch: &mut Batch, chat: &Chat, previous_chat: Option<&Chat>) -> Result<()> {
         match (
-            previous_chat
-                .as_ref()
-                .and_then(|c| c.device_hash.as_deref()),
+            previous_chat.and_then(|c| c.device_hash.as_deref()),
             chat.device_hash.as_deref(),
         ) {
             (Some(old_hash), Some(new_hash)) if old_hash != new_hash => {
-                self.remove_chat_from_device_index(old_hash, &chat.id)?;
-                self.add_chat_to_device_index(new_hash, &chat.id)?;
+                Self::remove_chat_from_device_index(batch, old_hash, &chat.id);
+                Self::add_chat_to_device_index(batch, new_hash, &chat.id);
             }
             (Some(old_hash), None) => {
-                self.remove_chat_from_device_index(old_hash, &chat.id)?;
+                Self::remove_chat_from_device_index(batch, old_hash, &chat.id);
             }
             (None, Some(new_hash)) => {
-                self.add_chat_to_device_index(new_hash, &chat.id)?;
+                Self::add_chat_to_device_index(batch, new_hash, &chat.id);
             }
             _ => {}
         }
 
-        let val = serde_json::to_vec(chat)?;
-        self.db.put(key, val)?;
+        let old_user = previous_chat.and_then(|c| c.user_id.as_deref());
+        if old_user != chat.user_id.as_deref() {
+            if let Some(old_user) = old_user {
+                Self::remove_chat_from_user_index(batch, old_user, &chat.id);
+            }
+        }
+        if let Some(user_id) = chat.user_id.as_deref() {
+            Self::add_chat_to_user_index(batch, user_id, &chat.id);
+        }
+
+        batch.put(format!("chat:meta:{}", chat.id), serde_json::to_vec(chat)?);
         Ok(())
     }
 
@@ -340,15 +589,52 @@ impl DBLayer {
         Ok(results)
     }
 
-    /// List all chats belonging to all devices of a user.
+    pub async fn set_chat_language(&self, chat_id: &str, language: &ChatLanguage) -> Result<()> {
+        if let Some(mut chat) = self.load_chat(chat_id).await? {
+            chat.language = language.clone();
+            self.save_chat(&chat).await?;
+        }
+        Ok(())
+    }
+
+    /// Internal evaluation chats created from the admin UI.
+    pub async fn list_sandbox_chats(&self) -> Result<Vec<Chat>> {
+        Ok(self
+            .list_chats()
+            .await?
+            .into_iter()
+            .filter(|chat| chat.sandbox)
+            .collect())
+    }
+
+    /// List chats owned by a user plus all chats of the user's devices.
     pub async fn list_chats_for_user(&self, user_id: &str) -> Result<Vec<Chat>> {
-        // 1. Load all devices for user
-        let devices = self.list_devices_for_user(user_id).await?;
+        self.ensure_user_chat_index().await?;
 
         let mut all_chats = Vec::new();
         let mut seen_ids = HashSet::new();
 
-        // 2. For each device, load its chats
+        // 1. Chats explicitly assigned to the user
+        let prefix = Self::user_chat_prefix(user_id);
+        for key in self.scan_keys(&prefix)? {
+            let chat_id = &key[prefix.len()..];
+            match self.load_chat(chat_id).await? {
+                Some(chat) if chat.sandbox => {}
+                Some(chat) => {
+                    if seen_ids.insert(chat.id.clone()) {
+                        all_chats.push(chat);
+                    }
+                }
+                None => {
+                    let mut batch = self.db.batch();
+                    Self::remove_chat_from_user_index(&mut batch, user_id, chat_id);
+                    self.db.write(batch)?;
+                }
+            }
+        }
+
+        // 2. Chats of every device linked to the user
+        let devices = self.list_devices_for_user(user_id).await?;
         for device in devices {
             let chats = self.list_chats_for_device(&device.device_hash).await?;
             for chat in chats {
@@ -386,9 +672,12 @@ impl DBLayer {
         let mut chats = Vec::with_capacity(chat_ids.len());
         for chat_id in chat_ids {
             match self.load_chat(&chat_id).await? {
+                Some(chat) if chat.sandbox => {}
                 Some(chat) => chats.push(chat),
                 None => {
-                    self.remove_chat_from_device_index(device_hash, &chat_id)?;
+                    let mut batch = self.db.batch();
+                    Self::remove_chat_from_device_index(&mut batch, device_hash, &chat_id);
+                    self.db.write(batch)?;
                 }
             }
         }
@@ -403,32 +692,54 @@ impl DBLayer {
 
         // Collect keys first to avoid mutating while iterating.
         let mut keys = Vec::new();
+        let mut message_ids = Vec::new();
+        let mut indexed = Vec::new();
         for item in self
             .db
             .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
         {
-            let (key, _) = item?;
+            let (key, val) = item?;
             let k_str = str::from_utf8(&key)?;
             if !k_str.starts_with(&prefix) {
                 break;
             }
+            if let Some((_, message_id)) = k_str[prefix.len()..].split_once(':') {
+                message_ids.push(message_id.to_string());
+            }
+            if let Ok(msg) = serde_json::from_slice::<Message>(&val) {
+                indexed.push(msg);
+            }
             keys.push(key);
         }
 
+        let _guard = self.counter_lock.lock().unwrap();
+        let mut batch = self.db.batch();
         for key in keys {
-            self.db.delete(key)?;
+            batch.delete(key);
+        }
+        for message_id in &message_ids {
+            Self::delete_routing_record(&mut batch, message_id);
+        }
+        for msg in &indexed {
+            Self::unindex_message(&mut batch, msg);
         }
+        self.delete_chat_moderation_records(&mut batch, chat_id)?;
+        let sandbox = existing_chat.as_ref().is_some_and(|chat| chat.sandbox);
+        self.drop_chat_stats(&mut batch, chat_id, sandbox)?;
 
         // Remove chat metadata if present.
-        let meta_key = format!("chat:meta:{chat_id}");
-        let _ = self.db.delete(meta_key);
+        batch.delete(format!("chat:meta:{chat_id}"));
 
         if let Some(chat) = existing_chat {
             if let Some(device_hash) = chat.device_hash.as_deref() {
-                self.remove_chat_from_device_index(device_hash, chat_id)?;
+                Self::remove_chat_from_device_index(&mut batch, device_hash, chat_id);
+            }
+            if let Some(user_id) = chat.user_id.as_deref() {
+                Self::remove_chat_from_user_index(&mut batch, user_id, chat_id);
             }
         }
 
+        self.db.write(batch)?;
         Ok(())
     }
 
@@ -448,12 +759,18 @@ impl DBLayer {
 
             let msg: Message = serde_json::from_slice(&val)?;
             if msg.role == role {
-                keys.push(key);
+                keys.push((key, msg));
             }
         }
 
-        for key in &keys {
-            self.db.delete(key)?;
+        // One batch per message: each stats update reads the last one.
+        let _guard = self.counter_lock.lock().unwrap();
+        for (key, msg) in &keys {
+            let mut batch = self.db.batch();
+            batch.delete(key);
+            Self::unindex_message(&mut batch, msg);
+            self.track_message_write(&mut batch, Some(msg), None, None)?;
+            self.db.write(batch)?;
         }
 
         Ok(keys.len())
@@ -521,6 +838,31 @@ impl DBLayer {
         let user_key = format!("user:{user_id}");
         self.db.delete(user_key)?;
 
+        for session in self.list_auth_sessions(user_id).await? {
+            self.revoke_session(&session.id).await?;
+            self.delete_auth_session(user_id, &session.id).await?;
+        }
+
+        for key in self.scan_keys(&Self::user_chat_prefix(user_id))? {
+            self.db.delete(key)?;
+        }
+
+        for key in self.scan_keys(&format!("persona:{user_id}:"))? {
+            self.db.delete(key)?;
+        }
+
+        for key in self.scan_keys(&format!("schedule:{user_id}:"))? {
+            self.db.delete(key)?;
+        }
+
+        for key in self.scan_keys(&format!("webhook:{user_id}:"))? {
+            self.db.delete(key)?;
+        }
+
+        self.delete_retention_override(user_id).await?;
+
+        self.db.delete(format!("storage_usage:user:{user_id}"))?;
+
         if let Ok(devices) = self.list_devices_for_user(user_id).await {
             for device in devices {
                 let key = Self::user_device_key(&device.user_id, &device.id);
@@ -535,6 +877,14 @@ impl DBLayer {
         Ok(())
     }
 
+    pub async fn find_user_id_by_device(&self, device_hash: &str) -> Result<Option<String>> {
+        let key = Self::device_lookup_key(device_hash);
+        match self.db.get(key)? {
+            Some(v) => Ok(Some(String::from_utf8(v)?)),
+            None => Ok(None),
+        }
+    }
+
     pub async fn list_all_devices(&self) -> Result<Vec<UserDevice>> {
         let prefix = "user_device:";
         let mut out = Vec::new();
@@ -574,20 +924,1628 @@ impl DBLayer {
 
         Ok(())
     }
-}
 
-fn normalize_message(mut msg: Message) -> Message {
-    normalize_option_text(&mut msg.text);
-    for attachment in msg.attachments.iter_mut() {
-        normalize_option_text(&mut attachment.description);
-        normalize_option_text(&mut attachment.ocr_text);
+    /// Assign every chat created by `device_hash` to `user_id`. Chats already
+    /// owned by a different user are left alone. Returns the chats now owned
+    /// by the user.
+    pub async fn claim_device_chats(&self, device_hash: &str, user_id: &str) -> Result<Vec<Chat>> {
+        let mut claimed = Vec::new();
+        for mut chat in self.list_chats_for_device(device_hash).await? {
+            match chat.user_id.as_deref() {
+                Some(owner) if owner == user_id => {}
+                Some(_) => continue,
+                None => {
+                    chat.user_id = Some(user_id.to_string());
+                    self.save_chat(&chat).await?;
+                }
+            }
+            claimed.push(chat);
+        }
+        Ok(claimed)
     }
-    msg
-}
 
-fn normalize_option_text(target: &mut Option<String>) {
-    if let Some(text) = target.take() {
-        let normalized = tidy_decoded_text(&text);
-        target.replace(normalized);
+    pub async fn load_device_alias(&self, legacy_hash: &str) -> Result<Option<String>> {
+        match self.db.get(format!("device_alias:{legacy_hash}"))? {
+            Some(v) => Ok(Some(String::from_utf8(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Move everything keyed by a client-made device hash to a server-minted
+    /// id: chats, uploads, the device → user lookup and the user's device
+    /// records. The old hash is remembered as an alias so it can be refused
+    /// later. Returns the number of chats moved.
+    pub async fn migrate_device_hash(&self, old_hash: &str, new_hash: &str) -> Result<usize> {
+        let mut moved = 0;
+        for mut chat in self.list_chats_for_device(old_hash).await? {
+            chat.device_hash = Some(new_hash.to_string());
+            self.save_chat(&chat).await?;
+            moved += 1;
+        }
+
+        // Uploads are only indexed by user, so find the device's by scan.
+        let prefix = "upload:";
+        let mut uploads = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if let Ok(file) = serde_json::from_slice::<StoredFile>(&val) {
+                if file.device_hash == old_hash {
+                    uploads.push(file);
+                }
+            }
+        }
+        for mut file in uploads {
+            file.device_hash = new_hash.to_string();
+            self.save_upload(&file).await?;
+        }
+
+        if let Some(user_id) = self.find_user_id_by_device(old_hash).await? {
+            for mut device in self.list_devices_for_user(&user_id).await? {
+                if device.device_hash == old_hash {
+                    device.device_hash = new_hash.to_string();
+                    let key = Self::user_device_key(&device.user_id, &device.id);
+                    self.db.put(key, serde_json::to_vec(&device)?)?;
+                }
+            }
+            self.db.put(Self::device_lookup_key(new_hash), user_id)?;
+            self.db.delete(Self::device_lookup_key(old_hash))?;
+        }
+
+        self.db.put(format!("device_alias:{old_hash}"), new_hash)?;
+        Ok(moved)
+    }
+    // ============================================================
+    // AUTH TOKENS
+    // ============================================================
+    pub async fn save_refresh_token(&self, token: &RefreshToken) -> Result<()> {
+        let key = format!("refresh_token:{}", token.token_hash);
+        let val = serde_json::to_vec(token)?;
+        self.db.put(key, val)?;
+        Ok(())
+    }
+
+    pub async fn load_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>> {
+        let key = format!("refresh_token:{token_hash}");
+        match self.db.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Mark a refresh token rotated at `now` and return the record as it
+    /// was. Of two concurrent claims only one sees `rotated_ts` unset.
+    pub async fn claim_refresh_token(
+        &self,
+        token_hash: &str,
+        now: i64,
+    ) -> Result<Option<RefreshToken>> {
+        let key = format!("refresh_token:{token_hash}");
+        let _guard = self.token_lock.lock().unwrap();
+        let Some(v) = self.db.get(&key)? else {
+            return Ok(None);
+        };
+        let record: RefreshToken = serde_json::from_slice(&v)?;
+        if record.rotated_ts.is_none() {
+            let claimed = RefreshToken {
+                rotated_ts: Some(now),
+                ..record.clone()
+            };
+            self.db.put(&key, serde_json::to_vec(&claimed)?)?;
+        }
+        Ok(Some(record))
+    }
+
+    pub async fn save_auth_session(&self, session: &AuthSession) -> Result<()> {
+        let key = format!("session:{}:{}", session.user_id, session.id);
+        let val = serde_json::to_vec(session)?;
+        self.db.put(key, val)?;
+        Ok(())
+    }
+
+    pub async fn load_auth_session(
+        &self,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<Option<AuthSession>> {
+        let key = format!("session:{user_id}:{session_id}");
+        match self.db.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_auth_sessions(&self, user_id: &str) -> Result<Vec<AuthSession>> {
+        let prefix = format!("session:{user_id}:");
+        let mut out = Vec::new();
+
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            out.push(serde_json::from_slice(&val)?);
+        }
+        Ok(out)
+    }
+
+    pub async fn delete_auth_session(&self, user_id: &str, session_id: &str) -> Result<()> {
+        let key = format!("session:{user_id}:{session_id}");
+        self.db.delete(key)?;
+        Ok(())
+    }
+
+    /// Blacklist an access token id until its natural expiry.
+    pub async fn revoke_jti(&self, jti: &str, expires_ts: i64) -> Result<()> {
+        let key = format!("revoked_jti:{jti}");
+        self.db.put(key, expires_ts.to_string())?;
+        Ok(())
+    }
+
+    pub async fn is_jti_revoked(&self, jti: &str) -> Result<bool> {
+        let key = format!("revoked_jti:{jti}");
+        Ok(self.db.get(key)?.is_some())
+    }
+
+    /// Revoke a whole token family: every refresh token issued for the
+    /// session and every access token carrying its `sid`.
+    pub async fn revoke_session(&self, session_id: &str) -> Result<()> {
+        let key = format!("revoked_session:{session_id}");
+        self.db
+            .put(key, chrono::Utc::now().timestamp().to_string())?;
+        Ok(())
+    }
+
+    pub async fn is_session_revoked(&self, session_id: &str) -> Result<bool> {
+        let key = format!("revoked_session:{session_id}");
+        Ok(self.db.get(key)?.is_some())
+    }
+
+    /// Drop blacklist entries, refresh tokens and sessions that are past
+    /// expiry. A revoked session is forgotten once every token issued for
+    /// it has expired, a refresh token lifetime after the revocation.
+    pub async fn purge_expired_tokens(&self) -> Result<usize> {
+        let now = chrono::Utc::now().timestamp();
+        let mut stale = Vec::new();
+
+        let prefix = "revoked_session:";
+        let revoked_before = now - crate::auth::jwt::refresh_token_ttl();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let revoked_ts = str::from_utf8(&val)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(0);
+            if revoked_ts < revoked_before {
+                stale.push(key.to_vec());
+            }
+        }
+
+        let prefix = "revoked_jti:";
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let expires_ts = str::from_utf8(&val)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(0);
+            if expires_ts < now {
+                stale.push(key.to_vec());
+            }
+        }
+
+        let prefix = "refresh_token:";
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            match serde_json::from_slice::<RefreshToken>(&val) {
+                Ok(token) if token.expires_ts >= now => {}
+                _ => stale.push(key.to_vec()),
+            }
+        }
+
+        let prefix = "session:";
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            match serde_json::from_slice::<AuthSession>(&val) {
+                Ok(session) if session.expires_ts >= now => {}
+                _ => stale.push(key.to_vec()),
+            }
+        }
+
+        for key in &stale {
+            self.db.delete(key)?;
+        }
+        Ok(stale.len())
+    }
+
+    // ============================================================
+    // UPLOADS
+    // ============================================================
+    pub async fn save_upload(&self, file: &StoredFile) -> Result<()> {
+        let key = format!("upload:{}", file.id);
+        self.db.put(key, serde_json::to_vec(file)?)?;
+        if let Some(user_id) = file.user_id.as_deref() {
+            self.db
+                .put(format!("upload_user:{user_id}:{}", file.id), b"")?;
+        }
+        Ok(())
+    }
+
+    /// Uploads recorded for `user_id`, from any of their devices.
+    pub async fn list_uploads_for_user(&self, user_id: &str) -> Result<Vec<StoredFile>> {
+        let prefix = format!("upload_user:{user_id}:");
+        let mut out = Vec::new();
+        for key in self.scan_keys(&prefix)? {
+            if let Some(file) = self.load_upload(&key[prefix.len()..]).await? {
+                out.push(file);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Record the chat a file was first attached to.
+    pub async fn link_upload_to_chat(&self, file_id: &str, chat_id: &str) -> Result<()> {
+        if let Some(mut file) = self.load_upload(file_id).await? {
+            if file.chat_id.is_none() {
+                file.chat_id = Some(chat_id.to_string());
+                self.save_upload(&file).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete_upload(&self, file: &StoredFile) -> Result<()> {
+        self.db.delete(format!("upload:{}", file.id))?;
+        if let Some(user_id) = file.user_id.as_deref() {
+            self.db
+                .delete(format!("upload_user:{user_id}:{}", file.id))?;
+        }
+        Ok(())
+    }
+
+    pub async fn load_upload(&self, id: &str) -> Result<Option<StoredFile>> {
+        let key = format!("upload:{id}");
+        match self.db.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Upload bytes charged to `owner`, a device hash or `user:<id>`.
+    pub async fn storage_usage(&self, owner: &str) -> Result<u64> {
+        match self.db.get(format!("storage_usage:{owner}"))? {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Charge `bytes` to `owner`, or refund them when `delta` is negative;
+    /// returns the new total.
+    pub async fn add_storage_usage(&self, owner: &str, delta: i64) -> Result<u64> {
+        let used = self
+            .storage_usage(owner)
+            .await?
+            .saturating_add_signed(delta);
+        self.db
+            .put(format!("storage_usage:{owner}"), serde_json::to_vec(&used)?)?;
+        Ok(used)
+    }
+
+    pub async fn list_uploads(&self) -> Result<Vec<StoredFile>> {
+        let prefix = "upload:";
+        let mut out = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            out.push(serde_json::from_slice(&val)?);
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // CANARY
+    // ============================================================
+    pub async fn save_canary_report(&self, report: &CanaryReport) -> Result<()> {
+        self.db.put("canary:last", serde_json::to_vec(report)?)?;
+        Ok(())
+    }
+
+    pub async fn load_canary_report(&self) -> Result<Option<CanaryReport>> {
+        match self.db.get("canary:last")? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    // ============================================================
+    // ROUTING RECORDS
+    // ============================================================
+    fn routing_key(message_id: &str) -> String {
+        format!("routing:{message_id}")
+    }
+
+    fn misroute_key(message_id: &str) -> String {
+        format!("routing_misroute:{message_id}")
+    }
+
+    /// Store a record; labeled misroutes are also indexed for export.
+    pub async fn save_routing_record(&self, record: &RoutingRecord) -> Result<()> {
+        let mut batch = self.db.batch();
+        batch.put(
+            Self::routing_key(&record.message_id),
+            serde_json::to_vec(record)?,
+        );
+        let misroute_key = Self::misroute_key(&record.message_id);
+        if record.feedback.as_ref().is_some_and(|f| f.misroute) {
+            batch.put(misroute_key, b"");
+        } else {
+            batch.delete(misroute_key);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    pub async fn load_routing_record(&self, message_id: &str) -> Result<Option<RoutingRecord>> {
+        match self.db.get(Self::routing_key(message_id))? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_routing_record(batch: &mut Batch, message_id: &str) {
+        batch.delete(Self::routing_key(message_id));
+        batch.delete(Self::misroute_key(message_id));
+    }
+
+    pub async fn list_misroutes(&self) -> Result<Vec<RoutingRecord>> {
+        let prefix = "routing_misroute:";
+        let mut out = Vec::new();
+        for key in self.scan_keys(prefix)? {
+            if let Some(record) = self.load_routing_record(&key[prefix.len()..]).await? {
+                out.push(record);
+            }
+        }
+        out.sort_by_key(|r| r.ts);
+        Ok(out)
+    }
+
+    // ============================================================
+    // MAINTENANCE
+    // ============================================================
+    pub async fn save_maintenance(&self, window: &MaintenanceWindow) -> Result<()> {
+        self.db
+            .put("maintenance:window", serde_json::to_vec(window)?)?;
+        Ok(())
+    }
+
+    pub async fn load_maintenance(&self) -> Result<Option<MaintenanceWindow>> {
+        match self.db.get("maintenance:window")? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    // ============================================================
+    // INCIDENTS
+    // ============================================================
+    fn incident_key(incident: &Incident) -> String {
+        format!("incident:{:020}:{}", incident.started_ts, incident.id)
+    }
+
+    /// Insert or update (on resolve) an incident.
+    pub async fn save_incident(&self, incident: &Incident) -> Result<()> {
+        self.db
+            .put(Self::incident_key(incident), serde_json::to_vec(incident)?)?;
+        Ok(())
+    }
+
+    /// Most recent incidents first.
+    pub async fn list_incidents(&self, limit: usize) -> Result<Vec<Incident>> {
+        let prefix = "incident:";
+        let mut out = Vec::new();
+        // '~' sorts after every digit, so iteration starts past the newest key.
+        let start = format!("{prefix}~");
+        for item in self
+            .db
+            .iterator(IteratorMode::From(start.as_bytes(), Direction::Reverse))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            out.push(serde_json::from_slice(&val)?);
+            if out.len() >= limit {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Incidents still open, e.g. from before a restart.
+    pub async fn list_open_incidents(&self) -> Result<Vec<Incident>> {
+        let prefix = "incident:";
+        let mut out = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let incident: Incident = serde_json::from_slice(&val)?;
+            if incident.resolved_ts.is_none() {
+                out.push(incident);
+            }
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // MODERATION
+    // ============================================================
+    fn moderation_key(ts: i64, message_id: &str) -> String {
+        format!("moderation:{ts:020}:{message_id}")
+    }
+
+    fn moderation_chat_key(chat_id: &str, ts: i64, message_id: &str) -> String {
+        format!("moderation_chat:{chat_id}:{ts:020}:{message_id}")
+    }
+
+    /// Records hold the flagged text, so they go with their message or
+    /// chat, and the text is redacted under `PII_REDACTION=all`.
+    pub async fn save_moderation_record(&self, record: &ModerationRecord) -> Result<()> {
+        let mut stored = record.clone();
+        if redaction::mode() == redaction::RedactionMode::All {
+            stored.text = redaction::redact(&record.text).into_owned();
+        }
+        let mut batch = self.db.batch();
+        Self::stage_moderation_record(&mut batch, &stored)?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn stage_moderation_record(batch: &mut Batch, record: &ModerationRecord) -> Result<()> {
+        batch.put(
+            Self::moderation_key(record.ts, &record.message_id),
+            serde_json::to_vec(record)?,
+        );
+        batch.put(
+            Self::moderation_chat_key(&record.chat_id, record.ts, &record.message_id),
+            b"",
+        );
+        Ok(())
+    }
+
+    fn delete_moderation_record(batch: &mut Batch, chat_id: &str, ts: i64, message_id: &str) {
+        batch.delete(Self::moderation_key(ts, message_id));
+        batch.delete(Self::moderation_chat_key(chat_id, ts, message_id));
+    }
+
+    /// Every moderation record of a chat, including those of prompts that
+    /// were never stored as messages.
+    fn delete_chat_moderation_records(&self, batch: &mut Batch, chat_id: &str) -> Result<()> {
+        let prefix = format!("moderation_chat:{chat_id}:");
+        for key in self.scan_keys(&prefix)? {
+            if let Some((ts, message_id)) = key[prefix.len()..].split_once(':') {
+                batch.delete(format!("moderation:{ts}:{message_id}"));
+            }
+            batch.delete(key);
+        }
+        Ok(())
+    }
+
+    /// Index records stored before `moderation_chat:` existed, so they
+    /// are deleted with their chat.
+    pub(crate) fn ensure_moderation_chat_index(&self) -> Result<()> {
+        let mut records = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(b"moderation:", Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(b"moderation:") {
+                break;
+            }
+            if let Ok(record) = serde_json::from_slice::<ModerationRecord>(&val) {
+                records.push(record);
+            }
+        }
+        let mut batch = self.db.batch();
+        for record in &records {
+            Self::stage_moderation_record(&mut batch, record)?;
+        }
+        self.db.write(batch)?;
+        info!(
+            records = records.len(),
+            "indexed moderation records by chat"
+        );
+        Ok(())
+    }
+
+    /// Most recent flags first.
+    pub async fn list_moderation_records(&self, limit: usize) -> Result<Vec<ModerationRecord>> {
+        let prefix = "moderation:";
+        let mut out = Vec::new();
+        let start = format!("{prefix}~");
+        for item in self
+            .db
+            .iterator(IteratorMode::From(start.as_bytes(), Direction::Reverse))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            out.push(serde_json::from_slice(&val)?);
+            if out.len() >= limit {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // AUDIT LOG
+    // ============================================================
+    /// Entries are only ever added.
+    pub async fn append_audit_entry(&self, entry: &AuditEntry) -> Result<()> {
+        self.db.put(
+            format!("audit:{:020}:{}", entry.ts, entry.id),
+            serde_json::to_vec(entry)?,
+        )?;
+        Ok(())
+    }
+
+    /// Newest first, within `from..=to` (unix seconds) and matching the
+    /// actor and action filters.
+    pub async fn list_audit_entries(
+        &self,
+        from: Option<i64>,
+        to: Option<i64>,
+        actor: Option<&str>,
+        action: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>> {
+        let prefix = "audit:";
+        let start = match to {
+            Some(to) => format!("{prefix}{:020}~", to.max(0)),
+            None => format!("{prefix}~"),
+        };
+        let mut out = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(start.as_bytes(), Direction::Reverse))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let entry: AuditEntry = serde_json::from_slice(&val)?;
+            if from.is_some_and(|from| entry.ts < from) {
+                break;
+            }
+            if actor.is_some_and(|actor| !entry.actor_matches(actor))
+                || action.is_some_and(|action| entry.action != action)
+            {
+                continue;
+            }
+            out.push(entry);
+            if out.len() >= limit {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // REASONING CACHE
+    // ============================================================
+    pub async fn save_reasoning_result(&self, key: &str, result: &ReasoningResult) -> Result<()> {
+        self.db.put(
+            format!("reasoning_cache:{key}"),
+            serde_json::to_vec(result)?,
+        )?;
+        Ok(())
+    }
+
+    /// Cached result created after `min_created_ts`; older entries are
+    /// dropped when read.
+    pub async fn load_reasoning_result(
+        &self,
+        key: &str,
+        min_created_ts: i64,
+    ) -> Result<Option<ReasoningResult>> {
+        let db_key = format!("reasoning_cache:{key}");
+        let Some(raw) = self.db.get(&db_key)? else {
+            return Ok(None);
+        };
+        let result: ReasoningResult = serde_json::from_slice(&raw)?;
+        if result.created_ts < min_created_ts {
+            self.db.delete(&db_key)?;
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+
+    // ============================================================
+    // AGENT RUNS
+    // ============================================================
+    pub async fn save_agent_run(&self, record: &AgentRunRecord) -> Result<()> {
+        self.db.put(
+            format!("agent_run:{}", record.run_id),
+            serde_json::to_vec(record)?,
+        )?;
+        Ok(())
+    }
+
+    pub async fn load_agent_run(&self, run_id: &str) -> Result<Option<AgentRunRecord>> {
+        match self.db.get(format!("agent_run:{run_id}"))? {
+            Some(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    // ============================================================
+    // BULK JOBS
+    // ============================================================
+    pub async fn save_bulk_job(&self, job: &BulkJob) -> Result<()> {
+        self.db
+            .put(format!("bulk_job:{}", job.job_id), serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    pub async fn load_bulk_job(&self, job_id: &str) -> Result<Option<BulkJob>> {
+        match self.db.get(format!("bulk_job:{job_id}"))? {
+            Some(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    // ============================================================
+    // SHARE LINKS
+    // ============================================================
+    pub async fn save_share_link(&self, link: &ShareLink) -> Result<()> {
+        self.db
+            .put(format!("share_link:{}", link.id), serde_json::to_vec(link)?)?;
+        Ok(())
+    }
+
+    pub async fn load_share_link(&self, share_id: &str) -> Result<Option<ShareLink>> {
+        match self.db.get(format!("share_link:{share_id}"))? {
+            Some(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    // ============================================================
+    // PERSONAS
+    // ============================================================
+    pub async fn save_persona(&self, persona: &Persona) -> Result<()> {
+        self.db.put(
+            format!("persona:{}:{}", persona.user_id, persona.id),
+            serde_json::to_vec(persona)?,
+        )?;
+        Ok(())
+    }
+
+    pub async fn load_persona(&self, user_id: &str, persona_id: &str) -> Result<Option<Persona>> {
+        match self.db.get(format!("persona:{user_id}:{persona_id}"))? {
+            Some(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_personas(&self, user_id: &str) -> Result<Vec<Persona>> {
+        let prefix = format!("persona:{user_id}:");
+        let mut out = Vec::new();
+        for key in self.scan_keys(&prefix)? {
+            if let Some(raw) = self.db.get(&key)? {
+                out.push(serde_json::from_slice(&raw)?);
+            }
+        }
+        Ok(out)
+    }
+
+    pub async fn delete_persona(&self, user_id: &str, persona_id: &str) -> Result<()> {
+        self.db.delete(format!("persona:{user_id}:{persona_id}"))?;
+        Ok(())
+    }
+
+    // ============================================================
+    // SCHEDULES
+    // ============================================================
+    pub async fn save_schedule(&self, schedule: &Schedule) -> Result<()> {
+        self.db.put(
+            format!("schedule:{}:{}", schedule.user_id, schedule.id),
+            serde_json::to_vec(schedule)?,
+        )?;
+        Ok(())
+    }
+
+    pub async fn load_schedule(
+        &self,
+        user_id: &str,
+        schedule_id: &str,
+    ) -> Result<Option<Schedule>> {
+        match self.db.get(format!("schedule:{user_id}:{schedule_id}"))? {
+            Some(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_schedules_for_user(&self, user_id: &str) -> Result<Vec<Schedule>> {
+        self.scan_schedules(&format!("schedule:{user_id}:"))
+    }
+
+    /// Every user's schedules, for the scheduler loop.
+    pub async fn list_schedules(&self) -> Result<Vec<Schedule>> {
+        self.scan_schedules("schedule:")
+    }
+
+    fn scan_schedules(&self, prefix: &str) -> Result<Vec<Schedule>> {
+        let mut out = Vec::new();
+        for key in self.scan_keys(prefix)? {
+            if let Some(raw) = self.db.get(&key)? {
+                out.push(serde_json::from_slice(&raw)?);
+            }
+        }
+        Ok(out)
+    }
+
+    pub async fn delete_schedule(&self, user_id: &str, schedule_id: &str) -> Result<()> {
+        self.db
+            .delete(format!("schedule:{user_id}:{schedule_id}"))?;
+        Ok(())
+    }
+
+    // ============================================================
+    // WEBHOOKS
+    // ============================================================
+    pub async fn save_webhook(&self, hook: &Webhook) -> Result<()> {
+        self.db.put(
+            format!("webhook:{}:{}", hook.user_id, hook.id),
+            serde_json::to_vec(hook)?,
+        )?;
+        Ok(())
+    }
+
+    pub async fn load_webhook(&self, user_id: &str, webhook_id: &str) -> Result<Option<Webhook>> {
+        match self.db.get(format!("webhook:{user_id}:{webhook_id}"))? {
+            Some(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_webhooks(&self, user_id: &str) -> Result<Vec<Webhook>> {
+        let mut out = Vec::new();
+        for key in self.scan_keys(&format!("webhook:{user_id}:"))? {
+            if let Some(raw) = self.db.get(&key)? {
+                out.push(serde_json::from_slice(&raw)?);
+            }
+        }
+        Ok(out)
+    }
+
+    pub async fn delete_webhook(&self, user_id: &str, webhook_id: &str) -> Result<()> {
+        self.db.delete(format!("webhook:{user_id}:{webhook_id}"))?;
+        Ok(())
+    }
+
+    // ============================================================
+    // PROMPT OVERRIDES
+    // ============================================================
+    pub async fn save_prompt_override(&self, item: &PromptOverride) -> Result<()> {
+        self.db.put(
+            format!("prompt_override:{}:{}", item.language, item.key),
+            serde_json::to_vec(item)?,
+        )?;
+        Ok(())
+    }
+
+    pub async fn delete_prompt_override(&self, language: &str, key: &str) -> Result<()> {
+        self.db
+            .delete(format!("prompt_override:{language}:{key}"))?;
+        Ok(())
+    }
+
+    pub async fn list_prompt_overrides(&self) -> Result<Vec<PromptOverride>> {
+        let prefix = "prompt_override:";
+        let mut out = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            out.push(serde_json::from_slice(&val)?);
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // RETENTION OVERRIDES
+    // ============================================================
+    pub async fn save_retention_override(&self, item: &RetentionOverride) -> Result<()> {
+        self.db.put(
+            format!("retention_override:{}", item.user_id),
+            serde_json::to_vec(item)?,
+        )?;
+        Ok(())
+    }
+
+    pub async fn delete_retention_override(&self, user_id: &str) -> Result<()> {
+        self.db.delete(format!("retention_override:{user_id}"))?;
+        Ok(())
+    }
+
+    pub async fn list_retention_overrides(&self) -> Result<Vec<RetentionOverride>> {
+        let prefix = "retention_override:";
+        let mut out = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            out.push(serde_json::from_slice(&val)?);
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // VECTORS
+    // ============================================================
+    pub async fn put_vectors(&self, collection: &str, records: &[VectorRecord]) -> Result<()> {
+        let mut batch = self.db.batch();
+        for record in records {
+            batch.put(
+                format!("vector:{collection}:{}", record.id),
+                serde_json::to_vec(record)?,
+            );
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    pub async fn delete_vectors(&self, collection: &str, ids: &[String]) -> Result<()> {
+        let mut batch = self.db.batch();
+        for id in ids {
+            batch.delete(format!("vector:{collection}:{id}"));
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Visit every record of a collection; the brute-force search runs on
+    /// top of this without collecting the whole collection.
+    pub fn for_each_vector(
+        &self,
+        collection: &str,
+        mut visit: impl FnMut(VectorRecord),
+    ) -> Result<()> {
+        let prefix = format!("vector:{collection}:");
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            match serde_json::from_slice::<VectorRecord>(&val) {
+                Ok(record) => visit(record),
+                Err(err) => warn!(
+                    key = %String::from_utf8_lossy(&key),
+                    "skipping unreadable vector: {err}"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    // ============================================================
+    // COUNTERS
+    // ============================================================
+    pub async fn incr_counter(&self, name: &str, by: u64) -> Result<u64> {
+        let key = format!("counter:{name}");
+        let _guard = self.counter_lock.lock().unwrap();
+        let current = self
+            .db
+            .get(&key)?
+            .and_then(|v| str::from_utf8(&v).ok()?.parse::<u64>().ok())
+            .unwrap_or(0);
+        let next = current.saturating_add(by);
+        self.db.put(&key, next.to_string())?;
+        Ok(next)
+    }
+
+    pub async fn get_counter(&self, name: &str) -> Result<u64> {
+        let key = format!("counter:{name}");
+        Ok(self
+            .db
+            .get(key)?
+            .and_then(|v| str::from_utf8(&v).ok()?.parse::<u64>().ok())
+            .unwrap_or(0))
+    }
+
+    /// All counters whose name starts with `prefix`, keyed by the remainder.
+    pub async fn list_counters(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
+        let full_prefix = format!("counter:{prefix}");
+        let mut out = Vec::new();
+
+        for item in self.db.iterator(IteratorMode::From(
+            full_prefix.as_bytes(),
+            Direction::Forward,
+        )) {
+            let (key, val) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with(&full_prefix) {
+                break;
+            }
+            let count = str::from_utf8(&val)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(0);
+            out.push((k[full_prefix.len()..].to_string(), count));
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // MESSAGE STATS
+    // ============================================================
+
+    /// Messages and likes over all non-sandbox chats.
+    pub async fn message_totals(&self) -> Result<MessageCounts> {
+        self.ensure_message_stats().await?;
+        self.load_stats(stats::TOTALS_KEY)
+    }
+
+    /// A chat's message counts and latest summary.
+    pub async fn chat_stats(&self, chat_id: &str) -> Result<ChatStats> {
+        self.ensure_message_stats().await?;
+        self.load_stats(&stats::chat_key(chat_id))
+    }
+
+    fn load_stats<T: Default + serde::de::DeserializeOwned>(&self, key: &str) -> Result<T> {
+        Ok(self
+            .db
+            .get(key)?
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default())
+    }
+
+    fn chat_is_sandbox(&self, chat_id: &str) -> Result<bool> {
+        Ok(self
+            .db
+            .get(format!("chat:meta:{chat_id}"))?
+            .and_then(|v| serde_json::from_slice::<Chat>(&v).ok())
+            .is_some_and(|chat| chat.sandbox))
+    }
+
+    /// Add the stats update of a write replacing `old` with `new` (either
+    /// may be missing) to `batch`, which holds that write. `chat` is the
+    /// chat record written in the same batch, if any. Callers hold
+    /// `counter_lock` until the batch is written.
+    fn track_message_write(
+        &self,
+        batch: &mut Batch,
+        old: Option<&Message>,
+        new: Option<&Message>,
+        chat: Option<&Chat>,
+    ) -> Result<()> {
+        let Some(chat_id) = new.or(old).map(|m| m.chat_id.clone()) else {
+            return Ok(());
+        };
+        let delta = CountDelta::between(old, new);
+        let chat_key = stats::chat_key(&chat_id);
+        let mut chat_stats: ChatStats = self.load_stats(&chat_key)?;
+        chat_stats.counts.apply(delta);
+        match (old, new) {
+            (Some(old), new) if chat_stats.loses_summary(&old.id) => {
+                chat_stats.summary = self.latest_summary(&chat_id, &old.id)?;
+                if let Some(new) = new {
+                    chat_stats.offer_summary(new);
+                }
+            }
+            (_, Some(new)) => chat_stats.offer_summary(new),
+            _ => {}
+        }
+
+        batch.put(chat_key, serde_json::to_vec(&chat_stats)?);
+        let sandbox = match chat {
+            Some(chat) => chat.sandbox,
+            None => self.chat_is_sandbox(&chat_id)?,
+        };
+        if !delta.is_zero() && !sandbox {
+            let mut totals: MessageCounts = self.load_stats(stats::TOTALS_KEY)?;
+            totals.apply(delta);
+            batch.put(stats::TOTALS_KEY, serde_json::to_vec(&totals)?);
+        }
+        Ok(())
+    }
+
+    /// Add forgetting a deleted chat's stats to `batch`. Callers hold
+    /// `counter_lock` until the batch is written.
+    fn drop_chat_stats(&self, batch: &mut Batch, chat_id: &str, sandbox: bool) -> Result<()> {
+        let chat_key = stats::chat_key(chat_id);
+        let chat: ChatStats = self.load_stats(&chat_key)?;
+        batch.delete(chat_key);
+        if !sandbox {
+            let mut totals: MessageCounts = self.load_stats(stats::TOTALS_KEY)?;
+            totals.apply(CountDelta::removing(chat.counts));
+            batch.put(stats::TOTALS_KEY, serde_json::to_vec(&totals)?);
+        }
+        Ok(())
+    }
+
+    /// The chat's latest summary other than `removed`, which is being
+    /// deleted or replaced in a batch not yet written.
+    fn latest_summary(&self, chat_id: &str, removed: &str) -> Result<Option<SummaryRef>> {
+        let prefix = format!("chat:{}:msg:", chat_id);
+        let mut chat = ChatStats::default();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if let Ok(msg) = serde_json::from_slice::<Message>(&val) {
+                if msg.id != removed {
+                    chat.offer_summary(&msg);
+                }
+            }
+        }
+        Ok(chat.summary)
+    }
+
+    /// Count every stored message once; later writes keep the counts up
+    /// to date.
+    async fn ensure_message_stats(&self) -> Result<()> {
+        if self.db.get(stats::BUILT_FLAG)?.is_some() {
+            return Ok(());
+        }
+        let sandbox: HashSet<String> = self
+            .list_sandbox_chats()
+            .await?
+            .into_iter()
+            .map(|chat| chat.id)
+            .collect();
+
+        let _guard = self.counter_lock.lock().unwrap();
+        for key in self.scan_keys(stats::PREFIX)? {
+            self.db.delete(key)?;
+        }
+        let mut chats: HashMap<String, ChatStats> = HashMap::new();
+        let mut totals = MessageCounts::default();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(b"chat:", Direction::Forward))
+        {
+            let (key, val) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with("chat:") {
+                break;
+            }
+            if k.starts_with("chat:meta:") || !k.contains(":msg:") {
+                continue;
+            }
+            let Ok(msg) = serde_json::from_slice::<Message>(&val) else {
+                continue;
+            };
+            let delta = CountDelta::between(None, Some(&msg));
+            if !sandbox.contains(&msg.chat_id) {
+                totals.apply(delta);
+            }
+            let chat = chats.entry(msg.chat_id.clone()).or_default();
+            chat.counts.apply(delta);
+            chat.offer_summary(&msg);
+        }
+
+        let mut batch = self.db.batch();
+        for (chat_id, chat) in &chats {
+            batch.put(stats::chat_key(chat_id), serde_json::to_vec(chat)?);
+        }
+        batch.put(stats::TOTALS_KEY, serde_json::to_vec(&totals)?);
+        batch.put(stats::BUILT_FLAG, b"1");
+        self.db.write(batch)?;
+        info!(
+            chats = chats.len(),
+            messages = totals.messages,
+            "message stats built"
+        );
+        Ok(())
+    }
+
+    // ============================================================
+    // MESSAGE SEARCH INDEX
+    // ============================================================
+    fn search_key(term: &str, chat_id: &str, message_id: &str) -> String {
+        format!("search:{term}:{chat_id}:{message_id}")
+    }
+
+    /// One key per distinct term of the message text; the value is the
+    /// message key.
+    fn index_message(batch: &mut Batch, msg_key: &str, msg: &Message) {
+        let Some(text) = msg.text.as_deref() else {
+            return;
+        };
+        if !search::is_indexed_role(&msg.role) {
+            return;
+        }
+        for term in search::terms(text) {
+            batch.put(Self::search_key(&term, &msg.chat_id, &msg.id), msg_key);
+        }
+    }
+
+    fn unindex_message(batch: &mut Batch, msg: &Message) {
+        let Some(text) = msg.text.as_deref() else {
+            return;
+        };
+        for term in search::terms(text) {
+            batch.delete(Self::search_key(&term, &msg.chat_id, &msg.id));
+        }
+    }
+
+    /// Index every stored message once; later messages are indexed as they
+    /// are saved.
+    async fn ensure_search_index(&self) -> Result<()> {
+        if self.db.get(SEARCH_INDEX_FLAG)?.is_some() {
+            return Ok(());
+        }
+
+        for key in self.scan_keys("search:")? {
+            self.db.delete(key)?;
+        }
+        let mut indexed = 0usize;
+        for item in self
+            .db
+            .iterator(IteratorMode::From(b"chat:", Direction::Forward))
+        {
+            let (key, val) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with("chat:") {
+                break;
+            }
+            if k.starts_with("chat:meta:") || !k.contains(":msg:") {
+                continue;
+            }
+            let Ok(msg) = serde_json::from_slice::<Message>(&val) else {
+                continue;
+            };
+            let mut batch = self.db.batch();
+            Self::index_message(&mut batch, k, &normalize_message(msg));
+            self.db.write(batch)?;
+            indexed += 1;
+        }
+
+        self.db.put(SEARCH_INDEX_FLAG, b"1")?;
+        info!(messages = indexed, "message search index built");
+        Ok(())
+    }
+
+    /// Messages whose text contains every term, newest first. The first
+    /// call builds the index over the messages stored so far.
+    pub async fn search_messages(
+        &self,
+        terms: &BTreeSet<String>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        if terms.is_empty() || limit == 0 {
+            return Ok(Vec::new());
+        }
+        self.ensure_search_index().await?;
+
+        let mut postings = Vec::new();
+        for term in terms {
+            let prefix = format!("search:{term}:");
+            let mut keys = HashSet::new();
+            for item in self
+                .db
+                .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+            {
+                let (key, val) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                keys.insert(String::from_utf8(val.to_vec())?);
+            }
+            postings.push(keys);
+        }
+        postings.sort_by_key(|keys| keys.len());
+        let (rarest, rest) = postings.split_first().expect("at least one term");
+        let mut candidates: Vec<&String> = rarest
+            .iter()
+            .filter(|key| rest.iter().all(|keys| keys.contains(*key)))
+            .collect();
+        // Message keys end in `:{ts:020}:{id}`, so sorting by the part
+        // after `:msg:` orders by timestamp.
+        candidates.sort_by_cached_key(|key| {
+            Reverse(key.split_once(":msg:").map(|(_, rest)| rest.to_string()))
+        });
+
+        let mut out = Vec::new();
+        for key in candidates {
+            let Some(val) = self.db.get(key)? else {
+                continue;
+            };
+            let msg = normalize_message(serde_json::from_slice(&val)?);
+            // The text may have changed since it was indexed.
+            let current = search::terms(msg.text.as_deref().unwrap_or_default());
+            if !terms.is_subset(&current) {
+                continue;
+            }
+            out.push(msg);
+            if out.len() >= limit {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    // ============================================================
+    // STATS & COMPACTION
+    // ============================================================
+    /// DB-wide properties are the same in every family.
+    fn int_property(&self, name: &str) -> Option<u64> {
+        self.db.int_property(name)
+    }
+
+    /// Size and compaction state of each column family.
+    pub fn column_family_stats(&self) -> Vec<FamilyStats> {
+        Family::ALL
+            .iter()
+            .map(|family| self.db.family_stats(*family))
+            .collect()
+    }
+
+    /// RocksDB internals for spotting write stalls: per-level file counts,
+    /// compaction backlog, memtable usage and the active tuning. Sizes and
+    /// file counts add up all column families.
+    pub fn db_stats(&self) -> DbStats {
+        let families = self.column_family_stats();
+        let sum = |field: fn(&FamilyStats) -> Option<u64>| {
+            families.iter().filter_map(field).reduce(|a, b| a + b)
+        };
+        let files_per_level = (0..7)
+            .map(|level| {
+                families
+                    .iter()
+                    .filter_map(|f| f.files_per_level.get(level))
+                    .sum()
+            })
+            .collect();
+
+        DbStats {
+            backend: self.db.backend(),
+            estimated_keys: sum(|f| f.estimated_keys),
+            live_data_bytes: sum(|f| f.live_data_bytes),
+            sst_files_bytes: sum(|f| f.sst_files_bytes),
+            memtable_bytes: sum(|f| f.memtable_bytes),
+            files_per_level,
+            compaction_pending: families.iter().any(|f| f.compaction_pending),
+            pending_compaction_bytes: sum(|f| f.pending_compaction_bytes),
+            running_compactions: self.int_property("rocksdb.num-running-compactions"),
+            running_flushes: self.int_property("rocksdb.num-running-flushes"),
+            delayed_write_rate: self.int_property("rocksdb.actual-delayed-write-rate"),
+            write_stopped: self.int_property("rocksdb.is-write-stopped") == Some(1),
+            manual_compaction_running: self.compacting.load(AtomicOrdering::SeqCst),
+            level_stats: self.db.property(Family::Default, "rocksdb.levelstats"),
+            tuning: self.tuning.clone(),
+        }
+    }
+
+    /// Full-range compaction. Reads and writes keep going while it runs;
+    /// returns `false` without doing anything if one is already running.
+    pub fn compact_all(&self) -> bool {
+        if self.compacting.swap(true, AtomicOrdering::SeqCst) {
+            return false;
+        }
+        self.db.compact_all();
+        self.compacting.store(false, AtomicOrdering::SeqCst);
+        true
+    }
+
+    /// Round trip through the DB for health checks.
+    pub fn probe(&self) -> Result<()> {
+        let value = chrono::Utc::now().timestamp().to_string();
+        self.db.put("health:probe", &value)?;
+        match self.db.get("health:probe")? {
+            Some(read) if read == value.as_bytes() => Ok(()),
+            _ => Err(anyhow::anyhow!("probe value mismatch")),
+        }
+    }
+
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.load(AtomicOrdering::SeqCst)
+    }
+
+    // ============================================================
+    // INTEGRITY CHECKS
+    // ============================================================
+    /// Scan for data left behind by partial deletes: messages without chat
+    /// metadata, chats whose owner no longer exists, and files under
+    /// `storage_root` that no message attachment references.
+    ///
+    /// With `repair` set, orphaned messages and files are deleted and chats
+    /// of deleted users are removed together with their messages.
+    pub async fn check_integrity(
+        &self,
+        storage_root: Option<&Path>,
+        repair: bool,
+    ) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport {
+            repaired: repair,
+            ..IntegrityReport::default()
+        };
+
+        // 1. Messages whose chat metadata is gone
+        let chat_ids: HashSet<String> = self
+            .list_chats()
+            .await?
+            .into_iter()
+            .map(|chat| chat.id)
+            .collect();
+
+        let mut orphan_keys = Vec::new();
+        let prefix = "chat:";
+
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, _) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            let Some(msg_pos) = k.find(":msg:") else {
+                continue;
+            };
+            let chat_id = &k[prefix.len()..msg_pos];
+
+            if !chat_ids.contains(chat_id) {
+                report.orphaned_messages.push(OrphanedMessage {
+                    chat_id: chat_id.to_string(),
+                    key: k.to_string(),
+                });
+                orphan_keys.push(key);
+            }
+        }
+
+        // 2. Chats owned by users that no longer exist
+        let user_ids: HashSet<String> = self
+            .list_users()
+            .await?
+            .into_iter()
+            .map(|user| user.id)
+            .collect();
+
+        for chat in self.list_chats().await? {
+            if let Some(user_id) = chat.user_id.as_deref() {
+                if !user_ids.contains(user_id) {
+                    report.chats_without_owner.push(chat.id.clone());
+                }
+            }
+        }
+
+        // 3. Stored files nothing points at (and references to missing files)
+        let referenced_paths = self.referenced_file_paths().await?;
+        if let Some(root) = storage_root.filter(|root| root.exists()) {
+            let referenced: HashSet<PathBuf> = referenced_paths
+                .iter()
+                .map(|p| fs::canonicalize(p).unwrap_or_else(|_| p.clone()))
+                .collect();
+
+            for file in walk_files(root) {
+                let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+                if !referenced.contains(&canonical) {
+                    let size = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+                    report.unreferenced_bytes += size;
+                    report.unreferenced_files.push(file.display().to_string());
+                }
+            }
+        }
+
+        for path in &referenced_paths {
+            if !path.exists() {
+                report.missing_files.push(path.display().to_string());
+            }
+        }
+
+        if repair {
+            {
+                let _guard = self.counter_lock.lock().unwrap();
+                for key in orphan_keys {
+                    let orphan = self
+                        .db
+                        .get(&key)?
+                        .and_then(|v| serde_json::from_slice::<Message>(&v).ok());
+                    let mut batch = self.db.batch();
+                    batch.delete(&key);
+                    if let Some(orphan) = orphan {
+                        self.track_message_write(&mut batch, Some(&orphan), None, None)?;
+                    }
+                    self.db.write(batch)?;
+                }
+            }
+            for chat_id in &report.chats_without_owner {
+                self.delete_thread(chat_id).await?;
+            }
+            for file in &report.unreferenced_files {
+                if let Err(err) = fs::remove_file(file) {
+                    warn!(
+                        path = file.as_str(),
+                        "failed to remove orphaned file: {err}"
+                    );
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Paths of stored files something still points at: message
+    /// attachments and upload records (uploads not attached yet still
+    /// belong to someone).
+    pub async fn referenced_file_paths(&self) -> Result<HashSet<PathBuf>> {
+        let mut paths = HashSet::new();
+        let prefix = "chat:";
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
+        {
+            let (key, val) = item?;
+            let k = str::from_utf8(&key)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            if !k.contains(":msg:") {
+                continue;
+            }
+            if let Ok(msg) = serde_json::from_slice::<Message>(&val) {
+                paths.extend(
+                    msg.attachments
+                        .into_iter()
+                        .filter_map(|a| a.path)
+                        .map(PathBuf::from),
+                );
+            }
+        }
+        for upload in self.list_uploads().await? {
+            paths.insert(PathBuf::from(upload.path));
+        }
+        Ok(paths)
+    }
+}
+
+fn normalize_message(mut msg: Message) -> Message {
+    normalize_option_text(&mut msg.text);
+    for attachment in msg.attachments.iter_mut() {
+        normalize_option_text(&mut attachment.description);
+        normalize_option_text(&mut attachment.ocr_text);
+    }
+    msg
+}
+
+fn normalize_option_text(target: &mut Option<String>) {
+    if let Some(text) = target.take() {
+        let normalized = tidy_decoded_text(&text);
+        target.replace(normalized);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbStats {
+    /// `rocksdb` or `postgres`; the RocksDB-only fields are empty on the
+    /// latter.
+    pub backend: &'static str,
+    pub estimated_keys: Option<u64>,
+    pub live_data_bytes: Option<u64>,
+    pub sst_files_bytes: Option<u64>,
+    pub memtable_bytes: Option<u64>,
+    /// SST file count for levels 0..=6.
+    pub files_per_level: Vec<u64>,
+    pub compaction_pending: bool,
+    pub pending_compaction_bytes: Option<u64>,
+    pub running_compactions: Option<u64>,
+    pub running_flushes: Option<u64>,
+    /// Non-zero while RocksDB is throttling writes.
+    pub delayed_write_rate: Option<u64>,
+    pub write_stopped: bool,
+    pub manual_compaction_running: bool,
+    /// `levelstats` of the default column family; see
+    /// `/internal/admin/db/column-families` for the others.
+    pub level_stats: Option<String>,
+    pub tuning: DbTuning,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct IntegrityReport {
+    pub repaired: bool,
+    pub orphaned_messages: Vec<OrphanedMessage>,
+    pub chats_without_owner: Vec<String>,
+    pub unreferenced_files: Vec<String>,
+    pub unreferenced_bytes: u64,
+    pub missing_files: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_messages.is_empty()
+            && self.chats_without_owner.is_empty()
+            && self.unreferenced_files.is_empty()
+            && self.missing_files.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrphanedMessage {
+    pub chat_id: String,
+    pub key: String,
+}
+
+pub(crate) fn walk_files(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut stack: VecDeque<PathBuf> = VecDeque::new();
+    stack.push_back(root.to_path_buf());
+    while let Some(dir) = stack.pop_front() {
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(rd) => rd,
+            Err(_) => continue,
+        };
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                stack.push_back(path);
+            } else {
+                files.push(path);
+            }
+        }
     }
+    files
 }
@@ -0,0 +1,211 @@
+//! Where [`DBLayer`](super::DBLayer) keeps its records. `DBLayer` reads
+//! and writes plain keys through [`Store`], which routes each key to its
+//! [`Family`] and hands it to a [`Persistence`] backend: RocksDB by
+//! default, or Postgres (`postgres` feature) for deployments that want
+//! standard backups, replication and SQL reporting.
+
+use anyhow::{bail, Result};
+use rocksdb::IteratorMode;
+
+use super::{
+    families::{Family, FamilyStats},
+    rocks::RocksPersistence,
+    tuning::DbTuning,
+};
+
+/// A key and its value, as scans return them.
+pub type Entry = (Box<[u8]>, Box<[u8]>);
+
+/// Entries in key order, or reverse key order for a backward scan.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+#[derive(Debug, Clone)]
+pub enum WriteOp {
+    Put {
+        family: Family,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        family: Family,
+        key: Vec<u8>,
+    },
+}
+
+/// A key-value store with one ordered keyspace per [`Family`]. Keys
+/// compare bytewise, as in RocksDB.
+pub trait Persistence: Send + Sync {
+    /// `rocksdb` or `postgres`.
+    fn name(&self) -> &'static str;
+
+    fn get(&self, family: Family, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Apply `ops` in order, all or none.
+    fn write(&self, ops: Vec<WriteOp>) -> Result<()>;
+
+    /// Entries of `family` from the key in `mode` on, or from either end.
+    fn scan(&self, family: Family, mode: IteratorMode<'_>) -> Entries<'_>;
+
+    fn family_stats(&self, family: Family) -> FamilyStats;
+
+    /// A DB-wide RocksDB property; `None` on other backends.
+    fn int_property(&self, _name: &str) -> Option<u64> {
+        None
+    }
+
+    /// A RocksDB property of one family; `None` on other backends.
+    fn property(&self, _family: Family, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Compact every family; backends that compact on their own ignore it.
+    fn compact_all(&self) {}
+}
+
+/// `DB_BACKEND=postgres` (with `DATABASE_URL`) keeps the records in
+/// Postgres when the `postgres` feature is compiled in; anything else uses
+/// RocksDB under `path`. Unlike the vector store, a Postgres backend that
+/// cannot be used fails the start instead of falling back, so records
+/// never land in a RocksDB nobody backs up.
+pub fn from_env(path: &str, tuning: &DbTuning) -> Result<Box<dyn Persistence>> {
+    let backend = std::env::var("DB_BACKEND")
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match backend.as_str() {
+        "" | "rocksdb" => Ok(Box::new(RocksPersistence::open(path, tuning)?)),
+        "postgres" => postgres_from_env(),
+        other => bail!("unknown DB_BACKEND {other} (rocksdb or postgres)"),
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_from_env() -> Result<Box<dyn Persistence>> {
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+        bail!("DB_BACKEND=postgres needs DATABASE_URL");
+    };
+    Ok(Box::new(super::postgres::PostgresPersistence::connect(
+        &url,
+    )?))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn postgres_from_env() -> Result<Box<dyn Persistence>> {
+    bail!("DB_BACKEND=postgres but built without the `postgres` feature")
+}
+
+/// The [`Persistence`] backend with keys routed to their family.
+pub struct Store {
+    backend: Box<dyn Persistence>,
+}
+
+/// Writes applied together by [`Store::write`].
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<WriteOp>,
+}
+
+impl Batch {
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        let key = key.as_ref();
+        self.ops.push(WriteOp::Put {
+            family: Family::of(key),
+            key: key.to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = key.as_ref();
+        self.ops.push(WriteOp::Delete {
+            family: Family::of(key),
+            key: key.to_vec(),
+        });
+    }
+}
+
+impl Store {
+    pub fn new(backend: Box<dyn Persistence>) -> Self {
+        Self { backend }
+    }
+
+    pub fn backend(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        self.backend.get(Family::of(key), key)
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        let mut batch = self.batch();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let mut batch = self.batch();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    pub fn batch(&self) -> Batch {
+        Batch::default()
+    }
+
+    pub fn write(&self, batch: Batch) -> Result<()> {
+        if batch.ops.is_empty() {
+            return Ok(());
+        }
+        self.backend.write(batch.ops)
+    }
+
+    /// Scan the family of the start key; `Start` and `End` walk the
+    /// default family.
+    pub fn iterator(&self, mode: IteratorMode<'_>) -> Entries<'_> {
+        let family = match mode {
+            IteratorMode::From(key, _) => Family::of(key),
+            IteratorMode::Start | IteratorMode::End => Family::Default,
+        };
+        self.backend.scan(family, mode)
+    }
+
+    pub fn int_property(&self, name: &str) -> Option<u64> {
+        self.backend.int_property(name)
+    }
+
+    pub fn property(&self, family: Family, name: &str) -> Option<String> {
+        self.backend.property(family, name)
+    }
+
+    pub fn family_stats(&self, family: Family) -> FamilyStats {
+        self.backend.family_stats(family)
+    }
+
+    pub fn compact_all(&self) {
+        self.backend.compact_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_routes_keys_to_their_family() {
+        let mut batch = Batch::default();
+        batch.put("chat:meta:c1", "{}");
+        batch.delete("vector:docs:1");
+        match &batch.ops[..] {
+            [WriteOp::Put { family, key, .. }, WriteOp::Delete {
+                family: deleted, ..
+            }] => {
+                assert_eq!(*family, Family::Chats);
+                assert_eq!(key, b"chat:meta:c1");
+                assert_eq!(*deleted, Family::Embeddings);
+            }
+            ops => panic!("unexpected ops: {ops:?}"),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! The Postgres [`Persistence`] backend (`postgres` feature,
+//! `DB_BACKEND=postgres`). Every record is a row of `kv(family, key,
+//! value)`, so `pg_dump`, streaming replication and point-in-time
+//! recovery work as for any other table. The `kv_messages`, `kv_chats`
+//! and `kv_users` views expose those records as `jsonb` for reporting.
+//!
+//! `DBLayer` is synchronous, so queries run on a small runtime of their
+//! own and the caller waits for them, as it waits for RocksDB's disk
+//! reads. That runtime also sweeps expired `transient` rows, which
+//! RocksDB drops on compaction.
+
+use std::{collections::VecDeque, future::Future, time::Duration};
+
+use anyhow::{Context, Result};
+use rocksdb::{Direction, IteratorMode};
+use sqlx::{postgres::PgPoolOptions, PgPool, Row};
+use tokio::runtime::Runtime;
+
+use super::{
+    families::{expired, Family, FamilyStats},
+    persistence::{Entries, Entry, Persistence, WriteOp},
+};
+
+const POOL_SIZE: u32 = 8;
+/// Rows fetched per round trip while scanning.
+const SCAN_PAGE: i64 = 256;
+const TRANSIENT_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS kv (
+        family TEXT NOT NULL,
+        key BYTEA NOT NULL,
+        value BYTEA NOT NULL,
+        PRIMARY KEY (family, key)
+    )",
+    "CREATE OR REPLACE VIEW kv_messages AS
+        SELECT convert_from(value, 'UTF8')::jsonb AS doc FROM kv WHERE family = 'messages'",
+    "CREATE OR REPLACE VIEW kv_chats AS
+        SELECT convert_from(value, 'UTF8')::jsonb AS doc FROM kv WHERE family = 'chats'",
+    "CREATE OR REPLACE VIEW kv_users AS
+        SELECT convert_from(value, 'UTF8')::jsonb AS doc FROM kv
+        WHERE family = 'users' AND key LIKE 'user:%'",
+];
+
+pub struct PostgresPersistence {
+    pool: PgPool,
+    /// Taken on drop: a runtime cannot be dropped inside another one.
+    runtime: Option<Runtime>,
+}
+
+impl PostgresPersistence {
+    /// Connect to `url` and create the table and views if missing.
+    pub fn connect(url: &str) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("postgres-db")
+            .enable_all()
+            .build()?;
+        let url = url.to_string();
+        let pool = run(&runtime, async move {
+            let pool = PgPoolOptions::new()
+                .max_connections(POOL_SIZE)
+                .connect(&url)
+                .await
+                .context("connecting to DATABASE_URL")?;
+            for statement in SCHEMA {
+                sqlx::query(statement).execute(&pool).await?;
+            }
+            Ok(pool)
+        })?;
+        runtime.spawn(sweep_transient(
+            pool.clone(),
+            crate::inference::reasoning::cache_ttl(),
+        ));
+        Ok(Self {
+            pool,
+            runtime: Some(runtime),
+        })
+    }
+
+    fn run<T: Send + 'static>(
+        &self,
+        fut: impl Future<Output = Result<T>> + Send + 'static,
+    ) -> Result<T> {
+        let runtime = self.runtime.as_ref().expect("postgres runtime in use");
+        run(runtime, fut)
+    }
+
+    /// One page of a scan of `family` past `cursor` (or from it, when
+    /// `inclusive`), in key order or reverse key order.
+    fn page(
+        &self,
+        family: Family,
+        cursor: Option<Vec<u8>>,
+        inclusive: bool,
+        forward: bool,
+    ) -> Result<Vec<Entry>> {
+        let op = match (forward, inclusive) {
+            (true, true) => ">=",
+            (true, false) => ">",
+            (false, true) => "<=",
+            (false, false) => "<",
+        };
+        let bound = if cursor.is_some() {
+            format!("AND key {op} $2")
+        } else {
+            String::new()
+        };
+        let order = if forward { "ASC" } else { "DESC" };
+        let sql = format!(
+            "SELECT key, value FROM kv WHERE family = $1 {bound} ORDER BY key {order} LIMIT {SCAN_PAGE}"
+        );
+        let pool = self.pool.clone();
+        self.run(async move {
+            let mut query = sqlx::query(&sql).bind(family.name());
+            if let Some(cursor) = cursor {
+                query = query.bind(cursor);
+            }
+            let rows = query.fetch_all(&pool).await?;
+            rows.into_iter()
+                .map(|row| -> Result<Entry> {
+                    let key: Vec<u8> = row.try_get("key")?;
+                    let value: Vec<u8> = row.try_get("value")?;
+                    Ok((key.into_boxed_slice(), value.into_boxed_slice()))
+                })
+                .collect()
+        })
+    }
+}
+
+/// Run `fut` on `runtime` and wait for it. Works from inside another
+/// runtime's tasks, unlike `Runtime::block_on`.
+fn run<T: Send + 'static>(
+    runtime: &Runtime,
+    fut: impl Future<Output = Result<T>> + Send + 'static,
+) -> Result<T> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    runtime.spawn(async move {
+        let _ = tx.send(fut.await);
+    });
+    rx.recv().context("postgres query task dropped")?
+}
+
+/// Delete expired `transient` rows (revoked access tokens, stale
+/// reasoning results) every [`TRANSIENT_SWEEP_INTERVAL`].
+async fn sweep_transient(pool: PgPool, reasoning_ttl: i64) {
+    let mut ticker = tokio::time::interval(TRANSIENT_SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match sweep_transient_once(&pool, reasoning_ttl).await {
+            Ok(0) => {}
+            Ok(rows) => tracing::info!(rows, "postgres swept expired transient rows"),
+            Err(err) => tracing::warn!("postgres transient sweep failed: {err}"),
+        }
+    }
+}
+
+async fn sweep_transient_once(pool: &PgPool, reasoning_ttl: i64) -> Result<u64> {
+    let now = chrono::Utc::now().timestamp();
+    let rows = sqlx::query("SELECT key, value FROM kv WHERE family = $1")
+        .bind(Family::Transient.name())
+        .fetch_all(pool)
+        .await?;
+    let stale: Vec<Vec<u8>> = rows
+        .into_iter()
+        .filter_map(|row| {
+            let key: Vec<u8> = row.try_get("key").ok()?;
+            let value: Vec<u8> = row.try_get("value").ok()?;
+            expired(&key, &value, now, reasoning_ttl).then_some(key)
+        })
+        .collect();
+    if stale.is_empty() {
+        return Ok(0);
+    }
+    let deleted = sqlx::query("DELETE FROM kv WHERE family = $1 AND key = ANY($2)")
+        .bind(Family::Transient.name())
+        .bind(stale)
+        .execute(pool)
+        .await?;
+    Ok(deleted.rows_affected())
+}
+
+impl Persistence for PostgresPersistence {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn get(&self, family: Family, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let pool = self.pool.clone();
+        let key = key.to_vec();
+        self.run(async move {
+            let row = sqlx::query("SELECT value FROM kv WHERE family = $1 AND key = $2")
+                .bind(family.name())
+                .bind(key)
+                .fetch_optional(&pool)
+                .await?;
+            Ok(row
+                .map(|row| row.try_get::<Vec<u8>, _>("value"))
+                .transpose()?)
+        })
+    }
+
+    fn write(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let pool = self.pool.clone();
+        self.run(async move {
+            let mut tx = pool.begin().await?;
+            for op in ops {
+                match op {
+                    WriteOp::Put { family, key, value } => {
+                        sqlx::query(
+                            "INSERT INTO kv (family, key, value) VALUES ($1, $2, $3)
+                             ON CONFLICT (family, key) DO UPDATE SET value = EXCLUDED.value",
+                        )
+                        .bind(family.name())
+                        .bind(key)
+                        .bind(value)
+                        .execute(&mut *tx)
+                        .await?;
+                    }
+                    WriteOp::Delete { family, key } => {
+                        sqlx::query("DELETE FROM kv WHERE family = $1 AND key = $2")
+                            .bind(family.name())
+                            .bind(key)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                }
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn scan(&self, family: Family, mode: IteratorMode<'_>) -> Entries<'_> {
+        let (cursor, forward) = match mode {
+            IteratorMode::Start => (None, true),
+            IteratorMode::End => (None, false),
+            IteratorMode::From(key, direction) => {
+                (Some(key.to_vec()), matches!(direction, Direction::Forward))
+            }
+        };
+        Box::new(PgScan {
+            store: self,
+            family,
+            forward,
+            cursor,
+            inclusive: true,
+            page: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    fn family_stats(&self, family: Family) -> FamilyStats {
+        let pool = self.pool.clone();
+        let counts = self.run(async move {
+            let row = sqlx::query(
+                "SELECT count(*)::BIGINT AS keys,
+                        coalesce(sum(octet_length(key) + octet_length(value)), 0)::BIGINT AS bytes
+                 FROM kv WHERE family = $1",
+            )
+            .bind(family.name())
+            .fetch_one(&pool)
+            .await?;
+            let keys: i64 = row.try_get("keys")?;
+            let bytes: i64 = row.try_get("bytes")?;
+            Ok((keys as u64, bytes as u64))
+        });
+        let (keys, bytes) = match counts {
+            Ok((keys, bytes)) => (Some(keys), Some(bytes)),
+            Err(err) => {
+                tracing::warn!(
+                    family = family.name(),
+                    "postgres family stats failed: {err}"
+                );
+                (None, None)
+            }
+        };
+        FamilyStats {
+            name: family.name(),
+            estimated_keys: keys,
+            live_data_bytes: bytes,
+            sst_files_bytes: None,
+            memtable_bytes: None,
+            files_per_level: Vec::new(),
+            compaction_pending: false,
+            pending_compaction_bytes: None,
+            compaction_stats: None,
+        }
+    }
+}
+
+impl Drop for PostgresPersistence {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// A scan that fetches [`SCAN_PAGE`] rows at a time as it is read.
+struct PgScan<'a> {
+    store: &'a PostgresPersistence,
+    family: Family,
+    forward: bool,
+    cursor: Option<Vec<u8>>,
+    /// The first page includes the start key; later ones start past the
+    /// last key read.
+    inclusive: bool,
+    page: VecDeque<Entry>,
+    done: bool,
+}
+
+impl Iterator for PgScan<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            let page = self.store.page(
+                self.family,
+                self.cursor.clone(),
+                self.inclusive,
+                self.forward,
+            );
+            match page {
+                Ok(rows) => {
+                    self.done = (rows.len() as i64) < SCAN_PAGE;
+                    if let Some((key, _)) = rows.last() {
+                        self.cursor = Some(key.to_vec());
+                        self.inclusive = false;
+                    }
+                    self.page.extend(rows);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,182 @@
+//! The RocksDB [`Persistence`] backend: one column family per [`Family`],
+//! each opened with its tailored options.
+
+use std::path::Path;
+
+use anyhow::Result;
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, ReadOptions, WriteBatch,
+    WriteOptions, DB,
+};
+use tracing::info;
+
+use super::{
+    families::{is_message_key, message_prefix, Family, FamilyStats},
+    persistence::{Entries, Persistence, WriteOp},
+    tuning::DbTuning,
+};
+
+/// Set in the default family once keys written before column families
+/// were moved into theirs.
+const LAYOUT_FLAG: &str = "cf_layout:v1";
+/// Writes per batch while moving keys.
+const MIGRATE_BATCH: usize = 20_000;
+
+/// The RocksDB handle with every column family open; writes use the
+/// configured durability.
+pub struct RocksPersistence {
+    db: DB,
+    write_opts: WriteOptions,
+}
+
+impl RocksPersistence {
+    /// Open the DB with every family, creating missing ones, and move
+    /// keys from a DB written before families existed into theirs.
+    pub fn open(path: impl AsRef<Path>, tuning: &DbTuning) -> Result<Self> {
+        let mut db_opts = tuning.db_options();
+        db_opts.create_missing_column_families(true);
+        let reasoning_ttl = crate::inference::reasoning::cache_ttl();
+        let descriptors = Family::ALL.iter().map(|family| {
+            let opts = if *family == Family::Default {
+                tuning.db_options()
+            } else {
+                family.options(tuning, reasoning_ttl)
+            };
+            ColumnFamilyDescriptor::new(family.name(), opts)
+        });
+        let store = Self {
+            db: DB::open_cf_descriptors(&db_opts, path, descriptors)?,
+            write_opts: tuning.write_options(),
+        };
+        store.migrate_default()?;
+        Ok(store)
+    }
+
+    fn cf(&self, family: Family) -> &ColumnFamily {
+        self.db
+            .cf_handle(family.name())
+            .expect("column family opened with the db")
+    }
+
+    /// Move keys that belong to another family out of the default one.
+    /// Runs once; an interrupted run picks up where it stopped.
+    fn migrate_default(&self) -> Result<()> {
+        let default = self.cf(Family::Default);
+        if self.db.get_cf(default, LAYOUT_FLAG)?.is_some() {
+            return Ok(());
+        }
+        let mut moved = 0usize;
+        let mut batch = WriteBatch::default();
+        for item in self.db.iterator_cf(default, IteratorMode::Start) {
+            let (key, value) = item?;
+            let family = Family::of(&key);
+            if family == Family::Default {
+                continue;
+            }
+            batch.put_cf(self.cf(family), &key, &value);
+            batch.delete_cf(default, &key);
+            moved += 1;
+            if batch.len() >= MIGRATE_BATCH {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        batch.put_cf(default, LAYOUT_FLAG, b"1");
+        self.db.write(batch)?;
+        if moved > 0 {
+            info!(moved, "moved keys into their column families");
+        }
+        Ok(())
+    }
+
+    fn cf_int_property(&self, family: Family, name: &str) -> Option<u64> {
+        self.db
+            .property_int_value_cf(self.cf(family), name)
+            .ok()
+            .flatten()
+    }
+}
+
+impl Persistence for RocksPersistence {
+    fn name(&self) -> &'static str {
+        "rocksdb"
+    }
+
+    fn get(&self, family: Family, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get_cf(self.cf(family), key)?)
+    }
+
+    fn write(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                WriteOp::Put { family, key, value } => batch.put_cf(self.cf(family), key, value),
+                WriteOp::Delete { family, key } => batch.delete_cf(self.cf(family), key),
+            }
+        }
+        self.db.write_opt(batch, &self.write_opts)?;
+        Ok(())
+    }
+
+    /// A scan from one chat's `chat:{id}:msg:` stays within that prefix,
+    /// others walk the family in key order.
+    fn scan(&self, family: Family, mode: IteratorMode<'_>) -> Entries<'_> {
+        let mut read_opts = ReadOptions::default();
+        match mode {
+            IteratorMode::From(key, Direction::Forward)
+                if family == Family::Messages
+                    && is_message_key(key)
+                    && message_prefix(key) == key =>
+            {
+                read_opts.set_prefix_same_as_start(true);
+            }
+            _ => read_opts.set_total_order_seek(true),
+        }
+        Box::new(
+            self.db
+                .iterator_cf_opt(self.cf(family), read_opts, mode)
+                .map(|item| item.map_err(Into::into)),
+        )
+    }
+
+    fn family_stats(&self, family: Family) -> FamilyStats {
+        let files_per_level = (0..7)
+            .map(|level| {
+                self.cf_int_property(family, &format!("rocksdb.num-files-at-level{level}"))
+                    .unwrap_or(0)
+            })
+            .collect();
+        FamilyStats {
+            name: family.name(),
+            estimated_keys: self.cf_int_property(family, "rocksdb.estimate-num-keys"),
+            live_data_bytes: self.cf_int_property(family, "rocksdb.estimate-live-data-size"),
+            sst_files_bytes: self.cf_int_property(family, "rocksdb.total-sst-files-size"),
+            memtable_bytes: self.cf_int_property(family, "rocksdb.cur-size-all-mem-tables"),
+            files_per_level,
+            compaction_pending: self.cf_int_property(family, "rocksdb.compaction-pending")
+                == Some(1),
+            pending_compaction_bytes: self
+                .cf_int_property(family, "rocksdb.estimate-pending-compaction-bytes"),
+            compaction_stats: self.property(family, "rocksdb.cfstats-no-file-histogram"),
+        }
+    }
+
+    /// DB-wide properties are the same in every family.
+    fn int_property(&self, name: &str) -> Option<u64> {
+        self.cf_int_property(Family::Default, name)
+    }
+
+    fn property(&self, family: Family, name: &str) -> Option<String> {
+        self.db
+            .property_value_cf(self.cf(family), name)
+            .ok()
+            .flatten()
+    }
+
+    /// Full-range compaction of every family, one after the other.
+    fn compact_all(&self) {
+        for family in Family::ALL {
+            self.db
+                .compact_range_cf::<&[u8], &[u8]>(self.cf(family), None, None);
+        }
+    }
+}
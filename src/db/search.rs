@@ -0,0 +1,122 @@
+//! Terms and highlights for the message search index. Text is split on
+//! anything that is not a letter or digit and lowercased; the index keeps
+//! one `search:{term}:{chat_id}:{message_id}` key per distinct term.
+
+use std::collections::BTreeSet;
+
+/// Shorter words are not indexed.
+const MIN_TERM_CHARS: usize = 2;
+/// Longer words are indexed by their prefix.
+const MAX_TERM_CHARS: usize = 32;
+/// Context kept before the first match in a highlight.
+const CONTEXT_BEFORE_CHARS: usize = 60;
+/// Highlight length from the first match on.
+const SNIPPET_CHARS: usize = 200;
+
+/// Roles whose text is indexed.
+pub fn is_indexed_role(role: &str) -> bool {
+    matches!(role, "user" | "assistant")
+}
+
+/// Byte ranges of the words in `text`.
+fn word_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (idx, ch) in text.char_indices() {
+        match (ch.is_alphanumeric(), start) {
+            (true, None) => start = Some(idx),
+            (false, Some(s)) => {
+                spans.push((s, idx));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+fn normalize_term(word: &str) -> Option<String> {
+    if word.chars().count() < MIN_TERM_CHARS {
+        return None;
+    }
+    Some(word.to_lowercase().chars().take(MAX_TERM_CHARS).collect())
+}
+
+/// Distinct index terms of `text`, sorted.
+pub fn terms(text: &str) -> BTreeSet<String> {
+    word_spans(text)
+        .into_iter()
+        .filter_map(|(s, e)| normalize_term(&text[s..e]))
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// HTML-escaped snippet of `text` around its first match, with every
+/// matching word wrapped in `<mark>`. `None` when nothing matches.
+pub fn highlight(text: &str, terms: &BTreeSet<String>) -> Option<String> {
+    let is_match = |&(s, e): &(usize, usize)| {
+        normalize_term(&text[s..e]).is_some_and(|term| terms.contains(&term))
+    };
+    let words = word_spans(text);
+    let first = words.iter().find(|span| is_match(span))?.0;
+    let from = text[..first]
+        .char_indices()
+        .rev()
+        .nth(CONTEXT_BEFORE_CHARS - 1)
+        .map_or(0, |(idx, _)| idx);
+    let to = text[first..]
+        .char_indices()
+        .nth(SNIPPET_CHARS)
+        .map_or(text.len(), |(idx, _)| first + idx);
+
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    let mut cursor = from;
+    for span in words.iter().filter(|&&(s, e)| s >= from && e <= to) {
+        if is_match(span) {
+            out.push_str(&escape_html(&text[cursor..span.0]));
+            out.push_str("<mark>");
+            out.push_str(&escape_html(&text[span.0..span.1]));
+            out.push_str("</mark>");
+            cursor = span.1;
+        }
+    }
+    out.push_str(&escape_html(&text[cursor..to]));
+    if to < text.len() {
+        out.push('…');
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terms_are_lowercased_words() {
+        let found: Vec<String> = terms("Привет, World! a <b>world</b> 42")
+            .into_iter()
+            .collect();
+        assert_eq!(found, vec!["42", "world", "привет"]);
+    }
+
+    #[test]
+    fn highlights_matches_with_escaped_context() {
+        let query = terms("refund");
+        let text = format!("{}I <need> a Refund, refund!", "x ".repeat(50));
+        let snippet = highlight(&text, &query).unwrap();
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with("I &lt;need&gt; a <mark>Refund</mark>, <mark>refund</mark>!"));
+        assert_eq!(highlight("nothing here", &query), None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Message counts kept up to date as messages are written, so the admin
+//! overview does not have to read every message. Each chat has a
+//! `stats:chat:{chat_id}` record, and `stats:messages` holds the totals
+//! over non-sandbox chats.
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::message::Message;
+
+pub const TOTALS_KEY: &str = "stats:messages";
+pub const BUILT_FLAG: &str = "stats:built";
+pub const PREFIX: &str = "stats:";
+
+pub fn chat_key(chat_id: &str) -> String {
+    format!("stats:chat:{chat_id}")
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageCounts {
+    pub messages: u64,
+    pub liked: u64,
+}
+
+impl MessageCounts {
+    pub fn apply(&mut self, delta: CountDelta) {
+        self.messages = self.messages.saturating_add_signed(delta.messages);
+        self.liked = self.liked.saturating_add_signed(delta.liked);
+    }
+}
+
+/// How one write changes [`MessageCounts`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CountDelta {
+    pub messages: i64,
+    pub liked: i64,
+}
+
+impl CountDelta {
+    /// `old` is the stored message a write replaces or deletes, `new` what
+    /// is stored after it.
+    pub fn between(old: Option<&Message>, new: Option<&Message>) -> Self {
+        let count = |msg: Option<&Message>| match msg {
+            Some(msg) => (1, i64::from(msg.liked)),
+            None => (0, 0),
+        };
+        let (old_messages, old_liked) = count(old);
+        let (new_messages, new_liked) = count(new);
+        Self {
+            messages: new_messages - old_messages,
+            liked: new_liked - old_liked,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The delta of removing `counts`.
+    pub fn removing(counts: MessageCounts) -> Self {
+        Self {
+            messages: -(counts.messages as i64),
+            liked: -(counts.liked as i64),
+        }
+    }
+}
+
+/// A chat's counts and its latest `summary` message.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatStats {
+    #[serde(flatten)]
+    pub counts: MessageCounts,
+    #[serde(default)]
+    pub summary: Option<SummaryRef>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SummaryRef {
+    pub message_id: String,
+    pub ts: i64,
+    pub text: Option<String>,
+}
+
+impl ChatStats {
+    /// Take `msg` as the chat's summary when it is one and not older than
+    /// the current one.
+    pub fn offer_summary(&mut self, msg: &Message) {
+        if msg.role != "summary" || self.summary.as_ref().is_some_and(|s| s.ts > msg.ts) {
+            return;
+        }
+        self.summary = Some(SummaryRef {
+            message_id: msg.id.clone(),
+            ts: msg.ts,
+            text: msg.text.clone(),
+        });
+    }
+
+    /// Whether removing message `message_id` leaves the summary unknown.
+    pub fn loses_summary(&self, message_id: &str) -> bool {
+        self.summary
+            .as_ref()
+            .is_some_and(|s| s.message_id == message_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, role: &str, ts: i64, liked: bool) -> Message {
+        Message {
+            id: id.into(),
+            chat_id: "c".into(),
+            session_id: None,
+            user_id: None,
+            device_hash: None,
+            role: role.into(),
+            text: Some(format!("{id} text")),
+            language: None,
+            attachments: Vec::new(),
+            liked,
+            feedback: None,
+            ts,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn counts_follow_saves_likes_and_deletes() {
+        let reply = message("a", "assistant", 2, false);
+        let liked = message("a", "assistant", 2, true);
+        let mut stats = ChatStats::default();
+        stats.counts.apply(CountDelta::between(None, Some(&reply)));
+        stats
+            .counts
+            .apply(CountDelta::between(Some(&reply), Some(&liked)));
+        assert_eq!(
+            stats.counts,
+            MessageCounts {
+                messages: 1,
+                liked: 1
+            }
+        );
+        assert!(CountDelta::between(Some(&liked), Some(&liked)).is_zero());
+        stats.counts.apply(CountDelta::between(Some(&liked), None));
+        assert_eq!(stats.counts, MessageCounts::default());
+
+        stats.offer_summary(&message("s2", "summary", 5, false));
+        stats.offer_summary(&message("s1", "summary", 3, false));
+        stats.offer_summary(&reply);
+        assert!(stats.loses_summary("s2"));
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["messages"], 0);
+    }
+}
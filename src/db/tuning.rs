@@ -0,0 +1,188 @@
+//! RocksDB options read from the environment (`config/rocksdb.env`), so
+//! write stalls can be addressed without a rebuild. Unset knobs keep the
+//! RocksDB defaults.
+
+use std::collections::BTreeMap;
+
+use rocksdb::{DBCompressionType, Options, WriteOptions};
+use serde::Serialize;
+
+const MIB: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DbTuning {
+    pub write_buffer_size: Option<usize>,
+    pub max_write_buffer_number: Option<i32>,
+    pub max_background_jobs: Option<i32>,
+    pub level0_slowdown_writes_trigger: Option<i32>,
+    pub level0_stop_writes_trigger: Option<i32>,
+    pub target_file_size_base: Option<u64>,
+    /// Compression for every column family without its own setting.
+    pub compression: Option<String>,
+    pub bottommost_compression: Option<String>,
+    /// Per column family compression, from `ROCKSDB_COMPRESSION_<CF>`.
+    pub cf_compression: BTreeMap<String, String>,
+    pub durability: Durability,
+    /// Sync the WAL in the background every this many bytes.
+    pub wal_bytes_per_sync: Option<u64>,
+}
+
+/// How far a write has to get before it returns, from
+/// `ROCKSDB_DURABILITY`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Written to the WAL and fsynced: survives power loss.
+    Sync,
+    /// Written to the WAL without waiting for fsync: survives a crash of
+    /// the server, but the last writes can be lost on power loss.
+    #[default]
+    Wal,
+    /// No WAL: writes still in the memtable are lost in a crash.
+    None,
+}
+
+impl Durability {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sync" => Some(Self::Sync),
+            "wal" => Some(Self::Wal),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+fn env_durability() -> Durability {
+    let Ok(value) = std::env::var("ROCKSDB_DURABILITY") else {
+        return Durability::default();
+    };
+    let value = value.trim().to_ascii_lowercase();
+    Durability::parse(&value).unwrap_or_else(|| {
+        tracing::warn!("ROCKSDB_DURABILITY={value} is not sync, wal or none, using wal");
+        Durability::default()
+    })
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
+
+fn env_compression(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok()?.trim().to_ascii_lowercase();
+    if parse_compression(&value).is_some() {
+        Some(value)
+    } else {
+        tracing::warn!("{name}={value} is not a known compression, ignoring");
+        None
+    }
+}
+
+pub fn parse_compression(name: &str) -> Option<DBCompressionType> {
+    match name {
+        "none" => Some(DBCompressionType::None),
+        "snappy" => Some(DBCompressionType::Snappy),
+        "zlib" => Some(DBCompressionType::Zlib),
+        "lz4" => Some(DBCompressionType::Lz4),
+        "lz4hc" => Some(DBCompressionType::Lz4hc),
+        "zstd" => Some(DBCompressionType::Zstd),
+        _ => None,
+    }
+}
+
+impl DbTuning {
+    /// `ROCKSDB_WRITE_BUFFER_MB`, `ROCKSDB_MAX_WRITE_BUFFERS`,
+    /// `ROCKSDB_MAX_BACKGROUND_JOBS`, `ROCKSDB_L0_SLOWDOWN_TRIGGER`,
+    /// `ROCKSDB_L0_STOP_TRIGGER`, `ROCKSDB_TARGET_FILE_MB`,
+    /// `ROCKSDB_COMPRESSION`, `ROCKSDB_BOTTOMMOST_COMPRESSION`,
+    /// `ROCKSDB_COMPRESSION_<CF>` for each name in `column_families`,
+    /// `ROCKSDB_DURABILITY` and `ROCKSDB_WAL_BYTES_PER_SYNC`.
+    pub fn from_env(column_families: &[&str]) -> Self {
+        let cf_compression = column_families
+            .iter()
+            .filter_map(|cf| {
+                let var = format!("ROCKSDB_COMPRESSION_{}", cf.to_ascii_uppercase());
+                env_compression(&var).map(|value| (cf.to_string(), value))
+            })
+            .collect();
+
+        Self {
+            write_buffer_size: env_parse::<usize>("ROCKSDB_WRITE_BUFFER_MB")
+                .filter(|mb| *mb > 0)
+                .map(|mb| mb * MIB),
+            max_write_buffer_number: env_parse("ROCKSDB_MAX_WRITE_BUFFERS"),
+            max_background_jobs: env_parse("ROCKSDB_MAX_BACKGROUND_JOBS"),
+            level0_slowdown_writes_trigger: env_parse("ROCKSDB_L0_SLOWDOWN_TRIGGER"),
+            level0_stop_writes_trigger: env_parse("ROCKSDB_L0_STOP_TRIGGER"),
+            target_file_size_base: env_parse::<u64>("ROCKSDB_TARGET_FILE_MB")
+                .filter(|mb| *mb > 0)
+                .map(|mb| mb * MIB as u64),
+            compression: env_compression("ROCKSDB_COMPRESSION"),
+            bottommost_compression: env_compression("ROCKSDB_BOTTOMMOST_COMPRESSION"),
+            cf_compression,
+            durability: env_durability(),
+            wal_bytes_per_sync: env_parse::<u64>("ROCKSDB_WAL_BYTES_PER_SYNC").filter(|b| *b > 0),
+        }
+    }
+
+    /// Options every write goes out with, per [`Durability`].
+    pub fn write_options(&self) -> WriteOptions {
+        let mut opts = WriteOptions::default();
+        match self.durability {
+            Durability::Sync => opts.set_sync(true),
+            Durability::Wal => {}
+            Durability::None => opts.disable_wal(true),
+        }
+        opts
+    }
+
+    /// DB-wide options; also applies the settings of the default column
+    /// family.
+    pub fn db_options(&self) -> Options {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.enable_statistics();
+        if let Some(jobs) = self.max_background_jobs {
+            opts.set_max_background_jobs(jobs);
+        }
+        if let Some(bytes) = self.wal_bytes_per_sync {
+            opts.set_wal_bytes_per_sync(bytes);
+        }
+        self.apply_cf(&mut opts, rocksdb::DEFAULT_COLUMN_FAMILY_NAME);
+        opts
+    }
+
+    /// Memtable, level and compression settings for one column family.
+    pub fn apply_cf(&self, opts: &mut Options, cf: &str) {
+        if let Some(size) = self.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(count) = self.max_write_buffer_number {
+            opts.set_max_write_buffer_number(count);
+        }
+        if let Some(trigger) = self.level0_slowdown_writes_trigger {
+            opts.set_level_zero_slowdown_writes_trigger(trigger);
+        }
+        if let Some(trigger) = self.level0_stop_writes_trigger {
+            opts.set_level_zero_stop_writes_trigger(trigger);
+        }
+        if let Some(size) = self.target_file_size_base {
+            opts.set_target_file_size_base(size);
+        }
+        let compression = self
+            .cf_compression
+            .get(cf)
+            .or(self.compression.as_ref())
+            .and_then(|name| parse_compression(name));
+        if let Some(compression) = compression {
+            opts.set_compression_type(compression);
+        }
+        if let Some(compression) = self
+            .bottommost_compression
+            .as_deref()
+            .and_then(parse_compression)
+        {
+            opts.set_bottommost_compression_type(compression);
+        }
+    }
+}
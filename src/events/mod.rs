@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+pub use crate::inference::backend::FinishReason;
+use crate::{
+    moderation::{ModerationCategory, ModerationSource},
+    status::Incident,
+};
+
+const EVENT_BUS_CAPACITY: usize = 256;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AssistantMessageFinalized {
+    pub message_id: String,
+    pub chat_id: String,
+    pub session_id: String,
+    pub request_id: String,
+    pub user_id: Option<String>,
+    pub device_hash: Option<String>,
+    pub intent: Option<String>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    pub ts: i64,
+}
+
+/// The short title summary of a chat was stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryCreated {
+    pub message_id: String,
+    pub chat_id: String,
+    pub user_id: Option<String>,
+    pub device_hash: Option<String>,
+    pub text: String,
+    pub ts: i64,
+}
+
+/// A prompt was flagged by moderation and answered with the safety prompt.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModerationFlagged {
+    pub message_id: String,
+    pub chat_id: String,
+    pub user_id: Option<String>,
+    pub device_hash: Option<String>,
+    pub category: ModerationCategory,
+    pub source: ModerationSource,
+    pub ts: i64,
+}
+
+/// A model canary case that used to pass (or any case after a model or
+/// prompt change) now fails.
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryRegression {
+    pub fingerprint: String,
+    pub previous_fingerprint: Option<String>,
+    pub failures: Vec<String>,
+    pub ts: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    AssistantMessageFinalized(AssistantMessageFinalized),
+    SummaryCreated(SummaryCreated),
+    ModerationFlagged(ModerationFlagged),
+    CanaryRegression(CanaryRegression),
+    /// A component left `operational`, got worse, or recovered
+    /// (`resolved_ts` set).
+    ComponentIncident(Incident),
+}
+
+/// In-process fan-out for integration events. Publishing never blocks and
+/// is a no-op when nobody is subscribed.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+/// Forward every event as a JSON POST to `EVENTS_WEBHOOK_URL`, if set.
+/// `EVENTS_WEBHOOK_SECRET` is sent as a bearer token when present.
+pub fn spawn_webhook_forwarder(bus: &EventBus) -> bool {
+    let Some(url) = dotenvy::var("EVENTS_WEBHOOK_URL")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+    else {
+        return false;
+    };
+    let secret = dotenvy::var("EVENTS_WEBHOOK_SECRET")
+        .ok()
+        .filter(|v| !v.is_empty());
+
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("events webhook disabled: {err}");
+            return false;
+        }
+    };
+
+    let mut rx = bus.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "events webhook lagging; events dropped");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            deliver(&client, &url, secret.as_deref(), &event).await;
+        }
+    });
+
+    info!("events webhook forwarder started");
+    true
+}
+
+/// POST `event` to `url`, retrying with backoff on failure.
+pub(crate) async fn deliver<T: Serialize + ?Sized>(
+    client: &reqwest::Client,
+    url: &str,
+    secret: Option<&str>,
+    event: &T,
+) {
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let mut req = client.post(url).json(event);
+        if let Some(secret) = secret {
+            req = req.bearer_auth(secret);
+        }
+        match req.send().await {
+            Ok(resp) if resp.status().is_success() => {
+                debug!(attempt, "event delivered to webhook");
+                return;
+            }
+            Ok(resp) => warn!(attempt, status = %resp.status(), "events webhook rejected event"),
+            Err(err) => warn!(attempt, "events webhook request failed: {err}"),
+        }
+        if attempt < WEBHOOK_ATTEMPTS {
+            tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1))).await;
+        }
+    }
+}
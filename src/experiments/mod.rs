@@ -0,0 +1,425 @@
+//! A/B experiments over prompts and routing, defined in
+//! `config/experiments.yaml` (`EXPERIMENTS_CONFIG`). Every chat lands in
+//! one arm of each enabled experiment, picked from a hash of the experiment
+//! name and the chat id, so a chat keeps its arm across turns and restarts.
+//! Arms can swap prompt keys, turn the hidden reasoning pass off and change
+//! the sampling temperature. Messages and ws frames are tagged with the
+//! arms, and outcomes are counted per arm.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::db::DBLayer;
+
+/// Hash buckets per experiment; `traffic` is resolved to this precision.
+const BUCKETS: u64 = 10_000;
+/// Prompt key mapping that applies to every key.
+const ANY_KEY: &str = "*";
+
+static CONFIG: Lazy<RwLock<Arc<ExperimentsConfig>>> = Lazy::new(|| {
+    let path = config_path();
+    let config = if path.exists() {
+        ExperimentsConfig::from_file(&path).unwrap_or_else(|err| {
+            warn!("experiments config: {err:#}; no experiments running");
+            ExperimentsConfig::default()
+        })
+    } else {
+        ExperimentsConfig::default()
+    };
+    RwLock::new(Arc::new(config))
+});
+
+fn config_path() -> PathBuf {
+    std::env::var("EXPERIMENTS_CONFIG")
+        .unwrap_or_else(|_| "config/experiments.yaml".to_string())
+        .into()
+}
+
+/// Experiments in effect.
+pub fn current() -> Arc<ExperimentsConfig> {
+    CONFIG.read().expect("experiments lock poisoned").clone()
+}
+
+/// Re-read the config file. The experiments in effect are kept when the
+/// file is unreadable or invalid.
+pub fn reload() -> Result<Arc<ExperimentsConfig>> {
+    let path = config_path();
+    let config = Arc::new(ExperimentsConfig::from_file(&path)?);
+    *CONFIG.write().expect("experiments lock poisoned") = config.clone();
+    info!(
+        path = %path.display(),
+        experiments = config.experiments.len(),
+        "experiments config reloaded"
+    );
+    Ok(config)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExperimentsConfig {
+    #[serde(default)]
+    pub experiments: Vec<Experiment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Share of chats enrolled, 0..=1.
+    #[serde(default = "default_traffic")]
+    pub traffic: f64,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Prompt key replacements; `"*"` replaces every key. Safety prompts
+    /// are never replaced.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prompt_keys: BTreeMap<String, String>,
+    /// `false` skips the hidden reasoning pass; unset keeps the routing
+    /// decision.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_traffic() -> f64 {
+    1.0
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl ExperimentsConfig {
+    pub fn from_file(path: &std::path::Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        Self::parse(&raw).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        let config: ExperimentsConfig = serde_yaml::from_str(raw)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for experiment in &self.experiments {
+            if !valid_name(&experiment.name) {
+                bail!(
+                    "experiment `{}`: names use letters, digits, `_` and `-`",
+                    experiment.name
+                );
+            }
+            if !names.insert(experiment.name.as_str()) {
+                bail!("experiment `{}` is defined twice", experiment.name);
+            }
+            if !(0.0..=1.0).contains(&experiment.traffic) {
+                bail!(
+                    "experiment `{}`: traffic must be within 0..=1",
+                    experiment.name
+                );
+            }
+            if experiment.variants.iter().map(|v| v.weight).sum::<u32>() == 0 {
+                bail!("experiment `{}` has no weighted variant", experiment.name);
+            }
+            let mut variants = HashSet::new();
+            for variant in &experiment.variants {
+                if !valid_name(&variant.name) || !variants.insert(variant.name.as_str()) {
+                    bail!(
+                        "experiment `{}`: variant `{}` is invalid or repeated",
+                        experiment.name,
+                        variant.name
+                    );
+                }
+                if variant.temperature.is_some_and(|t| !(t > 0.0 && t <= 2.0)) {
+                    bail!(
+                        "experiment `{}`, variant `{}`: temperature must be within (0, 2]",
+                        experiment.name,
+                        variant.name
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Arms of `chat_id` in the enabled experiments.
+    pub fn assign(&self, chat_id: &str) -> Assignment {
+        let mut assignment = Assignment::default();
+        for experiment in self.experiments.iter().filter(|e| e.enabled) {
+            if let Some(variant) = experiment.pick(chat_id) {
+                assignment.arms.push(Arm {
+                    experiment: experiment.name.clone(),
+                    variant: variant.name.clone(),
+                });
+                assignment.variants.push(variant.clone());
+            }
+        }
+        assignment
+    }
+}
+
+impl Experiment {
+    fn pick(&self, chat_id: &str) -> Option<&Variant> {
+        let digest = Sha256::digest(format!("{}:{chat_id}", self.name).as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+        if (hash % BUCKETS) as f64 >= self.traffic * BUCKETS as f64 {
+            return None;
+        }
+        let total: u64 = self.variants.iter().map(|v| v.weight as u64).sum();
+        let mut slot = (hash / BUCKETS) % total;
+        self.variants.iter().find(|variant| {
+            if slot < variant.weight as u64 {
+                return true;
+            }
+            slot -= variant.weight as u64;
+            false
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Arm {
+    pub experiment: String,
+    pub variant: String,
+}
+
+/// What a chat's arms change. Later experiments in the config win when
+/// they set the same thing.
+#[derive(Debug, Clone, Default)]
+pub struct Assignment {
+    pub arms: Vec<Arm>,
+    variants: Vec<Variant>,
+}
+
+impl Assignment {
+    pub fn is_empty(&self) -> bool {
+        self.arms.is_empty()
+    }
+
+    /// Prompt key to use instead of `key`, if an arm swaps it.
+    pub fn prompt_key(&self, key: &str) -> Option<String> {
+        if key.starts_with("safety_") {
+            return None;
+        }
+        self.variants.iter().rev().find_map(|variant| {
+            variant
+                .prompt_keys
+                .get(key)
+                .or_else(|| variant.prompt_keys.get(ANY_KEY))
+                .cloned()
+        })
+    }
+
+    pub fn reasoning_enabled(&self) -> bool {
+        self.variants
+            .iter()
+            .rev()
+            .find_map(|variant| variant.reasoning)
+            .unwrap_or(true)
+    }
+
+    pub fn temperature(&self) -> Option<f32> {
+        self.variants
+            .iter()
+            .rev()
+            .find_map(|variant| variant.temperature)
+    }
+
+    /// `{experiment: variant}` for message meta and ws frames.
+    pub fn tags(&self) -> Value {
+        tags(&self.arms)
+    }
+}
+
+pub fn tags(arms: &[Arm]) -> Value {
+    Value::Object(
+        arms.iter()
+            .map(|arm| (arm.experiment.clone(), Value::String(arm.variant.clone())))
+            .collect(),
+    )
+}
+
+/// Arms recorded in a message's meta.
+pub fn arms_from_meta(meta: Option<&Value>) -> Vec<Arm> {
+    meta.and_then(|meta| meta.get("experiments"))
+        .and_then(Value::as_object)
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|(experiment, variant)| {
+                    Some(Arm {
+                        experiment: experiment.clone(),
+                        variant: variant.as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Outcome counted per arm.
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Prompt,
+    Regeneration,
+    Reply,
+    ReplyChars,
+    Like,
+    Unlike,
+}
+
+impl Outcome {
+    fn counter(self) -> &'static str {
+        match self {
+            Outcome::Prompt => "prompts",
+            Outcome::Regeneration => "regenerations",
+            Outcome::Reply => "replies",
+            Outcome::ReplyChars => "reply_chars",
+            Outcome::Like => "likes",
+            Outcome::Unlike => "unlikes",
+        }
+    }
+}
+
+pub async fn record(db: &DBLayer, arms: &[Arm], outcome: Outcome, by: u64) {
+    for arm in arms {
+        let name = format!(
+            "experiment:{}:{}:{}",
+            arm.experiment,
+            arm.variant,
+            outcome.counter()
+        );
+        if let Err(err) = db.incr_counter(&name, by).await {
+            debug!("failed to count experiment outcome: {err}");
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ArmStats {
+    pub prompts: u64,
+    pub regenerations: u64,
+    pub replies: u64,
+    /// Likes minus likes taken back.
+    pub likes: u64,
+    pub like_rate: f64,
+    pub regeneration_rate: f64,
+    pub avg_reply_chars: f64,
+}
+
+/// Outcomes per experiment and variant since counting began, including
+/// experiments no longer in the config.
+pub async fn stats(db: &DBLayer) -> Result<BTreeMap<String, BTreeMap<String, ArmStats>>> {
+    let mut raw: BTreeMap<(String, String), BTreeMap<String, u64>> = BTreeMap::new();
+    for (name, count) in db.list_counters("experiment:").await? {
+        let mut parts = name.splitn(3, ':');
+        let (Some(experiment), Some(variant), Some(metric)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        raw.entry((experiment.to_string(), variant.to_string()))
+            .or_default()
+            .insert(metric.to_string(), count);
+    }
+
+    let mut out: BTreeMap<String, BTreeMap<String, ArmStats>> = BTreeMap::new();
+    for ((experiment, variant), counts) in raw {
+        let get = |metric: &str| counts.get(metric).copied().unwrap_or(0);
+        let ratio = |n: u64, d: u64| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+        let likes = get("likes").saturating_sub(get("unlikes"));
+        let stats = ArmStats {
+            prompts: get("prompts"),
+            regenerations: get("regenerations"),
+            replies: get("replies"),
+            likes,
+            like_rate: ratio(likes, get("replies")),
+            regeneration_rate: ratio(get("regenerations"), get("prompts")),
+            avg_reply_chars: ratio(get("reply_chars"), get("replies")),
+        };
+        out.entry(experiment).or_default().insert(variant, stats);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+experiments:
+  - name: casual_prompt
+    variants:
+      - { name: control, weight: 1 }
+      - name: narrative
+        weight: 1
+        prompt_keys: { chat_casual: chat_narrative }
+        reasoning: false
+        temperature: 0.4
+"#;
+
+    #[test]
+    fn assignment_is_stable_and_splits_chats() {
+        let config = ExperimentsConfig::parse(CONFIG).unwrap();
+        let first = config.assign("chat-1");
+        assert_eq!(first.arms, config.assign("chat-1").arms);
+
+        let narrative: Vec<Assignment> = (0..200)
+            .map(|i| config.assign(&format!("chat-{i}")))
+            .filter(|a| a.arms[0].variant == "narrative")
+            .collect();
+        assert!((60..140).contains(&narrative.len()));
+
+        let arm = &narrative[0];
+        assert_eq!(
+            arm.prompt_key("chat_casual").as_deref(),
+            Some("chat_narrative")
+        );
+        assert_eq!(arm.prompt_key("safety_refusal"), None);
+        assert!(!arm.reasoning_enabled());
+        assert_eq!(arm.temperature(), Some(0.4));
+    }
+
+    #[test]
+    fn rejects_invalid_experiments() {
+        assert!(ExperimentsConfig::parse(
+            "experiments:\n  - { name: a, traffic: 2, variants: [{ name: x }] }"
+        )
+        .is_err());
+        assert!(ExperimentsConfig::parse(
+            "experiments:\n  - { name: 'a:b', variants: [{ name: x }] }"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! Chat transcripts for end users: `GET /api/chats/{chat_id}/export`
+//! renders the user and assistant turns of a chat, with timestamps and
+//! attachment metadata, as Markdown or JSON for the chat owner.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use chrono::{DateTime, Utc};
+use headers::{authorization::Bearer, Authorization};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    auth::tokens::authenticate,
+    model::{
+        chat::Chat,
+        message::{Message, MessageAttachment},
+    },
+    ws::AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/api/chats/{chat_id}/export", get(export_handler))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// Rendered transcript of a chat.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChatTranscript {
+    pub chat_id: String,
+    pub title: Option<String>,
+    pub exported_ts: i64,
+    pub messages: Vec<TranscriptMessage>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TranscriptMessage {
+    pub id: String,
+    /// `user` or `assistant`.
+    pub role: String,
+    pub text: String,
+    pub ts: i64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<TranscriptAttachment>,
+}
+
+/// Attachment metadata; file contents and extracted text are left out.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TranscriptAttachment {
+    pub id: String,
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl From<&MessageAttachment> for TranscriptAttachment {
+    fn from(att: &MessageAttachment) -> Self {
+        Self {
+            id: att.id.clone(),
+            filename: att.filename.clone(),
+            mime_type: att.mime_type.clone(),
+            size: att.size,
+            description: att
+                .description
+                .clone()
+                .or_else(|| att.image_description.clone()),
+        }
+    }
+}
+
+impl ChatTranscript {
+    /// User and assistant turns in order; summaries and other internal
+    /// messages are skipped.
+    pub fn new(chat: &Chat, messages: &[Message], exported_ts: i64) -> Self {
+        let mut messages: Vec<&Message> = messages
+            .iter()
+            .filter(|m| m.role == "user" || m.role == "assistant")
+            .collect();
+        messages.sort_by_key(|m| m.ts);
+        Self {
+            chat_id: chat.id.clone(),
+            title: chat.title.clone(),
+            exported_ts,
+            messages: messages
+                .into_iter()
+                .map(|m| TranscriptMessage {
+                    id: m.id.clone(),
+                    role: m.role.clone(),
+                    text: m.text.clone().unwrap_or_default(),
+                    ts: m.ts,
+                    attachments: m.attachments.iter().map(Into::into).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let title = self.title.as_deref().unwrap_or("Chat");
+        let mut out = format!("# {title}\n\n_Exported {}_\n", format_ts(self.exported_ts));
+        for msg in &self.messages {
+            let speaker = if msg.role == "user" {
+                "You"
+            } else {
+                "Assistant"
+            };
+            out.push_str(&format!("\n### {speaker} · {}\n\n", format_ts(msg.ts)));
+            if !msg.text.trim().is_empty() {
+                out.push_str(msg.text.trim_end());
+                out.push('\n');
+            }
+            if !msg.attachments.is_empty() {
+                out.push('\n');
+            }
+            for att in &msg.attachments {
+                out.push_str(&format!("- 📎 {}", att.filename));
+                let details: Vec<String> = att
+                    .mime_type
+                    .iter()
+                    .cloned()
+                    .chain(att.size.map(format_size))
+                    .collect();
+                if !details.is_empty() {
+                    out.push_str(&format!(" ({})", details.join(", ")));
+                }
+                if let Some(description) = &att.description {
+                    out.push_str(&format!(": {}", description.trim()));
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Standalone read-only page, used for shared chats.
+    pub fn to_html(&self) -> String {
+        let title = escape_html(self.title.as_deref().unwrap_or("Chat"));
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+             <title>{title}</title><style>{HTML_STYLE}</style></head><body>\
+             <h1>{title}</h1><p class=\"meta\">Shared {}</p>\n",
+            format_ts(self.exported_ts)
+        );
+        for msg in &self.messages {
+            let speaker = if msg.role == "user" {
+                "User"
+            } else {
+                "Assistant"
+            };
+            out.push_str(&format!(
+                "<section class=\"{}\"><p class=\"meta\">{speaker} · {}</p><div class=\"text\">{}</div>",
+                msg.role,
+                format_ts(msg.ts),
+                escape_html(msg.text.trim_end())
+            ));
+            for att in &msg.attachments {
+                out.push_str(&format!(
+                    "<p class=\"attachment\">📎 {}</p>",
+                    escape_html(&att.filename)
+                ));
+            }
+            out.push_str("</section>\n");
+        }
+        out.push_str("</body></html>\n");
+        out
+    }
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:46rem;margin:2rem auto;padding:0 1rem;line-height:1.5}\
+section{margin:1rem 0;padding:.75rem 1rem;border-radius:.5rem}\
+section.user{background:#eef2ff}section.assistant{background:#f4f4f5}\
+.meta{color:#6b7280;font-size:.85rem;margin:0 0 .25rem}.text{white-space:pre-wrap}\
+.attachment{font-size:.9rem;margin:.25rem 0 0}";
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn format_ts(ts: i64) -> String {
+    DateTime::<Utc>::from_timestamp(ts, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| ts.to_string())
+}
+
+fn format_size(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{b} B"),
+    }
+}
+
+/// Load a chat for its owner: 404 `chat_not_found` when it does not exist,
+/// 403 `chat_owner_mismatch` when it belongs to someone else or to a device
+/// nobody has claimed.
+pub(crate) async fn load_owned_chat(
+    state: &AppState,
+    token: &str,
+    chat_id: &str,
+) -> Result<(Chat, Vec<Message>), (StatusCode, String)> {
+    let claims = authenticate(state, token).await?;
+    let chat = state
+        .db
+        .load_chat(chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "chat_not_found".to_string()))?;
+    if chat.user_id.as_deref() != Some(claims.sub.as_str()) {
+        return Err((StatusCode::FORBIDDEN, "chat_owner_mismatch".into()));
+    }
+    let messages = state
+        .db
+        .list_messages_for_chat(chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((chat, messages))
+}
+
+/// Download a chat transcript as Markdown or JSON.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{chat_id}/export",
+    tag = "chats",
+    params(
+        ("chat_id" = String, Path, description = "Chat id"),
+        ("format" = Option<ExportFormat>, Query, description = "`markdown` (default) or `json`"),
+    ),
+    responses(
+        (status = 200, description = "Transcript as a download: Markdown, or `ChatTranscript` JSON"),
+        (status = 400, description = "Unknown format"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "chat_owner_mismatch"),
+        (status = 404, description = "chat_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn export_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    Query(query): Query<ExportQuery>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Response, (StatusCode, String)> {
+    let (chat, messages) = load_owned_chat(&state, auth.token(), &chat_id).await?;
+    let transcript = ChatTranscript::new(&chat, &messages, Utc::now().timestamp());
+    let filename = |ext: &str| {
+        let safe: String = chat_id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect();
+        format!("attachment; filename=\"chat-{safe}.{ext}\"")
+    };
+    Ok(match query.format {
+        ExportFormat::Markdown => (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    "text/markdown; charset=utf-8".to_string(),
+                ),
+                (header::CONTENT_DISPOSITION, filename("md")),
+            ],
+            transcript.to_markdown(),
+        )
+            .into_response(),
+        ExportFormat::Json => (
+            [(header::CONTENT_DISPOSITION, filename("json"))],
+            Json(transcript),
+        )
+            .into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, text: &str, ts: i64) -> Message {
+        Message {
+            id: format!("m{ts}"),
+            chat_id: "c1".into(),
+            session_id: None,
+            user_id: Some("u1".into()),
+            device_hash: None,
+            role: role.into(),
+            text: Some(text.into()),
+            language: None,
+            attachments: Vec::new(),
+            liked: false,
+            feedback: None,
+            ts,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn markdown_lists_turns_and_attachments() {
+        let chat = Chat {
+            id: "c1".into(),
+            title: Some("Trip plan".into()),
+            user_id: Some("u1".into()),
+            device_hash: None,
+            updated_ts: 0,
+            meta: None,
+            sandbox: false,
+            language: Default::default(),
+            system_prompt_override: None,
+            folder: None,
+            pinned: false,
+            archived: false,
+        };
+        let mut question = message("user", "Where should I go?", 60);
+        question.attachments.push(MessageAttachment {
+            id: "f1".into(),
+            filename: "map.png".into(),
+            mime_type: Some("image/png".into()),
+            preview_base64: Some("AAAA".into()),
+            path: Some("/data/f1".into()),
+            size: Some(2048),
+            description: None,
+            ocr_text: None,
+            text_chunks: Vec::new(),
+            image_description: Some("A map of Portugal".into()),
+            labels: Vec::new(),
+        });
+        let messages = vec![
+            message("assistant", "Lisbon.", 120),
+            message("summary", "internal", 130),
+            question,
+        ];
+
+        let transcript = ChatTranscript::new(&chat, &messages, 0);
+        assert_eq!(transcript.messages.len(), 2);
+        assert_eq!(
+            transcript.to_markdown(),
+            "# Trip plan\n\n_Exported 1970-01-01 00:00 UTC_\n\
+             \n### You · 1970-01-01 00:01 UTC\n\nWhere should I go?\n\
+             \n- 📎 map.png (image/png, 2.0 KB): A map of Portugal\n\
+             \n### Assistant · 1970-01-01 00:02 UTC\n\nLisbon.\n"
+        );
+        let json = serde_json::to_value(&transcript).unwrap();
+        assert!(json["messages"][0]["attachments"][0].get("path").is_none());
+    }
+}
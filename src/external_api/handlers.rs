@@ -1,6 +1,9 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use axum::{extract::State, http::StatusCode, Json};
@@ -8,20 +11,34 @@ use axum_extra::typed_header::{TypedHeader, TypedHeaderRejection};
 use chrono::Utc;
 use headers::{authorization::Bearer, Authorization};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::{
     auth::jwt::decode_jwt,
-    conversation::{build_mistral_prompt, strip_chatml_markers, trim_partial_chatml},
+    conversation::{
+        build_mistral_prompt, compaction::fit_context, language, postprocess::clean_output,
+        stop_sequences,
+    },
+    images::{self, GeneratedImage, ImageGenerateRequest},
+    inference::{
+        backend::FinishReason,
+        gpu_watchdog::CAPACITY_ERROR,
+        json_schema::StructuredOutput,
+        llama_cpp_service::{random_seed, GenerationParams, ENGINE_ERROR_PREFIX, MAX_SEED},
+        response_cache::{CachedReply, ResponseCache},
+        timeouts::{self, TimeoutPolicy},
+    },
     model::{
         message::Message,
-        user::{User, UserRole},
+        user::{User, UserPreferences, UserRole, MAX_DISPLAY_NAME_CHARS},
     },
     prompts,
     ws::AppState,
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct GenerateRequest {
     pub prompt: String,
     #[serde(default)]
@@ -30,9 +47,95 @@ pub struct GenerateRequest {
     pub language: Option<String>,
     #[serde(default)]
     pub system_prompt: Option<String>,
+    /// Up to 4 strings that end the output; they are not included.
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Cap on generated tokens, below the server's own.
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    /// `{"type": "json_schema", "schema": {...}}` constrains the output to
+    /// JSON matching the schema; it is returned parsed in `json`.
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Token id (`"1234"`) or text → bias in -100..=100 added to its
+    /// logit; -100 bans it. A text biases its first token.
+    #[serde(default)]
+    pub logit_bias: BTreeMap<String, f32>,
+    /// Sampling seed (0 to 4294967294); the same seed, prompt and settings
+    /// reproduce the output. Random when absent; echoed as `seed`.
+    #[serde(default)]
+    pub seed: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonSchema {
+        #[schema(value_type = Object)]
+        schema: Value,
+    },
 }
 
-#[derive(Debug, Serialize)]
+const MAX_STOPS: usize = 4;
+const MAX_STOP_CHARS: usize = 64;
+const MAX_LOGIT_BIAS: usize = 300;
+
+impl GenerateRequest {
+    pub(crate) fn generation(&self) -> Result<GenerationParams, (StatusCode, String)> {
+        let valid = self.stop.len() <= MAX_STOPS
+            && self
+                .stop
+                .iter()
+                .all(|s| !s.is_empty() && s.chars().count() <= MAX_STOP_CHARS);
+        if !valid {
+            return Err((StatusCode::BAD_REQUEST, "invalid_stop".into()));
+        }
+        if self.max_tokens == Some(0) {
+            return Err((StatusCode::BAD_REQUEST, "invalid_max_tokens".into()));
+        }
+        let valid = self.logit_bias.len() <= MAX_LOGIT_BIAS
+            && self
+                .logit_bias
+                .iter()
+                .all(|(target, bias)| !target.is_empty() && (-100.0..=100.0).contains(bias));
+        if !valid {
+            return Err((StatusCode::BAD_REQUEST, "invalid_logit_bias".into()));
+        }
+        if self.seed.is_some_and(|seed| seed > MAX_SEED) {
+            return Err((StatusCode::BAD_REQUEST, "invalid_seed".into()));
+        }
+        Ok(GenerationParams {
+            temperature: None,
+            max_tokens: self.max_tokens,
+            stop: stop_sequences(self.stop.iter().cloned()),
+            grammar: None,
+            logit_bias: self
+                .logit_bias
+                .iter()
+                .map(|(target, bias)| (target.clone(), *bias))
+                .collect(),
+            seed: self.seed,
+            ..GenerationParams::default()
+        })
+    }
+
+    pub(crate) fn structured(&self) -> Result<Option<StructuredOutput>, (StatusCode, String)> {
+        match &self.response_format {
+            Some(ResponseFormat::JsonSchema { schema }) => StructuredOutput::new(schema.clone())
+                .map(Some)
+                .map_err(|err| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid_response_format: {err}"),
+                    )
+                }),
+            Some(ResponseFormat::Text) | None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct GenerateResponse {
     pub request_id: String,
     pub user_id: String,
@@ -42,9 +145,24 @@ pub struct GenerateResponse {
     pub generation_count: u64,
     pub generation_limit: Option<u64>,
     pub generations_remaining: Option<u64>,
+    /// The primary model failed and the output comes from the fallback model.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+    /// Parsed output of a `json_schema` request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub json: Option<Value>,
+    /// Seed the output was sampled with.
+    pub seed: u32,
+    /// Why generation stopped; anything but `eos` and `stop_sequence`
+    /// means the output may be cut short.
+    pub finish_reason: FinishReason,
+    /// Answered from the response cache; does not count as a generation.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ProfileResponse {
     pub user_id: String,
     pub email: Option<String>,
@@ -54,37 +172,41 @@ pub struct ProfileResponse {
     pub generation_count: u64,
     pub generation_limit: Option<u64>,
     pub generations_remaining: Option<u64>,
+    pub preferences: UserPreferences,
+    pub plan: Option<String>,
+    /// Plan a change was requested to, until Stripe confirms it.
+    pub pending_plan: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct ApiCredentialsRequest {
     pub api_key: String,
     pub api_secret: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ApiCredentialsResponse {
     pub stored: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ApiCredentialsGenerateResponse {
     pub api_key: String,
     pub api_secret: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct ApiCredentialsValidateRequest {
     pub api_key: String,
     pub api_secret: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ApiCredentialsValidateResponse {
     pub valid: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct GenerationUsageResponse {
     pub user_id: String,
     pub generation_count: u64,
@@ -92,6 +214,71 @@ pub struct GenerationUsageResponse {
     pub generations_remaining: Option<u64>,
 }
 
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImageGenerateResponse {
+    pub request_id: String,
+    pub images: Vec<GeneratedImage>,
+    /// Each image counts as one generation.
+    pub generation_count: u64,
+    pub generation_limit: Option<u64>,
+    pub generations_remaining: Option<u64>,
+}
+
+pub(crate) const MAX_EMBEDDING_INPUTS: usize = 256;
+
+/// A single string or a list of strings, as in the OpenAI embeddings API.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EmbeddingsRequest {
+    pub input: EmbeddingInput,
+    /// `roberta-intent` (default) or a secondary device copy such as
+    /// `roberta-intent@cpu`.
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EmbeddingData {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EmbeddingsResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[utoipa::path(
+    post,
+    path = "/external/api/generate",
+    tag = "external",
+    request_body = GenerateRequest,
+    responses(
+        (status = 200, description = "Single-turn completion", body = GenerateResponse),
+        (status = 400, description = "prompt_required / invalid_stop / invalid_max_tokens / invalid_response_format / invalid_logit_bias / invalid_seed / context_overflow"),
+        (status = 401, description = "invalid_token / login_required"),
+        (status = 403, description = "paid_plan_required / free_quota_exceeded"),
+        (status = 502, description = "structured_output_failed: the output did not match the schema"),
+        (status = 503, description = "capacity: GPU memory too low, retry later"),
+    ),
+    security(("bearer" = []))
+)]
 pub async fn generate(
     State(state): State<AppState>,
     auth_header: Result<TypedHeader<Authorization<Bearer>>, TypedHeaderRejection>,
@@ -100,6 +287,9 @@ pub async fn generate(
     if payload.prompt.trim().is_empty() {
         return Err((StatusCode::BAD_REQUEST, "prompt_required".into()));
     }
+    let mut generation = payload.generation()?;
+    let structured = payload.structured()?;
+    generation.grammar = structured.as_ref().map(|s| s.grammar().to_string());
 
     let auth = auth_header.map_err(|_| (StatusCode::UNAUTHORIZED, "login_required".into()))?;
 
@@ -128,22 +318,116 @@ pub async fn generate(
         language: payload.language.clone(),
         attachments: Vec::new(),
         liked: false,
+        feedback: None,
         ts: Utc::now().timestamp(),
         meta: None,
     });
 
-    let chatml_prompt = build_mistral_prompt(&history, system_prompt.as_deref());
+    let chatml_prompt = fit_context(&state.infer, &mut history, |history| {
+        build_mistral_prompt(history, system_prompt.as_deref())
+    })
+    .await
+    .map_err(|overflow| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "context_overflow ({} prompt tokens, limit {})",
+                overflow.prompt_tokens, overflow.limit
+            ),
+        )
+    })?;
+
+    // Keyed on the seed the client asked for, before one is picked.
+    let cache_key = ResponseCache::key(&chatml_prompt, &generation);
+    if let Some(hit) = state.infer.response_cache().get(&cache_key) {
+        return Ok(Json(GenerateResponse {
+            request_id,
+            user_id: user.id.clone(),
+            role: user.role.clone(),
+            system_prompt: system_prompt.unwrap_or_default(),
+            json: structured
+                .as_ref()
+                .and_then(|_| serde_json::from_str(&hit.output).ok()),
+            output: hit.output,
+            generation_count: user.generation_count,
+            generation_limit: user.generation_limit(),
+            generations_remaining: user.generations_remaining(),
+            fallback: hit.fallback,
+            seed: hit.seed,
+            finish_reason: hit.finish_reason,
+            cached: true,
+        }));
+    }
+
+    if state.models.gpu.admit().is_err() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, CAPACITY_ERROR.into()));
+    }
+    let seed = *generation.seed.get_or_insert_with(random_seed);
+    let outcome = generation.outcome.clone();
     let cancel = Arc::new(AtomicBool::new(false));
-    let raw = state
+    let mut reply = state
         .infer
-        .generate_completion(chatml_prompt, cancel.clone())
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .generate_reply(chatml_prompt, None, generation, cancel.clone());
+    let deadline = timeouts::deadline(TimeoutPolicy::current().generate);
+    let mut raw = String::new();
+    let mut timed_out = false;
+    loop {
+        match timeouts::recv_until(&mut reply.rx, deadline).await {
+            Ok(Some(chunk)) => raw.push_str(&chunk),
+            Ok(None) => break,
+            Err(_) => {
+                timed_out = true;
+                break;
+            }
+        }
+    }
 
     cancel.store(true, Ordering::SeqCst);
 
-    let trimmed = trim_partial_chatml(&raw);
-    let cleaned = strip_chatml_markers(trimmed).trim().to_string();
+    let mut cleaned = clean_output(&raw);
+    let json = match &structured {
+        Some(format) => {
+            let value = format.parse(&cleaned).map_err(|err| {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!("structured_output_failed: {err}"),
+                )
+            })?;
+            cleaned = value.to_string();
+            Some(value)
+        }
+        None => None,
+    };
+
+    let fallback = reply.fallback.load(Ordering::SeqCst);
+    let finish_reason = if raw.contains(ENGINE_ERROR_PREFIX) {
+        FinishReason::Error
+    } else if timed_out {
+        FinishReason::Timeout
+    } else {
+        outcome.reason().unwrap_or(FinishReason::Eos)
+    };
+    // Failed, cancelled, capped and looping replies are not worth
+    // replaying.
+    if !cleaned.is_empty()
+        && !matches!(
+            finish_reason,
+            FinishReason::Error
+                | FinishReason::Repetition
+                | FinishReason::Cancelled
+                | FinishReason::Timeout
+        )
+    {
+        state.infer.response_cache().insert(
+            cache_key,
+            CachedReply {
+                output: cleaned.clone(),
+                fallback,
+                seed,
+                finish_reason,
+            },
+        );
+    }
 
     user.generation_count = user.generation_count.saturating_add(1);
     state
@@ -162,9 +446,141 @@ pub async fn generate(
         generation_count: user.generation_count,
         generation_limit: user.generation_limit(),
         generations_remaining: user.generations_remaining(),
+        fallback,
+        json,
+        seed,
+        finish_reason,
+        cached: false,
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/external/api/embeddings",
+    tag = "external",
+    request_body = EmbeddingsRequest,
+    responses(
+        (status = 200, description = "One embedding per input, in input order", body = EmbeddingsResponse),
+        (status = 400, description = "input_required / too_many_inputs / unknown_model"),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn embeddings(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<EmbeddingsRequest>,
+) -> Result<Json<EmbeddingsResponse>, (StatusCode, String)> {
+    authenticate_user(&state, auth.token()).await?;
+
+    let inputs = match payload.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    if inputs.is_empty() || inputs.iter().any(|t| t.trim().is_empty()) {
+        return Err((StatusCode::BAD_REQUEST, "input_required".into()));
+    }
+    if inputs.len() > MAX_EMBEDDING_INPUTS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("too_many_inputs (max {MAX_EMBEDDING_INPUTS})"),
+        ));
+    }
+
+    let (model, encoder) = state
+        .models
+        .embedder(payload.model.as_deref())
+        .map(|(name, encoder)| (name.to_string(), encoder))
+        .ok_or((StatusCode::BAD_REQUEST, "unknown_model".to_string()))?;
+
+    let embedded = tokio::task::spawn_blocking(move || encoder.embed_batch(&inputs))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let prompt_tokens = embedded.iter().map(|e| e.tokens).sum();
+    let data = embedded
+        .into_iter()
+        .enumerate()
+        .map(|(index, e)| EmbeddingData {
+            object: "embedding".into(),
+            index,
+            embedding: e.vector,
+        })
+        .collect();
+
+    Ok(Json(EmbeddingsResponse {
+        object: "list".into(),
+        data,
+        model,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/external/api/images/generate",
+    tag = "external",
+    request_body = ImageGenerateRequest,
+    responses(
+        (status = 200, description = "Generated PNGs; fetch them from `url`", body = ImageGenerateResponse),
+        (status = 400, description = "prompt_required / prompt_too_long / too_many_images"),
+        (status = 401, description = "invalid_token / login_required"),
+        (status = 403, description = "paid_plan_required / free_quota_exceeded"),
+        (status = 422, description = "prompt_flagged"),
+        (status = 503, description = "image_generation_unavailable / capacity"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn generate_images(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<ImageGenerateRequest>,
+) -> Result<Json<ImageGenerateResponse>, (StatusCode, String)> {
+    let mut user = authenticate_user(&state, auth.token()).await?;
+    if !user.role.can_access_generation() {
+        return Err((StatusCode::FORBIDDEN, "paid_plan_required".into()));
+    }
+    if !user.can_generate_now() {
+        return Err((StatusCode::FORBIDDEN, "free_quota_exceeded".into()));
+    }
+
+    let owner = format!("user:{}", user.id);
+    let images =
+        images::generate_images(&state.models, &state.storage, &state.db, &owner, &payload)
+            .await
+            .map_err(|e| (e.status(), e.code().to_string()))?;
+    let images: Vec<GeneratedImage> = images.iter().map(|image| image.public()).collect();
+
+    user.generation_count = user.generation_count.saturating_add(images.len() as u64);
+    state
+        .db
+        .save_user(&user)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(ImageGenerateResponse {
+        request_id: Uuid::new_v4().to_string(),
+        images,
+        generation_count: user.generation_count,
+        generation_limit: user.generation_limit(),
+        generations_remaining: user.generations_remaining(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/external/api/profile",
+    tag = "external",
+    responses(
+        (status = 200, description = "Caller profile and quota", body = ProfileResponse),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
 pub async fn profile(
     State(state): State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
@@ -180,9 +596,70 @@ pub async fn profile(
         generation_count: user.generation_count,
         generation_limit: user.generation_limit(),
         generations_remaining: user.generations_remaining(),
+        preferences: user.preferences.clone(),
+        plan: user.plan.clone(),
+        pending_plan: user.pending_plan_change().map(|change| change.to.clone()),
     }))
 }
 
+/// Replace the caller's preferences. They shape the system prompt of every
+/// chat the user owns; omitted fields are cleared.
+#[utoipa::path(
+    put,
+    path = "/external/api/profile/preferences",
+    tag = "external",
+    request_body = UserPreferences,
+    responses(
+        (status = 200, description = "Stored preferences", body = UserPreferences),
+        (status = 400, description = "invalid_language / invalid_display_name"),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn update_preferences(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(mut prefs): Json<UserPreferences>,
+) -> Result<Json<UserPreferences>, (StatusCode, String)> {
+    let mut user = authenticate_user(&state, auth.token()).await?;
+
+    prefs.language = match prefs.language.as_deref() {
+        Some(lang) => Some(
+            language::normalize(lang)
+                .filter(|code| code.len() <= 8 && code.bytes().all(|b| b.is_ascii_lowercase()))
+                .ok_or((StatusCode::BAD_REQUEST, "invalid_language".to_string()))?,
+        ),
+        None => None,
+    };
+    prefs.display_name = prefs
+        .display_name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    if prefs.display_name.as_ref().is_some_and(|name| {
+        name.chars().count() > MAX_DISPLAY_NAME_CHARS || name.chars().any(char::is_control)
+    }) {
+        return Err((StatusCode::BAD_REQUEST, "invalid_display_name".into()));
+    }
+
+    user.preferences = prefs;
+    state
+        .db
+        .save_user(&user)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(user.preferences))
+}
+
+#[utoipa::path(
+    get,
+    path = "/external/api/usage",
+    tag = "external",
+    responses(
+        (status = 200, description = "Generation counters", body = GenerationUsageResponse),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
 pub async fn generation_usage(
     State(state): State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
@@ -196,6 +673,16 @@ pub async fn generation_usage(
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/external/api/credentials/generate",
+    tag = "external",
+    responses(
+        (status = 200, description = "Fresh API key/secret pair", body = ApiCredentialsGenerateResponse),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
 pub async fn generate_api_credentials(
     State(state): State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
@@ -220,6 +707,17 @@ pub async fn generate_api_credentials(
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/external/api/credentials",
+    tag = "external",
+    request_body = ApiCredentialsRequest,
+    responses(
+        (status = 200, description = "Credentials stored", body = ApiCredentialsResponse),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
 pub async fn store_api_credentials(
     State(state): State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
@@ -245,6 +743,17 @@ pub async fn store_api_credentials(
     Ok(Json(ApiCredentialsResponse { stored: true }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/external/api/credentials/validate",
+    tag = "external",
+    request_body = ApiCredentialsValidateRequest,
+    responses(
+        (status = 200, description = "Whether the pair matches", body = ApiCredentialsValidateResponse),
+        (status = 401, description = "invalid_token / login_required"),
+    ),
+    security(("bearer" = []))
+)]
 pub async fn validate_api_credentials(
     State(state): State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
@@ -273,7 +782,8 @@ pub async fn validate_api_credentials(
 }
 
 async fn authenticate_user(state: &AppState, token: &str) -> Result<User, (StatusCode, String)> {
-    let user_id = decode_jwt(token, &state.jwt_secret)
+    let user_id = decode_jwt(token, &state.jwt_secret, &state.db)
+        .await
         .map_err(|_| (StatusCode::UNAUTHORIZED, "invalid_token".into()))?;
     let user = state
         .db
@@ -1,6 +1,7 @@
-use crate::ws::AppState;
+use crate::{api::large_body_limit, ws::AppState};
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    routing::{get, post, put},
     Router,
 };
 
@@ -8,8 +9,23 @@ pub mod handlers;
 
 pub fn router() -> Router<AppState> {
     Router::new()
-        .route("/external/api/generate", post(handlers::generate))
+        .route(
+            "/external/api/generate",
+            post(handlers::generate).layer(DefaultBodyLimit::max(large_body_limit())),
+        )
+        .route(
+            "/external/api/embeddings",
+            post(handlers::embeddings).layer(DefaultBodyLimit::max(large_body_limit())),
+        )
+        .route(
+            "/external/api/images/generate",
+            post(handlers::generate_images),
+        )
         .route("/external/api/profile", get(handlers::profile))
+        .route(
+            "/external/api/profile/preferences",
+            put(handlers::update_preferences),
+        )
         .route("/external/api/usage", get(handlers::generation_usage))
         .route(
             "/external/api/credentials/generate",
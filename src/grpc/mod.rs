@@ -0,0 +1,357 @@
+//! gRPC surface for internal consumers: generation (whole and streamed),
+//! embeddings and intent classification, served next to the HTTP routes
+//! on `GRPC_PORT` when built with the `grpc` feature.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use futures_util::stream::{self, BoxStream};
+use tonic::{metadata::MetadataMap, transport::Server, Request, Response, Status};
+use uuid::Uuid;
+
+use crate::{
+    classifier::routing::{self, route_intent},
+    conversation::{build_mistral_prompt, postprocess::clean_output},
+    external_api::handlers::{GenerateRequest, ResponseFormat, MAX_EMBEDDING_INPUTS},
+    inference::{
+        backend::FinishReason,
+        gpu_watchdog::CAPACITY_ERROR,
+        llama_cpp_service::{random_seed, ENGINE_ERROR_PREFIX},
+        timeouts::{self, TimeoutPolicy},
+    },
+    model::message::Message,
+    ws::AppState,
+};
+
+pub mod pb {
+    tonic::include_proto!("ktulhu.v1");
+}
+
+use pb::ktulhu_server::{Ktulhu, KtulhuServer};
+
+/// Map an HTTP handler error onto the closest gRPC status.
+fn status((code, message): (StatusCode, String)) -> Status {
+    match code {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// The HTTP request the gRPC one stands for, so both share validation.
+fn http_request(req: &pb::GenerateRequest) -> Result<GenerateRequest, Status> {
+    let response_format = match &req.json_schema {
+        Some(schema) => {
+            let schema = serde_json::from_str(schema)
+                .map_err(|err| Status::invalid_argument(format!("invalid_json_schema: {err}")))?;
+            Some(ResponseFormat::JsonSchema { schema })
+        }
+        None => None,
+    };
+    Ok(GenerateRequest {
+        prompt: req.prompt.clone(),
+        intent: None,
+        language: None,
+        system_prompt: req.system_prompt.clone(),
+        stop: req.stop.clone(),
+        max_tokens: req.max_tokens.map(|n| n as usize),
+        response_format,
+        logit_bias: req
+            .logit_bias
+            .iter()
+            .map(|(target, bias)| (target.clone(), *bias))
+            .collect::<BTreeMap<_, _>>(),
+        seed: req.seed,
+    })
+}
+
+fn chatml_prompt(req: &pb::GenerateRequest) -> String {
+    let message = Message {
+        id: Uuid::new_v4().to_string(),
+        chat_id: "grpc".into(),
+        session_id: None,
+        user_id: None,
+        device_hash: None,
+        role: "user".into(),
+        text: Some(req.prompt.clone()),
+        language: None,
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: Utc::now().timestamp(),
+        meta: None,
+    };
+    build_mistral_prompt(&[message], req.system_prompt.as_deref())
+}
+
+fn head(prediction: &routing::HeadPrediction) -> pb::HeadPrediction {
+    pb::HeadPrediction {
+        label: prediction.label.clone(),
+        score: prediction.score,
+    }
+}
+
+pub struct KtulhuGrpc {
+    state: AppState,
+}
+
+impl KtulhuGrpc {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+#[tonic::async_trait]
+impl Ktulhu for KtulhuGrpc {
+    async fn generate(
+        &self,
+        request: Request<pb::GenerateRequest>,
+    ) -> Result<Response<pb::GenerateResponse>, Status> {
+        let req = request.into_inner();
+        if req.prompt.trim().is_empty() {
+            return Err(Status::invalid_argument("prompt_required"));
+        }
+        let http = http_request(&req)?;
+        let mut generation = http.generation().map_err(status)?;
+        let structured = http.structured().map_err(status)?;
+        generation.grammar = structured.as_ref().map(|s| s.grammar().to_string());
+
+        if self.state.models.gpu.admit().is_err() {
+            return Err(Status::unavailable(CAPACITY_ERROR));
+        }
+        let seed = *generation.seed.get_or_insert_with(random_seed);
+        let outcome = generation.outcome.clone();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut reply =
+            self.state
+                .infer
+                .generate_reply(chatml_prompt(&req), None, generation, cancel.clone());
+        let deadline = timeouts::deadline(TimeoutPolicy::current().generate);
+        let mut raw = String::new();
+        let mut timed_out = false;
+        loop {
+            match timeouts::recv_until(&mut reply.rx, deadline).await {
+                Ok(Some(chunk)) => raw.push_str(&chunk),
+                Ok(None) => break,
+                Err(_) => {
+                    timed_out = true;
+                    break;
+                }
+            }
+        }
+        cancel.store(true, Ordering::SeqCst);
+
+        if let Some(at) = raw.find(ENGINE_ERROR_PREFIX) {
+            return Err(Status::internal(raw[at..].trim().to_string()));
+        }
+        let mut output = clean_output(&raw);
+        let json = match &structured {
+            Some(format) => {
+                let value = format
+                    .parse(&output)
+                    .map_err(|err| Status::internal(format!("structured_output_failed: {err}")))?;
+                output = value.to_string();
+                Some(output.clone())
+            }
+            None => None,
+        };
+
+        Ok(Response::new(pb::GenerateResponse {
+            output,
+            seed,
+            fallback: reply.fallback.load(Ordering::SeqCst),
+            json,
+            finish_reason: if timed_out {
+                FinishReason::Timeout
+            } else {
+                outcome.reason().unwrap_or(FinishReason::Eos)
+            }
+            .as_str()
+            .to_string(),
+        }))
+    }
+
+    type GenerateStreamStream = BoxStream<'static, Result<pb::GenerateChunk, Status>>;
+
+    async fn generate_stream(
+        &self,
+        request: Request<pb::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let req = request.into_inner();
+        if req.prompt.trim().is_empty() {
+            return Err(Status::invalid_argument("prompt_required"));
+        }
+        if req.json_schema.is_some() {
+            return Err(Status::invalid_argument("json_schema_requires_generate"));
+        }
+        let mut generation = http_request(&req)?.generation().map_err(status)?;
+
+        if self.state.models.gpu.admit().is_err() {
+            return Err(Status::unavailable(CAPACITY_ERROR));
+        }
+        let seed = *generation.seed.get_or_insert_with(random_seed);
+        let outcome = generation.outcome.clone();
+        // Dropping the stream when the client goes away drops the receiver,
+        // which stops generation like a cancel.
+        let reply = self.state.infer.generate_reply(
+            chatml_prompt(&req),
+            None,
+            generation,
+            Arc::new(AtomicBool::new(false)),
+        );
+
+        let deadline = timeouts::deadline(TimeoutPolicy::current().generate);
+
+        let chunks = stream::unfold(Some((reply, 0u64, outcome)), move |current| async move {
+            let (mut reply, seq, outcome) = current?;
+            let finish_reason = match timeouts::recv_until(&mut reply.rx, deadline).await {
+                Ok(Some(text)) if text.starts_with(ENGINE_ERROR_PREFIX) => {
+                    return Some((Err(Status::internal(text.trim().to_string())), None));
+                }
+                Ok(Some(text)) => {
+                    return Some((
+                        Ok(pb::GenerateChunk {
+                            text,
+                            seq: seq + 1,
+                            seed,
+                            ..Default::default()
+                        }),
+                        Some((reply, seq + 1, outcome)),
+                    ));
+                }
+                Ok(None) => outcome.reason().unwrap_or(FinishReason::Eos),
+                // The reply is dropped with this state, which stops it.
+                Err(_) => FinishReason::Timeout,
+            };
+            Some((
+                Ok(pb::GenerateChunk {
+                    done: true,
+                    fallback: reply.fallback.load(Ordering::SeqCst),
+                    seed,
+                    finish_reason: finish_reason.as_str().to_string(),
+                    ..Default::default()
+                }),
+                None,
+            ))
+        });
+        Ok(Response::new(Box::pin(chunks)))
+    }
+
+    async fn embed(
+        &self,
+        request: Request<pb::EmbedRequest>,
+    ) -> Result<Response<pb::EmbedResponse>, Status> {
+        let req = request.into_inner();
+        if req.input.is_empty() || req.input.iter().any(|t| t.trim().is_empty()) {
+            return Err(Status::invalid_argument("input_required"));
+        }
+        if req.input.len() > MAX_EMBEDDING_INPUTS {
+            return Err(Status::invalid_argument(format!(
+                "too_many_inputs (max {MAX_EMBEDDING_INPUTS})"
+            )));
+        }
+
+        let (model, encoder) = self
+            .state
+            .models
+            .embedder(Some(&req.model))
+            .map(|(name, encoder)| (name.to_string(), encoder))
+            .ok_or_else(|| Status::invalid_argument("unknown_model"))?;
+
+        let inputs = req.input;
+        let embedded = tokio::task::spawn_blocking(move || encoder.embed_batch(&inputs))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let prompt_tokens = embedded.iter().map(|e| e.tokens as u32).sum();
+        Ok(Response::new(pb::EmbedResponse {
+            model,
+            data: embedded
+                .into_iter()
+                .map(|e| pb::Embedding { vector: e.vector })
+                .collect(),
+            prompt_tokens,
+        }))
+    }
+
+    async fn classify(
+        &self,
+        request: Request<pb::ClassifyRequest>,
+    ) -> Result<Response<pb::ClassifyResponse>, Status> {
+        let req = request.into_inner();
+        if req.text.trim().is_empty() {
+            return Err(Status::invalid_argument("text_required"));
+        }
+        let models = self.state.models.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let hint = (!req.language.is_empty()).then_some(req.language.as_str());
+            route_intent(&models, &req.text, hint)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(pb::ClassifyResponse {
+            language: result.language.clone(),
+            prompt_key: result.prompt_key.clone(),
+            intent_kind: format!("{:?}", result.final_intent_kind),
+            routing_path: format!("{:?}", result.routing_path),
+            reasoning_profile: result.reasoning_profile.map(|p| p.as_str().to_string()),
+            speech_act: Some(head(&result.speech_act)),
+            domain: Some(head(&result.domain)),
+            expectation: Some(head(&result.expectation)),
+            safety: result.safety.as_ref().map(head),
+            support_intent: result.support_intent,
+            recency_sensitive: result.recency_sensitive,
+            notes: result.notes,
+        }))
+    }
+}
+
+/// `authorization: Bearer <token>` on every call.
+fn authorized(metadata: &MetadataMap, token: &str) -> bool {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token)
+}
+
+/// Serve the gRPC API on `GRPC_PORT` (same address as the HTTP server)
+/// when both it and `GRPC_TOKEN` are set.
+pub fn spawn_grpc(state: AppState, ip: IpAddr) -> bool {
+    let Some(port) = std::env::var("GRPC_PORT")
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+    else {
+        return false;
+    };
+    let Some(token) = std::env::var("GRPC_TOKEN").ok().filter(|t| !t.is_empty()) else {
+        tracing::warn!("GRPC_PORT is set without GRPC_TOKEN; not serving gRPC");
+        return false;
+    };
+
+    let addr = SocketAddr::new(ip, port);
+    let service = KtulhuServer::with_interceptor(KtulhuGrpc::new(state), move |req: Request<()>| {
+        if authorized(req.metadata(), &token) {
+            Ok(req)
+        } else {
+            Err(Status::unauthenticated("invalid_token"))
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(err) = Server::builder().add_service(service).serve(addr).await {
+            tracing::error!("gRPC server on {addr} stopped: {err}");
+        }
+    });
+    true
+}
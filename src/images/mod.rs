@@ -0,0 +1,264 @@
+//! Text-to-image for `POST /external/api/images/generate` and the ws
+//! `image_prompt`. Images come from the diffusion model in `ModelManager`,
+//! are stored like uploads and served by `GET /api/images/{file_id}`; the
+//! unguessable file id is the only access check there.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    db::DBLayer,
+    inference::gpu_watchdog::CAPACITY_ERROR,
+    manager::ModelManager,
+    model::{message::MessageAttachment, upload::StoredFile},
+    moderation,
+    storage::{serve::file_response, StorageService},
+    ws::AppState,
+};
+
+/// Most images one request may ask for.
+pub const MAX_IMAGES: usize = 4;
+const MAX_PROMPT_CHARS: usize = 1_000;
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct ImageGenerateRequest {
+    pub prompt: String,
+    /// What the image should not contain.
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    /// Number of images, 1 (default) to 4.
+    #[serde(default)]
+    pub n: Option<usize>,
+    /// Seed of the first image; the others use the following seeds.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct GeneratedImage {
+    pub file_id: String,
+    /// Path of the PNG on this server.
+    #[schema(example = "/api/images/6f1c0e0e-8d7a-4b8e-9a55-0c1f2f1f9b1e")]
+    pub url: String,
+    /// Seed that reproduces the image.
+    pub seed: u64,
+}
+
+/// An image as stored, with the seed it was made with.
+pub struct Generated {
+    pub file: StoredFile,
+    pub seed: u64,
+}
+
+impl Generated {
+    pub fn public(&self) -> GeneratedImage {
+        GeneratedImage {
+            file_id: self.file.id.clone(),
+            url: image_url(&self.file.id),
+            seed: self.seed,
+        }
+    }
+
+    /// The image attached to the assistant message that answers a ws
+    /// `image_prompt`.
+    pub fn attachment(&self) -> MessageAttachment {
+        MessageAttachment {
+            id: self.file.id.clone(),
+            filename: self.file.filename.clone(),
+            mime_type: Some(self.file.mime_type.clone()),
+            preview_base64: None,
+            path: Some(self.file.path.clone()),
+            size: Some(self.file.size as usize),
+            description: None,
+            ocr_text: None,
+            text_chunks: Vec::new(),
+            image_description: None,
+            labels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImageGenError {
+    PromptRequired,
+    PromptTooLong,
+    TooMany,
+    Flagged,
+    Unavailable,
+    /// GPU memory too low to start another job.
+    Capacity,
+    Failed,
+}
+
+impl ImageGenError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ImageGenError::PromptRequired => "prompt_required",
+            ImageGenError::PromptTooLong => "prompt_too_long",
+            ImageGenError::TooMany => "too_many_images",
+            ImageGenError::Flagged => "prompt_flagged",
+            ImageGenError::Unavailable => "image_generation_unavailable",
+            ImageGenError::Capacity => CAPACITY_ERROR,
+            ImageGenError::Failed => "image_generation_failed",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ImageGenError::PromptRequired
+            | ImageGenError::PromptTooLong
+            | ImageGenError::TooMany => StatusCode::BAD_REQUEST,
+            ImageGenError::Flagged => StatusCode::UNPROCESSABLE_ENTITY,
+            ImageGenError::Unavailable | ImageGenError::Capacity => StatusCode::SERVICE_UNAVAILABLE,
+            ImageGenError::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Check `request`, count its images, and refuse prompts the moderation
+/// patterns flag.
+fn validate(request: &ImageGenerateRequest) -> Result<usize, ImageGenError> {
+    let prompt = request.prompt.trim();
+    if prompt.is_empty() {
+        return Err(ImageGenError::PromptRequired);
+    }
+    let negative = request.negative_prompt.as_deref().unwrap_or("");
+    if prompt.chars().count() > MAX_PROMPT_CHARS || negative.chars().count() > MAX_PROMPT_CHARS {
+        return Err(ImageGenError::PromptTooLong);
+    }
+    let count = request.n.unwrap_or(1);
+    if !(1..=MAX_IMAGES).contains(&count) {
+        return Err(ImageGenError::TooMany);
+    }
+    if let Some(verdict) = moderation::check(prompt, None) {
+        warn!(category = ?verdict.category, "image prompt flagged");
+        return Err(ImageGenError::Flagged);
+    }
+    Ok(count)
+}
+
+/// Generate the requested images and store them for `owner` (a device
+/// hash, or `user:<id>` for API callers).
+pub async fn generate_images(
+    models: &ModelManager,
+    storage: &StorageService,
+    db: &DBLayer,
+    owner: &str,
+    request: &ImageGenerateRequest,
+) -> Result<Vec<Generated>, ImageGenError> {
+    let count = validate(request)?;
+    let diffusion = models.diffusion.clone().ok_or(ImageGenError::Unavailable)?;
+    models.gpu.admit().map_err(|_| ImageGenError::Capacity)?;
+
+    let first_seed = request.seed.unwrap_or_else(rand::random);
+    let mut images = Vec::with_capacity(count);
+    for i in 0..count as u64 {
+        let seed = first_seed.wrapping_add(i);
+        let model = diffusion.clone();
+        let prompt = request.prompt.trim().to_string();
+        let negative = request.negative_prompt.clone();
+        let png =
+            tokio::task::spawn_blocking(move || model.generate(&prompt, negative.as_deref(), seed))
+                .await
+                .map_err(|err| {
+                    warn!("image generation task failed: {err}");
+                    ImageGenError::Failed
+                })?
+                .map_err(|err| {
+                    warn!("image generation failed: {err:#}");
+                    ImageGenError::Failed
+                })?;
+
+        let file = storage
+            .store_generated(db, &png, owner)
+            .await
+            .map_err(|err| {
+                warn!("failed to store generated image: {}", err.code());
+                ImageGenError::Failed
+            })?;
+        images.push(Generated { file, seed });
+    }
+    Ok(images)
+}
+
+pub fn image_url(file_id: &str) -> String {
+    format!("/api/images/{file_id}")
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/api/images/{file_id}", get(image_handler))
+}
+
+/// PNG made by `/external/api/images/generate` or a ws `image_prompt`.
+#[utoipa::path(
+    get,
+    path = "/api/images/{file_id}",
+    tag = "images",
+    params(("file_id" = String, Path, description = "Id returned with the image")),
+    responses(
+        (status = 200, description = "The image", content_type = "image/png"),
+        (status = 206, description = "Requested byte range"),
+        (status = 304, description = "`If-None-Match` matches the `ETag`"),
+        (status = 404, description = "image_not_found"),
+    )
+)]
+pub async fn image_handler(
+    State(state): State<AppState>,
+    Path(file_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let file = state
+        .db
+        .load_upload(&file_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .filter(|file| file.generated)
+        .ok_or((StatusCode::NOT_FOUND, "image_not_found".to_string()))?;
+    file_response(&file, &headers)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "image_not_found".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt: &str, n: Option<usize>) -> ImageGenerateRequest {
+        ImageGenerateRequest {
+            prompt: prompt.into(),
+            negative_prompt: None,
+            n,
+            seed: None,
+        }
+    }
+
+    #[test]
+    fn validates_prompt_and_count() {
+        assert_eq!(validate(&request("a red fox in snow", None)), Ok(1));
+        assert_eq!(validate(&request("a red fox", Some(4))), Ok(4));
+        assert_eq!(
+            validate(&request("  ", None)),
+            Err(ImageGenError::PromptRequired)
+        );
+        assert_eq!(
+            validate(&request("a red fox", Some(0))),
+            Err(ImageGenError::TooMany)
+        );
+        assert_eq!(
+            validate(&request("a red fox", Some(MAX_IMAGES + 1))),
+            Err(ImageGenError::TooMany)
+        );
+        assert_eq!(
+            validate(&request(&"x".repeat(MAX_PROMPT_CHARS + 1), None)),
+            Err(ImageGenError::PromptTooLong)
+        );
+    }
+}
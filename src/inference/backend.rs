@@ -0,0 +1,332 @@
+//! What every local text generator provides. [`GenerationBackend`] is the
+//! interface the rest of the server generates through, and [`TokenStream`]
+//! turns a backend's token bytes into reply text, so UTF-8 reassembly,
+//! stop strings and the token cap behave the same on every backend.
+
+use std::{
+    fmt::Display,
+    sync::{atomic::AtomicBool, Arc, OnceLock},
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+use super::{
+    json_schema::StructuredOutput,
+    llama_cpp_service::{GenerationParams, ENGINE_ERROR_PREFIX},
+    repetition::{RepetitionGuard, RepetitionLimits},
+    stop::StopMatcher,
+};
+
+/// Why a reply stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model ended its turn.
+    Eos,
+    /// A stop string matched.
+    StopSequence,
+    /// The token cap or the context window was reached.
+    MaxTokens,
+    /// The caller cancelled or stopped reading.
+    Cancelled,
+    /// The websocket went away mid-stream (chat replies only).
+    Disconnected,
+    /// The reply started repeating itself and was cut off.
+    Repetition,
+    /// The reply reached its wall-clock cap (see
+    /// [`TimeoutPolicy`](super::timeouts::TimeoutPolicy)).
+    Timeout,
+    /// The backend failed while generating.
+    Error,
+}
+
+impl FinishReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Eos => "eos",
+            Self::StopSequence => "stop_sequence",
+            Self::MaxTokens => "max_tokens",
+            Self::Cancelled => "cancelled",
+            Self::Disconnected => "disconnected",
+            Self::Repetition => "repetition",
+            Self::Timeout => "timeout",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// How a reply ended, filled in by the backend. Clones share it, so a
+/// caller keeps a clone of [`GenerationParams::outcome`] to read it once
+/// the stream closed.
+#[derive(Debug, Clone, Default)]
+pub struct ReplyOutcome(Arc<OnceLock<FinishReason>>);
+
+impl ReplyOutcome {
+    /// Record why the reply ended; the first reason recorded sticks.
+    pub fn set(&self, reason: FinishReason) {
+        let _ = self.0.set(reason);
+    }
+
+    /// `None` until the backend recorded a reason.
+    pub fn reason(&self) -> Option<FinishReason> {
+        self.0.get().copied()
+    }
+}
+
+#[async_trait]
+pub trait GenerationBackend: Send + Sync {
+    /// Stream a reply. `chat_id` lets the backend reuse what it cached for
+    /// the chat's earlier turns; `params` apply to this reply only. A
+    /// failure arrives as a chunk starting with [`ENGINE_ERROR_PREFIX`].
+    fn generate_stream_with(
+        &self,
+        prompt: String,
+        chat_id: Option<String>,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<String>;
+
+    /// Number of tokens `text` encodes to, special tokens included.
+    fn count_tokens(&self, text: &str) -> Result<usize>;
+
+    /// Tokens a reply's prompt and output share.
+    fn context_length(&self) -> usize;
+
+    fn generate_stream(&self, prompt: String, cancel: Arc<AtomicBool>) -> mpsc::Receiver<String> {
+        self.generate_stream_with(prompt, None, GenerationParams::default(), cancel)
+    }
+
+    fn generate_stream_for_chat(
+        &self,
+        prompt: String,
+        chat_id: String,
+        cancel: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<String> {
+        self.generate_stream_with(prompt, Some(chat_id), GenerationParams::default(), cancel)
+    }
+
+    async fn generate_completion(&self, prompt: String, cancel: Arc<AtomicBool>) -> Result<String> {
+        let mut rx = self.generate_stream(prompt, cancel);
+        let mut out = String::new();
+        while let Some(chunk) = rx.recv().await {
+            out.push_str(&chunk);
+        }
+        Ok(out)
+    }
+
+    /// Completion sampled under `format`'s grammar and checked against its
+    /// schema.
+    async fn generate_json(
+        &self,
+        prompt: String,
+        format: &StructuredOutput,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<serde_json::Value> {
+        let params = GenerationParams {
+            grammar: Some(format.grammar().to_string()),
+            ..GenerationParams::default()
+        };
+        let mut rx = self.generate_stream_with(prompt, None, params, cancel);
+        let mut out = String::new();
+        while let Some(chunk) = rx.recv().await {
+            if let Some(err) = chunk.strip_prefix(ENGINE_ERROR_PREFIX) {
+                bail!("generation failed:{err}");
+            }
+            out.push_str(&chunk);
+        }
+        format.parse(&out)
+    }
+}
+
+/// One reply on its way to the receiver. Backends push each generated
+/// token's bytes; a character split over tokens is held until complete,
+/// and text that may start a stop string until the next token settles it.
+/// A reply that starts looping is ended (see [`RepetitionGuard`]).
+pub struct TokenStream {
+    tx: mpsc::Sender<String>,
+    stop: StopMatcher,
+    repetition: RepetitionGuard,
+    outcome: ReplyOutcome,
+    pending: Vec<u8>,
+    produced: usize,
+    max_tokens: usize,
+}
+
+impl TokenStream {
+    /// `limit` is the backend's own token cap; `params.max_tokens` can only
+    /// lower it.
+    pub fn new(tx: mpsc::Sender<String>, params: &GenerationParams, limit: usize) -> Self {
+        Self {
+            tx,
+            stop: StopMatcher::new(params.stop.clone()),
+            repetition: RepetitionGuard::new(RepetitionLimits::current()),
+            outcome: params.outcome.clone(),
+            pending: Vec::new(),
+            produced: 0,
+            max_tokens: params.max_tokens.map_or(limit, |m| m.min(limit)),
+        }
+    }
+
+    /// The receiver is gone; generating more is wasted.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    pub fn produced(&self) -> usize {
+        self.produced
+    }
+
+    /// Tokens left before the cap.
+    pub fn remaining(&self) -> usize {
+        self.max_tokens.saturating_sub(self.produced)
+    }
+
+    /// Record why the reply ended, for the reasons only the backend sees
+    /// (end of turn, cancellation). [`push`](Self::push) and
+    /// [`fail`](Self::fail) record their own.
+    pub fn end(&self, reason: FinishReason) {
+        self.outcome.set(reason);
+    }
+
+    /// Stream one token. False once the reply is over: a stop string
+    /// matched, the receiver is gone, the reply loops or the token cap is
+    /// reached.
+    pub fn push(&mut self, piece: &[u8]) -> bool {
+        self.pending.extend_from_slice(piece);
+        if !self.flush() {
+            self.end(if self.stop.stopped() {
+                FinishReason::StopSequence
+            } else {
+                FinishReason::Cancelled
+            });
+            return false;
+        }
+        if self.repetition.push(piece) {
+            self.end(FinishReason::Repetition);
+            return false;
+        }
+        self.produced += 1;
+        if self.produced >= self.max_tokens {
+            self.end(FinishReason::MaxTokens);
+            return false;
+        }
+        true
+    }
+
+    /// Send what is still held back, unless a stop string ended the reply.
+    pub fn finish(&mut self) {
+        if self.stop.stopped() || !self.flush() {
+            return;
+        }
+        let rest = self.stop.finish();
+        if !rest.is_empty() {
+            let _ = self.tx.blocking_send(rest);
+        }
+    }
+
+    /// End the reply with an engine error chunk.
+    pub fn fail(&self, err: impl Display) {
+        self.end(FinishReason::Error);
+        let _ = self
+            .tx
+            .blocking_send(format!("{ENGINE_ERROR_PREFIX} {err}"));
+    }
+
+    /// Send the complete UTF-8 prefix of the pending bytes, keeping a split
+    /// character for the next token; invalid bytes become `�`.
+    fn flush(&mut self) -> bool {
+        loop {
+            if self.pending.is_empty() {
+                return true;
+            }
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    let text = valid.to_string();
+                    self.pending.clear();
+                    return self.send(&text);
+                }
+                Err(err) => {
+                    let valid_up_to = err.valid_up_to();
+                    if valid_up_to > 0 {
+                        let text =
+                            String::from_utf8_lossy(&self.pending[..valid_up_to]).into_owned();
+                        self.pending.drain(..valid_up_to);
+                        if !self.send(&text) {
+                            return false;
+                        }
+                        continue;
+                    }
+                    match err.error_len() {
+                        Some(error_len) => {
+                            self.pending.drain(..error_len);
+                            if !self.send("�") {
+                                return false;
+                            }
+                        }
+                        None => return true,
+                    }
+                }
+            }
+        }
+    }
+
+    /// False once a stop string ended the reply or the receiver is gone.
+    fn send(&mut self, text: &str) -> bool {
+        let text = self.stop.push(text);
+        if !text.is_empty() && self.tx.blocking_send(text).is_err() {
+            return false;
+        }
+        !self.stop.stopped()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(rx: &mut mpsc::Receiver<String>) -> String {
+        let mut out = String::new();
+        while let Ok(chunk) = rx.try_recv() {
+            out.push_str(&chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn joins_split_characters_and_applies_stops_and_cap() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let params = GenerationParams {
+            stop: vec!["END".into()],
+            ..GenerationParams::default()
+        };
+        let mut stream = TokenStream::new(tx, &params, 10);
+        let smile = "😊".as_bytes();
+        assert!(stream.push(&smile[..2]));
+        assert_eq!(drain(&mut rx), "");
+        assert!(stream.push(&smile[2..]));
+        assert!(stream.push(b" E"));
+        assert_eq!(drain(&mut rx), "😊 ");
+        assert!(!stream.push(b"ND!"));
+        stream.finish();
+        assert_eq!(drain(&mut rx), "");
+        assert_eq!(params.outcome.reason(), Some(FinishReason::StopSequence));
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let params = GenerationParams {
+            max_tokens: Some(2),
+            ..GenerationParams::default()
+        };
+        let mut stream = TokenStream::new(tx, &params, 10);
+        assert!(stream.push(b"a"));
+        assert!(!stream.push(b"b"));
+        assert_eq!(stream.remaining(), 0);
+        stream.end(FinishReason::Eos);
+        stream.finish();
+        assert_eq!(drain(&mut rx), "ab");
+        assert_eq!(params.outcome.reason(), Some(FinishReason::MaxTokens));
+    }
+}
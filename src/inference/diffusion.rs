@@ -0,0 +1,297 @@
+//! Text-to-image with Stable Diffusion (v1.5 or v2.1) on candle. The
+//! weights are read once from a diffusers-layout directory and stay loaded;
+//! images are generated one at a time.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use candle::{DType, Device, IndexOp, Module, Tensor};
+use candle_transformers::models::stable_diffusion::{
+    self, clip::ClipTextTransformer, unet_2d::UNet2DConditionModel, vae::AutoEncoderKL,
+    StableDiffusionConfig,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokenizers::Tokenizer;
+
+const DEFAULT_STEPS: usize = 30;
+const MAX_STEPS: usize = 150;
+const DEFAULT_GUIDANCE: f64 = 7.5;
+/// Latent scaling of the v1.5 and v2.1 VAEs.
+const VAE_SCALE: f64 = 0.18215;
+
+pub struct DiffusionService {
+    version: &'static str,
+    config: StableDiffusionConfig,
+    tokenizer: Tokenizer,
+    pad_id: u32,
+    clip: ClipTextTransformer,
+    unet: UNet2DConditionModel,
+    vae: AutoEncoderKL,
+    device: Device,
+    dtype: DType,
+    steps: usize,
+    guidance: f64,
+    /// The UNet is too large to run twice at once.
+    busy: Mutex<()>,
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Service for `IMAGE_GEN_MODEL_DIR`, a diffusers checkout with
+/// `text_encoder/`, `unet/`, `vae/` and a `tokenizer/tokenizer.json`.
+/// `IMAGE_GEN_VERSION` is `v1-5` (default) or `v2-1`; `IMAGE_GEN_DEVICE`
+/// `cpu` or `cuda[:N]` (default: CUDA when available); `IMAGE_GEN_STEPS`
+/// (default 30) and `IMAGE_GEN_GUIDANCE` (default 7.5) tune sampling.
+/// `None` disables image generation.
+pub fn diffusion_from_env() -> Option<Arc<DiffusionService>> {
+    let dir = PathBuf::from(env_value("IMAGE_GEN_MODEL_DIR")?);
+    match DiffusionService::load(&dir) {
+        Ok(service) => {
+            println!(
+                "ℹ️  image generation model {} loaded from {} ({}x{}, {} steps)",
+                service.version,
+                dir.display(),
+                service.config.width,
+                service.config.height,
+                service.steps
+            );
+            Some(Arc::new(service))
+        }
+        Err(err) => {
+            println!("⚠️  image generation disabled: {err:#}");
+            None
+        }
+    }
+}
+
+impl DiffusionService {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let (version, config) = match env_value("IMAGE_GEN_VERSION").as_deref() {
+            None | Some("v1-5") => ("v1-5", StableDiffusionConfig::v1_5(None, None, None)),
+            Some("v2-1") => ("v2-1", StableDiffusionConfig::v2_1(None, None, None)),
+            Some(other) => bail!("unsupported IMAGE_GEN_VERSION {other} (v1-5 or v2-1)"),
+        };
+        let device = build_device()?;
+        // Half precision on the GPU; the CPU kernels want f32.
+        let dtype = if device.is_cuda() {
+            DType::F16
+        } else {
+            DType::F32
+        };
+
+        let tokenizer_path = dir.join("tokenizer").join("tokenizer.json");
+        let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|err| {
+            anyhow!(
+                "failed to load {}: {err} (export the CLIP tokenizer as tokenizer.json)",
+                tokenizer_path.display()
+            )
+        })?;
+        let pad_token = config.clip.pad_with.as_deref().unwrap_or("<|endoftext|>");
+        let pad_id = tokenizer
+            .token_to_id(pad_token)
+            .ok_or_else(|| anyhow!("tokenizer has no {pad_token} token"))?;
+
+        let clip = stable_diffusion::build_clip_transformer(
+            &config.clip,
+            weights(dir, "text_encoder", "model", dtype)?,
+            &device,
+            dtype,
+        )?;
+        let unet = config.build_unet(
+            weights(dir, "unet", "diffusion_pytorch_model", dtype)?,
+            &device,
+            4,
+            false,
+            dtype,
+        )?;
+        let vae = config.build_vae(
+            weights(dir, "vae", "diffusion_pytorch_model", dtype)?,
+            &device,
+            dtype,
+        )?;
+
+        let steps = env_value("IMAGE_GEN_STEPS")
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| (1..=MAX_STEPS).contains(v))
+            .unwrap_or(DEFAULT_STEPS);
+        let guidance = env_value("IMAGE_GEN_GUIDANCE")
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|v| *v >= 1.0)
+            .unwrap_or(DEFAULT_GUIDANCE);
+
+        Ok(Self {
+            version,
+            config,
+            tokenizer,
+            pad_id,
+            clip,
+            unet,
+            vae,
+            device,
+            dtype,
+            steps,
+            guidance,
+            busy: Mutex::new(()),
+        })
+    }
+
+    /// PNG for `prompt`, steered away from `negative_prompt`. The same seed
+    /// gives the same image. Blocks for the whole run; call it from
+    /// `spawn_blocking`.
+    pub fn generate(
+        &self,
+        prompt: &str,
+        negative_prompt: Option<&str>,
+        seed: u64,
+    ) -> Result<Vec<u8>> {
+        let _busy = self
+            .busy
+            .lock()
+            .map_err(|_| anyhow!("diffusion model lock poisoned"))?;
+
+        let embeddings = Tensor::cat(
+            &[
+                self.embed(negative_prompt.unwrap_or(""))?,
+                self.embed(prompt)?,
+            ],
+            0,
+        )?
+        .to_dtype(self.dtype)?;
+
+        let mut scheduler = self.config.build_scheduler(self.steps)?;
+        let shape = (1, 4, self.config.height / 8, self.config.width / 8);
+        let noise = gaussian_noise(seed, shape.0 * shape.1 * shape.2 * shape.3);
+        let noise = Tensor::from_vec(noise, shape, &self.device)?;
+        let mut latents = (noise * scheduler.init_noise_sigma())?.to_dtype(self.dtype)?;
+
+        let timesteps = scheduler.timesteps().to_vec();
+        for timestep in timesteps {
+            let input = Tensor::cat(&[&latents, &latents], 0)?;
+            let input = scheduler.scale_model_input(input, timestep)?;
+            let predicted = self.unet.forward(&input, timestep as f64, &embeddings)?;
+            // Classifier-free guidance: unconditional first, then the prompt.
+            let predicted = predicted.chunk(2, 0)?;
+            let (uncond, cond) = (&predicted[0], &predicted[1]);
+            let guided = (uncond + ((cond - uncond)? * self.guidance)?)?;
+            latents = scheduler.step(&guided, timestep, &latents)?;
+        }
+
+        let image = self.vae.decode(&(&latents / VAE_SCALE)?)?;
+        let image = ((image / 2.)? + 0.5)?
+            .to_device(&Device::Cpu)?
+            .to_dtype(DType::F32)?;
+        let image = (image.clamp(0f32, 1f32)? * 255.)?
+            .to_dtype(DType::U8)?
+            .i(0)?;
+        encode_png(&image)
+    }
+
+    /// CLIP embedding of `text`, padded (or cut) to the model's context.
+    fn embed(&self, text: &str) -> Result<Tensor> {
+        let max_len = self.config.clip.max_position_embeddings;
+        let mut tokens = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|err| anyhow!("failed to tokenize prompt: {err}"))?
+            .get_ids()
+            .to_vec();
+        tokens.truncate(max_len);
+        tokens.resize(max_len, self.pad_id);
+        let tokens = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
+        Ok(self.clip.forward(&tokens)?)
+    }
+}
+
+fn build_device() -> Result<Device> {
+    match env_value("IMAGE_GEN_DEVICE") {
+        Some(pref) if pref.eq_ignore_ascii_case("cpu") => Ok(Device::Cpu),
+        Some(pref) => {
+            let ordinal = pref
+                .split(':')
+                .nth(1)
+                .and_then(|part| part.parse::<usize>().ok())
+                .unwrap_or(0);
+            Device::new_cuda(ordinal)
+                .map_err(|err| anyhow!("IMAGE_GEN_DEVICE {pref}: CUDA init failed: {err}"))
+        }
+        None => Ok(Device::cuda_if_available(0)?),
+    }
+}
+
+/// `<dir>/<part>/<name>.safetensors`, preferring the `.fp16` export when
+/// running in half precision.
+fn weights(dir: &Path, part: &str, name: &str, dtype: DType) -> Result<PathBuf> {
+    let part_dir = dir.join(part);
+    let fp16 = part_dir.join(format!("{name}.fp16.safetensors"));
+    if dtype == DType::F16 && fp16.exists() {
+        return Ok(fp16);
+    }
+    let full = part_dir.join(format!("{name}.safetensors"));
+    if full.exists() {
+        Ok(full)
+    } else {
+        bail!("{} not found", full.display())
+    }
+}
+
+/// Standard normal samples from a seeded rng (Box-Muller), so a seed
+/// reproduces an image on any device.
+fn gaussian_noise(seed: u64, len: usize) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|_| {
+            let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+            let u2: f32 = rng.gen();
+            (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+        })
+        .collect()
+}
+
+/// Encode a `(3, height, width)` u8 tensor as an RGB PNG.
+fn encode_png(image: &Tensor) -> Result<Vec<u8>> {
+    let (channels, height, width) = image.dims3()?;
+    if channels != 3 {
+        bail!("expected an RGB image, got {channels} channels");
+    }
+    let pixels = image.permute((1, 2, 0))?.flatten_all()?.to_vec1::<u8>()?;
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_is_reproducible_and_roughly_standard() {
+        let a = gaussian_noise(42, 4096);
+        assert_eq!(a, gaussian_noise(42, 4096));
+        assert_ne!(a, gaussian_noise(43, 4096));
+
+        let mean = a.iter().sum::<f32>() / a.len() as f32;
+        let var = a.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / a.len() as f32;
+        assert!(mean.abs() < 0.1, "mean {mean}");
+        assert!((var - 1.0).abs() < 0.1, "variance {var}");
+    }
+
+    #[test]
+    fn encodes_rgb_tensor_as_png() {
+        let image = Tensor::zeros((3, 4, 5), DType::U8, &Device::Cpu).unwrap();
+        let png = encode_png(&image).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+}
@@ -0,0 +1,263 @@
+//! GPU memory watchdog. Free device memory is sampled through ggml; when it
+//! runs low the llama.cpp context pools give up contexts (their KV caches
+//! are the GPU memory that can be freed without reloading a model), and
+//! below a harder limit new jobs are turned away with a `capacity` error
+//! instead of failing an allocation halfway through a forward pass.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+use super::llama_cpp_service::{self, LlamaCppService};
+
+const MIB: u64 = 1024 * 1024;
+const DEFAULT_INTERVAL_SECS: u64 = 5;
+const DEFAULT_REJECT_FREE_MIB: u64 = 768;
+const DEFAULT_SHRINK_FREE_MIB: u64 = 2048;
+const DEFAULT_GROW_FREE_MIB: u64 = 4096;
+
+/// Error code of jobs refused for lack of GPU memory.
+pub const CAPACITY_ERROR: &str = "capacity";
+
+/// A job was refused because the GPU is short of memory.
+#[derive(Debug, Clone, Copy)]
+pub struct CapacityError {
+    pub free_mib: u64,
+    pub total_mib: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pressure {
+    /// Refuse new jobs and shrink.
+    Critical,
+    /// Shrink a context pool.
+    High,
+    Normal,
+    /// Enough headroom to give a context back.
+    Low,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Thresholds {
+    reject_mib: u64,
+    shrink_mib: u64,
+    grow_mib: u64,
+}
+
+impl Thresholds {
+    fn pressure(&self, free_mib: u64) -> Pressure {
+        if free_mib < self.reject_mib {
+            Pressure::Critical
+        } else if free_mib < self.shrink_mib {
+            Pressure::High
+        } else if free_mib > self.grow_mib {
+            Pressure::Low
+        } else {
+            Pressure::Normal
+        }
+    }
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            reject_mib: DEFAULT_REJECT_FREE_MIB,
+            shrink_mib: DEFAULT_SHRINK_FREE_MIB,
+            grow_mib: DEFAULT_GROW_FREE_MIB,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GpuWatchdog {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    interval: Duration,
+    thresholds: Thresholds,
+    /// Last sample; `total_mib` is 0 until one was taken (or without GPU).
+    free_mib: AtomicU64,
+    total_mib: AtomicU64,
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+}
+
+impl GpuWatchdog {
+    /// Watchdog sampling every `GPU_WATCHDOG_INTERVAL_SECS` (default 5, `0`
+    /// disables it). Below `GPU_SHRINK_FREE_MIB` free (default 2048) a
+    /// context is given up per sample, below `GPU_REJECT_FREE_MIB` (default
+    /// 768) new jobs are refused, and above `GPU_GROW_FREE_MIB` (default
+    /// 4096) a context is given back.
+    pub fn from_env() -> Self {
+        let defaults = Thresholds::default();
+        let mut thresholds = Thresholds {
+            reject_mib: env_u64("GPU_REJECT_FREE_MIB").unwrap_or(defaults.reject_mib),
+            shrink_mib: env_u64("GPU_SHRINK_FREE_MIB").unwrap_or(defaults.shrink_mib),
+            grow_mib: env_u64("GPU_GROW_FREE_MIB").unwrap_or(defaults.grow_mib),
+        };
+        if !(thresholds.reject_mib <= thresholds.shrink_mib
+            && thresholds.shrink_mib < thresholds.grow_mib)
+        {
+            println!(
+                "⚠️  GPU watchdog needs GPU_REJECT_FREE_MIB <= GPU_SHRINK_FREE_MIB < GPU_GROW_FREE_MIB; using defaults"
+            );
+            thresholds = defaults;
+        }
+        Self {
+            inner: Arc::new(Inner {
+                interval: Duration::from_secs(
+                    env_u64("GPU_WATCHDOG_INTERVAL_SECS").unwrap_or(DEFAULT_INTERVAL_SECS),
+                ),
+                thresholds,
+                free_mib: AtomicU64::new(0),
+                total_mib: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Refuse a new job while free GPU memory is below the reject limit.
+    /// Always admits before the first sample and on CPU-only hosts.
+    pub fn admit(&self) -> Result<(), CapacityError> {
+        let total_mib = self.inner.total_mib.load(Ordering::Relaxed);
+        let free_mib = self.inner.free_mib.load(Ordering::Relaxed);
+        if total_mib > 0 && self.inner.thresholds.pressure(free_mib) == Pressure::Critical {
+            return Err(CapacityError {
+                free_mib,
+                total_mib,
+            });
+        }
+        Ok(())
+    }
+
+    /// Free and total MiB of the last sample; `None` before the first one
+    /// and without a GPU.
+    pub fn memory_mib(&self) -> Option<(u64, u64)> {
+        let total_mib = self.inner.total_mib.load(Ordering::Relaxed);
+        (total_mib > 0).then(|| (self.inner.free_mib.load(Ordering::Relaxed), total_mib))
+    }
+
+    /// Sample memory and resize the pools of `engines` (primary first) in
+    /// the background. False when disabled or no GPU is in use.
+    pub fn spawn(&self, engines: Vec<(&'static str, Arc<LlamaCppService>)>) -> bool {
+        if self.inner.interval.is_zero() || self.sample().is_none() {
+            return false;
+        }
+        let watchdog = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(watchdog.inner.interval);
+            let mut critical = false;
+            loop {
+                ticker.tick().await;
+                let Some(free_mib) = watchdog.sample() else {
+                    continue;
+                };
+                let pressure = watchdog.inner.thresholds.pressure(free_mib);
+                if (pressure == Pressure::Critical) != critical {
+                    critical = !critical;
+                    if critical {
+                        warn!(free_mib, "GPU memory critical, refusing new jobs");
+                    } else {
+                        info!(free_mib, "GPU memory recovered, accepting new jobs");
+                    }
+                }
+                match pressure {
+                    Pressure::Critical | Pressure::High => shrink_one(&engines, free_mib),
+                    Pressure::Low => grow_one(&engines, free_mib).await,
+                    Pressure::Normal => {}
+                }
+            }
+        });
+        true
+    }
+
+    /// Free MiB on the tightest GPU, also stored for [`admit`](Self::admit).
+    fn sample(&self) -> Option<u64> {
+        let memory = llama_cpp_service::gpu_memory()?;
+        let free_mib = memory.free_bytes / MIB;
+        self.inner.free_mib.store(free_mib, Ordering::Relaxed);
+        self.inner
+            .total_mib
+            .store(memory.total_bytes / MIB, Ordering::Relaxed);
+        Some(free_mib)
+    }
+}
+
+/// Give up one context, taking from the fallback engine before the
+/// primary one.
+fn shrink_one(engines: &[(&'static str, Arc<LlamaCppService>)], free_mib: u64) {
+    for (name, engine) in engines.iter().rev() {
+        if engine.shrink_pool() {
+            let (size, capacity) = engine.pool_size();
+            warn!(
+                engine = name,
+                free_mib, size, capacity, "GPU memory low, shrank context pool"
+            );
+            return;
+        }
+    }
+}
+
+/// Re-create one given-up context, primary engine first. One per sample so
+/// the next sample sees what it cost.
+async fn grow_one(engines: &[(&'static str, Arc<LlamaCppService>)], free_mib: u64) {
+    let Some((name, engine)) = engines.iter().find(|(_, engine)| {
+        let (size, capacity) = engine.pool_size();
+        size < capacity
+    }) else {
+        return;
+    };
+    let grower = engine.clone();
+    match tokio::task::spawn_blocking(move || grower.grow_pool()).await {
+        Ok(Ok(true)) => {
+            let (size, capacity) = engine.pool_size();
+            info!(
+                engine = name,
+                free_mib, size, capacity, "GPU memory available, grew context pool"
+            );
+        }
+        Ok(Ok(false)) => {}
+        Ok(Err(err)) => warn!(engine = name, "failed to grow context pool: {err:#}"),
+        Err(err) => warn!(engine = name, "context pool grow task failed: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure_bands_follow_thresholds() {
+        let thresholds = Thresholds::default();
+        assert_eq!(thresholds.pressure(100), Pressure::Critical);
+        assert_eq!(thresholds.pressure(1000), Pressure::High);
+        assert_eq!(thresholds.pressure(3000), Pressure::Normal);
+        assert_eq!(thresholds.pressure(4096), Pressure::Normal);
+        assert_eq!(thresholds.pressure(8000), Pressure::Low);
+    }
+
+    #[test]
+    fn admits_without_a_sample() {
+        let watchdog = GpuWatchdog {
+            inner: Arc::new(Inner {
+                interval: Duration::from_secs(5),
+                thresholds: Thresholds::default(),
+                free_mib: AtomicU64::new(0),
+                total_mib: AtomicU64::new(0),
+            }),
+        };
+        assert!(watchdog.admit().is_ok());
+        watchdog.inner.total_mib.store(24_000, Ordering::Relaxed);
+        assert_eq!(watchdog.admit().unwrap_err().free_mib, 0);
+    }
+}
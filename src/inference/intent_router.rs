@@ -13,6 +13,17 @@ pub struct IntentLogits {
     pub domain: Vec<f32>,
     pub expectation: Vec<f32>,
     pub support: Option<Vec<f32>>,
+    /// Moderation head, present in checkpoints trained with one.
+    pub safety: Option<Vec<f32>>,
+}
+
+/// Texts per forward pass when embedding.
+const EMBED_BATCH_SIZE: usize = 32;
+
+/// Mean-pooled, L2-normalized encoder output for one input.
+pub struct TextEmbedding {
+    pub vector: Vec<f32>,
+    pub tokens: usize,
 }
 
 pub struct RobertaIntentRouter {
@@ -25,6 +36,18 @@ pub struct RobertaIntentRouter {
 
 impl RobertaIntentRouter {
     pub fn load(snapshot: PathBuf, device_id: usize, with_phatic: bool) -> Result<Self> {
+        let device = build_device(device_id)?;
+        Self::load_on_device(snapshot, device, with_phatic)
+    }
+
+    /// Load onto an explicit device (`cpu`, `cuda:1`), ignoring
+    /// `INTENT_ROUTER_DEVICE`.
+    pub fn load_on(snapshot: PathBuf, device: &str, with_phatic: bool) -> Result<Self> {
+        let device = parse_device_preference(device.to_string(), 0)?;
+        Self::load_on_device(snapshot, device, with_phatic)
+    }
+
+    fn load_on_device(snapshot: PathBuf, device: Device, with_phatic: bool) -> Result<Self> {
         let tokenizer_path = snapshot.join("tokenizer.json");
         if !tokenizer_path.exists() {
             return Err(anyhow!(
@@ -48,7 +71,6 @@ impl RobertaIntentRouter {
             .map(|len| len.min(config.max_position_embeddings))
             .unwrap_or(config.max_position_embeddings);
 
-        let device = build_device(device_id)?;
         let dtype = DType::F16;
         let vb = build_var_builder(&weights_path, dtype, &device)?;
         let model = RouterModel::load(&config, vb, with_phatic)?;
@@ -84,6 +106,7 @@ impl RobertaIntentRouter {
         let domain = tensor_to_vec(outputs.domain)?;
         let expectation = tensor_to_vec(outputs.expectation)?;
         let support = outputs.support.map(tensor_to_vec).transpose()?;
+        let safety = outputs.safety.map(tensor_to_vec).transpose()?;
         let phatic_logits = if self.include_phatic {
             outputs.phatic.map(tensor_to_vec).transpose()?
         } else {
@@ -96,8 +119,53 @@ impl RobertaIntentRouter {
             domain,
             expectation,
             support,
+            safety,
         })
     }
+
+    /// Sentence embeddings from the shared encoder, batched
+    /// `EMBED_BATCH_SIZE` texts per forward pass and padded to the longest
+    /// text of each batch.
+    pub fn embed_batch(&self, texts: &[String]) -> Result<Vec<TextEmbedding>> {
+        let pad_id = pad_token_id(&self.tokenizer);
+        let mut out = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBED_BATCH_SIZE) {
+            let encoded = batch
+                .iter()
+                .map(|text| encode_truncated(&self.tokenizer, text, self.max_len))
+                .collect::<Result<Vec<_>>>()?;
+            let seq_len = encoded.iter().map(Vec::len).max().unwrap_or(1);
+
+            let mut ids = Vec::with_capacity(batch.len() * seq_len);
+            let mut mask = Vec::with_capacity(batch.len() * seq_len);
+            for tokens in &encoded {
+                ids.extend_from_slice(tokens);
+                ids.resize(ids.len() + seq_len - tokens.len(), pad_id);
+                mask.resize(mask.len() + tokens.len(), 1u32);
+                mask.resize(mask.len() + seq_len - tokens.len(), 0u32);
+            }
+            let shape = (batch.len(), seq_len);
+            let ids = Tensor::new(ids.as_slice(), &self.device)?.reshape(shape)?;
+            let mask = Tensor::new(mask.as_slice(), &self.device)?.reshape(shape)?;
+            let token_type_ids = Tensor::zeros(shape, DType::U32, &self.device)?;
+
+            let pooled = self
+                .model
+                .embed(&ids, &mask, &token_type_ids)
+                .context("embedding forward pass failed")?
+                .to_vec2::<f32>()?;
+            out.extend(
+                pooled
+                    .into_iter()
+                    .zip(&encoded)
+                    .map(|(vector, tokens)| TextEmbedding {
+                        vector,
+                        tokens: tokens.len(),
+                    }),
+            );
+        }
+        Ok(out)
+    }
 }
 
 struct RouterModel {
@@ -107,6 +175,7 @@ struct RouterModel {
     domain: RouterHead,
     expectation: RouterHead,
     support: Option<RouterHead>,
+    safety: Option<RouterHead>,
     weight_dtype: DType,
 }
 
@@ -121,6 +190,7 @@ struct RouterOutputs {
     expectation: Tensor,
     phatic: Option<Tensor>,
     support: Option<Tensor>,
+    safety: Option<Tensor>,
 }
 
 impl RouterModel {
@@ -135,6 +205,7 @@ impl RouterModel {
         let domain = RouterHead::new(&vb.pp("domain_head"))?;
         let expectation = RouterHead::new(&vb.pp("expectation_head"))?;
         let support = RouterHead::maybe_new(&vb.pp("support_head"))?;
+        let safety = RouterHead::maybe_new(&vb.pp("safety_head"))?;
 
         Ok(Self {
             roberta,
@@ -143,6 +214,7 @@ impl RouterModel {
             domain,
             expectation,
             support,
+            safety,
             weight_dtype: vb.dtype(),
         })
     }
@@ -151,6 +223,29 @@ impl RouterModel {
         self.phatic.is_some()
     }
 
+    /// Masked mean of the last hidden state, L2-normalized.
+    fn embed(
+        &self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        token_type_ids: &Tensor,
+    ) -> candle::Result<Tensor> {
+        let hidden = self
+            .roberta
+            .forward(input_ids, attention_mask, token_type_ids, None, None, None)?
+            .to_dtype(DType::F32)?;
+        let mask = attention_mask.to_dtype(DType::F32)?;
+        let summed = hidden.broadcast_mul(&mask.unsqueeze(2)?)?.sum(1)?;
+        let counts = mask.sum(1)?.clamp(1f32, f32::MAX)?.unsqueeze(1)?;
+        let mean = summed.broadcast_div(&counts)?;
+        let norm = mean
+            .sqr()?
+            .sum_keepdim(1)?
+            .sqrt()?
+            .clamp(1e-12f32, f32::MAX)?;
+        mean.broadcast_div(&norm)
+    }
+
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -175,6 +270,10 @@ impl RouterModel {
             Some(head) => Some(head.forward(&features)?),
             None => None,
         };
+        let safety = match &self.safety {
+            Some(head) => Some(head.forward(&features)?),
+            None => None,
+        };
 
         Ok(RouterOutputs {
             speech_act,
@@ -182,6 +281,7 @@ impl RouterModel {
             expectation,
             phatic,
             support,
+            safety,
         })
     }
 }
@@ -239,6 +339,18 @@ fn tokenize_ids(tokenizer: &Tokenizer, text: &str, max_len: usize) -> Result<(Ve
     Ok((ids, non_padding_len))
 }
 
+fn encode_truncated(tokenizer: &Tokenizer, text: &str, max_len: usize) -> Result<Vec<u32>> {
+    let enc = tokenizer
+        .encode(text, true)
+        .map_err(|e| anyhow!("Tokenizer encode error: {e}"))?;
+    let mut ids = enc.get_ids().to_vec();
+    if ids.is_empty() {
+        ids.push(0);
+    }
+    ids.truncate(max_len);
+    Ok(ids)
+}
+
 fn load_config(snapshot: &Path) -> Result<Config> {
     let path = snapshot.join("config.json");
     if !path.exists() {
@@ -0,0 +1,573 @@
+//! Structured output. A JSON schema is compiled to a llama.cpp GBNF grammar
+//! that constrains sampling, and the reply is checked against the schema
+//! afterwards; small slips (code fences, trailing commas, a reply cut off by
+//! the token cap, numbers sent as strings) are repaired first.
+//!
+//! Supported: `type` (one or a list), `properties`, `required`,
+//! `additionalProperties`, `items`, `minItems`/`maxItems`, `enum`, `const`,
+//! `anyOf`/`oneOf`, `minLength`/`maxLength` and `minimum`/`maximum`.
+//! Annotations (`title`, `description`, `format`, ...) are ignored.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+
+/// Keywords that would change what is valid but are not implemented.
+const UNSUPPORTED: &[&str] = &["$ref", "allOf", "not", "if", "pattern", "patternProperties"];
+/// Deepest schema nesting accepted.
+const MAX_DEPTH: usize = 16;
+
+const BASE_RULES: &str = r#"ws ::= | " " | "\n" [ \t]{0,20}
+char ::= [^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4})
+string ::= "\"" char* "\""
+integer ::= "-"? ("0" | [1-9] [0-9]{0,15})
+number ::= integer ("." [0-9]+)? ([eE] [-+]? [0-9]+)?
+boolean ::= "true" | "false"
+null ::= "null"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+"#;
+
+/// A schema together with its grammar.
+#[derive(Debug, Clone)]
+pub struct StructuredOutput {
+    schema: Value,
+    grammar: String,
+}
+
+impl StructuredOutput {
+    /// Fails on schemas using keywords this module cannot enforce.
+    pub fn new(schema: Value) -> Result<Self> {
+        let mut compiler = Compiler::default();
+        let root = compiler.rule("root", &schema, 0)?;
+        let mut grammar = format!("root ::= {root}\n");
+        for (name, body) in &compiler.rules {
+            grammar.push_str(&format!("{name} ::= {body}\n"));
+        }
+        grammar.push_str(BASE_RULES);
+        Ok(Self { schema, grammar })
+    }
+
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// GBNF with a `root` rule.
+    pub fn grammar(&self) -> &str {
+        &self.grammar
+    }
+
+    /// The JSON value in `text`, repaired where needed and checked against
+    /// the schema.
+    pub fn parse(&self, text: &str) -> Result<Value> {
+        let mut value = match serde_json::from_str::<Value>(text.trim()) {
+            Ok(value) => value,
+            Err(_) => repair(text).ok_or_else(|| anyhow!("no JSON value in the output"))?,
+        };
+        if validate(&self.schema, &value, "$").is_err() {
+            conform(&self.schema, &mut value);
+        }
+        validate(&self.schema, &value, "$").map_err(|err| anyhow!(err))?;
+        Ok(value)
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    rules: Vec<(String, String)>,
+}
+
+impl Compiler {
+    /// Add a rule for `schema` and return its name (or, for `root`, the
+    /// expression itself).
+    fn rule(&mut self, name: &str, schema: &Value, depth: usize) -> Result<String> {
+        if depth > MAX_DEPTH {
+            bail!("schema nested deeper than {MAX_DEPTH} levels");
+        }
+        let body = self.expression(name, schema, depth)?;
+        if name == "root" {
+            return Ok(body);
+        }
+        let name = self.unique(name);
+        self.rules.push((name.clone(), body));
+        Ok(name)
+    }
+
+    fn unique(&self, name: &str) -> String {
+        let taken = |candidate: &str| self.rules.iter().any(|(n, _)| n == candidate);
+        if !taken(name) {
+            return name.to_string();
+        }
+        (2..)
+            .map(|i| format!("{name}-{i}"))
+            .find(|candidate| !taken(candidate))
+            .expect("unbounded range")
+    }
+
+    fn expression(&mut self, name: &str, schema: &Value, depth: usize) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".into()),
+            Value::Bool(false) => bail!("`false` schema at {name} matches nothing"),
+            Value::Object(schema) => schema,
+            _ => bail!("schema at {name} is not an object"),
+        };
+        if let Some(keyword) = UNSUPPORTED.iter().find(|k| schema.contains_key(**k)) {
+            bail!("unsupported schema keyword `{keyword}` at {name}");
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| anyhow!("`enum` at {name} must be a non-empty array"))?;
+            let options: Vec<String> = values.iter().map(|v| literal(&v.to_string())).collect();
+            return Ok(format!("( {} )", options.join(" | ")));
+        }
+        if let Some(options) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let options = options
+                .as_array()
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| anyhow!("`anyOf` at {name} must be a non-empty array"))?;
+            let mut rules = Vec::with_capacity(options.len());
+            for (i, option) in options.iter().enumerate() {
+                rules.push(self.rule(&format!("{name}-{i}"), option, depth + 1)?);
+            }
+            return Ok(format!("( {} )", rules.join(" | ")));
+        }
+        let types: Vec<&str> = match schema.get("type") {
+            None => return Ok("value".into()),
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+            Some(_) => bail!("`type` at {name} must be a string or a list"),
+        };
+        let mut options = Vec::with_capacity(types.len());
+        for t in types {
+            options.push(match t {
+                "string" => string_expression(schema),
+                "integer" => "integer".into(),
+                "number" => "number".into(),
+                "boolean" => "boolean".into(),
+                "null" => "null".into(),
+                "object" => self.object(name, schema, depth)?,
+                "array" => self.array(name, schema, depth)?,
+                other => bail!("unknown type `{other}` at {name}"),
+            });
+        }
+        match options.len() {
+            0 => bail!("`type` at {name} is empty"),
+            1 => Ok(options.remove(0)),
+            _ => Ok(format!("( {} )", options.join(" | "))),
+        }
+    }
+
+    fn object(&mut self, name: &str, schema: &Map<String, Value>, depth: usize) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok("object".into());
+        };
+        let required = required(schema);
+        let mut kvs = Vec::with_capacity(properties.len());
+        for (key, property) in properties {
+            let value = self.rule(&format!("{name}-{}", rule_name(key)), property, depth + 1)?;
+            let kv = format!(
+                "{} ws \":\" ws {value} ws",
+                literal(&Value::String(key.clone()).to_string())
+            );
+            kvs.push((required.contains(&key.as_str()), kv));
+        }
+        let (must, may): (Vec<_>, Vec<_>) = kvs.into_iter().partition(|(req, _)| *req);
+        let must: Vec<String> = must.into_iter().map(|(_, kv)| kv).collect();
+        let may: Vec<String> = may.into_iter().map(|(_, kv)| kv).collect();
+
+        let body = if must.is_empty() {
+            optional_first(&may)
+        } else {
+            let mut body = must.join(" \",\" ws ");
+            for kv in &may {
+                body.push_str(&format!(" ( \",\" ws {kv} )?"));
+            }
+            body
+        };
+        Ok(format!("\"{{\" ws {body} \"}}\""))
+    }
+
+    fn array(&mut self, name: &str, schema: &Map<String, Value>, depth: usize) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.rule(&format!("{name}-item"), items, depth + 1)?,
+            None => "value".into(),
+        };
+        let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        let max = schema.get("maxItems").and_then(Value::as_u64);
+        if max == Some(0) {
+            return Ok("\"[\" ws \"]\"".into());
+        }
+        let more = match (min.saturating_sub(1), max.map(|m| m - 1)) {
+            (0, None) => "*".to_string(),
+            (lo, None) => format!("{{{lo},}}"),
+            (lo, Some(hi)) => format!("{{{lo},{hi}}}"),
+        };
+        let items = format!("{item} ws ( \",\" ws {item} ws ){more}");
+        if min == 0 {
+            Ok(format!("\"[\" ws ( {items} )? \"]\""))
+        } else {
+            Ok(format!("\"[\" ws {items} \"]\""))
+        }
+    }
+}
+
+fn required(schema: &Map<String, Value>) -> Vec<&str> {
+    schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|keys| keys.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+/// Optional properties in order, any of them present, comma separated.
+fn optional_first(kvs: &[String]) -> String {
+    if kvs.is_empty() {
+        return String::new();
+    }
+    let options: Vec<String> = (0..kvs.len())
+        .map(|first| {
+            let mut option = kvs[first].clone();
+            for kv in &kvs[first + 1..] {
+                option.push_str(&format!(" ( \",\" ws {kv} )?"));
+            }
+            option
+        })
+        .collect();
+    format!("( {} )?", options.join(" | "))
+}
+
+fn string_expression(schema: &Map<String, Value>) -> String {
+    let min = schema.get("minLength").and_then(Value::as_u64);
+    let max = schema.get("maxLength").and_then(Value::as_u64);
+    let chars = match (min, max) {
+        (None, None) => return "string".into(),
+        (min, None) => format!("char{{{},}}", min.unwrap_or(0)),
+        (min, Some(max)) => format!("char{{{},{max}}}", min.unwrap_or(0)),
+    };
+    format!("\"\\\"\" {chars} \"\\\"\"")
+}
+
+/// GBNF literal for `text`.
+fn literal(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Rule names allow letters, digits and dashes.
+fn rule_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if name.is_empty() {
+        "p".into()
+    } else {
+        name
+    }
+}
+
+/// Check `value` against `schema`; the error names the first mismatch.
+pub fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{path}: not allowed")),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(format!("{path}: expected {expected}"));
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            return Err(format!("{path}: not one of the allowed values"));
+        }
+    }
+    if let Some(options) = schema
+        .get("anyOf")
+        .or_else(|| schema.get("oneOf"))
+        .and_then(Value::as_array)
+    {
+        if !options.iter().any(|o| validate(o, value, path).is_ok()) {
+            return Err(format!("{path}: matches none of the alternatives"));
+        }
+    }
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+        return Err(format!("{path}: expected {}", types.join(" or ")));
+    }
+
+    match value {
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if schema
+                .get("minLength")
+                .and_then(Value::as_u64)
+                .is_some_and(|min| len < min)
+            {
+                return Err(format!("{path}: too short"));
+            }
+            if schema
+                .get("maxLength")
+                .and_then(Value::as_u64)
+                .is_some_and(|max| len > max)
+            {
+                return Err(format!("{path}: too long"));
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            if schema
+                .get("minimum")
+                .and_then(Value::as_f64)
+                .is_some_and(|min| n < min)
+            {
+                return Err(format!("{path}: below minimum"));
+            }
+            if schema
+                .get("maximum")
+                .and_then(Value::as_f64)
+                .is_some_and(|max| n > max)
+            {
+                return Err(format!("{path}: above maximum"));
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if schema
+                .get("minItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|min| len < min)
+            {
+                return Err(format!("{path}: too few items"));
+            }
+            if schema
+                .get("maxItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|max| len > max)
+            {
+                return Err(format!("{path}: too many items"));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &format!("{path}[{i}]"))?;
+                }
+            }
+        }
+        Value::Object(object) => {
+            for key in required(schema) {
+                if !object.contains_key(key) {
+                    return Err(format!("{path}: missing `{key}`"));
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let closed = schema.get("additionalProperties") == Some(&Value::Bool(false));
+            for (key, item) in object {
+                match properties.and_then(|p| p.get(key)) {
+                    Some(property) => validate(property, item, &format!("{path}.{key}"))?,
+                    None if closed => return Err(format!("{path}: unexpected `{key}`")),
+                    None => {}
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+    match t {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        _ => false,
+    }
+}
+
+/// Nudge `value` towards `schema`: drop properties a closed object does
+/// not allow and convert strings holding numbers or booleans.
+fn conform(schema: &Value, value: &mut Value) {
+    let Some(schema) = schema.as_object() else {
+        return;
+    };
+    let wants = |t: &str| match schema.get("type") {
+        Some(Value::String(s)) => s == t,
+        Some(Value::Array(types)) => types.iter().any(|s| s == t),
+        _ => false,
+    };
+    match value {
+        Value::String(s) if wants("integer") || wants("number") || wants("boolean") => {
+            if let Ok(parsed) = serde_json::from_str::<Value>(s.trim()) {
+                if parsed.is_number() || parsed.is_boolean() {
+                    *value = parsed;
+                }
+            }
+        }
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if schema.get("additionalProperties") == Some(&Value::Bool(false)) {
+                object.retain(|key, _| properties.is_some_and(|p| p.contains_key(key)));
+            }
+            if let Some(properties) = properties {
+                for (key, item) in object.iter_mut() {
+                    if let Some(property) = properties.get(key) {
+                        conform(property, item);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for item in items {
+                    conform(item_schema, item);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The first JSON object or array in `text`, with trailing commas removed
+/// and brackets closed when the text was cut off.
+pub fn repair(text: &str) -> Option<Value> {
+    let start = text.find(['{', '['])?;
+    let mut out = String::with_capacity(text.len() - start);
+    let mut open: Vec<char> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text[start..].chars() {
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            '}' | ']' => {
+                drop_trailing_comma(&mut out);
+                if open.pop() != Some(c) {
+                    return None;
+                }
+            }
+            _ => {}
+        }
+        out.push(c);
+        if open.is_empty() {
+            break;
+        }
+    }
+    if in_string {
+        if escaped {
+            out.pop();
+        }
+        out.push('"');
+    }
+    while let Some(close) = open.pop() {
+        drop_trailing_comma(&mut out);
+        out.push(close);
+    }
+    serde_json::from_str(&out).ok()
+}
+
+fn drop_trailing_comma(out: &mut String) {
+    let trimmed = out.trim_end().len();
+    if out[..trimmed].ends_with(',') {
+        out.truncate(trimmed - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn action_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "tool": { "enum": ["calculator", "web_search"] },
+                "args": { "type": "object" },
+                "note": { "type": "string", "maxLength": 20 },
+                "count": { "type": "integer", "minimum": 0 }
+            },
+            "required": ["tool", "args"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn compiles_objects_enums_and_arrays() {
+        let format = StructuredOutput::new(action_schema()).unwrap();
+        let grammar = format.grammar();
+        assert!(grammar.starts_with("root ::= \"{\" ws "), "{grammar}");
+        assert!(grammar.contains("\"\\\"args\\\"\" ws \":\" ws root-args ws"));
+        assert!(grammar.contains("\"\\\"calculator\\\"\" | \"\\\"web_search\\\"\""));
+        assert!(grammar.contains("char{0,20}"));
+
+        let list = StructuredOutput::new(json!({
+            "type": "array",
+            "items": { "type": "string" },
+            "minItems": 1,
+            "maxItems": 3
+        }))
+        .unwrap();
+        assert!(list.grammar().contains("{0,2}"), "{}", list.grammar());
+
+        let err = StructuredOutput::new(json!({ "$ref": "#/defs/a" })).unwrap_err();
+        assert!(err.to_string().contains("$ref"));
+    }
+
+    #[test]
+    fn repairs_and_validates_output() {
+        let format = StructuredOutput::new(action_schema()).unwrap();
+        let value = format
+            .parse("Sure:\n```json\n{\"tool\": \"calculator\", \"args\": {\"expression\": \"1+1\"},}\n```")
+            .unwrap();
+        assert_eq!(value["args"]["expression"], "1+1");
+
+        // Cut off by the token cap, with a stray property and a quoted number.
+        let value = format
+            .parse(r#"{"tool": "web_search", "count": "3", "extra": 1, "args": {"query": "rust"#)
+            .unwrap();
+        assert_eq!(value["count"], 3);
+        assert!(value.get("extra").is_none());
+        assert_eq!(value["args"]["query"], "rust");
+
+        assert!(format.parse(r#"{"tool": "run_cmd", "args": {}}"#).is_err());
+        assert!(format.parse("no json here").is_err());
+    }
+}
@@ -1,14 +1,18 @@
 use anyhow::{anyhow, bail, Result};
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+use super::backend::{FinishReason, GenerationBackend, ReplyOutcome, TokenStream};
+
 #[allow(
     non_camel_case_types,
     non_snake_case,
@@ -20,6 +24,9 @@ mod ffi {
     include!(concat!(env!("OUT_DIR"), "/llama_bindings.rs"));
 }
 
+/// Prefix of the chunk sent in place of output when generation fails.
+pub const ENGINE_ERROR_PREFIX: &str = "llama.cpp error:";
+
 static BACKEND_ONCE: OnceLock<()> = OnceLock::new();
 static BACKEND_USERS: AtomicUsize = AtomicUsize::new(0);
 
@@ -38,108 +45,107 @@ fn shutdown_backend() {
     }
 }
 
-pub struct LlamaCppService {
-    pool: ContextPool,
-}
-
-struct SharedModel {
-    model: *mut ffi::llama_model,
-    vocab: *const ffi::llama_vocab,
-    eos_token: ffi::llama_token,
-    n_batch: i32,
-    max_tokens: usize,
+#[derive(Debug, Clone, Copy)]
+pub struct GpuMemory {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
 }
 
-impl Drop for SharedModel {
-    fn drop(&mut self) {
-        unsafe {
-            if !self.model.is_null() {
-                ffi::llama_model_free(self.model);
+/// Memory of the GPU with the least free memory among the devices ggml
+/// drives; `None` without a GPU backend. Counts every user of the device,
+/// candle models included.
+pub fn gpu_memory() -> Option<GpuMemory> {
+    let mut tightest: Option<GpuMemory> = None;
+    unsafe {
+        for index in 0..ffi::ggml_backend_dev_count() {
+            let dev = ffi::ggml_backend_dev_get(index);
+            if dev.is_null()
+                || ffi::ggml_backend_dev_type(dev)
+                    != ffi::ggml_backend_dev_type_GGML_BACKEND_DEVICE_TYPE_GPU
+            {
+                continue;
             }
-        }
-        shutdown_backend();
-    }
-}
-
-struct LlamaContext {
-    shared: Arc<SharedModel>,
-    ctx: *mut ffi::llama_context,
-    sampler: *mut ffi::llama_sampler,
-    n_past: i32,
-}
-
-unsafe impl Send for LlamaContext {}
-unsafe impl Sync for LlamaContext {}
-
-impl Drop for LlamaContext {
-    fn drop(&mut self) {
-        unsafe {
-            if !self.sampler.is_null() {
-                ffi::llama_sampler_free(self.sampler);
+            let (mut free, mut total) = (0usize, 0usize);
+            ffi::ggml_backend_dev_memory(dev, &mut free, &mut total);
+            if total == 0 {
+                continue;
             }
-            if !self.ctx.is_null() {
-                ffi::llama_free(self.ctx);
+            let memory = GpuMemory {
+                free_bytes: free as u64,
+                total_bytes: total as u64,
+            };
+            if tightest.map_or(true, |t| memory.free_bytes < t.free_bytes) {
+                tightest = Some(memory);
             }
         }
     }
+    tightest
 }
 
-struct ContextHandle {
-    ctx: Mutex<LlamaContext>,
+/// Per-reply overrides of the engine's configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenerationParams {
+    /// `None` keeps `LLAMA_CLI_TEMP`.
+    pub temperature: Option<f32>,
+    /// Lower cap on generated tokens; the configured one still applies.
+    pub max_tokens: Option<usize>,
+    /// The reply ends before the first of these; it is not streamed.
+    pub stop: Vec<String>,
+    /// GBNF grammar (with a `root` rule) the reply must follow.
+    pub grammar: Option<String>,
+    /// Added to the logits of a token id (`"1234"`) or of the first token
+    /// of a text, with and without a leading space.
+    pub logit_bias: Vec<(String, f32)>,
+    /// Sampling seed, at most [`MAX_SEED`]; the same seed, prompt and
+    /// parameters give the same reply. `None` samples at random.
+    pub seed: Option<u32>,
+    /// Not a setting: how the reply ended, shared with the caller.
+    #[serde(skip)]
+    pub outcome: ReplyOutcome,
 }
 
-#[derive(Clone)]
-struct ContextPool {
-    inner: Arc<ContextPoolInner>,
-}
+/// `u32::MAX` is llama.cpp's "pick a random seed".
+pub const MAX_SEED: u32 = ffi::LLAMA_DEFAULT_SEED - 1;
 
-struct ContextPoolInner {
-    queue: Mutex<VecDeque<Arc<ContextHandle>>>,
-    available: Condvar,
+/// Seed for a reply whose caller did not pick one, so it can be reported
+/// and replayed.
+pub fn random_seed() -> u32 {
+    thread_rng().gen_range(0..=MAX_SEED)
 }
 
-struct ContextLease {
-    pool: Arc<ContextPoolInner>,
-    ctx: Option<Arc<ContextHandle>>,
+pub struct LlamaCppService {
+    shared: Arc<SharedModel>,
+    /// Tokens each reply's context holds.
+    ctx_length: usize,
+    /// Empty when replies are batched.
+    pool: ContextPool,
+    batcher: Option<Batcher>,
 }
 
-impl Drop for ContextLease {
-    fn drop(&mut self) {
-        if let Some(ctx) = self.ctx.take() {
-            let mut queue = self.pool.queue.lock().unwrap();
-            queue.push_back(ctx);
-            self.pool.available.notify_one();
-        }
-    }
+struct SharedModel {
+    model: *mut ffi::llama_model,
+    vocab: *const ffi::llama_vocab,
+    eos_token: ffi::llama_token,
+    n_batch: i32,
+    max_tokens: usize,
+    /// Never sampled (`LLAMA_BANNED_TOKENS`).
+    banned: Vec<ffi::llama_token>,
 }
 
-impl LlamaCppService {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
-        model_path: impl AsRef<Path>,
-        ctx_length: u32,
-        max_tokens: usize,
-        temperature: f32,
-        top_p: f32,
-        top_k: i32,
-        gpu_layers: Option<i32>,
-        threads: Option<i32>,
-        pool_size: usize,
-    ) -> Result<Self> {
-        init_backend();
-        println!("⚡️ llama.cpp params: ctx={ctx_length} max_tokens={max_tokens} temp={temperature} top_p={top_p} top_k={top_k} gpu_layers={:?} threads={:?}", gpu_layers, threads);
-        let path = model_path.as_ref();
+// The model and vocab are read-only once loaded; tokenization does not touch
+// per-context state.
+unsafe impl Send for SharedModel {}
+unsafe impl Sync for SharedModel {}
+
+impl SharedModel {
+    fn load(path: &Path, gpu_layers: Option<i32>, max_tokens: usize) -> Result<Self> {
         if !path.exists() {
             bail!("GGUF model not found at {}", path.display());
         }
-
-        if pool_size == 0 {
-            bail!("context pool size must be at least 1");
-        }
-
         let path_cstr = CString::new(path.as_os_str().as_bytes())
             .map_err(|_| anyhow!("model path contains interior null byte"))?;
 
+        init_backend();
         let mut model_params = unsafe { ffi::llama_model_default_params() };
         model_params.n_gpu_layers = gpu_layers.unwrap_or(-1);
         model_params.main_gpu = 0;
@@ -160,159 +166,102 @@ impl LlamaCppService {
             bail!("model vocabulary unavailable");
         }
 
-        let shared = Arc::new(SharedModel {
+        Ok(Self {
             model,
             vocab,
             eos_token: unsafe { ffi::llama_vocab_eos(vocab) },
             n_batch: 512,
             max_tokens,
-        });
-
-        let threads = threads.unwrap_or_else(|| num_cpus::get_physical() as i32);
-        let mut contexts = Vec::with_capacity(pool_size);
-        for _ in 0..pool_size {
-            contexts.push(LlamaContext::create(
-                shared.clone(),
-                ctx_length,
-                threads,
-                temperature,
-                top_p,
-                top_k,
-            )?);
-        }
-
-        Ok(Self {
-            pool: ContextPool::new(contexts),
+            banned: Vec::new(),
         })
     }
 
-    pub fn generate_stream(
-        &self,
-        prompt: String,
-        cancel: Arc<AtomicBool>,
-    ) -> mpsc::Receiver<String> {
-        let (tx, rx) = mpsc::channel(128);
-        let pool = self.pool.clone();
-        tokio::task::spawn_blocking(move || {
-            let lease = pool.checkout();
-            if let Err(err) = lease.run(&prompt, cancel, tx.clone()) {
-                let _ = tx.blocking_send(format!("llama.cpp error: {err}"));
+    /// The banned tokens and the tokens `extra` names, with their biases.
+    fn logit_biases(&self, extra: &[(String, f32)]) -> Vec<ffi::llama_logit_bias> {
+        let mut biases: Vec<ffi::llama_logit_bias> = self
+            .banned
+            .iter()
+            .map(|&token| ffi::llama_logit_bias {
+                token,
+                bias: f32::NEG_INFINITY,
+            })
+            .collect();
+        for (target, bias) in extra {
+            for token in self.bias_tokens(target) {
+                biases.push(ffi::llama_logit_bias { token, bias: *bias });
             }
-        });
-        rx
-    }
-
-    pub async fn generate_completion(
-        &self,
-        prompt: String,
-        cancel: Arc<AtomicBool>,
-    ) -> Result<String> {
-        let mut rx = self.generate_stream(prompt, cancel);
-        let mut out = String::new();
-        while let Some(chunk) = rx.recv().await {
-            out.push_str(&chunk);
         }
-        Ok(out)
+        biases
     }
-}
-
-impl LlamaContext {
-    fn create(
-        shared: Arc<SharedModel>,
-        ctx_length: u32,
-        threads: i32,
-        temperature: f32,
-        top_p: f32,
-        top_k: i32,
-    ) -> Result<Self> {
-        let mut ctx_params = unsafe { ffi::llama_context_default_params() };
-        ctx_params.n_ctx = ctx_length;
-        ctx_params.n_batch = shared.n_batch as u32;
-        ctx_params.n_ubatch = shared.n_batch as u32;
-        ctx_params.n_threads = threads;
-        ctx_params.n_threads_batch = threads;
-        ctx_params.offload_kqv = true;
-
-        let ctx = unsafe { ffi::llama_init_from_model(shared.model, ctx_params) };
-        if ctx.is_null() {
-            bail!("failed to create llama context");
-        }
-
-        let mut sampler_params = unsafe { ffi::llama_sampler_chain_default_params() };
-        sampler_params.no_perf = true;
 
-        let sampler = unsafe { ffi::llama_sampler_chain_init(sampler_params) };
-        if sampler.is_null() {
-            unsafe {
-                ffi::llama_free(ctx);
+    /// Tokens `target` stands for: a token id, or the first token of the
+    /// text and of the text after a space (how a word mid-sentence starts).
+    /// Ids outside the vocabulary name nothing.
+    fn bias_tokens(&self, target: &str) -> Vec<ffi::llama_token> {
+        if let Ok(id) = target.trim().parse::<ffi::llama_token>() {
+            let n_vocab = unsafe { ffi::llama_vocab_n_tokens(self.vocab) };
+            if (0..n_vocab).contains(&id) {
+                return vec![id];
             }
-            bail!("failed to create sampler chain");
+            tracing::warn!(token = id, n_vocab, "logit bias token id out of range");
+            return Vec::new();
         }
-
-        unsafe {
-            if top_k > 0 {
-                let topk = ffi::llama_sampler_init_top_k(top_k);
-                ffi::llama_sampler_chain_add(sampler, topk);
-            }
-            if top_p < 0.9999 {
-                let topp = ffi::llama_sampler_init_top_p(top_p, 1);
-                ffi::llama_sampler_chain_add(sampler, topp);
-            }
-            if (temperature - 1.0).abs() > f32::EPSILON {
-                let temp = ffi::llama_sampler_init_temp(temperature);
-                ffi::llama_sampler_chain_add(sampler, temp);
+        let space = self
+            .tokenize(" ")
+            .ok()
+            .and_then(|tokens| tokens.first().copied());
+        let mut tokens = Vec::new();
+        for variant in [target.to_string(), format!(" {target}")] {
+            let Some(first) = self
+                .tokenize(&variant)
+                .ok()
+                .and_then(|encoded| encoded.first().copied())
+            else {
+                continue;
+            };
+            // Special tokens do not merge with a leading space.
+            if Some(first) != space && !tokens.contains(&first) {
+                tokens.push(first);
             }
-            let seed = thread_rng().gen();
-            let dist = ffi::llama_sampler_init_dist(seed);
-            ffi::llama_sampler_chain_add(sampler, dist);
         }
-
-        Ok(Self {
-            shared,
-            ctx,
-            sampler,
-            n_past: 0,
-        })
+        tokens
     }
 
-    fn run(
-        &mut self,
-        prompt: &str,
-        cancel: Arc<AtomicBool>,
-        tx: mpsc::Sender<String>,
-    ) -> Result<()> {
+    /// Token ids mean the same in both models, so one can draft for the
+    /// other.
+    fn same_vocab(&self, other: &SharedModel) -> bool {
         unsafe {
-            let mem = ffi::llama_get_memory(self.ctx);
-            ffi::llama_memory_clear(mem, true);
-            ffi::llama_sampler_reset(self.sampler);
+            ffi::llama_vocab_n_tokens(self.vocab) == ffi::llama_vocab_n_tokens(other.vocab)
+                && ffi::llama_vocab_bos(self.vocab) == ffi::llama_vocab_bos(other.vocab)
+                && self.eos_token == other.eos_token
         }
-        self.n_past = 0;
+    }
 
-        let prompt_tokens = self.tokenize(prompt)?;
-        self.decode_sequence(&prompt_tokens)?;
-        let mut pending = Vec::new();
+    /// End of generation, or no token could be sampled.
+    fn ends_reply(&self, token: ffi::llama_token) -> bool {
+        token == self.eos_token || token == ffi::LLAMA_TOKEN_NULL
+    }
 
-        for _ in 0..self.shared.max_tokens {
-            if cancel.load(Ordering::SeqCst) {
-                break;
-            }
-            let token = unsafe { ffi::llama_sampler_sample(self.sampler, self.ctx, -1) };
-            if token == self.shared.eos_token || token == ffi::LLAMA_TOKEN_NULL {
-                break;
-            }
-            unsafe {
-                ffi::llama_sampler_accept(self.sampler, token);
-            }
-            let piece = self.render_token_bytes(token)?;
-            if !piece.is_empty() {
-                pending.extend_from_slice(&piece);
+    fn token_bytes(&self, token: ffi::llama_token) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; 64];
+        loop {
+            let res = unsafe {
+                ffi::llama_token_to_piece(
+                    self.vocab,
+                    token,
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len() as i32,
+                    0,
+                    false,
+                )
+            };
+            if res >= 0 {
+                let slice = &buf[..res as usize];
+                return Ok(slice.to_vec());
             }
-            self.flush_pending(&mut pending, &tx)?;
-            self.decode_sequence(std::slice::from_ref(&token))?;
+            let needed = (-res) as usize + 8;
+            buf.resize(needed, 0);
         }
-
-        self.flush_pending(&mut pending, &tx)?;
-        Ok(())
     }
 
     fn tokenize(&self, text: &str) -> Result<Vec<ffi::llama_token>> {
@@ -322,7 +271,7 @@ impl LlamaContext {
         loop {
             let res = unsafe {
                 ffi::llama_tokenize(
-                    self.shared.vocab,
+                    self.vocab,
                     text_ptr,
                     bytes.len() as i32,
                     buf.as_mut_ptr(),
@@ -339,125 +288,936 @@ impl LlamaContext {
             buf.resize(needed, 0);
         }
     }
+}
 
-    fn decode_sequence(&mut self, tokens: &[ffi::llama_token]) -> Result<()> {
-        if tokens.is_empty() {
-            return Ok(());
+impl Drop for SharedModel {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.model.is_null() {
+                ffi::llama_model_free(self.model);
+            }
         }
-        let mut processed = 0usize;
-        while processed < tokens.len() {
-            let take = (tokens.len() - processed).min(self.shared.n_batch as usize);
-            let chunk = &tokens[processed..processed + take];
-            let mut batch = unsafe { ffi::llama_batch_init(self.shared.n_batch, 0, 1) };
-            unsafe {
-                let token_slice = std::slice::from_raw_parts_mut(batch.token, chunk.len());
-                token_slice.copy_from_slice(chunk);
+        shutdown_backend();
+    }
+}
 
-                let pos_slice = std::slice::from_raw_parts_mut(batch.pos, chunk.len());
-                for (i, slot) in pos_slice.iter_mut().enumerate() {
-                    *slot = (self.n_past + i as i32) as ffi::llama_pos;
-                }
+#[derive(Clone, Copy)]
+struct SamplingParams {
+    temperature: f32,
+    top_p: f32,
+    top_k: i32,
+    /// `None` draws a new seed whenever the chain is reset.
+    seed: Option<u32>,
+}
 
-                let n_seq_slice = std::slice::from_raw_parts_mut(batch.n_seq_id, chunk.len());
-                let seq_heads = std::slice::from_raw_parts_mut(batch.seq_id, chunk.len());
-                let logits_slice = std::slice::from_raw_parts_mut(batch.logits, chunk.len());
+/// Sampler chain built for a single run.
+struct OwnedSampler(*mut ffi::llama_sampler);
 
-                for i in 0..chunk.len() {
-                    n_seq_slice[i] = 1;
-                    let seq_slot = std::slice::from_raw_parts_mut(seq_heads[i], 1);
-                    seq_slot[0] = 0;
-                    logits_slice[i] = if i == chunk.len() - 1 { 1 } else { 0 };
-                }
-            }
-            batch.n_tokens = chunk.len() as i32;
-            let err = unsafe { ffi::llama_decode(self.ctx, batch) };
-            unsafe { ffi::llama_batch_free(batch) };
-            if err != 0 {
-                bail!("llama_decode failed with code {}", err);
-            }
-            processed += chunk.len();
-            self.n_past += chunk.len() as i32;
+impl OwnedSampler {
+    /// Chain for `model` that never samples its banned tokens, adds `bias`
+    /// to the logits of the tokens it names, and, with a `grammar`, only
+    /// samples tokens the grammar allows.
+    fn new(
+        params: SamplingParams,
+        model: &SharedModel,
+        bias: &[(String, f32)],
+        grammar: Option<&str>,
+    ) -> Result<Self> {
+        let grammar = grammar
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| anyhow!("grammar contains NUL"))?;
+        let biases = model.logit_biases(bias);
+        let mut chain_params = unsafe { ffi::llama_sampler_chain_default_params() };
+        chain_params.no_perf = true;
+
+        let sampler = unsafe { ffi::llama_sampler_chain_init(chain_params) };
+        if sampler.is_null() {
+            bail!("failed to create sampler chain");
         }
-        Ok(())
-    }
 
-    fn render_token_bytes(&self, token: ffi::llama_token) -> Result<Vec<u8>> {
-        let mut buf = vec![0u8; 64];
-        loop {
-            let res = unsafe {
-                ffi::llama_token_to_piece(
-                    self.shared.vocab,
-                    token,
-                    buf.as_mut_ptr() as *mut c_char,
-                    buf.len() as i32,
-                    0,
-                    false,
-                )
+        if !biases.is_empty() {
+            unsafe {
+                let logit_bias = ffi::llama_sampler_init_logit_bias(
+                    ffi::llama_vocab_n_tokens(model.vocab),
+                    biases.len() as i32,
+                    biases.as_ptr(),
+                );
+                ffi::llama_sampler_chain_add(sampler, logit_bias);
+            }
+        }
+        if let Some(text) = &grammar {
+            let root = CString::new("root").expect("static rule name");
+            let constraint = unsafe {
+                ffi::llama_sampler_init_grammar(model.vocab, text.as_ptr(), root.as_ptr())
             };
-            if res >= 0 {
-                let slice = &buf[..res as usize];
-                return Ok(slice.to_vec());
+            if constraint.is_null() {
+                unsafe { ffi::llama_sampler_free(sampler) };
+                bail!("invalid grammar");
             }
-            let needed = (-res) as usize + 8;
-            buf.resize(needed, 0);
+            unsafe { ffi::llama_sampler_chain_add(sampler, constraint) };
         }
-    }
 
-    fn flush_pending(&self, pending: &mut Vec<u8>, tx: &mpsc::Sender<String>) -> Result<()> {
-        loop {
-            if pending.is_empty() {
-                return Ok(());
-            }
-            match std::str::from_utf8(pending) {
-                Ok(valid) => {
-                    if !valid.is_empty() {
-                        if tx.blocking_send(valid.to_string()).is_err() {
-                            return Ok(());
-                        }
-                    }
-                    pending.clear();
-                    return Ok(());
-                }
-                Err(err) => {
-                    let valid_up_to = err.valid_up_to();
-                    if valid_up_to > 0 {
-                        let chunk =
-                            unsafe { std::str::from_utf8_unchecked(&pending[..valid_up_to]) };
-                        if !chunk.is_empty() && tx.blocking_send(chunk.to_string()).is_err() {
-                            return Ok(());
-                        }
-                        pending.drain(..valid_up_to);
-                        continue;
-                    }
-                    if let Some(error_len) = err.error_len() {
-                        pending.drain(..error_len);
-                        if tx.blocking_send("�".to_string()).is_err() {
-                            return Ok(());
-                        }
-                    } else {
-                        return Ok(());
-                    }
-                }
+        unsafe {
+            if params.top_k > 0 {
+                let topk = ffi::llama_sampler_init_top_k(params.top_k);
+                ffi::llama_sampler_chain_add(sampler, topk);
+            }
+            if params.top_p < 0.9999 {
+                let topp = ffi::llama_sampler_init_top_p(params.top_p, 1);
+                ffi::llama_sampler_chain_add(sampler, topp);
             }
+            if (params.temperature - 1.0).abs() > f32::EPSILON {
+                let temp = ffi::llama_sampler_init_temp(params.temperature);
+                ffi::llama_sampler_chain_add(sampler, temp);
+            }
+            let seed = params.seed.unwrap_or(ffi::LLAMA_DEFAULT_SEED);
+            let dist = ffi::llama_sampler_init_dist(seed);
+            ffi::llama_sampler_chain_add(sampler, dist);
         }
+        Ok(Self(sampler))
     }
-}
 
-impl ContextHandle {
-    fn lock(&self) -> Result<std::sync::MutexGuard<'_, LlamaContext>> {
-        self.ctx
-            .lock()
-            .map_err(|_| anyhow!("failed to lock llama context"))
+    /// Hand the chain over to an owner that frees it itself.
+    fn into_raw(self) -> *mut ffi::llama_sampler {
+        let sampler = self.0;
+        std::mem::forget(self);
+        sampler
     }
 }
 
-impl ContextPool {
-    fn new(contexts: Vec<LlamaContext>) -> Self {
-        let handles: VecDeque<_> = contexts
+impl Drop for OwnedSampler {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::llama_sampler_free(self.0);
+        }
+    }
+}
+
+struct LlamaContext {
+    shared: Arc<SharedModel>,
+    ctx: *mut ffi::llama_context,
+    sampler: *mut ffi::llama_sampler,
+    sampling: SamplingParams,
+    n_past: i32,
+    /// Tokens currently in the KV cache, in position order.
+    cached: Vec<ffi::llama_token>,
+    /// Proposes tokens for speculative decoding.
+    draft: Option<DraftContext>,
+}
+
+/// Speculative decoding settings for [`LlamaCppService::new`]. The draft
+/// model must share the main model's vocabulary (e.g. a small model of the
+/// same family).
+#[derive(Debug, Clone)]
+pub struct DraftConfig {
+    pub model_path: PathBuf,
+    /// Tokens drafted per step.
+    pub tokens: usize,
+    /// Acceptance rate below which a reply stops drafting and decodes one
+    /// token at a time.
+    pub min_acceptance: f32,
+}
+
+/// Tokens drafted before a reply's acceptance rate is judged.
+const DRAFT_PROBE_TOKENS: usize = 32;
+
+/// Context on the draft model, next to the main one it drafts for. Drafts
+/// greedily; the main model's sampler decides what is kept.
+struct DraftContext {
+    model: Arc<SharedModel>,
+    ctx: *mut ffi::llama_context,
+    sampler: *mut ffi::llama_sampler,
+    n_past: i32,
+    cached: Vec<ffi::llama_token>,
+    tokens: usize,
+    min_acceptance: f32,
+}
+
+unsafe impl Send for DraftContext {}
+unsafe impl Sync for DraftContext {}
+
+impl Drop for DraftContext {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.sampler.is_null() {
+                ffi::llama_sampler_free(self.sampler);
+            }
+            if !self.ctx.is_null() {
+                ffi::llama_free(self.ctx);
+            }
+        }
+    }
+}
+
+/// Context with room for `ctx_length` tokens in each of `sequences`
+/// sequences.
+fn new_context(
+    model: &SharedModel,
+    ctx_length: u32,
+    threads: i32,
+    sequences: u32,
+) -> Result<*mut ffi::llama_context> {
+    let mut ctx_params = unsafe { ffi::llama_context_default_params() };
+    ctx_params.n_ctx = ctx_length * sequences;
+    ctx_params.n_seq_max = sequences;
+    ctx_params.n_batch = model.n_batch as u32;
+    ctx_params.n_ubatch = model.n_batch as u32;
+    ctx_params.n_threads = threads;
+    ctx_params.n_threads_batch = threads;
+    ctx_params.offload_kqv = true;
+
+    let ctx = unsafe { ffi::llama_init_from_model(model.model, ctx_params) };
+    if ctx.is_null() {
+        bail!("failed to create llama context");
+    }
+    Ok(ctx)
+}
+
+/// Decode `tokens` at positions `*n_past..` in batches of `n_batch`. Logits
+/// are kept for the last token, or for every token with `all_logits`.
+fn decode_tokens(
+    ctx: *mut ffi::llama_context,
+    n_batch: i32,
+    n_past: &mut i32,
+    tokens: &[ffi::llama_token],
+    all_logits: bool,
+) -> Result<()> {
+    let mut processed = 0usize;
+    while processed < tokens.len() {
+        let take = (tokens.len() - processed).min(n_batch as usize);
+        let chunk = &tokens[processed..processed + take];
+        let mut batch = unsafe { ffi::llama_batch_init(n_batch, 0, 1) };
+        unsafe {
+            let token_slice = std::slice::from_raw_parts_mut(batch.token, chunk.len());
+            token_slice.copy_from_slice(chunk);
+
+            let pos_slice = std::slice::from_raw_parts_mut(batch.pos, chunk.len());
+            for (i, slot) in pos_slice.iter_mut().enumerate() {
+                *slot = (*n_past + i as i32) as ffi::llama_pos;
+            }
+
+            let n_seq_slice = std::slice::from_raw_parts_mut(batch.n_seq_id, chunk.len());
+            let seq_heads = std::slice::from_raw_parts_mut(batch.seq_id, chunk.len());
+            let logits_slice = std::slice::from_raw_parts_mut(batch.logits, chunk.len());
+
+            for i in 0..chunk.len() {
+                n_seq_slice[i] = 1;
+                let seq_slot = std::slice::from_raw_parts_mut(seq_heads[i], 1);
+                seq_slot[0] = 0;
+                logits_slice[i] = if all_logits || i == chunk.len() - 1 {
+                    1
+                } else {
+                    0
+                };
+            }
+        }
+        batch.n_tokens = chunk.len() as i32;
+        let err = unsafe { ffi::llama_decode(ctx, batch) };
+        unsafe { ffi::llama_batch_free(batch) };
+        if err != 0 {
+            bail!("llama_decode failed with code {}", err);
+        }
+        processed += chunk.len();
+        *n_past += chunk.len() as i32;
+    }
+    Ok(())
+}
+
+/// Drop the KV cache entries from position `pos` on. False for memory
+/// types that cannot drop a partial sequence.
+fn truncate_memory(ctx: *mut ffi::llama_context, pos: usize) -> bool {
+    unsafe {
+        let mem = ffi::llama_get_memory(ctx);
+        ffi::llama_memory_seq_rm(mem, 0, pos as ffi::llama_pos, -1)
+    }
+}
+
+fn clear_context_memory(ctx: *mut ffi::llama_context) {
+    unsafe {
+        let mem = ffi::llama_get_memory(ctx);
+        ffi::llama_memory_clear(mem, true);
+    }
+}
+
+impl DraftContext {
+    fn create(
+        model: Arc<SharedModel>,
+        config: &DraftConfig,
+        ctx_length: u32,
+        threads: i32,
+    ) -> Result<Self> {
+        let ctx = new_context(&model, ctx_length, threads, 1)?;
+        let sampler = unsafe { ffi::llama_sampler_init_greedy() };
+        if sampler.is_null() {
+            unsafe {
+                ffi::llama_free(ctx);
+            }
+            bail!("failed to create draft sampler");
+        }
+        Ok(Self {
+            model,
+            ctx,
+            sampler,
+            n_past: 0,
+            cached: Vec::new(),
+            tokens: config.tokens,
+            min_acceptance: config.min_acceptance,
+        })
+    }
+
+    /// Up to `tokens` likely continuations of `sequence`, which must hold
+    /// at least one token. Only the part of `sequence` not already in the
+    /// draft cache is decoded.
+    fn propose(&mut self, sequence: &[ffi::llama_token]) -> Result<Vec<ffi::llama_token>> {
+        let drafted = self.draft_after(sequence);
+        if drafted.is_err() {
+            // The cache content is unknown after a failed decode.
+            self.reset();
+        }
+        drafted
+    }
+
+    fn reset(&mut self) {
+        clear_context_memory(self.ctx);
+        self.cached.clear();
+        self.n_past = 0;
+    }
+
+    fn draft_after(&mut self, sequence: &[ffi::llama_token]) -> Result<Vec<ffi::llama_token>> {
+        let common = self
+            .cached
+            .iter()
+            .zip(sequence)
+            .take_while(|(cached, token)| cached == token)
+            .count()
+            .min(sequence.len().saturating_sub(1));
+        if common < self.cached.len() {
+            if common > 0 && truncate_memory(self.ctx, common) {
+                self.cached.truncate(common);
+                self.n_past = common as i32;
+            } else {
+                self.reset();
+            }
+        }
+        let start = self.cached.len();
+        decode_tokens(
+            self.ctx,
+            self.model.n_batch,
+            &mut self.n_past,
+            &sequence[start..],
+            false,
+        )?;
+        self.cached.extend_from_slice(&sequence[start..]);
+
+        let mut drafted = Vec::with_capacity(self.tokens);
+        unsafe {
+            ffi::llama_sampler_reset(self.sampler);
+        }
+        while drafted.len() < self.tokens {
+            let token = unsafe { ffi::llama_sampler_sample(self.sampler, self.ctx, -1) };
+            if self.model.ends_reply(token) {
+                break;
+            }
+            drafted.push(token);
+            if drafted.len() == self.tokens {
+                break;
+            }
+            decode_tokens(
+                self.ctx,
+                self.model.n_batch,
+                &mut self.n_past,
+                std::slice::from_ref(&token),
+                false,
+            )?;
+            self.cached.push(token);
+        }
+        Ok(drafted)
+    }
+}
+
+unsafe impl Send for LlamaContext {}
+unsafe impl Sync for LlamaContext {}
+
+impl Drop for LlamaContext {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.sampler.is_null() {
+                ffi::llama_sampler_free(self.sampler);
+            }
+            if !self.ctx.is_null() {
+                ffi::llama_free(self.ctx);
+            }
+        }
+    }
+}
+
+struct ContextHandle {
+    ctx: Mutex<LlamaContext>,
+    /// Chat whose prompt prefix sits in this context's KV cache.
+    pin: Mutex<Option<ChatPin>>,
+}
+
+struct ChatPin {
+    chat_id: String,
+    last_used: Instant,
+}
+
+#[derive(Clone)]
+struct ContextPool {
+    inner: Arc<ContextPoolInner>,
+}
+
+struct ContextPoolInner {
+    queue: Mutex<VecDeque<Arc<ContextHandle>>>,
+    available: Condvar,
+    /// How long a chat keeps its context after its last turn; `None`
+    /// disables pinning and KV reuse.
+    pin_ttl: Option<Duration>,
+    /// Builds contexts when the pool grows back.
+    spec: ContextSpec,
+    /// Contexts alive, idle or leased. Changed under the `queue` lock.
+    live: AtomicUsize,
+    /// Contexts the pool should have, lowered under GPU memory pressure.
+    /// Changed under the `queue` lock.
+    target: AtomicUsize,
+    /// Configured pool size.
+    capacity: usize,
+}
+
+/// Parameters every context of a pool is created with.
+struct ContextSpec {
+    shared: Arc<SharedModel>,
+    ctx_length: u32,
+    threads: i32,
+    sampling: SamplingParams,
+    draft: Option<(Arc<SharedModel>, DraftConfig)>,
+}
+
+impl ContextSpec {
+    fn create(&self) -> Result<LlamaContext> {
+        let mut ctx = LlamaContext::create(
+            self.shared.clone(),
+            self.ctx_length,
+            self.threads,
+            self.sampling.temperature,
+            self.sampling.top_p,
+            self.sampling.top_k,
+        )?;
+        if let Some((model, config)) = &self.draft {
+            ctx.draft = Some(DraftContext::create(
+                model.clone(),
+                config,
+                self.ctx_length,
+                self.threads,
+            )?);
+        }
+        Ok(ctx)
+    }
+}
+
+struct ContextLease {
+    pool: Arc<ContextPoolInner>,
+    ctx: Option<Arc<ContextHandle>>,
+}
+
+impl Drop for ContextLease {
+    fn drop(&mut self) {
+        if let Some(ctx) = self.ctx.take() {
+            let mut queue = self.pool.queue.lock().unwrap();
+            // The pool shrank while this context was leased: free it.
+            if self.pool.live.load(Ordering::SeqCst) > self.pool.target.load(Ordering::SeqCst) {
+                self.pool.live.fetch_sub(1, Ordering::SeqCst);
+                drop(queue);
+                drop(ctx);
+                return;
+            }
+            queue.push_back(ctx);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+impl LlamaCppService {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        model_path: impl AsRef<Path>,
+        ctx_length: u32,
+        max_tokens: usize,
+        temperature: f32,
+        top_p: f32,
+        top_k: i32,
+        gpu_layers: Option<i32>,
+        threads: Option<i32>,
+        pool_size: usize,
+        prefix_pin_ttl: Option<Duration>,
+        draft: Option<DraftConfig>,
+        batch_slots: usize,
+        banned: &[String],
+    ) -> Result<Self> {
+        println!("⚡️ llama.cpp params: ctx={ctx_length} max_tokens={max_tokens} temp={temperature} top_p={top_p} top_k={top_k} gpu_layers={:?} threads={:?}", gpu_layers, threads);
+        if pool_size == 0 {
+            bail!("context pool size must be at least 1");
+        }
+        let mut model = SharedModel::load(model_path.as_ref(), gpu_layers, max_tokens)?;
+        let mut banned_tokens = Vec::new();
+        for target in banned {
+            let tokens = model.bias_tokens(target);
+            if tokens.is_empty() {
+                println!("⚠️  banned token {target:?} matches no token; ignored");
+            }
+            banned_tokens.extend(tokens);
+        }
+        if !banned_tokens.is_empty() {
+            println!("ℹ️  {} banned tokens", banned_tokens.len());
+        }
+        model.banned = banned_tokens;
+        let shared = Arc::new(model);
+
+        let batched = batch_slots > 1;
+        if batched && draft.is_some() {
+            println!("⚠️  speculative decoding is not available with batched replies; draft model ignored");
+        }
+        // A draft model that cannot be used only costs the speedup.
+        let draft = draft.filter(|_| !batched).and_then(|config| {
+            match SharedModel::load(&config.model_path, gpu_layers, max_tokens) {
+                Ok(model) if model.same_vocab(&shared) => {
+                    println!(
+                        "ℹ️  speculative decoding with draft model {} ({} tokens per step)",
+                        config.model_path.display(),
+                        config.tokens
+                    );
+                    Some((Arc::new(model), config))
+                }
+                Ok(_) => {
+                    println!(
+                        "⚠️  draft model {} has a different vocabulary; speculative decoding disabled",
+                        config.model_path.display()
+                    );
+                    None
+                }
+                Err(err) => {
+                    println!("⚠️  draft model unavailable, speculative decoding disabled: {err}");
+                    None
+                }
+            }
+        });
+
+        let spec = ContextSpec {
+            shared: shared.clone(),
+            ctx_length,
+            threads: threads.unwrap_or_else(|| num_cpus::get_physical() as i32),
+            sampling: SamplingParams {
+                temperature,
+                top_p,
+                top_k,
+                seed: None,
+            },
+            draft,
+        };
+        let batcher = if batched {
+            let batcher = Batcher::start(&spec, batch_slots)?;
+            println!(
+                "ℹ️  continuous batching: {} replies share each forward pass",
+                batcher.slots
+            );
+            Some(batcher)
+        } else {
+            None
+        };
+        let mut contexts = Vec::with_capacity(pool_size);
+        if batcher.is_none() {
+            for _ in 0..pool_size {
+                contexts.push(spec.create()?);
+            }
+        }
+
+        Ok(Self {
+            shared,
+            ctx_length: ctx_length as usize,
+            pool: ContextPool::new(contexts, prefix_pin_ttl, spec),
+            batcher,
+        })
+    }
+
+    /// Contexts the pool currently aims for, and its configured size.
+    pub fn pool_size(&self) -> (usize, usize) {
+        (
+            self.pool.inner.target.load(Ordering::SeqCst),
+            self.pool.inner.capacity,
+        )
+    }
+
+    /// Give up one context to free its KV cache: an idle one right away,
+    /// otherwise the next one to finish. Keeps at least one; false when
+    /// the pool is already down to it.
+    pub fn shrink_pool(&self) -> bool {
+        self.pool.shrink()
+    }
+
+    /// Re-create one context given up by [`shrink_pool`](Self::shrink_pool).
+    /// Blocks while the context is allocated; false when the pool is at
+    /// its configured size.
+    pub fn grow_pool(&self) -> Result<bool> {
+        self.pool.grow()
+    }
+
+    fn spawn_generation(
+        &self,
+        prompt: String,
+        chat_id: Option<String>,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel(128);
+        if let Some(batcher) = &self.batcher {
+            // No chat pinning: sequences are cleared when a reply ends.
+            batcher.submit(BatchJob {
+                prompt,
+                params,
+                cancel,
+                tx,
+            });
+            return rx;
+        }
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let lease = pool.checkout(chat_id.as_deref());
+            let outcome = params.outcome.clone();
+            if let Err(err) = lease.run(&prompt, chat_id, params, cancel, tx.clone()) {
+                outcome.set(FinishReason::Error);
+                let _ = tx.blocking_send(format!("{ENGINE_ERROR_PREFIX} {err}"));
+            }
+        });
+        rx
+    }
+}
+
+impl GenerationBackend for LlamaCppService {
+    fn generate_stream_with(
+        &self,
+        prompt: String,
+        chat_id: Option<String>,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<String> {
+        self.spawn_generation(prompt, chat_id, params, cancel)
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(self.shared.tokenize(text)?.len())
+    }
+
+    fn context_length(&self) -> usize {
+        self.ctx_length
+    }
+}
+
+impl LlamaContext {
+    fn create(
+        shared: Arc<SharedModel>,
+        ctx_length: u32,
+        threads: i32,
+        temperature: f32,
+        top_p: f32,
+        top_k: i32,
+    ) -> Result<Self> {
+        let ctx = new_context(&shared, ctx_length, threads, 1)?;
+
+        let sampling = SamplingParams {
+            temperature,
+            top_p,
+            top_k,
+            seed: None,
+        };
+        let sampler = match OwnedSampler::new(sampling, &shared, &[], None) {
+            Ok(sampler) => sampler.into_raw(),
+            Err(err) => {
+                unsafe {
+                    ffi::llama_free(ctx);
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            shared,
+            ctx,
+            sampler,
+            sampling,
+            n_past: 0,
+            cached: Vec::new(),
+            draft: None,
+        })
+    }
+
+    fn clear_memory(&mut self) {
+        clear_context_memory(self.ctx);
+        self.n_past = 0;
+        self.cached.clear();
+    }
+
+    /// Keep the cached tokens shared with `tokens` and drop the rest.
+    /// Returns how many prompt tokens are already in the cache.
+    fn reuse_prefix(&mut self, tokens: &[ffi::llama_token]) -> usize {
+        let common = self
+            .cached
+            .iter()
+            .zip(tokens)
+            .take_while(|(cached, token)| cached == token)
+            .count()
+            // The last prompt token is decoded again to get fresh logits.
+            .min(tokens.len().saturating_sub(1));
+        if common == 0 {
+            self.clear_memory();
+            return 0;
+        }
+        if !truncate_memory(self.ctx, common) {
+            // Memory types that cannot drop a partial sequence.
+            self.clear_memory();
+            return 0;
+        }
+        self.cached.truncate(common);
+        self.n_past = common as i32;
+        common
+    }
+
+    fn run(
+        &mut self,
+        prompt: &str,
+        reuse_cache: bool,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+        tx: mpsc::Sender<String>,
+    ) -> Result<()> {
+        // Cancelled or abandoned while waiting for a context.
+        if cancel.load(Ordering::SeqCst) || tx.is_closed() {
+            params.outcome.set(FinishReason::Cancelled);
+            return Ok(());
+        }
+        let mut out = TokenStream::new(tx, &params, self.shared.max_tokens);
+        // A different temperature, a seed, logit bias or a grammar gets its
+        // own chain for this run.
+        let sampling = SamplingParams {
+            temperature: params.temperature.unwrap_or(self.sampling.temperature),
+            seed: params.seed,
+            ..self.sampling
+        };
+        let custom = if params.grammar.is_some()
+            || params.seed.is_some()
+            || !params.logit_bias.is_empty()
+            || (sampling.temperature - self.sampling.temperature).abs() > f32::EPSILON
+        {
+            Some(OwnedSampler::new(
+                sampling,
+                &self.shared,
+                &params.logit_bias,
+                params.grammar.as_deref(),
+            )?)
+        } else {
+            None
+        };
+        let sampler = custom.as_ref().map_or(self.sampler, |s| s.0);
+        unsafe {
+            ffi::llama_sampler_reset(sampler);
+        }
+
+        let prompt_tokens = self.tokenize(prompt)?;
+        let reused = if reuse_cache {
+            self.reuse_prefix(&prompt_tokens)
+        } else {
+            self.clear_memory();
+            0
+        };
+        if reused > 0 {
+            tracing::debug!(
+                reused,
+                prompt_tokens = prompt_tokens.len(),
+                "reusing cached prompt prefix"
+            );
+        }
+
+        // Until decoding succeeds the cache content is unknown; an empty
+        // `cached` makes the next run start from a cleared memory.
+        self.cached.clear();
+        self.decode_sequence(&prompt_tokens[reused..])?;
+        self.cached = prompt_tokens;
+
+        // `next` is sampled but not yet decoded. Each step decodes it
+        // together with the draft's guesses for the tokens after it; a
+        // guess is kept while the sampler, run on the logits before it,
+        // picks the same token, so replies read as without a draft.
+        let mut drafting = self.draft.is_some();
+        let (mut drafted, mut accepted) = (0usize, 0usize);
+        let mut next = unsafe { ffi::llama_sampler_sample(sampler, self.ctx, -1) };
+        'decode: loop {
+            if cancel.load(Ordering::SeqCst) || out.is_closed() {
+                out.end(FinishReason::Cancelled);
+                break;
+            }
+            if self.shared.ends_reply(next) {
+                out.end(FinishReason::Eos);
+                break;
+            }
+            if !self.emit(sampler, next, &mut out)? {
+                break;
+            }
+
+            let guesses = if drafting {
+                match self.draft_guesses(next, out.remaining()) {
+                    Ok(guesses) => guesses,
+                    Err(err) => {
+                        tracing::warn!("draft model failed, decoding without it: {err}");
+                        drafting = false;
+                        Vec::new()
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+            let base = self.cached.len();
+            self.cached.push(next);
+            self.cached.extend_from_slice(&guesses);
+            let mut n_past = self.n_past;
+            let step = decode_tokens(
+                self.ctx,
+                self.shared.n_batch,
+                &mut n_past,
+                &self.cached[base..],
+                true,
+            );
+            self.n_past = n_past;
+            if let Err(err) = step {
+                self.cached.clear();
+                return Err(err);
+            }
+
+            let mut kept = 0;
+            let mut following = None;
+            for (i, &guess) in guesses.iter().enumerate() {
+                let token = unsafe { ffi::llama_sampler_sample(sampler, self.ctx, i as i32) };
+                if token != guess || token == self.shared.eos_token {
+                    following = Some(token);
+                    break;
+                }
+                if cancel.load(Ordering::SeqCst) || out.is_closed() {
+                    out.end(FinishReason::Cancelled);
+                    break 'decode;
+                }
+                if !self.emit(sampler, token, &mut out)? {
+                    break 'decode;
+                }
+                kept += 1;
+            }
+            next = following.unwrap_or_else(|| unsafe {
+                ffi::llama_sampler_sample(sampler, self.ctx, guesses.len() as i32)
+            });
+
+            if kept < guesses.len() {
+                let keep = base + 1 + kept;
+                if !truncate_memory(self.ctx, keep) {
+                    self.cached.clear();
+                    bail!("failed to drop rejected draft tokens");
+                }
+                self.cached.truncate(keep);
+                self.n_past = keep as i32;
+            }
+            drafted += guesses.len();
+            accepted += kept;
+            if drafting
+                && drafted >= DRAFT_PROBE_TOKENS
+                && (accepted as f32) < drafted as f32 * self.draft_min_acceptance()
+            {
+                tracing::debug!(
+                    drafted,
+                    accepted,
+                    "draft acceptance too low, decoding without it"
+                );
+                drafting = false;
+            }
+        }
+        if drafted > 0 {
+            tracing::debug!(
+                drafted,
+                accepted,
+                produced = out.produced(),
+                "speculative decoding"
+            );
+        }
+
+        out.finish();
+        Ok(())
+    }
+
+    /// Accept `token` into the sampler and stream its text. False once the
+    /// reply is over (see [`TokenStream::push`]).
+    fn emit(
+        &self,
+        sampler: *mut ffi::llama_sampler,
+        token: ffi::llama_token,
+        out: &mut TokenStream,
+    ) -> Result<bool> {
+        unsafe {
+            ffi::llama_sampler_accept(sampler, token);
+        }
+        Ok(out.push(&self.shared.token_bytes(token)?))
+    }
+
+    /// Draft tokens following the cache and `next`, at most `room` so the
+    /// reply stays within its token cap, and few enough to fit one batch.
+    fn draft_guesses(
+        &mut self,
+        next: ffi::llama_token,
+        room: usize,
+    ) -> Result<Vec<ffi::llama_token>> {
+        let Some(draft) = self.draft.as_mut() else {
+            return Ok(Vec::new());
+        };
+        let mut sequence = self.cached.clone();
+        sequence.push(next);
+        let mut guesses = draft.propose(&sequence)?;
+        guesses.truncate(room.min(self.shared.n_batch as usize - 1));
+        Ok(guesses)
+    }
+
+    fn draft_min_acceptance(&self) -> f32 {
+        self.draft
+            .as_ref()
+            .map_or(0.0, |draft| draft.min_acceptance)
+    }
+
+    fn tokenize(&self, text: &str) -> Result<Vec<ffi::llama_token>> {
+        self.shared.tokenize(text)
+    }
+
+    fn decode_sequence(&mut self, tokens: &[ffi::llama_token]) -> Result<()> {
+        decode_tokens(
+            self.ctx,
+            self.shared.n_batch,
+            &mut self.n_past,
+            tokens,
+            false,
+        )
+    }
+}
+
+impl ContextHandle {
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, LlamaContext>> {
+        self.ctx
+            .lock()
+            .map_err(|_| anyhow!("failed to lock llama context"))
+    }
+}
+
+impl ContextPool {
+    fn new(contexts: Vec<LlamaContext>, pin_ttl: Option<Duration>, spec: ContextSpec) -> Self {
+        let size = contexts.len();
+        let handles: VecDeque<_> = contexts
             .into_iter()
             .map(|ctx| {
                 Arc::new(ContextHandle {
                     ctx: Mutex::new(ctx),
+                    pin: Mutex::new(None),
                 })
             })
             .collect();
@@ -465,31 +1225,428 @@ impl ContextPool {
             inner: Arc::new(ContextPoolInner {
                 queue: Mutex::new(handles),
                 available: Condvar::new(),
+                pin_ttl,
+                spec,
+                live: AtomicUsize::new(size),
+                target: AtomicUsize::new(size),
+                capacity: size,
             }),
         }
     }
 
-    fn checkout(&self) -> ContextLease {
+    fn shrink(&self) -> bool {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let target = self.inner.target.load(Ordering::SeqCst);
+        if target <= 1 {
+            return false;
+        }
+        self.inner.target.store(target - 1, Ordering::SeqCst);
+        let idle = if self.inner.live.load(Ordering::SeqCst) > target - 1 {
+            self.pick(&queue, None).and_then(|idx| queue.remove(idx))
+        } else {
+            None
+        };
+        if idle.is_some() {
+            self.inner.live.fetch_sub(1, Ordering::SeqCst);
+        }
+        drop(queue);
+        drop(idle);
+        true
+    }
+
+    fn grow(&self) -> Result<bool> {
+        {
+            let _queue = self.inner.queue.lock().unwrap();
+            let target = self.inner.target.load(Ordering::SeqCst);
+            if target >= self.inner.capacity {
+                return Ok(false);
+            }
+            self.inner.target.store(target + 1, Ordering::SeqCst);
+            // A leased context not yet freed counts toward the new target.
+            if self.inner.live.load(Ordering::SeqCst) > target {
+                return Ok(true);
+            }
+        }
+
+        let ctx = match self.inner.spec.create() {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                let _queue = self.inner.queue.lock().unwrap();
+                self.inner.target.fetch_sub(1, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        let mut queue = self.inner.queue.lock().unwrap();
+        queue.push_back(Arc::new(ContextHandle {
+            ctx: Mutex::new(ctx),
+            pin: Mutex::new(None),
+        }));
+        self.inner.live.fetch_add(1, Ordering::SeqCst);
+        self.inner.available.notify_one();
+        Ok(true)
+    }
+
+    fn checkout(&self, chat_id: Option<&str>) -> ContextLease {
         let mut queue = self.inner.queue.lock().unwrap();
         loop {
-            if let Some(ctx) = queue.pop_front() {
+            if let Some(idx) = self.pick(&queue, chat_id) {
+                let ctx = queue.remove(idx);
                 return ContextLease {
                     pool: Arc::clone(&self.inner),
-                    ctx: Some(ctx),
+                    ctx,
                 };
             }
             queue = self.inner.available.wait(queue).unwrap();
         }
     }
+
+    /// The chat's own context if idle, else one nobody holds a live pin
+    /// on, else the least recently used one.
+    fn pick(&self, queue: &VecDeque<Arc<ContextHandle>>, chat_id: Option<&str>) -> Option<usize> {
+        if queue.is_empty() {
+            return None;
+        }
+        let Some(ttl) = self.inner.pin_ttl else {
+            return Some(0);
+        };
+        let now = Instant::now();
+        let pins: Vec<Option<(bool, Instant)>> = queue
+            .iter()
+            .map(|handle| {
+                let pin = handle.pin.lock().unwrap();
+                pin.as_ref()
+                    .map(|pin| (chat_id == Some(pin.chat_id.as_str()), pin.last_used))
+            })
+            .collect();
+
+        if let Some(idx) = pins.iter().position(|pin| matches!(pin, Some((true, _)))) {
+            return Some(idx);
+        }
+        if let Some(idx) = pins
+            .iter()
+            .position(|pin| pin.map_or(true, |(_, used)| now.duration_since(used) > ttl))
+        {
+            return Some(idx);
+        }
+        pins.iter()
+            .enumerate()
+            .min_by_key(|(_, pin)| pin.map(|(_, used)| used))
+            .map(|(idx, _)| idx)
+    }
 }
 
 impl ContextLease {
-    fn run(&self, prompt: &str, cancel: Arc<AtomicBool>, tx: mpsc::Sender<String>) -> Result<()> {
+    fn run(
+        &self,
+        prompt: &str,
+        chat_id: Option<String>,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+        tx: mpsc::Sender<String>,
+    ) -> Result<()> {
         let ctx = self
             .ctx
             .as_ref()
             .expect("context should not be None in active lease");
-        let mut guard = ctx.lock()?;
-        guard.run(prompt, cancel, tx)
+        let pinning = self.pool.pin_ttl.is_some();
+        let result = {
+            let mut guard = ctx.lock()?;
+            guard.run(prompt, pinning, params, cancel, tx)
+        };
+        let mut pin = ctx.pin.lock().unwrap();
+        *pin = match chat_id {
+            Some(chat_id) if pinning && result.is_ok() => Some(ChatPin {
+                chat_id,
+                last_used: Instant::now(),
+            }),
+            _ => None,
+        };
+        result
+    }
+}
+
+/// Reply waiting for, or running in, a batch slot.
+struct BatchJob {
+    prompt: String,
+    params: GenerationParams,
+    cancel: Arc<AtomicBool>,
+    tx: mpsc::Sender<String>,
+}
+
+/// Handle to the thread that runs every reply of a batched engine.
+struct Batcher {
+    jobs: std::sync::mpsc::Sender<BatchJob>,
+    slots: usize,
+}
+
+impl Batcher {
+    fn start(spec: &ContextSpec, slots: usize) -> Result<Self> {
+        // Every generating slot adds one token to each batch.
+        let slots = slots.min(spec.shared.n_batch as usize);
+        let ctx = new_context(&spec.shared, spec.ctx_length, spec.threads, slots as u32)?;
+        let mut scheduler = BatchScheduler {
+            shared: spec.shared.clone(),
+            ctx,
+            sampling: spec.sampling,
+            ctx_length: spec.ctx_length as usize,
+            slots: (0..slots).map(|_| None).collect(),
+        };
+        let (jobs, rx) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("llama-batch".into())
+            .spawn(move || scheduler.run(rx))?;
+        Ok(Self { jobs, slots })
+    }
+
+    fn submit(&self, job: BatchJob) {
+        if let Err(std::sync::mpsc::SendError(job)) = self.jobs.send(job) {
+            job.params.outcome.set(FinishReason::Error);
+            let _ = job
+                .tx
+                .try_send(format!("{ENGINE_ERROR_PREFIX} batch scheduler stopped"));
+        }
+    }
+}
+
+struct BatchSlot {
+    cancel: Arc<AtomicBool>,
+    out: TokenStream,
+    sampler: OwnedSampler,
+    prompt: Vec<ffi::llama_token>,
+    /// Prompt tokens decoded so far.
+    prefilled: usize,
+    /// Sampled and streamed, not yet decoded.
+    next: Option<ffi::llama_token>,
+    n_past: i32,
+    /// Batch index holding this slot's logits after the current decode.
+    logits_at: Option<i32>,
+}
+
+/// One context with a sequence per slot. Each step decodes a single batch
+/// with the next token of every generating slot plus as much pending
+/// prompt as fits, so concurrent replies share forward passes.
+struct BatchScheduler {
+    shared: Arc<SharedModel>,
+    ctx: *mut ffi::llama_context,
+    sampling: SamplingParams,
+    ctx_length: usize,
+    slots: Vec<Option<BatchSlot>>,
+}
+
+unsafe impl Send for BatchScheduler {}
+
+impl Drop for BatchScheduler {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::llama_free(self.ctx);
+        }
+    }
+}
+
+impl BatchScheduler {
+    fn run(&mut self, jobs: std::sync::mpsc::Receiver<BatchJob>) {
+        let mut open = true;
+        loop {
+            let idle = self.slots.iter().all(Option::is_none);
+            if idle {
+                if !open {
+                    return;
+                }
+                // Nothing running: wait for work.
+                match jobs.recv() {
+                    Ok(job) => self.admit(job),
+                    Err(_) => return,
+                }
+            }
+            while open && self.slots.iter().any(Option::is_none) {
+                match jobs.try_recv() {
+                    Ok(job) => self.admit(job),
+                    Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                    Err(std::sync::mpsc::TryRecvError::Disconnected) => open = false,
+                }
+            }
+            self.step();
+        }
+    }
+
+    fn admit(&mut self, job: BatchJob) {
+        if job.cancel.load(Ordering::SeqCst) || job.tx.is_closed() {
+            job.params.outcome.set(FinishReason::Cancelled);
+            return;
+        }
+        let Some(seq) = self.slots.iter().position(Option::is_none) else {
+            return;
+        };
+        let prepared = self.shared.tokenize(&job.prompt).and_then(|prompt| {
+            if prompt.is_empty() || prompt.len() >= self.ctx_length {
+                bail!(
+                    "prompt of {} tokens does not fit the {} token context",
+                    prompt.len(),
+                    self.ctx_length
+                );
+            }
+            let sampling = SamplingParams {
+                temperature: job.params.temperature.unwrap_or(self.sampling.temperature),
+                seed: job.params.seed,
+                ..self.sampling
+            };
+            let sampler = OwnedSampler::new(
+                sampling,
+                &self.shared,
+                &job.params.logit_bias,
+                job.params.grammar.as_deref(),
+            )?;
+            Ok((prompt, sampler))
+        });
+        match prepared {
+            Ok((prompt, sampler)) => {
+                self.slots[seq] = Some(BatchSlot {
+                    cancel: job.cancel,
+                    out: TokenStream::new(job.tx, &job.params, self.shared.max_tokens),
+                    sampler,
+                    prompt,
+                    prefilled: 0,
+                    next: None,
+                    n_past: 0,
+                    logits_at: None,
+                });
+            }
+            Err(err) => {
+                job.params.outcome.set(FinishReason::Error);
+                let _ = job.tx.blocking_send(format!("{ENGINE_ERROR_PREFIX} {err}"));
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        for seq in 0..self.slots.len() {
+            let abandoned = self.slots[seq]
+                .as_ref()
+                .filter(|slot| slot.cancel.load(Ordering::SeqCst) || slot.out.is_closed());
+            if let Some(slot) = abandoned {
+                slot.out.end(FinishReason::Cancelled);
+                self.finish(seq, None);
+            }
+        }
+
+        let n_batch = self.shared.n_batch as usize;
+        let mut entries: Vec<(ffi::llama_token, i32, i32, bool)> = Vec::with_capacity(n_batch);
+        // Generating slots first so running replies never wait on a prefill.
+        for (seq, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else { continue };
+            slot.logits_at = None;
+            if let Some(token) = slot.next.take() {
+                slot.logits_at = Some(entries.len() as i32);
+                entries.push((token, slot.n_past, seq as i32, true));
+                slot.n_past += 1;
+            }
+        }
+        for (seq, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else { continue };
+            if slot.prefilled == slot.prompt.len() {
+                continue;
+            }
+            let take = (slot.prompt.len() - slot.prefilled).min(n_batch - entries.len());
+            for &token in &slot.prompt[slot.prefilled..slot.prefilled + take] {
+                slot.prefilled += 1;
+                let last = slot.prefilled == slot.prompt.len();
+                if last {
+                    slot.logits_at = Some(entries.len() as i32);
+                }
+                entries.push((token, slot.n_past, seq as i32, last));
+                slot.n_past += 1;
+            }
+            if entries.len() == n_batch {
+                break;
+            }
+        }
+        if entries.is_empty() {
+            return;
+        }
+
+        if let Err(err) = self.decode(&entries) {
+            for seq in 0..self.slots.len() {
+                self.finish(seq, Some(&err));
+            }
+            return;
+        }
+
+        for seq in 0..self.slots.len() {
+            let Some(index) = self.slots[seq].as_ref().and_then(|slot| slot.logits_at) else {
+                continue;
+            };
+            let Some(mut slot) = self.slots[seq].take() else {
+                continue;
+            };
+            let outcome = self.sample(&mut slot, index);
+            self.slots[seq] = Some(slot);
+            match outcome {
+                Ok(true) => {}
+                Ok(false) => self.finish(seq, None),
+                Err(err) => self.finish(seq, Some(&err)),
+            }
+        }
+    }
+
+    /// Sample and stream the slot's next token. False once the reply is
+    /// complete.
+    fn sample(&self, slot: &mut BatchSlot, index: i32) -> Result<bool> {
+        let token = unsafe { ffi::llama_sampler_sample(slot.sampler.0, self.ctx, index) };
+        if self.shared.ends_reply(token) {
+            slot.out.end(FinishReason::Eos);
+            return Ok(false);
+        }
+        unsafe {
+            ffi::llama_sampler_accept(slot.sampler.0, token);
+        }
+        if !slot.out.push(&self.shared.token_bytes(token)?) {
+            return Ok(false);
+        }
+        if slot.n_past as usize >= self.ctx_length {
+            slot.out.end(FinishReason::MaxTokens);
+            return Ok(false);
+        }
+        slot.next = Some(token);
+        Ok(true)
+    }
+
+    fn decode(&self, entries: &[(ffi::llama_token, i32, i32, bool)]) -> Result<()> {
+        let mut batch = unsafe { ffi::llama_batch_init(self.shared.n_batch, 0, 1) };
+        unsafe {
+            let tokens = std::slice::from_raw_parts_mut(batch.token, entries.len());
+            let positions = std::slice::from_raw_parts_mut(batch.pos, entries.len());
+            let n_seq = std::slice::from_raw_parts_mut(batch.n_seq_id, entries.len());
+            let seq_heads = std::slice::from_raw_parts_mut(batch.seq_id, entries.len());
+            let logits = std::slice::from_raw_parts_mut(batch.logits, entries.len());
+            for (i, &(token, pos, seq, wants_logits)) in entries.iter().enumerate() {
+                tokens[i] = token;
+                positions[i] = pos as ffi::llama_pos;
+                n_seq[i] = 1;
+                std::slice::from_raw_parts_mut(seq_heads[i], 1)[0] = seq;
+                logits[i] = wants_logits as i8;
+            }
+        }
+        batch.n_tokens = entries.len() as i32;
+        let err = unsafe { ffi::llama_decode(self.ctx, batch) };
+        unsafe { ffi::llama_batch_free(batch) };
+        if err != 0 {
+            bail!("llama_decode failed with code {}", err);
+        }
+        Ok(())
+    }
+
+    /// Free slot `seq` and its sequence, streaming what is left or `error`.
+    fn finish(&mut self, seq: usize, error: Option<&anyhow::Error>) {
+        let Some(mut slot) = self.slots[seq].take() else {
+            return;
+        };
+        unsafe {
+            let mem = ffi::llama_get_memory(self.ctx);
+            ffi::llama_memory_seq_rm(mem, seq as i32, -1, -1);
+        }
+        slot.out.finish();
+        if let Some(err) = error {
+            slot.out.fail(err);
+        }
     }
 }
@@ -1,18 +1,193 @@
+pub mod backend;
 pub mod byte_decoder;
+pub mod diffusion;
+pub mod gpu_watchdog;
 pub mod intent_router;
+pub mod json_schema;
 pub mod llama_cpp_service;
+pub mod reasoning;
+pub mod remote;
+pub mod repetition;
+pub mod response_cache;
+pub mod stop;
+pub mod timeouts;
+pub mod vision;
+pub mod whisper;
 
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use llama_cpp_service::LlamaCppService;
+use backend::{FinishReason, GenerationBackend, ReplyOutcome};
+use llama_cpp_service::{GenerationParams, ENGINE_ERROR_PREFIX};
+use remote::RemoteInference;
+use response_cache::ResponseCache;
+use tokio::sync::mpsc;
+
+const DEFAULT_FIRST_TOKEN_TIMEOUT_SECS: u64 = 90;
 
 pub struct InferenceService {
-    engine: Arc<LlamaCppService>,
+    backend: Backend,
+    first_token_timeout: Duration,
+    responses: ResponseCache,
+}
+
+enum Backend {
+    Local {
+        engine: Arc<dyn GenerationBackend>,
+        fallback: Option<Arc<dyn GenerationBackend>>,
+    },
+    /// The models run in a separate inference service.
+    Remote(RemoteInference),
+}
+
+/// Chat reply stream; `fallback` is set once the reply is being produced
+/// by the fallback model.
+pub struct ReplyStream {
+    pub rx: mpsc::Receiver<String>,
+    pub fallback: Arc<AtomicBool>,
 }
 
 impl InferenceService {
-    pub fn new(engine: Arc<LlamaCppService>) -> Self {
-        Self { engine }
+    pub fn new(engine: Arc<dyn GenerationBackend>) -> Self {
+        Self {
+            backend: Backend::Local {
+                engine,
+                fallback: None,
+            },
+            first_token_timeout: Duration::from_secs(DEFAULT_FIRST_TOKEN_TIMEOUT_SECS),
+            responses: ResponseCache::from_env(),
+        }
+    }
+
+    /// Generate through the inference service at `remote`; the fallback
+    /// model, if any, is that service's.
+    pub fn remote(remote: RemoteInference) -> Self {
+        Self {
+            backend: Backend::Remote(remote),
+            first_token_timeout: Duration::from_secs(DEFAULT_FIRST_TOKEN_TIMEOUT_SECS),
+            responses: ResponseCache::from_env(),
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        matches!(self.backend, Backend::Remote(_))
+    }
+
+    /// Exact-match cache of finished replies (`RESPONSE_CACHE_TTL_SECS`,
+    /// off by default).
+    pub fn response_cache(&self) -> &ResponseCache {
+        &self.responses
+    }
+
+    /// Retry chat replies on `fallback` when the primary model fails or
+    /// produces nothing within `LLAMA_FIRST_TOKEN_TIMEOUT_SECS` (default 90).
+    pub fn with_fallback(mut self, fallback: Option<Arc<dyn GenerationBackend>>) -> Self {
+        if let Backend::Local {
+            fallback: ref mut slot,
+            ..
+        } = self.backend
+        {
+            *slot = fallback;
+        }
+        if let Some(secs) = std::env::var("LLAMA_FIRST_TOKEN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|v| *v > 0)
+        {
+            self.first_token_timeout = Duration::from_secs(secs);
+        }
+        self
+    }
+
+    /// Stream a reply from the primary model, switching to the fallback
+    /// model when the primary errors or times out before its first token.
+    /// Once the primary has produced output the reply stays on it.
+    /// `params` (temperature, token cap, stop strings) apply to this reply
+    /// only, on either model.
+    pub fn generate_reply(
+        &self,
+        prompt: String,
+        chat_id: Option<String>,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+    ) -> ReplyStream {
+        let (engine, fallback) = match &self.backend {
+            Backend::Local { engine, fallback } => (engine, fallback),
+            Backend::Remote(remote) => return remote.reply(prompt, chat_id, params, cancel),
+        };
+        let fallback_used = Arc::new(AtomicBool::new(false));
+        let Some(fallback) = fallback.clone() else {
+            return ReplyStream {
+                rx: engine.generate_stream_with(prompt, chat_id, params, cancel),
+                fallback: fallback_used,
+            };
+        };
+        // The primary reports into its own outcome; only the attempt that
+        // produces the reply reports into the caller's.
+        let primary_outcome = ReplyOutcome::default();
+        let mut primary = engine.generate_stream_with(
+            prompt.clone(),
+            chat_id,
+            GenerationParams {
+                outcome: primary_outcome.clone(),
+                ..params.clone()
+            },
+            cancel.clone(),
+        );
+
+        let (tx, rx) = mpsc::channel(64);
+        let timeout = self.first_token_timeout;
+        let flag = fallback_used.clone();
+        tokio::spawn(async move {
+            let reason = match tokio::time::timeout(timeout, primary.recv()).await {
+                Ok(Some(chunk)) if !chunk.starts_with(ENGINE_ERROR_PREFIX) => {
+                    if tx.send(chunk).await.is_err() {
+                        return;
+                    }
+                    while let Some(chunk) = primary.recv().await {
+                        if tx.send(chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                    if let Some(reason) = primary_outcome.reason() {
+                        params.outcome.set(reason);
+                    }
+                    return;
+                }
+                Ok(Some(error)) => error,
+                Ok(None) => {
+                    if let Some(reason) = primary_outcome.reason() {
+                        params.outcome.set(reason);
+                    }
+                    return;
+                }
+                Err(_) => format!("no output within {timeout:?}"),
+            };
+            // Closing the receiver makes the primary stop generating.
+            drop(primary);
+            if cancel.load(Ordering::SeqCst) {
+                params.outcome.set(FinishReason::Cancelled);
+                return;
+            }
+            tracing::warn!("primary model failed ({reason}), retrying on fallback model");
+            flag.store(true, Ordering::SeqCst);
+            let mut retry = fallback.generate_stream_with(prompt, None, params, cancel);
+            while let Some(chunk) = retry.recv().await {
+                if tx.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        ReplyStream {
+            rx,
+            fallback: fallback_used,
+        }
     }
 
     pub fn generate_stream(
@@ -20,7 +195,40 @@ impl InferenceService {
         prompt: String,
         cancel: Arc<std::sync::atomic::AtomicBool>,
     ) -> tokio::sync::mpsc::Receiver<String> {
-        self.engine.generate_stream(prompt, cancel)
+        match &self.backend {
+            Backend::Local { engine, .. } => engine.generate_stream(prompt, cancel),
+            Backend::Remote(remote) => remote.stream(prompt, None, cancel),
+        }
+    }
+
+    pub fn generate_stream_for_chat(
+        &self,
+        prompt: String,
+        chat_id: String,
+        cancel: Arc<std::sync::atomic::AtomicBool>,
+    ) -> tokio::sync::mpsc::Receiver<String> {
+        match &self.backend {
+            Backend::Local { engine, .. } => {
+                engine.generate_stream_for_chat(prompt, chat_id, cancel)
+            }
+            Backend::Remote(remote) => remote.stream(prompt, Some(chat_id), cancel),
+        }
+    }
+
+    pub async fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        match &self.backend {
+            Backend::Local { engine, .. } => engine.count_tokens(text),
+            Backend::Remote(remote) => remote.count_tokens(text).await,
+        }
+    }
+
+    /// Tokens a reply's prompt and output share; 0 when not known yet
+    /// (a remote service reports it with its first token count).
+    pub fn context_length(&self) -> usize {
+        match &self.backend {
+            Backend::Local { engine, .. } => engine.context_length(),
+            Backend::Remote(remote) => remote.context_length(),
+        }
     }
 
     pub async fn generate_completion(
@@ -28,6 +236,21 @@ impl InferenceService {
         prompt: String,
         cancel: Arc<std::sync::atomic::AtomicBool>,
     ) -> anyhow::Result<String> {
-        self.engine.generate_completion(prompt, cancel).await
+        match &self.backend {
+            Backend::Local { engine, .. } => engine.generate_completion(prompt, cancel).await,
+            Backend::Remote(remote) => remote.completion(prompt, cancel).await,
+        }
+    }
+
+    pub async fn generate_json(
+        &self,
+        prompt: String,
+        format: &json_schema::StructuredOutput,
+        cancel: Arc<std::sync::atomic::AtomicBool>,
+    ) -> anyhow::Result<serde_json::Value> {
+        match &self.backend {
+            Backend::Local { engine, .. } => engine.generate_json(prompt, format, cancel).await,
+            Backend::Remote(remote) => remote.json(prompt, format, cancel).await,
+        }
     }
 }
@@ -0,0 +1,540 @@
+//! Hidden analysis for reasoning turns. With self-consistency the analysis
+//! is sampled several times in parallel, the final answers are voted on, and
+//! the winning analysis is injected into the system prompt as a private
+//! block the reply is written from. Results are cached in RocksDB per
+//! normalized question, profile and language, so retries, regenerations and
+//! common questions skip the extra model calls. For math word problems the
+//! arithmetic in the winning analysis is re-computed with the calculator and
+//! the exact results are added to the block.
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use futures_util::future::join_all;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::agent::chat_tools::{evaluate, format_number};
+use crate::classifier::routing::ReasoningProfile;
+use crate::conversation::{build_mistral_prompt, strip_chatml_markers};
+use crate::db::DBLayer;
+use crate::model::message::Message;
+
+use super::InferenceService;
+
+const DEFAULT_SAMPLES: usize = 3;
+const DEFAULT_PROFILES: &str = "formal_logic,math_word_problem,constraint_puzzle";
+/// Longest analysis injected into the reply prompt.
+const MAX_PLAN_CHARS: usize = 4000;
+const FINAL_MARKER: &str = "FINAL:";
+const DEFAULT_CACHE_TTL_SECS: i64 = 24 * 60 * 60;
+/// Most calculator checks listed in the hidden block.
+const MAX_ARITHMETIC_CHECKS: usize = 12;
+
+const ANALYSIS_INSTRUCTIONS: &str = "Work through the last user message step by step before anyone answers it. Check each step and any arithmetic. Do not write a reply to the user. End with one line of the form `FINAL: <short answer>`.";
+const MATH_INSTRUCTIONS: &str = "Write every calculation on its own line as `<expression> = <result>`, using digits and + - * / ^ ( ) only.";
+
+static NUMBER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[$€£]?\d[\d,]*(?:\.\d+)?").expect("number pattern"));
+/// `<expression with at least one operator> = <number>`.
+static CALCULATION: Lazy<Regex> = Lazy::new(|| {
+    let number = r"[$€£]?\d[\d,]*(?:\.\d+)?";
+    let operand = format!(r"\(*\s*{number}\s*\)*");
+    Regex::new(&format!(
+        r"(?P<lhs>{operand}(?:\s*[-+*/×÷^%]\s*{operand})+)\s*=\s*(?P<rhs>-?{number})"
+    ))
+    .expect("calculation pattern")
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReasoningMode {
+    /// Reply directly.
+    Direct,
+    /// Sample the hidden analysis `samples` times and vote.
+    SelfConsistency { samples: usize },
+}
+
+impl ReasoningMode {
+    /// Self-consistency for the profiles in
+    /// `REASONING_SELF_CONSISTENCY_PROFILES` (default formal logic, math
+    /// word problems, constraint puzzles) with
+    /// `REASONING_SELF_CONSISTENCY_SAMPLES` samples (default 3; below 2
+    /// turns it off).
+    pub fn for_profile(profile: ReasoningProfile) -> Self {
+        let samples = std::env::var("REASONING_SELF_CONSISTENCY_SAMPLES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_SAMPLES);
+        let profiles = std::env::var("REASONING_SELF_CONSISTENCY_PROFILES")
+            .unwrap_or_else(|_| DEFAULT_PROFILES.to_string());
+        let enabled = profiles
+            .split(',')
+            .filter_map(ReasoningProfile::parse)
+            .any(|p| p == profile);
+        if enabled && samples >= 2 {
+            Self::SelfConsistency { samples }
+        } else {
+            Self::Direct
+        }
+    }
+
+    /// Analysis to run before the reply, `None` for direct replies.
+    pub fn hidden_analysis(
+        self,
+        profile: ReasoningProfile,
+        language: &str,
+        history: &[Message],
+        system_prompt: &str,
+    ) -> Option<HiddenAnalysis> {
+        let Self::SelfConsistency { samples } = self else {
+            return None;
+        };
+        let asked = history.iter().rposition(|m| m.role == "user");
+        let question = asked
+            .and_then(|i| history[i].text.as_deref())
+            .unwrap_or_default();
+        let earlier = &history[..asked.unwrap_or(0)];
+        let instructions = match profile {
+            ReasoningProfile::MathWordProblem => {
+                format!("{ANALYSIS_INSTRUCTIONS} {MATH_INSTRUCTIONS}")
+            }
+            _ => ANALYSIS_INSTRUCTIONS.to_string(),
+        };
+        Some(HiddenAnalysis {
+            analysis_prompt: build_mistral_prompt(history, Some(&instructions)),
+            profile,
+            samples,
+            cache_key: cache_key(question, earlier, profile, language),
+            history: history.to_vec(),
+            system_prompt: system_prompt.to_string(),
+        })
+    }
+}
+
+/// Seconds a reasoning result stays cached (`REASONING_CACHE_TTL_SECS`,
+/// default one day; `0` disables the cache).
+pub fn cache_ttl() -> i64 {
+    std::env::var("REASONING_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v >= 0)
+        .unwrap_or(DEFAULT_CACHE_TTL_SECS)
+}
+
+/// Hash of the question (case and whitespace folded), the turns before
+/// it, profile and language. The analysis reads the whole chat, so a
+/// follow-up like "and the next one?" only shares a result with the same
+/// conversation.
+pub fn cache_key(
+    question: &str,
+    earlier: &[Message],
+    profile: ReasoningProfile,
+    language: &str,
+) -> String {
+    let normalized = question
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{normalized}\n{}\n{language}", profile.as_str()).as_bytes());
+    for turn in earlier {
+        hasher.update(
+            format!(
+                "\n{}\0{}",
+                turn.role,
+                turn.text.as_deref().unwrap_or_default()
+            )
+            .as_bytes(),
+        );
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Output of the reasoning phase for one question.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReasoningResult {
+    /// Block appended to the system prompt.
+    pub hidden_block: String,
+    /// Stage that produced it (`self_consistency`).
+    pub stage: String,
+    #[serde(default)]
+    pub answer: Option<String>,
+    pub votes: usize,
+    pub samples: usize,
+    pub created_ts: i64,
+}
+
+/// Everything needed to run the analysis and rebuild the reply prompt
+/// around its result.
+pub struct HiddenAnalysis {
+    pub analysis_prompt: String,
+    pub profile: ReasoningProfile,
+    pub samples: usize,
+    pub cache_key: String,
+    pub history: Vec<Message>,
+    pub system_prompt: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consensus {
+    /// Majority final answer, if the samples gave any.
+    pub answer: Option<String>,
+    pub votes: usize,
+    pub samples: usize,
+    /// Analysis of the first sample that reached the majority answer.
+    pub plan: String,
+}
+
+impl Consensus {
+    fn agreed(&self) -> bool {
+        self.votes * 2 > self.samples
+    }
+}
+
+impl HiddenAnalysis {
+    /// Build the reply prompt with the reasoning result injected, from the
+    /// cache when possible. Falls back to the plain prompt when every sample
+    /// came back empty.
+    pub async fn reply_prompt(
+        &self,
+        infer: &InferenceService,
+        db: &DBLayer,
+        cancel: Arc<AtomicBool>,
+    ) -> String {
+        let ttl = cache_ttl();
+        let now = chrono::Utc::now().timestamp();
+        let cached = if ttl > 0 {
+            db.load_reasoning_result(&self.cache_key, now - ttl)
+                .await
+                .unwrap_or_else(|err| {
+                    debug!("reasoning cache read failed: {err}");
+                    None
+                })
+        } else {
+            None
+        };
+
+        let result = match cached {
+            Some(result) => {
+                info!(stage = result.stage.as_str(), "reasoning cache hit");
+                Some(result)
+            }
+            None => {
+                let result = self.self_consistency(infer, cancel.clone()).await;
+                // Only agreed results are reused; a split vote is retried.
+                if let Some(result) = result.as_ref().filter(|r| r.votes * 2 > r.samples) {
+                    if ttl > 0 && !cancel.load(std::sync::atomic::Ordering::SeqCst) {
+                        if let Err(err) = db.save_reasoning_result(&self.cache_key, result).await {
+                            debug!("reasoning cache write failed: {err}");
+                        }
+                    }
+                }
+                result
+            }
+        };
+
+        let system_prompt = match result {
+            Some(result) => format!("{}\n\n{}", self.system_prompt, result.hidden_block),
+            None => self.system_prompt.clone(),
+        };
+        build_mistral_prompt(&self.history, Some(&system_prompt))
+    }
+
+    /// Run the samples in parallel and vote.
+    async fn self_consistency(
+        &self,
+        infer: &InferenceService,
+        cancel: Arc<AtomicBool>,
+    ) -> Option<ReasoningResult> {
+        let runs = (0..self.samples)
+            .map(|_| infer.generate_completion(self.analysis_prompt.clone(), cancel.clone()));
+        let analyses: Vec<String> = join_all(runs)
+            .await
+            .into_iter()
+            .filter_map(|result| {
+                result
+                    .map_err(|err| debug!("analysis sample failed: {err}"))
+                    .ok()
+            })
+            .map(|text| clean_analysis(&text))
+            .collect();
+
+        let consensus = vote(&analyses)?;
+        let checks = match self.profile {
+            ReasoningProfile::MathWordProblem => check_arithmetic(&consensus.plan),
+            _ => Vec::new(),
+        };
+        info!(
+            samples = consensus.samples,
+            votes = consensus.votes,
+            answer = %crate::redaction::for_log(consensus.answer.as_deref().unwrap_or("-")),
+            calculations = checks.len(),
+            wrong = checks.iter().filter(|c| !c.correct()).count(),
+            "self-consistency analysis"
+        );
+        Some(ReasoningResult {
+            hidden_block: hidden_block(&consensus, &checks),
+            stage: "self_consistency".into(),
+            answer: consensus.answer,
+            votes: consensus.votes,
+            samples: consensus.samples,
+            created_ts: chrono::Utc::now().timestamp(),
+        })
+    }
+}
+
+fn clean_analysis(text: &str) -> String {
+    let text = text.split("<|im_end|>").next().unwrap_or_default();
+    strip_chatml_markers(text).trim().to_string()
+}
+
+/// Final answer of one analysis: the text after the last `FINAL:` marker.
+fn final_answer(analysis: &str) -> Option<String> {
+    let (_, answer) = analysis.rsplit_once(FINAL_MARKER)?;
+    let answer = answer.lines().next().unwrap_or_default();
+    let normalized = answer
+        .trim()
+        .trim_end_matches(['.', '!', ';'])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    (!normalized.is_empty()).then_some(normalized)
+}
+
+/// Majority vote over the samples' final answers; ties go to the answer
+/// seen first. Without any final answer the first non-empty analysis is
+/// used as the plan.
+fn vote(analyses: &[String]) -> Option<Consensus> {
+    let analyses: Vec<&String> = analyses.iter().filter(|a| !a.is_empty()).collect();
+    let first = analyses.first()?;
+    let answers: Vec<Option<String>> = analyses.iter().map(|a| final_answer(a)).collect();
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for answer in answers.iter().flatten() {
+        *counts.entry(answer.as_str()).or_default() += 1;
+    }
+    let mut winner: Option<(&str, usize)> = None;
+    for answer in answers.iter().flatten() {
+        let votes = counts[answer.as_str()];
+        if winner.map_or(true, |(_, best)| votes > best) {
+            winner = Some((answer.as_str(), votes));
+        }
+    }
+
+    let (answer, votes, plan) = match winner {
+        Some((answer, votes)) => {
+            let idx = answers
+                .iter()
+                .position(|a| a.as_deref() == Some(answer))
+                .unwrap_or(0);
+            (Some(answer.to_string()), votes, analyses[idx])
+        }
+        None => (None, 0, *first),
+    };
+    Some(Consensus {
+        answer,
+        votes,
+        samples: analyses.len(),
+        plan: plan.chars().take(MAX_PLAN_CHARS).collect(),
+    })
+}
+
+/// A calculation found in an analysis, re-computed exactly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArithmeticCheck {
+    pub expression: String,
+    pub claimed: f64,
+    /// Decimal places of the claimed result; rounding to them is accepted.
+    pub claimed_decimals: i32,
+    pub value: f64,
+}
+
+impl ArithmeticCheck {
+    pub fn correct(&self) -> bool {
+        let tolerance = 0.5 * 10f64.powi(-self.claimed_decimals) + 1e-9;
+        (self.value - self.claimed).abs() <= tolerance
+    }
+}
+
+/// `1,250` is a thousands separator, `1,5` a decimal comma.
+fn parse_number(literal: &str) -> String {
+    let literal = literal.trim_start_matches(['$', '€', '£']);
+    let thousands = literal
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .skip(1)
+        .all(|group| group.len() == 3);
+    if thousands {
+        literal.replace(',', "")
+    } else {
+        literal.replace(',', ".")
+    }
+}
+
+/// Every `<expression> = <number>` in the text, evaluated with the
+/// calculator. Expressions the calculator cannot parse are skipped.
+pub fn check_arithmetic(text: &str) -> Vec<ArithmeticCheck> {
+    let mut checks: Vec<ArithmeticCheck> = Vec::new();
+    for caps in CALCULATION.captures_iter(text) {
+        let expression = NUMBER
+            .replace_all(&caps["lhs"], |m: &regex::Captures| parse_number(&m[0]))
+            .replace('×', "*")
+            .replace('÷', "/")
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let claimed_text = parse_number(&caps["rhs"]);
+        let (Ok(value), Ok(claimed)) = (evaluate(&expression), claimed_text.parse::<f64>()) else {
+            continue;
+        };
+        if checks.iter().any(|c| c.expression == expression) {
+            continue;
+        }
+        let claimed_decimals = claimed_text
+            .split_once('.')
+            .map_or(0, |(_, decimals)| decimals.len() as i32);
+        checks.push(ArithmeticCheck {
+            expression,
+            claimed,
+            claimed_decimals,
+            value,
+        });
+        if checks.len() == MAX_ARITHMETIC_CHECKS {
+            break;
+        }
+    }
+    checks
+}
+
+fn hidden_block(consensus: &Consensus, checks: &[ArithmeticCheck]) -> String {
+    let mut block = format!(
+        "Private analysis (do not quote or mention it; use it to write your answer):\n{}",
+        consensus.plan
+    );
+    if let Some(answer) = &consensus.answer {
+        block.push_str(&format!(
+            "\n{} of {} independent analyses reached: {answer}",
+            consensus.votes, consensus.samples
+        ));
+    }
+    if !consensus.agreed() {
+        block.push_str("\nThe analyses disagree; re-check the reasoning before answering.");
+    }
+    if !checks.is_empty() {
+        block.push_str("\nCalculator results (exact; trust them over the analysis):");
+        for check in checks {
+            let value = format_number(check.value);
+            if check.correct() {
+                block.push_str(&format!("\n- {} = {value}", check.expression));
+            } else {
+                block.push_str(&format!(
+                    "\n- {} = {value} (the analysis wrote {}, which is wrong)",
+                    check.expression,
+                    format_number(check.claimed)
+                ));
+            }
+        }
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_key_folds_case_and_whitespace_and_keeps_chats_apart() {
+        let key = |text, language| cache_key(text, &[], ReasoningProfile::FormalLogic, language);
+        assert_eq!(key("Is 7 prime?", "en"), key("  is 7   PRIME?\n", "en"));
+        assert_ne!(key("Is 7 prime?", "en"), key("Is 7 prime?", "ru"));
+        assert_ne!(
+            key("Is 7 prime?", "en"),
+            cache_key("Is 7 prime?", &[], ReasoningProfile::MathWordProblem, "en")
+        );
+
+        let turn = |text: &str| -> Message {
+            serde_json::from_value(serde_json::json!({
+                "id": "m1",
+                "chat_id": "c1",
+                "session_id": null,
+                "user_id": null,
+                "device_hash": null,
+                "role": "user",
+                "text": text,
+                "ts": 1,
+            }))
+            .unwrap()
+        };
+        let follow_up = |earlier: &[Message]| {
+            cache_key(
+                "and the next one?",
+                earlier,
+                ReasoningProfile::FormalLogic,
+                "en",
+            )
+        };
+        assert_eq!(
+            follow_up(&[turn("primes after 7")]),
+            follow_up(&[turn("primes after 7")])
+        );
+        assert_ne!(
+            follow_up(&[turn("primes after 7")]),
+            follow_up(&[turn("my salary in May")])
+        );
+        assert_ne!(follow_up(&[turn("primes after 7")]), follow_up(&[]));
+    }
+
+    #[test]
+    fn majority_answer_wins_and_picks_its_analysis() {
+        let analyses = vec![
+            "2 + 2 = 5\nFINAL: 5".to_string(),
+            "2 + 2 = 4\nFINAL: 4.".to_string(),
+            "two plus two is four\nFINAL:  4".to_string(),
+        ];
+        let consensus = vote(&analyses).unwrap();
+        assert_eq!(consensus.answer.as_deref(), Some("4"));
+        assert_eq!(consensus.votes, 2);
+        assert!(consensus.agreed());
+        assert!(consensus.plan.starts_with("2 + 2 = 4"));
+
+        let consensus = vote(&["no marker here".to_string()]).unwrap();
+        assert_eq!(consensus.answer, None);
+        assert!(!consensus.agreed());
+        assert!(vote(&[String::new()]).is_none());
+    }
+
+    #[test]
+    fn arithmetic_checks_catch_wrong_steps() {
+        let checks = check_arithmetic(
+            "Each box holds 12 * 7 = 84 pens.\nSplit four ways: 84 / 4 = 22\n\
+             Total cost $1,250 + $310 = $1,560; per person 10 / 3 = 3.33\nx = 5",
+        );
+        let summary: Vec<(&str, bool)> = checks
+            .iter()
+            .map(|c| (c.expression.as_str(), c.correct()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("12 * 7", true),
+                ("84 / 4", false),
+                ("1250 + 310", true),
+                ("10 / 3", true),
+            ]
+        );
+        assert_eq!(checks[1].value, 21.0);
+    }
+}
@@ -0,0 +1,573 @@
+//! Inference as a separate service. The `inference_server` binary loads the
+//! llama.cpp models and serves them under `/inference/v1`; a web tier
+//! started with `INFERENCE_URL` loads none and sends every generation there
+//! through [`RemoteInference`], so it can be scaled and redeployed without
+//! reloading models. Streams are newline-delimited JSON frames, and closing
+//! one stops its generation on the service.
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::{
+    backend::{FinishReason, ReplyOutcome},
+    json_schema::StructuredOutput,
+    llama_cpp_service::{GenerationParams, ENGINE_ERROR_PREFIX},
+    InferenceService, ReplyStream,
+};
+use crate::manager::{ModelManager, WarmupResult};
+
+/// How often a waiting client checks the cancel flag.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+const STREAM_CAPACITY: usize = 64;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GenerateRequest {
+    prompt: String,
+    #[serde(default)]
+    chat_id: Option<String>,
+    #[serde(default)]
+    params: GenerationParams,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonRequest {
+    prompt: String,
+    schema: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokensRequest {
+    text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokensResponse {
+    count: usize,
+    /// Missing from older services.
+    #[serde(default)]
+    context_length: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CompletionResponse {
+    text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonResponse {
+    value: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadyResponse {
+    ready: bool,
+    models: Vec<WarmupResult>,
+}
+
+/// One line of a generation stream.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Frame {
+    Chunk(String),
+    /// The rest of the reply comes from the fallback model.
+    Fallback,
+    /// Sent last: why the reply ended.
+    Finish(FinishReason),
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Bearer token callers of the inference service must send
+/// (`INFERENCE_TOKEN`); `None` leaves the service open.
+pub fn service_token() -> Option<String> {
+    env("INFERENCE_TOKEN")
+}
+
+// ------------------------------------------------------------
+// SERVICE
+// ------------------------------------------------------------
+
+#[derive(Clone)]
+struct ServiceState {
+    infer: Arc<InferenceService>,
+    models: Arc<ModelManager>,
+    token: Option<Arc<str>>,
+}
+
+/// Routes of the inference service. `/readyz` answers like the web tier's,
+/// without a token.
+pub fn service_router(infer: Arc<InferenceService>, models: Arc<ModelManager>) -> Router {
+    let state = ServiceState {
+        infer,
+        models,
+        token: service_token().map(Arc::from),
+    };
+    Router::new()
+        .route("/inference/v1/reply", post(reply_handler))
+        .route("/inference/v1/stream", post(stream_handler))
+        .route("/inference/v1/completion", post(completion_handler))
+        .route("/inference/v1/json", post(json_handler))
+        .route("/inference/v1/tokens", post(tokens_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .route("/readyz", get(ready_handler))
+        .with_state(state)
+}
+
+async fn require_token(
+    State(state): State<ServiceState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(token) = &state.token {
+        let given = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if given != Some(token.as_ref()) {
+            return (StatusCode::UNAUTHORIZED, "invalid_inference_token").into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Sets the flag when the response is dropped, i.e. when the client went
+/// away, so queued and running generations stop.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+fn frame_line(frame: &Frame) -> String {
+    let mut line = serde_json::to_string(frame).expect("frame serializes");
+    line.push('\n');
+    line
+}
+
+fn ndjson(
+    rx: mpsc::Receiver<String>,
+    cancel: Arc<AtomicBool>,
+    fallback: Option<Arc<AtomicBool>>,
+    outcome: Option<ReplyOutcome>,
+) -> Response {
+    let state = (rx, CancelOnDrop(cancel), fallback, false, outcome);
+    let stream = futures_util::stream::unfold(
+        state,
+        |(mut rx, guard, fallback, announced, outcome)| async move {
+            let Some(chunk) = rx.recv().await else {
+                // Reported once, after the last chunk.
+                let reason = outcome?.reason()?;
+                return Some((
+                    Ok(frame_line(&Frame::Finish(reason))),
+                    (rx, guard, fallback, announced, None),
+                ));
+            };
+            let mut out = String::new();
+            let switched = fallback
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::SeqCst));
+            if switched && !announced {
+                out.push_str(&frame_line(&Frame::Fallback));
+            }
+            out.push_str(&frame_line(&Frame::Chunk(chunk)));
+            Some((
+                Ok::<_, Infallible>(out),
+                (rx, guard, fallback, announced || switched, outcome),
+            ))
+        },
+    );
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(stream))
+        .expect("valid response")
+}
+
+async fn reply_handler(
+    State(state): State<ServiceState>,
+    Json(req): Json<GenerateRequest>,
+) -> Response {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let outcome = req.params.outcome.clone();
+    let reply = state
+        .infer
+        .generate_reply(req.prompt, req.chat_id, req.params, cancel.clone());
+    ndjson(reply.rx, cancel, Some(reply.fallback), Some(outcome))
+}
+
+async fn stream_handler(
+    State(state): State<ServiceState>,
+    Json(req): Json<GenerateRequest>,
+) -> Response {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let rx = match req.chat_id {
+        Some(chat_id) => state
+            .infer
+            .generate_stream_for_chat(req.prompt, chat_id, cancel.clone()),
+        None => state.infer.generate_stream(req.prompt, cancel.clone()),
+    };
+    ndjson(rx, cancel, None, None)
+}
+
+fn internal(err: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+async fn completion_handler(
+    State(state): State<ServiceState>,
+    Json(req): Json<GenerateRequest>,
+) -> Result<Json<CompletionResponse>, (StatusCode, String)> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(cancel.clone());
+    let text = state
+        .infer
+        .generate_completion(req.prompt, cancel)
+        .await
+        .map_err(internal)?;
+    Ok(Json(CompletionResponse { text }))
+}
+
+async fn json_handler(
+    State(state): State<ServiceState>,
+    Json(req): Json<JsonRequest>,
+) -> Result<Json<JsonResponse>, (StatusCode, String)> {
+    let format = StructuredOutput::new(req.schema)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid_schema: {err}")))?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(cancel.clone());
+    let value = state
+        .infer
+        .generate_json(req.prompt, &format, cancel)
+        .await
+        .map_err(internal)?;
+    Ok(Json(JsonResponse { value }))
+}
+
+async fn tokens_handler(
+    State(state): State<ServiceState>,
+    Json(req): Json<TokensRequest>,
+) -> Result<Json<TokensResponse>, (StatusCode, String)> {
+    let count = state
+        .infer
+        .count_tokens(&req.text)
+        .await
+        .map_err(internal)?;
+    Ok(Json(TokensResponse {
+        count,
+        context_length: state.infer.context_length(),
+    }))
+}
+
+async fn ready_handler(State(state): State<ServiceState>) -> (StatusCode, Json<ReadyResponse>) {
+    let ready = state.models.is_ready();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(ReadyResponse {
+            ready,
+            models: state.models.warmup_results(),
+        }),
+    )
+}
+
+// ------------------------------------------------------------
+// CLIENT
+// ------------------------------------------------------------
+
+/// Client of the inference service, used by [`InferenceService::remote`].
+#[derive(Clone)]
+pub struct RemoteInference {
+    client: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+    /// As last reported with a token count; 0 before the first.
+    context_length: AtomicUsize,
+}
+
+/// Resolves once `cancel` is set.
+async fn cancelled(cancel: &AtomicBool) {
+    while !cancel.load(Ordering::SeqCst) {
+        tokio::time::sleep(CANCEL_POLL).await;
+    }
+}
+
+impl RemoteInference {
+    /// `INFERENCE_URL` with `INFERENCE_TOKEN`; `None` when no URL is set
+    /// and the models run in this process.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(url) = env("INFERENCE_URL") else {
+            return Ok(None);
+        };
+        Ok(Some(Self::new(url, service_token())?))
+    }
+
+    pub fn new(base_url: String, token: Option<String>) -> Result<Self> {
+        // No overall timeout: a reply streams for as long as it generates.
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            context_length: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.post(format!("{}{path}", self.base_url));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    pub fn reply(
+        &self,
+        prompt: String,
+        chat_id: Option<String>,
+        params: GenerationParams,
+        cancel: Arc<AtomicBool>,
+    ) -> ReplyStream {
+        let fallback = Arc::new(AtomicBool::new(false));
+        let outcome = params.outcome.clone();
+        let body = GenerateRequest {
+            prompt,
+            chat_id,
+            params,
+        };
+        let rx = spawn_stream(
+            self.post("/inference/v1/reply").json(&body),
+            cancel,
+            Some(fallback.clone()),
+            Some(outcome),
+        );
+        ReplyStream { rx, fallback }
+    }
+
+    pub fn stream(
+        &self,
+        prompt: String,
+        chat_id: Option<String>,
+        cancel: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<String> {
+        let body = GenerateRequest {
+            prompt,
+            chat_id,
+            params: GenerationParams::default(),
+        };
+        spawn_stream(
+            self.post("/inference/v1/stream").json(&body),
+            cancel,
+            None,
+            None,
+        )
+    }
+
+    pub async fn count_tokens(&self, text: &str) -> Result<usize> {
+        let body = TokensRequest {
+            text: text.to_string(),
+        };
+        let response: TokensResponse =
+            call(self.post("/inference/v1/tokens").json(&body), None).await?;
+        self.context_length
+            .store(response.context_length, Ordering::Relaxed);
+        Ok(response.count)
+    }
+
+    /// The service's context length as of the last token count.
+    pub fn context_length(&self) -> usize {
+        self.context_length.load(Ordering::Relaxed)
+    }
+
+    pub async fn completion(&self, prompt: String, cancel: Arc<AtomicBool>) -> Result<String> {
+        let body = GenerateRequest {
+            prompt,
+            chat_id: None,
+            params: GenerationParams::default(),
+        };
+        let response: CompletionResponse = call(
+            self.post("/inference/v1/completion").json(&body),
+            Some(&cancel),
+        )
+        .await?;
+        Ok(response.text)
+    }
+
+    pub async fn json(
+        &self,
+        prompt: String,
+        format: &StructuredOutput,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<Value> {
+        let body = JsonRequest {
+            prompt,
+            schema: format.schema().clone(),
+        };
+        let response: JsonResponse =
+            call(self.post("/inference/v1/json").json(&body), Some(&cancel)).await?;
+        Ok(response.value)
+    }
+}
+
+/// Send a request and decode its JSON answer. Setting `cancel` drops the
+/// request, which stops the generation on the service.
+async fn call<T: DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+    cancel: Option<&AtomicBool>,
+) -> Result<T> {
+    let send = async {
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("inference service returned {status}: {body}");
+        }
+        Ok(response.json::<T>().await?)
+    };
+    match cancel {
+        None => send.await,
+        Some(cancel) => tokio::select! {
+            result = send => result,
+            _ = cancelled(cancel) => bail!("generation cancelled"),
+        },
+    }
+}
+
+/// Chunks of a generation stream, as the local engine would send them:
+/// failures arrive as one chunk starting with [`ENGINE_ERROR_PREFIX`].
+fn spawn_stream(
+    request: reqwest::RequestBuilder,
+    cancel: Arc<AtomicBool>,
+    fallback: Option<Arc<AtomicBool>>,
+    outcome: Option<ReplyOutcome>,
+) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+    tokio::spawn(async move {
+        let response = tokio::select! {
+            response = request.send() => response,
+            _ = cancelled(&cancel) => return,
+        };
+        let mut body = match response {
+            Ok(response) if response.status().is_success() => response.bytes_stream(),
+            Ok(response) => {
+                let status = response.status();
+                let _ = tx
+                    .send(format!(
+                        "{ENGINE_ERROR_PREFIX} inference service returned {status}"
+                    ))
+                    .await;
+                return;
+            }
+            Err(err) => {
+                let _ = tx
+                    .send(format!(
+                        "{ENGINE_ERROR_PREFIX} inference service unreachable: {err}"
+                    ))
+                    .await;
+                return;
+            }
+        };
+
+        // Returning drops the body, which closes the connection and stops
+        // the generation on the service.
+        let mut pending = Vec::new();
+        loop {
+            let bytes = tokio::select! {
+                next = body.next() => match next {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        let _ = tx
+                            .send(format!("{ENGINE_ERROR_PREFIX} inference stream broke: {err}"))
+                            .await;
+                        return;
+                    }
+                    None => return,
+                },
+                _ = cancelled(&cancel) => return,
+                _ = tx.closed() => return,
+            };
+            pending.extend_from_slice(&bytes);
+            while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                match serde_json::from_slice::<Frame>(&line) {
+                    Ok(Frame::Chunk(chunk)) => {
+                        if tx.send(chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Frame::Fallback) => {
+                        if let Some(flag) = &fallback {
+                            flag.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    Ok(Frame::Finish(reason)) => {
+                        if let Some(outcome) = &outcome {
+                            outcome.set(reason);
+                        }
+                    }
+                    Err(err) => warn!("malformed frame from inference service: {err}"),
+                }
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_one_json_value_per_line() {
+        let line = frame_line(&Frame::Chunk("a\nb".into()));
+        assert_eq!(line, "{\"chunk\":\"a\\nb\"}\n");
+        assert_eq!(line.matches('\n').count(), 1);
+        assert_eq!(frame_line(&Frame::Fallback), "\"fallback\"\n");
+        assert_eq!(
+            frame_line(&Frame::Finish(FinishReason::MaxTokens)),
+            "{\"finish\":\"max_tokens\"}\n"
+        );
+        assert!(matches!(
+            serde_json::from_slice::<Frame>(b"\"fallback\"\n"),
+            Ok(Frame::Fallback)
+        ));
+    }
+}
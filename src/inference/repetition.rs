@@ -0,0 +1,172 @@
+//! Ends replies that degenerate into a loop, where the latest output is
+//! one stretch of text repeated over and over. Checked after every token
+//! on the bytes at the end of the reply.
+//!
+//! Runs of whitespace and punctuation are left alone, since table rules,
+//! separators and indentation repeat by design, and stretches shorter than
+//! `SHORT_PERIOD` bytes must repeat `SHORT_PERIOD_FACTOR` times as far.
+
+use std::sync::OnceLock;
+
+const DEFAULT_MIN_REPEATS: usize = 4;
+const DEFAULT_MIN_SPAN: usize = 256;
+const DEFAULT_MAX_PERIOD: usize = 512;
+/// Stretches shorter than this are a letter or syllable held, not a phrase.
+const SHORT_PERIOD: usize = 4;
+/// How much longer than the usual span a short stretch must repeat.
+const SHORT_PERIOD_FACTOR: usize = 4;
+
+/// A reply loops once its last `min_span` bytes, and at least
+/// `min_repeats` copies, are one stretch of up to `max_period` bytes
+/// repeated.
+#[derive(Debug, Clone, Copy)]
+pub struct RepetitionLimits {
+    pub min_repeats: usize,
+    pub min_span: usize,
+    pub max_period: usize,
+}
+
+fn env(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(default)
+}
+
+impl RepetitionLimits {
+    /// `REPETITION_MIN_REPEATS` (default 4, `0` turns the guard off),
+    /// `REPETITION_MIN_SPAN` (bytes, default 256) and
+    /// `REPETITION_MAX_PERIOD` (bytes, default 512), read once.
+    pub fn current() -> Self {
+        static LIMITS: OnceLock<RepetitionLimits> = OnceLock::new();
+        *LIMITS.get_or_init(|| Self {
+            min_repeats: env("REPETITION_MIN_REPEATS", DEFAULT_MIN_REPEATS),
+            min_span: env("REPETITION_MIN_SPAN", DEFAULT_MIN_SPAN),
+            max_period: env("REPETITION_MAX_PERIOD", DEFAULT_MAX_PERIOD).max(1),
+        })
+    }
+
+    fn enabled(&self) -> bool {
+        self.min_repeats > 0
+    }
+
+    /// Bytes that must repeat with period `period`.
+    fn span(&self, period: usize) -> usize {
+        let span = (period * self.min_repeats).max(self.min_span);
+        if period < SHORT_PERIOD {
+            span * SHORT_PERIOD_FACTOR
+        } else {
+            span
+        }
+    }
+}
+
+pub struct RepetitionGuard {
+    limits: RepetitionLimits,
+    tail: Vec<u8>,
+}
+
+impl RepetitionGuard {
+    pub fn new(limits: RepetitionLimits) -> Self {
+        Self {
+            limits,
+            tail: Vec::new(),
+        }
+    }
+
+    /// Add the bytes of one token. True once the reply is looping.
+    pub fn push(&mut self, piece: &[u8]) -> bool {
+        if !self.limits.enabled() || piece.is_empty() {
+            return false;
+        }
+        self.tail.extend_from_slice(piece);
+        let window = self
+            .limits
+            .span(self.limits.max_period)
+            .max(self.limits.span(1));
+        if self.tail.len() > 2 * window {
+            self.tail.drain(..self.tail.len() - window);
+        }
+        self.looping()
+    }
+
+    fn looping(&self) -> bool {
+        for period in 1..=self.limits.max_period {
+            let span = self.limits.span(period);
+            if span > self.tail.len() {
+                if period < SHORT_PERIOD {
+                    continue;
+                }
+                // From here on, longer periods need at least as long a span.
+                return false;
+            }
+            let end = &self.tail[self.tail.len() - span..];
+            if !repeats(end, period) {
+                continue;
+            }
+            let filler = end[..period]
+                .iter()
+                .all(|b| b.is_ascii_whitespace() || b.is_ascii_punctuation());
+            // A held letter repeats with every period; it was judged
+            // against its own, longer span.
+            let short =
+                period >= SHORT_PERIOD && (1..SHORT_PERIOD).any(|shorter| repeats(end, shorter));
+            if !filler && !short {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Whether `bytes` is one stretch of `period` bytes repeated.
+fn repeats(bytes: &[u8], period: usize) -> bool {
+    bytes[..bytes.len() - period] == bytes[period..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: RepetitionLimits = RepetitionLimits {
+        min_repeats: 4,
+        min_span: 64,
+        max_period: 128,
+    };
+
+    fn loops(pieces: &[&str]) -> bool {
+        let mut guard = RepetitionGuard::new(LIMITS);
+        pieces.iter().any(|piece| guard.push(piece.as_bytes()))
+    }
+
+    #[test]
+    fn stops_a_repeated_phrase() {
+        let phrase = "I am here to help you. ";
+        assert!(!loops(&[phrase, phrase]));
+        assert!(loops(&[phrase; 4]));
+        // A held letter needs a much longer span than a phrase.
+        assert!(!loops(&["a"; 255]));
+        assert!(loops(&["a"; 256]));
+    }
+
+    #[test]
+    fn ignores_runs_of_whitespace_and_punctuation() {
+        assert!(!loops(&["!"; 1000]));
+        assert!(!loops(&["|---"; 300]));
+        assert!(!loops(&["\n    "; 300]));
+        assert!(!loops(&["=", "-", "="].repeat(300)));
+    }
+
+    #[test]
+    fn lets_varied_text_through() {
+        let text: Vec<String> = (0..200).map(|i| format!("line {i}\n")).collect();
+        let pieces: Vec<&str> = text.iter().map(String::as_str).collect();
+        assert!(!loops(&pieces));
+
+        let mut off = RepetitionGuard::new(RepetitionLimits {
+            min_repeats: 0,
+            ..LIMITS
+        });
+        assert!(!(0..100).any(|_| off.push(b"again ")));
+    }
+}
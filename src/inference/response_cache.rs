@@ -0,0 +1,237 @@
+//! Exact-match cache of finished replies, keyed by the final prompt and the
+//! sampling parameters. Clients of the external API retry aggressively; a
+//! retry of a request that already completed is answered from here instead
+//! of running the model again. Entries expire after a TTL and the cache is
+//! bounded by entry count and total text size, evicting the oldest first.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use super::{backend::FinishReason, llama_cpp_service::GenerationParams};
+
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// A completed reply as it was returned the first time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedReply {
+    pub output: String,
+    pub fallback: bool,
+    pub seed: u32,
+    pub finish_reason: FinishReason,
+}
+
+struct Entry {
+    reply: CachedReply,
+    inserted: Instant,
+    /// Insertion order, oldest evicted first.
+    seq: u64,
+}
+
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<[u8; 32], Entry>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            max_bytes,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// `RESPONSE_CACHE_TTL_SECS` (default 0, disabled),
+    /// `RESPONSE_CACHE_MAX_ENTRIES` (default 1024) and
+    /// `RESPONSE_CACHE_MAX_BYTES` (default 64 MiB of reply text).
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<usize> {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+        }
+        Self::new(
+            Duration::from_secs(var("RESPONSE_CACHE_TTL_SECS").unwrap_or(0) as u64),
+            var("RESPONSE_CACHE_MAX_ENTRIES").unwrap_or(DEFAULT_MAX_ENTRIES),
+            var("RESPONSE_CACHE_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES),
+        )
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    /// Hash of the prompt and every parameter that changes the output. The
+    /// seed is the one the client asked for, so unseeded retries hit too.
+    pub fn key(prompt: &str, params: &GenerationParams) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        field(prompt.as_bytes());
+        field(
+            &params
+                .temperature
+                .map(f32::to_bits)
+                .unwrap_or(u32::MAX)
+                .to_le_bytes(),
+        );
+        field(
+            &params
+                .max_tokens
+                .map(|n| n as u64)
+                .unwrap_or(u64::MAX)
+                .to_le_bytes(),
+        );
+        field(&(params.stop.len() as u64).to_le_bytes());
+        for stop in &params.stop {
+            field(stop.as_bytes());
+        }
+        field(params.grammar.as_deref().unwrap_or_default().as_bytes());
+        field(&(params.logit_bias.len() as u64).to_le_bytes());
+        for (target, bias) in &params.logit_bias {
+            field(target.as_bytes());
+            field(&bias.to_bits().to_le_bytes());
+        }
+        field(&params.seed.map(u64::from).unwrap_or(u64::MAX).to_le_bytes());
+        hasher.finalize().into()
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> Option<CachedReply> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        if entry.inserted.elapsed() < self.ttl {
+            return Some(entry.reply.clone());
+        }
+        inner.remove(key);
+        None
+    }
+
+    pub fn insert(&self, key: [u8; 32], reply: CachedReply) {
+        if !self.enabled() || reply.output.len() > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        let ttl = self.ttl;
+        let expired: Vec<[u8; 32]> = inner
+            .entries
+            .iter()
+            .filter(|(_, e)| e.inserted.elapsed() >= ttl)
+            .map(|(k, _)| *k)
+            .collect();
+        for k in &expired {
+            inner.remove(k);
+        }
+        while inner.entries.len() >= self.max_entries
+            || inner.bytes + reply.output.len() > self.max_bytes
+        {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.seq)
+                .map(|(k, _)| *k)
+            else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+        inner.bytes += reply.output.len();
+        inner.next_seq += 1;
+        let seq = inner.next_seq;
+        inner.entries.insert(
+            key,
+            Entry {
+                reply,
+                inserted: Instant::now(),
+                seq,
+            },
+        );
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &[u8; 32]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.reply.output.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(output: &str) -> CachedReply {
+        CachedReply {
+            output: output.to_string(),
+            fallback: false,
+            seed: 7,
+            finish_reason: FinishReason::Eos,
+        }
+    }
+
+    #[test]
+    fn key_covers_sampling_params() {
+        let base = GenerationParams::default();
+        let seeded = GenerationParams {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let stops = GenerationParams {
+            stop: vec!["a".into(), "b".into()],
+            ..Default::default()
+        };
+        let joined = GenerationParams {
+            stop: vec!["ab".into()],
+            ..Default::default()
+        };
+        let key = ResponseCache::key("hi", &base);
+        assert_eq!(key, ResponseCache::key("hi", &base));
+        assert_ne!(key, ResponseCache::key("hi!", &base));
+        assert_ne!(key, ResponseCache::key("hi", &seeded));
+        assert_ne!(
+            ResponseCache::key("hi", &stops),
+            ResponseCache::key("hi", &joined)
+        );
+    }
+
+    #[test]
+    fn evicts_oldest_past_bounds() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 2, 10);
+        cache.insert([1; 32], reply("aaaa"));
+        cache.insert([2; 32], reply("bbbb"));
+        cache.insert([3; 32], reply("cccc"));
+        assert_eq!(cache.get(&[1; 32]), None);
+        assert_eq!(cache.get(&[3; 32]), Some(reply("cccc")));
+
+        cache.insert([4; 32], reply("dddddddd"));
+        assert_eq!(cache.get(&[2; 32]), None);
+        assert_eq!(cache.get(&[3; 32]), None);
+        assert_eq!(cache.get(&[4; 32]), Some(reply("dddddddd")));
+
+        let disabled = ResponseCache::new(Duration::ZERO, 2, 10);
+        disabled.insert([1; 32], reply("a"));
+        assert_eq!(disabled.get(&[1; 32]), None);
+    }
+}
@@ -0,0 +1,105 @@
+//! Stop strings applied to streamed text. A stop string can arrive split
+//! over several token pieces, so text that may turn out to be its start is
+//! held back until the next piece settles it.
+
+pub struct StopMatcher {
+    stops: Vec<String>,
+    held: String,
+    stopped: bool,
+}
+
+impl StopMatcher {
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            held: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// A stop string has been seen; later pieces are dropped.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Text of `piece` (and of earlier held-back pieces) that can be passed
+    /// on, up to the first stop string.
+    pub fn push(&mut self, piece: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        if self.stops.is_empty() {
+            return piece.to_string();
+        }
+        self.held.push_str(piece);
+        if let Some(at) = self.stops.iter().filter_map(|s| self.held.find(s)).min() {
+            self.stopped = true;
+            self.held.truncate(at);
+            return std::mem::take(&mut self.held);
+        }
+        let keep = self
+            .stops
+            .iter()
+            .map(|stop| partial_suffix(&self.held, stop))
+            .max()
+            .unwrap_or(0);
+        let ready = self.held.len() - keep;
+        let rest = self.held.split_off(ready);
+        std::mem::replace(&mut self.held, rest)
+    }
+
+    /// Held-back text once the stream ended without a stop string.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// Length of the longest end of `text` that is a proper start of `stop`.
+fn partial_suffix(text: &str, stop: &str) -> usize {
+    stop.char_indices()
+        .map(|(i, _)| i)
+        .skip(1)
+        .filter(|&len| text.ends_with(&stop[..len]))
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(stops: &[&str], pieces: &[&str]) -> (String, bool) {
+        let mut matcher = StopMatcher::new(stops.iter().map(|s| s.to_string()).collect());
+        let mut out = String::new();
+        for piece in pieces {
+            out.push_str(&matcher.push(piece));
+        }
+        out.push_str(&matcher.finish());
+        (out, matcher.stopped())
+    }
+
+    #[test]
+    fn stops_at_a_string_split_across_pieces() {
+        assert_eq!(
+            run(&["\nUser:"], &["Sure", ".\nUs", "er: hi"]),
+            ("Sure.".to_string(), true)
+        );
+        assert_eq!(
+            run(&["###", "END"], &["a", "b#", "#c"]),
+            ("ab##c".to_string(), false)
+        );
+        assert_eq!(
+            run(&["###", "END"], &["ab", "EN", "D tail"]),
+            ("ab".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn holds_back_only_possible_starts() {
+        let mut matcher = StopMatcher::new(vec!["<|end|>".into()]);
+        assert_eq!(matcher.push("hello <|"), "hello ");
+        assert_eq!(matcher.push("x"), "<|x");
+        assert_eq!(matcher.push("привет"), "привет");
+        assert!(!matcher.stopped());
+    }
+}
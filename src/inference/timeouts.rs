@@ -0,0 +1,93 @@
+//! Wall-clock caps on generation: one for `/external/api/generate` and
+//! gRPC `Generate` calls, and one per role of the chat owner for chat
+//! replies. A reply that reaches its cap is stopped and finishes with
+//! `timeout`, keeping what it generated so far.
+
+use std::{sync::OnceLock, time::Duration};
+
+use tokio::{
+    sync::mpsc,
+    time::{error::Elapsed, Instant},
+};
+
+use crate::model::user::UserRole;
+
+const DEFAULT_GENERATE_SECS: u64 = 120;
+const DEFAULT_FREE_SECS: u64 = 60;
+const DEFAULT_PAID_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutPolicy {
+    pub generate: Option<Duration>,
+    pub free: Option<Duration>,
+    pub paid: Option<Duration>,
+    pub admin: Option<Duration>,
+}
+
+/// `0` means no cap.
+fn env_cap(name: &str, default: u64) -> Option<Duration> {
+    let secs = std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(default);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl TimeoutPolicy {
+    /// `GENERATE_TIMEOUT_SECS` (default 120) for API calls and
+    /// `CHAT_REPLY_TIMEOUT_{FREE,PAID,ADMIN}_SECS` (defaults 60, 300 and
+    /// none) for chat replies, read once; `0` lifts a cap.
+    pub fn current() -> &'static Self {
+        static POLICY: OnceLock<TimeoutPolicy> = OnceLock::new();
+        POLICY.get_or_init(|| Self {
+            generate: env_cap("GENERATE_TIMEOUT_SECS", DEFAULT_GENERATE_SECS),
+            free: env_cap("CHAT_REPLY_TIMEOUT_FREE_SECS", DEFAULT_FREE_SECS),
+            paid: env_cap("CHAT_REPLY_TIMEOUT_PAID_SECS", DEFAULT_PAID_SECS),
+            admin: env_cap("CHAT_REPLY_TIMEOUT_ADMIN_SECS", 0),
+        })
+    }
+
+    /// Cap on a chat reply; chats without an account count as free.
+    pub fn chat_reply(&self, role: Option<&UserRole>) -> Option<Duration> {
+        match role {
+            None | Some(UserRole::Free) => self.free,
+            Some(UserRole::Paid) => self.paid,
+            Some(UserRole::Admin) => self.admin,
+        }
+    }
+}
+
+/// `cap` from now, as a deadline for [`recv_until`].
+pub fn deadline(cap: Option<Duration>) -> Option<Instant> {
+    cap.map(|cap| Instant::now() + cap)
+}
+
+/// The next chunk of a reply, or `Err` once `deadline` passed first.
+pub async fn recv_until(
+    rx: &mut mpsc::Receiver<String>,
+    deadline: Option<Instant>,
+) -> Result<Option<String>, Elapsed> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, rx.recv()).await,
+        None => Ok(rx.recv().await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_chats_get_the_free_cap() {
+        let policy = TimeoutPolicy {
+            generate: None,
+            free: Some(Duration::from_secs(60)),
+            paid: None,
+            admin: None,
+        };
+        assert_eq!(policy.chat_reply(None), policy.free);
+        assert_eq!(policy.chat_reply(Some(&UserRole::Free)), policy.free);
+        assert_eq!(policy.chat_reply(Some(&UserRole::Paid)), None);
+        assert_eq!(env_cap("CHAT_REPLY_TIMEOUT_TEST_UNSET", 0), None);
+    }
+}
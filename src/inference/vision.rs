@@ -0,0 +1,142 @@
+//! Image descriptions for uploaded image attachments, so replies and
+//! summaries do not depend on client-side OCR. The bundled backend runs
+//! llama.cpp's multimodal CLI (`llama-mtmd-cli`) with a small VLM such as
+//! moondream2 or llava and its `mmproj` projector.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use tokio::{process::Command, sync::Semaphore};
+use tracing::{info, warn};
+
+const DEFAULT_CLI_BIN: &str = "llama.cpp/build/bin/llama-mtmd-cli";
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_TOKENS: usize = 160;
+const DESCRIBE_PROMPT: &str = "Describe this image for someone who cannot see it. Mention the main subjects, any visible text verbatim, and anything else needed to answer questions about it. Answer in at most four sentences.";
+
+#[async_trait]
+pub trait ImageDescriber: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Plain-text description of the image at `image`.
+    async fn describe(&self, image: &Path) -> Result<String>;
+}
+
+/// `llama-mtmd-cli` with a GGUF model and its multimodal projector. Runs
+/// one image at a time to keep the model's memory bounded.
+pub struct LlamaMtmdDescriber {
+    bin: PathBuf,
+    model: PathBuf,
+    mmproj: PathBuf,
+    gpu_layers: Option<i32>,
+    max_tokens: usize,
+    timeout: Duration,
+    slots: Semaphore,
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Describer configured by `VISION_MODEL` and `VISION_MMPROJ` (GGUF paths),
+/// with `VISION_CLI_BIN` (default `llama.cpp/build/bin/llama-mtmd-cli`),
+/// `VISION_NGL`, `VISION_MAX_TOKENS` (default 160) and
+/// `VISION_TIMEOUT_SECS` (default 60). `None` leaves images to their
+/// client-side description.
+pub fn describer_from_env() -> Option<Arc<dyn ImageDescriber>> {
+    let model = PathBuf::from(env_value("VISION_MODEL")?);
+    let Some(mmproj) = env_value("VISION_MMPROJ").map(PathBuf::from) else {
+        println!("⚠️  VISION_MODEL set without VISION_MMPROJ; image descriptions disabled");
+        return None;
+    };
+    let bin = PathBuf::from(env_value("VISION_CLI_BIN").unwrap_or_else(|| DEFAULT_CLI_BIN.into()));
+    for (name, path) in [("VISION_MODEL", &model), ("VISION_MMPROJ", &mmproj)] {
+        if !path.exists() {
+            println!(
+                "⚠️  {name} {} not found; image descriptions disabled",
+                path.display()
+            );
+            return None;
+        }
+    }
+    let timeout = env_value("VISION_TIMEOUT_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    println!("ℹ️  vision model loaded from {}", model.display());
+    Some(Arc::new(LlamaMtmdDescriber {
+        bin,
+        model,
+        mmproj,
+        gpu_layers: env_value("VISION_NGL").and_then(|v| v.parse().ok()),
+        max_tokens: env_value("VISION_MAX_TOKENS")
+            .and_then(|v| v.parse().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(DEFAULT_MAX_TOKENS),
+        timeout: Duration::from_secs(timeout),
+        slots: Semaphore::new(1),
+    }))
+}
+
+#[async_trait]
+impl ImageDescriber for LlamaMtmdDescriber {
+    fn name(&self) -> &'static str {
+        "llama-mtmd"
+    }
+
+    async fn describe(&self, image: &Path) -> Result<String> {
+        let _slot = self.slots.acquire().await?;
+        let mut command = Command::new(&self.bin);
+        command
+            .arg("-m")
+            .arg(&self.model)
+            .arg("--mmproj")
+            .arg(&self.mmproj)
+            .arg("--image")
+            .arg(image)
+            .args(["-p", DESCRIBE_PROMPT, "-n", &self.max_tokens.to_string()])
+            .args(["--temp", "0.1", "--no-warmup"]);
+        if let Some(layers) = self.gpu_layers {
+            command.args(["-ngl", &layers.to_string()]);
+        }
+        let output = tokio::time::timeout(
+            self.timeout,
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| anyhow!("image description timed out"))?
+        .context("failed to start the vision model")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let tail: String = stderr.lines().rev().take(3).collect::<Vec<_>>().join(" | ");
+            bail!("vision model failed: {tail}");
+        }
+
+        // The CLI logs to stderr; stdout is only the answer.
+        let description = normalize_description(&String::from_utf8_lossy(&output.stdout));
+        if description.is_empty() {
+            warn!(image = %image.display(), "vision model returned no description");
+            bail!("empty image description");
+        }
+        info!(chars = description.chars().count(), "image described");
+        Ok(description)
+    }
+}
+
+fn normalize_description(raw: &str) -> String {
+    raw.split_whitespace().collect::<Vec<_>>().join(" ")
+}
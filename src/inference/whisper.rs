@@ -0,0 +1,216 @@
+//! Speech to text for voice messages. The server side only needs
+//! [`Transcriber`]; the bundled backend runs whisper.cpp's CLI on a 16 kHz
+//! mono WAV that ffmpeg converts the upload to.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Transcript {
+    pub text: String,
+    /// Spoken language as detected by the model (ISO 639-1), when known.
+    pub language: Option<String>,
+}
+
+#[async_trait]
+pub trait Transcriber: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Transcribe the audio file at `audio` (any format ffmpeg reads).
+    /// `language` (ISO 639-1) skips language detection.
+    async fn transcribe(&self, audio: &Path, language: Option<&str>) -> Result<Transcript>;
+}
+
+/// whisper.cpp `whisper-cli` with a ggml model.
+pub struct WhisperCppTranscriber {
+    bin: String,
+    model: PathBuf,
+    ffmpeg: String,
+    threads: Option<usize>,
+    timeout: Duration,
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Transcriber configured by `WHISPER_MODEL` (path to a ggml model), with
+/// `WHISPER_CLI_BIN` (default `whisper-cli`), `FFMPEG_BIN` (default
+/// `ffmpeg`), `WHISPER_THREADS` and `WHISPER_TIMEOUT_SECS` (default 120).
+/// `None` disables voice messages.
+pub fn transcriber_from_env() -> Option<Arc<dyn Transcriber>> {
+    let model = PathBuf::from(env_value("WHISPER_MODEL")?);
+    if !model.exists() {
+        warn!(
+            "WHISPER_MODEL {} not found; voice messages disabled",
+            model.display()
+        );
+        return None;
+    }
+    let timeout = env_value("WHISPER_TIMEOUT_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Some(Arc::new(WhisperCppTranscriber {
+        bin: env_value("WHISPER_CLI_BIN").unwrap_or_else(|| "whisper-cli".into()),
+        model,
+        ffmpeg: env_value("FFMPEG_BIN").unwrap_or_else(|| "ffmpeg".into()),
+        threads: env_value("WHISPER_THREADS").and_then(|v| v.parse().ok()),
+        timeout: Duration::from_secs(timeout),
+    }))
+}
+
+async fn run(command: &mut Command, timeout: Duration, what: &str) -> Result<()> {
+    let output = tokio::time::timeout(
+        timeout,
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| anyhow!("{what} timed out"))?
+    .with_context(|| format!("failed to start {what}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{what} failed: {}", stderr.trim());
+    }
+    Ok(())
+}
+
+impl WhisperCppTranscriber {
+    async fn transcribe_in(
+        &self,
+        dir: &Path,
+        audio: &Path,
+        language: Option<&str>,
+    ) -> Result<Transcript> {
+        let wav = dir.join("audio.wav");
+        run(
+            Command::new(&self.ffmpeg)
+                .args(["-nostdin", "-loglevel", "error", "-y", "-i"])
+                .arg(audio)
+                .args(["-ar", "16000", "-ac", "1", "-c:a", "pcm_s16le"])
+                .arg(&wav),
+            self.timeout,
+            "ffmpeg",
+        )
+        .await?;
+
+        let prefix = dir.join("transcript");
+        let mut whisper = Command::new(&self.bin);
+        whisper
+            .arg("-m")
+            .arg(&self.model)
+            .arg("-f")
+            .arg(&wav)
+            .args(["-l", language.unwrap_or("auto"), "-nt", "-np", "-oj", "-of"])
+            .arg(&prefix);
+        if let Some(threads) = self.threads {
+            whisper.args(["-t", &threads.to_string()]);
+        }
+        run(&mut whisper, self.timeout, "whisper").await?;
+
+        let json = tokio::fs::read(prefix.with_extension("json")).await?;
+        parse_whisper_json(&json)
+    }
+}
+
+#[async_trait]
+impl Transcriber for WhisperCppTranscriber {
+    fn name(&self) -> &'static str {
+        "whisper.cpp"
+    }
+
+    async fn transcribe(&self, audio: &Path, language: Option<&str>) -> Result<Transcript> {
+        let dir = std::env::temp_dir().join(format!("whisper-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let result = self.transcribe_in(&dir, audio, language).await;
+        if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+            warn!("failed to remove {}: {err}", dir.display());
+        }
+        let transcript = result?;
+        info!(
+            chars = transcript.text.chars().count(),
+            language = transcript.language.as_deref().unwrap_or(""),
+            "audio transcribed"
+        );
+        Ok(transcript)
+    }
+}
+
+#[derive(Deserialize)]
+struct WhisperOutput {
+    #[serde(default)]
+    result: Option<WhisperResult>,
+    #[serde(default)]
+    transcription: Vec<WhisperSegment>,
+}
+
+#[derive(Deserialize)]
+struct WhisperResult {
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WhisperSegment {
+    text: String,
+}
+
+/// Text and language from whisper.cpp's `-oj` output.
+fn parse_whisper_json(json: &[u8]) -> Result<Transcript> {
+    let output: WhisperOutput = serde_json::from_slice(json)?;
+    let text = output
+        .transcription
+        .iter()
+        .map(|segment| segment.text.trim())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(Transcript {
+        text,
+        language: output
+            .result
+            .and_then(|r| r.language)
+            .filter(|lang| !lang.is_empty() && lang != "auto"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whisper_json_segments_are_joined() {
+        let json = r#"{
+            "result": {"language": "ru"},
+            "transcription": [
+                {"timestamps": {}, "offsets": {}, "text": " Привет,"},
+                {"timestamps": {}, "offsets": {}, "text": " как дела? "},
+                {"timestamps": {}, "offsets": {}, "text": " "}
+            ]
+        }"#;
+        let transcript = parse_whisper_json(json.as_bytes()).unwrap();
+        assert_eq!(transcript.text, "Привет, как дела?");
+        assert_eq!(transcript.language.as_deref(), Some("ru"));
+    }
+}
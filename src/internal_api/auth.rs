@@ -11,6 +11,8 @@ use serde::Deserialize;
 use std::{fs, path::Path};
 use tracing::{error, warn};
 
+use crate::audit::AdminActor;
+
 const AUTH_FILE: &str = "internal_admin_auth.json";
 static INTERNAL_AUTH: OnceCell<Option<InternalAuthConfig>> = OnceCell::new();
 
@@ -20,7 +22,10 @@ struct InternalAuthConfig {
     password: String,
 }
 
-pub async fn require_internal_auth(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
+pub async fn require_internal_auth(
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
     let Some(config) = auth_config() else {
         error!("internal admin credentials are missing; create internal_admin_auth.json");
         return Ok(internal_error_response());
@@ -55,6 +60,8 @@ pub async fn require_internal_auth(req: Request<Body>, next: Next) -> Result<Res
         return Ok(unauthorized_response());
     }
 
+    let actor = AdminActor(username.to_string());
+    req.extensions_mut().insert(actor);
     Ok(next.run(req).await)
 }
 
@@ -0,0 +1,432 @@
+//! Batched admin operations. Each request starts a background job and
+//! returns its id at once; the job record in RocksDB (`bulk_job:{id}`)
+//! carries the progress and is read back with
+//! `GET /internal/admin/jobs/{job_id}`.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditContext, AuditEntry},
+    auth::account::remove_stored_files,
+    db::SECONDARY_INDEXES,
+    model::{chat::Chat, user::UserRole},
+    ws::AppState,
+};
+
+/// Errors kept on a job record; later ones are only counted.
+const MAX_JOB_ERRORS: usize = 50;
+/// Most rows in one bulk role update.
+const MAX_ROLE_UPDATES: usize = 10_000;
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkJobKind {
+    DeleteOldChats,
+    PurgeDevice,
+    Reindex,
+    RoleUpdate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkJobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkJob {
+    pub job_id: String,
+    pub kind: BulkJobKind,
+    pub status: BulkJobStatus,
+    pub actor: String,
+    /// Parameters the job was started with.
+    pub params: Value,
+    /// Items to process; known once the job has listed them.
+    pub total: usize,
+    pub processed: usize,
+    pub failed: usize,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    pub started_ts: i64,
+    #[serde(default)]
+    pub finished_ts: Option<i64>,
+}
+
+/// Job record plus throttled saves while the job runs.
+struct Progress {
+    state: AppState,
+    job: BulkJob,
+    last_save: Instant,
+}
+
+impl Progress {
+    async fn save(&mut self) {
+        self.last_save = Instant::now();
+        if let Err(err) = self.state.db.save_bulk_job(&self.job).await {
+            warn!(
+                job_id = self.job.job_id.as_str(),
+                "bulk job save failed: {err}"
+            );
+        }
+    }
+
+    async fn set_total(&mut self, total: usize) {
+        self.job.total = total;
+        self.save().await;
+    }
+
+    async fn step(&mut self, item: &str, result: anyhow::Result<()>) {
+        self.job.processed += 1;
+        if let Err(err) = result {
+            self.job.failed += 1;
+            if self.job.errors.len() < MAX_JOB_ERRORS {
+                self.job.errors.push(format!("{item}: {err}"));
+            }
+        }
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save().await;
+        }
+    }
+
+    async fn finish(mut self, outcome: anyhow::Result<()>) {
+        self.job.status = match outcome {
+            Ok(()) => BulkJobStatus::Done,
+            Err(err) => {
+                self.job.errors.push(err.to_string());
+                BulkJobStatus::Failed
+            }
+        };
+        self.job.finished_ts = Some(chrono::Utc::now().timestamp());
+        info!(
+            job_id = self.job.job_id.as_str(),
+            kind = ?self.job.kind,
+            processed = self.job.processed,
+            failed = self.job.failed,
+            "bulk job finished"
+        );
+        self.save().await;
+    }
+}
+
+/// Save the job record, record the request in the audit log and run
+/// `work` in the background.
+async fn start_job<F, Fut>(
+    state: &AppState,
+    ctx: &AuditContext,
+    kind: BulkJobKind,
+    params: Value,
+    work: F,
+) -> Result<Json<BulkJob>, (StatusCode, String)>
+where
+    F: FnOnce(Progress) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let job = BulkJob {
+        job_id: Uuid::new_v4().to_string(),
+        kind,
+        status: BulkJobStatus::Running,
+        actor: ctx.admin_actor(),
+        params,
+        total: 0,
+        processed: 0,
+        failed: 0,
+        errors: Vec::new(),
+        started_ts: chrono::Utc::now().timestamp(),
+        finished_ts: None,
+    };
+    state
+        .db
+        .save_bulk_job(&job)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::BULK_JOB, ctx.admin_actor(), ctx)
+            .target(format!("job:{}", job.job_id))
+            .details(json!({ "kind": job.kind, "params": job.params })),
+    )
+    .await;
+
+    tokio::spawn(work(Progress {
+        state: state.clone(),
+        job: job.clone(),
+        last_save: Instant::now(),
+    }));
+    Ok(Json(job))
+}
+
+/// Delete chats with their messages and attachment files.
+async fn delete_chats(progress: &mut Progress, chats: Vec<Chat>) {
+    progress.set_total(chats.len()).await;
+    for chat in chats {
+        let result = async {
+            let db = &progress.state.db;
+            let files: HashSet<String> = db
+                .list_messages_for_chat(&chat.id)
+                .await?
+                .into_iter()
+                .flat_map(|msg| msg.attachments)
+                .filter_map(|att| att.path)
+                .collect();
+            db.delete_thread(&chat.id).await?;
+            remove_stored_files(files);
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        progress.step(&chat.id, result).await;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteOldChatsPayload {
+    pub older_than_days: u32,
+    /// Also delete internal evaluation chats.
+    #[serde(default)]
+    pub include_sandbox: bool,
+}
+
+/// Delete every chat not updated in the last `older_than_days` days.
+pub async fn admin_bulk_delete_old_chats(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(payload): Json<DeleteOldChatsPayload>,
+) -> Result<Json<BulkJob>, (StatusCode, String)> {
+    if payload.older_than_days == 0 {
+        return Err((StatusCode::BAD_REQUEST, "invalid_older_than_days".into()));
+    }
+    let cutoff = chrono::Utc::now().timestamp() - i64::from(payload.older_than_days) * 24 * 60 * 60;
+    let include_sandbox = payload.include_sandbox;
+    let params = json!({
+        "older_than_days": payload.older_than_days,
+        "include_sandbox": include_sandbox,
+        "cutoff_ts": cutoff,
+    });
+    start_job(
+        &state,
+        &ctx,
+        BulkJobKind::DeleteOldChats,
+        params,
+        move |mut progress| async move {
+            let outcome = match progress.state.db.list_chats().await {
+                Ok(chats) => {
+                    let old = chats
+                        .into_iter()
+                        .filter(|c| c.updated_ts < cutoff && (include_sandbox || !c.sandbox))
+                        .collect();
+                    delete_chats(&mut progress, old).await;
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            };
+            progress.finish(outcome).await;
+        },
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeDevicePayload {
+    pub device_hash: String,
+}
+
+/// Delete every chat of a device hash.
+pub async fn admin_bulk_purge_device(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(payload): Json<PurgeDevicePayload>,
+) -> Result<Json<BulkJob>, (StatusCode, String)> {
+    let device_hash = payload.device_hash.trim().to_string();
+    if device_hash.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "missing_device_hash".into()));
+    }
+    let params = json!({ "device_hash": device_hash });
+    start_job(
+        &state,
+        &ctx,
+        BulkJobKind::PurgeDevice,
+        params,
+        move |mut progress| async move {
+            let outcome = match progress.state.db.list_chats_for_device(&device_hash).await {
+                Ok(chats) => {
+                    delete_chats(&mut progress, chats).await;
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            };
+            progress.finish(outcome).await;
+        },
+    )
+    .await
+}
+
+/// Drop and rebuild the user-chat, device-chat and message search indexes.
+pub async fn admin_bulk_reindex(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+) -> Result<Json<BulkJob>, (StatusCode, String)> {
+    start_job(
+        &state,
+        &ctx,
+        BulkJobKind::Reindex,
+        json!({ "indexes": SECONDARY_INDEXES }),
+        |mut progress| async move {
+            progress.set_total(SECONDARY_INDEXES.len()).await;
+            for index in SECONDARY_INDEXES {
+                let result = progress.state.db.rebuild_index(index).await;
+                progress.step(index, result).await;
+            }
+            progress.finish(Ok(())).await;
+        },
+    )
+    .await
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoleUpdate {
+    pub user_id: String,
+    pub role: UserRole,
+}
+
+/// `user_id,role` lines; a header line and blank lines are skipped.
+fn parse_role_csv(body: &str) -> Result<Vec<RoleUpdate>, String> {
+    let mut updates = Vec::new();
+    for (n, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (n == 0 && line.eq_ignore_ascii_case("user_id,role")) {
+            continue;
+        }
+        let (user_id, role) = line
+            .split_once(',')
+            .ok_or_else(|| format!("line {}: expected user_id,role", n + 1))?;
+        let role = serde_json::from_value(Value::String(role.trim().to_lowercase()))
+            .map_err(|_| format!("line {}: unknown role", n + 1))?;
+        updates.push(RoleUpdate {
+            user_id: user_id.trim().to_string(),
+            role,
+        });
+    }
+    Ok(updates)
+}
+
+/// Set roles from a JSON array of `{ user_id, role }` or, with
+/// `Content-Type: text/csv`, `user_id,role` lines.
+pub async fn admin_bulk_update_roles(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<BulkJob>, (StatusCode, String)> {
+    let is_csv = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/csv"));
+    let updates = if is_csv {
+        let text = std::str::from_utf8(&body)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "invalid_role_updates".to_string()))?;
+        parse_role_csv(text)
+    } else {
+        serde_json::from_slice::<Vec<RoleUpdate>>(&body).map_err(|e| e.to_string())
+    }
+    .map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("invalid_role_updates: {e}"),
+        )
+    })?;
+    if updates.is_empty() || updates.len() > MAX_ROLE_UPDATES {
+        return Err((StatusCode::BAD_REQUEST, "invalid_role_updates".into()));
+    }
+
+    let audit_ctx = ctx.clone();
+    let params = json!({ "count": updates.len() });
+    start_job(
+        &state,
+        &ctx,
+        BulkJobKind::RoleUpdate,
+        params,
+        move |mut progress| async move {
+            progress.set_total(updates.len()).await;
+            for update in updates {
+                let result = async {
+                    let db = &progress.state.db;
+                    let mut user = db
+                        .load_user(&update.user_id)
+                        .await?
+                        .ok_or_else(|| anyhow::anyhow!("user_not_found"))?;
+                    if user.role == update.role {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                    let previous = std::mem::replace(&mut user.role, update.role);
+                    db.save_user(&user).await?;
+                    audit::record(
+                        db,
+                        AuditEntry::new(audit::ROLE_CHANGE, audit_ctx.admin_actor(), &audit_ctx)
+                            .target(format!("user:{}", user.id))
+                            .details(json!({
+                                "from": previous,
+                                "to": user.role,
+                                "job_id": progress.job.job_id,
+                            })),
+                    )
+                    .await;
+                    Ok(())
+                }
+                .await;
+                progress.step(&update.user_id, result).await;
+            }
+            progress.finish(Ok(())).await;
+        },
+    )
+    .await
+}
+
+/// Progress of a bulk job.
+pub async fn admin_get_bulk_job(
+    Path(job_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<BulkJob>, (StatusCode, String)> {
+    state
+        .db
+        .load_bulk_job(&job_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "job_not_found".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_role_csv() {
+        let updates = parse_role_csv("user_id,role\nu1, Paid\n\nu2,free\n").unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].user_id, "u1");
+        assert_eq!(updates[0].role, UserRole::Paid);
+        assert_eq!(updates[1].role, UserRole::Free);
+
+        assert_eq!(
+            parse_role_csv("u1,owner").unwrap_err(),
+            "line 1: unknown role"
+        );
+        assert!(parse_role_csv("u1").is_err());
+    }
+}
@@ -1,25 +1,50 @@
 use crate::{
+    agent::{
+        runs,
+        sandbox::{Sandbox, ToolPolicy},
+    },
+    attachments::storage_root,
+    audit::{self, AuditContext, AuditEntry},
+    auth::account::delete_account,
+    canary,
+    classifier::routing::{prompt_key_routes, rules as routing_rules, PromptKeyRoute},
+    conversation::{language, MAX_SYSTEM_PROMPT_OVERRIDE_CHARS},
+    db::search as db_search,
+    experiments,
+    maintenance::MaintenanceWindow,
+    manager::PRIMARY_GENERATOR,
     model::{
         chat::Chat,
-        message::Message,
+        message::{FeedbackKind, FeedbackReason, Message, MessageFeedback},
+        routing::{RoutingFeedback, RoutingRecord},
         user::{User, UserRole},
     },
+    prompts,
+    retention::{self, RetentionOverride, RetentionReport},
+    storage::gc::{collect_garbage, FileGcReport},
     ws::AppState,
 };
 
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
-    response::Html,
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse,
+    },
     Json,
 };
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{cmp::Reverse, collections::HashMap};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
+};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, ToSchema)]
 pub struct MessagesResponse {
     pub chat_id: String,
     pub messages: Vec<Message>,
@@ -30,6 +55,7 @@ pub struct AdminOverview {
     pub total_users: usize,
     pub total_devices: usize,
     pub total_chats: usize,
+    pub sandbox_chats: usize,
     pub total_messages: usize,
     pub liked_messages: usize,
     pub recent_chats: Vec<AdminChatSummary>,
@@ -45,18 +71,122 @@ pub struct AdminChatSummary {
     pub updated_ts: i64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SummaryUpdatePayload {
     pub summary: String,
     #[serde(default)]
     pub language: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Empty or `null` clears the override.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SystemPromptPayload {
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+}
+
+/// `null` unpins the chat and keeps its current language.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChatLanguagePayload {
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+/// Omitted fields are left as they are; an empty `folder` removes the chat
+/// from its folder.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChatOrganizePayload {
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub pinned: Option<bool>,
+    #[serde(default)]
+    pub archived: Option<bool>,
+}
+
+const MAX_FOLDER_CHARS: usize = 64;
+
+/// Filters and order for the chat lists. Without filters every chat is
+/// listed, newest first.
+#[derive(Debug, Default, Deserialize)]
+pub struct ChatListQuery {
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub pinned: Option<bool>,
+    #[serde(default)]
+    pub archived: Option<bool>,
+    /// `updated` (default), `pinned` (pinned first), `folder` or `title`.
+    #[serde(default)]
+    pub sort: Option<String>,
+}
+
+impl ChatListQuery {
+    fn apply(&self, chats: &mut Vec<Chat>) -> Result<(), (StatusCode, String)> {
+        let folder = self.folder.as_deref().map(str::trim);
+        chats.retain(|c| {
+            folder.map_or(true, |f| c.folder.as_deref().unwrap_or_default() == f)
+                && self.pinned.map_or(true, |p| c.pinned == p)
+                && self.archived.map_or(true, |a| c.archived == a)
+        });
+        chats.sort_by_key(|c| Reverse(c.updated_ts));
+        match self.sort.as_deref().unwrap_or("updated") {
+            "updated" => {}
+            "pinned" => chats.sort_by_key(|c| !c.pinned),
+            // Chats outside any folder go last.
+            "folder" => chats.sort_by(|a, b| match (&a.folder, &b.folder) {
+                (Some(a), Some(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }),
+            "title" => chats.sort_by_key(|c| c.title.as_deref().unwrap_or_default().to_lowercase()),
+            _ => return Err((StatusCode::BAD_REQUEST, "invalid_sort".into())),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct MessageLikePayload {
     pub liked: bool,
 }
 
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MessageFeedbackPayload {
+    /// `null` clears the feedback.
+    #[serde(default)]
+    pub kind: Option<FeedbackKind>,
+    #[serde(default)]
+    pub reasons: Vec<FeedbackReason>,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+const MAX_FEEDBACK_COMMENT_CHARS: usize = 1000;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RoutingFeedbackPayload {
+    /// Defaults to `true`; send `false` to clear an earlier misroute label.
+    #[serde(default = "default_misroute")]
+    pub misroute: bool,
+    #[serde(default)]
+    pub expected_prompt_key: Option<String>,
+    #[serde(default)]
+    pub expected_speech_act: Option<String>,
+    #[serde(default)]
+    pub expected_domain: Option<String>,
+    #[serde(default)]
+    pub expected_expectation: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// `admin` (default) or `user`.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+fn default_misroute() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UpdateUserRolePayload {
     pub role: UserRole,
@@ -72,6 +202,45 @@ fn default_latest_limit() -> usize {
     25
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    50
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptKeyInfo {
+    pub key: String,
+    pub is_default: bool,
+    /// Template per language; languages without their own entry fall back
+    /// to the default key and are listed in `missing_languages`.
+    pub templates: BTreeMap<String, String>,
+    pub missing_languages: Vec<String>,
+    pub routes: Vec<PromptKeyRoute>,
+    pub usage_total: u64,
+    pub usage_by_language: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SandboxChatPayload {
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/internal/chat-thread/{chat_id}/summary",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    request_body = SummaryUpdatePayload,
+    responses((status = 200, description = "`{ chat_id, message_id, updated }`"))
+)]
 pub async fn update_summary(
     Path(chat_id): Path<String>,
     State(state): State<AppState>,
@@ -104,6 +273,7 @@ pub async fn update_summary(
         language: payload.language.clone(),
         attachments: Vec::new(),
         liked: false,
+        feedback: None,
         ts: Utc::now().timestamp(),
         meta: None,
     };
@@ -122,6 +292,172 @@ pub async fn update_summary(
     }
 }
 
+fn system_prompt_override(
+    payload: SystemPromptPayload,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let prompt = payload
+        .system_prompt
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty());
+    if prompt
+        .as_ref()
+        .is_some_and(|p| p.chars().count() > MAX_SYSTEM_PROMPT_OVERRIDE_CHARS)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "system_prompt_too_long".to_string(),
+        ));
+    }
+    Ok(prompt)
+}
+
+#[utoipa::path(
+    put,
+    path = "/internal/chat-thread/{chat_id}/system-prompt",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    request_body = SystemPromptPayload,
+    responses(
+        (status = 200, description = "`{ chat_id, system_prompt }`"),
+        (status = 400, description = "`system_prompt_too_long`"),
+        (status = 404, description = "`chat_not_found`")
+    )
+)]
+pub async fn update_chat_system_prompt(
+    Path(chat_id): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<SystemPromptPayload>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let prompt = system_prompt_override(payload)?;
+    let mut chat = state
+        .db
+        .load_chat(&chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "chat_not_found".to_string()))?;
+
+    chat.system_prompt_override = prompt;
+    state
+        .db
+        .save_chat(&chat)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(json!({
+        "chat_id": chat.id,
+        "system_prompt": chat.system_prompt_override,
+    })))
+}
+
+/// Same as the ws `set_language` frame: the chat keeps this language even
+/// when later turns are in another one.
+#[utoipa::path(
+    put,
+    path = "/internal/chat-thread/{chat_id}/language",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    request_body = ChatLanguagePayload,
+    responses(
+        (status = 200, description = "`{ chat_id, language, pinned }`"),
+        (status = 400, description = "`invalid_language`"),
+        (status = 404, description = "`chat_not_found`")
+    )
+)]
+pub async fn update_chat_language(
+    Path(chat_id): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<ChatLanguagePayload>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pinned = match payload.language {
+        Some(lang) => Some(
+            language::normalize(&lang)
+                .filter(|code| code.len() <= 8 && code.bytes().all(|b| b.is_ascii_lowercase()))
+                .ok_or((StatusCode::BAD_REQUEST, "invalid_language".to_string()))?,
+        ),
+        None => None,
+    };
+    let mut chat = state
+        .db
+        .load_chat(&chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "chat_not_found".to_string()))?;
+
+    language::pin(&mut chat.language, pinned, Utc::now().timestamp());
+    state
+        .db
+        .save_chat(&chat)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(json!({
+        "chat_id": chat.id,
+        "language": chat.language.locked,
+        "pinned": chat.language.pinned,
+    })))
+}
+
+#[utoipa::path(
+    put,
+    path = "/internal/chat-thread/{chat_id}/organize",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    request_body = ChatOrganizePayload,
+    responses(
+        (status = 200, description = "`{ chat_id, folder, pinned, archived }`"),
+        (status = 400, description = "`folder_too_long`"),
+        (status = 404, description = "`chat_not_found`")
+    )
+)]
+pub async fn organize_chat(
+    Path(chat_id): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<ChatOrganizePayload>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let folder = payload.folder.map(|f| f.trim().to_string());
+    if folder
+        .as_ref()
+        .is_some_and(|f| f.chars().count() > MAX_FOLDER_CHARS)
+    {
+        return Err((StatusCode::BAD_REQUEST, "folder_too_long".to_string()));
+    }
+    let mut chat = state
+        .db
+        .load_chat(&chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "chat_not_found".to_string()))?;
+
+    if let Some(folder) = folder {
+        chat.folder = Some(folder).filter(|f| !f.is_empty());
+    }
+    if let Some(pinned) = payload.pinned {
+        chat.pinned = pinned;
+    }
+    if let Some(archived) = payload.archived {
+        chat.archived = archived;
+    }
+    state
+        .db
+        .save_chat(&chat)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(json!({
+        "chat_id": chat.id,
+        "folder": chat.folder,
+        "pinned": chat.pinned,
+        "archived": chat.archived,
+    })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/internal/chat-thread/{chat_id}",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    responses((status = 200, description = "`{ chat_id, messages }` ordered by timestamp"))
+)]
 pub async fn get_thread(
     Path(chat_id): Path<String>,
     State(state): State<AppState>,
@@ -143,6 +479,13 @@ pub async fn get_thread(
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/internal/chat-thread/{chat_id}",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    responses((status = 200, description = "`{ chat_id, deleted }`"))
+)]
 pub async fn delete_thread(
     Path(chat_id): Path<String>,
     State(state): State<AppState>,
@@ -161,13 +504,30 @@ pub async fn delete_thread(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/internal/chats/by-device/{device_hash}",
+    tag = "chats",
+    params(
+        ("device_hash" = String, Path, description = "Device hash"),
+        ("folder" = Option<String>, Query, description = "Only chats in this folder; empty for chats outside folders"),
+        ("pinned" = Option<bool>, Query, description = "Only pinned or unpinned chats"),
+        ("archived" = Option<bool>, Query, description = "Only archived or unarchived chats"),
+        ("sort" = Option<String>, Query, description = "`updated` (default, newest first), `pinned`, `folder` or `title`"),
+    ),
+    responses(
+        (status = 200, description = "Chats of a device"),
+        (status = 400, description = "`invalid_sort`")
+    )
+)]
 pub async fn list_chats_by_device(
     Path(device_hash): Path<String>,
+    Query(query): Query<ChatListQuery>,
     State(state): State<AppState>,
-) -> Json<serde_json::Value> {
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     match state.db.list_chats_for_device(&device_hash).await {
         Ok(mut chats) => {
-            chats.sort_by_key(|c| Reverse(c.updated_ts));
+            query.apply(&mut chats)?;
             let mut rows = Vec::with_capacity(chats.len());
             for chat in chats {
                 let summary_text = state
@@ -189,20 +549,30 @@ pub async fn list_chats_by_device(
                     "user_id": chat.user_id,
                     "device_hash": chat.device_hash,
                     "updated_ts": chat.updated_ts,
+                    "folder": chat.folder,
+                    "pinned": chat.pinned,
+                    "archived": chat.archived,
                     "meta": chat.meta
                 }));
             }
 
-            Json(json!({ "device_hash": device_hash, "chats": rows }))
+            Ok(Json(json!({ "device_hash": device_hash, "chats": rows })))
         }
-        Err(e) => Json(json!({
+        Err(e) => Ok(Json(json!({
             "device_hash": device_hash,
             "chats": [],
             "error": e.to_string()
-        })),
+        }))),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/internal/messages/by-device/{device_hash}",
+    tag = "chats",
+    params(("device_hash" = String, Path, description = "Device hash")),
+    responses((status = 200, description = "Messages across all chats of a device"))
+)]
 pub async fn list_messages_by_device(
     Path(device_hash): Path<String>,
     State(state): State<AppState>,
@@ -229,6 +599,13 @@ pub async fn list_messages_by_device(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/chats/{chat_id}/messages",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    responses((status = 200, description = "Messages of a chat", body = MessagesResponse))
+)]
 pub async fn list_messages_for_chat(
     State(state): State<AppState>,
     Path(chat_id): Path<String>,
@@ -247,6 +624,13 @@ pub async fn list_messages_for_chat(
     })
 }
 
+#[utoipa::path(
+    delete,
+    path = "/internal/chat-thread/{chat_id}/message/{message_id}",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id"), ("message_id" = String, Path, description = "Message id")),
+    responses((status = 200, description = "`{ chat_id, message_id, deleted }`"))
+)]
 pub async fn delete_message(
     Path((chat_id, message_id)): Path<(String, String)>,
     State(state): State<AppState>,
@@ -272,23 +656,35 @@ pub async fn delete_message(
     }
 }
 
+/// Shorthand for `feedback`: `true` stores a like, `false` clears whatever
+/// feedback the message has.
+#[utoipa::path(
+    put,
+    path = "/internal/chat-thread/{chat_id}/message/{message_id}/liked",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id"), ("message_id" = String, Path, description = "Message id")),
+    request_body = MessageLikePayload,
+    responses((status = 200, description = "`{ chat_id, message_id, liked, updated }`"))
+)]
 pub async fn set_message_liked(
     Path((chat_id, message_id)): Path<(String, String)>,
     State(state): State<AppState>,
     Json(payload): Json<MessageLikePayload>,
 ) -> Json<serde_json::Value> {
-    match state
-        .db
-        .set_message_liked(&chat_id, &message_id, payload.liked)
-        .await
-    {
-        Ok(true) => Json(json!({
+    let feedback = payload.liked.then(|| MessageFeedback {
+        kind: FeedbackKind::Liked,
+        reasons: Vec::new(),
+        comment: None,
+        ts: Utc::now().timestamp(),
+    });
+    match store_message_feedback(&state, &chat_id, &message_id, feedback).await {
+        Ok(Some(_)) => Json(json!({
             "chat_id": chat_id,
             "message_id": message_id,
             "liked": payload.liked,
             "updated": true
         })),
-        Ok(false) => Json(json!({
+        Ok(None) => Json(json!({
             "chat_id": chat_id,
             "message_id": message_id,
             "liked": payload.liked,
@@ -305,6 +701,82 @@ pub async fn set_message_liked(
     }
 }
 
+/// Store a message's feedback and count like changes for the experiment
+/// arms the message was tagged with. `Ok(None)` when the message does not
+/// exist.
+async fn store_message_feedback(
+    state: &AppState,
+    chat_id: &str,
+    message_id: &str,
+    feedback: Option<MessageFeedback>,
+) -> anyhow::Result<Option<Message>> {
+    let liked = feedback
+        .as_ref()
+        .is_some_and(|f| f.kind == FeedbackKind::Liked);
+    let Some(previous) = state
+        .db
+        .set_message_feedback(chat_id, message_id, feedback)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let arms = experiments::arms_from_meta(previous.meta.as_ref());
+    if previous.liked != liked && !arms.is_empty() {
+        let outcome = if liked {
+            experiments::Outcome::Like
+        } else {
+            experiments::Outcome::Unlike
+        };
+        experiments::record(&state.db, &arms, outcome, 1).await;
+    }
+    Ok(Some(previous))
+}
+
+/// Like or dislike a reply, with reason codes and a comment for dislikes.
+/// Repeated reasons are dropped and the comment is cut to 1000 characters.
+#[utoipa::path(
+    put,
+    path = "/internal/chat-thread/{chat_id}/message/{message_id}/feedback",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id"), ("message_id" = String, Path, description = "Message id")),
+    request_body = MessageFeedbackPayload,
+    responses(
+        (status = 200, description = "`{ chat_id, message_id, liked, feedback }`"),
+        (status = 404, description = "message_not_found"),
+    )
+)]
+pub async fn set_message_feedback(
+    Path((chat_id, message_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(payload): Json<MessageFeedbackPayload>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let feedback = payload.kind.map(|kind| {
+        let mut reasons = Vec::new();
+        for reason in payload.reasons {
+            if !reasons.contains(&reason) {
+                reasons.push(reason);
+            }
+        }
+        MessageFeedback {
+            kind,
+            reasons,
+            comment: non_empty(payload.comment)
+                .map(|c| c.chars().take(MAX_FEEDBACK_COMMENT_CHARS).collect()),
+            ts: Utc::now().timestamp(),
+        }
+    });
+    store_message_feedback(&state, &chat_id, &message_id, feedback.clone())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "message_not_found".to_string()))?;
+    Ok(Json(json!({
+        "chat_id": chat_id,
+        "message_id": message_id,
+        "liked": payload.kind == Some(FeedbackKind::Liked),
+        "feedback": feedback,
+    })))
+}
+
 /// Ensure a chat exists for the given id/device; create one if missing.
 pub async fn ensure_chat_for_device(
     db: &crate::db::DBLayer,
@@ -328,21 +800,45 @@ pub async fn ensure_chat_for_device(
         device_hash: Some(device_hash.to_string()),
         updated_ts: chrono::Utc::now().timestamp(),
         meta: None,
+        sandbox: false,
+        language: Default::default(),
+        system_prompt_override: None,
+        folder: None,
+        pinned: false,
+        archived: false,
     };
     db.save_chat(&chat).await?;
     Ok(new_id)
 }
 
+#[utoipa::path(
+    get,
+    path = "/internal/chats/by-user/{user_id}",
+    tag = "chats",
+    params(
+        ("user_id" = String, Path, description = "User id"),
+        ("folder" = Option<String>, Query, description = "Only chats in this folder; empty for chats outside folders"),
+        ("pinned" = Option<bool>, Query, description = "Only pinned or unpinned chats"),
+        ("archived" = Option<bool>, Query, description = "Only archived or unarchived chats"),
+        ("sort" = Option<String>, Query, description = "`updated` (default, newest first), `pinned`, `folder` or `title`"),
+    ),
+    responses(
+        (status = 200, description = "`{ user_id, count, chats }`"),
+        (status = 400, description = "`invalid_sort`")
+    )
+)]
 pub async fn list_chats_by_user(
     State(state): State<AppState>,
     Path(user_id): Path<String>,
+    Query(query): Query<ChatListQuery>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
     // Collect all chats (explicit + devices)
-    let chats = state
+    let mut chats = state
         .db
         .list_chats_for_user(&user_id)
         .await
         .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    query.apply(&mut chats)?;
 
     Ok(Json(serde_json::json!({
         "user_id": user_id,
@@ -371,6 +867,69 @@ pub async fn admin_latest_messages(
     }
 }
 
+/// Messages containing every word of `q`, grouped by chat. Chats are
+/// ordered by their newest match; each match carries an HTML-escaped
+/// `highlight` with the matching words in `<mark>`.
+pub async fn admin_search(
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let terms = db_search::terms(&query.q);
+    if terms.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "empty_query".into()));
+    }
+    let limit = query.limit.clamp(1, 200);
+    let messages = state
+        .db
+        .search_messages(&terms, limit)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut order: Vec<String> = Vec::new();
+    let mut grouped: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    for message in &messages {
+        let text = message.text.as_deref().unwrap_or_default();
+        if !grouped.contains_key(&message.chat_id) {
+            order.push(message.chat_id.clone());
+        }
+        grouped
+            .entry(message.chat_id.clone())
+            .or_default()
+            .push(json!({
+                "message_id": message.id,
+                "role": message.role,
+                "language": message.language,
+                "ts": message.ts,
+                "highlight": db_search::highlight(text, &terms),
+            }));
+    }
+
+    let mut chats = Vec::with_capacity(order.len());
+    for chat_id in order {
+        let chat = state
+            .db
+            .load_chat(&chat_id)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        chats.push(json!({
+            "chat_id": chat_id,
+            "title": chat.as_ref().and_then(|c| c.title.clone()),
+            "user_id": chat.as_ref().and_then(|c| c.user_id.clone()),
+            "device_hash": chat.as_ref().and_then(|c| c.device_hash.clone()),
+            "sandbox": chat.as_ref().is_some_and(|c| c.sandbox),
+            "matches": grouped.remove(&chat_id).unwrap_or_default(),
+        }));
+    }
+
+    Ok(Json(json!({
+        "query": query.q,
+        "terms": terms,
+        "limit": limit,
+        "count": messages.len(),
+        "chats": chats,
+    })))
+}
+
 pub async fn admin_page() -> Html<&'static str> {
     Html(include_str!("admin.html"))
 }
@@ -398,6 +957,8 @@ pub async fn admin_list_users(State(state): State<AppState>) -> Json<serde_json:
                 "can_generate": user.can_generate_now(),
                 "stripe_customer_id": user.stripe_customer_id,
                 "stripe_subscription_id": user.stripe_subscription_id,
+                "plan": user.plan,
+                "plan_history": user.plan_history,
             })
         })
         .collect();
@@ -411,6 +972,7 @@ pub async fn admin_list_users(State(state): State<AppState>) -> Json<serde_json:
 pub async fn admin_update_user_role(
     Path(user_id): Path<String>,
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(payload): Json<UpdateUserRolePayload>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let mut user = state
@@ -420,12 +982,20 @@ pub async fn admin_update_user_role(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
         .ok_or((StatusCode::NOT_FOUND, "user_not_found".to_string()))?;
 
+    let previous = user.role.clone();
     user.role = payload.role;
     state
         .db
         .save_user(&user)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::ROLE_CHANGE, ctx.admin_actor(), &ctx)
+            .target(format!("user:{}", user.id))
+            .details(json!({ "from": previous, "to": user.role })),
+    )
+    .await;
 
     Ok(Json(json!({
         "user_id": user.id,
@@ -437,72 +1007,182 @@ pub async fn admin_update_user_role(
     })))
 }
 
-pub async fn admin_delete_user(
+/// Per-user system prompt override, kept in `meta.system_prompt` and
+/// applied to all of the user's chats before the per-chat one.
+pub async fn admin_update_user_system_prompt(
     Path(user_id): Path<String>,
     State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(payload): Json<SystemPromptPayload>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let prompt = system_prompt_override(payload)?;
+    let mut user = state
+        .db
+        .load_user(&user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "user_not_found".to_string()))?;
+
+    let mut meta = match user.meta.take() {
+        Some(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    match &prompt {
+        Some(prompt) => {
+            meta.insert("system_prompt".into(), json!(prompt));
+        }
+        None => {
+            meta.remove("system_prompt");
+        }
+    }
+    user.meta = Some(serde_json::Value::Object(meta));
     state
         .db
-        .delete_user(&user_id)
+        .save_user(&user)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::SYSTEM_PROMPT_EDIT, ctx.admin_actor(), &ctx)
+            .target(format!("user:{}", user.id))
+            .details(json!({ "system_prompt": prompt })),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "user_id": user.id,
+        "system_prompt": prompt,
+    })))
+}
+
+pub async fn admin_delete_user(
+    Path(user_id): Path<String>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user = state
+        .db
+        .load_user(&user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "user_not_found".to_string()))?;
+
+    let report = delete_account(&state, &user)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
+    tracing::info!(
+        target: "audit",
+        user_id = %user_id,
+        chats = report.chats,
+        messages = report.messages,
+        files = report.files,
+        "account deleted by admin"
+    );
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::USER_DELETE, ctx.admin_actor(), &ctx)
+            .target(format!("user:{user_id}"))
+            .details(json!({ "email": user.email, "removed": report })),
+    )
+    .await;
+
     Ok(Json(json!({
         "user_id": user_id,
-        "deleted": true
+        "deleted": true,
+        "removed": report
     })))
 }
 
 pub async fn admin_overview(State(state): State<AppState>) -> Json<AdminOverview> {
     let users = state.db.list_users().await.unwrap_or_default();
     let devices = state.db.list_all_devices().await.unwrap_or_default();
-    let chats = state.db.list_chats().await.unwrap_or_default();
-
-    let mut total_messages = 0usize;
-    let mut liked_messages = 0usize;
-    let mut chat_rows = Vec::new();
-
-    for chat in chats.iter() {
-        let messages = state
-            .db
-            .list_messages_for_chat(&chat.id)
-            .await
-            .unwrap_or_default();
-        let liked_count = messages.iter().filter(|m| m.liked).count();
-        let message_count = messages.len();
-
-        total_messages += message_count;
-        liked_messages += liked_count;
+    let (sandbox, chats): (Vec<Chat>, Vec<Chat>) = state
+        .db
+        .list_chats()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .partition(|chat| chat.sandbox);
 
-        let summary_text = messages
-            .iter()
-            .rev()
-            .find(|m| m.role == "summary")
-            .and_then(|m| m.text.clone());
+    // Counts are kept up to date on write; no message is read here.
+    let totals = state.db.message_totals().await.unwrap_or_default();
+    let mut recent: Vec<&Chat> = chats.iter().collect();
+    recent.sort_by_key(|c| Reverse(c.updated_ts));
+    recent.truncate(25);
 
+    let mut chat_rows = Vec::with_capacity(recent.len());
+    for chat in recent {
+        let stats = state.db.chat_stats(&chat.id).await.unwrap_or_default();
         chat_rows.push(AdminChatSummary {
             chat_id: chat.id.clone(),
-            summary: summary_text,
+            summary: stats.summary.and_then(|s| s.text),
             device_hash: chat.device_hash.clone(),
-            message_count,
-            liked_count,
+            message_count: stats.counts.messages as usize,
+            liked_count: stats.counts.liked as usize,
             updated_ts: chat.updated_ts,
         });
     }
 
-    chat_rows.sort_by_key(|c| Reverse(c.updated_ts));
-    chat_rows.truncate(25);
-
     Json(AdminOverview {
         total_users: users.len(),
         total_devices: devices.len(),
         total_chats: chats.len(),
-        total_messages,
-        liked_messages,
+        sandbox_chats: sandbox.len(),
+        total_messages: totals.messages as usize,
+        liked_messages: totals.liked as usize,
         recent_chats: chat_rows,
     })
 }
 
+pub async fn admin_list_sandbox_chats(State(state): State<AppState>) -> Json<serde_json::Value> {
+    match state.db.list_sandbox_chats().await {
+        Ok(mut chats) => {
+            chats.sort_by_key(|c| Reverse(c.updated_ts));
+            Json(json!({
+                "count": chats.len(),
+                "chats": chats
+            }))
+        }
+        Err(err) => Json(json!({
+            "count": 0,
+            "chats": [],
+            "error": err.to_string()
+        })),
+    }
+}
+
+pub async fn admin_create_sandbox_chat(
+    State(state): State<AppState>,
+    Json(payload): Json<SandboxChatPayload>,
+) -> Result<Json<Chat>, (StatusCode, String)> {
+    let chat = Chat {
+        id: Uuid::new_v4().to_string(),
+        title: payload
+            .title
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty()),
+        user_id: None,
+        device_hash: None,
+        updated_ts: Utc::now().timestamp(),
+        meta: None,
+        sandbox: true,
+        language: Default::default(),
+        system_prompt_override: None,
+        folder: None,
+        pinned: false,
+        archived: false,
+    };
+
+    state
+        .db
+        .save_chat(&chat)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(chat))
+}
+
 pub async fn admin_devices_page() -> Html<&'static str> {
     Html(include_str!("devices.html"))
 }
@@ -553,3 +1233,814 @@ pub async fn admin_list_devices(State(state): State<AppState>) -> Json<serde_jso
         "devices": rows
     }))
 }
+
+/// Report orphaned messages, ownerless chats and unreferenced files
+/// without touching them.
+pub async fn admin_integrity_check(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    integrity_check(&state, false).await
+}
+
+/// Delete what the integrity check reports. POST only, so a link or a
+/// prefetch cannot trigger it.
+pub async fn admin_integrity_repair(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    integrity_check(&state, true).await
+}
+
+async fn integrity_check(
+    state: &AppState,
+    repair: bool,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let root = storage_root();
+    let report = state
+        .db
+        .check_integrity(Some(root.as_path()), repair)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(json!({
+        "clean": report.is_clean(),
+        "storage_root": root.display().to_string(),
+        "report": report,
+    })))
+}
+
+/// Dry run of the file garbage collector: orphaned files older than the
+/// grace period and the space deleting them would free.
+pub async fn admin_file_gc_report(
+    State(state): State<AppState>,
+) -> Result<Json<FileGcReport>, (StatusCode, String)> {
+    collect_garbage(&state.db, &storage_root(), true)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Run the file garbage collector now.
+pub async fn admin_run_file_gc(
+    State(state): State<AppState>,
+) -> Result<Json<FileGcReport>, (StatusCode, String)> {
+    collect_garbage(&state.db, &storage_root(), false)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Dry run of retention: the chats and messages the next run would
+/// delete under the current policy and overrides.
+pub async fn admin_retention_report(
+    State(state): State<AppState>,
+) -> Result<Json<RetentionReport>, (StatusCode, String)> {
+    retention::enforce(&state.db, true)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Apply the retention policy now.
+pub async fn admin_run_retention(
+    State(state): State<AppState>,
+) -> Result<Json<RetentionReport>, (StatusCode, String)> {
+    retention::enforce(&state.db, false)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+pub async fn admin_list_retention_overrides(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<RetentionOverride>>, (StatusCode, String)> {
+    state
+        .db
+        .list_retention_overrides()
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionOverridePayload {
+    /// Days the user's messages are kept, `0` for ever; `null` drops the
+    /// override and restores the plan's policy.
+    #[serde(default)]
+    pub message_days: Option<u32>,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Give one user retention terms other than their plan's.
+pub async fn admin_set_retention_override(
+    Path(user_id): Path<String>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(payload): Json<RetentionOverridePayload>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    state
+        .db
+        .load_user(&user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "user_not_found".to_string()))?;
+
+    let previous = state
+        .db
+        .list_retention_overrides()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .into_iter()
+        .find(|o| o.user_id == user_id)
+        .map(|o| o.message_days);
+    let item = payload.message_days.map(|message_days| RetentionOverride {
+        user_id: user_id.clone(),
+        message_days,
+        note: payload
+            .note
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty()),
+        updated_ts: Utc::now().timestamp(),
+    });
+    let stored = match &item {
+        Some(item) => state.db.save_retention_override(item).await,
+        None => state.db.delete_retention_override(&user_id).await,
+    };
+    stored.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::RETENTION_OVERRIDE, ctx.admin_actor(), &ctx)
+            .target(format!("user:{user_id}"))
+            .details(json!({
+                "from": previous,
+                "to": item.as_ref().map(|i| i.message_days),
+            })),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "override": item,
+    })))
+}
+
+pub async fn admin_canary_report(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let report = state
+        .db
+        .load_canary_report()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(json!({
+        "fingerprint": canary::config_fingerprint(),
+        "last_run": report,
+    })))
+}
+
+pub async fn admin_run_canary(
+    State(state): State<AppState>,
+) -> Result<Json<canary::CanaryReport>, (StatusCode, String)> {
+    let report = canary::run_canary(&state.models, &state.infer, &state.db, &state.events)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(report))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AgentRunPayload {
+    pub goal: String,
+    #[serde(default)]
+    pub policy: ToolPolicy,
+}
+
+/// Start an agent run and stream its events as server-sent events, one per
+/// model action or tool result, named after the event `type`. The run id is
+/// in the `started` event and the `x-agent-run-id` header. Closing the
+/// stream does not stop the run; cancel it by id.
+pub async fn admin_run_agent(
+    State(state): State<AppState>,
+    Json(payload): Json<AgentRunPayload>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let goal = payload.goal.trim().to_string();
+    if goal.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "missing_goal".into()));
+    }
+    // Agents drive the model directly, which a web tier using a remote
+    // inference service does not have.
+    let Some(llama) = state.models.generator(PRIMARY_GENERATOR) else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "agent_requires_local_model".into(),
+        ));
+    };
+    // Commands the model picks run unattended; refuse unless the jail is
+    // kept away from the server's own files.
+    let sandbox = Sandbox::unattended(&payload.policy).map_err(|err| {
+        tracing::warn!("agent run refused: {err}");
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "agent_sandbox_not_configured".to_string(),
+        )
+    })?;
+    let (run_id, events) = runs::start_run(
+        llama,
+        state.db.clone(),
+        state.agent_runs.clone(),
+        goal,
+        payload.policy,
+        sandbox,
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let stream = futures_util::stream::unfold(events, |mut events| async move {
+        let event = events.recv().await?;
+        let sse = Event::default()
+            .event(event.kind())
+            .json_data(&event)
+            .ok()?;
+        Some((Ok::<_, std::convert::Infallible>(sse), events))
+    });
+    Ok((
+        [("x-agent-run-id", run_id)],
+        Sse::new(stream).keep_alive(KeepAlive::default()),
+    ))
+}
+
+/// Transcript of an agent run, including runs still in progress.
+pub async fn admin_get_agent_run(
+    Path(run_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<runs::AgentRunRecord>, (StatusCode, String)> {
+    state
+        .db
+        .load_agent_run(&run_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "run_not_found".to_string()))
+}
+
+pub async fn admin_cancel_agent_run(
+    Path(run_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if state.agent_runs.cancel(&run_id) {
+        return Ok(Json(json!({ "run_id": run_id, "cancelled": true })));
+    }
+    let record = state
+        .db
+        .load_agent_run(&run_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match record {
+        Some(_) => Err((StatusCode::CONFLICT, "run_finished".into())),
+        None => Err((StatusCode::NOT_FOUND, "run_not_found".into())),
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Label the routing decision of a user message. The frontend thumbs-down
+/// sends the id of the user message that preceded the disliked reply.
+#[utoipa::path(
+    post,
+    path = "/internal/routing/{message_id}/feedback",
+    tag = "chats",
+    params(("message_id" = String, Path, description = "User message id")),
+    request_body = RoutingFeedbackPayload,
+    responses(
+        (status = 200, description = "Updated routing record", body = RoutingRecord),
+        (status = 400, description = "invalid_source"),
+        (status = 404, description = "routing_not_found"),
+    )
+)]
+pub async fn routing_feedback(
+    Path(message_id): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<RoutingFeedbackPayload>,
+) -> Result<Json<RoutingRecord>, (StatusCode, String)> {
+    let source = non_empty(payload.source).unwrap_or_else(|| "admin".to_string());
+    if !matches!(source.as_str(), "admin" | "user") {
+        return Err((StatusCode::BAD_REQUEST, "invalid_source".into()));
+    }
+
+    let mut record = state
+        .db
+        .load_routing_record(&message_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "routing_not_found".to_string()))?;
+
+    record.feedback = Some(RoutingFeedback {
+        misroute: payload.misroute,
+        expected_prompt_key: non_empty(payload.expected_prompt_key),
+        expected_speech_act: non_empty(payload.expected_speech_act),
+        expected_domain: non_empty(payload.expected_domain),
+        expected_expectation: non_empty(payload.expected_expectation),
+        comment: non_empty(payload.comment)
+            .map(|c| c.chars().take(MAX_FEEDBACK_COMMENT_CHARS).collect()),
+        source,
+        ts: Utc::now().timestamp(),
+    });
+    state
+        .db
+        .save_routing_record(&record)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(record))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Unix seconds, inclusive.
+    #[serde(default)]
+    pub from: Option<i64>,
+    #[serde(default)]
+    pub to: Option<i64>,
+    /// Whole actor (`admin:root`, `user:<id>`) or just its id.
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default = "default_audit_limit")]
+    pub limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+/// Audit entries, newest first.
+pub async fn admin_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err((StatusCode::BAD_REQUEST, "invalid_range".into()));
+        }
+    }
+    let limit = query.limit.clamp(1, 1000);
+    let actor = non_empty(query.actor);
+    let action = non_empty(query.action);
+    let entries = state
+        .db
+        .list_audit_entries(
+            query.from,
+            query.to,
+            actor.as_deref(),
+            action.as_deref(),
+            limit,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(json!({
+        "from": query.from,
+        "to": query.to,
+        "actor": actor,
+        "action": action,
+        "limit": limit,
+        "count": entries.len(),
+        "entries": entries,
+    })))
+}
+
+/// Latest moderation audit records, newest first.
+pub async fn admin_moderation_records(
+    State(state): State<AppState>,
+    Query(query): Query<LatestMessagesQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let limit = query.limit.clamp(1, 200);
+    let records = state
+        .db
+        .list_moderation_records(limit)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(json!({
+        "limit": limit,
+        "count": records.len(),
+        "records": records
+    })))
+}
+
+/// Labeled misroutes as JSONL, one training example per line: the
+/// classifier input, what the router predicted and the expected labels.
+pub async fn admin_export_misroutes(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let records = state
+        .db
+        .list_misroutes()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut body = String::new();
+    for record in records {
+        let Some(feedback) = record.feedback else {
+            continue;
+        };
+        let label = |head: &str| record.result[head]["label"].clone();
+        let line = json!({
+            "message_id": record.message_id,
+            "text": record.text,
+            "language": record.language,
+            "predicted": {
+                "speech_act": label("speech_act"),
+                "domain": label("domain"),
+                "expectation": label("expectation"),
+                "prompt_key": record.prompt_key,
+            },
+            "expected": {
+                "speech_act": feedback.expected_speech_act,
+                "domain": feedback.expected_domain,
+                "expectation": feedback.expected_expectation,
+                "prompt_key": feedback.expected_prompt_key,
+            },
+            "comment": feedback.comment,
+            "source": feedback.source,
+            "ts": record.ts,
+        });
+        body.push_str(&line.to_string());
+        body.push('\n');
+    }
+
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body))
+}
+
+/// Rated assistant replies as JSONL, one labeled example per line: the
+/// user prompt that preceded the reply, the reply and its feedback.
+/// Sandbox chats are left out.
+pub async fn admin_export_feedback(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let chats = state
+        .db
+        .list_chats()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut body = String::new();
+    for chat in chats.into_iter().filter(|c| !c.sandbox) {
+        let messages = state
+            .db
+            .list_messages_for_chat(&chat.id)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        for (idx, message) in messages.iter().enumerate() {
+            if message.role != "assistant" {
+                continue;
+            }
+            // Messages liked before feedback was stored only carry `liked`.
+            let feedback = match (&message.feedback, message.liked) {
+                (Some(feedback), _) => feedback.clone(),
+                (None, true) => MessageFeedback {
+                    kind: FeedbackKind::Liked,
+                    reasons: Vec::new(),
+                    comment: None,
+                    ts: message.ts,
+                },
+                (None, false) => continue,
+            };
+            let prompt = messages[..idx].iter().rev().find(|m| m.role == "user");
+            let line = json!({
+                "chat_id": chat.id,
+                "message_id": message.id,
+                "language": message.language,
+                "prompt": prompt.and_then(|m| m.text.as_deref()),
+                "prompt_key": prompt
+                    .and_then(|m| m.meta.as_ref())
+                    .and_then(|meta| meta.get("prompt_key")),
+                "reply": message.text,
+                "kind": feedback.kind,
+                "reasons": feedback.reasons,
+                "comment": feedback.comment,
+                "experiments": message.meta.as_ref().and_then(|meta| meta.get("experiments")),
+                "ts": feedback.ts,
+            });
+            body.push_str(&line.to_string());
+            body.push('\n');
+        }
+    }
+
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body))
+}
+
+pub async fn admin_routing_config() -> Json<routing_rules::RoutingConfig> {
+    Json(routing_rules::current().as_ref().clone())
+}
+
+/// Re-read `config/routing.yaml`. An invalid file is rejected with the
+/// parse error and the rules in effect stay untouched.
+pub async fn admin_reload_routing() -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let config = tokio::task::spawn_blocking(routing_rules::reload)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    Ok(Json(json!({
+        "reloaded": true,
+        "rules": config.rules.len(),
+        "label_overrides": config.label_overrides.len(),
+    })))
+}
+
+/// Experiments in effect plus outcomes per arm (prompts, regenerations,
+/// replies, net likes, average reply length) since counting began.
+pub async fn admin_experiments(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let stats = experiments::stats(&state.db)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(json!({
+        "config": experiments::current().as_ref(),
+        "stats": stats,
+    })))
+}
+
+/// Re-read `config/experiments.yaml`. An invalid file is rejected with the
+/// parse error and the experiments in effect stay untouched. Changing
+/// variants or weights moves chats between arms.
+pub async fn admin_reload_experiments() -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let config = tokio::task::spawn_blocking(experiments::reload)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    Ok(Json(json!({
+        "reloaded": true,
+        "experiments": config.experiments.len(),
+        "enabled": config.experiments.iter().filter(|e| e.enabled).count(),
+    })))
+}
+
+pub async fn admin_get_maintenance(State(state): State<AppState>) -> Json<MaintenanceWindow> {
+    Json(state.maintenance.current())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceUpdate {
+    pub enabled: bool,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub starts_ts: Option<i64>,
+    #[serde(default)]
+    pub ends_ts: Option<i64>,
+}
+
+/// Replace the maintenance window. Takes effect for new prompts right away.
+pub async fn admin_set_maintenance(
+    State(state): State<AppState>,
+    Json(payload): Json<MaintenanceUpdate>,
+) -> Result<Json<MaintenanceWindow>, (StatusCode, String)> {
+    if let (Some(start), Some(end)) = (payload.starts_ts, payload.ends_ts) {
+        if end <= start {
+            return Err((StatusCode::BAD_REQUEST, "invalid_window".into()));
+        }
+    }
+    let window = MaintenanceWindow {
+        enabled: payload.enabled,
+        message: payload.message.filter(|m| !m.trim().is_empty()),
+        starts_ts: payload.starts_ts,
+        ends_ts: payload.ends_ts,
+        updated_ts: 0,
+    };
+    let window = state
+        .maintenance
+        .set(&state.db, window)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    tracing::info!(
+        enabled = window.enabled,
+        starts_ts = ?window.starts_ts,
+        ends_ts = ?window.ends_ts,
+        "maintenance window updated"
+    );
+    Ok(Json(window))
+}
+
+pub async fn admin_db_stats(State(state): State<AppState>) -> Json<crate::db::DbStats> {
+    Json(state.db.db_stats())
+}
+
+/// Schema version with the migrations applied and still pending.
+pub async fn admin_db_migrations(
+    State(state): State<AppState>,
+) -> Result<Json<crate::db::migrations::SchemaStatus>, (StatusCode, String)> {
+    state
+        .db
+        .schema_status()
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Per column family sizes, files per level and compaction statistics.
+pub async fn admin_db_column_families(
+    State(state): State<AppState>,
+) -> Json<Vec<crate::db::families::FamilyStats>> {
+    Json(state.db.column_family_stats())
+}
+
+/// Start a full compaction in the background. RocksDB keeps serving reads
+/// and writes meanwhile; poll `/internal/admin/db/stats` for progress.
+pub async fn admin_compact_db(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    if state.db.is_compacting() {
+        return Err((StatusCode::CONFLICT, "compaction_in_progress".into()));
+    }
+    let db = state.db.clone();
+    tokio::task::spawn_blocking(move || {
+        let started = std::time::Instant::now();
+        if db.compact_all() {
+            tracing::info!(
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "manual rocksdb compaction finished"
+            );
+        }
+    });
+    Ok((StatusCode::ACCEPTED, Json(json!({ "started": true }))))
+}
+
+pub async fn admin_prompt_keys(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let languages = prompts::known_languages();
+
+    let mut keys: BTreeSet<String> = BTreeSet::new();
+    let mut fallback_templates = BTreeMap::new();
+    for lang in &languages {
+        let set = prompts::prompt_templates(lang);
+        keys.extend(set.prompts.keys().cloned());
+        fallback_templates.insert(lang.to_string(), set.default_prompt.clone());
+    }
+
+    let routes = prompt_key_routes();
+    keys.extend(routes.iter().map(|r| r.prompt_key.clone()));
+
+    let usage = state
+        .db
+        .list_counters("prompt_usage:")
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut usage_by_key: HashMap<String, BTreeMap<String, u64>> = HashMap::new();
+    for (name, count) in usage {
+        if let Some((lang, key)) = name.split_once(':') {
+            keys.insert(key.to_string());
+            usage_by_key
+                .entry(key.to_string())
+                .or_default()
+                .insert(lang.to_string(), count);
+        }
+    }
+
+    let rows: Vec<PromptKeyInfo> = keys
+        .into_iter()
+        .map(|key| {
+            let mut templates = BTreeMap::new();
+            let mut missing_languages = Vec::new();
+            for lang in &languages {
+                match prompts::prompt_templates(lang).prompts.get(&key) {
+                    Some(template) => {
+                        templates.insert(lang.to_string(), template.clone());
+                    }
+                    None => missing_languages.push(lang.to_string()),
+                }
+            }
+            let usage_by_language = usage_by_key.remove(&key).unwrap_or_default();
+            PromptKeyInfo {
+                is_default: key == prompts::default_intent(),
+                routes: routes
+                    .iter()
+                    .filter(|r| r.prompt_key == key)
+                    .cloned()
+                    .collect(),
+                usage_total: usage_by_language.values().sum(),
+                usage_by_language,
+                templates,
+                missing_languages,
+                key,
+            }
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "languages": languages,
+        "default_key": prompts::default_intent(),
+        "fallback_templates": fallback_templates,
+        "count": rows.len(),
+        "prompt_keys": rows,
+        "overrides": prompts::overrides(),
+    })))
+}
+
+/// Re-read `lang/*/prompts.json`. If any file is unreadable or invalid the
+/// prompts in effect stay untouched; admin overrides are kept either way.
+pub async fn admin_reload_prompts(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let counts = tokio::task::spawn_blocking(prompts::reload)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::PROMPT_RELOAD, ctx.admin_actor(), &ctx)
+            .details(json!({ "templates": counts.iter().collect::<BTreeMap<_, _>>() })),
+    )
+    .await;
+    Ok(Json(json!({
+        "reloaded": true,
+        "templates": counts.into_iter().collect::<BTreeMap<_, _>>(),
+        "overrides": prompts::overrides().len(),
+    })))
+}
+
+/// Per language: where its prompts come from, its fallback chain, and the
+/// keys it lacks with the language that answers for each.
+pub async fn admin_prompt_coverage() -> Json<serde_json::Value> {
+    let languages = prompts::coverage();
+    let complete = languages.iter().filter(|l| l.missing.is_empty()).count();
+    Json(json!({
+        "count": languages.len(),
+        "complete": complete,
+        "languages": languages,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromptTemplatePayload {
+    /// Empty or `null` drops the override and restores the file's template.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// Override one template (`default` addresses the language's fallback
+/// prompt). Stored in the DB and applied to new prompts right away.
+pub async fn admin_update_prompt(
+    Path((lang, key)): Path<(String, String)>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(payload): Json<PromptTemplatePayload>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let lang = prompts::normalize_language(&lang);
+    if !prompts::known_languages().contains(&lang) {
+        return Err((StatusCode::NOT_FOUND, "unknown_language".into()));
+    }
+    let key = key.trim().to_string();
+    if key.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "invalid_prompt_key".into()));
+    }
+    let item = payload
+        .template
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .map(|template| prompts::PromptOverride {
+            language: lang.clone(),
+            key: key.clone(),
+            template,
+            updated_ts: Utc::now().timestamp(),
+        });
+
+    let previous = prompts::overrides()
+        .into_iter()
+        .find(|o| o.language == lang && o.key == key)
+        .map(|o| o.template);
+    let stored = match &item {
+        Some(item) => state.db.save_prompt_override(item).await,
+        None => state.db.delete_prompt_override(&lang, &key).await,
+    };
+    stored.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    prompts::set_override(&lang, &key, item.clone())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    tracing::info!(
+        language = lang.as_str(),
+        key = key.as_str(),
+        overridden = item.is_some(),
+        "prompt template updated"
+    );
+    audit::record(
+        &state.db,
+        AuditEntry::new(audit::PROMPT_EDIT, ctx.admin_actor(), &ctx)
+            .target(format!("prompt:{lang}:{key}"))
+            .details(json!({
+                "previous": previous,
+                "template": item.as_ref().map(|i| i.template.as_str()),
+            })),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "language": lang,
+        "key": key,
+        "overridden": item.is_some(),
+        "template": item.map(|i| i.template),
+    })))
+}
@@ -0,0 +1,85 @@
+//! `/internal/admin/live`: a websocket that pushes what the server is doing
+//! right now once a second, read from memory only, so an ops dashboard
+//! does not have to poll the overview, which reads every stored message.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::ws::{
+    inference_worker::QueueStats,
+    queue::{LineEntry, LineError},
+    AppState,
+};
+
+const LIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+struct GpuUsage {
+    free_mib: u64,
+    total_mib: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct LiveFrame {
+    ts: i64,
+    queue: QueueStats,
+    /// Chat replies waiting for or streaming tokens, in line order.
+    generations: Vec<LineEntry>,
+    /// Last sample of the GPU watchdog; `null` without a local GPU.
+    gpu: Option<GpuUsage>,
+    /// Chat replies that failed or were turned away, oldest first.
+    errors: Vec<LineError>,
+}
+
+pub async fn admin_live(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| stream_live(socket, state))
+}
+
+async fn stream_live(mut socket: WebSocket, state: AppState) {
+    let mut ticker = tokio::time::interval(LIVE_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let Ok(frame) = serde_json::to_string(&live_frame(&state)) else {
+                    return;
+                };
+                if socket.send(Message::Text(frame.into())).await.is_err() {
+                    return;
+                }
+            }
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+fn live_frame(state: &AppState) -> LiveFrame {
+    let line = state.worker.line();
+    LiveFrame {
+        ts: Utc::now().timestamp_millis(),
+        queue: state.worker.queue_stats(),
+        generations: line.entries(),
+        gpu: state
+            .models
+            .gpu
+            .memory_mib()
+            .map(|(free_mib, total_mib)| GpuUsage {
+                free_mib,
+                total_mib,
+            }),
+        errors: line.errors(),
+    }
+}
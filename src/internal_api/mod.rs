@@ -6,13 +6,26 @@ use axum::{
 };
 
 mod auth;
+pub mod bulk;
 pub mod handlers;
+mod live;
 use auth::require_internal_auth;
 use handlers::{
-    admin_delete_user, admin_devices_page, admin_latest_messages, admin_list_devices,
-    admin_list_users, admin_overview, admin_page, admin_update_user_role, admin_users_page,
+    admin_audit, admin_canary_report, admin_cancel_agent_run, admin_compact_db,
+    admin_create_sandbox_chat, admin_db_column_families, admin_db_migrations, admin_db_stats,
+    admin_delete_user, admin_devices_page, admin_experiments, admin_export_feedback,
+    admin_export_misroutes, admin_file_gc_report, admin_get_agent_run, admin_get_maintenance,
+    admin_integrity_check, admin_integrity_repair, admin_latest_messages, admin_list_devices,
+    admin_list_retention_overrides, admin_list_sandbox_chats, admin_list_users,
+    admin_moderation_records, admin_overview, admin_page, admin_prompt_coverage, admin_prompt_keys,
+    admin_reload_experiments, admin_reload_prompts, admin_reload_routing, admin_retention_report,
+    admin_routing_config, admin_run_agent, admin_run_canary, admin_run_file_gc,
+    admin_run_retention, admin_search, admin_set_maintenance, admin_set_retention_override,
+    admin_update_prompt, admin_update_user_role, admin_update_user_system_prompt, admin_users_page,
     delete_message, delete_thread, get_thread, list_chats_by_device, list_chats_by_user,
-    list_messages_by_device, list_messages_for_chat, set_message_liked, update_summary,
+    list_messages_by_device, list_messages_for_chat, organize_chat, routing_feedback,
+    set_message_feedback, set_message_liked, update_chat_language, update_chat_system_prompt,
+    update_summary,
 };
 
 pub fn router() -> Router<AppState> {
@@ -21,7 +34,107 @@ pub fn router() -> Router<AppState> {
         .route("/internal/admin/devices", get(admin_devices_page))
         .route("/internal/admin/devices/list", get(admin_list_devices))
         .route("/internal/admin/overview", get(admin_overview))
+        .route("/internal/admin/live", get(live::admin_live))
         .route("/internal/admin/last", get(admin_latest_messages))
+        .route("/internal/admin/search", get(admin_search))
+        .route(
+            "/internal/admin/integrity",
+            get(admin_integrity_check).post(admin_integrity_repair),
+        )
+        .route(
+            "/internal/admin/sandbox",
+            get(admin_list_sandbox_chats).post(admin_create_sandbox_chat),
+        )
+        .route(
+            "/internal/admin/canary",
+            get(admin_canary_report).post(admin_run_canary),
+        )
+        .route(
+            "/internal/admin/maintenance",
+            get(admin_get_maintenance).put(admin_set_maintenance),
+        )
+        .route("/internal/admin/moderation", get(admin_moderation_records))
+        .route("/internal/admin/audit", get(admin_audit))
+        .route("/internal/admin/feedback.jsonl", get(admin_export_feedback))
+        .route(
+            "/internal/admin/prompts/coverage",
+            get(admin_prompt_coverage),
+        )
+        .route("/internal/admin/experiments", get(admin_experiments))
+        .route(
+            "/internal/admin/experiments/reload",
+            axum::routing::post(admin_reload_experiments),
+        )
+        .route(
+            "/internal/admin/prompts/reload",
+            axum::routing::post(admin_reload_prompts),
+        )
+        .route(
+            "/internal/admin/prompts/{lang}/{key}",
+            axum::routing::put(admin_update_prompt),
+        )
+        .route(
+            "/internal/admin/bulk/delete-old-chats",
+            axum::routing::post(bulk::admin_bulk_delete_old_chats),
+        )
+        .route(
+            "/internal/admin/bulk/purge-device",
+            axum::routing::post(bulk::admin_bulk_purge_device),
+        )
+        .route(
+            "/internal/admin/bulk/reindex",
+            axum::routing::post(bulk::admin_bulk_reindex),
+        )
+        .route(
+            "/internal/admin/bulk/roles",
+            axum::routing::post(bulk::admin_bulk_update_roles),
+        )
+        .route(
+            "/internal/admin/jobs/{job_id}",
+            get(bulk::admin_get_bulk_job),
+        )
+        .route(
+            "/internal/admin/storage/gc",
+            get(admin_file_gc_report).post(admin_run_file_gc),
+        )
+        .route(
+            "/internal/admin/retention",
+            get(admin_retention_report).post(admin_run_retention),
+        )
+        .route(
+            "/internal/admin/retention/overrides",
+            get(admin_list_retention_overrides),
+        )
+        .route(
+            "/internal/admin/retention/overrides/{user_id}",
+            axum::routing::put(admin_set_retention_override),
+        )
+        .route("/internal/admin/db/stats", get(admin_db_stats))
+        .route(
+            "/internal/admin/db/column-families",
+            get(admin_db_column_families),
+        )
+        .route("/internal/admin/db/migrations", get(admin_db_migrations))
+        .route(
+            "/internal/admin/db/compact",
+            axum::routing::post(admin_compact_db),
+        )
+        .route("/internal/agent/run", axum::routing::post(admin_run_agent))
+        .route("/internal/agent/runs/{run_id}", get(admin_get_agent_run))
+        .route(
+            "/internal/agent/runs/{run_id}/cancel",
+            axum::routing::post(admin_cancel_agent_run),
+        )
+        .route("/internal/routing/prompt-keys", get(admin_prompt_keys))
+        .route("/internal/routing/config", get(admin_routing_config))
+        .route(
+            "/internal/routing/reload",
+            axum::routing::post(admin_reload_routing),
+        )
+        .route(
+            "/internal/routing/misroutes.jsonl",
+            get(admin_export_misroutes),
+        )
         .route("/internal/users", get(admin_users_page))
         .route("/internal/users/list", get(admin_list_users))
         .route("/internal/users/{user_id}", delete(admin_delete_user))
@@ -29,6 +142,10 @@ pub fn router() -> Router<AppState> {
             "/internal/users/{user_id}/role",
             axum::routing::put(admin_update_user_role),
         )
+        .route(
+            "/internal/users/{user_id}/system-prompt",
+            axum::routing::put(admin_update_user_system_prompt),
+        )
         .layer(middleware::from_fn(require_internal_auth));
 
     Router::new()
@@ -38,6 +155,18 @@ pub fn router() -> Router<AppState> {
             "/internal/chat-thread/{chat_id}/summary",
             axum::routing::put(update_summary),
         )
+        .route(
+            "/internal/chat-thread/{chat_id}/system-prompt",
+            axum::routing::put(update_chat_system_prompt),
+        )
+        .route(
+            "/internal/chat-thread/{chat_id}/language",
+            axum::routing::put(update_chat_language),
+        )
+        .route(
+            "/internal/chat-thread/{chat_id}/organize",
+            axum::routing::put(organize_chat),
+        )
         // Alias to match FE
         .route("/chat-thread/{chat_id}", get(get_thread))
         .route("/chat-thread/{chat_id}", delete(delete_thread))
@@ -49,6 +178,14 @@ pub fn router() -> Router<AppState> {
             "/internal/chat-thread/{chat_id}/message/{message_id}/liked",
             axum::routing::put(set_message_liked),
         )
+        .route(
+            "/internal/chat-thread/{chat_id}/message/{message_id}/feedback",
+            axum::routing::put(set_message_feedback),
+        )
+        .route(
+            "/internal/routing/{message_id}/feedback",
+            axum::routing::post(routing_feedback),
+        )
         .route(
             "/internal/chats/by-device/{device_hash}",
             get(list_chats_by_device),
@@ -1,14 +1,38 @@
 pub mod agent;
+pub mod api;
 pub mod attachments;
+pub mod audit;
 pub mod auth;
+pub mod canary;
 pub mod classifier;
+pub mod cluster;
 pub mod conversation;
 pub mod db;
+pub mod events;
+pub mod experiments;
+pub mod export;
 pub mod external_api;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod images;
 pub mod inference;
 pub mod internal_api;
+pub mod maintenance;
 pub mod manager;
 pub mod model;
+pub mod moderation;
+pub mod openapi;
 pub mod payment;
+pub mod personas;
 pub mod prompts;
+pub mod redaction;
+pub mod retention;
+pub mod schedules;
+pub mod server;
+pub mod share;
+pub mod status;
+pub mod storage;
+pub mod tools;
+pub mod vector;
+pub mod webhooks;
 pub mod ws;
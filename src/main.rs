@@ -1,23 +1,43 @@
-use std::{fs, sync::Arc, time::Duration};
+use std::{fs, sync::Arc};
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::{header::AUTHORIZATION, header::CONTENT_TYPE, HeaderName, HeaderValue, Method},
-    Router,
+    middleware, Router,
 };
 use dotenvy::dotenv;
-use tokio::net::TcpListener;
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+use ktulhuMain::attachments::storage_root;
+use ktulhuMain::canary::spawn_canary;
 use ktulhuMain::db::DBLayer;
-use ktulhuMain::inference::intent_router::logits_argmax;
-use ktulhuMain::manager::ModelManager;
-use ktulhuMain::ws::{self, AppState, InferenceWorker};
+use ktulhuMain::events::{spawn_webhook_forwarder, EventBus};
+use ktulhuMain::manager::{ModelManager, FALLBACK_GENERATOR, PRIMARY_GENERATOR};
+use ktulhuMain::ws::{
+    self, AppState, ChatBroadcast, ConnectionRegistry, InferenceWorker, ResumeRegistry,
+};
 use ktulhuMain::{
-    auth, external_api,
-    inference::InferenceService,
+    agent::runs::AgentRunRegistry,
+    api::{self, REQUEST_ID_HEADER},
+    auth::{self, device::DeviceIdSigner, mailer::mailer_from_env},
+    cluster::{self, Cluster},
+    export, external_api, images,
+    inference::{remote::RemoteInference, whisper, InferenceService},
     internal_api,
+    maintenance::MaintenanceMode,
+    openapi,
     payment::{self, PaymentService},
+    personas, prompts,
+    retention::spawn_retention,
+    schedules::{self, spawn_scheduler},
+    server::{self, ServerConfig},
+    share,
+    status::{self, spawn_health_checks, HealthMonitor},
+    storage::{self, gc::spawn_file_gc, StorageService},
+    tools::web_search,
+    vector::vector_store_from_env,
+    webhooks::{self, spawn_webhook_dispatcher},
 };
 
 #[tokio::main]
@@ -28,6 +48,7 @@ async fn main() -> anyhow::Result<()> {
     dotenv().ok();
     dotenvy::from_filename("config/payment.env").ok();
     dotenvy::from_filename("config/llamacpp.env").ok();
+    dotenvy::from_filename("config/rocksdb.env").ok();
 
     // -----------------------------------
     // Logging
@@ -61,41 +82,80 @@ async fn main() -> anyhow::Result<()> {
     // Shared DB
     // -----------------------------------
     let db = Arc::new(DBLayer::new("chatdb")?);
+    let migrated = db.migrate().await?;
+    if !migrated.is_empty() {
+        println!("🗄️  {} schema migration(s) applied", migrated.len());
+    }
+
+    // -----------------------------------
+    // Prompt template overrides (admin edits)
+    // -----------------------------------
+    match db.list_prompt_overrides().await {
+        Ok(overrides) => {
+            if !overrides.is_empty() {
+                println!("📝 {} prompt template override(s) applied", overrides.len());
+            }
+            prompts::load_overrides(overrides);
+        }
+        Err(err) => eprintln!("failed to load prompt overrides: {err}"),
+    }
+
+    // -----------------------------------
+    // Orphaned data check (background)
+    // -----------------------------------
+    if env_flag("INTEGRITY_CHECK_ON_BOOT", true) {
+        let db = db.clone();
+        let repair = env_flag("INTEGRITY_REPAIR_ON_BOOT", false);
+        tokio::spawn(async move {
+            let root = storage_root();
+            match db.check_integrity(Some(root.as_path()), repair).await {
+                Ok(report) if report.is_clean() => println!("🧹 integrity check: no orphaned data"),
+                Ok(report) => println!(
+                    "⚠️  integrity check: {} orphaned messages, {} ownerless chats, {} unreferenced files ({} bytes), {} missing files{}",
+                    report.orphaned_messages.len(),
+                    report.chats_without_owner.len(),
+                    report.unreferenced_files.len(),
+                    report.unreferenced_bytes,
+                    report.missing_files.len(),
+                    if report.repaired { " — repaired" } else { "" }
+                ),
+                Err(err) => println!("⚠️  integrity check failed: {err}"),
+            }
+        });
+    }
 
     // -----------------------------------
     // Load ML models
     // -----------------------------------
-    let models = Arc::new(ModelManager::new().await?);
+    // With INFERENCE_URL the llama.cpp models run in a separate
+    // inference service and are not loaded here.
+    let remote_inference = RemoteInference::from_env()?;
+    let models = Arc::new(ModelManager::load(remote_inference.is_none()).await?);
 
-    println!("5️⃣ Sanity check (classifier quick pass)");
-    let router = models.intent_router.clone();
-    let sanity_handle = tokio::task::spawn_blocking(move || {
-        router.classify("machine learning is cool").and_then(|out| {
-            let (speech_idx, _) = logits_argmax(&out.speech_act)?;
-            let (expect_idx, _) = logits_argmax(&out.expectation)?;
-            Ok((speech_idx, expect_idx))
-        })
-    });
-    match tokio::time::timeout(Duration::from_secs(10), sanity_handle).await {
-        Ok(Ok(Ok((speech, expect)))) => {
-            println!(
-                "🧪 classifier check → speech_act={} expectation={}",
-                speech, expect
-            );
-        }
-        Ok(Ok(Err(err))) => {
-            println!("⚠️  classifier sanity check failed: {err}");
-        }
-        Ok(Err(join_err)) => {
-            println!("⚠️  classifier sanity check panicked: {join_err}");
-        }
-        Err(_) => println!("⚠️  classifier sanity check timed out (>10s)"),
+    // Runs while the server starts; /readyz answers 503 until every model
+    // has passed.
+    println!("5️⃣ Model warmup (background, gates /readyz)");
+    tokio::spawn(models.clone().warm_up());
+    if models.spawn_gpu_watchdog() {
+        println!("🧯 GPU memory watchdog running (GPU_WATCHDOG_INTERVAL_SECS)");
     }
 
     // -----------------------------------
     // Unified inference service
     // -----------------------------------
-    let infer = Arc::new(InferenceService::new(models.mistral_llama.clone()));
+    let infer = match (remote_inference, models.generator(PRIMARY_GENERATOR)) {
+        (Some(remote), _) => {
+            println!(
+                "🛰️  Generation via inference service at {}",
+                remote.base_url()
+            );
+            Arc::new(InferenceService::remote(remote))
+        }
+        (None, Some(primary)) => Arc::new(
+            InferenceService::new(primary).with_fallback(models.generator(FALLBACK_GENERATOR)),
+        ),
+        (None, None) => anyhow::bail!("no llama.cpp model loaded and no INFERENCE_URL set"),
+    };
 
     // -----------------------------------
     // Optional payment service (Stripe)
@@ -112,9 +172,58 @@ async fn main() -> anyhow::Result<()> {
     // -----------------------------------
     let worker = InferenceWorker::new(16);
 
+    // -----------------------------------
+    // Integration events
+    // -----------------------------------
+    let events = EventBus::new();
+    if spawn_webhook_forwarder(&events) {
+        println!("📣 Events webhook enabled via EVENTS_WEBHOOK_URL");
+    }
+    if spawn_webhook_dispatcher(db.clone(), &events) {
+        println!("🪝 User webhooks enabled (/api/webhooks)");
+    }
+
+    // -----------------------------------
+    // Model canary
+    // -----------------------------------
+    if spawn_canary(models.clone(), infer.clone(), db.clone(), events.clone()) {
+        println!("🐤 Model canary scheduled (CANARY_INTERVAL_SECS)");
+    }
+
     // -----------------------------------
     // Global AppState
     // -----------------------------------
+    let device_ids = Arc::new(DeviceIdSigner::from_env(&jwt_secret));
+    let storage = Arc::new(StorageService::from_env());
+    let upload_limit = storage.max_bytes();
+    let vectors = vector_store_from_env(db.clone());
+    let maintenance = MaintenanceMode::load(&db).await;
+    if maintenance.current().enabled {
+        println!("🚧 Maintenance window configured (see /api/status)");
+    }
+    let health = HealthMonitor::load(&db).await;
+    let web_search = web_search::provider_from_env();
+    if let Some(provider) = &web_search {
+        println!(
+            "🔎 Web search for recent-events questions via {}",
+            provider.name()
+        );
+    }
+
+    let transcriber = whisper::transcriber_from_env();
+    if let Some(transcriber) = &transcriber {
+        println!("🎙️ Voice messages transcribed with {}", transcriber.name());
+    }
+
+    let cluster = Cluster::from_env().await?;
+    if cluster.mode() != "single" {
+        println!(
+            "🛰️  Cluster mode {} as node {}",
+            cluster.mode(),
+            cluster.node().id
+        );
+    }
+
     let state = AppState {
         db,
         models,
@@ -124,8 +233,44 @@ async fn main() -> anyhow::Result<()> {
         google_client_id,
         apple_client_id,
         payment: payment_service,
+        events,
+        mailer: mailer_from_env(),
+        connections: ConnectionRegistry::new(),
+        resume: ResumeRegistry::new(),
+        chats: ChatBroadcast::new(),
+        device_ids,
+        storage,
+        vectors,
+        maintenance,
+        health,
+        agent_runs: AgentRunRegistry::new(),
+        cluster,
+        web_search,
+        transcriber,
     };
 
+    // -----------------------------------
+    // Component health (/api/status)
+    // -----------------------------------
+    if spawn_health_checks(state.clone()) {
+        println!("🩺 Health checks scheduled (HEALTH_CHECK_INTERVAL_SECS)");
+    }
+    if spawn_scheduler(state.clone()) {
+        println!("⏰ Scheduled prompts enabled (SCHEDULER_INTERVAL_SECS)");
+    }
+    if state.cluster.spawn_cancel_listener(state.resume.clone()) {
+        println!("🛰️  Cancels relayed between nodes");
+    }
+    if spawn_file_gc(state.db.clone()) {
+        println!("🗑️  Orphaned file collection scheduled (FILE_GC_INTERVAL_SECS)");
+    }
+    if spawn_retention(state.db.clone()) {
+        println!("⌛ Data retention enforced (RETENTION_INTERVAL_SECS)");
+    }
+    if auth::jwt::spawn_token_purge(state.db.clone()) {
+        println!("🔑 Expired auth tokens purged (TOKEN_PURGE_INTERVAL_SECS)");
+    }
+
     // -----------------------------------
     // Routers
     // -----------------------------------
@@ -151,35 +296,63 @@ async fn main() -> anyhow::Result<()> {
             CONTENT_TYPE,
             AUTHORIZATION,
             device_header.clone(),
+            REQUEST_ID_HEADER,
         ]))
+        .expose_headers([REQUEST_ID_HEADER])
         .allow_credentials(true);
 
+    #[cfg(feature = "grpc")]
+    let grpc_state = state.clone();
     let app = Router::new()
         .merge(ws::ws_router())
         .merge(auth::router())
-        .merge(internal_api::router())
+        .merge(internal_api::router().layer(DefaultBodyLimit::max(api::large_body_limit())))
         .merge(external_api::router())
+        .merge(export::router())
+        .merge(share::router())
+        .merge(personas::router())
+        .merge(schedules::router())
+        .merge(webhooks::router())
         .merge(payment::router())
+        .merge(storage::router(upload_limit))
+        .merge(images::router())
+        .merge(status::router())
+        .merge(cluster::router())
+        .merge(openapi::router())
+        // Routers with larger bodies set their own limit, which wins.
+        .layer(DefaultBodyLimit::max(api::json_body_limit()))
+        .layer(middleware::from_fn(api::request_context))
         .layer(cors_layer)
         .with_state(state);
 
     // -----------------------------------
     // Startup info
     // -----------------------------------
-    let addr = "0.0.0.0:3000";
+    let server_config = ServerConfig::from_env()?;
+    let addr = server_config.addr;
+    let (http, ws) = server_config.scheme();
 
-    println!("🌍 HTTP server  → http://{addr}");
-    println!("🔌 WebSocket    → ws://{addr}/ws");
-    println!("🔐 Auth API     → http://{addr}/api/auth/google");
-    println!("🧠 Internal API → http://{addr}/internal\n");
+    println!("🌍 HTTP server  → {http}://{addr}");
+    println!("🔌 WebSocket    → {ws}://{addr}/ws");
+    println!("🔐 Auth API     → {http}://{addr}/api/auth/google");
+    println!("🧠 Internal API → {http}://{addr}/internal");
+    println!("📘 API docs     → {http}://{addr}/docs\n");
+    if let (Some(_), Some(port)) = (&server_config.tls, server_config.redirect_port) {
+        println!("↪️  HTTP :{port} redirects to HTTPS\n");
+    }
+    #[cfg(feature = "grpc")]
+    if ktulhuMain::grpc::spawn_grpc(grpc_state, addr.ip()) {
+        println!(
+            "📡 gRPC API     → {}:{} (GRPC_PORT)\n",
+            addr.ip(),
+            std::env::var("GRPC_PORT").unwrap_or_default()
+        );
+    }
 
     // -----------------------------------
     // Bind + serve
     // -----------------------------------
-    let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app.into_make_service()).await?;
-
-    Ok(())
+    server::serve(app, server_config).await
 }
 
 fn load_allowed_origins(path: &str) -> Vec<HeaderValue> {
@@ -223,3 +396,10 @@ fn default_allowed_origin_strings() -> Vec<String> {
 fn to_header_value(origin: String) -> Option<HeaderValue> {
     HeaderValue::from_str(&origin).ok()
 }
+
+fn env_flag(name: &str, default: bool) -> bool {
+    std::env::var(name)
+        .ok()
+        .map(|v| !matches!(v.trim(), "0" | "false" | "off" | ""))
+        .unwrap_or(default)
+}
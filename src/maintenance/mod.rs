@@ -0,0 +1,124 @@
+//! Maintenance mode. While a window is active the ws handler answers new
+//! prompts with a localized notice instead of generating; history, chat
+//! lists and the other read paths keep working. Frontends learn about
+//! current and planned windows from `GET /api/status` (`crate::status`).
+
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::db::DBLayer;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct MaintenanceWindow {
+    pub enabled: bool,
+    /// Replaces the built-in localized notice when set.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Unix seconds; an enabled window without a start is active right away.
+    #[serde(default)]
+    pub starts_ts: Option<i64>,
+    /// Unix seconds; an enabled window without an end lasts until disabled.
+    #[serde(default)]
+    pub ends_ts: Option<i64>,
+    #[serde(default)]
+    pub updated_ts: i64,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.enabled
+            && self.starts_ts.map_or(true, |start| start <= now)
+            && self.ends_ts.map_or(true, |end| now < end)
+    }
+
+    /// Active or still to come, i.e. worth announcing.
+    pub fn is_announced(&self, now: i64) -> bool {
+        self.enabled && self.ends_ts.map_or(true, |end| now < end)
+    }
+
+    /// Notice shown instead of a reply, in the chat language when known.
+    pub fn notice(&self, language: Option<&str>) -> String {
+        if let Some(message) = self.message.as_deref().filter(|m| !m.trim().is_empty()) {
+            return message.to_string();
+        }
+        match language {
+            Some("es") => "El servicio está en mantenimiento. Puedes leer tus chats, pero los mensajes nuevos estarán disponibles cuando terminemos.",
+            Some("ru") => "Сервис на техническом обслуживании. Чаты доступны для чтения, новые сообщения можно будет отправить после завершения работ.",
+            Some("pt") => "O serviço está em manutenção. Você pode ler seus chats, mas novas mensagens ficarão disponíveis quando terminarmos.",
+            _ => "The service is under maintenance. You can still read your chats; new messages will be available once we are done.",
+        }
+        .to_string()
+    }
+}
+
+/// Current maintenance window, cached in memory and persisted in RocksDB
+/// so it survives restarts.
+#[derive(Clone, Default)]
+pub struct MaintenanceMode {
+    window: Arc<RwLock<MaintenanceWindow>>,
+}
+
+impl MaintenanceMode {
+    pub async fn load(db: &DBLayer) -> Self {
+        let window = match db.load_maintenance().await {
+            Ok(window) => window.unwrap_or_default(),
+            Err(err) => {
+                tracing::warn!("failed to load maintenance window: {err}");
+                MaintenanceWindow::default()
+            }
+        };
+        Self {
+            window: Arc::new(RwLock::new(window)),
+        }
+    }
+
+    pub fn current(&self) -> MaintenanceWindow {
+        self.window
+            .read()
+            .expect("maintenance lock poisoned")
+            .clone()
+    }
+
+    pub async fn set(
+        &self,
+        db: &DBLayer,
+        mut window: MaintenanceWindow,
+    ) -> Result<MaintenanceWindow> {
+        window.updated_ts = chrono::Utc::now().timestamp();
+        db.save_maintenance(&window).await?;
+        *self.window.write().expect("maintenance lock poisoned") = window.clone();
+        Ok(window)
+    }
+
+    /// The active window, if prompts should be refused right now.
+    pub fn active(&self) -> Option<MaintenanceWindow> {
+        let window = self.current();
+        window
+            .is_active(chrono::Utc::now().timestamp())
+            .then_some(window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn planned_window_is_announced_before_it_is_active() {
+        let window = MaintenanceWindow {
+            enabled: true,
+            starts_ts: Some(100),
+            ends_ts: Some(200),
+            ..Default::default()
+        };
+        assert!(!window.is_active(50));
+        assert!(window.is_announced(50));
+        assert!(window.is_active(150));
+        assert!(!window.is_active(200));
+        assert!(!window.is_announced(200));
+        assert!(!MaintenanceWindow::default().is_announced(0));
+    }
+}
@@ -1,18 +1,241 @@
 use anyhow::{anyhow, Result};
-use std::{path::PathBuf, sync::Arc};
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
 
-use crate::inference::{intent_router::RobertaIntentRouter, llama_cpp_service::LlamaCppService};
+use crate::{
+    conversation::build_mistral_prompt,
+    inference::{
+        backend::GenerationBackend,
+        diffusion::{self, DiffusionService},
+        gpu_watchdog::GpuWatchdog,
+        intent_router::RobertaIntentRouter,
+        llama_cpp_service::{DraftConfig, LlamaCppService, ENGINE_ERROR_PREFIX},
+        vision::{self, ImageDescriber},
+    },
+    model::message::Message,
+};
+
+pub const PRIMARY_EMBEDDING_MODEL: &str = "roberta-intent";
+/// Names of the chat model and its fallback in [`ModelManager::generators`].
+pub const PRIMARY_GENERATOR: &str = "mistral";
+pub const FALLBACK_GENERATOR: &str = "fallback";
+
+const DEFAULT_WARMUP_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_WARMUP_RETRY_SECS: u64 = 30;
+const WARMUP_PROMPT: &str = "Say hello in one short sentence.";
+const WARMUP_TEXT: &str = "What is the capital of France?";
+
+/// Outcome of the warmup pass of one model.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WarmupResult {
+    #[schema(example = "mistral")]
+    pub model: String,
+    pub passed: bool,
+    /// Until the first token for generators, the whole pass otherwise.
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct WarmupState {
+    ready: bool,
+    results: Vec<WarmupResult>,
+}
+
+enum WarmupTarget {
+    Generator(String, Arc<dyn GenerationBackend>),
+    IntentRouter,
+    Embedder(String, Arc<RobertaIntentRouter>),
+}
+
+impl WarmupTarget {
+    fn name(&self) -> String {
+        match self {
+            WarmupTarget::Generator(name, _) => name.clone(),
+            WarmupTarget::IntentRouter => "intent_router".into(),
+            WarmupTarget::Embedder(name, _) => format!("embeddings:{name}"),
+        }
+    }
+}
 
 pub struct ModelManager {
-    pub mistral_llama: Arc<LlamaCppService>,
+    /// `None` when generation runs in a separate inference service
+    /// (`INFERENCE_URL`).
+    pub mistral_llama: Option<Arc<LlamaCppService>>,
+    /// Smaller model (e.g. a lower-bit quant) that chat replies are retried
+    /// on when the primary fails; `LLAMA_FALLBACK_MODEL`.
+    pub fallback_llama: Option<Arc<LlamaCppService>>,
+    /// Every loaded text generator by name, primary first. Generation goes
+    /// through these; the typed fields above are for pool control.
+    pub generators: Vec<(String, Arc<dyn GenerationBackend>)>,
     pub intent_router: Arc<RobertaIntentRouter>,
+    /// Encoders served by the embeddings API, by model name. The first one
+    /// is the intent router itself.
+    pub embedders: Vec<(String, Arc<RobertaIntentRouter>)>,
+    /// Describes uploaded images; `VISION_MODEL` + `VISION_MMPROJ`.
+    pub vision: Option<Arc<dyn ImageDescriber>>,
+    /// Text-to-image model; `IMAGE_GEN_MODEL_DIR`.
+    pub diffusion: Option<Arc<DiffusionService>>,
+    /// Refuses new jobs and shrinks the llama.cpp context pools when GPU
+    /// memory runs low.
+    pub gpu: GpuWatchdog,
+    warmup: Mutex<WarmupState>,
 }
 
 impl ModelManager {
     pub async fn new() -> Result<Self> {
+        Self::load(true).await
+    }
+
+    /// Load the models; without `generators` no llama.cpp model is loaded
+    /// and generation goes to a separate inference service.
+    pub async fn load(generators: bool) -> Result<Self> {
         let default_intent_router_dir =
             PathBuf::from("/home/yaro/projects/ktulhu-main/models/robertaTunedHeads");
 
+        let (mistral_llama, fallback_llama) = if generators {
+            let (primary, fallback) = Self::load_generators()?;
+            (Some(primary), fallback)
+        } else {
+            (None, None)
+        };
+
+        let env_intent_router_dir = std::env::var("INTENT_ROUTER_DIR")
+            .ok()
+            .filter(|s| !s.trim().is_empty());
+        let legacy_phatic_dir = if env_intent_router_dir.is_none() {
+            std::env::var("PHATIC_MODEL_DIR")
+                .ok()
+                .filter(|s| !s.trim().is_empty())
+        } else {
+            None
+        };
+        let (intent_router_dir, log_msg) = if let Some(dir) = env_intent_router_dir {
+            (PathBuf::from(&dir), format!("INTENT_ROUTER_DIR -> {}", dir))
+        } else if let Some(dir) = legacy_phatic_dir {
+            (
+                PathBuf::from(&dir),
+                format!("PHATIC_MODEL_DIR (legacy) -> {}", dir),
+            )
+        } else {
+            (
+                default_intent_router_dir.clone(),
+                format!(
+                    "INTENT_ROUTER_DIR not set – defaulting to {}",
+                    default_intent_router_dir.display()
+                ),
+            )
+        };
+        println!("ℹ️  {log_msg}");
+
+        let intent_router_dir = if intent_router_dir.exists() {
+            intent_router_dir
+        } else {
+            let fallback = intent_router_dir.join("out");
+            if fallback.exists() {
+                println!(
+                    "ℹ️  intent router directory missing, falling back to {}",
+                    fallback.display()
+                );
+                fallback
+            } else {
+                intent_router_dir
+            }
+        };
+
+        if !intent_router_dir.join("tokenizer.json").exists() {
+            return Err(anyhow!(
+                "tokenizer.json not found under {}",
+                intent_router_dir.display()
+            ));
+        }
+        if !intent_router_dir.join("config.json").exists() {
+            return Err(anyhow!(
+                "config.json not found under {}",
+                intent_router_dir.display()
+            ));
+        }
+        let has_weights = ["model.safetensors", "pytorch_model.bin", "model.bin"]
+            .iter()
+            .any(|name| intent_router_dir.join(name).exists());
+        if !has_weights {
+            return Err(anyhow!(
+                "no model weights found under {} (expected model.safetensors or pytorch_model.bin)",
+                intent_router_dir.display()
+            ));
+        }
+
+        let use_phatic_head = std::env::var("INTENT_ROUTER_PHATIC")
+            .ok()
+            .map(|v| v != "0")
+            .unwrap_or(true);
+
+        let router_dir_clone = intent_router_dir.clone();
+        let intent_router = tokio::task::spawn_blocking(move || {
+            RobertaIntentRouter::load(router_dir_clone, 0, use_phatic_head)
+        })
+        .await??;
+        let intent_router = Arc::new(intent_router);
+
+        let mut embedders = vec![(PRIMARY_EMBEDDING_MODEL.to_string(), intent_router.clone())];
+        // Optional second copy of the encoder on another device (e.g. `cpu`
+        // or `cuda:1`) so embedding traffic can stay off the routing GPU.
+        if let Some(device) = std::env::var("EMBEDDINGS_SECONDARY_DEVICE")
+            .ok()
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+        {
+            let dir = intent_router_dir.clone();
+            let spec = device.clone();
+            match tokio::task::spawn_blocking(move || {
+                RobertaIntentRouter::load_on(dir, &spec, false)
+            })
+            .await?
+            {
+                Ok(router) => {
+                    let name = format!("{PRIMARY_EMBEDDING_MODEL}@{device}");
+                    println!("ℹ️  embeddings model {name} loaded");
+                    embedders.push((name, Arc::new(router)));
+                }
+                Err(err) => println!("⚠️  EMBEDDINGS_SECONDARY_DEVICE={device} unavailable: {err}"),
+            }
+        }
+
+        let vision = vision::describer_from_env();
+        let diffusion = diffusion::diffusion_from_env();
+
+        let mut generators: Vec<(String, Arc<dyn GenerationBackend>)> = Vec::new();
+        if let Some(primary) = &mistral_llama {
+            generators.push((PRIMARY_GENERATOR.to_string(), primary.clone()));
+        }
+        if let Some(fallback) = &fallback_llama {
+            generators.push((FALLBACK_GENERATOR.to_string(), fallback.clone()));
+        }
+
+        Ok(Self {
+            mistral_llama,
+            fallback_llama,
+            generators,
+            intent_router,
+            embedders,
+            vision,
+            diffusion,
+            gpu: GpuWatchdog::from_env(),
+            warmup: Mutex::default(),
+        })
+    }
+
+    /// The primary llama.cpp model and the optional fallback one.
+    fn load_generators() -> Result<(Arc<LlamaCppService>, Option<Arc<LlamaCppService>>)> {
         let env_llama_cli_bin = std::env::var("LLAMA_CLI_BIN")
             .ok()
             .filter(|s| !s.trim().is_empty());
@@ -80,6 +303,14 @@ impl ModelManager {
             .ok()
             .and_then(|v| v.parse::<usize>().ok())
             .unwrap_or(3);
+        // Seconds a chat keeps its llama context (and cached prompt prefix)
+        // after its last turn; 0 disables pinning.
+        let llama_prefix_pin_ttl = std::env::var("LLAMA_PREFIX_PIN_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(600);
+        let llama_prefix_pin_ttl =
+            (llama_prefix_pin_ttl > 0).then(|| Duration::from_secs(llama_prefix_pin_ttl));
         let llama_max_tokens = std::env::var("LLAMA_CLI_MAX_TOKENS")
             .ok()
             .and_then(|v| v.parse::<usize>().ok())
@@ -102,6 +333,37 @@ impl ModelManager {
         let llama_threads = std::env::var("LLAMA_CLI_THREADS")
             .ok()
             .and_then(|v| v.parse::<i32>().ok());
+        // Replies sharing forward passes on one multi-sequence context;
+        // 0 or 1 keeps the context pool.
+        let llama_batch_slots = std::env::var("LLAMA_BATCH_SLOTS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        // Small model with the same vocabulary that drafts tokens for the
+        // primary model to verify in one batch.
+        let llama_draft = std::env::var("LLAMA_DRAFT_MODEL")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .map(|path| DraftConfig {
+                model_path: PathBuf::from(path.trim()),
+                tokens: std::env::var("LLAMA_DRAFT_TOKENS")
+                    .ok()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .filter(|v| *v > 0)
+                    .unwrap_or(5),
+                min_acceptance: std::env::var("LLAMA_DRAFT_MIN_ACCEPT")
+                    .ok()
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .unwrap_or(0.35),
+            });
+
+        // Token ids or texts never sampled by either model, comma separated.
+        let llama_banned: Vec<String> = std::env::var("LLAMA_BANNED_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
 
         let mistral_llama = match (llama_cli_bin_path, llama_cli_model_path) {
             (Some(_bin), Some(model)) => Arc::new(LlamaCppService::new(
@@ -114,6 +376,10 @@ impl ModelManager {
                 llama_gpu_layers,
                 llama_threads,
                 llama_ctx_pool,
+                llama_prefix_pin_ttl,
+                llama_draft,
+                llama_batch_slots,
+                &llama_banned,
             )?),
             _ => {
                 return Err(anyhow!(
@@ -122,86 +388,238 @@ impl ModelManager {
             }
         };
 
-        let env_intent_router_dir = std::env::var("INTENT_ROUTER_DIR")
+        let fallback_llama = match std::env::var("LLAMA_FALLBACK_MODEL")
             .ok()
-            .filter(|s| !s.trim().is_empty());
-        let legacy_phatic_dir = if env_intent_router_dir.is_none() {
-            std::env::var("PHATIC_MODEL_DIR")
-                .ok()
-                .filter(|s| !s.trim().is_empty())
-        } else {
-            None
-        };
-        let (intent_router_dir, log_msg) = if let Some(dir) = env_intent_router_dir {
-            (PathBuf::from(&dir), format!("INTENT_ROUTER_DIR -> {}", dir))
-        } else if let Some(dir) = legacy_phatic_dir {
-            (
-                PathBuf::from(&dir),
-                format!("PHATIC_MODEL_DIR (legacy) -> {}", dir),
-            )
-        } else {
-            (
-                default_intent_router_dir.clone(),
-                format!(
-                    "INTENT_ROUTER_DIR not set – defaulting to {}",
-                    default_intent_router_dir.display()
-                ),
-            )
-        };
-        println!("ℹ️  {log_msg}");
-
-        let intent_router_dir = if intent_router_dir.exists() {
-            intent_router_dir
-        } else {
-            let fallback = intent_router_dir.join("out");
-            if fallback.exists() {
-                println!(
-                    "ℹ️  intent router directory missing, falling back to {}",
-                    fallback.display()
-                );
-                fallback
-            } else {
-                intent_router_dir
+            .filter(|s| !s.trim().is_empty())
+        {
+            Some(path) => {
+                let pool = std::env::var("LLAMA_FALLBACK_CTX_POOL")
+                    .ok()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(1);
+                match LlamaCppService::new(
+                    &path,
+                    llama_ctx_size,
+                    llama_max_tokens,
+                    llama_temp,
+                    llama_top_p,
+                    llama_top_k,
+                    llama_gpu_layers,
+                    llama_threads,
+                    pool,
+                    None,
+                    None,
+                    0,
+                    &llama_banned,
+                ) {
+                    Ok(service) => {
+                        println!("ℹ️  fallback model loaded from {path}");
+                        Some(Arc::new(service))
+                    }
+                    Err(err) => {
+                        println!("⚠️  LLAMA_FALLBACK_MODEL unavailable: {err}");
+                        None
+                    }
+                }
             }
+            None => None,
         };
 
-        if !intent_router_dir.join("tokenizer.json").exists() {
-            return Err(anyhow!(
-                "tokenizer.json not found under {}",
-                intent_router_dir.display()
-            ));
+        Ok((mistral_llama, fallback_llama))
+    }
+
+    /// Start the GPU memory watchdog over the llama.cpp engines. False when
+    /// it is disabled or no GPU is in use.
+    pub fn spawn_gpu_watchdog(&self) -> bool {
+        let mut engines = Vec::new();
+        if let Some(primary) = &self.mistral_llama {
+            engines.push(("primary", primary.clone()));
         }
-        if !intent_router_dir.join("config.json").exists() {
-            return Err(anyhow!(
-                "config.json not found under {}",
-                intent_router_dir.display()
-            ));
+        if let Some(fallback) = &self.fallback_llama {
+            engines.push(("fallback", fallback.clone()));
         }
-        let has_weights = ["model.safetensors", "pytorch_model.bin", "model.bin"]
+        if engines.is_empty() {
+            return false;
+        }
+        self.gpu.spawn(engines)
+    }
+
+    /// Text generator by name, see [`PRIMARY_GENERATOR`].
+    pub fn generator(&self, name: &str) -> Option<Arc<dyn GenerationBackend>> {
+        self.generators
             .iter()
-            .any(|name| intent_router_dir.join(name).exists());
-        if !has_weights {
-            return Err(anyhow!(
-                "no model weights found under {} (expected model.safetensors or pytorch_model.bin)",
-                intent_router_dir.display()
-            ));
+            .find(|(n, _)| n == name)
+            .map(|(_, backend)| backend.clone())
+    }
+
+    /// Embedding model by name; `None` picks the primary one.
+    pub fn embedder(&self, name: Option<&str>) -> Option<(&str, Arc<RobertaIntentRouter>)> {
+        match name.map(str::trim).filter(|n| !n.is_empty()) {
+            None => self.embedders.first(),
+            Some(name) => self.embedders.iter().find(|(n, _)| n == name),
         }
+        .map(|(name, router)| (name.as_str(), router.clone()))
+    }
 
-        let use_phatic_head = std::env::var("INTENT_ROUTER_PHATIC")
-            .ok()
-            .map(|v| v != "0")
-            .unwrap_or(true);
+    /// Every model passed warmup; `/readyz` answers 503 until then.
+    pub fn is_ready(&self) -> bool {
+        self.warmup.lock().unwrap().ready
+    }
 
-        let router_dir_clone = intent_router_dir.clone();
-        let intent_router = tokio::task::spawn_blocking(move || {
-            RobertaIntentRouter::load(router_dir_clone, 0, use_phatic_head)
-        })
-        .await??;
-        let intent_router = Arc::new(intent_router);
+    /// Latest warmup result of each model.
+    pub fn warmup_results(&self) -> Vec<WarmupResult> {
+        self.warmup.lock().unwrap().results.clone()
+    }
 
-        Ok(Self {
-            mistral_llama,
-            intent_router,
-        })
+    /// Run a representative input through every generator, the intent
+    /// router heads and each embedder, logging per-model latency, until all
+    /// pass. Failed models are retried every `WARMUP_RETRY_SECS` (default
+    /// 30); each pass gets `WARMUP_TIMEOUT_SECS` (default 120). `WARMUP=0`
+    /// marks the server ready without it.
+    pub async fn warm_up(self: Arc<Self>) {
+        if std::env::var("WARMUP").is_ok_and(|v| v.trim() == "0") {
+            println!("ℹ️  WARMUP=0 – skipping model warmup");
+            self.warmup.lock().unwrap().ready = true;
+            return;
+        }
+        let limit =
+            Duration::from_secs(env_secs("WARMUP_TIMEOUT_SECS", DEFAULT_WARMUP_TIMEOUT_SECS));
+        let retry = Duration::from_secs(env_secs("WARMUP_RETRY_SECS", DEFAULT_WARMUP_RETRY_SECS));
+
+        let mut pending = self.warmup_targets();
+        let started = Instant::now();
+        loop {
+            let mut failed = Vec::new();
+            for target in pending {
+                let result = self.warm_up_one(&target, limit).await;
+                if result.passed {
+                    info!(
+                        model = result.model.as_str(),
+                        latency_ms = result.latency_ms,
+                        "model warmed up"
+                    );
+                    println!("🔥 warmup {}: {} ms", result.model, result.latency_ms);
+                } else {
+                    warn!(
+                        model = result.model.as_str(),
+                        latency_ms = result.latency_ms,
+                        error = result.error.as_deref().unwrap_or(""),
+                        "model warmup failed"
+                    );
+                    failed.push(target);
+                }
+                self.record_warmup(result);
+            }
+            if failed.is_empty() {
+                break;
+            }
+            println!(
+                "⚠️  warmup: {} model(s) failed, retrying in {}s",
+                failed.len(),
+                retry.as_secs()
+            );
+            tokio::time::sleep(retry).await;
+            pending = failed;
+        }
+
+        self.warmup.lock().unwrap().ready = true;
+        println!(
+            "✅ all models warmed up in {} ms – ready",
+            started.elapsed().as_millis()
+        );
+    }
+
+    fn warmup_targets(&self) -> Vec<WarmupTarget> {
+        let mut targets: Vec<WarmupTarget> = self
+            .generators
+            .iter()
+            .map(|(name, backend)| WarmupTarget::Generator(name.clone(), backend.clone()))
+            .collect();
+        targets.push(WarmupTarget::IntentRouter);
+        targets.extend(
+            self.embedders
+                .iter()
+                .map(|(name, encoder)| WarmupTarget::Embedder(name.clone(), encoder.clone())),
+        );
+        targets
+    }
+
+    async fn warm_up_one(&self, target: &WarmupTarget, limit: Duration) -> WarmupResult {
+        let started = Instant::now();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let pass = async {
+            match target {
+                WarmupTarget::Generator(_, engine) => {
+                    let prompt = build_mistral_prompt(&[warmup_turn()], None);
+                    let mut rx = engine.generate_stream(prompt, cancel.clone());
+                    // One token means the prefill and a decode step ran.
+                    match rx.recv().await {
+                        Some(chunk) if chunk.starts_with(ENGINE_ERROR_PREFIX) => {
+                            Err(anyhow!(chunk))
+                        }
+                        Some(_) => Ok(()),
+                        None => Err(anyhow!("no tokens generated")),
+                    }
+                }
+                WarmupTarget::IntentRouter => {
+                    let router = self.intent_router.clone();
+                    tokio::task::spawn_blocking(move || router.classify(WARMUP_TEXT).map(|_| ()))
+                        .await?
+                }
+                WarmupTarget::Embedder(_, encoder) => {
+                    let encoder = encoder.clone();
+                    tokio::task::spawn_blocking(move || {
+                        encoder.embed_batch(&[WARMUP_TEXT.to_string()]).map(|_| ())
+                    })
+                    .await?
+                }
+            }
+        };
+        let outcome = match tokio::time::timeout(limit, pass).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(anyhow!("timed out after {}s", limit.as_secs())),
+        };
+        cancel.store(true, Ordering::SeqCst);
+
+        WarmupResult {
+            model: target.name(),
+            passed: outcome.is_ok(),
+            latency_ms: started.elapsed().as_millis() as u64,
+            error: outcome.err().map(|err| err.to_string()),
+        }
+    }
+
+    fn record_warmup(&self, result: WarmupResult) {
+        let mut state = self.warmup.lock().unwrap();
+        match state.results.iter_mut().find(|r| r.model == result.model) {
+            Some(existing) => *existing = result,
+            None => state.results.push(result),
+        }
+    }
+}
+
+fn env_secs(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
+}
+
+fn warmup_turn() -> Message {
+    Message {
+        id: "warmup".into(),
+        chat_id: "warmup".into(),
+        session_id: None,
+        user_id: None,
+        device_hash: None,
+        role: "user".into(),
+        text: Some(WARMUP_PROMPT.to_string()),
+        language: Some("en".into()),
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: chrono::Utc::now().timestamp(),
+        meta: None,
     }
 }
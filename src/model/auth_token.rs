@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub token_hash: String, // sha256 of the opaque token handed to the client
+    pub user_id: String,    // FK → User.id
+    pub session_id: String, // token family; shared by every rotation of one login
+    pub device_hash: Option<String>,
+    pub created_ts: i64,
+    pub expires_ts: i64,
+    pub rotated_ts: Option<i64>, // set once exchanged; presenting it again is reuse
+}
+
+/// One login on one device; refresh tokens rotate within it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthSession {
+    pub id: String,      // same as RefreshToken.session_id and the JWT `sid`
+    pub user_id: String, // FK → User.id
+    pub device_hash: Option<String>,
+    pub created_ts: i64,
+    pub last_used_ts: i64,
+    pub expires_ts: i64, // expiry of the newest refresh token
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Chat {
     pub id: String,
     pub title: Option<String>,
@@ -8,4 +9,47 @@ pub struct Chat {
     pub device_hash: Option<String>,
     pub updated_ts: i64,
     pub meta: Option<serde_json::Value>,
+    /// Internal evaluation chat: hidden from user chat lists, analytics,
+    /// summaries and integration events.
+    #[serde(default)]
+    pub sandbox: bool,
+    /// Language the chat is locked to, plus switch history.
+    #[serde(default)]
+    pub language: ChatLanguage,
+    /// Persistent instructions from the user, merged into the
+    /// intent-selected system prompt of every reply in this chat.
+    #[serde(default)]
+    pub system_prompt_override: Option<String>,
+    /// User-chosen folder name.
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ChatLanguage {
+    /// Language used for prompts, classification and summaries.
+    pub locked: Option<String>,
+    /// Different language seen in the latest user turns, not yet adopted.
+    #[serde(default)]
+    pub pending: Option<String>,
+    #[serde(default)]
+    pub pending_count: u32,
+    /// Set by the user; other languages only get a switch suggestion.
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub history: Vec<LanguageChange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LanguageChange {
+    pub from: Option<String>,
+    pub to: String,
+    /// `initial`, `auto` (repeated turns in the new language) or `user`.
+    pub reason: String,
+    pub ts: i64,
 }
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Message {
     pub id: String,
     pub chat_id: String,
@@ -14,14 +15,73 @@ pub struct Message {
     pub language: Option<String>,
     #[serde(default)]
     pub attachments: Vec<MessageAttachment>,
+    /// Mirrors `feedback`: true while the feedback is `liked`.
     #[serde(default)]
     pub liked: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feedback: Option<MessageFeedback>,
     pub ts: i64,
     #[serde(default)]
     pub meta: Option<Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Message {
+    /// An assistant reply cut short by a cancel or disconnect
+    /// (`meta.truncated`).
+    pub fn truncated(&self) -> bool {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.get("truncated"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// A reply that stopped before the model finished it: truncated, or
+    /// cut at the token cap.
+    pub fn continuable(&self) -> bool {
+        self.role == "assistant"
+            && (self.truncated()
+                || self
+                    .meta
+                    .as_ref()
+                    .and_then(|meta| meta.get("finish_reason"))
+                    .and_then(Value::as_str)
+                    == Some("max_tokens"))
+    }
+}
+
+/// Thumbs up or down on an assistant reply, kept as a labeled example.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MessageFeedback {
+    pub kind: FeedbackKind,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<FeedbackReason>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    pub ts: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedbackKind {
+    Liked,
+    Disliked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedbackReason {
+    Wrong,
+    Unsafe,
+    TooLong,
+    TooShort,
+    OffTopic,
+    WrongLanguage,
+    NotHelpful,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct MessageAttachment {
     pub id: String,
     pub filename: String,
@@ -37,6 +97,12 @@ pub struct MessageAttachment {
     pub description: Option<String>,
     #[serde(default)]
     pub ocr_text: Option<String>,
+    /// Text extracted server-side from an uploaded document, in chunks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub text_chunks: Vec<String>,
+    /// Description of an uploaded image by the server's vision model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_description: Option<String>,
     #[serde(default)]
     pub labels: Vec<String>,
 }
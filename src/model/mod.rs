@@ -1,4 +1,7 @@
+pub mod auth_token;
 pub mod chat;
 pub mod message;
+pub mod routing;
+pub mod upload;
 pub mod user;
 pub mod user_device;
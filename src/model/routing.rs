@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+/// Routing decision made for a user message, kept so misroutes can be
+/// labeled and exported for classifier retraining.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoutingRecord {
+    /// The user message that was classified.
+    pub message_id: String,
+    pub chat_id: String,
+    /// Classifier input, including attachment notes.
+    pub text: String,
+    pub language: String,
+    pub prompt_key: String,
+    /// The full `IntentRoutingResult`.
+    #[schema(value_type = Object)]
+    pub result: Value,
+    pub ts: i64,
+    #[serde(default)]
+    pub feedback: Option<RoutingFeedback>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoutingFeedback {
+    pub misroute: bool,
+    #[serde(default)]
+    pub expected_prompt_key: Option<String>,
+    #[serde(default)]
+    pub expected_speech_act: Option<String>,
+    #[serde(default)]
+    pub expected_domain: Option<String>,
+    #[serde(default)]
+    pub expected_expectation: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// `admin` or `user` (frontend thumbs-down).
+    pub source: String,
+    pub ts: i64,
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// File uploaded through `POST /api/uploads`, referenced from ws
+/// attachments by `file_id`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StoredFile {
+    pub id: String,
+    pub filename: String,
+    /// Detected from the file contents, not the client's claim.
+    pub mime_type: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Location on disk, under `STORAGE_DIR`.
+    pub path: String,
+    /// Device that uploaded the file.
+    pub device_hash: String,
+    /// Account the device belonged to at upload time; its other devices
+    /// may use the file too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Chat the file was first attached to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<String>,
+    pub created_ts: i64,
+    /// Made by the image model; served by `GET /api/images/{id}`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub generated: bool,
+}
+
+impl StoredFile {
+    /// Whether a request from `device_hash`, linked to `user_id`, may use
+    /// the file: it uploaded it, or it belongs to the uploader's account.
+    pub fn is_owned_by(&self, device_hash: &str, user_id: Option<&str>) -> bool {
+        self.device_hash == device_hash
+            || user_id.is_some_and(|user| self.user_id.as_deref() == Some(user))
+    }
+}
@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 pub const FREE_GENERATION_LIMIT: u64 = 10;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum UserRole {
     Free,
@@ -27,6 +28,10 @@ impl UserRole {
             UserRole::Paid | UserRole::Admin => None,
         }
     }
+
+    pub fn is_paid(&self) -> bool {
+        matches!(self, UserRole::Paid | UserRole::Admin)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,9 +55,117 @@ pub struct User {
     pub stripe_customer_id: Option<String>,
     #[serde(default)]
     pub stripe_subscription_id: Option<String>,
+    #[serde(default)]
+    pub preferences: UserPreferences,
+    /// Paid plan of the subscription, as last confirmed by Stripe.
+    #[serde(default)]
+    pub plan: Option<String>,
+    /// Plan changes, oldest first, for support.
+    #[serde(default)]
+    pub plan_history: Vec<PlanChange>,
+}
+
+/// Entries kept in [`User::plan_history`].
+pub const MAX_PLAN_HISTORY: usize = 50;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanChangeStatus {
+    /// Sent to Stripe; the role and plan stay until the webhook confirms.
+    Requested,
+    Confirmed,
+    /// Stripe dropped the change because its proration invoice went unpaid.
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PlanChange {
+    pub from: Option<String>,
+    /// Plan name, or `free` once the subscription ends.
+    pub to: String,
+    pub status: PlanChangeStatus,
+    pub ts: i64,
+    /// The webhook event that confirmed or expired the change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stripe_event_id: Option<String>,
+}
+
+/// Longest display name accepted in preferences.
+pub const MAX_DISPLAY_NAME_CHARS: usize = 64;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseLength {
+    Short,
+    Medium,
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Formality {
+    Casual,
+    Neutral,
+    Formal,
+}
+
+/// How the user wants to be answered. Unset fields leave the model's
+/// defaults alone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct UserPreferences {
+    /// Preferred reply language (ISO 639-1).
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub response_length: Option<ResponseLength>,
+    #[serde(default)]
+    pub formality: Option<Formality>,
+    /// Name the assistant should address the user by.
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+impl UserPreferences {
+    /// System prompt lines for the set preferences; `None` when nothing is
+    /// set. The reply language is handled by the chat language logic, not
+    /// here.
+    pub fn prompt_block(&self) -> Option<String> {
+        let mut lines = Vec::new();
+        if let Some(name) = &self.display_name {
+            lines.push(format!("The user's name is {name}; address them by it."));
+        }
+        match self.response_length {
+            Some(ResponseLength::Short) => {
+                lines.push("Keep answers short: a few sentences unless asked for more.".into())
+            }
+            Some(ResponseLength::Medium) => lines.push("Give answers of moderate length.".into()),
+            Some(ResponseLength::Long) => lines.push("Give thorough, detailed answers.".into()),
+            None => {}
+        }
+        match self.formality {
+            Some(Formality::Casual) => lines.push("Use a casual, friendly tone.".into()),
+            Some(Formality::Neutral) => lines.push("Use a neutral tone.".into()),
+            Some(Formality::Formal) => lines.push("Use a formal, polite tone.".into()),
+            None => {}
+        }
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
 }
 
 impl User {
+    /// The change sent to Stripe and not yet confirmed or expired.
+    pub fn pending_plan_change(&self) -> Option<&PlanChange> {
+        self.plan_history
+            .last()
+            .filter(|change| change.status == PlanChangeStatus::Requested)
+    }
+
+    pub fn record_plan_change(&mut self, change: PlanChange) {
+        self.plan_history.push(change);
+        let excess = self.plan_history.len().saturating_sub(MAX_PLAN_HISTORY);
+        self.plan_history.drain(..excess);
+    }
+
     pub fn generation_limit(&self) -> Option<u64> {
         self.role.generation_limit()
     }
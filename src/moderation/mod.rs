@@ -0,0 +1,203 @@
+//! Safety gate run on every prompt before inference. Prompts about
+//! self-harm, illegal activity, or sexual content involving minors are
+//! flagged by keyword patterns or by the router's safety head, and routed
+//! to a safety prompt instead of the normal one. Flags are sent to the
+//! client as a `moderation` ws event and kept as audit records.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::classifier::routing::{HeadPrediction, IntentKind, IntentRoutingResult, RoutingPath};
+
+const DEFAULT_CLASSIFIER_THRESHOLD: f32 = 0.85;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationCategory {
+    SelfHarm,
+    IllegalActivity,
+    SexualMinors,
+}
+
+impl ModerationCategory {
+    /// Safety head label for this category.
+    fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "SELF_HARM" => Some(Self::SelfHarm),
+            "ILLEGAL_ACTIVITY" => Some(Self::IllegalActivity),
+            "SEXUAL_MINORS" => Some(Self::SexualMinors),
+            _ => None,
+        }
+    }
+
+    /// Self-harm gets a supportive reply with crisis resources; the rest a
+    /// refusal.
+    pub fn prompt_key(self) -> &'static str {
+        match self {
+            Self::SelfHarm => "safety_self_harm",
+            Self::IllegalActivity | Self::SexualMinors => "safety_refusal",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationSource {
+    Keyword,
+    Classifier,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ModerationVerdict {
+    pub category: ModerationCategory,
+    pub source: ModerationSource,
+    /// 1.0 for keyword hits, the safety head probability otherwise.
+    pub score: f32,
+    /// The matched phrase for keyword hits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched: Option<String>,
+}
+
+/// Audit entry for a flagged prompt.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ModerationRecord {
+    pub message_id: String,
+    pub chat_id: String,
+    #[serde(default)]
+    pub device_hash: Option<String>,
+    pub text: String,
+    pub language: String,
+    #[serde(flatten)]
+    pub verdict: ModerationVerdict,
+    pub ts: i64,
+}
+
+/// Most severe category first; the first hit wins.
+static PATTERNS: Lazy<Vec<(ModerationCategory, Regex)>> = Lazy::new(|| {
+    let build = |pattern: &str| Regex::new(&format!("(?i){pattern}")).expect("moderation pattern");
+    vec![
+        (
+            ModerationCategory::SexualMinors,
+            build(
+                r"\b(child|kiddie|underage)\s*porn\w*|\bcsam\b|\b(nude|porn\w*|erotic\w*)\b.{0,40}\b(child|children|minor|minors|kid|kids|underage|preteen|([1-9]|1[0-7])[- ]?(yo|year[- ]?old))\b|\b(child|children|minor|minors|kid|kids|underage|preteen)\b.{0,40}\b(nude|porn\w*|erotic\w*)\b|\bsex(ual)?\s+(with|involving)\s+(a\s+)?(child|children|minor|minors|kid|kids|underage|preteen)\b",
+            ),
+        ),
+        (
+            ModerationCategory::SexualMinors,
+            build(
+                r"детск\w*\s+порн\w*|порн\w*.{0,40}\b(несовершеннолетн|ребен|ребён|дет)\w*|pornograf[ií]a\s+infantil|pornografia\s+infantil",
+            ),
+        ),
+        (
+            ModerationCategory::IllegalActivity,
+            build(
+                r"\b(make|build|assemble)\s+(a\s+)?(pipe\s+)?bomb\b|\b(cook|make|synthesi[sz]e)\s+(meth|methamphetamine|fentanyl)\b|\bhire\s+(a\s+)?hitman\b|\blaunder\s+(the\s+)?money\b|\bbuy\s+(a\s+)?(stolen\s+credit\s+cards?|untraceable\s+gun)\b",
+            ),
+        ),
+        (
+            ModerationCategory::IllegalActivity,
+            build(
+                r"(сделать|собрать)\s+бомбу|(сварить|синтезировать)\s+(мет|метамфетамин|фентанил)|нанять\s+киллера|(hacer|fabricar)\s+una\s+bomba|(fazer|fabricar)\s+uma\s+bomba|contratar\s+(a\s+)?um\s+assassino|contratar\s+a\s+un\s+sicario",
+            ),
+        ),
+        (
+            ModerationCategory::SelfHarm,
+            build(
+                r"\b(kill|killing|hurt|hurting|cut|cutting)\s+myself\b|\bend\s+my\s+life\b|\bwant\s+to\s+die\b|\bsuicid(e|al)\b|\bself[- ]harm\w*",
+            ),
+        ),
+        (
+            ModerationCategory::SelfHarm,
+            build(
+                r"покончить\s+с\s+собой|убить\s+себя|не\s+хочу\s+жить|суицид\w*|suicid(arme|io)|quitarme\s+la\s+vida|me\s+matar|suic[ií]dio|tirar\s+(a\s+)?minha\s+(pr[óo]pria\s+)?vida",
+            ),
+        ),
+    ]
+});
+
+/// Minimum safety head probability that flags a prompt
+/// (`MODERATION_CLASSIFIER_THRESHOLD`).
+pub fn classifier_threshold() -> f32 {
+    std::env::var("MODERATION_CLASSIFIER_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|v| (0.0..=1.0).contains(v))
+        .unwrap_or(DEFAULT_CLASSIFIER_THRESHOLD)
+}
+
+/// Score a prompt: keyword patterns first, then the safety head (when the
+/// router checkpoint has one).
+pub fn check(text: &str, safety: Option<&HeadPrediction>) -> Option<ModerationVerdict> {
+    if let Some(verdict) = keyword_verdict(text) {
+        return Some(verdict);
+    }
+    let prediction = safety?;
+    let category = ModerationCategory::from_label(&prediction.label)?;
+    (prediction.score >= classifier_threshold()).then(|| ModerationVerdict {
+        category,
+        source: ModerationSource::Classifier,
+        score: prediction.score,
+        matched: None,
+    })
+}
+
+fn keyword_verdict(text: &str) -> Option<ModerationVerdict> {
+    PATTERNS.iter().find_map(|(category, pattern)| {
+        pattern.find(text).map(|hit| ModerationVerdict {
+            category: *category,
+            source: ModerationSource::Keyword,
+            score: 1.0,
+            matched: Some(hit.as_str().to_string()),
+        })
+    })
+}
+
+/// Route a flagged prompt to the category's safety prompt. Wins over
+/// reasoning profile overrides and support routing.
+pub fn apply(result: &mut IntentRoutingResult, verdict: &ModerationVerdict) {
+    result.notes.push(format!(
+        "moderation: {:?} via {:?} ({:.2}) → {} prompt",
+        verdict.category,
+        verdict.source,
+        verdict.score,
+        verdict.category.prompt_key()
+    ));
+    result.final_intent_kind = IntentKind::ChatCasual;
+    result.routing_path = RoutingPath::ChatLayer;
+    result.reasoning_profile = None;
+    result.support_intent = false;
+    result.prompt_key = verdict.category.prompt_key().to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_flag_by_category_and_ignore_benign_text() {
+        let verdict = check("I just want to end my life", None).expect("flagged");
+        assert_eq!(verdict.category, ModerationCategory::SelfHarm);
+        assert_eq!(verdict.source, ModerationSource::Keyword);
+
+        let verdict = check("how do I make a pipe bomb at home", None).expect("flagged");
+        assert_eq!(verdict.category, ModerationCategory::IllegalActivity);
+
+        assert!(check("the bomb squad movie was great", None).is_none());
+        assert!(check("how do I kill a zombie process", None).is_none());
+    }
+
+    #[test]
+    fn safety_head_flags_above_threshold_only() {
+        let prediction = |label: &str, score| HeadPrediction {
+            label: label.into(),
+            score,
+            distribution: Vec::new(),
+        };
+        let verdict = check("hello", Some(&prediction("ILLEGAL_ACTIVITY", 0.95))).unwrap();
+        assert_eq!(verdict.source, ModerationSource::Classifier);
+        assert!(check("hello", Some(&prediction("ILLEGAL_ACTIVITY", 0.4))).is_none());
+        assert!(check("hello", Some(&prediction("SAFE", 0.99))).is_none());
+    }
+}
@@ -0,0 +1,383 @@
+//! OpenAPI description of the HTTP surface, served as `/openapi.json` with a
+//! Swagger UI at `/docs`. The websocket protocol has no HTTP operations, so
+//! its frames are published as schemas prefixed with `Ws`.
+
+use axum::Router;
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::ws::AppState;
+
+/// `{"type":"assistant","token":…}` – one streamed chunk of the reply.
+#[derive(Serialize, ToSchema)]
+pub struct WsAssistantToken {
+    #[schema(example = "assistant")]
+    pub r#type: String,
+    pub token: String,
+    /// Numbers the chunks of a reply from 1; a jump means chunks were lost.
+    pub seq: u64,
+}
+
+/// `{"type":"assistant","done":true}` – end of the reply stream.
+#[derive(Serialize, ToSchema)]
+pub struct WsAssistantDone {
+    #[schema(example = "assistant")]
+    pub r#type: String,
+    pub done: bool,
+    /// Present and `true` when the reply came from the fallback model.
+    pub fallback: Option<bool>,
+    /// Seed the reply was sampled with; send it back to reproduce it.
+    pub seed: Option<u32>,
+}
+
+/// `{"type":"system",…}` – `registered` (echoes session/chat/device),
+/// `chat_created` (the server assigned a new chat id), `maintenance` (the
+/// prompt was refused, see `message`), `transcribed` (a voice prompt was
+/// transcribed, see `text`), `resumed` (a `resume` was accepted),
+/// `frames_dropped` (frames were lost, send `sync`), `image_generating` (an
+/// `image_prompt` was accepted) or one of the chat language events.
+#[derive(Serialize, ToSchema)]
+pub struct WsSystemEvent {
+    #[schema(example = "system")]
+    pub r#type: String,
+    #[schema(example = "chat_created")]
+    pub event: String,
+    pub chat_id: Option<String>,
+    pub session_id: Option<String>,
+    pub device_hash: Option<String>,
+    /// `resumed`: reply being replayed; `image_generating`: the prompt.
+    pub request_id: Option<String>,
+    /// `language_switch_suggested` / `language_switched`: old and new language.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// `language_locked`: language set by `set_language`.
+    pub language: Option<String>,
+    /// `maintenance`: localized notice and the planned end of the window.
+    pub message: Option<String>,
+    pub ends_ts: Option<i64>,
+    /// `frames_dropped`: number of frames lost.
+    pub count: Option<u64>,
+    /// `transcribed`: transcript that became the prompt's text.
+    pub text: Option<String>,
+}
+
+/// `{"type":"summary",…}` – short chat title generated after the first exchange.
+#[derive(Serialize, ToSchema)]
+pub struct WsSummary {
+    #[schema(example = "summary")]
+    pub r#type: String,
+    pub chat_id: String,
+    pub message_id: String,
+    pub role: String,
+    pub text: String,
+    pub ts: i64,
+    pub language: Option<String>,
+}
+
+/// `{"type":"sync",…}` – answer to `sync`: the reply text up to chunk `seq`.
+#[derive(Serialize, ToSchema)]
+pub struct WsSync {
+    #[schema(example = "sync")]
+    pub r#type: String,
+    pub request_id: String,
+    pub chat_id: String,
+    pub text: String,
+    pub seq: u64,
+    /// The reply finished; `text` is final.
+    pub done: bool,
+}
+
+/// `{"type":"image",…}` – answer to `image_prompt`. Also sent to the
+/// chat's other sockets.
+#[derive(Serialize, ToSchema)]
+pub struct WsImage {
+    #[schema(example = "image")]
+    pub r#type: String,
+    pub request_id: String,
+    pub chat_id: String,
+    /// Stored assistant message carrying the images as attachments.
+    pub message_id: String,
+    pub images: Vec<crate::images::GeneratedImage>,
+}
+
+/// `{"type":"vision_summary",…}` – combined description of the prompt's attachments.
+#[derive(Serialize, ToSchema)]
+pub struct WsVisionSummary {
+    #[schema(example = "vision_summary")]
+    pub r#type: String,
+    pub chat_id: String,
+    pub summary: String,
+}
+
+/// `{"type":"classifier_debug",…}` – routing decision for the prompt.
+#[derive(Serialize, ToSchema)]
+pub struct WsClassifierDebug {
+    #[schema(example = "classifier_debug")]
+    pub r#type: String,
+    pub intent_result: serde_json::Value,
+    /// `{experiment: variant}` for the chat's experiment arms; also set on
+    /// the `done` frame and in the `meta` of both stored messages.
+    pub experiments: serde_json::Value,
+}
+
+/// `{"type":"moderation",…}` – the prompt was flagged and is answered with
+/// a safety prompt instead of the normal pipeline. Sent before the reply.
+#[derive(Serialize, ToSchema)]
+pub struct WsModeration {
+    #[schema(example = "moderation")]
+    pub r#type: String,
+    pub chat_id: String,
+    /// Id of the flagged user message.
+    pub request_id: String,
+    pub category: crate::moderation::ModerationCategory,
+}
+
+/// `{"type":"tool_call",…}` – the model called a tool on a turn sent with
+/// `tools`; followed by the matching `tool_result`.
+#[derive(Serialize, ToSchema)]
+pub struct WsToolCall {
+    #[schema(example = "tool_call")]
+    pub r#type: String,
+    pub chat_id: String,
+    pub step: usize,
+    #[schema(example = "calculator")]
+    pub tool: String,
+    pub args: serde_json::Value,
+}
+
+/// `{"type":"tool_result",…}` – output of a tool call (truncated to 2000
+/// chars); `ok` is false when the call failed or the tool is not allowed.
+#[derive(Serialize, ToSchema)]
+pub struct WsToolResult {
+    #[schema(example = "tool_result")]
+    pub r#type: String,
+    pub chat_id: String,
+    pub step: usize,
+    pub tool: String,
+    pub ok: bool,
+    pub output: String,
+}
+
+/// `{"type":"web_search",…}` – sources found for a recency-sensitive
+/// question, sent before the reply; the reply cites them as `[n]`.
+#[derive(Serialize, ToSchema)]
+pub struct WsWebSearch {
+    #[schema(example = "web_search")]
+    pub r#type: String,
+    pub chat_id: String,
+    pub query: String,
+    pub sources: Vec<WsWebSource>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WsWebSource {
+    pub n: usize,
+    pub title: String,
+    pub url: String,
+}
+
+/// `{"type":"live_preview",…}` – head of a reply streaming on another socket
+/// of the same user; only sent to sockets registered with `live_preview`.
+#[derive(Serialize, ToSchema)]
+pub struct WsLivePreview {
+    #[schema(example = "live_preview")]
+    pub r#type: String,
+    pub chat_id: String,
+    pub text: String,
+    pub done: bool,
+}
+
+/// `{"type":"error","message":…}`
+#[derive(Serialize, ToSchema)]
+pub struct WsError {
+    #[schema(example = "error")]
+    pub r#type: String,
+    #[schema(example = "server_busy")]
+    pub message: String,
+    /// Replacement id when `message` is `device_migrated`.
+    pub device_hash: Option<String>,
+    /// Free and total GPU memory (MiB) when `message` is `capacity`.
+    pub free_mib: Option<u64>,
+    pub total_mib: Option<u64>,
+    /// Node serving the chat when `message` is `wrong_node`.
+    pub node: Option<String>,
+    /// Base URL of that node.
+    pub url: Option<String>,
+    /// Id of the websocket upgrade request, as in the server logs.
+    pub request_id: String,
+}
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Ktulhu API",
+        description = "Auth, chat history, external completion and payment endpoints. Streaming chat runs over the `/ws` websocket; see the `Ws*` schemas for its frames."
+    ),
+    paths(
+        crate::auth::google::google_login_handler,
+        crate::auth::apple::apple_login_handler,
+        crate::auth::email_auth::email_register_handler,
+        crate::auth::email_auth::email_login_handler,
+        crate::auth::password_reset::forgot_password_handler,
+        crate::auth::password_reset::reset_password_handler,
+        crate::auth::device::register_device_handler,
+        crate::auth::device::claim_device_handler,
+        crate::auth::tokens::refresh_handler,
+        crate::auth::account::delete_account_handler,
+        crate::auth::tokens::logout_handler,
+        crate::auth::tokens::list_sessions_handler,
+        crate::auth::tokens::revoke_session_handler,
+        crate::internal_api::handlers::get_thread,
+        crate::internal_api::handlers::delete_thread,
+        crate::internal_api::handlers::update_summary,
+        crate::internal_api::handlers::update_chat_system_prompt,
+        crate::internal_api::handlers::update_chat_language,
+        crate::internal_api::handlers::organize_chat,
+        crate::internal_api::handlers::delete_message,
+        crate::internal_api::handlers::set_message_liked,
+        crate::internal_api::handlers::set_message_feedback,
+        crate::internal_api::handlers::routing_feedback,
+        crate::internal_api::handlers::list_chats_by_device,
+        crate::internal_api::handlers::list_messages_by_device,
+        crate::internal_api::handlers::list_chats_by_user,
+        crate::internal_api::handlers::list_messages_for_chat,
+        crate::export::export_handler,
+        crate::share::create_share_handler,
+        crate::share::revoke_share_handler,
+        crate::share::shared_chat_handler,
+        crate::personas::list_personas_handler,
+        crate::personas::create_persona_handler,
+        crate::personas::get_persona_handler,
+        crate::personas::update_persona_handler,
+        crate::personas::delete_persona_handler,
+        crate::schedules::list_schedules_handler,
+        crate::schedules::create_schedule_handler,
+        crate::schedules::get_schedule_handler,
+        crate::schedules::update_schedule_handler,
+        crate::schedules::delete_schedule_handler,
+        crate::webhooks::list_webhooks_handler,
+        crate::webhooks::create_webhook_handler,
+        crate::webhooks::delete_webhook_handler,
+        crate::external_api::handlers::generate,
+        crate::external_api::handlers::embeddings,
+        crate::external_api::handlers::generate_images,
+        crate::external_api::handlers::profile,
+        crate::external_api::handlers::update_preferences,
+        crate::external_api::handlers::generation_usage,
+        crate::external_api::handlers::generate_api_credentials,
+        crate::external_api::handlers::store_api_credentials,
+        crate::external_api::handlers::validate_api_credentials,
+        crate::storage::upload_handler,
+        crate::storage::list_uploads_handler,
+        crate::storage::download_handler,
+        crate::storage::delete_upload_handler,
+        crate::storage::transcription_handler,
+        crate::images::image_handler,
+        crate::payment::create_checkout_session,
+        crate::payment::payment_config,
+        crate::payment::activate_subscription,
+        crate::payment::plans::change_plan,
+        crate::status::status_handler,
+        crate::status::readiness_handler,
+        crate::cluster::route_handler,
+    ),
+    components(schemas(
+        crate::model::chat::Chat,
+        crate::model::message::Message,
+        crate::model::message::MessageAttachment,
+        crate::model::message::MessageFeedback,
+        crate::model::message::FeedbackKind,
+        crate::model::message::FeedbackReason,
+        crate::export::ExportFormat,
+        crate::export::ChatTranscript,
+        crate::export::TranscriptMessage,
+        crate::export::TranscriptAttachment,
+        crate::share::CreateShareRequest,
+        crate::share::CreateShareResponse,
+        crate::share::ShareFormat,
+        crate::personas::Persona,
+        crate::personas::PersonaPayload,
+        crate::schedules::Schedule,
+        crate::schedules::SchedulePayload,
+        crate::webhooks::WebhookEvent,
+        crate::webhooks::CreateWebhookRequest,
+        crate::webhooks::WebhookResponse,
+        crate::model::user::UserRole,
+        crate::model::user::UserPreferences,
+        crate::model::user::ResponseLength,
+        crate::model::user::Formality,
+        crate::ws::handler::PromptMsg,
+        crate::ws::handler::MsgType,
+        crate::attachments::IncomingAttachment,
+        crate::storage::TranscriptionRequest,
+        crate::inference::whisper::Transcript,
+        crate::inference::backend::FinishReason,
+        crate::images::ImageGenerateRequest,
+        crate::images::GeneratedImage,
+        crate::external_api::handlers::ImageGenerateResponse,
+        crate::maintenance::MaintenanceWindow,
+        crate::status::StatusResponse,
+        crate::status::ReadinessResponse,
+        crate::manager::WarmupResult,
+        crate::status::ComponentHealth,
+        crate::status::Incident,
+        crate::cluster::ChatRoute,
+        crate::cluster::Node,
+        crate::api::ErrorEnvelope,
+        crate::api::ErrorBody,
+        WsAssistantToken,
+        WsAssistantDone,
+        WsSystemEvent,
+        WsSummary,
+        WsSync,
+        WsImage,
+        WsVisionSummary,
+        WsClassifierDebug,
+        WsModeration,
+        WsToolCall,
+        WsToolResult,
+        WsWebSearch,
+        WsWebSource,
+        WsLivePreview,
+        WsError,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "auth", description = "Login, token refresh and session management"),
+        (name = "chats", description = "Chat threads and messages"),
+        (name = "external", description = "Token-gated completion API"),
+        (name = "personas", description = "User-defined assistant personas"),
+        (name = "schedules", description = "Recurring prompts"),
+        (name = "webhooks", description = "Signed event notifications to user endpoints"),
+        (name = "payment", description = "Stripe Checkout helpers"),
+        (name = "uploads", description = "Attachment file uploads"),
+        (name = "images", description = "Generated images"),
+        (name = "status", description = "Component health, incidents and maintenance windows"),
+    )
+)]
+pub struct ApiDoc;
+
+pub fn router() -> Router<AppState> {
+    Router::new().merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()))
+}
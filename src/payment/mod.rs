@@ -2,10 +2,15 @@ use anyhow::{anyhow, Result};
 use axum::{http::StatusCode, routing::post, Json, Router};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use utoipa::ToSchema;
 
 use axum_extra::typed_header::TypedHeader;
 use headers::{authorization::Bearer, Authorization};
 
+pub mod plans;
+
+use plans::PlanCatalog;
+
 use crate::{
     auth::jwt::decode_jwt,
     model::user::{User, UserRole},
@@ -29,6 +34,9 @@ pub struct PaymentService {
     checkout_mode: String,
     success_url: String,
     cancel_url: String,
+    plans: PlanCatalog,
+    webhook_secret: Option<String>,
+    proration_behavior: String,
 }
 
 impl PaymentService {
@@ -43,6 +51,11 @@ impl PaymentService {
         let cancel_url = dotenvy::var("STRIPE_CANCEL_URL")
             .unwrap_or_else(|_| "http://localhost:3000/payment/cancel".to_string());
 
+        let plans = PlanCatalog::from_env(&price_id);
+        let webhook_secret = dotenvy::var("STRIPE_WEBHOOK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty());
+
         Some(Self {
             client: reqwest::Client::new(),
             secret_key,
@@ -51,6 +64,9 @@ impl PaymentService {
             checkout_mode,
             success_url,
             cancel_url,
+            plans,
+            webhook_secret,
+            proration_behavior: plans::proration_behavior(),
         })
     }
 
@@ -69,6 +85,13 @@ impl PaymentService {
         form.push(("line_items[0][price]".to_string(), self.price_id.clone()));
         form.push(("line_items[0][quantity]".to_string(), "1".to_string()));
         form.push(("metadata[user_id]".to_string(), user_id.to_string()));
+        if self.checkout_mode == "subscription" {
+            // Lets subscription webhooks find the user.
+            form.push((
+                "subscription_data[metadata][user_id]".to_string(),
+                user_id.to_string(),
+            ));
+        }
 
         let response = self
             .client
@@ -110,6 +133,40 @@ impl PaymentService {
         Ok(response.json().await?)
     }
 
+    /// Cancel a subscription immediately. Already cancelled or unknown
+    /// subscriptions count as done.
+    pub async fn cancel_subscription(&self, subscription_id: &str) -> Result<()> {
+        let url = format!(
+            "https://api.stripe.com/v1/subscriptions/{}",
+            subscription_id
+        );
+        self.delete_resource(&url).await
+    }
+
+    /// Delete the Stripe customer, dropping its payment methods.
+    pub async fn delete_customer(&self, customer_id: &str) -> Result<()> {
+        let url = format!("https://api.stripe.com/v1/customers/{}", customer_id);
+        self.delete_resource(&url).await
+    }
+
+    async fn delete_resource(&self, url: &str) -> Result<()> {
+        let response = self
+            .client
+            .delete(url)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Bearer {}", self.secret_key),
+            )
+            .send()
+            .await?;
+
+        if response.status().is_success() || response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        let text = response.text().await.unwrap_or_default();
+        Err(anyhow!("stripe_error: {}", text))
+    }
+
     fn success_url_with_session_placeholder(&self) -> String {
         if self.success_url.contains("{CHECKOUT_SESSION_ID}") {
             return self.success_url.clone();
@@ -139,27 +196,29 @@ struct StripeSessionDetails {
     customer: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct CheckoutSessionResponse {
     pub session_id: String,
     pub checkout_url: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct PaymentConfigResponse {
     pub publishable_key: String,
+    /// Plans `POST /payment/change-plan` accepts.
+    pub plans: Vec<String>,
 }
 
-#[derive(Deserialize)]
-struct ActivateRequest {
-    session_id: String,
+#[derive(Deserialize, ToSchema)]
+pub struct ActivateRequest {
+    pub session_id: String,
 }
 
-#[derive(Serialize)]
-struct ActivateResponse {
-    user_id: String,
-    role: UserRole,
-    updated: bool,
+#[derive(Serialize, ToSchema)]
+pub struct ActivateResponse {
+    pub user_id: String,
+    pub role: UserRole,
+    pub updated: bool,
 }
 
 pub fn router() -> Router<AppState> {
@@ -170,9 +229,21 @@ pub fn router() -> Router<AppState> {
         )
         .route("/payment/config", axum::routing::get(payment_config))
         .route("/payment/activate", post(activate_subscription))
+        .route("/payment/change-plan", post(plans::change_plan))
+        .route("/payment/webhook", post(plans::stripe_webhook))
 }
 
-async fn create_checkout_session(
+#[utoipa::path(
+    post,
+    path = "/payment/create-checkout-session",
+    tag = "payment",
+    responses(
+        (status = 200, description = "Stripe Checkout session", body = CheckoutSessionResponse),
+        (status = 503, description = "payments_not_configured"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_checkout_session(
     axum::extract::State(state): axum::extract::State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
 ) -> Result<Json<CheckoutSessionResponse>, (StatusCode, String)> {
@@ -198,7 +269,16 @@ async fn create_checkout_session(
     }))
 }
 
-async fn payment_config(
+#[utoipa::path(
+    get,
+    path = "/payment/config",
+    tag = "payment",
+    responses(
+        (status = 200, description = "Stripe publishable key", body = PaymentConfigResponse),
+        (status = 503, description = "payments_not_configured"),
+    )
+)]
+pub async fn payment_config(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<PaymentConfigResponse>, (StatusCode, String)> {
     let service = state.payment.as_ref().ok_or((
@@ -208,10 +288,22 @@ async fn payment_config(
 
     Ok(Json(PaymentConfigResponse {
         publishable_key: service.publishable_key.clone(),
+        plans: service.plans.names(),
     }))
 }
 
-async fn activate_subscription(
+#[utoipa::path(
+    post,
+    path = "/payment/activate",
+    tag = "payment",
+    request_body = ActivateRequest,
+    responses(
+        (status = 200, description = "Role after checking the Checkout session", body = ActivateResponse),
+        (status = 503, description = "payments_not_configured"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn activate_subscription(
     axum::extract::State(state): axum::extract::State<AppState>,
     TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
     Json(payload): Json<ActivateRequest>,
@@ -258,6 +350,14 @@ async fn activate_subscription(
         updated = true;
     }
 
+    if user.plan.is_none() {
+        user.plan = service
+            .plans
+            .plan_for_price(&service.price_id)
+            .map(str::to_string);
+        updated = true;
+    }
+
     if let Some(customer_id) = session.customer.clone() {
         if user.stripe_customer_id.as_deref() != Some(customer_id.as_str()) {
             user.stripe_customer_id = Some(customer_id);
@@ -295,7 +395,8 @@ fn session_is_paid(session: &StripeSessionDetails) -> bool {
 }
 
 async fn authenticate_user(state: &AppState, token: &str) -> Result<User, (StatusCode, String)> {
-    let user_id = decode_jwt(token, &state.jwt_secret)
+    let user_id = decode_jwt(token, &state.jwt_secret, &state.db)
+        .await
         .map_err(|_| (StatusCode::UNAUTHORIZED, "invalid_token".into()))?;
 
     let user = state
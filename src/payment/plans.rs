@@ -0,0 +1,755 @@
+//! Plan changes on an existing subscription. `POST /payment/change-plan`
+//! swaps the subscription's price with proration and
+//! `payment_behavior=pending_if_incomplete`, so Stripe applies it only
+//! once the proration invoice is paid. The local role and plan change only
+//! when the `customer.subscription.*` webhook confirms it; until then the
+//! request sits in the user's plan history as `requested`.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use headers::{authorization::Bearer, Authorization};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::{authenticate_user, PaymentService};
+use crate::{
+    db::DBLayer,
+    model::user::{PlanChange, PlanChangeStatus, User, UserRole},
+    ws::AppState,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Plan name of the checkout price when `STRIPE_PLANS` does not list it.
+const CHECKOUT_PLAN: &str = "default";
+const DEFAULT_PRORATION: &str = "always_invoice";
+/// Oldest webhook signature timestamp accepted, against replays.
+const SIGNATURE_TOLERANCE_SECS: i64 = 300;
+/// Age after which a `requested` change Stripe no longer has pending is
+/// settled from the subscription instead of blocking new changes; younger
+/// ones may belong to a request still talking to Stripe.
+const PENDING_RESYNC_AFTER_SECS: i64 = 600;
+
+/// Paid plans by name, each a Stripe price.
+#[derive(Debug, Clone, Default)]
+pub struct PlanCatalog {
+    plans: Vec<(String, String)>,
+}
+
+impl PlanCatalog {
+    /// `name=price_id` pairs separated by commas. The checkout price is
+    /// the `default` plan unless a pair names it.
+    fn parse(spec: &str, checkout_price: &str) -> Self {
+        let mut plans: Vec<(String, String)> = spec
+            .split(',')
+            .filter_map(|pair| {
+                let (name, price) = pair.split_once('=')?;
+                let (name, price) = (name.trim().to_ascii_lowercase(), price.trim());
+                (!name.is_empty() && name != "free" && !price.is_empty())
+                    .then(|| (name, price.to_string()))
+            })
+            .collect();
+        if !plans.iter().any(|(_, price)| price == checkout_price) {
+            plans.push((CHECKOUT_PLAN.to_string(), checkout_price.to_string()));
+        }
+        Self { plans }
+    }
+
+    /// `STRIPE_PLANS`, e.g. `pro=price_123,team=price_456`.
+    pub fn from_env(checkout_price: &str) -> Self {
+        Self::parse(
+            &dotenvy::var("STRIPE_PLANS").unwrap_or_default(),
+            checkout_price,
+        )
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.plans.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn price(&self, plan: &str) -> Option<&str> {
+        self.plans
+            .iter()
+            .find(|(name, _)| name == plan)
+            .map(|(_, price)| price.as_str())
+    }
+
+    pub fn plan_for_price(&self, price: &str) -> Option<&str> {
+        self.plans
+            .iter()
+            .find(|(_, p)| p == price)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// `STRIPE_PRORATION_BEHAVIOR`: `always_invoice` (default) bills the
+/// difference at once, `create_prorations` on the next invoice.
+pub fn proration_behavior() -> String {
+    match dotenvy::var("STRIPE_PRORATION_BEHAVIOR") {
+        Ok(value) if matches!(value.trim(), "always_invoice" | "create_prorations") => {
+            value.trim().to_string()
+        }
+        Ok(value) => {
+            warn!(
+                value = value.as_str(),
+                "unsupported STRIPE_PRORATION_BEHAVIOR, using always_invoice"
+            );
+            DEFAULT_PRORATION.to_string()
+        }
+        Err(_) => DEFAULT_PRORATION.to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StripeSubscription {
+    id: String,
+    #[serde(default)]
+    customer: Option<String>,
+    status: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    /// Set while a change waits for its invoice to be paid.
+    #[serde(default)]
+    pending_update: Option<serde_json::Value>,
+    items: SubscriptionItems,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionItems {
+    data: Vec<SubscriptionItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionItem {
+    id: String,
+    price: StripePrice,
+}
+
+#[derive(Debug, Deserialize)]
+struct StripePrice {
+    id: String,
+}
+
+impl StripeSubscription {
+    fn price(&self) -> Option<&str> {
+        self.items.data.first().map(|item| item.price.id.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+struct StripeEvent {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    data: StripeEventData,
+}
+
+#[derive(Deserialize)]
+struct StripeEventData {
+    object: serde_json::Value,
+}
+
+impl PaymentService {
+    async fn retrieve_subscription(&self, subscription_id: &str) -> Result<StripeSubscription> {
+        let url = format!("https://api.stripe.com/v1/subscriptions/{subscription_id}");
+        let response = self
+            .client
+            .get(url)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Bearer {}", self.secret_key),
+            )
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("stripe_error: {}", text));
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// Move the subscription's item to `price`. `idempotency_key` makes a
+    /// retried request a no-op instead of a second change.
+    async fn change_subscription_price(
+        &self,
+        subscription: &StripeSubscription,
+        price: &str,
+        idempotency_key: &str,
+    ) -> Result<StripeSubscription> {
+        let item = subscription
+            .items
+            .data
+            .first()
+            .ok_or_else(|| anyhow!("subscription_without_items"))?;
+        let form = [
+            ("items[0][id]", item.id.as_str()),
+            ("items[0][price]", price),
+            ("proration_behavior", self.proration_behavior.as_str()),
+            ("payment_behavior", "pending_if_incomplete"),
+        ];
+        let url = format!(
+            "https://api.stripe.com/v1/subscriptions/{}",
+            subscription.id
+        );
+        let response = self
+            .client
+            .post(url)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Bearer {}", self.secret_key),
+            )
+            .header("Idempotency-Key", idempotency_key)
+            .form(&form)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("stripe_error: {}", text));
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChangePlanRequest {
+    /// A plan name from `GET /payment/config`.
+    pub plan: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChangePlanResponse {
+    pub user_id: String,
+    pub from: Option<String>,
+    pub to: String,
+    /// `requested` until the Stripe webhook confirms the change.
+    pub status: PlanChangeStatus,
+    pub proration_behavior: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/payment/change-plan",
+    tag = "payment",
+    request_body = ChangePlanRequest,
+    responses(
+        (status = 202, description = "Change sent to Stripe; role and plan follow the webhook", body = ChangePlanResponse),
+        (status = 400, description = "unknown_plan"),
+        (status = 409, description = "no_subscription / already_on_plan / plan_change_pending"),
+        (status = 503, description = "payments_not_configured"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn change_plan(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<ChangePlanRequest>,
+) -> Result<(StatusCode, Json<ChangePlanResponse>), (StatusCode, String)> {
+    let mut user = authenticate_user(&state, auth.token()).await?;
+    let service = state.payment.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "payments_not_configured".to_string(),
+    ))?;
+    // Only the webhook applies a change; without it Stripe would move and
+    // charge the subscription while the user stays on the old plan.
+    if service.webhook_secret.is_none() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "webhook_not_configured".to_string(),
+        ));
+    }
+    let plan = payload.plan.trim().to_ascii_lowercase();
+    let price = service
+        .plans
+        .price(&plan)
+        .ok_or((StatusCode::BAD_REQUEST, "unknown_plan".to_string()))?;
+    let subscription_id = user
+        .stripe_subscription_id
+        .clone()
+        .ok_or((StatusCode::CONFLICT, "no_subscription".to_string()))?;
+    let now = chrono::Utc::now().timestamp();
+    if user
+        .pending_plan_change()
+        .is_some_and(|change| now - change.ts < PENDING_RESYNC_AFTER_SECS)
+    {
+        return Err((StatusCode::CONFLICT, "plan_change_pending".to_string()));
+    }
+
+    let subscription = service
+        .retrieve_subscription(&subscription_id)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    if subscription.pending_update.is_some() {
+        return Err((StatusCode::CONFLICT, "plan_change_pending".to_string()));
+    }
+    if user.pending_plan_change().is_some() {
+        settle_pending(&mut user, &service.plans, &subscription, now);
+        state
+            .db
+            .save_user(&user)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if user.stripe_subscription_id.is_none() {
+            return Err((StatusCode::CONFLICT, "no_subscription".to_string()));
+        }
+    }
+    if subscription.price() == Some(price) {
+        return Err((StatusCode::CONFLICT, "already_on_plan".to_string()));
+    }
+    let from = subscription
+        .price()
+        .and_then(|p| service.plans.plan_for_price(p))
+        .map(str::to_string)
+        .or_else(|| user.plan.clone());
+
+    // Saved before Stripe is called: with a card on file the confirming
+    // webhook can arrive before the call returns, and must find it.
+    let requested = PlanChange {
+        from: from.clone(),
+        to: plan.clone(),
+        status: PlanChangeStatus::Requested,
+        ts: now,
+        stripe_event_id: None,
+    };
+    user.record_plan_change(requested.clone());
+    state
+        .db
+        .save_user(&user)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let idempotency_key = format!("change-plan:{}:{}", subscription.id, Uuid::new_v4());
+    if let Err(err) = service
+        .change_subscription_price(&subscription, price, &idempotency_key)
+        .await
+    {
+        withdraw_plan_change(&state.db, &user.id, &requested).await;
+        return Err((StatusCode::BAD_GATEWAY, err.to_string()));
+    }
+    info!(
+        user_id = user.id.as_str(),
+        from = from.as_deref().unwrap_or("-"),
+        to = plan.as_str(),
+        "plan change requested"
+    );
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(ChangePlanResponse {
+            user_id: user.id,
+            from,
+            to: plan,
+            status: PlanChangeStatus::Requested,
+            proration_behavior: service.proration_behavior.clone(),
+        }),
+    ))
+}
+
+/// Drop a `requested` entry Stripe refused, from a fresh copy of the user.
+async fn withdraw_plan_change(db: &DBLayer, user_id: &str, requested: &PlanChange) {
+    let withdrawn = async {
+        let Some(mut user) = db.load_user(user_id).await? else {
+            return Ok(());
+        };
+        let Some(index) = user.plan_history.iter().rposition(|change| {
+            change.status == PlanChangeStatus::Requested
+                && change.ts == requested.ts
+                && change.to == requested.to
+        }) else {
+            return Ok(());
+        };
+        user.plan_history.remove(index);
+        db.save_user(&user).await
+    };
+    if let Err(err) = withdrawn.await {
+        warn!(user_id, "failed to withdraw refused plan change: {err}");
+    }
+}
+
+/// Stripe events (`STRIPE_WEBHOOK_SECRET`): subscription updates confirm
+/// plan changes and set the role, deletions drop the user to free, and
+/// `pending_update_expired` marks an unpaid change as expired. Other
+/// events are acknowledged and ignored.
+pub async fn stripe_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let service = state.payment.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "payments_not_configured".to_string(),
+    ))?;
+    let secret = service.webhook_secret.as_deref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "webhook_not_configured".to_string(),
+    ))?;
+    let signature = headers
+        .get("stripe-signature")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !signature_valid(secret, signature, &body, chrono::Utc::now().timestamp()) {
+        return Err((StatusCode::BAD_REQUEST, "invalid_signature".to_string()));
+    }
+
+    let event: StripeEvent = serde_json::from_slice(&body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid_event".to_string()))?;
+    if !event.kind.starts_with("customer.subscription.") {
+        return Ok(StatusCode::OK);
+    }
+    let subscription: StripeSubscription = serde_json::from_value(event.data.object)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid_event".to_string()))?;
+
+    let Some(mut user) = subscription_owner(&state.db, &subscription)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    else {
+        warn!(
+            subscription_id = subscription.id.as_str(),
+            event = event.kind.as_str(),
+            "stripe event for a subscription without a user"
+        );
+        return Ok(StatusCode::OK);
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if apply_subscription_event(
+        &mut user,
+        &service.plans,
+        &event.id,
+        &event.kind,
+        &subscription,
+        now,
+    ) {
+        state
+            .db
+            .save_user(&user)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        info!(
+            user_id = user.id.as_str(),
+            event = event.kind.as_str(),
+            role = ?user.role,
+            plan = user.plan.as_deref().unwrap_or("-"),
+            "subscription change applied"
+        );
+    }
+    Ok(StatusCode::OK)
+}
+
+/// The user a subscription belongs to: its `user_id` metadata (set at
+/// checkout), else the user holding its subscription or customer id.
+async fn subscription_owner(
+    db: &DBLayer,
+    subscription: &StripeSubscription,
+) -> Result<Option<User>> {
+    if let Some(user_id) = subscription.metadata.get("user_id") {
+        if let Some(user) = db.load_user(user_id).await? {
+            return Ok(Some(user));
+        }
+    }
+    let users = db.list_users().await?;
+    let by_subscription = users
+        .iter()
+        .position(|u| u.stripe_subscription_id.as_deref() == Some(subscription.id.as_str()));
+    let by_customer = || {
+        users.iter().position(|u| {
+            u.stripe_customer_id.is_some() && u.stripe_customer_id == subscription.customer
+        })
+    };
+    Ok(by_subscription
+        .or_else(by_customer)
+        .map(|index| users[index].clone()))
+}
+
+/// Bring `user` in line with `subscription` as `event_kind` reports it.
+/// Returns whether the user changed; replayed events change nothing.
+/// Close a `requested` change whose webhook never came, now that Stripe
+/// has nothing pending: the subscription as it stands decides the plan,
+/// and a change it does not reflect is marked expired.
+fn settle_pending(
+    user: &mut User,
+    plans: &PlanCatalog,
+    subscription: &StripeSubscription,
+    now: i64,
+) {
+    let sync_id = format!("sync:{}", Uuid::new_v4());
+    apply_subscription_event(
+        user,
+        plans,
+        &sync_id,
+        "customer.subscription.updated",
+        subscription,
+        now,
+    );
+    if let Some(pending) = user.pending_plan_change().map(|c| c.to.clone()) {
+        let from = user.plan.clone();
+        user.record_plan_change(PlanChange {
+            from,
+            to: pending,
+            status: PlanChangeStatus::Expired,
+            ts: now,
+            stripe_event_id: None,
+        });
+    }
+}
+
+fn apply_subscription_event(
+    user: &mut User,
+    plans: &PlanCatalog,
+    event_id: &str,
+    event_kind: &str,
+    subscription: &StripeSubscription,
+    now: i64,
+) -> bool {
+    let seen = user
+        .plan_history
+        .iter()
+        .any(|change| change.stripe_event_id.as_deref() == Some(event_id));
+    // A deleted subscription the user already replaced says nothing.
+    let replaced = user
+        .stripe_subscription_id
+        .as_deref()
+        .is_some_and(|id| id != subscription.id);
+    if seen || replaced {
+        return false;
+    }
+    let entry = |from: Option<String>, to: String, status| PlanChange {
+        from,
+        to,
+        status,
+        ts: now,
+        stripe_event_id: Some(event_id.to_string()),
+    };
+
+    if event_kind == "customer.subscription.pending_update_expired" {
+        let Some(pending) = user.pending_plan_change().map(|c| c.to.clone()) else {
+            return false;
+        };
+        let from = user.plan.clone();
+        user.record_plan_change(entry(from, pending, PlanChangeStatus::Expired));
+        return true;
+    }
+    if subscription.pending_update.is_some() {
+        return false;
+    }
+
+    let active = event_kind != "customer.subscription.deleted"
+        && matches!(
+            subscription.status.as_str(),
+            "active" | "trialing" | "past_due"
+        );
+    let price = if active { subscription.price() } else { None };
+    let plan = price.map(|price| plans.plan_for_price(price).unwrap_or(price).to_string());
+    let role = match (&user.role, active) {
+        (UserRole::Admin, _) => UserRole::Admin,
+        (_, true) => UserRole::Paid,
+        (_, false) => UserRole::Free,
+    };
+    let pending_confirmed = user
+        .pending_plan_change()
+        .is_some_and(|change| Some(&change.to) == plan.as_ref());
+    if user.plan == plan && user.role == role && !pending_confirmed {
+        return false;
+    }
+
+    let from = user.plan.clone();
+    user.role = role;
+    user.plan = plan.clone();
+    if active {
+        user.stripe_subscription_id = Some(subscription.id.clone());
+        if user.stripe_customer_id.is_none() {
+            user.stripe_customer_id = subscription.customer.clone();
+        }
+    } else {
+        user.stripe_subscription_id = None;
+    }
+    user.record_plan_change(entry(
+        from,
+        plan.unwrap_or_else(|| "free".to_string()),
+        PlanChangeStatus::Confirmed,
+    ));
+    true
+}
+
+/// Check a `Stripe-Signature` header (`t=<ts>,v1=<hex HMAC-SHA256 of
+/// "<ts>.<body>">`, possibly several `v1`) against `secret`.
+fn signature_valid(secret: &str, header: &str, body: &[u8], now: i64) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.extend(decode_hex(value)),
+            _ => {}
+        }
+    }
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
+    if (now - timestamp).abs() > SIGNATURE_TOLERANCE_SECS {
+        return false;
+    }
+    signatures.iter().any(|signature| {
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.verify_slice(signature).is_ok()
+    })
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> User {
+        serde_json::from_value(serde_json::json!({
+            "id": "u1",
+            "name": null,
+            "external_id": null,
+            "created_ts": 0,
+            "meta": null,
+            "email": null,
+            "password_hash": null,
+            "role": "paid",
+            "stripe_subscription_id": "sub_1",
+        }))
+        .unwrap()
+    }
+
+    fn subscription(price: &str, pending: bool) -> StripeSubscription {
+        let pending_update = pending.then(|| serde_json::json!({ "expires_at": 1 }));
+        serde_json::from_value(serde_json::json!({
+            "id": "sub_1",
+            "customer": "cus_1",
+            "status": "active",
+            "pending_update": pending_update,
+            "items": { "data": [{ "id": "si_1", "price": { "id": price } }] },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn plan_changes_apply_only_when_stripe_confirms_them() {
+        let plans = PlanCatalog::parse("pro=price_pro, team=price_team", "price_basic");
+        assert_eq!(plans.names(), ["pro", "team", "default"]);
+        assert_eq!(plans.plan_for_price("price_team"), Some("team"));
+
+        let mut user = user();
+        user.plan = Some("pro".into());
+        user.record_plan_change(PlanChange {
+            from: Some("pro".into()),
+            to: "team".into(),
+            status: PlanChangeStatus::Requested,
+            ts: 1,
+            stripe_event_id: None,
+        });
+
+        let updated = "customer.subscription.updated";
+        let awaiting_payment = subscription("price_pro", true);
+        assert!(!apply_subscription_event(
+            &mut user,
+            &plans,
+            "evt_1",
+            updated,
+            &awaiting_payment,
+            2
+        ));
+        assert_eq!(user.plan.as_deref(), Some("pro"));
+
+        let paid = subscription("price_team", false);
+        assert!(apply_subscription_event(
+            &mut user, &plans, "evt_2", updated, &paid, 3
+        ));
+        assert_eq!(user.plan.as_deref(), Some("team"));
+        assert!(user.pending_plan_change().is_none());
+        assert!(!apply_subscription_event(
+            &mut user, &plans, "evt_2", updated, &paid, 4
+        ));
+
+        let deleted = "customer.subscription.deleted";
+        assert!(apply_subscription_event(
+            &mut user, &plans, "evt_3", deleted, &paid, 5
+        ));
+        assert_eq!(user.role, UserRole::Free);
+        assert_eq!(user.plan_history.last().unwrap().to, "free");
+    }
+
+    #[test]
+    fn stale_requests_settle_from_the_subscription() {
+        let plans = PlanCatalog::parse("pro=price_pro, team=price_team", "price_basic");
+        let requested = |user: &mut User| {
+            user.plan = Some("pro".into());
+            user.record_plan_change(PlanChange {
+                from: Some("pro".into()),
+                to: "team".into(),
+                status: PlanChangeStatus::Requested,
+                ts: 1,
+                stripe_event_id: None,
+            });
+        };
+
+        let mut dropped = user();
+        requested(&mut dropped);
+        settle_pending(&mut dropped, &plans, &subscription("price_pro", false), 2);
+        assert!(dropped.pending_plan_change().is_none());
+        assert_eq!(dropped.plan.as_deref(), Some("pro"));
+        assert_eq!(
+            dropped.plan_history.last().unwrap().status,
+            PlanChangeStatus::Expired
+        );
+
+        let mut applied = user();
+        requested(&mut applied);
+        settle_pending(&mut applied, &plans, &subscription("price_team", false), 2);
+        assert!(applied.pending_plan_change().is_none());
+        assert_eq!(applied.plan.as_deref(), Some("team"));
+        assert_eq!(
+            applied.plan_history.last().unwrap().status,
+            PlanChangeStatus::Confirmed
+        );
+    }
+
+    #[test]
+    fn webhook_signatures_must_match_and_be_recent() {
+        let body = br#"{"a":1}"#;
+        // Same scheme as outgoing webhooks (`webhooks::signature`).
+        let header = crate::webhooks::signature("whsec_test", 1700000000, body);
+        assert!(signature_valid("whsec_test", &header, body, 1700000100));
+        assert!(!signature_valid("whsec_other", &header, body, 1700000100));
+        assert!(!signature_valid(
+            "whsec_test",
+            &header,
+            br#"{"a":2}"#,
+            1700000100
+        ));
+        assert!(!signature_valid("whsec_test", &header, body, 1700001000));
+        assert!(!signature_valid("whsec_test", "v1=00", body, 1700000000));
+    }
+}
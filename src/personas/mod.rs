@@ -0,0 +1,345 @@
+//! User-defined assistant personas. Paid users save a named system prompt
+//! with an optional temperature and starter messages, then pick it for a
+//! turn with `persona_id` on the ws `prompt` frame. Personas are stored
+//! per user under `persona:{user_id}:{persona_id}`.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use chrono::Utc;
+use headers::{authorization::Bearer, Authorization};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::tokens::authenticate, conversation::MAX_SYSTEM_PROMPT_OVERRIDE_CHARS, model::user::User,
+    ws::AppState,
+};
+
+pub const MAX_PERSONAS_PER_USER: usize = 20;
+const MAX_NAME_CHARS: usize = 64;
+const MAX_STARTER_MESSAGES: usize = 4;
+const MAX_STARTER_MESSAGE_CHARS: usize = 200;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/personas",
+            get(list_personas_handler).post(create_persona_handler),
+        )
+        .route(
+            "/api/personas/{persona_id}",
+            get(get_persona_handler)
+                .put(update_persona_handler)
+                .delete(delete_persona_handler),
+        )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Persona {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    /// Instructions added to the system prompt of turns using the persona.
+    pub system_prompt: String,
+    /// Sampling temperature; the server default when unset.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Suggested first messages for a new chat, shown by clients.
+    #[serde(default)]
+    pub starter_messages: Vec<String>,
+    pub created_ts: i64,
+    pub updated_ts: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PersonaPayload {
+    pub name: String,
+    pub system_prompt: String,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub starter_messages: Vec<String>,
+}
+
+impl PersonaPayload {
+    /// Trimmed copy of the payload, or the error code of the first bad
+    /// field.
+    fn validate(self) -> Result<Self, &'static str> {
+        let name = self.name.trim().to_string();
+        if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+            return Err("invalid_name");
+        }
+        let system_prompt = self.system_prompt.trim().to_string();
+        if system_prompt.is_empty() {
+            return Err("missing_system_prompt");
+        }
+        if system_prompt.chars().count() > MAX_SYSTEM_PROMPT_OVERRIDE_CHARS {
+            return Err("system_prompt_too_long");
+        }
+        if self.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+            return Err("invalid_temperature");
+        }
+        let starter_messages: Vec<String> = self
+            .starter_messages
+            .iter()
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .collect();
+        if starter_messages.len() > MAX_STARTER_MESSAGES
+            || starter_messages
+                .iter()
+                .any(|m| m.chars().count() > MAX_STARTER_MESSAGE_CHARS)
+        {
+            return Err("invalid_starter_messages");
+        }
+        Ok(Self {
+            name,
+            system_prompt,
+            temperature: self.temperature,
+            starter_messages,
+        })
+    }
+}
+
+/// Caller's account; `paid_plan_required` when `require_paid` is set and the
+/// caller is on the free plan.
+async fn caller(
+    state: &AppState,
+    token: &str,
+    require_paid: bool,
+) -> Result<User, (StatusCode, String)> {
+    let claims = authenticate(state, token).await?;
+    let user = state
+        .db
+        .load_user(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::UNAUTHORIZED, "user_not_found".to_string()))?;
+    if require_paid && !user.role.is_paid() {
+        return Err((StatusCode::FORBIDDEN, "paid_plan_required".into()));
+    }
+    Ok(user)
+}
+
+async fn load_persona(
+    state: &AppState,
+    user_id: &str,
+    persona_id: &str,
+) -> Result<Persona, (StatusCode, String)> {
+    state
+        .db
+        .load_persona(user_id, persona_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "persona_not_found".to_string()))
+}
+
+/// The caller's personas, oldest first.
+#[utoipa::path(
+    get,
+    path = "/api/personas",
+    tag = "personas",
+    responses(
+        (status = 200, description = "Personas of the caller", body = [Persona]),
+        (status = 401, description = "invalid_token"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_personas_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Vec<Persona>>, (StatusCode, String)> {
+    let user = caller(&state, auth.token(), false).await?;
+    let mut personas = state
+        .db
+        .list_personas(&user.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    personas.sort_by_key(|p| p.created_ts);
+    Ok(Json(personas))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/personas",
+    tag = "personas",
+    request_body = PersonaPayload,
+    responses(
+        (status = 200, description = "Created persona", body = Persona),
+        (status = 400, description = "invalid_name / missing_system_prompt / system_prompt_too_long / invalid_temperature / invalid_starter_messages"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "paid_plan_required"),
+        (status = 409, description = "persona_limit_reached"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_persona_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<PersonaPayload>,
+) -> Result<Json<Persona>, (StatusCode, String)> {
+    let user = caller(&state, auth.token(), true).await?;
+    let payload = payload
+        .validate()
+        .map_err(|code| (StatusCode::BAD_REQUEST, code.to_string()))?;
+    let existing = state
+        .db
+        .list_personas(&user.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if existing.len() >= MAX_PERSONAS_PER_USER {
+        return Err((StatusCode::CONFLICT, "persona_limit_reached".into()));
+    }
+
+    let now = Utc::now().timestamp();
+    let persona = Persona {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.id,
+        name: payload.name,
+        system_prompt: payload.system_prompt,
+        temperature: payload.temperature,
+        starter_messages: payload.starter_messages,
+        created_ts: now,
+        updated_ts: now,
+    };
+    state
+        .db
+        .save_persona(&persona)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(persona))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/personas/{persona_id}",
+    tag = "personas",
+    params(("persona_id" = String, Path, description = "Persona id")),
+    responses(
+        (status = 200, description = "Persona", body = Persona),
+        (status = 401, description = "invalid_token"),
+        (status = 404, description = "persona_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn get_persona_handler(
+    State(state): State<AppState>,
+    Path(persona_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Persona>, (StatusCode, String)> {
+    let user = caller(&state, auth.token(), false).await?;
+    load_persona(&state, &user.id, &persona_id).await.map(Json)
+}
+
+/// Replace a persona's fields; its id and creation time are kept.
+#[utoipa::path(
+    put,
+    path = "/api/personas/{persona_id}",
+    tag = "personas",
+    params(("persona_id" = String, Path, description = "Persona id")),
+    request_body = PersonaPayload,
+    responses(
+        (status = 200, description = "Updated persona", body = Persona),
+        (status = 400, description = "invalid_name / missing_system_prompt / system_prompt_too_long / invalid_temperature / invalid_starter_messages"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "paid_plan_required"),
+        (status = 404, description = "persona_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn update_persona_handler(
+    State(state): State<AppState>,
+    Path(persona_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<PersonaPayload>,
+) -> Result<Json<Persona>, (StatusCode, String)> {
+    let user = caller(&state, auth.token(), true).await?;
+    let payload = payload
+        .validate()
+        .map_err(|code| (StatusCode::BAD_REQUEST, code.to_string()))?;
+    let mut persona = load_persona(&state, &user.id, &persona_id).await?;
+    persona.name = payload.name;
+    persona.system_prompt = payload.system_prompt;
+    persona.temperature = payload.temperature;
+    persona.starter_messages = payload.starter_messages;
+    persona.updated_ts = Utc::now().timestamp();
+    state
+        .db
+        .save_persona(&persona)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(persona))
+}
+
+/// Delete a persona. Prompts still naming it fall back to no persona.
+#[utoipa::path(
+    delete,
+    path = "/api/personas/{persona_id}",
+    tag = "personas",
+    params(("persona_id" = String, Path, description = "Persona id")),
+    responses(
+        (status = 200, description = "`{ persona_id, deleted: true }`"),
+        (status = 401, description = "invalid_token"),
+        (status = 404, description = "persona_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_persona_handler(
+    State(state): State<AppState>,
+    Path(persona_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user = caller(&state, auth.token(), false).await?;
+    load_persona(&state, &user.id, &persona_id).await?;
+    state
+        .db
+        .delete_persona(&user.id, &persona_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(
+        serde_json::json!({ "persona_id": persona_id, "deleted": true }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> PersonaPayload {
+        PersonaPayload {
+            name: "  Pirate ".into(),
+            system_prompt: "Talk like a pirate.".into(),
+            temperature: Some(0.9),
+            starter_messages: vec!["Ahoy?".into(), "  ".into()],
+        }
+    }
+
+    #[test]
+    fn validates_persona_fields() {
+        let ok = payload().validate().unwrap();
+        assert_eq!(ok.name, "Pirate");
+        assert_eq!(ok.starter_messages, vec!["Ahoy?".to_string()]);
+
+        let bad_temp = PersonaPayload {
+            temperature: Some(2.5),
+            ..payload()
+        };
+        assert_eq!(bad_temp.validate().unwrap_err(), "invalid_temperature");
+        let no_name = PersonaPayload {
+            name: " ".into(),
+            ..payload()
+        };
+        assert_eq!(no_name.validate().unwrap_err(), "invalid_name");
+        let many = PersonaPayload {
+            starter_messages: vec!["hi".into(); MAX_STARTER_MESSAGES + 1],
+            ..payload()
+        };
+        assert_eq!(many.validate().unwrap_err(), "invalid_starter_messages");
+    }
+}
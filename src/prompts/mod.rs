@@ -1,10 +1,28 @@
+//! Per-language system prompts. Every `<lang>/prompts.json` under
+//! `PROMPTS_DIR` (default `lang`) is loaded at startup and on reload, so a
+//! new language (`de`, `fr`, `pt-BR`, ...) only needs a directory. The
+//! copies of `en`, `es`, `ru` and `pt` compiled in from the repo stand in
+//! for files that are missing or invalid. Lookups walk a fallback chain,
+//! regional → base language → `en`, key by key. Templates edited from the
+//! admin API are stored in RocksDB and layered on top of the files, so they
+//! survive reloads and restarts.
+
 use crate::classifier::routing::ReasoningProfile;
+use anyhow::{anyhow, Context, Result};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
-use std::collections::HashMap;
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::{debug, info, warn};
 
 const DEFAULT_INTENT: &str = "chat_casual";
+/// Key that addresses a language's fallback prompt in overrides.
+pub const DEFAULT_PROMPT_KEY: &str = "default";
+/// Instructions for the short chat title.
+pub const SUMMARY_PROMPT_KEY: &str = "chat_summary";
 const CHAT_LAYER_ENGAGEMENT_HINT: &str =
     "Always be engaged in conversation, ask follow-up questions, and seek clarifications when needed.";
 
@@ -14,9 +32,21 @@ struct PromptFile {
     prompts: HashMap<String, String>,
 }
 
-struct LanguagePromptSet {
-    default_prompt: String,
-    prompts: HashMap<String, String>,
+#[derive(Debug, Clone)]
+pub struct LanguagePromptSet {
+    pub default_prompt: String,
+    pub prompts: HashMap<String, String>,
+    /// File the set was read from; `None` for a compiled-in copy.
+    pub path: Option<PathBuf>,
+}
+
+/// Template edited from the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptOverride {
+    pub language: String,
+    pub key: String,
+    pub template: String,
+    pub updated_ts: i64,
 }
 
 macro_rules! prompt_file {
@@ -30,44 +60,332 @@ macro_rules! prompt_file {
     };
 }
 
-static EN_PROMPTS: Lazy<LanguagePromptSet> = Lazy::new(|| load_prompt_set(prompt_file!("en")));
-static ES_PROMPTS: Lazy<LanguagePromptSet> = Lazy::new(|| load_prompt_set(prompt_file!("es")));
-static RU_PROMPTS: Lazy<LanguagePromptSet> = Lazy::new(|| load_prompt_set(prompt_file!("ru")));
-static PT_PROMPTS: Lazy<LanguagePromptSet> = Lazy::new(|| load_prompt_set(prompt_file!("pt")));
+const BUILTIN: [(&str, &str); 4] = [
+    ("en", prompt_file!("en")),
+    ("es", prompt_file!("es")),
+    ("ru", prompt_file!("ru")),
+    ("pt", prompt_file!("pt")),
+];
+
+const FALLBACK_LANGUAGE: &str = "en";
+
+struct PromptStore {
+    files: HashMap<String, LanguagePromptSet>,
+    overrides: HashMap<(String, String), PromptOverride>,
+    effective: HashMap<String, Arc<LanguagePromptSet>>,
+}
+
+impl PromptStore {
+    fn new(files: HashMap<String, LanguagePromptSet>) -> Self {
+        let mut store = Self {
+            files,
+            overrides: HashMap::new(),
+            effective: HashMap::new(),
+        };
+        store.rebuild();
+        store
+    }
 
-fn load_prompt_set(raw: &str) -> LanguagePromptSet {
-    let parsed: PromptFile = serde_json::from_str(raw).expect("invalid prompt config");
-    LanguagePromptSet {
+    /// Files with the overrides applied.
+    fn rebuild(&mut self) {
+        self.effective = self
+            .files
+            .iter()
+            .map(|(lang, file)| {
+                let mut set = file.clone();
+                for ((o_lang, key), item) in &self.overrides {
+                    if o_lang != lang {
+                        continue;
+                    }
+                    if key == DEFAULT_PROMPT_KEY {
+                        set.default_prompt = item.template.clone();
+                    } else {
+                        set.prompts.insert(key.clone(), item.template.clone());
+                    }
+                }
+                (lang.clone(), Arc::new(set))
+            })
+            .collect();
+    }
+
+    /// Sets along the fallback chain of `language`; never empty.
+    fn chain(&self, language: Option<&str>) -> Vec<(String, Arc<LanguagePromptSet>)> {
+        let mut languages: Vec<&str> = self.effective.keys().map(String::as_str).collect();
+        languages.sort_unstable();
+        fallback_chain(language.unwrap_or(FALLBACK_LANGUAGE), &languages)
+            .into_iter()
+            .filter_map(|lang| {
+                let set = self.effective.get(&lang)?.clone();
+                Some((lang, set))
+            })
+            .collect()
+    }
+}
+
+static STORE: Lazy<RwLock<PromptStore>> = Lazy::new(|| {
+    let files = match load_files(false) {
+        Ok(files) => files,
+        Err(err) => {
+            warn!("prompts: {err:#}; using the built-in copies");
+            builtin_files()
+        }
+    };
+    RwLock::new(PromptStore::new(files))
+});
+
+fn prompts_dir() -> PathBuf {
+    std::env::var("PROMPTS_DIR")
+        .unwrap_or_else(|_| "lang".to_string())
+        .into()
+}
+
+/// `pt_BR` and `PT-br` both become `pt-br`.
+pub fn normalize_language(language: &str) -> String {
+    language.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+/// Languages to try for `language`, most specific first: the regional
+/// variant, its base language, then `en`. Only languages in `available`
+/// are kept.
+pub fn fallback_chain(language: &str, available: &[&str]) -> Vec<String> {
+    let normalized = normalize_language(language);
+    let mut candidates = vec![normalized.clone()];
+    if let Some((base, _)) = normalized.split_once('-') {
+        candidates.push(base.to_string());
+    }
+    candidates.push(FALLBACK_LANGUAGE.to_string());
+
+    let mut chain: Vec<String> = Vec::new();
+    for lang in candidates {
+        if available.contains(&lang.as_str()) && !chain.contains(&lang) {
+            chain.push(lang);
+        }
+    }
+    chain
+}
+
+fn parse_prompt_set(raw: &str, path: Option<PathBuf>) -> Result<LanguagePromptSet> {
+    let parsed: PromptFile = serde_json::from_str(raw)?;
+    Ok(LanguagePromptSet {
         default_prompt: parsed.default,
         prompts: parsed.prompts,
+        path,
+    })
+}
+
+fn builtin_files() -> HashMap<String, LanguagePromptSet> {
+    BUILTIN
+        .iter()
+        .map(|(lang, raw)| {
+            let set = parse_prompt_set(raw, None).expect("invalid built-in prompt config");
+            (lang.to_string(), set)
+        })
+        .collect()
+}
+
+/// Every language directory under `PROMPTS_DIR` with a `prompts.json`,
+/// plus the compiled-in languages it lacks. With `strict` set an invalid
+/// file is an error; otherwise it is skipped (or replaced by the
+/// compiled-in copy) with a warning.
+fn load_files(strict: bool) -> Result<HashMap<String, LanguagePromptSet>> {
+    let dir = prompts_dir();
+    let mut files = HashMap::new();
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).collect(),
+        Err(err) if strict => {
+            return Err(anyhow!("reading {}: {err}", dir.display()));
+        }
+        Err(err) => {
+            warn!("prompts dir {}: {err}", dir.display());
+            Vec::new()
+        }
+    };
+    for entry in entries {
+        let path = entry.path().join("prompts.json");
+        if !path.is_file() {
+            continue;
+        }
+        let lang = normalize_language(&entry.file_name().to_string_lossy());
+        let parsed = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))
+            .and_then(|raw| {
+                parse_prompt_set(&raw, Some(path.clone()))
+                    .with_context(|| format!("parsing {}", path.display()))
+            });
+        match parsed {
+            Ok(set) => {
+                files.insert(lang, set);
+            }
+            Err(err) if strict => return Err(err),
+            Err(err) => warn!("prompts for {lang}: {err:#}"),
+        }
     }
+    for (lang, builtin) in builtin_files() {
+        files.entry(lang.clone()).or_insert_with(|| {
+            warn!("no prompt file for {lang}; using the built-in copy");
+            builtin
+        });
+    }
+    Ok(files)
 }
 
-fn language_prompts(language: Option<&str>) -> &'static LanguagePromptSet {
-    let normalized = language
-        .and_then(|lang| lang.split(|c| c == '-' || c == '_').next())
-        .unwrap_or("en")
-        .to_ascii_lowercase();
+/// Re-read the prompts dir, picking up new languages. Nothing changes
+/// unless every file parses. Admin overrides stay on top of the new files.
+/// Returns the number of templates per language.
+pub fn reload() -> Result<HashMap<String, usize>> {
+    let files = load_files(true)?;
+    let counts: HashMap<String, usize> = files
+        .iter()
+        .map(|(lang, set)| (lang.clone(), set.prompts.len()))
+        .collect();
+    let mut store = STORE.write().expect("prompt store lock poisoned");
+    store.files = files;
+    store.rebuild();
+    info!(
+        dir = %prompts_dir().display(),
+        languages = counts.len(),
+        "prompts reloaded"
+    );
+    Ok(counts)
+}
+
+/// Install the overrides persisted in the DB; called once at startup.
+/// Overrides for languages without a prompt file are kept and apply once
+/// the language is added.
+pub fn load_overrides(items: Vec<PromptOverride>) {
+    let mut store = STORE.write().expect("prompt store lock poisoned");
+    store.overrides = items
+        .into_iter()
+        .map(|item| ((item.language.clone(), item.key.clone()), item))
+        .collect();
+    store.rebuild();
+}
 
-    match normalized.as_str() {
-        "es" => &ES_PROMPTS,
-        "ru" => &RU_PROMPTS,
-        "pt" => &PT_PROMPTS,
-        _ => &EN_PROMPTS,
+/// Apply one override in memory, or drop it with `None`. The caller
+/// persists it.
+pub fn set_override(language: &str, key: &str, item: Option<PromptOverride>) -> Result<()> {
+    let language = normalize_language(language);
+    let mut store = STORE.write().expect("prompt store lock poisoned");
+    if !store.files.contains_key(&language) {
+        return Err(anyhow!("unknown language {language}"));
     }
+    let id = (language, key.to_string());
+    match item {
+        Some(item) => store.overrides.insert(id, item),
+        None => store.overrides.remove(&id),
+    };
+    store.rebuild();
+    Ok(())
+}
+
+/// Overrides in effect, by language and key.
+pub fn overrides() -> Vec<PromptOverride> {
+    let store = STORE.read().expect("prompt store lock poisoned");
+    let mut items: Vec<PromptOverride> = store.overrides.values().cloned().collect();
+    items.sort_by(|a, b| (&a.language, &a.key).cmp(&(&b.language, &b.key)));
+    items
+}
+
+/// Languages with a prompt file (or a compiled-in copy), sorted.
+pub fn known_languages() -> Vec<String> {
+    let store = STORE.read().expect("prompt store lock poisoned");
+    let mut languages: Vec<String> = store.files.keys().cloned().collect();
+    languages.sort();
+    languages
+}
+
+/// Default prompt and the keyed templates of the first language on the
+/// fallback chain of `language`, as in effect.
+pub fn prompt_templates(language: &str) -> Arc<LanguagePromptSet> {
+    let store = STORE.read().expect("prompt store lock poisoned");
+    store.chain(Some(language))[0].1.clone()
+}
+
+/// Per key, the language along the fallback chain that supplies it.
+#[derive(Debug, Serialize)]
+pub struct LanguageCoverage {
+    pub language: String,
+    /// `None` when the compiled-in copy is used.
+    pub file: Option<String>,
+    pub chain: Vec<String>,
+    pub templates: usize,
+    pub overrides: usize,
+    /// Keys some language has but this one lacks, with the language on the
+    /// chain that answers for them (`None` falls back to `chat_casual` or
+    /// the default prompt).
+    pub missing: BTreeMap<String, Option<String>>,
+}
+
+/// Which prompt keys each language is missing and where they come from.
+pub fn coverage() -> Vec<LanguageCoverage> {
+    let store = STORE.read().expect("prompt store lock poisoned");
+    let all_keys: BTreeSet<&String> = store
+        .effective
+        .values()
+        .flat_map(|set| set.prompts.keys())
+        .collect();
+
+    let mut languages: Vec<&String> = store.effective.keys().collect();
+    languages.sort();
+    languages
+        .into_iter()
+        .map(|lang| {
+            let chain = store.chain(Some(lang));
+            let own = &chain[0].1;
+            let missing = all_keys
+                .iter()
+                .filter(|key| !own.prompts.contains_key(key.as_str()))
+                .map(|key| {
+                    let from = chain
+                        .iter()
+                        .skip(1)
+                        .find(|(_, set)| set.prompts.contains_key(key.as_str()))
+                        .map(|(l, _)| l.clone());
+                    (key.to_string(), from)
+                })
+                .collect();
+            LanguageCoverage {
+                language: lang.clone(),
+                file: own.path.as_ref().map(|p| p.display().to_string()),
+                chain: chain.iter().map(|(l, _)| l.clone()).collect(),
+                templates: own.prompts.len(),
+                overrides: store.overrides.keys().filter(|(l, _)| l == lang).count(),
+                missing,
+            }
+        })
+        .collect()
 }
 
 pub fn default_intent() -> &'static str {
     DEFAULT_INTENT
 }
 
+/// Template for `intent` from the first language on the fallback chain
+/// that has it, then `chat_casual` the same way, then the default prompt
+/// of the requested language.
 pub fn prompt_for_intent(intent: &str, language: Option<&str>) -> String {
-    let set = language_prompts(language);
-    set.prompts
-        .get(intent)
+    let chain = STORE
+        .read()
+        .expect("prompt store lock poisoned")
+        .chain(language);
+    [intent, DEFAULT_INTENT]
+        .iter()
+        .find_map(|key| chain.iter().find_map(|(_, set)| set.prompts.get(*key)))
+        .cloned()
+        .unwrap_or_else(|| chain[0].1.default_prompt.clone())
+}
+
+/// Template for `key` from the first language on the fallback chain that
+/// has it; unlike [`prompt_for_intent`] there is no fallback to another key.
+pub fn prompt_for_key(key: &str, language: Option<&str>) -> Option<String> {
+    let chain = STORE
+        .read()
+        .expect("prompt store lock poisoned")
+        .chain(language);
+    chain
+        .iter()
+        .find_map(|(_, set)| set.prompts.get(key))
         .cloned()
-        .or_else(|| set.prompts.get(DEFAULT_INTENT).cloned())
-        .unwrap_or_else(|| set.default_prompt.clone())
 }
 
 pub fn resolved_prompt_key(intent: &str, profile: Option<ReasoningProfile>) -> String {
@@ -115,6 +433,7 @@ pub enum Initiative {
 #[derive(Clone, Copy, Debug)]
 pub enum Constraint {
     ExplainSteps,
+    AddressAllParts,
 }
 
 #[derive(Clone, Debug)]
@@ -126,8 +445,34 @@ pub struct PromptPlan {
     pub constraints: Vec<Constraint>,
 }
 
+/// Domain overrides ensure technical content always lands in safe prompts.
+pub fn plan_prompt_key(prompt_key: &str, domain: &str, expectation: &str) -> String {
+    match domain {
+        "technical" if matches!(expectation, "INFO" | "ADVICE" | "ACTION") => "reasoning".into(),
+        "legal" => "advice_practical".into(),
+        _ => prompt_key.to_string(),
+    }
+}
+
 pub fn build_prompt_plan(routing: &crate::classifier::routing::IntentRoutingResult) -> PromptPlan {
     let mut base_prompt = routing.prompt_key.clone();
+    let multi_part = routing.segments.len() > 1;
+
+    // Safety prompts set by `crate::moderation` are used as they are.
+    if base_prompt.starts_with("safety_") {
+        let tone = if base_prompt == "safety_self_harm" {
+            Tone::Supportive
+        } else {
+            Tone::Neutral
+        };
+        return PromptPlan {
+            base_prompt,
+            tone,
+            depth: Depth::Shallow,
+            initiative: Initiative::Reactive,
+            constraints: Vec::new(),
+        };
+    }
 
     if routing.support_intent {
         return PromptPlan {
@@ -135,23 +480,18 @@ pub fn build_prompt_plan(routing: &crate::classifier::routing::IntentRoutingResu
             tone: Tone::Supportive,
             depth: Depth::Shallow,
             initiative: Initiative::Suggestive,
-            constraints: Vec::new(),
+            constraints: multi_part
+                .then_some(Constraint::AddressAllParts)
+                .into_iter()
+                .collect(),
         };
     }
 
-    // Domain overrides ensure technical content always lands in safe prompts.
-    match routing.domain.label.as_str() {
-        "technical"
-            if matches!(
-                routing.expectation.label.as_str(),
-                "INFO" | "ADVICE" | "ACTION"
-            ) =>
-        {
-            base_prompt = "reasoning".into()
-        }
-        "legal" => base_prompt = "advice_practical".into(),
-        _ => {}
-    }
+    base_prompt = plan_prompt_key(
+        &base_prompt,
+        &routing.domain.label,
+        &routing.expectation.label,
+    );
 
     // Tone is primarily influenced by the speech act.
     let is_statement_like =
@@ -195,6 +535,9 @@ pub fn build_prompt_plan(routing: &crate::classifier::routing::IntentRoutingResu
     {
         constraints.push(Constraint::ExplainSteps);
     }
+    if multi_part {
+        constraints.push(Constraint::AddressAllParts);
+    }
 
     PromptPlan {
         base_prompt,
@@ -230,6 +573,9 @@ pub fn render_prompt(plan: &PromptPlan, language: Option<&str>) -> String {
     for constraint in &plan.constraints {
         match constraint {
             Constraint::ExplainSteps => prompt.push_str("\nExplain your reasoning step by step."),
+            Constraint::AddressAllParts => prompt.push_str(
+                "\nThe message contains several separate requests; address each of them.",
+            ),
         }
     }
 
@@ -244,3 +590,17 @@ pub fn render_prompt(plan: &PromptPlan, language: Option<&str>) -> String {
 
     prompt
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_chain_goes_regional_base_english() {
+        let available = ["de", "en", "pt", "pt-br"];
+        assert_eq!(fallback_chain("pt_BR", &available), ["pt-br", "pt", "en"]);
+        assert_eq!(fallback_chain("de-AT", &available), ["de", "en"]);
+        assert_eq!(fallback_chain("en-US", &available), ["en"]);
+        assert_eq!(fallback_chain("uk", &available), ["en"]);
+    }
+}
@@ -0,0 +1,230 @@
+//! PII redaction. Email addresses, phone numbers and payment card numbers
+//! are replaced with `[email]`, `[phone]` and `[card]` before user text
+//! reaches the logs and, when the deployment asks for it, before messages
+//! are stored. Patterns find the candidates; card numbers must pass the
+//! Luhn check, and phone-like digit runs are scored on their shape and the
+//! words around them, so years, prices and order numbers stay readable.
+//!
+//! `PII_REDACTION` picks where it applies: `logs` (default) redacts
+//! tracing fields only, `all` also the stored message and attachment text,
+//! and `off` turns it off.
+
+use std::borrow::Cow;
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use crate::model::message::Message;
+
+/// Phone candidates scoring below this are left alone.
+const PHONE_THRESHOLD: f32 = 0.5;
+/// How far before a phone candidate to look for words like "call".
+const CONTEXT_CHARS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactionMode {
+    Off,
+    Logs,
+    All,
+}
+
+impl RedactionMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" | "none" | "0" | "false" => Some(Self::Off),
+            "logs" | "" => Some(Self::Logs),
+            "all" | "storage" => Some(Self::All),
+            _ => None,
+        }
+    }
+}
+
+static MODE: Lazy<RedactionMode> = Lazy::new(|| {
+    let value = std::env::var("PII_REDACTION").unwrap_or_default();
+    RedactionMode::parse(&value).unwrap_or_else(|| {
+        tracing::warn!(
+            value = value.as_str(),
+            "unknown PII_REDACTION, redacting logs"
+        );
+        RedactionMode::Logs
+    })
+});
+
+static EMAIL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b")
+        .expect("email pattern")
+});
+
+static CARD: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").expect("card pattern"));
+
+static PHONE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\+?\(?\d[\d ().-]{5,}\d").expect("phone pattern"));
+
+static PHONE_CONTEXT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)phone|\btel\b|call|text me|mobile|cell|whatsapp|телефон|тел\.|номер|звони|tel[eé]fono|telefone|celular|m[oó]vil|llam")
+        .expect("phone context pattern")
+});
+
+static DATE_LIKE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:\d{4}\s*-\s*\d{4}|\d{1,4}[./-]\d{1,2}[./-]\d{1,4})$").expect("date pattern")
+});
+
+/// Where redaction applies in this deployment (`PII_REDACTION`, read
+/// once).
+pub fn mode() -> RedactionMode {
+    *MODE
+}
+
+/// `text` with every email, phone and card number replaced.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    if !text.bytes().any(|b| b.is_ascii_digit() || b == b'@') {
+        return Cow::Borrowed(text);
+    }
+    let emails = EMAIL.replace_all(text, "[email]");
+    let cards = CARD.replace_all(&emails, |caps: &Captures| {
+        if is_card_number(&caps[0]) {
+            "[card]".to_string()
+        } else {
+            caps[0].to_string()
+        }
+    });
+    let haystack: &str = &cards;
+    let phones = PHONE.replace_all(haystack, |caps: &Captures| {
+        let hit = caps.get(0).expect("whole match");
+        if phone_score(haystack, hit.start(), hit.end()) >= PHONE_THRESHOLD {
+            "[phone]".to_string()
+        } else {
+            hit.as_str().to_string()
+        }
+    });
+    if phones == text {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(phones.into_owned())
+    }
+}
+
+/// User text for a tracing field: redacted unless `PII_REDACTION=off`.
+pub fn for_log(text: &str) -> Cow<'_, str> {
+    match mode() {
+        RedactionMode::Off => Cow::Borrowed(text),
+        RedactionMode::Logs | RedactionMode::All => redact(text),
+    }
+}
+
+/// A message as it should be stored: text and attachment descriptions
+/// redacted under `PII_REDACTION=all`, untouched otherwise.
+pub fn message_for_storage(mut msg: Message) -> Message {
+    if mode() != RedactionMode::All {
+        return msg;
+    }
+    redact_option(&mut msg.text);
+    for attachment in msg.attachments.iter_mut() {
+        redact_option(&mut attachment.description);
+        redact_option(&mut attachment.ocr_text);
+    }
+    msg
+}
+
+fn redact_option(target: &mut Option<String>) {
+    if let Some(text) = target.as_mut() {
+        if let Cow::Owned(redacted) = redact(text) {
+            *text = redacted;
+        }
+    }
+}
+
+/// 13 to 19 digits passing the Luhn check, not all the same digit.
+fn is_card_number(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) || digits.iter().all(|d| *d == digits[0]) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match (i % 2 == 1, d * 2) {
+            (true, doubled) if doubled > 9 => doubled - 9,
+            (true, doubled) => doubled,
+            (false, _) => *d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// How much a digit run in `text[start..end]` looks like a phone number:
+/// a leading `+` or area code in parentheses, digit groups, ten or more
+/// digits, and words like "call" or "телефон" just before it count for
+/// it; dates and year ranges against it.
+fn phone_score(text: &str, start: usize, end: usize) -> f32 {
+    let candidate = &text[start..end];
+    let glued = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    if glued(text[..start].chars().next_back()) || glued(text[end..].chars().next()) {
+        return 0.0;
+    }
+    let digits = candidate.chars().filter(char::is_ascii_digit).count();
+    if !(7..=15).contains(&digits) {
+        return 0.0;
+    }
+    let mut score = 0.0;
+    if candidate.starts_with('+') {
+        score += 0.4;
+    }
+    if candidate.contains('(') && candidate.contains(')') {
+        score += 0.2;
+    }
+    if digits >= 10 {
+        score += 0.3;
+    }
+    let groups: Vec<&str> = candidate
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|group| !group.is_empty())
+        .collect();
+    if groups.len() >= 2 && groups.iter().all(|group| group.len() <= 4) {
+        score += 0.2;
+    }
+    let context_start = text[..start]
+        .char_indices()
+        .rev()
+        .nth(CONTEXT_CHARS - 1)
+        .map_or(0, |(index, _)| index);
+    if PHONE_CONTEXT.is_match(&text[context_start..start]) {
+        score += 0.4;
+    }
+    if DATE_LIKE.is_match(candidate.trim()) {
+        score -= 0.5;
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_contacts_and_cards_but_keeps_numbers_that_are_not() {
+        assert_eq!(
+            redact("mail me at jane.doe+work@example.co.uk please"),
+            "mail me at [email] please"
+        );
+        assert_eq!(
+            redact("my card is 4111 1111 1111 1111, exp 12/27"),
+            "my card is [card], exp 12/27"
+        );
+        assert_eq!(redact("call me on +1 (555) 123-4567"), "call me on [phone]");
+        assert_eq!(redact("my number: 555-123-4567"), "my number: [phone]");
+        assert_eq!(redact("звоните 8 912 345 67 89"), "звоните [phone]");
+
+        for kept in [
+            "order 1234567890123456 shipped",
+            "it happened in 2019 - 2021",
+            "due on 2024-01-15",
+            "it costs 1299.99 dollars",
+            "no digits here",
+        ] {
+            assert!(matches!(redact(kept), Cow::Borrowed(_)), "{kept}");
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Data retention. Messages older than `RETENTION_FREE_DAYS` (free users
+//! and device-only chats) or `RETENTION_PAID_DAYS` (paid users and admins)
+//! are deleted, and device-only chats untouched for
+//! `RETENTION_DEVICE_CHAT_DAYS` are purged whole, every
+//! `RETENTION_INTERVAL_SECS`. An admin can give one user other terms; the
+//! override is stored under `retention_override:{user_id}`. `0` days keeps
+//! data forever, which is the default for every policy. A chat left
+//! without messages is deleted with its metadata.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    db::DBLayer,
+    model::{chat::Chat, message::Message},
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+fn env_u32(name: &str) -> u32 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+}
+
+/// Days data is kept; `0` keeps it forever.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct RetentionPolicy {
+    pub free_days: u32,
+    pub paid_days: u32,
+    /// Inactivity after which a chat without an owning user is purged.
+    pub device_chat_days: u32,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            free_days: env_u32("RETENTION_FREE_DAYS"),
+            paid_days: env_u32("RETENTION_PAID_DAYS"),
+            device_chat_days: env_u32("RETENTION_DEVICE_CHAT_DAYS"),
+        }
+    }
+
+    fn message_days(&self, paid: bool) -> u32 {
+        if paid {
+            self.paid_days
+        } else {
+            self.free_days
+        }
+    }
+}
+
+/// Terms for one user that replace the plan's.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RetentionOverride {
+    pub user_id: String,
+    /// Days the user's messages are kept; `0` keeps them forever.
+    pub message_days: u32,
+    #[serde(default)]
+    pub note: Option<String>,
+    pub updated_ts: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionReason {
+    MessageAge,
+    InactiveDeviceChat,
+}
+
+/// What a run removes from one chat.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ChatRetention {
+    pub chat_id: String,
+    pub user_id: Option<String>,
+    pub device_hash: Option<String>,
+    pub reason: RetentionReason,
+    /// Messages before this (or, for inactive chats, activity before it)
+    /// are removed.
+    pub cutoff_ts: i64,
+    pub messages: usize,
+    /// The chat goes with its messages.
+    pub whole_chat: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RetentionReport {
+    /// Nothing was deleted; `chats` is what a run would delete.
+    pub dry_run: bool,
+    pub policy: RetentionPolicy,
+    pub overrides: usize,
+    pub chats: Vec<ChatRetention>,
+    pub messages: usize,
+    /// Chats and messages actually removed; `0` on a dry run.
+    pub deleted_chats: usize,
+    pub deleted_messages: usize,
+}
+
+/// What the policy removes from `chat`, whose stored messages are
+/// `messages`; `None` when it keeps everything.
+fn plan_chat(
+    chat: &Chat,
+    messages: &[Message],
+    message_days: u32,
+    device_chat_days: u32,
+    now: i64,
+) -> Option<ChatRetention> {
+    let plan = |reason, cutoff_ts, count, whole_chat| ChatRetention {
+        chat_id: chat.id.clone(),
+        user_id: chat.user_id.clone(),
+        device_hash: chat.device_hash.clone(),
+        reason,
+        cutoff_ts,
+        messages: count,
+        whole_chat,
+    };
+    if chat.user_id.is_none() && device_chat_days > 0 {
+        let cutoff = now - i64::from(device_chat_days) * DAY_SECS;
+        if chat.updated_ts < cutoff {
+            return Some(plan(
+                RetentionReason::InactiveDeviceChat,
+                cutoff,
+                messages.len(),
+                true,
+            ));
+        }
+    }
+    if message_days == 0 {
+        return None;
+    }
+    let cutoff = now - i64::from(message_days) * DAY_SECS;
+    let expired = messages.iter().filter(|m| m.ts < cutoff).count();
+    (expired > 0).then(|| {
+        plan(
+            RetentionReason::MessageAge,
+            cutoff,
+            expired,
+            expired == messages.len(),
+        )
+    })
+}
+
+/// Find, and unless `dry_run` delete, the chats and messages past their
+/// retention. Sandbox chats are left alone.
+pub async fn enforce(db: &DBLayer, dry_run: bool) -> Result<RetentionReport> {
+    let policy = RetentionPolicy::from_env();
+    let overrides: HashMap<String, u32> = db
+        .list_retention_overrides()
+        .await?
+        .into_iter()
+        .map(|o| (o.user_id, o.message_days))
+        .collect();
+    let now = chrono::Utc::now().timestamp();
+    let mut report = RetentionReport {
+        dry_run,
+        policy,
+        overrides: overrides.len(),
+        chats: Vec::new(),
+        messages: 0,
+        deleted_chats: 0,
+        deleted_messages: 0,
+    };
+
+    let mut paid_users: HashMap<String, bool> = HashMap::new();
+    for chat in db.list_chats().await? {
+        if chat.sandbox {
+            continue;
+        }
+        let message_days = match chat.user_id.as_deref() {
+            Some(user_id) => match overrides.get(user_id) {
+                Some(days) => *days,
+                None => {
+                    let paid = match paid_users.get(user_id) {
+                        Some(paid) => *paid,
+                        None => {
+                            let paid = db
+                                .load_user(user_id)
+                                .await?
+                                .is_some_and(|user| user.role.is_paid());
+                            paid_users.insert(user_id.to_string(), paid);
+                            paid
+                        }
+                    };
+                    policy.message_days(paid)
+                }
+            },
+            None => policy.free_days,
+        };
+        if message_days == 0 && (chat.user_id.is_some() || policy.device_chat_days == 0) {
+            continue;
+        }
+
+        let messages = db.list_messages_for_chat(&chat.id).await?;
+        let Some(plan) = plan_chat(&chat, &messages, message_days, policy.device_chat_days, now)
+        else {
+            continue;
+        };
+        if !dry_run {
+            // A chat that moved on since it was listed keeps its new
+            // messages: only the expired ones go, or nothing if it was
+            // purged for inactivity.
+            let unchanged = plan.whole_chat
+                && db
+                    .load_chat(&chat.id)
+                    .await?
+                    .is_some_and(|current| current.updated_ts == chat.updated_ts);
+            if unchanged {
+                db.delete_thread(&chat.id).await?;
+                report.deleted_chats += 1;
+                report.deleted_messages += plan.messages;
+            } else if plan.reason == RetentionReason::InactiveDeviceChat {
+                continue;
+            } else {
+                let expired: Vec<Message> = messages
+                    .into_iter()
+                    .filter(|m| m.ts < plan.cutoff_ts)
+                    .collect();
+                report.deleted_messages += db.delete_messages(&expired).await?;
+            }
+        }
+        report.messages += plan.messages;
+        report.chats.push(plan);
+    }
+    Ok(report)
+}
+
+/// `RETENTION_INTERVAL_SECS` (default daily, `0` disables enforcement).
+pub fn spawn_retention(db: Arc<DBLayer>) -> bool {
+    let interval = std::env::var("RETENTION_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_INTERVAL_SECS);
+    if interval == 0 {
+        return false;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick fires at once; let the server finish starting.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match enforce(&db, false).await {
+                Ok(report) if report.deleted_chats + report.deleted_messages > 0 => info!(
+                    chats = report.deleted_chats,
+                    messages = report.deleted_messages,
+                    "retention removed expired data"
+                ),
+                Ok(_) => {}
+                Err(err) => warn!("retention run failed: {err}"),
+            }
+        }
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(user_id: Option<&str>, updated_ts: i64) -> Chat {
+        serde_json::from_value(serde_json::json!({
+            "id": "c1",
+            "title": null,
+            "user_id": user_id,
+            "device_hash": "d1",
+            "updated_ts": updated_ts,
+            "meta": null,
+        }))
+        .unwrap()
+    }
+
+    fn message(ts: i64) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": format!("m{ts}"),
+            "chat_id": "c1",
+            "session_id": null,
+            "user_id": null,
+            "device_hash": null,
+            "role": "user",
+            "text": "hi",
+            "ts": ts,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn plans_expired_messages_and_inactive_device_chats() {
+        let now = 100 * DAY_SECS;
+        let messages = [message(now - 40 * DAY_SECS), message(now - DAY_SECS)];
+
+        let plan = plan_chat(&chat(Some("u1"), now), &messages, 30, 7, now).unwrap();
+        assert_eq!(plan.reason, RetentionReason::MessageAge);
+        assert_eq!((plan.messages, plan.whole_chat), (1, false));
+        assert!(plan_chat(&chat(Some("u1"), now), &messages, 0, 7, now).is_none());
+
+        let plan = plan_chat(&chat(Some("u1"), now), &messages[..1], 30, 0, now).unwrap();
+        assert!(plan.whole_chat);
+
+        let idle = chat(None, now - 10 * DAY_SECS);
+        let plan = plan_chat(&idle, &messages, 0, 7, now).unwrap();
+        assert_eq!(plan.reason, RetentionReason::InactiveDeviceChat);
+        assert_eq!((plan.messages, plan.whole_chat), (2, true));
+        assert!(plan_chat(&chat(None, now), &messages, 0, 7, now).is_none());
+    }
+}
@@ -0,0 +1,535 @@
+//! Recurring prompts. A user registers a prompt with a daily or weekly
+//! spec (see [`spec`]); a background loop runs due prompts one at a time,
+//! stores each reply as an assistant message in the chosen chat and, when
+//! a webhook is set, POSTs the result there. Runs wait while interactive
+//! replies are queued or the GPU watchdog refuses work, so schedules never
+//! compete with live chats. Schedules are stored per user under
+//! `schedule:{user_id}:{schedule_id}`.
+
+pub mod spec;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use chrono::Utc;
+use headers::{authorization::Bearer, Authorization};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::tokens::authenticate,
+    conversation::{build_mistral_prompt, postprocess::clean_output, stop_sequences},
+    events,
+    inference::llama_cpp_service::{random_seed, GenerationParams, ENGINE_ERROR_PREFIX},
+    model::message::Message,
+    webhooks::target,
+    ws::AppState,
+};
+
+use spec::ScheduleSpec;
+
+pub const MAX_SCHEDULES_PER_USER: usize = 10;
+const MAX_PROMPT_CHARS: usize = 4000;
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/schedules",
+            get(list_schedules_handler).post(create_schedule_handler),
+        )
+        .route(
+            "/api/schedules/{schedule_id}",
+            get(get_schedule_handler)
+                .put(update_schedule_handler)
+                .delete(delete_schedule_handler),
+        )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Schedule {
+    pub id: String,
+    pub user_id: String,
+    /// Chat the replies are stored in.
+    pub chat_id: String,
+    pub prompt: String,
+    /// `minute hour * * weekdays` in UTC, or `@daily` / `@weekly`.
+    pub spec: String,
+    /// Receives a `schedule_completed` POST after each run.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    pub enabled: bool,
+    pub next_run_ts: i64,
+    #[serde(default)]
+    pub last_run_ts: Option<i64>,
+    #[serde(default)]
+    pub last_message_id: Option<String>,
+    /// Why the last run stored nothing, if it failed.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    pub created_ts: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SchedulePayload {
+    pub chat_id: String,
+    pub prompt: String,
+    pub spec: String,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Body of the webhook POST after a run.
+#[derive(Debug, Serialize)]
+struct ScheduleCompleted<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    schedule_id: &'a str,
+    chat_id: &'a str,
+    message_id: &'a str,
+    text: &'a str,
+    ts: i64,
+}
+
+impl SchedulePayload {
+    /// Trimmed payload with its parsed spec, or a 400 error code.
+    fn validate(mut self) -> Result<(Self, ScheduleSpec), &'static str> {
+        self.prompt = self.prompt.trim().to_string();
+        if self.prompt.is_empty() || self.prompt.chars().count() > MAX_PROMPT_CHARS {
+            return Err("invalid_prompt");
+        }
+        let spec = ScheduleSpec::parse(&self.spec).map_err(|_| "invalid_spec")?;
+        self.webhook_url = self
+            .webhook_url
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty());
+        Ok((self, spec))
+    }
+}
+
+/// Check the payload and that the caller owns its chat.
+async fn validated(
+    state: &AppState,
+    user_id: &str,
+    payload: SchedulePayload,
+) -> Result<(SchedulePayload, ScheduleSpec), (StatusCode, String)> {
+    let (payload, spec) = payload
+        .validate()
+        .map_err(|code| (StatusCode::BAD_REQUEST, code.to_string()))?;
+    if let Some(url) = &payload.webhook_url {
+        if let Err(err) = target::check_url(url).await {
+            debug!("schedule webhook url refused: {err}");
+            return Err((StatusCode::BAD_REQUEST, "invalid_webhook_url".into()));
+        }
+    }
+    let chat = state
+        .db
+        .load_chat(&payload.chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "chat_not_found".to_string()))?;
+    if chat.user_id.as_deref() != Some(user_id) {
+        return Err((StatusCode::FORBIDDEN, "chat_owner_mismatch".into()));
+    }
+    Ok((payload, spec))
+}
+
+async fn load_schedule(
+    state: &AppState,
+    user_id: &str,
+    schedule_id: &str,
+) -> Result<Schedule, (StatusCode, String)> {
+    state
+        .db
+        .load_schedule(user_id, schedule_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "schedule_not_found".to_string()))
+}
+
+/// The caller's schedules, oldest first.
+#[utoipa::path(
+    get,
+    path = "/api/schedules",
+    tag = "schedules",
+    responses(
+        (status = 200, description = "Schedules of the caller", body = [Schedule]),
+        (status = 401, description = "invalid_token"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_schedules_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Vec<Schedule>>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let mut schedules = state
+        .db
+        .list_schedules_for_user(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    schedules.sort_by_key(|s| s.created_ts);
+    Ok(Json(schedules))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/schedules",
+    tag = "schedules",
+    request_body = SchedulePayload,
+    responses(
+        (status = 200, description = "Created schedule", body = Schedule),
+        (status = 400, description = "invalid_prompt / invalid_spec / invalid_webhook_url"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "chat_owner_mismatch"),
+        (status = 404, description = "chat_not_found"),
+        (status = 409, description = "schedule_limit_reached"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_schedule_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<SchedulePayload>,
+) -> Result<Json<Schedule>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let (payload, spec) = validated(&state, &claims.sub, payload).await?;
+    let existing = state
+        .db
+        .list_schedules_for_user(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if existing.len() >= MAX_SCHEDULES_PER_USER {
+        return Err((StatusCode::CONFLICT, "schedule_limit_reached".into()));
+    }
+
+    let now = Utc::now().timestamp();
+    let schedule = Schedule {
+        id: Uuid::new_v4().to_string(),
+        user_id: claims.sub,
+        chat_id: payload.chat_id,
+        prompt: payload.prompt,
+        spec: payload.spec.trim().to_string(),
+        webhook_url: payload.webhook_url,
+        enabled: payload.enabled,
+        next_run_ts: spec.next_after(now),
+        last_run_ts: None,
+        last_message_id: None,
+        last_error: None,
+        created_ts: now,
+    };
+    state
+        .db
+        .save_schedule(&schedule)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(schedule))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/schedules/{schedule_id}",
+    tag = "schedules",
+    params(("schedule_id" = String, Path, description = "Schedule id")),
+    responses(
+        (status = 200, description = "Schedule with its last run", body = Schedule),
+        (status = 401, description = "invalid_token"),
+        (status = 404, description = "schedule_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn get_schedule_handler(
+    State(state): State<AppState>,
+    Path(schedule_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Schedule>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    load_schedule(&state, &claims.sub, &schedule_id)
+        .await
+        .map(Json)
+}
+
+/// Replace a schedule's settings; the next run is recomputed from now.
+#[utoipa::path(
+    put,
+    path = "/api/schedules/{schedule_id}",
+    tag = "schedules",
+    params(("schedule_id" = String, Path, description = "Schedule id")),
+    request_body = SchedulePayload,
+    responses(
+        (status = 200, description = "Updated schedule", body = Schedule),
+        (status = 400, description = "invalid_prompt / invalid_spec / invalid_webhook_url"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "chat_owner_mismatch"),
+        (status = 404, description = "schedule_not_found / chat_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn update_schedule_handler(
+    State(state): State<AppState>,
+    Path(schedule_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<SchedulePayload>,
+) -> Result<Json<Schedule>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let mut schedule = load_schedule(&state, &claims.sub, &schedule_id).await?;
+    let (payload, spec) = validated(&state, &claims.sub, payload).await?;
+    schedule.chat_id = payload.chat_id;
+    schedule.prompt = payload.prompt;
+    schedule.spec = payload.spec.trim().to_string();
+    schedule.webhook_url = payload.webhook_url;
+    schedule.enabled = payload.enabled;
+    schedule.next_run_ts = spec.next_after(Utc::now().timestamp());
+    state
+        .db
+        .save_schedule(&schedule)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(schedule))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/schedules/{schedule_id}",
+    tag = "schedules",
+    params(("schedule_id" = String, Path, description = "Schedule id")),
+    responses(
+        (status = 200, description = "`{ schedule_id, deleted: true }`"),
+        (status = 401, description = "invalid_token"),
+        (status = 404, description = "schedule_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_schedule_handler(
+    State(state): State<AppState>,
+    Path(schedule_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    load_schedule(&state, &claims.sub, &schedule_id).await?;
+    state
+        .db
+        .delete_schedule(&claims.sub, &schedule_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(json!({ "schedule_id": schedule_id, "deleted": true })))
+}
+
+/// `SCHEDULER_INTERVAL_SECS` (default 60, `0` disables scheduled prompts).
+pub fn spawn_scheduler(state: AppState) -> bool {
+    let interval = std::env::var("SCHEDULER_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_INTERVAL_SECS);
+    if interval == 0 {
+        return false;
+    }
+    let client = match target::client(WEBHOOK_TIMEOUT) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("scheduler disabled: {err}");
+            return false;
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            run_due(&state, &client).await;
+        }
+    });
+    true
+}
+
+/// Run every due schedule, oldest due first, until live traffic needs the
+/// model; the rest wait for the next tick.
+async fn run_due(state: &AppState, client: &reqwest::Client) {
+    let now = Utc::now().timestamp();
+    let mut due: Vec<Schedule> = match state.db.list_schedules().await {
+        Ok(all) => all
+            .into_iter()
+            .filter(|s| s.enabled && s.next_run_ts <= now)
+            .collect(),
+        Err(err) => {
+            warn!("scheduler could not list schedules: {err}");
+            return;
+        }
+    };
+    due.sort_by_key(|s| s.next_run_ts);
+
+    for mut schedule in due {
+        if state.maintenance.active().is_some()
+            || state.worker.queue_stats().depth > 0
+            || state.models.gpu.admit().is_err()
+        {
+            break;
+        }
+        let started = Utc::now().timestamp();
+        match run_schedule(state, &schedule).await {
+            Ok(message) => {
+                schedule.last_message_id = Some(message.id.clone());
+                schedule.last_error = None;
+                if let Some(url) = &schedule.webhook_url {
+                    let body = ScheduleCompleted {
+                        kind: "schedule_completed",
+                        schedule_id: &schedule.id,
+                        chat_id: &schedule.chat_id,
+                        message_id: &message.id,
+                        text: message.text.as_deref().unwrap_or_default(),
+                        ts: message.ts,
+                    };
+                    match target::check_url(url).await {
+                        Ok(_) => events::deliver(client, url, None, &body).await,
+                        Err(err) => warn!(
+                            schedule_id = schedule.id.as_str(),
+                            "schedule webhook url refused at delivery: {err}"
+                        ),
+                    }
+                }
+            }
+            Err(err) => {
+                warn!(
+                    schedule_id = schedule.id.as_str(),
+                    "scheduled prompt failed: {err}"
+                );
+                schedule.last_error = Some(err.to_string());
+            }
+        }
+        schedule.last_run_ts = Some(started);
+        // A spec that no longer parses stops the schedule instead of
+        // running it every tick.
+        match ScheduleSpec::parse(&schedule.spec) {
+            Ok(spec) => schedule.next_run_ts = spec.next_after(started),
+            Err(_) => schedule.enabled = false,
+        }
+        if let Err(err) = state.db.save_schedule(&schedule).await {
+            warn!(
+                schedule_id = schedule.id.as_str(),
+                "schedule save failed: {err}"
+            );
+        }
+    }
+}
+
+/// Generate the reply to a schedule's prompt and store it in its chat.
+async fn run_schedule(state: &AppState, schedule: &Schedule) -> anyhow::Result<Message> {
+    let mut user = state
+        .db
+        .load_user(&schedule.user_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("user_not_found"))?;
+    let mut chat = state
+        .db
+        .load_chat(&schedule.chat_id)
+        .await?
+        .filter(|chat| chat.user_id.as_deref() == Some(user.id.as_str()))
+        .ok_or_else(|| anyhow::anyhow!("chat_not_found"))?;
+    if !user.can_generate_now() {
+        anyhow::bail!("free_quota_exceeded");
+    }
+
+    let now = Utc::now().timestamp();
+    let turn = Message {
+        id: Uuid::new_v4().to_string(),
+        chat_id: chat.id.clone(),
+        session_id: None,
+        user_id: Some(user.id.clone()),
+        device_hash: None,
+        role: "user".into(),
+        text: Some(schedule.prompt.clone()),
+        language: chat.language.locked.clone(),
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: now,
+        meta: None,
+    };
+    let system_prompt = user.preferences.prompt_block();
+    let prompt = build_mistral_prompt(&[turn], system_prompt.as_deref());
+    let seed = random_seed();
+    let params = GenerationParams {
+        stop: stop_sequences(Vec::new()),
+        seed: Some(seed),
+        ..Default::default()
+    };
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut reply = state
+        .infer
+        .generate_reply(prompt, None, params, cancel.clone());
+    let mut raw = String::new();
+    while let Some(chunk) = reply.rx.recv().await {
+        raw.push_str(&chunk);
+    }
+    cancel.store(true, Ordering::SeqCst);
+    if raw.contains(ENGINE_ERROR_PREFIX) {
+        anyhow::bail!("generation_failed");
+    }
+    let text = clean_output(&raw);
+    if text.is_empty() {
+        anyhow::bail!("empty_reply");
+    }
+
+    let message = Message {
+        id: Uuid::new_v4().to_string(),
+        chat_id: chat.id.clone(),
+        session_id: None,
+        user_id: Some(user.id.clone()),
+        device_hash: None,
+        role: "assistant".into(),
+        text: Some(text),
+        language: chat.language.locked.clone(),
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: Utc::now().timestamp(),
+        meta: Some(json!({
+            "schedule_id": schedule.id,
+            "prompt": schedule.prompt,
+            "fallback": reply.fallback.load(Ordering::SeqCst),
+            "seed": seed,
+        })),
+    };
+    chat.updated_ts = message.ts;
+    state.db.save_message_with_chat(&message, &chat).await?;
+    user.generation_count = user.generation_count.saturating_add(1);
+    state.db.save_user(&user).await?;
+
+    let frame = json!({
+        "type": "system",
+        "event": "scheduled_message",
+        "chat_id": chat.id,
+        "schedule_id": schedule.id,
+        "message_id": message.id,
+        "text": message.text,
+    });
+    state.chats.publish(&chat.id, None, &frame.to_string());
+    info!(
+        schedule_id = schedule.id.as_str(),
+        chat_id = chat.id.as_str(),
+        "scheduled prompt stored"
+    );
+    Ok(message)
+}
@@ -0,0 +1,121 @@
+//! Schedule specs: a cron subset that covers daily and weekly runs.
+//!
+//! `minute hour * * weekdays`, in UTC, where `weekdays` is `*` or a comma
+//! list of 0–7 (0 and 7 are Sunday). `@daily` and `@weekly` stand for
+//! `0 0 * * *` and `0 0 * * 0`. Day-of-month and month must be `*`.
+
+use chrono::{DateTime, Datelike, Days};
+
+const ALL_DAYS: u8 = 0b111_1111;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleSpec {
+    minute: u32,
+    hour: u32,
+    /// Bit `n` set: runs on day `n` of the week, Sunday = 0.
+    weekdays: u8,
+}
+
+impl ScheduleSpec {
+    pub fn parse(spec: &str) -> Result<Self, &'static str> {
+        let spec = match spec.trim() {
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            other => other,
+        };
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekdays] = fields[..] else {
+            return Err("expected five fields");
+        };
+        if day != "*" || month != "*" {
+            return Err("day of month and month must be *");
+        }
+        let minute = minute
+            .parse::<u32>()
+            .ok()
+            .filter(|m| *m < 60)
+            .ok_or("minute must be 0-59")?;
+        let hour = hour
+            .parse::<u32>()
+            .ok()
+            .filter(|h| *h < 24)
+            .ok_or("hour must be 0-23")?;
+        let weekdays = if weekdays == "*" {
+            ALL_DAYS
+        } else {
+            weekdays.split(',').try_fold(0u8, |mask, day| {
+                let day = day
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|d| *d <= 7)
+                    .ok_or("weekdays must be * or 0-7")?;
+                Ok(mask | 1 << (day % 7))
+            })?
+        };
+        Ok(Self {
+            minute,
+            hour,
+            weekdays,
+        })
+    }
+
+    /// First run strictly after `ts` (unix seconds).
+    pub fn next_after(&self, ts: i64) -> i64 {
+        let Some(now) = DateTime::from_timestamp(ts, 0) else {
+            return ts + 24 * 60 * 60;
+        };
+        let today = now.date_naive();
+        (0..=7)
+            .filter_map(|offset| today.checked_add_days(Days::new(offset)))
+            .filter(|day| self.weekdays & (1 << day.weekday().num_days_from_sunday()) != 0)
+            .filter_map(|day| day.and_hms_opt(self.hour, self.minute, 0))
+            .map(|at| at.and_utc().timestamp())
+            .find(|at| *at > ts)
+            .unwrap_or(ts + 7 * 24 * 60 * 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-03 12:00:00 UTC, a Wednesday.
+    const WED_NOON: i64 = 1_704_283_200;
+
+    #[test]
+    fn parses_daily_and_weekly_specs() {
+        assert_eq!(
+            ScheduleSpec::parse("@daily"),
+            ScheduleSpec::parse("0 0 * * *")
+        );
+        assert_eq!(
+            ScheduleSpec::parse("30 8 * * 1,7").unwrap().weekdays,
+            0b000_0011
+        );
+        assert!(ScheduleSpec::parse("0 0 1 * *").is_err());
+        assert!(ScheduleSpec::parse("60 0 * * *").is_err());
+        assert!(ScheduleSpec::parse("0 0 * * 8").is_err());
+        assert!(ScheduleSpec::parse("0 0 * *").is_err());
+    }
+
+    #[test]
+    fn finds_next_run() {
+        let daily = ScheduleSpec::parse("30 8 * * *").unwrap();
+        // Tomorrow 08:30
+        assert_eq!(daily.next_after(WED_NOON), WED_NOON + 20 * 3600 + 1800);
+        let later_today = ScheduleSpec::parse("0 13 * * *").unwrap();
+        assert_eq!(later_today.next_after(WED_NOON), WED_NOON + 3600);
+        // Exactly at the run time: the next one
+        let noon = ScheduleSpec::parse("0 12 * * *").unwrap();
+        assert_eq!(noon.next_after(WED_NOON), WED_NOON + 24 * 3600);
+        let weekly = ScheduleSpec::parse("@weekly").unwrap();
+        // Sunday 00:00
+        assert_eq!(
+            weekly.next_after(WED_NOON),
+            WED_NOON + 3 * 24 * 3600 + 12 * 3600
+        );
+        let wednesdays = ScheduleSpec::parse("0 12 * * 3").unwrap();
+        assert_eq!(wednesdays.next_after(WED_NOON), WED_NOON + 7 * 24 * 3600);
+    }
+}
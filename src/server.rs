@@ -0,0 +1,158 @@
+//! Listener setup. `BIND_ADDR` (default `0.0.0.0`) and `PORT` (default
+//! 3000) pick the socket. With `TLS_CERT_PATH` and `TLS_KEY_PATH` (PEM) the
+//! server terminates TLS itself with rustls, and `HTTP_REDIRECT_PORT`, if
+//! set, adds a plain HTTP listener that redirects every request to HTTPS.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    http::{header::HOST, HeaderMap, Uri},
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::net::TcpListener;
+
+const DEFAULT_PORT: u16 = 3000;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsPaths>,
+    /// Port of the HTTP → HTTPS redirect listener; only used with TLS.
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert: String,
+    pub key: String,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self> {
+        let ip = match env("BIND_ADDR") {
+            Some(raw) => raw
+                .parse::<IpAddr>()
+                .with_context(|| format!("BIND_ADDR `{raw}` is not an IP address"))?,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let port = port_var("PORT")?.unwrap_or(DEFAULT_PORT);
+        let tls = match (env("TLS_CERT_PATH"), env("TLS_KEY_PATH")) {
+            (Some(cert), Some(key)) => Some(TlsPaths { cert, key }),
+            (None, None) => None,
+            _ => bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+        };
+        Ok(Self {
+            addr: SocketAddr::new(ip, port),
+            tls,
+            redirect_port: port_var("HTTP_REDIRECT_PORT")?,
+        })
+    }
+
+    pub fn scheme(&self) -> (&'static str, &'static str) {
+        if self.tls.is_some() {
+            ("https", "wss")
+        } else {
+            ("http", "ws")
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+fn port_var(name: &str) -> Result<Option<u16>> {
+    env(name)
+        .map(|raw| {
+            raw.parse::<u16>()
+                .with_context(|| format!("{name} `{raw}` is not a port"))
+        })
+        .transpose()
+}
+
+/// Serve `app` until the listener fails.
+pub async fn serve(app: Router, config: ServerConfig) -> Result<()> {
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let Some(tls) = &config.tls else {
+        let listener = TcpListener::bind(config.addr).await?;
+        axum::serve(listener, service).await?;
+        return Ok(());
+    };
+
+    // reqwest also links rustls; pick the provider explicitly so the
+    // process-wide default is not ambiguous.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .with_context(|| format!("failed to load TLS cert {} / key {}", tls.cert, tls.key))?;
+
+    if let Some(port) = config.redirect_port {
+        let https_port = config.addr.port();
+        let redirect = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+            let host = headers
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("localhost");
+            Redirect::permanent(&redirect_target(host, &uri, https_port)).into_response()
+        });
+        let listener = TcpListener::bind(SocketAddr::new(config.addr.ip(), port)).await?;
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, redirect).await {
+                tracing::warn!("http redirect listener stopped: {err}");
+            }
+        });
+    }
+
+    axum_server::bind_rustls(config.addr, rustls)
+        .serve(service)
+        .await?;
+    Ok(())
+}
+
+/// HTTPS URL for a plain HTTP request: same host and path, on
+/// `https_port`.
+fn redirect_target(host: &str, uri: &Uri, https_port: u16) -> String {
+    let host = match host.rsplit_once(':') {
+        // Keep IPv6 literals such as `[::1]` intact.
+        Some((name, port)) if !name.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    if https_port == 443 {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{https_port}{path}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_to_https_port() {
+        let uri: Uri = "/api/status?x=1".parse().unwrap();
+        assert_eq!(
+            redirect_target("chat.example.com:80", &uri, 443),
+            "https://chat.example.com/api/status?x=1"
+        );
+        assert_eq!(
+            redirect_target("chat.example.com", &uri, 8443),
+            "https://chat.example.com:8443/api/status?x=1"
+        );
+        assert_eq!(
+            redirect_target("[::1]:8080", &"/".parse().unwrap(), 443),
+            "https://[::1]/"
+        );
+        assert_eq!(
+            redirect_target("[::1]", &"/".parse().unwrap(), 443),
+            "https://[::1]/"
+        );
+    }
+}
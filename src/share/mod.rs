@@ -0,0 +1,333 @@
+//! Read-only chat links. The owner of a chat creates a share, which
+//! snapshots the chat up to its latest message; anyone holding the signed
+//! token can read that snapshot as JSON or HTML until the share expires or
+//! is revoked. Messages added after the share point never show up.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use base64::Engine;
+use chrono::Utc;
+use headers::{authorization::Bearer, Authorization};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::ToSchema;
+
+use crate::{
+    export::{load_owned_chat, ChatTranscript},
+    ws::AppState,
+};
+
+const TOKEN_PREFIX: &str = "sh1.";
+/// Longest `expires_in_secs` accepted (one year).
+const MAX_EXPIRY_SECS: i64 = 365 * 24 * 60 * 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/chats/{chat_id}/share", post(create_share_handler))
+        .route(
+            "/api/chats/{chat_id}/share/{share_id}",
+            delete(revoke_share_handler),
+        )
+        .route("/share/{token}", get(shared_chat_handler))
+}
+
+/// Stored share of a chat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: String,
+    pub chat_id: String,
+    pub user_id: String,
+    pub created_ts: i64,
+    /// Timestamp of the newest message at share time; later messages are
+    /// left out of the snapshot.
+    pub upto_ts: i64,
+    #[serde(default)]
+    pub expires_ts: Option<i64>,
+    #[serde(default)]
+    pub revoked_ts: Option<i64>,
+}
+
+/// Signs share ids into tokens of the form `sh1.<id>.<hmac>`, so forged
+/// tokens are turned away without a database read.
+pub struct ShareSigner {
+    secret: Vec<u8>,
+}
+
+impl ShareSigner {
+    /// `SHARE_LINK_SECRET`, falling back to a key derived from the JWT
+    /// secret.
+    pub fn from_env(jwt_secret: &str) -> Self {
+        let secret = std::env::var("SHARE_LINK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| format!("{jwt_secret}:share_link"));
+        Self {
+            secret: secret.into_bytes(),
+        }
+    }
+
+    fn mac(&self, id: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key size");
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    pub fn new_id() -> String {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn token(&self, id: &str) -> String {
+        let sig = self.mac(id).finalize().into_bytes();
+        let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&sig[..16]);
+        format!("{TOKEN_PREFIX}{id}.{sig}")
+    }
+
+    /// Share id of a token with a valid signature.
+    pub fn verify<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let (id, sig) = token.strip_prefix(TOKEN_PREFIX)?.split_once('.')?;
+        let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(sig)
+            .ok()?;
+        (sig.len() == 16 && self.mac(id).verify_truncated_left(&sig).is_ok()).then_some(id)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct CreateShareRequest {
+    /// Seconds until the link stops working; no expiry when omitted.
+    #[serde(default)]
+    pub expires_in_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreateShareResponse {
+    pub share_id: String,
+    pub token: String,
+    /// Path of the read-only snapshot.
+    pub url: String,
+    pub upto_ts: i64,
+    pub expires_ts: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareFormat {
+    #[default]
+    Json,
+    Html,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareQuery {
+    #[serde(default)]
+    pub format: ShareFormat,
+}
+
+/// Share a chat as it is now.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{chat_id}/share",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id")),
+    request_body(content = Option<CreateShareRequest>, description = "Optional expiry"),
+    responses(
+        (status = 200, description = "Share token and link", body = CreateShareResponse),
+        (status = 400, description = "invalid_expiry / chat_empty"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "chat_owner_mismatch"),
+        (status = 404, description = "chat_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_share_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    body: Option<Json<CreateShareRequest>>,
+) -> Result<Json<CreateShareResponse>, (StatusCode, String)> {
+    let request = body.map(|Json(req)| req).unwrap_or_default();
+    if request
+        .expires_in_secs
+        .is_some_and(|secs| !(1..=MAX_EXPIRY_SECS).contains(&secs))
+    {
+        return Err((StatusCode::BAD_REQUEST, "invalid_expiry".into()));
+    }
+
+    let (chat, messages) = load_owned_chat(&state, auth.token(), &chat_id).await?;
+    let upto_ts = messages
+        .iter()
+        .filter(|m| m.role == "user" || m.role == "assistant")
+        .map(|m| m.ts)
+        .max()
+        .ok_or((StatusCode::BAD_REQUEST, "chat_empty".to_string()))?;
+
+    let now = Utc::now().timestamp();
+    let link = ShareLink {
+        id: ShareSigner::new_id(),
+        chat_id: chat.id.clone(),
+        user_id: chat.user_id.clone().unwrap_or_default(),
+        created_ts: now,
+        upto_ts,
+        expires_ts: request.expires_in_secs.map(|secs| now + secs),
+        revoked_ts: None,
+    };
+    state
+        .db
+        .save_share_link(&link)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let token = ShareSigner::from_env(&state.jwt_secret).token(&link.id);
+    Ok(Json(CreateShareResponse {
+        share_id: link.id,
+        url: format!("/share/{token}"),
+        token,
+        upto_ts,
+        expires_ts: link.expires_ts,
+    }))
+}
+
+/// Revoke a share; its link stops working at once.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{chat_id}/share/{share_id}",
+    tag = "chats",
+    params(("chat_id" = String, Path, description = "Chat id"), ("share_id" = String, Path, description = "Share id")),
+    responses(
+        (status = 200, description = "`{ share_id, revoked: true }`"),
+        (status = 401, description = "invalid_token"),
+        (status = 403, description = "chat_owner_mismatch"),
+        (status = 404, description = "chat_not_found / share_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn revoke_share_handler(
+    State(state): State<AppState>,
+    Path((chat_id, share_id)): Path<(String, String)>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    load_owned_chat(&state, auth.token(), &chat_id).await?;
+    let mut link = state
+        .db
+        .load_share_link(&share_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .filter(|link| link.chat_id == chat_id)
+        .ok_or((StatusCode::NOT_FOUND, "share_not_found".to_string()))?;
+    if link.revoked_ts.is_none() {
+        link.revoked_ts = Some(Utc::now().timestamp());
+        state
+            .db
+            .save_share_link(&link)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    Ok(Json(
+        serde_json::json!({ "share_id": share_id, "revoked": true }),
+    ))
+}
+
+/// Read-only snapshot of a shared chat. No login needed; the token is the
+/// credential.
+#[utoipa::path(
+    get,
+    path = "/share/{token}",
+    tag = "chats",
+    params(
+        ("token" = String, Path, description = "Share token"),
+        ("format" = Option<ShareFormat>, Query, description = "`json` (default) or `html`"),
+    ),
+    responses(
+        (status = 200, description = "Transcript up to the share point, as `ChatTranscript` JSON or an HTML page"),
+        (status = 404, description = "share_not_found"),
+        (status = 410, description = "share_expired / share_revoked"),
+    )
+)]
+pub async fn shared_chat_handler(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(query): Query<ShareQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, "share_not_found".to_string());
+    let share_id = ShareSigner::from_env(&state.jwt_secret)
+        .verify(&token)
+        .ok_or_else(not_found)?;
+    let link = state
+        .db
+        .load_share_link(share_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(not_found)?;
+    if link.revoked_ts.is_some() {
+        return Err((StatusCode::GONE, "share_revoked".into()));
+    }
+    if link
+        .expires_ts
+        .is_some_and(|ts| ts <= Utc::now().timestamp())
+    {
+        return Err((StatusCode::GONE, "share_expired".into()));
+    }
+
+    let chat = state
+        .db
+        .load_chat(&link.chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(not_found)?;
+    let mut messages = state
+        .db
+        .list_messages_for_chat(&link.chat_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    messages.retain(|m| m.ts <= link.upto_ts);
+    let transcript = ChatTranscript::new(&chat, &messages, link.created_ts);
+
+    let cache = (header::CACHE_CONTROL, "private, no-store");
+    Ok(match query.format {
+        ShareFormat::Json => ([cache], Json(transcript)).into_response(),
+        ShareFormat::Html => (
+            [(header::CONTENT_TYPE, "text/html; charset=utf-8"), cache],
+            transcript.to_html(),
+        )
+            .into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_verify_only_with_their_signature() {
+        let signer = ShareSigner {
+            secret: b"secret".to_vec(),
+        };
+        let id = ShareSigner::new_id();
+        let token = signer.token(&id);
+        assert_eq!(signer.verify(&token), Some(id.as_str()));
+
+        let other = ShareSigner {
+            secret: b"other".to_vec(),
+        };
+        assert_eq!(other.verify(&token), None);
+        let forged = format!(
+            "{TOKEN_PREFIX}{}.{}",
+            ShareSigner::new_id(),
+            &token[token.len() - 22..]
+        );
+        assert_eq!(signer.verify(&forged), None);
+        assert_eq!(signer.verify(&id), None);
+    }
+}
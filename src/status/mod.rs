@@ -0,0 +1,456 @@
+//! Component health for `GET /api/status`. A background task checks the
+//! models, the database, payments and the inference queue every
+//! `HEALTH_CHECK_INTERVAL_SECS`, keeps a short history per component and
+//! opens an incident whenever a component leaves `operational`. Incidents
+//! are stored in RocksDB and published as `component_incident` events, so
+//! the events webhook doubles as alerting.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    db::DBLayer, events::Event, maintenance::MaintenanceWindow, manager::WarmupResult, ws::AppState,
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_HISTORY_SAMPLES: usize = 90;
+const DEFAULT_INCIDENTS: usize = 10;
+const DEFAULT_QUEUE_DEGRADED_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Models,
+    Db,
+    Payment,
+    Queue,
+}
+
+impl Component {
+    pub const ALL: [Component; 4] = [
+        Component::Models,
+        Component::Db,
+        Component::Payment,
+        Component::Queue,
+    ];
+}
+
+/// Ordered from best to worst; the overall status is the worst component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Operational,
+    /// Not configured on this deployment (e.g. payments without Stripe keys).
+    Disabled,
+    Degraded,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HealthSample {
+    pub ts: i64,
+    pub status: HealthStatus,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ComponentHealth {
+    pub component: Component,
+    pub status: HealthStatus,
+    pub detail: Option<String>,
+    pub checked_ts: i64,
+    /// Share of samples in `history` that were not degraded or down.
+    pub uptime: f32,
+    /// Oldest first.
+    pub history: Vec<HealthSample>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Incident {
+    pub id: String,
+    pub component: Component,
+    /// Worst status seen while the incident was open.
+    pub status: HealthStatus,
+    pub detail: Option<String>,
+    pub started_ts: i64,
+    pub resolved_ts: Option<i64>,
+}
+
+struct ComponentState {
+    status: HealthStatus,
+    detail: Option<String>,
+    checked_ts: i64,
+    history: VecDeque<HealthSample>,
+    open_incident: Option<Incident>,
+}
+
+/// Latest check results, history and open incidents per component.
+#[derive(Clone)]
+pub struct HealthMonitor {
+    components: Arc<Mutex<BTreeMap<Component, ComponentState>>>,
+    history_samples: usize,
+}
+
+impl HealthMonitor {
+    /// `HEALTH_HISTORY_SAMPLES` (default 90). Incidents left open by a
+    /// previous run are picked up so they resolve normally.
+    pub async fn load(db: &DBLayer) -> Self {
+        let history_samples = std::env::var("HEALTH_HISTORY_SAMPLES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(DEFAULT_HISTORY_SAMPLES);
+        let open = db.list_open_incidents().await.unwrap_or_else(|err| {
+            warn!("failed to load open incidents: {err}");
+            Vec::new()
+        });
+        Self::new(history_samples, open)
+    }
+
+    fn new(history_samples: usize, mut open: Vec<Incident>) -> Self {
+        let components = Component::ALL
+            .iter()
+            .map(|component| {
+                let open_incident = open
+                    .iter()
+                    .position(|i| i.component == *component)
+                    .map(|idx| open.swap_remove(idx));
+                let state = ComponentState {
+                    status: HealthStatus::Operational,
+                    detail: None,
+                    checked_ts: 0,
+                    history: VecDeque::with_capacity(history_samples),
+                    open_incident,
+                };
+                (*component, state)
+            })
+            .collect();
+
+        Self {
+            components: Arc::new(Mutex::new(components)),
+            history_samples,
+        }
+    }
+
+    pub fn components(&self) -> Vec<ComponentHealth> {
+        let components = self.components.lock().expect("health lock poisoned");
+        components
+            .iter()
+            .map(|(component, state)| {
+                let healthy = state
+                    .history
+                    .iter()
+                    .filter(|s| s.status < HealthStatus::Degraded)
+                    .count();
+                ComponentHealth {
+                    component: *component,
+                    status: state.status,
+                    detail: state.detail.clone(),
+                    checked_ts: state.checked_ts,
+                    uptime: if state.history.is_empty() {
+                        1.0
+                    } else {
+                        healthy as f32 / state.history.len() as f32
+                    },
+                    history: state.history.iter().cloned().collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Record a check result. Returns the incident that was opened, updated
+    /// or resolved by it, if any.
+    fn record(
+        &self,
+        component: Component,
+        status: HealthStatus,
+        detail: Option<String>,
+        now: i64,
+    ) -> Option<Incident> {
+        let mut components = self.components.lock().expect("health lock poisoned");
+        let state = components.get_mut(&component)?;
+        state.status = status;
+        state.detail = detail.clone();
+        state.checked_ts = now;
+        if state.history.len() == self.history_samples {
+            state.history.pop_front();
+        }
+        state.history.push_back(HealthSample { ts: now, status });
+
+        if status < HealthStatus::Degraded {
+            let mut incident = state.open_incident.take()?;
+            incident.resolved_ts = Some(now);
+            return Some(incident);
+        }
+        if let Some(incident) = state.open_incident.as_mut() {
+            if status <= incident.status {
+                return None;
+            }
+            incident.status = status;
+            incident.detail = detail;
+            return Some(incident.clone());
+        }
+        let incident = Incident {
+            id: Uuid::new_v4().to_string(),
+            component,
+            status,
+            detail,
+            started_ts: now,
+            resolved_ts: None,
+        };
+        state.open_incident = Some(incident.clone());
+        Some(incident)
+    }
+}
+
+async fn check(state: &AppState, component: Component) -> (HealthStatus, Option<String>) {
+    match component {
+        Component::Models => {
+            if let Err(err) = state.infer.count_tokens("status probe").await {
+                return (HealthStatus::Down, Some(format!("generator: {err}")));
+            }
+            match state.db.load_canary_report().await {
+                Ok(Some(report)) if !report.passed => {
+                    let failing: Vec<_> = report
+                        .results
+                        .iter()
+                        .filter(|r| !r.passed)
+                        .map(|r| r.name.as_str())
+                        .collect();
+                    (
+                        HealthStatus::Degraded,
+                        Some(format!("canary failing: {}", failing.join(", "))),
+                    )
+                }
+                _ => (HealthStatus::Operational, None),
+            }
+        }
+        Component::Db => {
+            let db = state.db.clone();
+            match tokio::task::spawn_blocking(move || db.probe().map(|_| db.db_stats())).await {
+                Ok(Ok(stats)) if stats.write_stopped => {
+                    (HealthStatus::Degraded, Some("writes stopped".into()))
+                }
+                Ok(Ok(stats)) if stats.delayed_write_rate.unwrap_or(0) > 0 => {
+                    (HealthStatus::Degraded, Some("writes throttled".into()))
+                }
+                Ok(Ok(_)) => (HealthStatus::Operational, None),
+                Ok(Err(err)) => (HealthStatus::Down, Some(err.to_string())),
+                Err(err) => (HealthStatus::Down, Some(err.to_string())),
+            }
+        }
+        Component::Payment => match state.payment {
+            Some(_) => (HealthStatus::Operational, None),
+            None => (HealthStatus::Disabled, None),
+        },
+        Component::Queue => {
+            let stats = state.worker.queue_stats();
+            let threshold = std::env::var("HEALTH_QUEUE_DEGRADED_MS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_QUEUE_DEGRADED_MS);
+            let detail = format!(
+                "depth {}/{}, avg wait {} ms",
+                stats.depth, stats.capacity, stats.avg_wait_ms
+            );
+            if stats.depth >= stats.capacity || stats.avg_wait_ms > threshold {
+                (HealthStatus::Degraded, Some(detail))
+            } else {
+                (HealthStatus::Operational, Some(detail))
+            }
+        }
+    }
+}
+
+/// Run every check once and persist/publish incident changes.
+pub async fn run_checks(state: &AppState) {
+    let now = chrono::Utc::now().timestamp();
+    for component in Component::ALL {
+        let (status, detail) = check(state, component).await;
+        let Some(incident) = state.health.record(component, status, detail, now) else {
+            continue;
+        };
+        if incident.resolved_ts.is_some() {
+            info!(component = ?incident.component, "incident resolved");
+        } else {
+            warn!(
+                component = ?incident.component,
+                status = ?incident.status,
+                detail = incident.detail.as_deref().unwrap_or(""),
+                "component incident"
+            );
+        }
+        if let Err(err) = state.db.save_incident(&incident).await {
+            warn!("failed to store incident: {err}");
+        }
+        state.events.publish(Event::ComponentIncident(incident));
+    }
+}
+
+/// `HEALTH_CHECK_INTERVAL_SECS` (default 60, `0` disables the checks).
+pub fn spawn_health_checks(state: AppState) -> bool {
+    let interval = std::env::var("HEALTH_CHECK_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_INTERVAL_SECS);
+    if interval == 0 {
+        return false;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            run_checks(&state).await;
+        }
+    });
+    true
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatusResponse {
+    /// `ok`, `degraded`, `down` or `maintenance`.
+    #[schema(example = "ok")]
+    pub status: String,
+    /// Current or planned maintenance window, if any.
+    pub maintenance: Option<MaintenanceWindow>,
+    pub components: Vec<ComponentHealth>,
+    /// Latest first, `STATUS_INCIDENTS` of them (default 10).
+    pub incidents: Vec<Incident>,
+    pub ts: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    /// Warmup result per model so far.
+    pub models: Vec<WarmupResult>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/status", get(status_handler))
+        .route("/readyz", get(readiness_handler))
+}
+
+/// For load balancers and orchestrators: 200 once every model passed its
+/// warmup pass, 503 before.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "status",
+    responses(
+        (status = 200, description = "All models warmed up", body = ReadinessResponse),
+        (status = 503, description = "Warmup still running or failing", body = ReadinessResponse),
+    )
+)]
+pub async fn readiness_handler(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let ready = state.models.is_ready();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(ReadinessResponse {
+            ready,
+            models: state.models.warmup_results(),
+        }),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/status",
+    tag = "status",
+    responses((status = 200, description = "Component health, incidents and maintenance window", body = StatusResponse))
+)]
+pub async fn status_handler(State(state): State<AppState>) -> Json<StatusResponse> {
+    let now = chrono::Utc::now().timestamp();
+    let window = state.maintenance.current();
+    let components = state.health.components();
+    let incident_limit = std::env::var("STATUS_INCIDENTS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_INCIDENTS);
+    let incidents = state
+        .db
+        .list_incidents(incident_limit)
+        .await
+        .unwrap_or_default();
+
+    let worst = components
+        .iter()
+        .map(|c| c.status)
+        .max()
+        .unwrap_or(HealthStatus::Operational);
+    let status = if window.is_active(now) {
+        "maintenance"
+    } else {
+        match worst {
+            HealthStatus::Down => "down",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Operational | HealthStatus::Disabled => "ok",
+        }
+    };
+
+    Json(StatusResponse {
+        status: status.to_string(),
+        maintenance: window.is_announced(now).then_some(window),
+        components,
+        incidents,
+        ts: now,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incidents_open_escalate_and_resolve() {
+        let monitor = HealthMonitor::new(3, Vec::new());
+        assert!(monitor
+            .record(Component::Db, HealthStatus::Operational, None, 1)
+            .is_none());
+
+        let opened = monitor
+            .record(Component::Db, HealthStatus::Degraded, None, 2)
+            .unwrap();
+        assert_eq!(opened.resolved_ts, None);
+        assert!(monitor
+            .record(Component::Db, HealthStatus::Degraded, None, 3)
+            .is_none());
+
+        let escalated = monitor
+            .record(Component::Db, HealthStatus::Down, Some("io".into()), 4)
+            .unwrap();
+        assert_eq!(escalated.id, opened.id);
+        assert_eq!(escalated.status, HealthStatus::Down);
+
+        let resolved = monitor
+            .record(Component::Db, HealthStatus::Operational, None, 5)
+            .unwrap();
+        assert_eq!(resolved.id, opened.id);
+        assert_eq!(resolved.resolved_ts, Some(5));
+
+        let db = monitor
+            .components()
+            .into_iter()
+            .find(|c| c.component == Component::Db)
+            .unwrap();
+        assert_eq!(db.history.len(), 3);
+        assert!((db.uptime - 1.0 / 3.0).abs() < 1e-6);
+    }
+}
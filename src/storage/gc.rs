@@ -0,0 +1,173 @@
+//! Garbage collection of stored files. A file under `STORAGE_DIR` that no
+//! message attachment and no upload record points at, and that is older
+//! than `FILE_GC_GRACE_SECS`, is deleted every `FILE_GC_INTERVAL_SECS`.
+//! The grace period covers files whose record is still being written.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    attachments::storage_root,
+    db::{walk_files, DBLayer},
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_GRACE_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct OrphanedFile {
+    pub path: String,
+    pub bytes: u64,
+    pub modified_ts: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FileGcReport {
+    /// Nothing was deleted; `files` is what a run would delete.
+    pub dry_run: bool,
+    pub grace_secs: u64,
+    pub files: Vec<OrphanedFile>,
+    pub reclaimable_bytes: u64,
+    /// Files actually removed; `0` on a dry run.
+    pub deleted: usize,
+}
+
+fn env_secs(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(default)
+}
+
+/// Age below which unreferenced files are kept (`FILE_GC_GRACE_SECS`,
+/// default one day).
+pub fn grace_secs() -> u64 {
+    env_secs("FILE_GC_GRACE_SECS", DEFAULT_GRACE_SECS)
+}
+
+fn modified_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Files under `root` outside `referenced` and last modified before
+/// `cutoff` (unix seconds).
+fn orphans(root: &Path, referenced: &HashSet<PathBuf>, cutoff: u64) -> Vec<OrphanedFile> {
+    let referenced: HashSet<PathBuf> = referenced
+        .iter()
+        .map(|p| fs::canonicalize(p).unwrap_or_else(|_| p.clone()))
+        .collect();
+    walk_files(root)
+        .into_iter()
+        .filter(|file| {
+            let canonical = fs::canonicalize(file).unwrap_or_else(|_| file.clone());
+            !referenced.contains(&canonical)
+        })
+        .filter_map(|file| {
+            let meta = fs::metadata(&file).ok()?;
+            let modified = modified_secs(&meta);
+            (modified < cutoff).then(|| OrphanedFile {
+                path: file.display().to_string(),
+                bytes: meta.len(),
+                modified_ts: modified as i64,
+            })
+        })
+        .collect()
+}
+
+/// Find, and unless `dry_run` delete, orphaned files under `root`.
+pub async fn collect_garbage(db: &DBLayer, root: &Path, dry_run: bool) -> Result<FileGcReport> {
+    let grace = grace_secs();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let files = if root.exists() {
+        let referenced = db.referenced_file_paths().await?;
+        orphans(root, &referenced, now.saturating_sub(grace))
+    } else {
+        Vec::new()
+    };
+
+    let mut report = FileGcReport {
+        dry_run,
+        grace_secs: grace,
+        reclaimable_bytes: files.iter().map(|f| f.bytes).sum(),
+        files,
+        deleted: 0,
+    };
+    if !dry_run {
+        for file in &report.files {
+            match fs::remove_file(&file.path) {
+                Ok(()) => report.deleted += 1,
+                Err(err) => warn!(
+                    path = file.path.as_str(),
+                    "failed to remove orphaned file: {err}"
+                ),
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// `FILE_GC_INTERVAL_SECS` (default 6 hours, `0` disables collection).
+pub fn spawn_file_gc(db: Arc<DBLayer>) -> bool {
+    let interval = env_secs("FILE_GC_INTERVAL_SECS", DEFAULT_INTERVAL_SECS);
+    if interval == 0 {
+        return false;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick fires at once; leave startup to the integrity check.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match collect_garbage(&db, &storage_root(), false).await {
+                Ok(report) if report.deleted > 0 => info!(
+                    deleted = report.deleted,
+                    bytes = report.reclaimable_bytes,
+                    "removed orphaned files"
+                ),
+                Ok(_) => {}
+                Err(err) => warn!("file garbage collection failed: {err}"),
+            }
+        }
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_referenced_and_recent_files() {
+        let root = std::env::temp_dir().join(format!("file-gc-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("uploads")).unwrap();
+        let kept = root.join("uploads/kept");
+        let orphan = root.join("uploads/orphan");
+        fs::write(&kept, b"kept").unwrap();
+        fs::write(&orphan, b"orphan").unwrap();
+        let referenced = HashSet::from([kept.clone()]);
+
+        let found = orphans(&root, &referenced, u64::MAX);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, orphan.display().to_string());
+        assert_eq!(found[0].bytes, 6);
+        // Everything is newer than a cutoff in the past.
+        assert!(orphans(&root, &referenced, 0).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,653 @@
+//! Binary uploads for message attachments. Files land under
+//! `STORAGE_DIR/uploads/` and are described by a `StoredFile` record; the
+//! ws `prompt` references them by id.
+//!
+//! Each upload is capped at `MAX_UPLOAD_BYTES`, and the bytes uploaded are
+//! charged to the device's user (or the device itself before it is linked)
+//! against `STORAGE_QUOTA_BYTES`.
+
+pub mod gc;
+pub mod serve;
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use headers::{authorization::Bearer, Authorization};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::ApiError,
+    attachments::{
+        ingest::{load_owned_upload, transcribe_upload, IngestError},
+        storage_root,
+    },
+    auth::tokens::authenticate,
+    db::DBLayer,
+    inference::whisper::Transcript,
+    model::upload::StoredFile,
+    ws::AppState,
+};
+
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 25 * 1024 * 1024;
+const DEFAULT_STORAGE_QUOTA_BYTES: u64 = 1024 * 1024 * 1024;
+const MAX_FILENAME_CHARS: usize = 200;
+
+pub const MIME_PDF: &str = "application/pdf";
+pub const MIME_DOCX: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+pub const MIME_TEXT: &str = "text/plain";
+pub const MIME_MARKDOWN: &str = "text/markdown";
+
+/// Voice messages; transcribed instead of text-extracted.
+pub fn is_audio(mime: &str) -> bool {
+    mime.starts_with("audio/")
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    Empty,
+    TooLarge(usize),
+    QuotaExceeded { used: u64, quota: u64 },
+    UnsupportedType,
+    Storage(anyhow::Error),
+}
+
+impl UploadError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UploadError::Empty => StatusCode::BAD_REQUEST,
+            UploadError::TooLarge(_) | UploadError::QuotaExceeded { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            UploadError::UnsupportedType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UploadError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> String {
+        match self {
+            UploadError::Empty => "empty_file".into(),
+            UploadError::TooLarge(_) => "file_too_large".into(),
+            UploadError::QuotaExceeded { .. } => "storage_quota_exceeded".into(),
+            UploadError::UnsupportedType => "unsupported_file_type".into(),
+            UploadError::Storage(err) => err.to_string(),
+        }
+    }
+}
+
+impl From<UploadError> for ApiError {
+    fn from(err: UploadError) -> Self {
+        match err {
+            UploadError::TooLarge(max) => ApiError::new(err.status(), err.code())
+                .with_message(format!("files may be at most {max} bytes")),
+            UploadError::QuotaExceeded { used, quota } => ApiError::new(err.status(), err.code())
+                .with_message(format!("{used} of {quota} bytes of storage used")),
+            UploadError::Storage(err) => ApiError::internal(err),
+            _ => ApiError::new(err.status(), err.code()),
+        }
+    }
+}
+
+/// Detect the file type from its leading bytes. The client's declared
+/// mime type is never trusted; the extension only separates DOCX from
+/// other zip files and Markdown from plain text.
+pub fn sniff_mime(bytes: &[u8], filename: &str) -> Option<&'static str> {
+    let ext = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    if bytes.starts_with(b"%PDF-") {
+        return Some(MIME_PDF);
+    }
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WAVE" {
+        return Some("audio/wav");
+    }
+    if bytes.starts_with(b"OggS") {
+        return Some("audio/ogg");
+    }
+    if bytes.starts_with(b"fLaC") {
+        return Some("audio/flac");
+    }
+    if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some("audio/webm");
+    }
+    if bytes.starts_with(b"ID3")
+        || (bytes.len() >= 2 && bytes[0] == 0xFF && bytes[1] & 0xE0 == 0xE0)
+    {
+        return Some("audio/mpeg");
+    }
+    // MP4 container: only voice recordings (`.m4a`, or the M4A brand).
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return (&bytes[8..12] == b"M4A " || ext == "m4a").then_some("audio/mp4");
+    }
+    if bytes.starts_with(b"PK\x03\x04") {
+        return (ext == "docx").then_some(MIME_DOCX);
+    }
+    if std::str::from_utf8(bytes).is_ok() && !bytes.contains(&0) {
+        return Some(if matches!(ext.as_str(), "md" | "markdown") {
+            MIME_MARKDOWN
+        } else {
+            MIME_TEXT
+        });
+    }
+    None
+}
+
+fn sanitize_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_CHARS)
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        "upload".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+pub struct StorageService {
+    root: PathBuf,
+    max_bytes: usize,
+    /// Upload bytes allowed per owner; `0` for no quota.
+    quota_bytes: u64,
+    /// Serializes the quota check with the usage update.
+    quota_lock: tokio::sync::Mutex<()>,
+}
+
+impl StorageService {
+    /// `STORAGE_DIR` for the location, `MAX_UPLOAD_BYTES` (default 25 MiB)
+    /// per file and `STORAGE_QUOTA_BYTES` (default 1 GiB, `0` disables) per
+    /// user or unlinked device.
+    pub fn from_env() -> Self {
+        let max_bytes = std::env::var("MAX_UPLOAD_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES);
+        let quota_bytes = std::env::var("STORAGE_QUOTA_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_STORAGE_QUOTA_BYTES);
+        Self {
+            root: storage_root(),
+            max_bytes,
+            quota_bytes,
+            quota_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Validate and persist an upload for `device_hash`, charging it to the
+    /// device's storage quota.
+    pub async fn store(
+        &self,
+        db: &DBLayer,
+        bytes: &[u8],
+        filename: &str,
+        device_hash: &str,
+    ) -> Result<StoredFile, UploadError> {
+        if bytes.is_empty() {
+            return Err(UploadError::Empty);
+        }
+        if bytes.len() > self.max_bytes {
+            return Err(UploadError::TooLarge(self.max_bytes));
+        }
+        let filename = sanitize_filename(filename);
+        let mime = sniff_mime(bytes, &filename).ok_or(UploadError::UnsupportedType)?;
+
+        let user_id = db.find_user_id_by_device(device_hash).await.ok().flatten();
+        let owner = quota_owner(user_id.as_deref(), device_hash);
+        let _guard = self.quota_lock.lock().await;
+        let used = db
+            .storage_usage(&owner)
+            .await
+            .map_err(UploadError::Storage)?;
+        if self.quota_bytes > 0 && used.saturating_add(bytes.len() as u64) > self.quota_bytes {
+            return Err(UploadError::QuotaExceeded {
+                used,
+                quota: self.quota_bytes,
+            });
+        }
+        let file = self
+            .write(db, bytes, filename, mime, device_hash, user_id, false)
+            .await?;
+        db.add_storage_usage(&owner, file.size as i64)
+            .await
+            .map_err(UploadError::Storage)?;
+        Ok(file)
+    }
+
+    /// Persist a PNG made by the image model for `owner` (a device hash, or
+    /// `user:<id>` for API callers). Not subject to the upload size limit
+    /// or the storage quota.
+    pub async fn store_generated(
+        &self,
+        db: &DBLayer,
+        png: &[u8],
+        owner: &str,
+    ) -> Result<StoredFile, UploadError> {
+        if sniff_mime(png, "image.png") != Some("image/png") {
+            return Err(UploadError::UnsupportedType);
+        }
+        let filename = format!("image-{}.png", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+        let user_id = owner.strip_prefix("user:").map(str::to_string);
+        self.write(db, png, filename, "image/png", owner, user_id, true)
+            .await
+    }
+
+    async fn write(
+        &self,
+        db: &DBLayer,
+        bytes: &[u8],
+        filename: String,
+        mime: &str,
+        device_hash: &str,
+        user_id: Option<String>,
+        generated: bool,
+    ) -> Result<StoredFile, UploadError> {
+        let id = Uuid::new_v4().to_string();
+        let dir = self.root.join("uploads");
+        let path = dir.join(&id);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| UploadError::Storage(e.into()))?;
+        tokio::fs::write(&path, bytes)
+            .await
+            .map_err(|e| UploadError::Storage(e.into()))?;
+
+        let file = StoredFile {
+            id,
+            filename,
+            mime_type: mime.to_string(),
+            size: bytes.len() as u64,
+            sha256: Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+            path: path.display().to_string(),
+            device_hash: device_hash.to_string(),
+            user_id,
+            chat_id: None,
+            created_ts: chrono::Utc::now().timestamp(),
+            generated,
+        };
+        db.save_upload(&file).await.map_err(UploadError::Storage)?;
+        Ok(file)
+    }
+
+    pub async fn read(&self, file: &StoredFile) -> Result<Vec<u8>> {
+        Ok(tokio::fs::read(&file.path).await?)
+    }
+
+    /// Remove the file from disk and its record, refunding its quota.
+    /// Messages it was attached to keep their copy of the metadata.
+    pub async fn delete(&self, db: &DBLayer, file: &StoredFile) -> Result<()> {
+        match tokio::fs::remove_file(&file.path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let _guard = self.quota_lock.lock().await;
+        db.delete_upload(file).await?;
+        if !file.generated {
+            let owner = quota_owner(file.user_id.as_deref(), &file.device_hash);
+            db.add_storage_usage(&owner, -(file.size as i64)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Who an upload is charged to: the device's user, else the device.
+fn quota_owner(user_id: Option<&str>, device_hash: &str) -> String {
+    match user_id {
+        Some(user_id) => format!("user:{user_id}"),
+        None => device_hash.to_string(),
+    }
+}
+
+/// Upload `file_id` if the requesting device may use it.
+async fn load_owned(
+    state: &AppState,
+    device_hash: &str,
+    file_id: &str,
+) -> Result<StoredFile, (StatusCode, String)> {
+    state
+        .device_ids
+        .check(&state.db, device_hash)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.code().to_string()))?;
+    let file = state
+        .db
+        .load_upload(file_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "unknown_file_id".to_string()))?;
+    let user_id = state
+        .db
+        .find_user_id_by_device(device_hash)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !file.is_owned_by(device_hash, user_id.as_deref()) {
+        return Err((StatusCode::FORBIDDEN, "file_not_owned_by_device".into()));
+    }
+    Ok(file)
+}
+
+pub fn router(max_bytes: usize) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/uploads",
+            get(list_uploads_handler).post(upload_handler),
+        )
+        .route(
+            "/api/uploads/{file_id}",
+            get(download_handler).delete(delete_upload_handler),
+        )
+        .route(
+            "/api/uploads/{file_id}/transcription",
+            post(transcription_handler),
+        )
+        // Multipart framing on top of the file itself.
+        .layer(DefaultBodyLimit::max(max_bytes + 64 * 1024))
+}
+
+/// Multipart upload with a `device_hash` field and one `file` field.
+/// Returns the stored file record; pass its `id` as `fileId` on a ws
+/// attachment.
+#[utoipa::path(
+    post,
+    path = "/api/uploads",
+    tag = "uploads",
+    request_body(content_type = "multipart/form-data", description = "`device_hash` field plus a `file` part"),
+    responses(
+        (status = 200, description = "Stored file", body = StoredFile),
+        (status = 400, description = "invalid_device_hash / missing_file / empty_file"),
+        (status = 413, description = "file_too_large / storage_quota_exceeded"),
+        (status = 415, description = "unsupported_file_type"),
+    )
+)]
+pub async fn upload_handler(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Json<StoredFile>, ApiError> {
+    let mut device_hash = None;
+    let mut file = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    {
+        match field.name() {
+            Some("device_hash") => {
+                let value = field
+                    .text()
+                    .await
+                    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                device_hash = Some(value.trim().to_string());
+            }
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("upload").to_string();
+                let bytes = field.bytes().await.map_err(|e| {
+                    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "file_too_large")
+                        .with_message(e.to_string())
+                })?;
+                file = Some((filename, bytes));
+            }
+            _ => {}
+        }
+    }
+
+    let device_hash = device_hash.unwrap_or_default();
+    state
+        .device_ids
+        .check(&state.db, &device_hash)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.code().to_string()))?;
+    let (filename, bytes) = file.ok_or((StatusCode::BAD_REQUEST, "missing_file".to_string()))?;
+
+    let stored = state
+        .storage
+        .store(&state.db, &bytes, &filename, &device_hash)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(stored))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    pub device_hash: String,
+}
+
+/// The uploaded file with its detected type, for the device that uploaded
+/// it. Supports `Range`, `If-Range` and `If-None-Match`, so it can back
+/// `<img>` and `<audio>` elements directly.
+#[utoipa::path(
+    get,
+    path = "/api/uploads/{file_id}",
+    tag = "uploads",
+    params(
+        ("file_id" = String, Path, description = "Id returned by `POST /api/uploads`"),
+        ("device_hash" = String, Query, description = "Device that uploaded the file"),
+    ),
+    responses(
+        (status = 200, description = "File contents, `Content-Type` from the stored record"),
+        (status = 206, description = "Requested byte range"),
+        (status = 304, description = "`If-None-Match` matches the `ETag`"),
+        (status = 400, description = "invalid_device_hash"),
+        (status = 403, description = "file_not_owned_by_device"),
+        (status = 404, description = "unknown_file_id"),
+        (status = 416, description = "Range outside the file"),
+    )
+)]
+pub async fn download_handler(
+    State(state): State<AppState>,
+    Path(file_id): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let file = load_owned(&state, query.device_hash.trim(), &file_id).await?;
+    serve::file_response(&file, &headers)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "unknown_file_id".to_string()))
+}
+
+/// Delete an upload: the file, its record and its share of the quota.
+#[utoipa::path(
+    delete,
+    path = "/api/uploads/{file_id}",
+    tag = "uploads",
+    params(
+        ("file_id" = String, Path, description = "Id returned by `POST /api/uploads`"),
+        ("device_hash" = String, Query, description = "Device of the uploader's account"),
+    ),
+    responses(
+        (status = 200, description = "`{ file_id, deleted: true }`"),
+        (status = 400, description = "invalid_device_hash"),
+        (status = 403, description = "file_not_owned_by_device"),
+        (status = 404, description = "unknown_file_id"),
+    )
+)]
+pub async fn delete_upload_handler(
+    State(state): State<AppState>,
+    Path(file_id): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let file = load_owned(&state, query.device_hash.trim(), &file_id).await?;
+    state
+        .storage
+        .delete(&state.db, &file)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(
+        serde_json::json!({ "file_id": file_id, "deleted": true }),
+    ))
+}
+
+/// Files uploaded from any device of the caller's account, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/uploads",
+    tag = "uploads",
+    responses(
+        (status = 200, description = "Stored files of the caller", body = [StoredFile]),
+        (status = 401, description = "invalid_token"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_uploads_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Vec<StoredFile>>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let mut files = state
+        .db
+        .list_uploads_for_user(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    files.sort_by_key(|f| std::cmp::Reverse(f.created_ts));
+    Ok(Json(files))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TranscriptionRequest {
+    pub device_hash: String,
+}
+
+fn ingest_status(err: &IngestError) -> StatusCode {
+    match err {
+        IngestError::UnknownFile(_) => StatusCode::NOT_FOUND,
+        IngestError::NotOwner(_) => StatusCode::FORBIDDEN,
+        IngestError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        IngestError::NotAudio(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        IngestError::TranscriptionUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        IngestError::TranscriptionFailed => StatusCode::BAD_GATEWAY,
+        IngestError::EmptyTranscript | IngestError::TooMany => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+/// Transcribe an uploaded voice recording, e.g. to let the user review the
+/// text before sending it. A ws `prompt` with an empty `text` and the
+/// recording attached is transcribed the same way.
+#[utoipa::path(
+    post,
+    path = "/api/uploads/{file_id}/transcription",
+    tag = "uploads",
+    params(("file_id" = String, Path, description = "Id returned by `POST /api/uploads`")),
+    request_body = TranscriptionRequest,
+    responses(
+        (status = 200, description = "Transcript", body = Transcript),
+        (status = 400, description = "invalid_device_hash"),
+        (status = 403, description = "file_not_owned_by_device"),
+        (status = 404, description = "unknown_file_id"),
+        (status = 415, description = "file_not_audio"),
+        (status = 422, description = "empty_transcript"),
+        (status = 502, description = "transcription_failed"),
+        (status = 503, description = "transcription_unavailable"),
+    )
+)]
+pub async fn transcription_handler(
+    State(state): State<AppState>,
+    Path(file_id): Path<String>,
+    Json(body): Json<TranscriptionRequest>,
+) -> Result<Json<Transcript>, (StatusCode, String)> {
+    let device_hash = body.device_hash.trim();
+    state
+        .device_ids
+        .check(&state.db, device_hash)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.code().to_string()))?;
+    let reject = |err: IngestError| (ingest_status(&err), err.code().to_string());
+
+    let file = load_owned_upload(&state.db, &state.storage, device_hash, &file_id)
+        .await
+        .map_err(reject)?;
+    let transcript = transcribe_upload(state.transcriber.as_deref(), &file, None)
+        .await
+        .map_err(reject)?;
+    Ok(Json(transcript))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing_ignores_declared_extension() {
+        assert_eq!(sniff_mime(b"%PDF-1.7 ...", "notes.txt"), Some(MIME_PDF));
+        assert_eq!(
+            sniff_mime(b"# Title\nbody", "readme.md"),
+            Some(MIME_MARKDOWN)
+        );
+        assert_eq!(sniff_mime(b"plain", "evil.pdf"), Some(MIME_TEXT));
+        assert_eq!(sniff_mime(b"PK\x03\x04rest", "archive.zip"), None);
+        assert_eq!(sniff_mime(&[0x7F, b'E', b'L', b'F', 0, 1], "a.bin"), None);
+    }
+
+    #[test]
+    fn upload_errors_carry_codes() {
+        let quota = ApiError::from(UploadError::QuotaExceeded {
+            used: 900,
+            quota: 1000,
+        });
+        assert_eq!(quota.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(quota.code, "storage_quota_exceeded");
+        assert_eq!(
+            quota.message.as_deref(),
+            Some("900 of 1000 bytes of storage used")
+        );
+        assert_eq!(
+            ApiError::from(UploadError::TooLarge(10)).code,
+            "file_too_large"
+        );
+    }
+
+    #[test]
+    fn sniffs_voice_recordings() {
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WAVEfmt ", "a"), Some("audio/wav"));
+        assert_eq!(sniff_mime(b"OggS\0\x02", "voice.ogg"), Some("audio/ogg"));
+        assert_eq!(
+            sniff_mime(b"\0\0\0\x1cftypM4A \0", "voice"),
+            Some("audio/mp4")
+        );
+        assert_eq!(
+            sniff_mime(b"\0\0\0\x1cftypisom\0", "voice.m4a"),
+            Some("audio/mp4")
+        );
+        assert_eq!(sniff_mime(b"\0\0\0\x1cftypisom\0", "clip.mp4"), None);
+        assert_eq!(
+            sniff_mime(&[0x1A, 0x45, 0xDF, 0xA3, 0x9F], "rec"),
+            Some("audio/webm")
+        );
+    }
+}
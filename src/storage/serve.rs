@@ -0,0 +1,195 @@
+//! Stored files over HTTP: the recorded MIME type, a strong `ETag` from the
+//! content hash, single `Range` requests, and the body streamed from disk,
+//! so browsers can show image previews and seek in audio.
+
+use std::io::SeekFrom;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::Response,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::model::upload::StoredFile;
+
+/// Byte range to send, inclusive on both ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    Full,
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header against a file of `size` bytes. Only single
+/// `bytes=` ranges are honoured; anything else gets the whole file.
+pub fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // `bytes=-N`: the last N bytes.
+        match end.parse::<u64>() {
+            Ok(0) | Err(_) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        let end = match end {
+            "" => size.saturating_sub(1),
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+                _ => return ByteRange::Full,
+            },
+        };
+        (start, end)
+    };
+    if size == 0 || range.0 >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        start: range.0,
+        end: range.1,
+    }
+}
+
+/// `If-None-Match` / `If-Range` style comparison against our strong tag.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+fn content_type(mime: &str) -> String {
+    if mime.starts_with("text/") {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+/// `inline` disposition keeping the original name, RFC 5987 encoded.
+fn content_disposition(filename: &str) -> String {
+    let encoded: String = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect();
+    format!("inline; filename*=UTF-8''{encoded}")
+}
+
+/// Response for `file`, honouring `If-None-Match`, `Range` and `If-Range`.
+pub async fn file_response(file: &StoredFile, request: &HeaderMap) -> std::io::Result<Response> {
+    let etag = format!("\"{}\"", file.sha256);
+    let header_str = |name: HeaderName| request.get(name).and_then(|v| v.to_str().ok());
+
+    let mut response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "private, max-age=86400, immutable")
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+
+    if header_str(header::IF_NONE_MATCH).is_some_and(|h| etag_matches(h, &etag)) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .expect("valid response"));
+    }
+
+    let mut handle = tokio::fs::File::open(&file.path).await?;
+    let size = handle.metadata().await?.len();
+    // A stale `If-Range` means the client's partial copy is of another
+    // version: send everything.
+    let range = match header_str(header::IF_RANGE) {
+        Some(tag) if !etag_matches(tag, &etag) => ByteRange::Full,
+        _ => parse_range(header_str(header::RANGE), size),
+    };
+
+    response = response
+        .header(header::CONTENT_TYPE, content_type(&file.mime_type))
+        .header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(&file.filename),
+        );
+    let response = match range {
+        ByteRange::Full => response
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::from_stream(ReaderStream::new(handle))),
+        ByteRange::Partial { start, end } => {
+            handle.seek(SeekFrom::Start(start)).await?;
+            let len = end - start + 1;
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_LENGTH, len)
+                .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{size}"))
+                .body(Body::from_stream(ReaderStream::new(handle.take(len))))
+        }
+        ByteRange::Unsatisfiable => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+            .body(Body::empty()),
+    };
+    Ok(response.expect("valid response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            parse_range(Some("bytes=0-9"), 100),
+            ByteRange::Partial { start: 0, end: 9 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=90-"), 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-10"), 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=50-500"), 100),
+            ByteRange::Partial { start: 50, end: 99 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=9-2"), 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn matches_etags_and_encodes_names() {
+        assert!(etag_matches("\"abc\", \"def\"", "\"def\""));
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abc\"", "\"abd\""));
+        assert_eq!(
+            content_disposition("my voice \"1\".ogg"),
+            "inline; filename*=UTF-8''my%20voice%20%221%22.ogg"
+        );
+    }
+}
@@ -0,0 +1,3 @@
+//! Tools that bring outside information into replies.
+
+pub mod web_search;
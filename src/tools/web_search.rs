@@ -0,0 +1,391 @@
+//! Web search for questions about recent events. A provider is picked from
+//! the environment (SearxNG, Brave or Bing); its results are summarized by
+//! the model with numbered citations and injected into the reply prompt as
+//! context, so answers about current events come from sources instead of
+//! the training data.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::extract::ws::Message as WsMessage;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::conversation::{build_mistral_prompt, strip_chatml_markers, trim_partial_chatml};
+use crate::inference::InferenceService;
+use crate::model::message::Message;
+
+const DEFAULT_RESULTS: usize = 5;
+const MAX_QUERY_CHARS: usize = 300;
+const MAX_SNIPPET_CHARS: usize = 500;
+
+const SUMMARY_INSTRUCTIONS: &str = "Summarize the web search results below as far as they answer the user's last message. Keep facts, numbers and dates exactly as the results give them and cite each fact with its source number, e.g. [2]. If the results do not answer the question, say so. Write only the summary.";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+#[async_trait]
+pub trait SearchProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Up to `limit` results for `query`, in `language` (ISO 639-1, empty
+    /// for any) where the provider supports it.
+    async fn search(&self, query: &str, language: &str, limit: usize) -> Result<Vec<SearchResult>>;
+}
+
+fn http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(8))
+        .build()?)
+}
+
+fn text_field(value: &Value, key: &str) -> String {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn language_param<'a>(key: &'a str, language: &'a str) -> Vec<(&'a str, &'a str)> {
+    if language.is_empty() {
+        Vec::new()
+    } else {
+        vec![(key, language)]
+    }
+}
+
+async fn get_json(req: reqwest::RequestBuilder, provider: &str) -> Result<Value> {
+    let resp = req.send().await?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(anyhow!("{provider} returned {status}"));
+    }
+    Ok(resp.json().await?)
+}
+
+/// Self-hosted SearxNG with the JSON format enabled.
+pub struct SearxngProvider {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl SearxngProvider {
+    pub fn new(base_url: String) -> Result<Self> {
+        Ok(Self {
+            client: http_client()?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl SearchProvider for SearxngProvider {
+    fn name(&self) -> &'static str {
+        "searxng"
+    }
+
+    async fn search(&self, query: &str, language: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        let req = self
+            .client
+            .get(format!("{}/search", self.base_url))
+            .query(&[("q", query), ("format", "json")])
+            .query(&language_param("language", language));
+        let body = get_json(req, self.name()).await?;
+        Ok(body["results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .take(limit)
+            .map(|r| SearchResult {
+                title: text_field(r, "title"),
+                url: text_field(r, "url"),
+                snippet: text_field(r, "content"),
+            })
+            .collect())
+    }
+}
+
+pub struct BraveProvider {
+    client: reqwest::Client,
+    api_key: String,
+}
+
+impl BraveProvider {
+    pub fn new(api_key: String) -> Result<Self> {
+        Ok(Self {
+            client: http_client()?,
+            api_key,
+        })
+    }
+}
+
+#[async_trait]
+impl SearchProvider for BraveProvider {
+    fn name(&self) -> &'static str {
+        "brave"
+    }
+
+    async fn search(&self, query: &str, language: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        let req = self
+            .client
+            .get("https://api.search.brave.com/res/v1/web/search")
+            .header("X-Subscription-Token", &self.api_key)
+            .header("Accept", "application/json")
+            .query(&[("q", query), ("count", &limit.to_string())])
+            .query(&language_param("search_lang", language));
+        let body = get_json(req, self.name()).await?;
+        Ok(body["web"]["results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .take(limit)
+            .map(|r| SearchResult {
+                title: text_field(r, "title"),
+                url: text_field(r, "url"),
+                snippet: text_field(r, "description"),
+            })
+            .collect())
+    }
+}
+
+pub struct BingProvider {
+    client: reqwest::Client,
+    api_key: String,
+    endpoint: String,
+}
+
+impl BingProvider {
+    pub fn new(api_key: String, endpoint: String) -> Result<Self> {
+        Ok(Self {
+            client: http_client()?,
+            api_key,
+            endpoint,
+        })
+    }
+}
+
+#[async_trait]
+impl SearchProvider for BingProvider {
+    fn name(&self) -> &'static str {
+        "bing"
+    }
+
+    async fn search(&self, query: &str, language: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        let req = self
+            .client
+            .get(&self.endpoint)
+            .header("Ocp-Apim-Subscription-Key", &self.api_key)
+            .query(&[("q", query), ("count", &limit.to_string())])
+            .query(&language_param("setLang", language));
+        let body = get_json(req, self.name()).await?;
+        Ok(body["webPages"]["value"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .take(limit)
+            .map(|r| SearchResult {
+                title: text_field(r, "name"),
+                url: text_field(r, "url"),
+                snippet: text_field(r, "snippet"),
+            })
+            .collect())
+    }
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Provider named by `WEB_SEARCH_PROVIDER` (`searxng`, `brave`, `bing`),
+/// or the first one configured: `SEARXNG_URL`, `BRAVE_SEARCH_API_KEY`,
+/// `BING_SEARCH_API_KEY` (`BING_SEARCH_ENDPOINT` overrides the v7 URL).
+/// `None` disables web search.
+pub fn provider_from_env() -> Option<Arc<dyn SearchProvider>> {
+    let wanted = env_value("WEB_SEARCH_PROVIDER").map(|v| v.to_ascii_lowercase());
+    let wants = |name: &str| wanted.as_deref().map_or(true, |w| w == name);
+
+    let provider: Result<Arc<dyn SearchProvider>> =
+        if let Some(url) = env_value("SEARXNG_URL").filter(|_| wants("searxng")) {
+            SearxngProvider::new(url).map(|p| Arc::new(p) as Arc<dyn SearchProvider>)
+        } else if let Some(key) = env_value("BRAVE_SEARCH_API_KEY").filter(|_| wants("brave")) {
+            BraveProvider::new(key).map(|p| Arc::new(p) as Arc<dyn SearchProvider>)
+        } else if let Some(key) = env_value("BING_SEARCH_API_KEY").filter(|_| wants("bing")) {
+            let endpoint = env_value("BING_SEARCH_ENDPOINT")
+                .unwrap_or_else(|| "https://api.bing.microsoft.com/v7.0/search".into());
+            BingProvider::new(key, endpoint).map(|p| Arc::new(p) as Arc<dyn SearchProvider>)
+        } else {
+            if let Some(wanted) = wanted {
+                warn!("WEB_SEARCH_PROVIDER={wanted} is not configured; web search disabled");
+            }
+            return None;
+        };
+
+    match provider {
+        Ok(provider) => Some(provider),
+        Err(err) => {
+            warn!("web search disabled: {err}");
+            None
+        }
+    }
+}
+
+pub fn result_count() -> usize {
+    std::env::var("WEB_SEARCH_RESULTS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_RESULTS)
+}
+
+/// Search step of one reply.
+pub struct WebSearch {
+    provider: Arc<dyn SearchProvider>,
+    query: String,
+    language: String,
+    chat_id: String,
+    history: Vec<Message>,
+    system_prompt: String,
+}
+
+impl WebSearch {
+    pub fn new(
+        provider: Arc<dyn SearchProvider>,
+        question: &str,
+        language: &str,
+        chat_id: &str,
+        history: &[Message],
+        system_prompt: &str,
+    ) -> Self {
+        Self {
+            provider,
+            query: question.trim().chars().take(MAX_QUERY_CHARS).collect(),
+            language: language.to_string(),
+            chat_id: chat_id.to_string(),
+            history: history.to_vec(),
+            system_prompt: system_prompt.to_string(),
+        }
+    }
+
+    /// Reply prompt with the search context appended to the system prompt.
+    pub fn reply_prompt(&self, context: &str) -> String {
+        let system_prompt = format!("{}\n\n{context}", self.system_prompt);
+        build_mistral_prompt(&self.history, Some(&system_prompt))
+    }
+
+    /// Search, tell the client which sources were used, and summarize the
+    /// results. Returns the context block for the system prompt, or `None`
+    /// when the search failed or found nothing.
+    pub async fn context(
+        &self,
+        infer: &InferenceService,
+        sender: &mpsc::Sender<WsMessage>,
+        cancel: Arc<AtomicBool>,
+    ) -> Option<String> {
+        let results = match self
+            .provider
+            .search(&self.query, &self.language, result_count())
+            .await
+        {
+            Ok(results) => results,
+            Err(err) => {
+                warn!(provider = self.provider.name(), "web search failed: {err}");
+                return None;
+            }
+        };
+        let results: Vec<SearchResult> = results
+            .into_iter()
+            .filter(|r| !r.url.is_empty())
+            .map(|mut r| {
+                r.snippet = r.snippet.chars().take(MAX_SNIPPET_CHARS).collect();
+                r
+            })
+            .collect();
+        if results.is_empty() || cancel.load(Ordering::SeqCst) {
+            return None;
+        }
+        info!(
+            provider = self.provider.name(),
+            results = results.len(),
+            "web search"
+        );
+
+        let frame = json!({
+            "type": "web_search",
+            "chat_id": self.chat_id,
+            "query": self.query,
+            "sources": results
+                .iter()
+                .enumerate()
+                .map(|(idx, r)| json!({ "n": idx + 1, "title": r.title, "url": r.url }))
+                .collect::<Vec<_>>(),
+        });
+        if sender
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await
+            .is_err()
+        {
+            return None;
+        }
+
+        let listing = numbered(&results);
+        let prompt = build_mistral_prompt(
+            &self.history,
+            Some(&format!("{SUMMARY_INSTRUCTIONS}\n\n{listing}")),
+        );
+        let summary = match infer.generate_completion(prompt, cancel).await {
+            Ok(raw) => strip_chatml_markers(trim_partial_chatml(&raw))
+                .trim()
+                .to_string(),
+            Err(err) => {
+                debug!("web result summary failed: {err}");
+                String::new()
+            }
+        };
+        // Without a summary the raw snippets still beat no context.
+        let body = if summary.is_empty() {
+            listing.clone()
+        } else {
+            summary
+        };
+
+        let mut block = format!(
+            "Web search results for this question (cite sources as [n]; prefer them over what you remember):\n{body}\n\nSources:"
+        );
+        for (idx, result) in results.iter().enumerate() {
+            block.push_str(&format!(
+                "\n[{}] {} – {}",
+                idx + 1,
+                result.title,
+                result.url
+            ));
+        }
+        Some(block)
+    }
+}
+
+/// Results as `[n] title (url)` followed by the snippet.
+pub fn numbered(results: &[SearchResult]) -> String {
+    results
+        .iter()
+        .enumerate()
+        .map(|(idx, r)| format!("[{}] {} ({})\n{}", idx + 1, r.title, r.url, r.snippet))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
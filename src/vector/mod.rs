@@ -0,0 +1,142 @@
+//! Embedding storage shared by features that need nearest-neighbour
+//! lookups (conversation memory, document retrieval). Records live in named
+//! collections and carry string metadata that queries can filter on.
+
+#[cfg(feature = "qdrant")]
+mod qdrant;
+mod rocks;
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::db::DBLayer;
+
+#[cfg(feature = "qdrant")]
+pub use qdrant::QdrantVectorStore;
+pub use rocks::RocksVectorStore;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorRecord {
+    pub id: String,
+    pub vector: Vec<f32>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoredRecord {
+    pub id: String,
+    /// Cosine similarity, higher is closer.
+    pub score: f32,
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Metadata conditions a record must all match (exact string equality).
+#[derive(Debug, Clone, Default)]
+pub struct VectorFilter {
+    pub must: Vec<(String, String)>,
+}
+
+impl VectorFilter {
+    pub fn eq(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.must.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn matches(&self, metadata: &BTreeMap<String, String>) -> bool {
+        self.must
+            .iter()
+            .all(|(key, value)| metadata.get(key) == Some(value))
+    }
+}
+
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Insert or replace records by id.
+    async fn insert(&self, collection: &str, records: Vec<VectorRecord>) -> Result<()>;
+
+    async fn delete(&self, collection: &str, ids: &[String]) -> Result<()>;
+
+    /// Remove every record matching `filter`, e.g. all vectors of a user.
+    async fn delete_where(&self, collection: &str, filter: &VectorFilter) -> Result<()>;
+
+    /// The `k` records closest to `vector` among those matching `filter`.
+    async fn query(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        k: usize,
+        filter: &VectorFilter,
+    ) -> Result<Vec<ScoredRecord>>;
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// `VECTOR_STORE=qdrant` (with `QDRANT_URL`, optional `QDRANT_API_KEY`)
+/// selects Qdrant when the `qdrant` feature is compiled in; anything else
+/// uses brute-force search over RocksDB.
+pub fn vector_store_from_env(db: Arc<DBLayer>) -> Arc<dyn VectorStore> {
+    let backend = std::env::var("VECTOR_STORE")
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if backend == "qdrant" {
+        #[cfg(feature = "qdrant")]
+        {
+            let url =
+                std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://localhost:6333".into());
+            let api_key = std::env::var("QDRANT_API_KEY")
+                .ok()
+                .filter(|v| !v.is_empty());
+            match QdrantVectorStore::new(url, api_key) {
+                Ok(store) => return Arc::new(store),
+                Err(err) => tracing::warn!("qdrant unavailable, using rocksdb vectors: {err}"),
+            }
+        }
+        #[cfg(not(feature = "qdrant"))]
+        tracing::warn!("VECTOR_STORE=qdrant but built without the `qdrant` feature; using rocksdb");
+    }
+    Arc::new(RocksVectorStore::new(db))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_and_filters() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 2.0]), 0.0);
+
+        let metadata = BTreeMap::from([
+            ("user_id".to_string(), "u1".to_string()),
+            ("kind".to_string(), "memory".to_string()),
+        ]);
+        assert!(VectorFilter::default().matches(&metadata));
+        assert!(VectorFilter::default()
+            .eq("user_id", "u1")
+            .matches(&metadata));
+        assert!(!VectorFilter::default()
+            .eq("user_id", "u1")
+            .eq("kind", "doc")
+            .matches(&metadata));
+    }
+}
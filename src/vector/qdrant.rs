@@ -0,0 +1,190 @@
+use std::{collections::HashSet, sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::{ScoredRecord, VectorFilter, VectorRecord, VectorStore};
+
+/// Payload field holding our record id; Qdrant point ids must be UUIDs or
+/// integers, so points are keyed by a UUID derived from it.
+const ID_FIELD: &str = "_record_id";
+
+/// HNSW search through Qdrant's REST API.
+pub struct QdrantVectorStore {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    // Collections known to exist, so inserts only create them once.
+    collections: Mutex<HashSet<String>>,
+}
+
+fn point_id(id: &str) -> String {
+    let digest = Sha256::digest(id.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    Uuid::from_bytes(bytes).to_string()
+}
+
+fn filter_json(filter: &VectorFilter) -> Value {
+    let must: Vec<Value> = filter
+        .must
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "match": { "value": value } }))
+        .collect();
+    json!({ "must": must })
+}
+
+impl QdrantVectorStore {
+    pub fn new(base_url: String, api_key: Option<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    async fn call(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value> {
+        let mut req = self
+            .client
+            .request(method, format!("{}{path}", self.base_url));
+        if let Some(body) = &body {
+            req = req.json(body);
+        }
+        if let Some(key) = &self.api_key {
+            req = req.header("api-key", key);
+        }
+        let resp = req.send().await?;
+        let status = resp.status();
+        let body: Value = resp.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            return Err(anyhow!("qdrant {path} returned {status}: {body}"));
+        }
+        Ok(body)
+    }
+
+    async fn ensure_collection(&self, collection: &str, dim: usize) -> Result<()> {
+        if self.collections.lock().unwrap().contains(collection) {
+            return Ok(());
+        }
+        let path = format!("/collections/{collection}");
+        let exists = self.call(reqwest::Method::GET, &path, None).await.is_ok();
+        if !exists {
+            self.call(
+                reqwest::Method::PUT,
+                &path,
+                Some(json!({ "vectors": { "size": dim, "distance": "Cosine" } })),
+            )
+            .await?;
+        }
+        self.collections
+            .lock()
+            .unwrap()
+            .insert(collection.to_string());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantVectorStore {
+    async fn insert(&self, collection: &str, records: Vec<VectorRecord>) -> Result<()> {
+        let Some(first) = records.first() else {
+            return Ok(());
+        };
+        self.ensure_collection(collection, first.vector.len())
+            .await?;
+        let points: Vec<Value> = records
+            .into_iter()
+            .map(|record| {
+                let mut payload = json!(record.metadata);
+                payload[ID_FIELD] = json!(record.id);
+                json!({
+                    "id": point_id(&record.id),
+                    "vector": record.vector,
+                    "payload": payload,
+                })
+            })
+            .collect();
+        self.call(
+            reqwest::Method::PUT,
+            &format!("/collections/{collection}/points?wait=true"),
+            Some(json!({ "points": points })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, collection: &str, ids: &[String]) -> Result<()> {
+        let points: Vec<String> = ids.iter().map(|id| point_id(id)).collect();
+        self.call(
+            reqwest::Method::POST,
+            &format!("/collections/{collection}/points/delete?wait=true"),
+            Some(json!({ "points": points })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_where(&self, collection: &str, filter: &VectorFilter) -> Result<()> {
+        self.call(
+            reqwest::Method::POST,
+            &format!("/collections/{collection}/points/delete?wait=true"),
+            Some(json!({ "filter": filter_json(filter) })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        k: usize,
+        filter: &VectorFilter,
+    ) -> Result<Vec<ScoredRecord>> {
+        let body = self
+            .call(
+                reqwest::Method::POST,
+                &format!("/collections/{collection}/points/search"),
+                Some(json!({
+                    "vector": vector,
+                    "limit": k,
+                    "filter": filter_json(filter),
+                    "with_payload": true,
+                })),
+            )
+            .await?;
+
+        let hits = body["result"].as_array().cloned().unwrap_or_default();
+        Ok(hits
+            .into_iter()
+            .map(|hit| {
+                let mut metadata: std::collections::BTreeMap<String, String> = hit["payload"]
+                    .as_object()
+                    .map(|payload| {
+                        payload
+                            .iter()
+                            .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                ScoredRecord {
+                    id: metadata.remove(ID_FIELD).unwrap_or_default(),
+                    score: hit["score"].as_f64().unwrap_or(0.0) as f32,
+                    metadata,
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,105 @@
+use std::{cmp::Ordering, collections::BinaryHeap, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{cosine_similarity, ScoredRecord, VectorFilter, VectorRecord, VectorStore};
+use crate::db::DBLayer;
+
+/// Exact search over records stored in RocksDB. Every query scans the
+/// collection, which is fine up to some tens of thousands of vectors.
+pub struct RocksVectorStore {
+    db: Arc<DBLayer>,
+}
+
+impl RocksVectorStore {
+    pub fn new(db: Arc<DBLayer>) -> Self {
+        Self { db }
+    }
+}
+
+/// Min-heap entry so the heap root is the weakest of the current top k.
+struct Candidate(ScoredRecord);
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.score.total_cmp(&self.0.score)
+    }
+}
+
+#[async_trait]
+impl VectorStore for RocksVectorStore {
+    async fn insert(&self, collection: &str, records: Vec<VectorRecord>) -> Result<()> {
+        self.db.put_vectors(collection, &records).await
+    }
+
+    async fn delete(&self, collection: &str, ids: &[String]) -> Result<()> {
+        self.db.delete_vectors(collection, ids).await
+    }
+
+    async fn delete_where(&self, collection: &str, filter: &VectorFilter) -> Result<()> {
+        let db = self.db.clone();
+        let collection_name = collection.to_string();
+        let filter = filter.clone();
+        let ids = tokio::task::spawn_blocking(move || {
+            let mut ids = Vec::new();
+            db.for_each_vector(&collection_name, |record| {
+                if filter.matches(&record.metadata) {
+                    ids.push(record.id);
+                }
+            })
+            .map(|_| ids)
+        })
+        .await??;
+        self.db.delete_vectors(collection, &ids).await
+    }
+
+    async fn query(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        k: usize,
+        filter: &VectorFilter,
+    ) -> Result<Vec<ScoredRecord>> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let db = self.db.clone();
+        let collection = collection.to_string();
+        let query = vector.to_vec();
+        let filter = filter.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut heap = BinaryHeap::with_capacity(k + 1);
+            db.for_each_vector(&collection, |record| {
+                if !filter.matches(&record.metadata) {
+                    return;
+                }
+                heap.push(Candidate(ScoredRecord {
+                    score: cosine_similarity(&query, &record.vector),
+                    id: record.id,
+                    metadata: record.metadata,
+                }));
+                if heap.len() > k {
+                    heap.pop();
+                }
+            })?;
+            // Ascending by `Candidate` order is descending by score.
+            Ok(heap.into_sorted_vec().into_iter().map(|c| c.0).collect())
+        })
+        .await?
+    }
+}
@@ -0,0 +1,398 @@
+//! Per-user outbound webhooks. A user registers an endpoint for some of
+//! `generation_completed`, `summary_created` and `moderation_flagged`; the
+//! dispatcher follows the event bus and POSTs each matching event of the
+//! user's chats there, signed with the webhook's secret:
+//!
+//! `X-Webhook-Signature: t=<unix ts>,v1=<hex HMAC-SHA256 of "<ts>.<body>">`
+//!
+//! Failed deliveries are retried with backoff. Only public hosts are
+//! accepted (see [`target`]). Webhooks are stored per user under
+//! `webhook:{user_id}:{webhook_id}`.
+
+pub mod target;
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get},
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use base64::Engine;
+use chrono::Utc;
+use headers::{authorization::Bearer, Authorization};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::tokens::authenticate,
+    db::DBLayer,
+    events::{Event, EventBus},
+    ws::AppState,
+};
+
+pub const MAX_WEBHOOKS_PER_USER: usize = 5;
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_ATTEMPTS: u32 = 5;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/webhooks",
+            get(list_webhooks_handler).post(create_webhook_handler),
+        )
+        .route("/api/webhooks/{webhook_id}", delete(delete_webhook_handler))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    GenerationCompleted,
+    SummaryCreated,
+    ModerationFlagged,
+}
+
+impl WebhookEvent {
+    const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::GenerationCompleted,
+        WebhookEvent::SummaryCreated,
+        WebhookEvent::ModerationFlagged,
+    ];
+
+    /// Kind and owning user of a bus event; `None` for events that are not
+    /// delivered to user webhooks.
+    fn of(event: &Event) -> Option<(Self, Option<&str>)> {
+        match event {
+            Event::AssistantMessageFinalized(e) => {
+                Some((Self::GenerationCompleted, e.user_id.as_deref()))
+            }
+            Event::SummaryCreated(e) => Some((Self::SummaryCreated, e.user_id.as_deref())),
+            Event::ModerationFlagged(e) => Some((Self::ModerationFlagged, e.user_id.as_deref())),
+            Event::CanaryRegression(_) | Event::ComponentIncident(_) => None,
+        }
+    }
+}
+
+/// Stored webhook registration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub user_id: String,
+    pub url: String,
+    /// HMAC key for the signature header.
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_ts: i64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Events to deliver; all of them when empty or omitted.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookResponse {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_ts: i64,
+    /// Signing secret; only returned when the webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<Webhook> for WebhookResponse {
+    fn from(hook: Webhook) -> Self {
+        Self {
+            id: hook.id,
+            url: hook.url,
+            events: hook.events,
+            created_ts: hook.created_ts,
+            secret: None,
+        }
+    }
+}
+
+/// Body of every delivery.
+#[derive(Debug, Serialize)]
+struct Delivery<'a> {
+    /// Unique per delivery, repeated on retries, so receivers can dedupe.
+    id: String,
+    #[serde(rename = "type")]
+    kind: WebhookEvent,
+    created_ts: i64,
+    data: &'a Value,
+}
+
+/// `t=<ts>,v1=<hex hmac>` over `"<ts>.<body>"`.
+pub fn signature(secret: &str, ts: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+    mac.update(ts.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("t={ts},v1={hex}")
+}
+
+fn new_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!(
+        "whsec_{}",
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "Webhooks of the caller, without secrets", body = [WebhookResponse]),
+        (status = 401, description = "invalid_token"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_webhooks_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Vec<WebhookResponse>>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let mut hooks = state
+        .db
+        .list_webhooks(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    hooks.sort_by_key(|h| h.created_ts);
+    Ok(Json(hooks.into_iter().map(WebhookResponse::from).collect()))
+}
+
+/// Register a webhook. The signing secret is in this response only.
+#[utoipa::path(
+    post,
+    path = "/api/webhooks",
+    tag = "webhooks",
+    request_body = CreateWebhookRequest,
+    responses(
+        (status = 200, description = "Webhook with its signing secret", body = WebhookResponse),
+        (status = 400, description = "invalid_webhook_url"),
+        (status = 401, description = "invalid_token"),
+        (status = 409, description = "webhook_limit_reached"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_webhook_handler(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<CreateWebhookRequest>,
+) -> Result<Json<WebhookResponse>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    let url = payload.url.trim().to_string();
+    if let Err(err) = target::check_url(&url).await {
+        debug!("webhook url refused: {err}");
+        return Err((StatusCode::BAD_REQUEST, "invalid_webhook_url".into()));
+    }
+    let existing = state
+        .db
+        .list_webhooks(&claims.sub)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if existing.len() >= MAX_WEBHOOKS_PER_USER {
+        return Err((StatusCode::CONFLICT, "webhook_limit_reached".into()));
+    }
+
+    let events = WebhookEvent::ALL
+        .into_iter()
+        .filter(|e| payload.events.is_empty() || payload.events.contains(e))
+        .collect();
+    let hook = Webhook {
+        id: Uuid::new_v4().to_string(),
+        user_id: claims.sub,
+        url,
+        secret: new_secret(),
+        events,
+        created_ts: Utc::now().timestamp(),
+    };
+    state
+        .db
+        .save_webhook(&hook)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let secret = hook.secret.clone();
+    Ok(Json(WebhookResponse {
+        secret: Some(secret),
+        ..hook.into()
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/webhooks/{webhook_id}",
+    tag = "webhooks",
+    params(("webhook_id" = String, Path, description = "Webhook id")),
+    responses(
+        (status = 200, description = "`{ webhook_id, deleted: true }`"),
+        (status = 401, description = "invalid_token"),
+        (status = 404, description = "webhook_not_found"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_webhook_handler(
+    State(state): State<AppState>,
+    Path(webhook_id): Path<String>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let claims = authenticate(&state, auth.token()).await?;
+    state
+        .db
+        .load_webhook(&claims.sub, &webhook_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "webhook_not_found".to_string()))?;
+    state
+        .db
+        .delete_webhook(&claims.sub, &webhook_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(
+        serde_json::json!({ "webhook_id": webhook_id, "deleted": true }),
+    ))
+}
+
+/// Deliver bus events to the webhooks of the user they belong to.
+pub fn spawn_webhook_dispatcher(db: Arc<DBLayer>, bus: &EventBus) -> bool {
+    let client = match target::client(DELIVERY_TIMEOUT) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("user webhooks disabled: {err}");
+            return false;
+        }
+    };
+
+    let mut rx = bus.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "user webhooks lagging; events dropped");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Some((kind, Some(user_id))) = WebhookEvent::of(&event) else {
+                continue;
+            };
+            let hooks = match db.list_webhooks(user_id).await {
+                Ok(hooks) => hooks,
+                Err(err) => {
+                    warn!("failed to list webhooks: {err}");
+                    continue;
+                }
+            };
+            let data = match serde_json::to_value(&event) {
+                Ok(data) => Arc::new(data),
+                Err(err) => {
+                    warn!("failed to encode event: {err}");
+                    continue;
+                }
+            };
+            for hook in hooks.into_iter().filter(|h| h.events.contains(&kind)) {
+                tokio::spawn(deliver(client.clone(), hook, kind, data.clone()));
+            }
+        }
+    });
+    info!("user webhook dispatcher started");
+    true
+}
+
+/// POST one event, retrying failures with exponential backoff. Each attempt
+/// is signed with a fresh timestamp.
+async fn deliver(client: reqwest::Client, hook: Webhook, kind: WebhookEvent, data: Arc<Value>) {
+    let delivery = Delivery {
+        id: Uuid::new_v4().to_string(),
+        kind,
+        created_ts: Utc::now().timestamp(),
+        data: &data,
+    };
+    let body = match serde_json::to_vec(&delivery) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("failed to encode webhook delivery: {err}");
+            return;
+        }
+    };
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        if let Err(err) = target::check_url(&hook.url).await {
+            warn!(
+                webhook_id = hook.id.as_str(),
+                "webhook url refused at delivery: {err}"
+            );
+            return;
+        }
+        let ts = Utc::now().timestamp();
+        let result = client
+            .post(&hook.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, signature(&hook.secret, ts, &body))
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(resp) if resp.status().is_success() => {
+                debug!(webhook_id = hook.id.as_str(), attempt, "webhook delivered");
+                return;
+            }
+            Ok(resp) => warn!(
+                webhook_id = hook.id.as_str(),
+                attempt,
+                status = %resp.status(),
+                "webhook rejected delivery"
+            ),
+            Err(err) => warn!(
+                webhook_id = hook.id.as_str(),
+                attempt, "webhook request failed: {err}"
+            ),
+        }
+        if attempt < DELIVERY_ATTEMPTS {
+            tokio::time::sleep(Duration::from_secs(2u64.pow(attempt - 1))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            signature("whsec_test", 1700000000, br#"{"a":1}"#),
+            "t=1700000000,v1=38877139021993b830af32feea6e18a8da83eb2f6e49ee50bd9e4cf4ca4d3789"
+        );
+        assert_ne!(
+            signature("whsec_test", 1700000001, br#"{"a":1}"#),
+            signature("whsec_test", 1700000000, br#"{"a":1}"#)
+        );
+    }
+}
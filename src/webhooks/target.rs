@@ -0,0 +1,152 @@
+//! Where a user-registered URL may point. Webhooks and schedule webhooks
+//! are set by ordinary users, so their host must resolve to public
+//! addresses only: loopback, private, link-local, unique-local and the
+//! other special ranges would let a user make the server POST into its
+//! own network. The URL is checked when it is registered and again before
+//! each delivery, since DNS can change in between, and redirects are not
+//! followed.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::Url;
+
+const MAX_URL_CHARS: usize = 2048;
+
+/// An `http(s)` URL of acceptable length whose host, if an IP literal, is
+/// public. Names are left to [`check_url`].
+fn parse(url: &str) -> Result<Url> {
+    if url.len() > MAX_URL_CHARS {
+        bail!("url is longer than {MAX_URL_CHARS} characters");
+    }
+    let parsed = Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("url scheme must be http or https");
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| anyhow!("url has no host"))?;
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        if !is_public(ip) {
+            bail!("{ip} is not a public address");
+        }
+    }
+    Ok(parsed)
+}
+
+/// [`parse`] `url` and resolve its host; every address must be public.
+pub async fn check_url(url: &str) -> Result<Url> {
+    let parsed = parse(url)?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| anyhow!("url has no host"))?
+        .trim_matches(['[', ']'])
+        .to_string();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let mut resolved = false;
+    for addr in tokio::net::lookup_host((host.as_str(), port)).await? {
+        if !is_public(addr.ip()) {
+            bail!("{host} resolves to {}, which is not public", addr.ip());
+        }
+        resolved = true;
+    }
+    if !resolved {
+        bail!("{host} does not resolve");
+    }
+    Ok(parsed)
+}
+
+/// Client for deliveries to user URLs: no redirects, so a public endpoint
+/// cannot bounce the POST to an internal one.
+pub fn client(timeout: Duration) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Carrier-grade NAT, IETF protocol assignments, benchmarking and
+        // the reserved 240/4.
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local fc00::/7, link-local fe80::/10, site-local
+        // fec0::/10 and documentation 2001:db8::/32.
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xffc0) == 0xfec0
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // IPv4-compatible and NAT64 forms carry an IPv4 address.
+        || ip.segments()[..6].iter().all(|s| *s == 0)
+        || (first == 0x0064 && ip.segments()[1] == 0xff9b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_internal_addresses_and_other_schemes() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "https://10.0.0.5/",
+            "http://192.168.1.1/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "ftp://example.com/",
+            "file:///etc/passwd",
+            "not a url",
+        ] {
+            assert!(parse(url).is_err(), "{url}");
+        }
+        assert!(parse(&format!(
+            "https://example.com/{}",
+            "a".repeat(MAX_URL_CHARS)
+        ))
+        .is_err());
+
+        for url in [
+            "https://example.com/hook",
+            "http://93.184.216.34/",
+            "https://[2606:4700::1111]/",
+        ] {
+            assert!(parse(url).is_ok(), "{url}");
+        }
+    }
+}
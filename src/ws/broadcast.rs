@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::extract::ws::Message as WsMessage;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+/// Frames a slow socket may fall behind before it skips some.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone)]
+struct ChatFrame {
+    /// Socket that already got the frame directly.
+    origin: Option<Arc<str>>,
+    text: Arc<str>,
+}
+
+/// One broadcast channel per chat, so every socket registered on a chat
+/// (phone and desktop, say) sees replies streaming on any of them.
+#[derive(Clone, Default)]
+pub struct ChatBroadcast {
+    inner: Arc<Mutex<HashMap<String, broadcast::Sender<ChatFrame>>>>,
+}
+
+impl ChatBroadcast {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a frame to the sockets registered on `chat_id`, except `origin`.
+    pub fn publish(&self, chat_id: &str, origin: Option<&str>, text: &str) {
+        let chats = self.inner.lock().unwrap();
+        if let Some(tx) = chats.get(chat_id) {
+            let _ = tx.send(ChatFrame {
+                origin: origin.map(Arc::from),
+                text: Arc::from(text),
+            });
+        }
+    }
+
+    /// Forward the frames of `chat_id` to a socket until the returned task
+    /// is aborted or the socket closes. Channels nobody listens to any more
+    /// are dropped here.
+    pub fn subscribe(
+        &self,
+        chat_id: &str,
+        connection_id: &str,
+        sender: mpsc::Sender<WsMessage>,
+    ) -> JoinHandle<()> {
+        let mut rx = {
+            let mut chats = self.inner.lock().unwrap();
+            chats.retain(|_, tx| tx.receiver_count() > 0);
+            chats
+                .entry(chat_id.to_string())
+                .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+                .subscribe()
+        };
+        let connection_id = connection_id.to_string();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(frame) => {
+                        if frame.origin.as_deref() == Some(connection_id.as_str()) {
+                            continue;
+                        }
+                        let msg = WsMessage::Text(frame.text.to_string().into());
+                        if sender.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!(connection_id = %connection_id, skipped, "chat feed lagged");
+                        let gap = serde_json::json!({
+                            "type": "system",
+                            "event": "frames_dropped",
+                            "count": skipped,
+                        });
+                        if sender
+                            .send(WsMessage::Text(gap.to_string().into()))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
@@ -7,24 +7,115 @@ use serde::Deserialize;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, Duration, Instant, Interval, MissedTickBehavior};
+use utoipa::ToSchema;
 
-use crate::attachments::{attachment_summaries, IncomingAttachment};
-use crate::conversation::{build_mistral_prompt, trim_history};
+use crate::agent::chat_tools::ToolSession;
+use crate::agent::runs::AgentRunRegistry;
+use crate::api::RequestId;
+use crate::attachments::{
+    ingest::{ingest_attachments, transcribe_voice_message},
+    message_attachment_summaries, IncomingAttachment,
+};
+use crate::auth::device::DeviceIdSigner;
+use crate::auth::mailer::Mailer;
+use crate::classifier::routing::{apply_reasoning_profile_override, rules, ReasoningProfile};
+use crate::cluster::Cluster;
+use crate::conversation::compaction::{compact_history, fit_context, HISTORY_WINDOW};
+use crate::conversation::language::{self, LanguageTransition};
+use crate::conversation::{build_mistral_prompt, merge_system_prompt, stop_sequences};
 use crate::db::DBLayer;
-use crate::inference::InferenceService;
+use crate::events::{Event, EventBus, ModerationFlagged};
+use crate::experiments::{self, Assignment, Outcome};
+use crate::images::{self, ImageGenerateRequest};
+use crate::inference::{
+    gpu_watchdog::{CapacityError, CAPACITY_ERROR},
+    llama_cpp_service::{random_seed, GenerationParams, MAX_SEED},
+    reasoning::ReasoningMode,
+    timeouts::TimeoutPolicy,
+    whisper::Transcriber,
+    InferenceService,
+};
 use crate::internal_api::handlers::ensure_chat_for_device;
+use crate::maintenance::MaintenanceMode;
 use crate::manager::ModelManager;
 use crate::model::chat::Chat;
-use crate::model::message::{Message, MessageAttachment};
+use crate::model::message::Message;
+use crate::model::routing::RoutingRecord;
+use crate::moderation::{self, ModerationRecord};
 use crate::payment::PaymentService;
 use crate::prompts;
+use crate::redaction;
+use crate::status::HealthMonitor;
+use crate::storage::StorageService;
+use crate::tools::web_search::{SearchProvider, WebSearch};
+use crate::vector::VectorStore;
+use crate::ws::broadcast::ChatBroadcast;
 use crate::ws::inference_worker::{InferenceJob, InferenceWorker};
+use crate::ws::registry::ConnectionRegistry;
+use crate::ws::resume::{CatchUp, ResumeOwner, ResumeRegistry};
 use anyhow::{anyhow, Error};
-use tracing::{debug, info, warn};
+use tracing::{debug, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 const CLASSIFIER_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_PING_INTERVAL_SECS: u64 = 20;
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+/// Largest inbound frame; prompts may inline attachment previews as base64.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 10 * 1024 * 1024;
+/// How long a closing connection may keep flushing queued frames.
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn env_secs(name: &str, default: u64) -> Option<Duration> {
+    let secs = std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(default);
+    (secs > 0).then_some(Duration::from_secs(secs))
+}
+
+/// Server pings and the idle timeout of a connection
+/// (`WS_PING_INTERVAL_SECS`, default 20, and `WS_IDLE_TIMEOUT_SECS`,
+/// default 60; `0` disables either). Any inbound frame, pongs included,
+/// counts as activity.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    ping_every: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl Heartbeat {
+    fn from_env() -> Self {
+        Self {
+            ping_every: env_secs("WS_PING_INTERVAL_SECS", DEFAULT_PING_INTERVAL_SECS),
+            idle_timeout: env_secs("WS_IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT_SECS),
+        }
+    }
+
+    /// Ticks at the ping interval, or at the idle timeout when pings are off.
+    fn ticker(&self) -> Option<Interval> {
+        let period = self.ping_every.or(self.idle_timeout)?;
+        let mut ticker = tokio::time::interval_at(Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Some(ticker)
+    }
+
+    fn is_idle(&self, last_seen: Instant) -> bool {
+        self.idle_timeout
+            .is_some_and(|limit| last_seen.elapsed() >= limit)
+    }
+}
+
+/// Next heartbeat tick; never resolves when the heartbeat is disabled.
+async fn next_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
 // ------------------------------------------------------------
 // TYPES
 // ------------------------------------------------------------
@@ -38,9 +129,28 @@ pub struct AppState {
     pub google_client_id: String,
     pub apple_client_id: String,
     pub payment: Option<PaymentService>,
+    pub events: EventBus,
+    pub mailer: Arc<dyn Mailer>,
+    pub connections: ConnectionRegistry,
+    /// Replies that survive a dropped socket, for `resume`.
+    pub resume: ResumeRegistry,
+    /// Reply frames of each chat, for all sockets registered on it.
+    pub chats: ChatBroadcast,
+    pub device_ids: Arc<DeviceIdSigner>,
+    pub storage: Arc<StorageService>,
+    pub vectors: Arc<dyn VectorStore>,
+    pub maintenance: MaintenanceMode,
+    pub health: HealthMonitor,
+    pub agent_runs: AgentRunRegistry,
+    /// This node and which node serves each chat.
+    pub cluster: Cluster,
+    /// `None` when no search provider is configured.
+    pub web_search: Option<Arc<dyn SearchProvider>>,
+    /// Speech to text for voice messages; `None` when not configured.
+    pub transcriber: Option<Arc<dyn Transcriber>>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct PromptMsg {
     pub msg_type: MsgType,
     pub request_id: String,
@@ -52,14 +162,60 @@ pub struct PromptMsg {
     pub language: Option<String>,
     #[serde(default)]
     pub attachments: Vec<IncomingAttachment>,
+    /// On `register`: receive previews of replies streaming on the user's
+    /// other sockets.
+    #[serde(default)]
+    pub live_preview: bool,
+    /// On `prompt`: skip reasoning profile detection and use this one
+    /// (snake_case name, e.g. `formal_logic`). Meant for internal tools and
+    /// evaluation runs.
+    #[serde(default)]
+    pub reasoning_profile: Option<String>,
+    /// On `prompt`: let the model call these tools (`calculator`,
+    /// `retrieval`, `web_search`) before replying; tools the server does
+    /// not allow are ignored.
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// On `prompt`: sampling seed (0 to 4294967294); the same seed, history
+    /// and settings reproduce a reply. Without one a random seed is used.
+    /// Both are echoed as `seed` in the `done` frame. On `image_prompt`:
+    /// seed of the image.
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// On `prompt`: answer as this persona of the chat owner (see
+    /// `/api/personas`). Unknown ids and personas of free accounts are
+    /// ignored.
+    #[serde(default)]
+    pub persona_id: Option<String>,
+    /// On `prompt`: when the chat's last reply was cut short (`truncated`)
+    /// and `text` repeats the prompt it answered, generate the rest of
+    /// that reply instead of a new one.
+    #[serde(default, rename = "continue")]
+    pub continue_reply: bool,
+    /// On `continue`: the reply to finish.
+    #[serde(default)]
+    pub message_id: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum MsgType {
     Prompt,
     Register,
     Cancel,
+    /// Lock the chat to `language` (e.g. after `language_switch_suggested`).
+    SetLanguage,
+    /// Receive the reply to `request_id` from a dropped socket: what was
+    /// generated so far, then the rest as it streams.
+    Resume,
+    /// Get the whole text of reply `request_id` so far, e.g. after
+    /// `frames_dropped`.
+    Sync,
+    /// Generate an image from `text`; answered with an `image` frame.
+    ImagePrompt,
+    /// Finish the cut-short reply `message_id`, the chat's latest message:
+    /// its prompt is answered again with the reply left open.
+    Continue,
 }
 
 #[derive(Debug, Default)]
@@ -68,6 +224,8 @@ struct WsSession {
     session_id: Option<String>,
     chat_id: Option<String>,
     cancel: Arc<AtomicBool>,
+    /// Forwards replies streaming on other sockets of `chat_id`.
+    chat_feed: Option<tokio::task::JoinHandle<()>>,
 }
 
 // ------------------------------------------------------------
@@ -80,37 +238,96 @@ pub fn ws_router() -> Router<AppState> {
 async fn ws_handler(
     ws: axum::extract::WebSocketUpgrade,
     State(state): State<AppState>,
+    RequestId(request_id): RequestId,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    // The upgrade runs outside the HTTP request's span.
+    let span = info_span!("ws", request_id = %request_id);
+    // `WS_MAX_MESSAGE_BYTES`, default 10 MiB.
+    let max_message = std::env::var("WS_MAX_MESSAGE_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES);
+    ws.max_message_size(max_message)
+        .on_upgrade(move |socket| handle_socket(socket, state, request_id).instrument(span))
 }
 
 // ------------------------------------------------------------
 // WEBSOCKET HANDLER (SPLIT SOCKET)
 // ------------------------------------------------------------
-async fn handle_socket(socket: WebSocket, state: AppState) {
+/// `request_id` is the upgrade request's id; error frames carry it so a
+/// client report can be matched with the connection's logs.
+async fn handle_socket(socket: WebSocket, state: AppState, request_id: String) {
     let (mut ws_sender, mut receiver) = socket.split();
 
     let session = Arc::new(Mutex::new(WsSession::default()));
     let (tx, mut rx) = mpsc::channel::<WsMessage>(32);
+    let connection_id = Uuid::new_v4().to_string();
 
-    // Dedicated writer task keeps websocket flushing smoothly.
-    let writer = tokio::spawn(async move {
+    // Dedicated writer task keeps websocket flushing smoothly. A frame the
+    // socket does not take in time is dropped; the next one is preceded by
+    // `frames_dropped` so the client knows to `sync`.
+    let mut writer = tokio::spawn(async move {
+        let mut dropped = 0u64;
         while let Some(msg) = rx.recv().await {
+            if dropped > 0 {
+                let mut gap = json_system("frames_dropped");
+                gap["count"] = serde_json::json!(dropped);
+                let gap = WsMessage::Text(gap.to_string().into());
+                match timeout(Duration::from_secs(30), ws_sender.send(gap)).await {
+                    Ok(Ok(_)) => dropped = 0,
+                    Ok(Err(_)) => break,
+                    Err(_) => {}
+                }
+            }
             match timeout(Duration::from_secs(30), ws_sender.send(msg)).await {
                 Ok(Ok(_)) => {}
                 Ok(Err(_)) => break,
-                Err(_) => continue,
+                Err(_) => {
+                    dropped += 1;
+                    continue;
+                }
             }
         }
     });
 
-    'socket_loop: while let Some(Ok(msg)) = receiver.next().await {
+    let heartbeat = Heartbeat::from_env();
+    let mut ticker = heartbeat.ticker();
+    let mut last_seen = Instant::now();
+
+    'socket_loop: loop {
+        let msg = tokio::select! {
+            frame = receiver.next() => match frame {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = next_tick(&mut ticker) => {
+                if heartbeat.is_idle(last_seen) {
+                    info!(
+                        connection_id = %connection_id,
+                        idle_secs = last_seen.elapsed().as_secs(),
+                        "closing idle websocket"
+                    );
+                    break;
+                }
+                // A full queue means the writer is stuck; the idle check
+                // closes the connection if the peer stays silent.
+                if heartbeat.ping_every.is_some() {
+                    let _ = tx.try_send(WsMessage::Ping(Default::default()));
+                }
+                continue;
+            }
+        };
+        last_seen = Instant::now();
+
         match msg {
             WsMessage::Text(raw) => {
                 let parsed: PromptMsg = match serde_json::from_str(raw.as_str()) {
                     Ok(v) => v,
                     Err(_) => {
-                        if let Err(err) = send_json(&tx, json_error("Invalid JSON")).await {
+                        if let Err(err) =
+                            send_json(&tx, json_error("Invalid JSON", &request_id)).await
+                        {
                             eprintln!("failed to send ws message: {err}");
                             break 'socket_loop;
                         }
@@ -120,6 +337,17 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
 
                 tokio::task::yield_now().await;
 
+                if !matches!(parsed.msg_type, MsgType::Cancel) {
+                    if let Err(err) = state.device_ids.check(&state.db, &parsed.device_hash).await {
+                        if let Err(err) = send_json(&tx, json_error(err.code(), &request_id)).await
+                        {
+                            eprintln!("failed to send ws message: {err}");
+                            break 'socket_loop;
+                        }
+                        continue;
+                    }
+                }
+
                 if !matches!(parsed.msg_type, MsgType::Register) {
                     info!(
                         chat_id = parsed.chat_id.as_str(),
@@ -129,30 +357,282 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         device_hash = parsed.device_hash.as_str(),
                         language = parsed.language.as_deref().unwrap_or(""),
                         attachments = parsed.attachments.len(),
-                        text = parsed.text.as_str(),
+                        text = %redaction::for_log(&parsed.text),
                         "incoming ws message"
                     );
                 }
 
                 match parsed.msg_type {
                     MsgType::Register => {
-                        if let Err(err) = handle_register(parsed, &session, &tx).await {
+                        if let Err(err) =
+                            handle_register(parsed, &session, &tx, &state, &connection_id).await
+                        {
                             eprintln!("failed to send ws message: {err}");
                             break 'socket_loop;
                         }
                     }
 
-                    MsgType::Prompt => {
+                    MsgType::Prompt | MsgType::Continue => {
                         // Reset cancel
                         {
                             let s = session.lock().await;
                             s.cancel.store(false, Ordering::SeqCst);
                         }
 
+                        // Maintenance: refuse new prompts, reads stay available
+                        if let Some(window) = state.maintenance.active() {
+                            let lang = pinned_language(&state.db, &parsed.chat_id)
+                                .await
+                                .or_else(|| {
+                                    parsed.language.as_deref().and_then(language::normalize)
+                                })
+                                .or_else(|| {
+                                    language::detect_language(&parsed.text).map(str::to_string)
+                                });
+                            let mut payload = json_system("maintenance");
+                            payload["chat_id"] = serde_json::json!(parsed.chat_id);
+                            payload["message"] = serde_json::json!(window.notice(lang.as_deref()));
+                            payload["ends_ts"] = serde_json::json!(window.ends_ts);
+                            if let Err(err) = send_json(&tx, payload).await {
+                                eprintln!("failed to send ws message: {err}");
+                                break 'socket_loop;
+                            }
+                            continue;
+                        }
+
+                        // Another replica serves this chat
+                        if !parsed.chat_id.is_empty() {
+                            match state.cluster.remote_owner(&parsed.chat_id).await {
+                                Ok(None) => {}
+                                Ok(Some(node)) => {
+                                    let mut payload = json_error("wrong_node", &request_id);
+                                    payload["chat_id"] = serde_json::json!(parsed.chat_id);
+                                    payload["node"] = serde_json::json!(node.id);
+                                    payload["url"] = serde_json::json!(node.url);
+                                    if let Err(err) = send_json(&tx, payload).await {
+                                        eprintln!("failed to send ws message: {err}");
+                                        break 'socket_loop;
+                                    }
+                                    continue;
+                                }
+                                Err(err) => {
+                                    warn!(
+                                        chat_id = parsed.chat_id.as_str(),
+                                        "chat routing failed, serving locally: {err}"
+                                    );
+                                }
+                            }
+                        }
+
+                        // GPU memory too low for another reply
+                        if let Err(capacity) = state.models.gpu.admit() {
+                            state.worker.line().record_error(
+                                &parsed.request_id,
+                                &parsed.chat_id,
+                                CAPACITY_ERROR,
+                            );
+                            if let Err(err) =
+                                send_json(&tx, json_capacity(capacity, &parsed.request_id)).await
+                            {
+                                eprintln!("failed to send ws message: {err}");
+                                break 'socket_loop;
+                            }
+                            continue;
+                        }
+
+                        let profile_override = match parsed.reasoning_profile.as_deref() {
+                            None => None,
+                            Some(name) => match ReasoningProfile::parse(name) {
+                                Some(profile) => Some(profile),
+                                None => {
+                                    if let Err(err) = send_json(
+                                        &tx,
+                                        json_error("invalid_reasoning_profile", &request_id),
+                                    )
+                                    .await
+                                    {
+                                        eprintln!("failed to send ws message: {err}");
+                                        break 'socket_loop;
+                                    }
+                                    continue;
+                                }
+                            },
+                        };
+                        let seed = match parsed.seed {
+                            Some(seed) if seed > MAX_SEED => {
+                                if let Err(err) =
+                                    send_json(&tx, json_error("invalid_seed", &request_id)).await
+                                {
+                                    eprintln!("failed to send ws message: {err}");
+                                    break 'socket_loop;
+                                }
+                                continue;
+                            }
+                            Some(seed) => seed,
+                            None => random_seed(),
+                        };
+
+                        // -----------------------------------------------------
+                        // VOICE — a prompt without text but with an uploaded
+                        // recording is transcribed; the transcript is its text
+                        // -----------------------------------------------------
+                        let mut parsed = parsed;
+                        if matches!(parsed.msg_type, MsgType::Continue) {
+                            match continued_prompt(&state.db, &parsed).await {
+                                Some(text) => {
+                                    parsed.text = text;
+                                    parsed.continue_reply = true;
+                                }
+                                None => {
+                                    if let Err(err) =
+                                        send_json(&tx, json_error("not_continuable", &request_id))
+                                            .await
+                                    {
+                                        eprintln!("failed to send ws message: {err}");
+                                        break 'socket_loop;
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        if parsed.text.trim().is_empty() {
+                            match transcribe_voice_message(
+                                &state.db,
+                                &state.storage,
+                                state.transcriber.as_deref(),
+                                &parsed.device_hash,
+                                &parsed.attachments,
+                                // The client hint is often just the UI
+                                // language; let the model detect the speech.
+                                None,
+                            )
+                            .await
+                            {
+                                Ok(Some(transcript)) => {
+                                    let mut payload = json_system("transcribed");
+                                    payload["request_id"] = serde_json::json!(parsed.request_id);
+                                    payload["chat_id"] = serde_json::json!(parsed.chat_id);
+                                    payload["text"] = serde_json::json!(transcript.text);
+                                    payload["language"] = serde_json::json!(transcript.language);
+                                    if let Err(err) = send_json(&tx, payload).await {
+                                        eprintln!("failed to send ws message: {err}");
+                                        break 'socket_loop;
+                                    }
+                                    parsed.text = transcript.text;
+                                    if parsed.language.is_none() {
+                                        parsed.language = transcript.language;
+                                    }
+                                }
+                                Ok(None) => {}
+                                Err(err) => {
+                                    warn!(
+                                        request_id = parsed.request_id.as_str(),
+                                        error = ?err,
+                                        "voice message rejected"
+                                    );
+                                    if let Err(err) =
+                                        send_json(&tx, json_error(err.code(), &request_id)).await
+                                    {
+                                        eprintln!("failed to send ws message: {err}");
+                                        break 'socket_loop;
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // -----------------------------------------------------
+                        // 0) LANGUAGE — the chat keeps one language until the
+                        //    user clearly moves to another one
+                        // -----------------------------------------------------
+                        let stored_chat = state.db.load_chat(&parsed.chat_id).await.ok().flatten();
+                        // Chat owner, for their preferences, own system
+                        // prompt and personas
+                        let chat_owner = match stored_chat
+                            .as_ref()
+                            .and_then(|chat| chat.user_id.as_deref())
+                        {
+                            Some(user_id) => state.db.load_user(user_id).await.ok().flatten(),
+                            None => None,
+                        };
+                        let persona = match (&chat_owner, parsed.persona_id.as_deref()) {
+                            (Some(user), Some(persona_id)) if user.role.is_paid() => state
+                                .db
+                                .load_persona(&user.id, persona_id)
+                                .await
+                                .ok()
+                                .flatten(),
+                            _ => None,
+                        };
+                        if parsed.persona_id.is_some() && persona.is_none() {
+                            warn!(
+                                chat_id = parsed.chat_id.as_str(),
+                                persona_id = parsed.persona_id.as_deref().unwrap_or_default(),
+                                "persona not available, ignoring"
+                            );
+                        }
+                        // New chats get their id here so experiments can
+                        // bucket the first turn too
+                        let requested_chat_id = if parsed.chat_id.is_empty() {
+                            Uuid::new_v4().to_string()
+                        } else {
+                            parsed.chat_id.clone()
+                        };
+                        let experiment = if stored_chat.as_ref().is_some_and(|chat| chat.sandbox) {
+                            Assignment::default()
+                        } else {
+                            experiments::current().assign(&requested_chat_id)
+                        };
+                        let mut chat_language = stored_chat
+                            .as_ref()
+                            .map(|chat| chat.language.clone())
+                            .unwrap_or_default();
+                        let language_transition = language::observe(
+                            &mut chat_language,
+                            language::detect_language(&parsed.text),
+                            chat_owner
+                                .as_ref()
+                                .and_then(|user| user.preferences.language.as_deref())
+                                .or(parsed.language.as_deref()),
+                            language::switch_after(),
+                            chrono::Utc::now().timestamp(),
+                        );
+                        let chat_lang = chat_language.locked.clone();
+
+                        // -----------------------------------------------------
+                        // ATTACHMENTS — uploaded files are validated and read
+                        // here; client-side text is not trusted
+                        // -----------------------------------------------------
+                        let stored_attachments = match ingest_attachments(
+                            &state.db,
+                            &state.storage,
+                            &parsed.device_hash,
+                            &parsed.attachments,
+                            state.models.vision.as_deref(),
+                        )
+                        .await
+                        {
+                            Ok(attachments) => attachments,
+                            Err(err) => {
+                                warn!(
+                                    request_id = parsed.request_id.as_str(),
+                                    error = ?err,
+                                    "attachment rejected"
+                                );
+                                if let Err(err) =
+                                    send_json(&tx, json_error(err.code(), &request_id)).await
+                                {
+                                    eprintln!("failed to send ws message: {err}");
+                                    break 'socket_loop;
+                                }
+                                continue;
+                            }
+                        };
+
                         // -----------------------------------------------------
                         // 1) CLASSIFICATION — this is the only added section
                         // -----------------------------------------------------
-                        let attachment_notes = attachment_summaries(&parsed.attachments);
+                        let attachment_notes = message_attachment_summaries(&stored_attachments);
                         let classification_text = if attachment_notes.is_empty() {
                             parsed.text.clone()
                         } else {
@@ -173,39 +653,74 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                 chat_id = parsed.chat_id.as_str(),
                                 request_id = parsed.request_id.as_str(),
                                 attachments = attachment_notes.len(),
-                                summary = combined.as_str(),
+                                summary = %redaction::for_log(&combined),
                                 "attachment summary generated"
                             );
                             Some(combined)
                         };
 
-                        let stored_attachments: Vec<MessageAttachment> = parsed
-                            .attachments
-                            .iter()
-                            .map(|att| MessageAttachment {
-                                id: att.id.clone(),
-                                filename: att.filename.clone(),
-                                mime_type: att.mime_type.clone(),
-                                preview_base64: att.preview_base64.clone(),
-                                path: att.path.clone(),
-                                size: None,
-                                description: att.description.clone(),
-                                ocr_text: att.ocr_text.clone(),
-                                labels: att.labels.clone().unwrap_or_default(),
-                            })
-                            .collect();
-
-                        let routing_result = classify_with_timeout(
+                        let mut routing_result = classify_with_timeout(
                             state.models.clone(),
                             classification_text.clone(),
-                            parsed.language.clone(),
+                            chat_lang.clone(),
                         )
                         .await;
-                        let prompt_plan = prompts::build_prompt_plan(&routing_result);
-                        let rendered_system_prompt =
-                            prompts::render_prompt(&prompt_plan, parsed.language.as_deref());
+                        if let Some(profile) = profile_override {
+                            apply_reasoning_profile_override(&mut routing_result, profile);
+                        }
+                        // Safety gate: flagged prompts get the safety prompt
+                        // whatever the router picked
+                        let moderation_verdict =
+                            moderation::check(&classification_text, routing_result.safety.as_ref());
+                        if let Some(verdict) = &moderation_verdict {
+                            warn!(
+                                chat_id = parsed.chat_id.as_str(),
+                                request_id = parsed.request_id.as_str(),
+                                category = ?verdict.category,
+                                source = ?verdict.source,
+                                "prompt flagged by moderation"
+                            );
+                            moderation::apply(&mut routing_result, verdict);
+                        }
+                        let mut prompt_plan = prompts::build_prompt_plan(&routing_result);
+                        if let Some(key) = experiment.prompt_key(&prompt_plan.base_prompt) {
+                            prompt_plan.base_prompt = key;
+                        }
+                        let intent_system_prompt =
+                            prompts::render_prompt(&prompt_plan, chat_lang.as_deref());
+                        // User instructions never override the safety prompt
+                        let rendered_system_prompt = if moderation_verdict.is_some() {
+                            intent_system_prompt
+                        } else {
+                            // Preferences, then the user's own instructions,
+                            // then the persona; later ones win on conflict
+                            let user_override = chat_owner.as_ref().and_then(|user| {
+                                let parts: Vec<String> = [
+                                    user.preferences.prompt_block(),
+                                    user.meta
+                                        .as_ref()
+                                        .and_then(|meta| meta.get("system_prompt"))
+                                        .and_then(|v| v.as_str())
+                                        .map(str::to_string),
+                                    persona.as_ref().map(|p| p.system_prompt.clone()),
+                                ]
+                                .into_iter()
+                                .flatten()
+                                .collect();
+                                (!parts.is_empty()).then(|| parts.join("\n\n"))
+                            });
+                            merge_system_prompt(
+                                &intent_system_prompt,
+                                user_override.as_deref(),
+                                stored_chat
+                                    .as_ref()
+                                    .and_then(|chat| chat.system_prompt_override.as_deref()),
+                            )
+                        };
 
-                        let routing_language = routing_result.language.clone();
+                        let routing_language = chat_lang
+                            .clone()
+                            .unwrap_or_else(|| routing_result.language.clone());
 
                         let decision_chain = if routing_result.notes.is_empty() {
                             "n/a".to_string()
@@ -226,12 +741,16 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             "intent decision summary"
                         );
 
-                        let classifier_meta = build_classifier_metadata(&routing_result);
+                        let mut classifier_meta = build_classifier_metadata(&routing_result);
+                        if !experiment.is_empty() {
+                            classifier_meta["experiments"] = experiment.tags();
+                        }
 
                         // Send classifier debug meta
                         let classifier_payload = serde_json::json!({
                             "type": "classifier_debug",
                             "intent_result": routing_result.clone(),
+                            "experiments": experiment.tags(),
                         });
                         if let Err(err) = send_json(&tx, classifier_payload).await {
                             eprintln!("failed to send ws message: {err}");
@@ -241,7 +760,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         // Ensure chat exists (create if missing)
                         let chat_id = match ensure_chat_for_device(
                             &state.db,
-                            parsed.chat_id.as_str(),
+                            requested_chat_id.as_str(),
                             parsed.device_hash.as_str(),
                         )
                         .await
@@ -250,7 +769,8 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             Err(e) => {
                                 eprintln!("failed to ensure chat: {e}");
                                 if let Err(err) =
-                                    send_json(&tx, json_error("chat_init_failed")).await
+                                    send_json(&tx, json_error("chat_init_failed", &request_id))
+                                        .await
                                 {
                                     eprintln!("failed to send ws message: {err}");
                                     break 'socket_loop;
@@ -259,6 +779,71 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             }
                         };
 
+                        let sandbox = state
+                            .db
+                            .load_chat(&chat_id)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(|chat| chat.sandbox)
+                            .unwrap_or(false);
+
+                        if let Err(err) = state.db.set_chat_language(&chat_id, &chat_language).await
+                        {
+                            warn!(
+                                chat_id = chat_id.as_str(),
+                                "failed to store chat language: {err}"
+                            );
+                        }
+                        for file_id in parsed
+                            .attachments
+                            .iter()
+                            .filter_map(|a| a.file_id.as_deref())
+                        {
+                            if let Err(err) = state.db.link_upload_to_chat(file_id, &chat_id).await
+                            {
+                                warn!(file_id, "failed to link upload to chat: {err}");
+                            }
+                        }
+                        let language_event = match &language_transition {
+                            LanguageTransition::Suggested { from, to }
+                                if chat_language.pending_count == 1 =>
+                            {
+                                Some(("language_switch_suggested", from, to))
+                            }
+                            LanguageTransition::Switched { from, to } => {
+                                Some(("language_switched", from, to))
+                            }
+                            _ => None,
+                        };
+                        if let Some((event, from, to)) = language_event {
+                            if let Err(err) = send_json(
+                                &tx,
+                                serde_json::json!({
+                                    "type": "system",
+                                    "event": event,
+                                    "chat_id": chat_id,
+                                    "from": from,
+                                    "to": to
+                                }),
+                            )
+                            .await
+                            {
+                                eprintln!("failed to send ws message: {err}");
+                                break 'socket_loop;
+                            }
+                        }
+
+                        if !sandbox {
+                            let usage_counter = format!(
+                                "prompt_usage:{}:{}",
+                                routing_language, prompt_plan.base_prompt
+                            );
+                            if let Err(err) = state.db.incr_counter(&usage_counter, 1).await {
+                                debug!("failed to count prompt usage: {err}");
+                            }
+                        }
+
                         // Inform client if a new chat id was created
                         if chat_id != parsed.chat_id {
                             if let Err(err) = send_json(
@@ -320,45 +905,216 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             language: Some(routing_language.clone()),
                             attachments: stored_attachments.clone(),
                             liked: false,
+                            feedback: None,
                             ts: chrono::Utc::now().timestamp(),
                             meta: Some(classifier_meta),
                         };
 
-                        if matches!(history.last().map(|m| m.role.as_str()), Some("user")) {
-                            if let Some(removed) = history.pop() {
-                                if let Err(err) =
-                                    state.db.delete_message(&chat_id, &removed.id).await
-                                {
-                                    warn!(
-                                        chat_id = chat_id.as_str(),
-                                        message_id = removed.id.as_str(),
-                                        "failed to delete duplicate user message: {err}"
-                                    );
+                        // Asking the same thing again counts as a
+                        // regeneration of the previous reply
+                        let regeneration = history
+                            .iter()
+                            .rev()
+                            .find(|m| m.role == "user")
+                            .and_then(|m| m.text.as_deref())
+                            .is_some_and(|prev| prev.trim() == user_text.trim());
+
+                        // A regeneration with `continue` picks a cut-short
+                        // reply up where it stopped; the reply leaves the
+                        // history and its stored prompt is answered again
+                        let continues = match history.as_slice() {
+                            [.., prompt, reply]
+                                if parsed.continue_reply
+                                    && regeneration
+                                    && prompt.role == "user"
+                                    && reply.continuable() =>
+                            {
+                                history.pop()
+                            }
+                            _ => None,
+                        };
+
+                        if continues.is_none() {
+                            if matches!(history.last().map(|m| m.role.as_str()), Some("user")) {
+                                if let Some(removed) = history.pop() {
+                                    if let Err(err) =
+                                        state.db.delete_message(&chat_id, &removed.id).await
+                                    {
+                                        warn!(
+                                            chat_id = chat_id.as_str(),
+                                            message_id = removed.id.as_str(),
+                                            "failed to delete duplicate user message: {err}"
+                                        );
+                                    }
                                 }
                             }
+                            history.push(user_msg.clone());
                         }
 
-                        history.push(user_msg.clone());
+                        // Trim long histories; the long summary stands in
+                        // for what was cut
+                        history = compact_history(history, HISTORY_WINDOW);
 
-                        // Trim long histories
-                        history = trim_history(history, 24);
-
-                        // Build chat prompt
-                        let base_prompt =
-                            build_mistral_prompt(&history, Some(&rendered_system_prompt));
+                        // Build chat prompt; a continued reply is left
+                        // open for the model to finish. Older turns go
+                        // when it does not fit the context
+                        let continued_text = continues
+                            .as_ref()
+                            .and_then(|m| m.text.as_deref())
+                            .unwrap_or_default();
+                        let fitted = fit_context(&state.infer, &mut history, |history| {
+                            build_mistral_prompt(history, Some(&rendered_system_prompt))
+                                + continued_text
+                        })
+                        .await;
+                        let base_prompt = match fitted {
+                            Ok(prompt) => prompt,
+                            Err(overflow) => {
+                                let mut payload = json_error("context_overflow", &request_id);
+                                payload["limit"] = serde_json::json!(overflow.limit);
+                                payload["context_length"] =
+                                    serde_json::json!(overflow.context_length);
+                                payload["prompt_tokens"] =
+                                    serde_json::json!(overflow.prompt_tokens);
+                                if let Err(err) = send_json(&tx, payload).await {
+                                    eprintln!("failed to send ws message: {err}");
+                                    break 'socket_loop;
+                                }
+                                continue;
+                            }
+                        };
                         info!(
                             chat_id = parsed.chat_id.as_str(),
                             session_id = parsed.session_id.as_str(),
-                            prompt = rendered_system_prompt.as_str(),
+                            prompt = %redaction::for_log(&rendered_system_prompt),
                             "rendered system prompt"
                         );
 
-                        // Save user message
-                        if let Err(err) = state.db.save_message(&user_msg).await {
-                            eprintln!("failed to save user message {}: {err}", user_msg.id);
+                        // Save user message with the chat touch
+                        let saved = continues.is_none().then_some(&user_msg);
+                        if let Err(err) =
+                            touch_chat(&state.db, &chat_id, Some(parsed.device_hash.clone()), saved)
+                                .await
+                        {
+                            if saved.is_some() {
+                                eprintln!("failed to save user message {}: {err}", user_msg.id);
+                            }
+                        }
+                        if !sandbox && !experiment.is_empty() {
+                            experiments::record(&state.db, &experiment.arms, Outcome::Prompt, 1)
+                                .await;
+                            if regeneration && continues.is_none() {
+                                experiments::record(
+                                    &state.db,
+                                    &experiment.arms,
+                                    Outcome::Regeneration,
+                                    1,
+                                )
+                                .await;
+                            }
+                        }
+
+                        // Keep the routing decision for misroute feedback
+                        if !sandbox && continues.is_none() {
+                            let record = RoutingRecord {
+                                message_id: user_msg.id.clone(),
+                                chat_id: chat_id.clone(),
+                                text: classification_text,
+                                language: routing_language.clone(),
+                                prompt_key: routing_result.prompt_key.clone(),
+                                result: serde_json::to_value(&routing_result).unwrap_or_default(),
+                                ts: user_msg.ts,
+                                feedback: None,
+                            };
+                            if let Err(err) = state.db.save_routing_record(&record).await {
+                                debug!("failed to store routing record: {err}");
+                            }
+                        }
+
+                        // Flagged prompts never reach tools; continued
+                        // replies skip them, they only rebuild the prompt
+                        let tool_session = if moderation_verdict.is_none() && continues.is_none() {
+                            ToolSession::new(
+                                &parsed.tools,
+                                &history,
+                                &rendered_system_prompt,
+                                &chat_id,
+                                state.models.clone(),
+                                state.vectors.clone(),
+                                state.web_search.clone(),
+                            )
+                        } else {
+                            None
+                        };
+                        let web_search = state
+                            .web_search
+                            .clone()
+                            .filter(|_| {
+                                routing_result.recency_sensitive
+                                    && moderation_verdict.is_none()
+                                    && continues.is_none()
+                            })
+                            .map(|provider| {
+                                WebSearch::new(
+                                    provider,
+                                    &user_text,
+                                    &routing_language,
+                                    &chat_id,
+                                    &history,
+                                    &rendered_system_prompt,
+                                )
+                            });
+
+                        if let Some(verdict) = moderation_verdict {
+                            if let Err(err) = send_json(
+                                &tx,
+                                serde_json::json!({
+                                    "type": "moderation",
+                                    "chat_id": chat_id,
+                                    "request_id": user_msg.id,
+                                    "category": verdict.category,
+                                }),
+                            )
+                            .await
+                            {
+                                eprintln!("failed to send ws message: {err}");
+                                break 'socket_loop;
+                            }
+                            let record = ModerationRecord {
+                                message_id: user_msg.id.clone(),
+                                chat_id: chat_id.clone(),
+                                device_hash: Some(parsed.device_hash.clone())
+                                    .filter(|d| !d.is_empty()),
+                                text: user_text.clone(),
+                                language: routing_language.clone(),
+                                verdict,
+                                ts: user_msg.ts,
+                            };
+                            if let Err(err) = state.db.save_moderation_record(&record).await {
+                                warn!("failed to store moderation record: {err}");
+                            }
+                            if !sandbox {
+                                let user_id = match &chat_owner {
+                                    Some(user) => Some(user.id.clone()),
+                                    None => state
+                                        .db
+                                        .find_user_id_by_device(&parsed.device_hash)
+                                        .await
+                                        .unwrap_or_default(),
+                                };
+                                state
+                                    .events
+                                    .publish(Event::ModerationFlagged(ModerationFlagged {
+                                        message_id: record.message_id,
+                                        chat_id: record.chat_id,
+                                        user_id,
+                                        device_hash: record.device_hash,
+                                        category: record.verdict.category,
+                                        source: record.verdict.source,
+                                        ts: record.ts,
+                                    }));
+                            }
                         }
-                        let _ =
-                            touch_chat(&state.db, &chat_id, Some(parsed.device_hash.clone())).await;
 
                         // Share cancel flag
                         let cancel_flag = {
@@ -373,25 +1129,161 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             prompt: prompt_for_model,
                             chat_id: chat_id.clone(),
                             session_id: parsed.session_id.clone(),
+                            request_id: user_msg.id.clone(),
+                            device_hash: Some(parsed.device_hash.clone()).filter(|d| !d.is_empty()),
+                            prompt_key: Some(routing_result.prompt_key.clone()),
+                            language: Some(routing_language.clone()),
+                            web_search,
+                            tools: tool_session,
+                            analysis: routing_result
+                                .reasoning_profile
+                                .filter(|_| experiment.reasoning_enabled() && continues.is_none())
+                                .and_then(|profile| {
+                                    ReasoningMode::for_profile(profile).hidden_analysis(
+                                        profile,
+                                        &routing_language,
+                                        &history,
+                                        &rendered_system_prompt,
+                                    )
+                                }),
+                            generation: {
+                                let limits = rules::current().generation_limits(
+                                    &routing_result.prompt_key,
+                                    routing_result.reasoning_profile,
+                                );
+                                GenerationParams {
+                                    temperature: persona
+                                        .as_ref()
+                                        .and_then(|p| p.temperature)
+                                        .or(experiment.temperature()),
+                                    max_tokens: limits.max_tokens,
+                                    stop: stop_sequences(limits.stop),
+                                    grammar: None,
+                                    logit_bias: Vec::new(),
+                                    seed: Some(seed),
+                                    ..GenerationParams::default()
+                                }
+                            },
+                            continues,
+                            timeout: TimeoutPolicy::current()
+                                .chat_reply(chat_owner.as_ref().map(|user| &user.role)),
+                            experiments: experiment.arms.clone(),
                             sender: tx.clone(),
                             infer: state.infer.clone(),
+                            embedder: state.models.embedder(None).map(|(_, e)| e),
                             db: state.db.clone(),
                             cancel: cancel_flag,
+                            sandbox,
+                            events: state.events.clone(),
+                            connection_id: connection_id.clone(),
+                            connections: state.connections.clone(),
+                            resume: state.resume.clone(),
+                            chats: state.chats.clone(),
+                            enqueued_at: std::time::Instant::now(),
                         };
 
-                        if !state.worker.try_enqueue(job) {
+                        let reply_id = job.request_id.clone();
+                        if state.worker.waits_for_room() {
+                            // Keeps reading the socket, e.g. for `cancel`,
+                            // while the job waits for room.
+                            let worker = state.worker.clone();
+                            let tx = tx.clone();
+                            let request_id = request_id.clone();
+                            let chat_id = chat_id.clone();
+                            tokio::spawn(async move {
+                                if !worker.enqueue_or_wait(job).await {
+                                    eprintln!("inference worker busy, gave up waiting");
+                                    worker
+                                        .line()
+                                        .record_error(&reply_id, &chat_id, "server_busy");
+                                    let _ = send_json(&tx, json_error("server_busy", &request_id))
+                                        .await;
+                                }
+                            });
+                        } else if !state.worker.try_enqueue(job) {
                             eprintln!("inference worker busy, rejecting request");
-                            let _ = send_json(&tx, json_error("server_busy")).await;
+                            state
+                                .worker
+                                .line()
+                                .record_error(&reply_id, &chat_id, "server_busy");
+                            let _ = send_json(&tx, json_error("server_busy", &request_id)).await;
                             continue;
                         }
                     }
 
+                    MsgType::SetLanguage => {
+                        let payload = match handle_set_language(&parsed, &state).await {
+                            Ok(payload) => payload,
+                            Err(err) => json_error(&err.to_string(), &request_id),
+                        };
+                        if let Err(err) = send_json(&tx, payload).await {
+                            eprintln!("failed to send ws message: {err}");
+                            break 'socket_loop;
+                        }
+                    }
+
+                    MsgType::Resume => {
+                        if let Err(err) = handle_resume(
+                            &parsed,
+                            &session,
+                            &tx,
+                            &state,
+                            &connection_id,
+                            &request_id,
+                        )
+                        .await
+                        {
+                            eprintln!("failed to send ws message: {err}");
+                            break 'socket_loop;
+                        }
+                    }
+
+                    MsgType::Sync => {
+                        let payload = handle_sync(&parsed, &state, &request_id);
+                        if let Err(err) = send_json(&tx, payload).await {
+                            eprintln!("failed to send ws message: {err}");
+                            break 'socket_loop;
+                        }
+                    }
+
+                    MsgType::ImagePrompt => {
+                        // Generation takes seconds to minutes; keep reading
+                        // the socket meanwhile
+                        let state = state.clone();
+                        let tx = tx.clone();
+                        let connection_id = connection_id.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) =
+                                handle_image_prompt(parsed, &state, &tx, &connection_id).await
+                            {
+                                eprintln!("failed to send ws message: {err}");
+                            }
+                        });
+                    }
+
                     MsgType::Cancel => {
                         // Actually set cancel flag!
                         {
                             let s = session.lock().await;
                             s.cancel.store(true, Ordering::SeqCst);
                         }
+                        // A reply named by id that this socket is not
+                        // attached to, here or on another node.
+                        if !parsed.request_id.is_empty() {
+                            match reply_owner(&parsed, &state) {
+                                Some(owner) => owner.cancel.store(true, Ordering::SeqCst),
+                                None => {
+                                    state
+                                        .cluster
+                                        .relay_cancel(
+                                            &parsed.request_id,
+                                            &parsed.chat_id,
+                                            &parsed.device_hash,
+                                        )
+                                        .await;
+                                }
+                            }
+                        }
                         if let Err(err) = send_json(&tx, json_system("cancel_ack")).await {
                             eprintln!("failed to send ws message: {err}");
                             break 'socket_loop;
@@ -407,15 +1299,249 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
         };
     }
 
-    // Socket closed → set cancel flag
+    state.connections.unregister(&connection_id);
+
+    // Socket closed → set cancel flag; queued jobs share it and are skipped.
+    // A reply the worker picked up is detached instead and keeps generating
+    // for a `resume`; jobs still queued then see the closed channel.
     {
-        let s = session.lock().await;
-        s.cancel.store(true, Ordering::SeqCst);
+        let mut s = session.lock().await;
+        if !state.resume.detach(&connection_id, &s.cancel) {
+            s.cancel.store(true, Ordering::SeqCst);
+        }
+        if let Some(feed) = s.chat_feed.take() {
+            feed.abort();
+        }
     }
 
-    // Drop sender to stop writer task
+    // Drop sender to stop writer task. Queued jobs hold their own senders,
+    // so stop waiting after a short drain; aborting the writer closes the
+    // channel and jobs already running see a disconnect.
     drop(tx);
-    let _ = writer.await;
+    if timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
+    }
+}
+
+// ------------------------------------------------------------
+// RESUME / SYNC HANDLERS
+// ------------------------------------------------------------
+/// Owner of the buffered reply `msg.request_id`, if it is the chat and
+/// device of `msg`.
+fn reply_owner(msg: &PromptMsg, state: &AppState) -> Option<ResumeOwner> {
+    state
+        .resume
+        .owner(&msg.request_id)
+        .filter(|owner| owner.matches(&msg.chat_id, &msg.device_hash))
+}
+
+/// Text of the prompt answered by reply `msg.message_id`, when that reply
+/// is the latest message of the chat and can be continued.
+async fn continued_prompt(db: &DBLayer, msg: &PromptMsg) -> Option<String> {
+    let history = db.list_messages_for_chat(&msg.chat_id).await.ok()?;
+    match history.as_slice() {
+        [.., prompt, reply]
+            if prompt.role == "user"
+                && Some(reply.id.as_str()) == msg.message_id.as_deref()
+                && reply.continuable() =>
+        {
+            prompt.text.clone()
+        }
+        _ => None,
+    }
+}
+
+/// Text of reply `msg.request_id` so far. Token frames with a `seq` above
+/// the returned one continue it.
+fn handle_sync(msg: &PromptMsg, state: &AppState, request_id: &str) -> serde_json::Value {
+    let snapshot = reply_owner(msg, state).and_then(|_| state.resume.snapshot(&msg.request_id));
+    let Some(snapshot) = snapshot else {
+        return json_error("sync_not_found", request_id);
+    };
+    serde_json::json!({
+        "type": "sync",
+        "request_id": msg.request_id,
+        "chat_id": msg.chat_id,
+        "text": snapshot.text,
+        "seq": snapshot.seq,
+        "done": snapshot.done,
+    })
+}
+
+/// Replay the buffered frames of `msg.request_id`, then attach this socket
+/// to the live tail. The reply must belong to the same chat and device.
+async fn handle_resume(
+    msg: &PromptMsg,
+    session: &Arc<Mutex<WsSession>>,
+    tx: &mpsc::Sender<WsMessage>,
+    state: &AppState,
+    connection_id: &str,
+    request_id: &str,
+) -> anyhow::Result<()> {
+    let Some(owner) = reply_owner(msg, state) else {
+        return send_json(tx, json_error("resume_not_found", request_id)).await;
+    };
+
+    // `cancel` on this socket now stops the resumed reply.
+    session.lock().await.cancel = owner.cancel;
+
+    let mut resumed = json_system("resumed");
+    resumed["request_id"] = serde_json::json!(msg.request_id);
+    resumed["chat_id"] = serde_json::json!(msg.chat_id);
+    send_json(tx, resumed).await?;
+
+    let mut seen = 0;
+    while let Some(CatchUp::Frames(frames)) =
+        state
+            .resume
+            .catch_up(&msg.request_id, seen, connection_id, tx)
+    {
+        seen += frames.len();
+        for frame in frames {
+            tx.send(WsMessage::Text(frame.into()))
+                .await
+                .map_err(|_| anyhow!("ws channel closed"))?;
+        }
+    }
+    Ok(())
+}
+
+// ------------------------------------------------------------
+// IMAGE PROMPT HANDLER
+// ------------------------------------------------------------
+/// Generate an image for `msg.text`, store the prompt and the image as a
+/// user/assistant pair in the chat, and send the `image` frame to this
+/// socket and the chat's other sockets.
+async fn handle_image_prompt(
+    msg: PromptMsg,
+    state: &AppState,
+    tx: &mpsc::Sender<WsMessage>,
+    connection_id: &str,
+) -> anyhow::Result<()> {
+    let request_id = msg.request_id.clone();
+    if let Some(window) = state.maintenance.active() {
+        let lang = pinned_language(&state.db, &msg.chat_id)
+            .await
+            .or_else(|| msg.language.as_deref().and_then(language::normalize));
+        let mut payload = json_system("maintenance");
+        payload["chat_id"] = serde_json::json!(msg.chat_id);
+        payload["message"] = serde_json::json!(window.notice(lang.as_deref()));
+        payload["ends_ts"] = serde_json::json!(window.ends_ts);
+        return send_json(tx, payload).await;
+    }
+    if let Err(capacity) = state.models.gpu.admit() {
+        return send_json(tx, json_capacity(capacity, &request_id)).await;
+    }
+
+    let chat_id = match ensure_chat_for_device(&state.db, &msg.chat_id, &msg.device_hash).await {
+        Ok(chat_id) => chat_id,
+        Err(err) => {
+            eprintln!("failed to ensure chat: {err}");
+            return send_json(tx, json_error("chat_init_failed", &request_id)).await;
+        }
+    };
+    if chat_id != msg.chat_id {
+        let mut payload = json_system("chat_created");
+        payload["chat_id"] = serde_json::json!(chat_id);
+        send_json(tx, payload).await?;
+    }
+
+    let mut generating = json_system("image_generating");
+    generating["request_id"] = serde_json::json!(request_id);
+    generating["chat_id"] = serde_json::json!(chat_id);
+    send_json(tx, generating).await?;
+
+    let request = ImageGenerateRequest {
+        prompt: msg.text.clone(),
+        negative_prompt: None,
+        n: None,
+        seed: msg.seed.map(u64::from),
+    };
+    let images = match images::generate_images(
+        &state.models,
+        &state.storage,
+        &state.db,
+        &msg.device_hash,
+        &request,
+    )
+    .await
+    {
+        Ok(images) => images,
+        Err(err) => {
+            warn!(
+                request_id = request_id.as_str(),
+                error = ?err,
+                "image prompt failed"
+            );
+            return send_json(tx, json_error(err.code(), &request_id)).await;
+        }
+    };
+
+    let lang = pinned_language(&state.db, &chat_id)
+        .await
+        .or_else(|| msg.language.clone());
+    let now = chrono::Utc::now().timestamp();
+    let user_msg = Message {
+        id: if request_id.is_empty() {
+            Uuid::new_v4().to_string()
+        } else {
+            request_id.clone()
+        },
+        chat_id: chat_id.clone(),
+        session_id: Some(msg.session_id.clone()),
+        user_id: None,
+        device_hash: Some(msg.device_hash.clone()),
+        role: "user".into(),
+        text: Some(msg.text.clone()),
+        language: lang,
+        attachments: Vec::new(),
+        liked: false,
+        feedback: None,
+        ts: now,
+        meta: Some(serde_json::json!({ "image_prompt": true })),
+    };
+    let assistant_msg = Message {
+        id: Uuid::new_v4().to_string(),
+        chat_id: chat_id.clone(),
+        session_id: Some(msg.session_id.clone()),
+        user_id: None,
+        device_hash: None,
+        role: "assistant".into(),
+        text: None,
+        language: None,
+        attachments: images.iter().map(|image| image.attachment()).collect(),
+        liked: false,
+        feedback: None,
+        ts: now,
+        meta: Some(serde_json::json!({
+            "image_seeds": images.iter().map(|image| image.seed).collect::<Vec<_>>(),
+        })),
+    };
+    if let Err(err) = state.db.save_message(&user_msg).await {
+        eprintln!("failed to save message {}: {err}", user_msg.id);
+    }
+    if let Err(err) = touch_chat(
+        &state.db,
+        &chat_id,
+        Some(msg.device_hash.clone()),
+        Some(&assistant_msg),
+    )
+    .await
+    {
+        eprintln!("failed to save message {}: {err}", assistant_msg.id);
+    }
+
+    let frame = serde_json::json!({
+        "type": "image",
+        "request_id": request_id,
+        "chat_id": chat_id,
+        "message_id": assistant_msg.id,
+        "images": images.iter().map(|image| image.public()).collect::<Vec<_>>(),
+    });
+    state
+        .chats
+        .publish(&chat_id, Some(connection_id), &frame.to_string());
+    send_json(tx, frame).await
 }
 
 // ------------------------------------------------------------
@@ -425,9 +1551,33 @@ async fn handle_register(
     msg: PromptMsg,
     session: &Arc<Mutex<WsSession>>,
     sender: &mpsc::Sender<WsMessage>,
+    state: &AppState,
+    connection_id: &str,
 ) -> anyhow::Result<()> {
+    let user_id = state
+        .db
+        .find_user_id_by_device(&msg.device_hash)
+        .await
+        .unwrap_or_default();
+    if !may_follow_chat(state, &msg.chat_id, &msg.device_hash, user_id.as_deref()).await {
+        return send_json(sender, json_error("chat_not_found", &msg.request_id)).await;
+    }
+    state.connections.register(
+        connection_id,
+        ConnectionRegistry::owner_key(user_id.as_deref(), &msg.device_hash),
+        sender.clone(),
+        msg.live_preview,
+    );
+
     let mut s = session.lock().await;
 
+    let feed = state
+        .chats
+        .subscribe(&msg.chat_id, connection_id, sender.clone());
+    if let Some(previous) = s.chat_feed.replace(feed) {
+        previous.abort();
+    }
+
     s.device_hash = Some(msg.device_hash);
     s.session_id = Some(msg.session_id);
     s.chat_id = Some(msg.chat_id);
@@ -447,6 +1597,70 @@ async fn handle_register(
     Ok(())
 }
 
+/// Whether a socket of `device_hash` (linked to `user_id`) may receive the
+/// frames of `chat_id`: the chat is new, or it belongs to that device or
+/// user. A chat that cannot be read is refused.
+async fn may_follow_chat(
+    state: &AppState,
+    chat_id: &str,
+    device_hash: &str,
+    user_id: Option<&str>,
+) -> bool {
+    match state.db.load_chat(chat_id).await {
+        Ok(Some(chat)) => {
+            chat.device_hash.as_deref() == Some(device_hash)
+                || (user_id.is_some() && chat.user_id.as_deref() == user_id)
+        }
+        Ok(None) => true,
+        Err(err) => {
+            warn!(chat_id, error = ?err, "failed to load chat for register");
+            false
+        }
+    }
+}
+
+/// Language the chat is locked to, which wins over the per-message hint.
+async fn pinned_language(db: &DBLayer, chat_id: &str) -> Option<String> {
+    if chat_id.is_empty() {
+        return None;
+    }
+    db.load_chat(chat_id)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|chat| chat.language.locked)
+}
+
+async fn handle_set_language(
+    msg: &PromptMsg,
+    state: &AppState,
+) -> anyhow::Result<serde_json::Value> {
+    let lang = msg
+        .language
+        .as_deref()
+        .and_then(language::normalize)
+        .ok_or_else(|| anyhow!("missing_language"))?;
+    let mut chat = state
+        .db
+        .load_chat(&msg.chat_id)
+        .await?
+        .ok_or_else(|| anyhow!("chat_not_found"))?;
+
+    language::pin(
+        &mut chat.language,
+        Some(lang.clone()),
+        chrono::Utc::now().timestamp(),
+    );
+    state.db.save_chat(&chat).await?;
+
+    Ok(serde_json::json!({
+        "type": "system",
+        "event": "language_locked",
+        "chat_id": chat.id,
+        "language": lang
+    }))
+}
+
 async fn classify_with_timeout(
     models: Arc<ModelManager>,
     text: String,
@@ -516,13 +1730,22 @@ async fn send_json(
     }
 }
 
-fn json_error(msg: &str) -> serde_json::Value {
+fn json_error(msg: &str, request_id: &str) -> serde_json::Value {
     serde_json::json!({
         "type": "error",
-        "message": msg
+        "message": msg,
+        "request_id": request_id
     })
 }
 
+/// `capacity` error with the free GPU memory, so clients can retry later.
+fn json_capacity(capacity: CapacityError, request_id: &str) -> serde_json::Value {
+    let mut payload = json_error(CAPACITY_ERROR, request_id);
+    payload["free_mib"] = serde_json::json!(capacity.free_mib);
+    payload["total_mib"] = serde_json::json!(capacity.total_mib);
+    payload
+}
+
 fn json_system(event: &str) -> serde_json::Value {
     serde_json::json!({
         "type": "system",
@@ -533,10 +1756,13 @@ fn json_system(event: &str) -> serde_json::Value {
 // ------------------------------------------------------------
 // STREAMING INFERENCE HELPERS
 // ------------------------------------------------------------
+/// Move the chat's `updated_ts` up, creating the chat if needed. A
+/// `message` is saved in the same write as the chat.
 pub(crate) async fn touch_chat(
     db: &DBLayer,
     chat_id: &str,
     device_hash: Option<String>,
+    message: Option<&Message>,
 ) -> anyhow::Result<bool> {
     // ---------------------------------------------------------
     // 1. Load chat or initialize new
@@ -548,6 +1774,12 @@ pub(crate) async fn touch_chat(
         device_hash: device_hash.clone(),
         updated_ts: chrono::Utc::now().timestamp(),
         meta: Some(serde_json::json!({})),
+        sandbox: false,
+        language: Default::default(),
+        system_prompt_override: None,
+        folder: None,
+        pinned: false,
+        archived: false,
     });
 
     // Ensure meta exists
@@ -596,7 +1828,10 @@ pub(crate) async fn touch_chat(
     // 4. Update timestamp + save chat
     // ---------------------------------------------------------
     chat.updated_ts = chrono::Utc::now().timestamp();
-    db.save_chat(&chat).await?;
+    match message {
+        Some(message) => db.save_message_with_chat(message, &chat).await?,
+        None => db.save_chat(&chat).await?,
+    }
 
     Ok(has_summary)
 }
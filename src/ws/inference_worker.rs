@@ -1,39 +1,141 @@
 use axum::extract::ws::Message as WsMessage;
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tracing::{debug, info};
 use uuid::Uuid;
 
+use crate::agent::chat_tools::ToolSession;
 use crate::conversation::{
-    build_mistral_prompt, strip_chatml_markers, trim_history, trim_partial_chatml,
+    build_mistral_prompt, compaction, postprocess, strip_chatml_markers, summary_drift,
+    trim_history, trim_partial_chatml,
 };
 use crate::db::DBLayer;
-use crate::inference::{byte_decoder::tidy_decoded_text, InferenceService};
+use crate::events::{AssistantMessageFinalized, Event, EventBus, FinishReason, SummaryCreated};
+use crate::experiments::{self, Arm, Outcome};
+use crate::inference::{
+    intent_router::RobertaIntentRouter,
+    llama_cpp_service::{GenerationParams, ENGINE_ERROR_PREFIX},
+    reasoning::HiddenAnalysis,
+    timeouts, InferenceService,
+};
 use crate::model::message::Message;
+use crate::prompts;
+use crate::tools::web_search::WebSearch;
 
+use super::broadcast::ChatBroadcast;
 use super::handler::touch_chat;
+use super::queue::{self, WaitLine};
+use super::registry::ConnectionRegistry;
+use super::resume::{self, Attached, ResumeRegistry};
+
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(1500);
+const PREVIEW_CHARS: usize = 100;
+/// Token deltas for a backed-up socket are batched into frames this far
+/// apart.
+const COALESCE_INTERVAL: Duration = Duration::from_millis(50);
+/// Recent waits kept for `QueueStats`.
+const WAIT_SAMPLES: usize = 50;
 
 pub struct InferenceJob {
     pub prompt: String,
     pub chat_id: String,
     pub session_id: String,
+    pub request_id: String,
+    pub device_hash: Option<String>,
+    pub prompt_key: Option<String>,
+    /// Language of the chat; the summary is written in it.
+    pub language: Option<String>,
+    /// Web search to run first; its summary goes into the system prompt of
+    /// the reply, the tool turn and the analysis.
+    pub web_search: Option<WebSearch>,
+    /// Tool calls to run first; `prompt` is then rebuilt around their
+    /// results and `analysis` is skipped.
+    pub tools: Option<ToolSession>,
+    /// Analysis to run first; `prompt` is then rebuilt around its result.
+    pub analysis: Option<HiddenAnalysis>,
+    /// Temperature, token cap and stop strings of the reply.
+    pub generation: GenerationParams,
+    /// Cut-short reply this job finishes: `prompt` ends with its text and
+    /// the continuation is appended to the same stored message.
+    pub continues: Option<Message>,
+    /// Wall-clock cap on generating the reply.
+    pub timeout: Option<Duration>,
+    /// Experiment arms of the chat; tagged on the reply and counted.
+    pub experiments: Vec<Arm>,
     pub sender: mpsc::Sender<WsMessage>,
     pub infer: Arc<InferenceService>,
+    /// Encoder for the summary drift check; skipped without one.
+    pub embedder: Option<Arc<RobertaIntentRouter>>,
     pub db: Arc<DBLayer>,
     pub cancel: Arc<AtomicBool>,
+    /// Sandbox chats skip summaries and integration events.
+    pub sandbox: bool,
+    pub events: EventBus,
+    pub connection_id: String,
+    pub connections: ConnectionRegistry,
+    /// Buffers the reply under `request_id` so a reconnecting client can
+    /// resume it.
+    pub resume: ResumeRegistry,
+    /// Fans the reply out to the chat's other sockets.
+    pub chats: ChatBroadcast,
+    pub enqueued_at: Instant,
+}
+
+/// Queue depth and how long recent jobs waited for their first token,
+/// which includes waiting for a free llama context.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct QueueStats {
+    pub depth: usize,
+    /// Started replies still waiting for their first token.
+    pub waiting: usize,
+    pub capacity: usize,
+    pub avg_wait_ms: u64,
+    pub max_wait_ms: u64,
 }
 
 #[derive(Clone)]
 pub struct InferenceWorker {
     tx: mpsc::Sender<InferenceJob>,
+    waits: Arc<Mutex<VecDeque<u64>>>,
+    line: WaitLine,
+    /// How long a job may wait for room in a full queue; `None` rejects it
+    /// at once.
+    enqueue_wait: Option<Duration>,
 }
 
 impl InferenceWorker {
+    /// A full queue rejects jobs unless `QUEUE_WAIT_SECS` (default 0) lets
+    /// them wait that long for room.
     pub fn new(queue_size: usize) -> Self {
         let (tx, rx) = mpsc::channel(queue_size);
-        tokio::spawn(worker_loop(rx));
-        Self { tx }
+        let waits = Arc::new(Mutex::new(VecDeque::with_capacity(WAIT_SAMPLES)));
+        let line = WaitLine::default();
+        tokio::spawn(worker_loop(rx, waits.clone(), line.clone()));
+        let enqueue_wait = std::env::var("QUEUE_WAIT_SECS")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs);
+        Self {
+            tx,
+            waits,
+            line,
+            enqueue_wait,
+        }
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        let waits = self.waits.lock().expect("queue stats lock poisoned");
+        QueueStats {
+            depth: self.tx.max_capacity() - self.tx.capacity(),
+            waiting: self.line.waiting(),
+            capacity: self.tx.max_capacity(),
+            avg_wait_ms: waits.iter().sum::<u64>() / waits.len().max(1) as u64,
+            max_wait_ms: waits.iter().copied().max().unwrap_or(0),
+        }
     }
 
     pub fn try_enqueue(&self, job: InferenceJob) -> bool {
@@ -50,6 +152,43 @@ impl InferenceWorker {
     ) -> Result<(), mpsc::error::SendError<InferenceJob>> {
         self.tx.send(job).await
     }
+
+    /// [`try_enqueue`](Self::try_enqueue), except that with
+    /// `QUEUE_WAIT_SECS` set a full queue sends the client a `queued`
+    /// frame and holds the job until there is room or the wait is over.
+    pub async fn enqueue_or_wait(&self, job: InferenceJob) -> bool {
+        let job = match self.tx.try_send(job) {
+            Ok(()) => return true,
+            Err(mpsc::error::TrySendError::Full(job)) => job,
+            Err(mpsc::error::TrySendError::Closed(_)) => return false,
+        };
+        let Some(wait) = self.enqueue_wait else {
+            return false;
+        };
+        // Held jobs stand in the line too, so concurrent waiters get their
+        // own places and the admin feed counts them; the ticket leaves the
+        // line once the job is sent or the wait is over.
+        let ticket = self.line.join(&job.request_id, &job.chat_id);
+        let position = self.tx.max_capacity() + ticket.position().unwrap_or(1);
+        let frame = queue::queued_frame(&job.request_id, position, self.line.eta(position));
+        let _ = job.sender.send(WsMessage::Text(frame.into())).await;
+        let sent = matches!(
+            tokio::time::timeout(wait, self.tx.send(job)).await,
+            Ok(Ok(()))
+        );
+        drop(ticket);
+        sent
+    }
+
+    /// Replies waiting or streaming, and the last failures.
+    pub fn line(&self) -> &WaitLine {
+        &self.line
+    }
+
+    /// Whether [`enqueue_or_wait`](Self::enqueue_or_wait) may hold a job.
+    pub fn waits_for_room(&self) -> bool {
+        self.enqueue_wait.is_some()
+    }
 }
 
 fn should_generate_summary(history: &[Message]) -> bool {
@@ -63,68 +202,229 @@ fn should_generate_summary(history: &[Message]) -> bool {
     user_count > 0 && assistant_count >= 1
 }
 
-async fn worker_loop(mut rx: mpsc::Receiver<InferenceJob>) {
+async fn worker_loop(
+    mut rx: mpsc::Receiver<InferenceJob>,
+    waits: Arc<Mutex<VecDeque<u64>>>,
+    line: WaitLine,
+) {
     while let Some(job) = rx.recv().await {
-        tokio::spawn(process_job(job));
+        tokio::spawn(process_job(job, waits.clone(), line.clone()));
     }
 }
-async fn process_job(job: InferenceJob) {
-    if job.cancel.load(Ordering::SeqCst) {
-        return;
+
+fn record_wait(waits: &Mutex<VecDeque<u64>>, wait: Duration) {
+    let mut waits = waits.lock().expect("queue stats lock poisoned");
+    if waits.len() == WAIT_SAMPLES {
+        waits.pop_front();
     }
+    waits.push_back(wait.as_millis() as u64);
+}
 
-    if job.sender.is_closed() {
+async fn process_job(mut job: InferenceJob, waits: Arc<Mutex<VecDeque<u64>>>, line: WaitLine) {
+    if job.cancel.load(Ordering::SeqCst) || job.sender.is_closed() {
+        debug!(
+            connection_id = %job.connection_id,
+            request_id = %job.request_id,
+            "dropping queued job of a cancelled or closed connection"
+        );
         return;
     }
 
+    // From here on the reply outlives the socket: a disconnect detaches it
+    // and it keeps generating for a client that resumes.
+    job.resume.start(
+        &job.request_id,
+        &job.chat_id,
+        job.device_hash.as_deref(),
+        job.cancel.clone(),
+        &job.connection_id,
+        job.sender.clone(),
+    );
+
+    // Search, tool and analysis time is not queue wait.
+    let mut analysis_time = Duration::ZERO;
+    if let Some(search) = job.web_search.take() {
+        let started = Instant::now();
+        if let Some(block) = search
+            .context(&job.infer, &job.sender, job.cancel.clone())
+            .await
+        {
+            job.prompt = search.reply_prompt(&block);
+            if let Some(tools) = job.tools.as_mut() {
+                tools.system_prompt = format!("{}\n\n{block}", tools.system_prompt);
+            }
+            if let Some(analysis) = job.analysis.as_mut() {
+                analysis.system_prompt = format!("{}\n\n{block}", analysis.system_prompt);
+            }
+        }
+        analysis_time = started.elapsed();
+        if job.cancel.load(Ordering::SeqCst) {
+            job.resume.abandon(&job.request_id);
+            return;
+        }
+    }
+    if let Some(tools) = job.tools.take() {
+        let started = Instant::now();
+        if let Some(prompt) = tools.run(&job.infer, &job.sender, job.cancel.clone()).await {
+            job.prompt = prompt;
+            job.analysis = None;
+        }
+        analysis_time += started.elapsed();
+        if job.cancel.load(Ordering::SeqCst) {
+            job.resume.abandon(&job.request_id);
+            return;
+        }
+    }
+    if let Some(analysis) = job.analysis.take() {
+        let started = Instant::now();
+        job.prompt = analysis
+            .reply_prompt(&job.infer, &job.db, job.cancel.clone())
+            .await;
+        analysis_time += started.elapsed();
+        if job.cancel.load(Ordering::SeqCst) {
+            job.resume.abandon(&job.request_id);
+            return;
+        }
+    }
+
     info!(
         chat_id = job.chat_id.as_str(),
         session_id = job.session_id.as_str(),
-        stop_sequences = "<|im_end|>",
+        stop_sequences = ?job.generation.stop,
+        max_tokens = job.generation.max_tokens,
         "starting mistral stream"
     );
 
-    let mut stream = job
-        .infer
-        .generate_stream(job.prompt.clone(), job.cancel.clone());
+    let ticket = line.join(&job.request_id, &job.chat_id);
+    ticket.announce(&job.request_id, job.sender.clone());
+    let reply = job.infer.generate_reply(
+        job.prompt.clone(),
+        Some(job.chat_id.clone()),
+        job.generation.clone(),
+        job.cancel.clone(),
+    );
+    let mut stream = reply.rx;
+    let deadline = timeouts::deadline(job.timeout);
 
     let mut assistant_reply = String::new();
-
-    while let Some(token) = stream.recv().await {
+    // Set here for what only the worker sees; otherwise the backend's.
+    let mut finish_reason = None;
+    let mut last_preview = Instant::now();
+    let mut previewed_len = 0usize;
+
+    let max_detached_tokens = resume::max_detached_tokens();
+    let mut detached_tokens = 0usize;
+    let mut pending = String::new();
+    let mut seq = 0u64;
+    let mut last_flush = Instant::now();
+    let mut first_token = true;
+    let mut received = 0usize;
+    let mut streaming_since = Instant::now();
+    loop {
+        let token = match timeouts::recv_until(&mut stream, deadline).await {
+            Ok(Some(token)) => token,
+            Ok(None) => break,
+            Err(_) => {
+                finish_reason = Some(FinishReason::Timeout);
+                break;
+            }
+        };
+        received += 1;
+        ticket.token();
+        if first_token {
+            first_token = false;
+            streaming_since = Instant::now();
+            record_wait(
+                &waits,
+                job.enqueued_at.elapsed().saturating_sub(analysis_time),
+            );
+        }
         if token.contains("<|im_end|>") {
             break;
         }
 
-        assistant_reply.push_str(token.as_str());
+        if let Some(err) = token.strip_prefix(ENGINE_ERROR_PREFIX) {
+            finish_reason = Some(FinishReason::Error);
+            line.record_error(&job.request_id, &job.chat_id, err.trim());
+        }
 
-        let msg = serde_json::json!({
-            "type": "assistant",
-            "token": token
-        });
+        assistant_reply.push_str(token.as_str());
+        pending.push_str(token.as_str());
 
         if job.cancel.load(Ordering::SeqCst) {
+            finish_reason = Some(FinishReason::Cancelled);
             break;
         }
 
-        if job.sender.is_closed() {
-            break;
+        if !is_backed_up(&job) || last_flush.elapsed() >= COALESCE_INTERVAL {
+            last_flush = Instant::now();
+            seq += 1;
+            if !emit_token(&job, &std::mem::take(&mut pending), seq).await {
+                detached_tokens += 1;
+                if detached_tokens > max_detached_tokens {
+                    finish_reason = Some(FinishReason::Disconnected);
+                    break;
+                }
+            }
         }
 
-        if job
-            .sender
-            .send(WsMessage::Text(msg.to_string().into()))
-            .await
-            .is_err()
+        if !job.sandbox
+            && previewed_len < PREVIEW_CHARS
+            && last_preview.elapsed() >= PREVIEW_INTERVAL
         {
-            break;
+            last_preview = Instant::now();
+            previewed_len = send_live_preview(&job, &assistant_reply, false);
         }
     }
 
-    let final_response = trim_partial_chatml(&strip_chatml_markers(&assistant_reply)).to_string();
-    let final_response = tidy_decoded_text(&final_response);
+    // Stops a reply still generating when the loop broke early.
+    drop(stream);
+    if finish_reason.is_none() && received > 0 {
+        line.record(received, streaming_since.elapsed());
+    }
+    drop(ticket);
+    if !pending.is_empty() {
+        emit_token(&job, &pending, seq + 1).await;
+    }
+    let finish_reason = finish_reason
+        .or_else(|| job.generation.outcome.reason())
+        .unwrap_or(FinishReason::Eos);
+
+    let earlier = job
+        .continues
+        .as_ref()
+        .and_then(|m| m.text.as_deref())
+        .unwrap_or_default();
+    let final_response = postprocess::clean_output(&format!("{earlier}{assistant_reply}"));
+    let fallback = reply.fallback.load(Ordering::SeqCst);
+
+    let mut meta = serde_json::Map::new();
+    if fallback {
+        meta.insert("fallback".into(), serde_json::Value::Bool(true));
+    }
+    if let Some(seed) = job.generation.seed {
+        meta.insert("seed".into(), seed.into());
+    }
+    meta.insert("finish_reason".into(), serde_json::json!(finish_reason));
+    // What the user saw before it stopped; a `continue` can finish it.
+    if matches!(
+        finish_reason,
+        FinishReason::Cancelled | FinishReason::Disconnected | FinishReason::Timeout
+    ) {
+        meta.insert("truncated".into(), true.into());
+    }
+    if !job.experiments.is_empty() {
+        meta.insert("experiments".into(), experiments::tags(&job.experiments));
+    }
 
+    // A continuation keeps the id and timestamp, so it overwrites the
+    // stored message in place.
+    let (id, ts) = match &job.continues {
+        Some(earlier) => (earlier.id.clone(), earlier.ts),
+        None => (Uuid::new_v4().to_string(), chrono::Utc::now().timestamp()),
+    };
     let assistant_msg = Message {
-        id: Uuid::new_v4().to_string(),
+        id,
         chat_id: job.chat_id.clone(),
         session_id: Some(job.session_id.clone()),
         user_id: None,
@@ -134,18 +434,28 @@ async fn process_job(job: InferenceJob) {
         language: None,
         attachments: Vec::new(),
         liked: false,
-        ts: chrono::Utc::now().timestamp(),
-        meta: None,
+        feedback: None,
+        ts,
+        meta: Some(serde_json::Value::Object(meta)),
     };
 
-    if let Err(err) = job.db.save_message(&assistant_msg).await {
+    if let Err(err) = touch_chat(&job.db, &assistant_msg.chat_id, None, Some(&assistant_msg)).await
+    {
         eprintln!(
             "failed to save assistant message {}: {err}",
             assistant_msg.id
         );
     }
 
-    let _ = touch_chat(&job.db, &assistant_msg.chat_id, None).await;
+    if !job.sandbox {
+        send_live_preview(&job, &final_response, true);
+        publish_finalized(&job, &assistant_msg, &final_response, finish_reason).await;
+        if !job.experiments.is_empty() {
+            let chars = final_response.chars().count() as u64;
+            experiments::record(&job.db, &job.experiments, Outcome::Reply, 1).await;
+            experiments::record(&job.db, &job.experiments, Outcome::ReplyChars, chars).await;
+        }
+    }
 
     // -----------------------
     // LOAD UPDATED HISTORY
@@ -159,56 +469,281 @@ async fn process_job(job: InferenceJob) {
     // -----------------------
     // SUMMARY TRIGGER (correct!)
     // -----------------------
-    if should_generate_summary(&history) {
+    if !job.sandbox && should_generate_summary(&history) {
         debug!("summary triggered for chat {}", job.chat_id);
-        if let Err(e) = generate_summary_message(
+        match generate_summary_message(
             job.db.clone(),
             job.chat_id.clone(),
-            job.sender.clone(),
             history.clone(),
             job.infer.clone(),
+            job.language.clone(),
         )
         .await
         {
-            eprintln!("summary generation failed: {e}");
+            Ok(Some(summary_msg)) => {
+                emit(&job, summary_msg.to_string()).await;
+                publish_summary(&job, &summary_msg).await;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("summary generation failed: {e}"),
+        }
+    } else if !job.sandbox {
+        if let Some(embedder) = job.embedder.clone() {
+            refresh_drifted_summary(&job, &history, embedder).await;
         }
     }
+    if !job.sandbox && compaction::needs_compaction(&history) {
+        compaction::spawn_compaction(job.db.clone(), job.infer.clone(), job.chat_id.clone());
+    }
 
-    let done_msg = serde_json::json!({
+    let mut done_msg = serde_json::json!({
         "type": "assistant",
         "done": true
     });
+    if fallback {
+        done_msg["fallback"] = serde_json::Value::Bool(true);
+    }
+    if let Some(seed) = job.generation.seed {
+        done_msg["seed"] = seed.into();
+    }
+    done_msg["finish_reason"] = serde_json::json!(finish_reason);
+    if let Some(earlier) = &job.continues {
+        done_msg["continues"] = earlier.id.clone().into();
+    }
+    // The streamed tokens were not filtered, and a continuation only
+    // streamed its new part; hand over what was stored.
+    if postprocess::pipeline().rewrites() || job.continues.is_some() {
+        done_msg["text"] = final_response.clone().into();
+    }
+    if !job.experiments.is_empty() {
+        done_msg["experiments"] = experiments::tags(&job.experiments);
+    }
+
+    let done_text = done_msg.to_string();
+    let attached = job
+        .resume
+        .finish(&job.request_id, done_text.clone(), &final_response);
+    deliver(&job, attached, done_text).await;
+}
+
+/// Token frames are numbered per reply so a client can tell it missed
+/// one.
+fn token_frame(delta: &str, seq: u64) -> String {
+    serde_json::json!({
+        "type": "assistant",
+        "token": delta,
+        "seq": seq,
+    })
+    .to_string()
+}
+
+/// True when the attached socket's channel is over half full.
+fn is_backed_up(job: &InferenceJob) -> bool {
+    job.resume
+        .attached(&job.request_id)
+        .is_some_and(|a| a.sender.capacity() < a.sender.max_capacity() / 2)
+}
+
+/// Buffer a reply frame for `resume`, send it to the attached socket and
+/// to the chat's other sockets. False while no socket is attached.
+async fn emit(job: &InferenceJob, frame: String) -> bool {
+    let attached = job.resume.push(&job.request_id, frame.clone());
+    deliver(job, attached, frame).await
+}
 
-    if job
+/// [`emit`] for a token delta, which also extends the text `sync` returns.
+async fn emit_token(job: &InferenceJob, delta: &str, seq: u64) -> bool {
+    let frame = token_frame(delta, seq);
+    let attached = job
+        .resume
+        .push_token(&job.request_id, delta, seq, frame.clone());
+    deliver(job, attached, frame).await
+}
+
+async fn deliver(job: &InferenceJob, attached: Option<Attached>, frame: String) -> bool {
+    job.chats.publish(
+        &job.chat_id,
+        attached.as_ref().map(|a| a.connection_id.as_str()),
+        &frame,
+    );
+    let Some(attached) = attached else {
+        return false;
+    };
+    if attached
         .sender
-        .send(WsMessage::Text(done_msg.to_string().into()))
+        .send(WsMessage::Text(frame.into()))
         .await
         .is_err()
     {
+        job.resume.drop_sender(&job.request_id);
+    }
+    true
+}
+
+/// Share the head of an in-progress reply with the user's other sockets so
+/// their chat list can show activity. Returns the number of chars sent.
+fn send_live_preview(job: &InferenceJob, reply: &str, done: bool) -> usize {
+    let head: String = strip_chatml_markers(reply)
+        .trim_start()
+        .chars()
+        .take(PREVIEW_CHARS)
+        .collect();
+    if head.is_empty() && !done {
+        return 0;
+    }
+    let preview = serde_json::json!({
+        "type": "live_preview",
+        "chat_id": job.chat_id,
+        "text": head,
+        "done": done,
+    });
+    job.connections
+        .send_preview_to_peers(&job.connection_id, &preview);
+    head.chars().count()
+}
+
+async fn publish_finalized(
+    job: &InferenceJob,
+    msg: &Message,
+    reply: &str,
+    finish_reason: FinishReason,
+) {
+    job.events.publish(Event::AssistantMessageFinalized(
+        AssistantMessageFinalized {
+            message_id: msg.id.clone(),
+            chat_id: msg.chat_id.clone(),
+            session_id: job.session_id.clone(),
+            request_id: job.request_id.clone(),
+            user_id: job_user_id(job).await,
+            device_hash: job.device_hash.clone(),
+            intent: job.prompt_key.clone(),
+            prompt_tokens: token_count(&job.infer, &job.prompt).await,
+            completion_tokens: token_count(&job.infer, reply).await,
+            finish_reason,
+            ts: msg.ts,
+        },
+    ));
+}
+
+async fn token_count(infer: &InferenceService, text: &str) -> usize {
+    infer.count_tokens(text).await.unwrap_or_else(|err| {
+        debug!("token count failed: {err}");
+        0
+    })
+}
+
+/// User linked to the job's device, if any.
+async fn job_user_id(job: &InferenceJob) -> Option<String> {
+    match job.device_hash.as_deref() {
+        Some(hash) => job
+            .db
+            .find_user_id_by_device(hash)
+            .await
+            .unwrap_or_default(),
+        None => None,
+    }
+}
+
+async fn publish_summary(job: &InferenceJob, frame: &serde_json::Value) {
+    let field = |name: &str| frame[name].as_str().unwrap_or_default().to_string();
+    job.events.publish(Event::SummaryCreated(SummaryCreated {
+        message_id: field("message_id"),
+        chat_id: job.chat_id.clone(),
+        user_id: job_user_id(job).await,
+        device_hash: job.device_hash.clone(),
+        text: field("text"),
+        ts: frame["ts"].as_i64().unwrap_or_default(),
+    }));
+}
+
+/// Rewrite the summary when the recent turns drifted away from it.
+async fn refresh_drifted_summary(
+    job: &InferenceJob,
+    history: &[Message],
+    embedder: Arc<RobertaIntentRouter>,
+) {
+    let drift = match summary_drift::summary_drift(history, embedder).await {
+        Ok(Some(drift)) => drift,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("summary drift check failed: {err}");
+            return;
+        }
+    };
+    debug!(
+        chat_id = job.chat_id.as_str(),
+        drift, "summary drift checked"
+    );
+    if drift <= summary_drift::drift_threshold() {
         return;
     }
+    let Some(previous) = history.iter().rev().find(|m| m.role == "summary") else {
+        return;
+    };
+    match write_summary(
+        &job.db,
+        &job.chat_id,
+        history,
+        &job.infer,
+        job.language.clone(),
+        Some((previous, drift)),
+    )
+    .await
+    {
+        Ok(Some(summary_msg)) => {
+            info!(
+                chat_id = job.chat_id.as_str(),
+                drift, "chat summary regenerated"
+            );
+            emit(job, summary_msg.to_string()).await;
+            publish_summary(job, &summary_msg).await;
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("summary regeneration failed: {e}"),
+    }
 }
 
+/// Store a short chat summary and return the `summary` frame announcing
+/// it; `None` when there is nothing to summarize. The summary is written
+/// in `language`, else in the language of the latest turn.
 pub async fn generate_summary_message(
     db: Arc<DBLayer>,
     chat_id: String,
-    ws_tx: mpsc::Sender<WsMessage>,
     history: Vec<Message>,
     infer: Arc<InferenceService>,
-) -> anyhow::Result<()> {
+    language: Option<String>,
+) -> anyhow::Result<Option<serde_json::Value>> {
     if history.iter().any(|m| m.role == "summary") {
-        return Ok(());
+        return Ok(None);
     }
+    write_summary(&db, &chat_id, &history, &infer, language, None).await
+}
 
+/// Summarize `history` and store the result. With `previous` (the summary
+/// being replaced and the drift that retired it) the old summary message is
+/// deleted and the frame names it in `replaces`.
+async fn write_summary(
+    db: &DBLayer,
+    chat_id: &str,
+    history: &[Message],
+    infer: &InferenceService,
+    language: Option<String>,
+    previous: Option<(&Message, f32)>,
+) -> anyhow::Result<Option<serde_json::Value>> {
+    // Turns carry the chat's locked language, so the latest one is current.
     let language_hint = history
         .iter()
+        .rev()
         .filter_map(|m| m.language.as_deref())
         .find(|lang| !lang.trim().is_empty());
 
-    let normalized_lang = language_hint.and_then(|lang| normalize_language_code(lang));
-    let summary_prompt = build_summary_prompt(&history);
+    let normalized_lang = language
+        .as_deref()
+        .and_then(normalize_language_code)
+        .or_else(|| language_hint.and_then(normalize_language_code));
+    let summary_prompt = build_summary_prompt(history, normalized_lang.as_deref());
     if summary_prompt.is_empty() {
-        return Ok(());
+        return Ok(None);
     }
 
     let cancel = Arc::new(AtomicBool::new(false));
@@ -219,12 +754,16 @@ pub async fn generate_summary_message(
     let trimmed = trim_partial_chatml(&raw);
     let cleaned = strip_chatml_markers(trimmed).trim().to_string();
     if cleaned.is_empty() {
-        return Ok(());
+        return Ok(None);
+    }
+
+    if previous.is_some_and(|(old, _)| old.text.as_deref() == Some(cleaned.as_str())) {
+        return Ok(None);
     }
 
     let msg = Message {
         id: Uuid::new_v4().to_string(),
-        chat_id: chat_id.clone(),
+        chat_id: chat_id.to_string(),
         session_id: None,
         user_id: None,
         device_hash: None,
@@ -233,14 +772,19 @@ pub async fn generate_summary_message(
         language: normalized_lang.clone(),
         attachments: Vec::new(),
         liked: false,
+        feedback: None,
         ts: chrono::Utc::now().timestamp(),
-        meta: None,
+        meta: previous
+            .map(|(old, drift)| serde_json::json!({ "replaces": old.id, "drift": drift })),
     };
 
     db.save_message(&msg).await?;
-    let _ = touch_chat(&db, &chat_id, None).await;
+    if let Some((old, _)) = previous {
+        db.delete_message(chat_id, &old.id).await?;
+    }
+    let _ = touch_chat(db, chat_id, None, None).await;
 
-    let summary_msg = serde_json::json!({
+    let mut summary_msg = serde_json::json!({
         "type": "summary",
         "chat_id": chat_id,
         "message_id": msg.id,
@@ -249,17 +793,19 @@ pub async fn generate_summary_message(
         "ts": msg.ts,
         "language": normalized_lang,
     });
+    if let Some((old, _)) = previous {
+        summary_msg["replaces"] = old.id.clone().into();
+    }
 
-    let _ = ws_tx
-        .send(WsMessage::Text(summary_msg.to_string().into()))
-        .await;
-
-    Ok(())
+    Ok(Some(summary_msg))
 }
 
+/// Used when no prompt file has a `chat_summary` template.
 const SUMMARY_PROMPT: &str = "Summarize user message to display in ui as chat summary with at most 20 characters.\nAvoid punctuation and keep it lowercase and plain text. If request is in other language than English, summarize in that language.\n";
 
-fn build_summary_prompt(history: &[Message]) -> String {
+/// Summary prompt over the latest user turns, with the `chat_summary`
+/// template of `language`.
+pub(crate) fn build_summary_prompt(history: &[Message], language: Option<&str>) -> String {
     if history.is_empty() {
         return String::new();
     }
@@ -275,7 +821,9 @@ fn build_summary_prompt(history: &[Message]) -> String {
     }
 
     summary_history = trim_history(summary_history, 6);
-    build_mistral_prompt(&summary_history, Some(SUMMARY_PROMPT))
+    let system_prompt = prompts::prompt_for_key(prompts::SUMMARY_PROMPT_KEY, language)
+        .unwrap_or_else(|| SUMMARY_PROMPT.to_string());
+    build_mistral_prompt(&summary_history, Some(&system_prompt))
 }
 
 fn normalize_language_code(raw: &str) -> Option<String> {
@@ -1,6 +1,13 @@
+pub mod broadcast;
 pub mod handler;
 pub mod inference_worker;
+pub mod queue;
+pub mod registry;
+pub mod resume;
 
+pub use broadcast::ChatBroadcast;
 pub use handler::ws_router;
 pub use handler::AppState;
 pub use inference_worker::InferenceWorker;
+pub use registry::ConnectionRegistry;
+pub use resume::ResumeRegistry;
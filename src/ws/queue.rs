@@ -0,0 +1,288 @@
+//! Where a chat reply stands in line. The worker starts every job at
+//! once, but replies wait for a free llama context before their first
+//! token; while one waits, its client is told its place in line and a
+//! rough ETA from how fast recent replies streamed. The admin live feed
+//! reads the line and its recent failures too.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::extract::ws::Message as WsMessage;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// How often a waiting reply's client hears where it stands; the first
+/// notice comes after one interval, so replies that start promptly get
+/// none.
+const NOTICE_INTERVAL: Duration = Duration::from_secs(2);
+const RATE_SAMPLES: usize = 20;
+const ERROR_SAMPLES: usize = 20;
+
+/// Tokens and streaming time of one finished reply.
+#[derive(Debug, Clone, Copy)]
+struct ReplyRate {
+    tokens: usize,
+    elapsed: Duration,
+}
+
+struct Entry {
+    id: u64,
+    request_id: String,
+    chat_id: String,
+    joined: Instant,
+    /// When the first token arrived; `None` while waiting.
+    started: Option<Instant>,
+    tokens: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct Line {
+    /// Waiting and streaming replies, in the order they joined.
+    entries: Vec<Entry>,
+    next_ticket: u64,
+    rates: VecDeque<ReplyRate>,
+    errors: VecDeque<LineError>,
+}
+
+impl Line {
+    fn streaming(&self) -> usize {
+        self.entries.iter().filter(|e| e.started.is_some()).count()
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        let mut position = 0;
+        for entry in self.entries.iter().filter(|e| e.started.is_none()) {
+            position += 1;
+            if entry.id == id {
+                return Some(position);
+            }
+        }
+        None
+    }
+}
+
+/// A reply in line, as the admin live feed shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct LineEntry {
+    pub request_id: String,
+    pub chat_id: String,
+    /// Still waiting for the first token.
+    pub waiting: bool,
+    /// Since the reply was queued.
+    pub elapsed_ms: u64,
+    pub tokens: usize,
+}
+
+/// A reply that failed or was turned away.
+#[derive(Debug, Clone, Serialize)]
+pub struct LineError {
+    pub ts: i64,
+    pub request_id: String,
+    pub chat_id: String,
+    pub message: String,
+}
+
+#[derive(Clone, Default)]
+pub struct WaitLine {
+    inner: Arc<Mutex<Line>>,
+}
+
+/// A reply's place in the [`WaitLine`]; leaves it when dropped.
+pub struct Ticket {
+    line: WaitLine,
+    id: u64,
+    tokens: Arc<AtomicUsize>,
+}
+
+impl WaitLine {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Line> {
+        self.inner.lock().expect("wait line lock poisoned")
+    }
+
+    /// Queue a reply at the back.
+    pub fn join(&self, request_id: &str, chat_id: &str) -> Ticket {
+        let mut line = self.lock();
+        let id = line.next_ticket;
+        line.next_ticket += 1;
+        let tokens = Arc::new(AtomicUsize::new(0));
+        line.entries.push(Entry {
+            id,
+            request_id: request_id.to_string(),
+            chat_id: chat_id.to_string(),
+            joined: Instant::now(),
+            started: None,
+            tokens: tokens.clone(),
+        });
+        Ticket {
+            line: self.clone(),
+            id,
+            tokens,
+        }
+    }
+
+    /// Replies waiting for their first token.
+    pub fn waiting(&self) -> usize {
+        let line = self.lock();
+        line.entries.len() - line.streaming()
+    }
+
+    /// Rough time until the reply at `position` (1 = next) starts.
+    pub fn eta(&self, position: usize) -> Option<Duration> {
+        let mut line = self.lock();
+        let streaming = line.streaming();
+        estimate(position, streaming, line.rates.make_contiguous())
+    }
+
+    /// Remember how fast a finished reply streamed, for [`eta`](Self::eta).
+    pub fn record(&self, tokens: usize, elapsed: Duration) {
+        let mut line = self.lock();
+        if line.rates.len() == RATE_SAMPLES {
+            line.rates.pop_front();
+        }
+        line.rates.push_back(ReplyRate { tokens, elapsed });
+    }
+
+    /// Remember why a reply failed or was turned away.
+    pub fn record_error(&self, request_id: &str, chat_id: &str, message: &str) {
+        let mut line = self.lock();
+        if line.errors.len() == ERROR_SAMPLES {
+            line.errors.pop_front();
+        }
+        line.errors.push_back(LineError {
+            ts: chrono::Utc::now().timestamp(),
+            request_id: request_id.to_string(),
+            chat_id: chat_id.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Waiting and streaming replies, in line order.
+    pub fn entries(&self) -> Vec<LineEntry> {
+        self.lock()
+            .entries
+            .iter()
+            .map(|e| LineEntry {
+                request_id: e.request_id.clone(),
+                chat_id: e.chat_id.clone(),
+                waiting: e.started.is_none(),
+                elapsed_ms: e.joined.elapsed().as_millis() as u64,
+                tokens: e.tokens.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// The last errors, oldest first.
+    pub fn errors(&self) -> Vec<LineError> {
+        self.lock().errors.iter().cloned().collect()
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.lock().position(id)
+    }
+}
+
+impl Ticket {
+    /// Place among the replies still waiting (1 = next); `None` once the
+    /// reply started.
+    pub fn position(&self) -> Option<usize> {
+        self.line.position(self.id)
+    }
+
+    /// The reply got a token; the first one ends its wait.
+    pub fn token(&self) {
+        if self.tokens.fetch_add(1, Ordering::Relaxed) == 0 {
+            let mut line = self.line.lock();
+            if let Some(entry) = line.entries.iter_mut().find(|e| e.id == self.id) {
+                entry.started = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Send `{"type":"queued","request_id","position","eta_secs"}` to
+    /// `sender` every [`NOTICE_INTERVAL`] until the reply starts.
+    pub fn announce(&self, request_id: &str, sender: mpsc::Sender<WsMessage>) {
+        let line = self.line.clone();
+        let id = self.id;
+        let request_id = request_id.to_string();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(NOTICE_INTERVAL).await;
+                let Some(position) = line.position(id) else {
+                    return;
+                };
+                let frame = queued_frame(&request_id, position, line.eta(position));
+                if sender.send(WsMessage::Text(frame.into())).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.line.lock().entries.retain(|e| e.id != self.id);
+    }
+}
+
+pub fn queued_frame(request_id: &str, position: usize, eta: Option<Duration>) -> String {
+    serde_json::json!({
+        "type": "queued",
+        "request_id": request_id,
+        "position": position,
+        "eta_secs": eta.map(|eta| eta.as_secs()),
+    })
+    .to_string()
+}
+
+/// Replies ahead of `position` each take as long as an average recent
+/// reply at the recent streaming rate, spread over the replies streaming
+/// now. `None` until a reply finished.
+fn estimate(position: usize, streaming: usize, rates: &[ReplyRate]) -> Option<Duration> {
+    let tokens: usize = rates.iter().map(|r| r.tokens).sum();
+    let secs: f64 = rates.iter().map(|r| r.elapsed.as_secs_f64()).sum();
+    if tokens == 0 || secs <= 0.0 {
+        return None;
+    }
+    let tokens_per_sec = tokens as f64 / secs;
+    let reply_secs = tokens as f64 / rates.len() as f64 / tokens_per_sec;
+    Some(Duration::from_secs_f64(
+        reply_secs * position.max(1) as f64 / streaming.max(1) as f64,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tickets_move_up_and_estimate_from_recent_replies() {
+        let line = WaitLine::default();
+        assert_eq!(line.eta(1), None);
+        let first = line.join("r1", "c1");
+        let second = line.join("r2", "c1");
+        assert_eq!(line.position(second.id), Some(2));
+        first.token();
+        first.token();
+        assert_eq!(line.position(second.id), Some(1));
+        let entries = line.entries();
+        assert!(!entries[0].waiting && entries[1].waiting);
+        assert_eq!(entries[0].tokens, 2);
+        drop(first);
+        assert_eq!(line.lock().streaming(), 0);
+
+        line.record(100, Duration::from_secs(4));
+        line.record(300, Duration::from_secs(8));
+        assert_eq!(line.eta(2), Some(Duration::from_secs(12)));
+        let rates = line.lock().rates.iter().copied().collect::<Vec<_>>();
+        assert_eq!(estimate(2, 2, &rates), Some(Duration::from_secs(6)));
+        drop(second);
+        assert_eq!(line.waiting(), 0);
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use axum::extract::ws::Message as WsMessage;
+use tokio::sync::mpsc;
+
+struct Connection {
+    owner: String,
+    sender: mpsc::Sender<WsMessage>,
+    live_preview: bool,
+}
+
+/// Open websocket connections grouped by owner (the user when the device is
+/// linked to an account, otherwise the device hash), so one socket can reach
+/// the others of the same person.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    inner: Arc<RwLock<HashMap<String, Connection>>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn owner_key(user_id: Option<&str>, device_hash: &str) -> String {
+        match user_id {
+            Some(uid) => format!("user:{uid}"),
+            None => format!("device:{device_hash}"),
+        }
+    }
+
+    /// Add or update a connection. Re-registering replaces the previous entry.
+    pub fn register(
+        &self,
+        connection_id: &str,
+        owner: String,
+        sender: mpsc::Sender<WsMessage>,
+        live_preview: bool,
+    ) {
+        self.inner.write().unwrap().insert(
+            connection_id.to_string(),
+            Connection {
+                owner,
+                sender,
+                live_preview,
+            },
+        );
+    }
+
+    pub fn unregister(&self, connection_id: &str) {
+        self.inner.write().unwrap().remove(connection_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Best-effort delivery to the other live-preview sockets of the same
+    /// owner. Never waits: a full or closed channel just misses the frame.
+    pub fn send_preview_to_peers(&self, connection_id: &str, value: &serde_json::Value) -> usize {
+        let conns = self.inner.read().unwrap();
+        let Some(owner) = conns.get(connection_id).map(|c| c.owner.as_str()) else {
+            return 0;
+        };
+        let text = value.to_string();
+        conns
+            .iter()
+            .filter(|(id, c)| id.as_str() != connection_id && c.owner == owner && c.live_preview)
+            .filter(|(_, c)| {
+                c.sender
+                    .try_send(WsMessage::Text(text.clone().into()))
+                    .is_ok()
+            })
+            .count()
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::extract::ws::Message as WsMessage;
+use tokio::sync::mpsc;
+
+const DEFAULT_MAX_DETACHED_TOKENS: usize = 1024;
+const DEFAULT_TTL_SECS: u64 = 300;
+
+/// Tokens a reply may generate while no socket is attached
+/// (`WS_RESUME_MAX_TOKENS`, default 1024, `0` stops on disconnect).
+pub fn max_detached_tokens() -> usize {
+    std::env::var("WS_RESUME_MAX_TOKENS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_DETACHED_TOKENS)
+}
+
+/// How long a reply stays resumable after it finished
+/// (`WS_RESUME_TTL_SECS`, default 300).
+fn ttl() -> Duration {
+    let secs = std::env::var("WS_RESUME_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TTL_SECS);
+    Duration::from_secs(secs)
+}
+
+struct Stream {
+    chat_id: String,
+    device_hash: Option<String>,
+    cancel: Arc<AtomicBool>,
+    /// Every frame sent for the reply so far, in order.
+    frames: Vec<String>,
+    /// Reply text so far; the final, cleaned-up text once finished.
+    text: String,
+    /// `seq` of the last token frame.
+    seq: u64,
+    /// Socket receiving the live tail; `None` while detached.
+    attached: Option<Attached>,
+    finished_at: Option<Instant>,
+}
+
+/// Socket a reply is streamed to.
+#[derive(Clone)]
+pub struct Attached {
+    pub connection_id: String,
+    pub sender: mpsc::Sender<WsMessage>,
+}
+
+/// Who a reply belongs to; a resume must match it.
+pub struct ResumeOwner {
+    pub chat_id: String,
+    pub device_hash: Option<String>,
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl ResumeOwner {
+    /// The reply is of `chat_id` and, when it was started from a known
+    /// device, of `device_hash`.
+    pub fn matches(&self, chat_id: &str, device_hash: &str) -> bool {
+        self.chat_id == chat_id
+            && self
+                .device_hash
+                .as_deref()
+                .map_or(true, |hash| hash == device_hash)
+    }
+}
+
+/// What `sync` returns: the reply text up to token frame `seq`.
+pub struct Snapshot {
+    pub text: String,
+    pub seq: u64,
+    pub done: bool,
+}
+
+pub enum CatchUp {
+    /// Frames the client has not seen yet; ask again after sending them.
+    Frames(Vec<String>),
+    /// The client is up to date and now receives the live tail.
+    Attached,
+}
+
+/// Frames of in-progress and recently finished replies, keyed by request
+/// id, so a client that reconnects can pick a reply up where it left off.
+/// A reply outlives its socket: on disconnect it is only detached and
+/// keeps generating, up to [`max_detached_tokens`].
+#[derive(Clone, Default)]
+pub struct ResumeRegistry {
+    inner: Arc<Mutex<HashMap<String, Stream>>>,
+}
+
+impl ResumeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start buffering a reply streamed to `sender`. Drops replies that
+    /// finished more than the TTL ago.
+    pub fn start(
+        &self,
+        request_id: &str,
+        chat_id: &str,
+        device_hash: Option<&str>,
+        cancel: Arc<AtomicBool>,
+        connection_id: &str,
+        sender: mpsc::Sender<WsMessage>,
+    ) {
+        let ttl = ttl();
+        let mut streams = self.inner.lock().unwrap();
+        streams.retain(|_, s| s.finished_at.map_or(true, |at| at.elapsed() < ttl));
+        streams.insert(
+            request_id.to_string(),
+            Stream {
+                chat_id: chat_id.to_string(),
+                device_hash: device_hash.map(str::to_string),
+                cancel,
+                frames: Vec::new(),
+                text: String::new(),
+                seq: 0,
+                attached: Some(Attached {
+                    connection_id: connection_id.to_string(),
+                    sender,
+                }),
+                finished_at: None,
+            },
+        );
+    }
+
+    /// Buffer a frame. Returns the attached socket to send it to, or `None`
+    /// while detached.
+    pub fn push(&self, request_id: &str, frame: String) -> Option<Attached> {
+        let mut streams = self.inner.lock().unwrap();
+        let stream = streams.get_mut(request_id)?;
+        stream.frames.push(frame);
+        stream.attached.clone()
+    }
+
+    /// [`push`](Self::push) for token frame `seq` carrying `delta`.
+    pub fn push_token(
+        &self,
+        request_id: &str,
+        delta: &str,
+        seq: u64,
+        frame: String,
+    ) -> Option<Attached> {
+        let mut streams = self.inner.lock().unwrap();
+        let stream = streams.get_mut(request_id)?;
+        stream.text.push_str(delta);
+        stream.seq = seq;
+        stream.frames.push(frame);
+        stream.attached.clone()
+    }
+
+    /// Buffer the last frame of a reply and mark it finished with its
+    /// final `text`.
+    pub fn finish(&self, request_id: &str, frame: String, text: &str) -> Option<Attached> {
+        let mut streams = self.inner.lock().unwrap();
+        let stream = streams.get_mut(request_id)?;
+        stream.frames.push(frame);
+        stream.text = text.to_string();
+        stream.finished_at = Some(Instant::now());
+        stream.attached.clone()
+    }
+
+    /// Socket a reply is streamed to, if any.
+    pub fn attached(&self, request_id: &str) -> Option<Attached> {
+        let streams = self.inner.lock().unwrap();
+        streams.get(request_id)?.attached.clone()
+    }
+
+    /// Detach a reply whose socket stopped accepting frames.
+    pub fn drop_sender(&self, request_id: &str) {
+        if let Some(stream) = self.inner.lock().unwrap().get_mut(request_id) {
+            stream.attached = None;
+        }
+    }
+
+    /// Forget a reply that stopped without finishing (cancelled before it
+    /// started streaming).
+    pub fn abandon(&self, request_id: &str) {
+        self.inner.lock().unwrap().remove(request_id);
+    }
+
+    /// Detach the unfinished replies of a closing socket. True when a reply
+    /// using the socket's `cancel` flag is still unfinished, here or resumed
+    /// on another socket, so the flag must not be set.
+    pub fn detach(&self, connection_id: &str, cancel: &Arc<AtomicBool>) -> bool {
+        let mut streams = self.inner.lock().unwrap();
+        let mut running = false;
+        for stream in streams.values_mut().filter(|s| s.finished_at.is_none()) {
+            if stream
+                .attached
+                .as_ref()
+                .is_some_and(|a| a.connection_id == connection_id)
+            {
+                stream.attached = None;
+            }
+            running |= Arc::ptr_eq(&stream.cancel, cancel);
+        }
+        running
+    }
+
+    pub fn snapshot(&self, request_id: &str) -> Option<Snapshot> {
+        let streams = self.inner.lock().unwrap();
+        let stream = streams.get(request_id)?;
+        Some(Snapshot {
+            text: stream.text.clone(),
+            seq: stream.seq,
+            done: stream.finished_at.is_some(),
+        })
+    }
+
+    pub fn owner(&self, request_id: &str) -> Option<ResumeOwner> {
+        let streams = self.inner.lock().unwrap();
+        let stream = streams.get(request_id)?;
+        Some(ResumeOwner {
+            chat_id: stream.chat_id.clone(),
+            device_hash: stream.device_hash.clone(),
+            cancel: stream.cancel.clone(),
+        })
+    }
+
+    /// Frames after the first `seen`, or, once there are none, attach
+    /// `sender` for the rest. Checking and attaching under one lock means
+    /// no frame is lost or sent twice. `None` when the reply is unknown.
+    pub fn catch_up(
+        &self,
+        request_id: &str,
+        seen: usize,
+        connection_id: &str,
+        sender: &mpsc::Sender<WsMessage>,
+    ) -> Option<CatchUp> {
+        let mut streams = self.inner.lock().unwrap();
+        let stream = streams.get_mut(request_id)?;
+        if seen < stream.frames.len() {
+            return Some(CatchUp::Frames(stream.frames[seen..].to_vec()));
+        }
+        if stream.finished_at.is_none() {
+            stream.attached = Some(Attached {
+                connection_id: connection_id.to_string(),
+                sender: sender.clone(),
+            });
+        }
+        Some(CatchUp::Attached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumed_socket_gets_backlog_then_live_tail() {
+        let registry = ResumeRegistry::new();
+        let (old_tx, _old_rx) = mpsc::channel(4);
+        let (new_tx, _new_rx) = mpsc::channel(4);
+        let cancel = Arc::new(AtomicBool::new(false));
+        registry.start("r1", "chat", None, cancel.clone(), "conn-1", old_tx);
+
+        assert!(registry.push("r1", "a".into()).is_some());
+        assert!(registry.detach("conn-1", &cancel));
+        assert!(registry.push("r1", "b".into()).is_none());
+
+        let Some(CatchUp::Frames(frames)) = registry.catch_up("r1", 0, "conn-2", &new_tx) else {
+            panic!("expected backlog");
+        };
+        assert_eq!(frames, vec!["a", "b"]);
+        assert!(matches!(
+            registry.catch_up("r1", 2, "conn-2", &new_tx),
+            Some(CatchUp::Attached)
+        ));
+        assert!(registry.push("r1", "c".into()).is_some());
+        assert!(!registry.detach("conn-2", &Arc::new(AtomicBool::new(false))));
+        registry.abandon("r1");
+        assert!(registry.catch_up("r1", 3, "conn-2", &new_tx).is_none());
+    }
+
+    #[test]
+    fn snapshot_has_text_up_to_last_token_then_final_text() {
+        let registry = ResumeRegistry::new();
+        let (tx, _rx) = mpsc::channel(4);
+        let cancel = Arc::new(AtomicBool::new(false));
+        registry.start("r1", "chat", None, cancel, "conn-1", tx);
+        registry.push_token("r1", "Hel", 1, "f1".into());
+        registry.push_token("r1", "lo ", 2, "f2".into());
+
+        let snapshot = registry.snapshot("r1").unwrap();
+        assert_eq!(
+            (snapshot.text.as_str(), snapshot.seq, snapshot.done),
+            ("Hello ", 2, false)
+        );
+
+        registry.finish("r1", "done".into(), "Hello");
+        let snapshot = registry.snapshot("r1").unwrap();
+        assert_eq!(
+            (snapshot.text.as_str(), snapshot.seq, snapshot.done),
+            ("Hello", 2, true)
+        );
+    }
+}